@@ -0,0 +1,466 @@
+//! Backends that models run on, behind one interface for the load and inference code.
+//!
+//! A backend sets up the device of a model, uploads its weights into a runtime, runs the softmax
+//! of the samplers and reads state files for that device. The WebGPU backend is always built and
+//! also serves `Cpu` on the software adapter; the HIP backend needs the `hip` feature. A new
+//! backend implements [`InferenceBackend`] and is returned by [`select`], and the orchestration in
+//! `reload` and `run` stays as it is.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use futures::{future::BoxFuture, FutureExt};
+use safetensors::SafeTensors;
+use web_rwkv::{
+    context::{Context, ContextBuilder, ContextError, InstanceExt},
+    runtime::{
+        infer::Rnn,
+        model::{ContextAutoLimits, ModelInfo, State},
+        Runtime,
+    },
+    tensor::TensorCpu,
+    wgpu::{Backends, PowerPreference, RequestAdapterOptions},
+};
+
+#[cfg(feature = "hip")]
+use crate::{hip_lora, hip_state, reload, LoadPhase};
+use crate::{
+    load_model_state, load_runtime, preflight,
+    reload::{AdapterOption, Backend},
+    stats, AdapterList, InitState, LoadTracker, LoadType, ModelSerialize, ReloadRequest,
+};
+
+/// What a backend hands over once the weights of a model are uploaded.
+pub(crate) struct LoadedRuntime {
+    pub states: Vec<InitState>,
+    pub runtime: Arc<dyn Runtime<Rnn> + Send + Sync>,
+    pub state: Arc<dyn State + Send + Sync>,
+    pub model: Option<Arc<dyn ModelSerialize + Send + Sync>>,
+}
+
+/// A device family that models can be loaded on and run with.
+pub(crate) trait InferenceBackend: Send + Sync {
+    /// Names of the devices of this backend, as listed by `ThreadRequest::Adapter`.
+    fn adapters(&self) -> BoxFuture<'_, Vec<String>>;
+
+    /// Set up the device for `info`, refusing a load that cannot fit it.
+    fn create_context<'a>(
+        &'a mut self,
+        name: &'a str,
+        info: &'a ModelInfo,
+        request: &'a ReloadRequest,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Upload the weights and build the runtime; after [`InferenceBackend::create_context`].
+    fn load_runtime<'a>(
+        &'a self,
+        info: &'a ModelInfo,
+        request: &'a ReloadRequest,
+        load: LoadType,
+        tracker: &'a LoadTracker,
+    ) -> BoxFuture<'a, Result<LoadedRuntime>>;
+
+    /// Softmax of a batch of logits.
+    fn softmax(&self, input: Vec<TensorCpu<f32>>) -> BoxFuture<'_, Result<Vec<TensorCpu<f32>>>>;
+
+    /// Read an initial state from a SafeTensors state file.
+    fn read_state<'a>(
+        &'a self,
+        info: &'a ModelInfo,
+        model: SafeTensors<'a>,
+    ) -> BoxFuture<'a, Result<TensorCpu<f32>>>;
+
+    /// The WebGPU context of the model, for work beyond inference such as baking quantization
+    /// into a save. `None` for backends without one.
+    fn context(&self) -> Option<Context>;
+}
+
+/// The backend that `kind` selects.
+pub(crate) fn select(kind: Backend) -> Result<Box<dyn InferenceBackend>> {
+    match kind {
+        Backend::WebGpu | Backend::Cpu => Ok(Box::new(WebGpuBackend::new(kind))),
+        #[cfg(feature = "hip")]
+        Backend::Hip => Ok(Box::new(HipBackend)),
+        #[cfg(not(feature = "hip"))]
+        Backend::Hip => bail!("HIP backend requested but the 'hip' feature is not enabled"),
+    }
+}
+
+/// Devices of every backend built in.
+pub(crate) async fn list_adapters() -> AdapterList {
+    let backends: Vec<Box<dyn InferenceBackend>> = vec![
+        Box::new(WebGpuBackend::new(Backend::WebGpu)),
+        #[cfg(feature = "hip")]
+        Box::new(HipBackend),
+    ];
+    let mut list = vec![];
+    for backend in &backends {
+        list.extend(backend.adapters().await);
+    }
+    AdapterList(list)
+}
+
+/// Runs models with the WebGPU kernels of `web-rwkv`, on a hardware adapter or, for
+/// [`Backend::Cpu`], on the software adapter of the platform.
+pub(crate) struct WebGpuBackend {
+    kind: Backend,
+    context: Option<Context>,
+}
+
+impl WebGpuBackend {
+    pub fn new(kind: Backend) -> Self {
+        Self {
+            kind,
+            context: None,
+        }
+    }
+
+    fn device(&self) -> Result<&Context> {
+        match &self.context {
+            Some(context) => Ok(context),
+            None => bail!("the WebGPU context is not created yet"),
+        }
+    }
+}
+
+/// Create the context on the selected adapter, once the model is checked to fit it.
+async fn create_context(
+    kind: Backend,
+    info: &ModelInfo,
+    request: &ReloadRequest,
+) -> Result<Context> {
+    let backends = Backends::all();
+    let instance = web_rwkv::wgpu::Instance::default();
+    let adapter = match (kind, request.adapter) {
+        // the software adapter of the platform, such as lavapipe or WARP
+        (Backend::Cpu, _) => {
+            let options = RequestAdapterOptions {
+                power_preference: PowerPreference::LowPower,
+                force_fallback_adapter: true,
+                compatible_surface: None,
+            };
+            instance.request_adapter(&options).await.map_err(|err| {
+                anyhow::anyhow!(
+                    "no CPU adapter is available ({err}); \
+                     install a software Vulkan driver such as Mesa's lavapipe"
+                )
+            })?
+        }
+        (_, AdapterOption::Auto) => instance.adapter(PowerPreference::HighPerformance).await?,
+        (_, AdapterOption::Economical) => instance.adapter(PowerPreference::LowPower).await?,
+        (_, AdapterOption::Manual(selection)) => instance
+            .enumerate_adapters(backends)
+            .await
+            .into_iter()
+            .nth(selection)
+            .ok_or(ContextError::RequestAdapterFailed)?,
+    };
+    let estimate = preflight::check(info, request, Some(&adapter.limits()))?;
+    tracing::info!(
+        event = "memory_estimate",
+        weights = estimate.weights,
+        states = estimate.states,
+        buffers = estimate.buffers,
+        "Estimated device memory"
+    );
+    let context = ContextBuilder::new(adapter)
+        .auto_limits(info)
+        .build()
+        .await?;
+    Ok(context)
+}
+
+impl InferenceBackend for WebGpuBackend {
+    fn adapters(&self) -> BoxFuture<'_, Vec<String>> {
+        async {
+            let instance = web_rwkv::wgpu::Instance::default();
+            instance
+                .enumerate_adapters(Backends::all())
+                .await
+                .into_iter()
+                .map(|adapter| adapter.get_info())
+                .map(|info| format!("{} ({:?})", info.name, info.backend))
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn create_context<'a>(
+        &'a mut self,
+        name: &'a str,
+        info: &'a ModelInfo,
+        request: &'a ReloadRequest,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let context = create_context(self.kind, info, request).await?;
+            let adapter_info = context.adapter.get_info();
+            tracing::info!(
+                event = "gpu_context",
+                adapter_name = %adapter_info.name,
+                vendor = adapter_info.vendor,
+                device = adapter_info.device,
+                device_type = ?adapter_info.device_type,
+                driver = %adapter_info.driver,
+                driver_info = %adapter_info.driver_info,
+                backend = ?adapter_info.backend,
+                "GPU context created"
+            );
+            metrics::gauge!(
+                stats::ADAPTER_INFO,
+                "model" => name.to_owned(),
+                "adapter" => adapter_info.name.clone(),
+                "backend" => format!("{:?}", adapter_info.backend),
+                "device_type" => format!("{:?}", adapter_info.device_type),
+                "driver" => adapter_info.driver.clone()
+            )
+            .set(1.0);
+            self.context = Some(context);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn load_runtime<'a>(
+        &'a self,
+        info: &'a ModelInfo,
+        request: &'a ReloadRequest,
+        load: LoadType,
+        tracker: &'a LoadTracker,
+    ) -> BoxFuture<'a, Result<LoadedRuntime>> {
+        async move {
+            let context = self.device()?;
+            let (states, runtime, state, model) =
+                load_runtime(context, info, request, load, tracker).await?;
+            Ok(LoadedRuntime {
+                states,
+                runtime,
+                state,
+                model: Some(model),
+            })
+        }
+        .boxed()
+    }
+
+    fn softmax(&self, input: Vec<TensorCpu<f32>>) -> BoxFuture<'_, Result<Vec<TensorCpu<f32>>>> {
+        async move {
+            let context = self.device()?;
+            Ok(web_rwkv::runtime::softmax::softmax(context, input).await?)
+        }
+        .boxed()
+    }
+
+    fn read_state<'a>(
+        &'a self,
+        info: &'a ModelInfo,
+        model: SafeTensors<'a>,
+    ) -> BoxFuture<'a, Result<TensorCpu<f32>>> {
+        async move { load_model_state(self.device()?, info, model).await }.boxed()
+    }
+
+    fn context(&self) -> Option<Context> {
+        self.context.clone()
+    }
+}
+
+/// Runs RWKV v7 models on AMD GPUs through ROCm, with the kernels of `hip-rwkv`.
+#[cfg(feature = "hip")]
+pub(crate) struct HipBackend;
+
+/// Weights of a model served by the HIP backend.
+///
+/// The HIP backend uploads the SafeTensors file with its LoRAs blended in, so saving the weights
+/// blends them again and writes the result as SafeTensors, which every backend loads.
+#[cfg(feature = "hip")]
+struct HipWeights {
+    path: std::path::PathBuf,
+    lora: Vec<reload::Lora>,
+}
+
+#[cfg(feature = "hip")]
+impl ModelSerialize for HipWeights {
+    fn serialize(&self, mut file: std::fs::File) -> Result<()> {
+        use std::io::Write;
+
+        if self.lora.is_empty() {
+            let mut source = std::fs::File::open(&self.path)?;
+            std::io::copy(&mut source, &mut file)?;
+        } else {
+            file.write_all(&hip_lora::blend_model(&self.path, &self.lora)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Load an RWKV model using the HIP backend (AMD GPU via ROCm).
+///
+/// Only supports V7 models. Loads the model weights into HIP device memory
+/// via `Rwkv7Hip::load`, then creates a `HipRuntime` for inference and a
+/// `HipStateAdapter` for state management.
+///
+/// LoRAs of the request are blended on the CPU into a temporary SafeTensors
+/// file, with the blending of the WebGPU backend, which is then uploaded instead.
+///
+/// The serializable model handle saves the weights as SafeTensors rather than
+/// the CBOR prefab of the WebGPU backend.
+#[cfg(feature = "hip")]
+async fn load_runtime_hip(
+    info: &ModelInfo,
+    request: &ReloadRequest,
+    tracker: &LoadTracker,
+) -> Result<LoadedRuntime> {
+    use web_rwkv::runtime::model::ModelVersion;
+
+    if info.version != ModelVersion::V7 {
+        bail!(
+            "HIP backend only supports RWKV v7 models, got {:?}",
+            info.version
+        );
+    }
+
+    let model = Arc::new(HipWeights {
+        path: request.model_path.clone(),
+        lora: request.lora.clone(),
+    });
+    let token_chunk_size = request.token_chunk_size;
+    let max_batch = request.max_batch;
+
+    // `Rwkv7Hip::load` reads the weights from disk, so blended ones go through a temporary file
+    let blended = match request.lora.is_empty() {
+        true => None,
+        false => {
+            tracker.phase(LoadPhase::Lora, 0);
+            log::info!(
+                "[hip] blending {} LoRA(s) on the CPU...",
+                request.lora.len()
+            );
+            let path = std::env::temp_dir().join(format!("ai00-hip-{}.st", uuid::Uuid::new_v4()));
+            let (model_path, lora, output) = (
+                request.model_path.clone(),
+                request.lora.clone(),
+                path.clone(),
+            );
+            tokio::task::spawn_blocking(move || -> Result<()> {
+                let data = hip_lora::blend_model(&model_path, &lora)?;
+                std::fs::write(&output, data)?;
+                Ok(())
+            })
+            .await??;
+            Some(path)
+        }
+    };
+    let model_path = blended.clone().unwrap_or(request.model_path.clone());
+
+    // Load model weights on a blocking thread (file I/O + GPU upload)
+    tracker.phase(LoadPhase::Upload, 0);
+    log::info!("[hip] loading model weights from {:?}...", model_path);
+    let hip_model = tokio::task::spawn_blocking(move || {
+        log::info!("[hip] spawn_blocking: calling Rwkv7Hip::load...");
+        let result = hip_rwkv::hip::Rwkv7Hip::load(&model_path);
+        log::info!(
+            "[hip] spawn_blocking: Rwkv7Hip::load returned {:?}",
+            result.is_ok()
+        );
+        result
+    })
+    .await;
+    if let Some(path) = blended {
+        let _ = std::fs::remove_file(path);
+    }
+    let hip_model = hip_model?.map_err(|e| anyhow::anyhow!("HIP model load failed: {}", e))?;
+
+    log::info!("[hip] model loaded, creating runtime...");
+    tracker.phase(LoadPhase::Runtime, 0);
+    // Create runtime with configuration matching the request
+    let config = hip_rwkv::hip::HipRuntimeConfig::new(token_chunk_size, max_batch);
+    let hip_runtime = hip_rwkv::hip::HipRuntime::with_config(hip_model, config)
+        .map_err(|e| anyhow::anyhow!("HIP runtime init failed: {}", e))?;
+    log::info!("[hip] runtime created successfully");
+
+    let runtime = Arc::new(hip_runtime);
+    let state: Arc<dyn State + Send + Sync> =
+        Arc::new(hip_state::HipStateAdapter::new(runtime.clone(), max_batch));
+
+    // HIP path does not support loading initial states from SafeTensors files
+    // (that requires a wgpu Context). Return empty states list.
+    let states = Vec::new();
+
+    log::info!(
+        "HIP runtime created: max_batch={}, chunk_size={}",
+        max_batch,
+        token_chunk_size
+    );
+
+    Ok(LoadedRuntime {
+        states,
+        runtime,
+        state,
+        model: Some(model),
+    })
+}
+
+#[cfg(feature = "hip")]
+impl InferenceBackend for HipBackend {
+    fn adapters(&self) -> BoxFuture<'_, Vec<String>> {
+        async {
+            let Ok(count) = hip_rwkv::hip::get_device_count() else {
+                return vec![];
+            };
+            (0..count)
+                .map(|id| {
+                    let name = hip_rwkv::hip::get_device_name(id)
+                        .unwrap_or_else(|_| format!("HIP Device {}", id));
+                    format!("{} (HIP)", name)
+                })
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn create_context<'a>(
+        &'a mut self,
+        _name: &'a str,
+        info: &'a ModelInfo,
+        request: &'a ReloadRequest,
+    ) -> BoxFuture<'a, Result<()>> {
+        // HIP reports no buffer limits, so only the budget of the request is checked
+        async move {
+            preflight::check(info, request, None)?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn load_runtime<'a>(
+        &'a self,
+        info: &'a ModelInfo,
+        request: &'a ReloadRequest,
+        _load: LoadType,
+        tracker: &'a LoadTracker,
+    ) -> BoxFuture<'a, Result<LoadedRuntime>> {
+        load_runtime_hip(info, request, tracker).boxed()
+    }
+
+    fn softmax(&self, input: Vec<TensorCpu<f32>>) -> BoxFuture<'_, Result<Vec<TensorCpu<f32>>>> {
+        async move {
+            // GPU softmax on HIP device -- synchronous but runs in its own task
+            tokio::task::spawn_blocking(move || {
+                hip_rwkv::hip::softmax_hip_batch(input)
+                    .map_err(|e| anyhow::anyhow!("HIP softmax error: {}", e))
+            })
+            .await?
+        }
+        .boxed()
+    }
+
+    fn read_state<'a>(
+        &'a self,
+        _info: &'a ModelInfo,
+        _model: SafeTensors<'a>,
+    ) -> BoxFuture<'a, Result<TensorCpu<f32>>> {
+        async { bail!("loading state files from SafeTensors requires a WebGPU context") }.boxed()
+    }
+
+    fn context(&self) -> Option<Context> {
+        None
+    }
+}
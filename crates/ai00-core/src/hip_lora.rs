@@ -0,0 +1,258 @@
+//! LoRA blending for the HIP backend, done on the CPU before the weights are uploaded.
+//!
+//! The blending follows the LoRA format of the WebGPU loader. A matrix `{name}.weight` of the
+//! model is blended with the `{name}.lora.0` (`A`, `[rank, in]`) and `{name}.lora.1`
+//! (`B`, `[out, rank]`) of a LoRA into `W + alpha / rank * B A`. Any other tensor of the LoRA
+//! named after a tensor of the model is added to it, times `alpha`. LoRAs are blended in order.
+//!
+//! The blended model is written out as SafeTensors, which `Rwkv7Hip::load` reads from disk.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use half::{bf16, f16};
+use itertools::Itertools;
+use memmap2::Mmap;
+use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+
+use crate::reload;
+
+fn decode(tensor: &TensorView) -> Result<Vec<f32>> {
+    let data = tensor.data();
+    Ok(match tensor.dtype() {
+        Dtype::F32 => data
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect(),
+        Dtype::F16 => data
+            .chunks_exact(2)
+            .map(|x| f16::from_le_bytes([x[0], x[1]]).to_f32())
+            .collect(),
+        Dtype::BF16 => data
+            .chunks_exact(2)
+            .map(|x| bf16::from_le_bytes([x[0], x[1]]).to_f32())
+            .collect(),
+        dtype => bail!("cannot blend a LoRA into a tensor of {dtype:?}"),
+    })
+}
+
+fn encode(data: &[f32], dtype: Dtype) -> Result<Vec<u8>> {
+    Ok(match dtype {
+        Dtype::F32 => data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        Dtype::F16 => data
+            .iter()
+            .flat_map(|&x| f16::from_f32(x).to_le_bytes())
+            .collect(),
+        Dtype::BF16 => data
+            .iter()
+            .flat_map(|&x| bf16::from_f32(x).to_le_bytes())
+            .collect(),
+        dtype => bail!("cannot blend a LoRA into a tensor of {dtype:?}"),
+    })
+}
+
+fn matrix_shape(name: &str, tensor: &TensorView) -> Result<[usize; 2]> {
+    match tensor.shape() {
+        &[rows, cols] => Ok([rows, cols]),
+        shape => bail!("{name} has shape {shape:?}, expected a matrix"),
+    }
+}
+
+/// `weight += scale * b a`, with `weight` of rows of `cols`, `b` of `[rows, rank]` and `a` of
+/// `[rank, cols]`, spread over the cores.
+fn add_low_rank(weight: &mut [f32], b: &[f32], a: &[f32], cols: usize, rank: usize, scale: f32) {
+    let threads = std::thread::available_parallelism().map_or(1, usize::from);
+    let rows = weight.len() / cols;
+    let chunk = rows.div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        for (index, rows) in weight.chunks_mut(chunk * cols).enumerate() {
+            scope.spawn(move || {
+                for (row, weight) in rows.chunks_mut(cols).enumerate() {
+                    let row = index * chunk + row;
+                    for r in 0..rank {
+                        let factor = scale * b[row * rank + r];
+                        let a = &a[r * cols..(r + 1) * cols];
+                        weight.iter_mut().zip(a).for_each(|(w, a)| *w += factor * a);
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// Blend `lora` into the tensors of `model`, keeping the blended ones in `blended`.
+fn blend(
+    model: &SafeTensors,
+    lora: &SafeTensors,
+    alpha: f32,
+    blended: &mut HashMap<String, Vec<f32>>,
+) -> Result<()> {
+    let mut fetch = |name: &str| -> Result<&mut Vec<f32>> {
+        if !blended.contains_key(name) {
+            let tensor = decode(&model.tensor(name)?)?;
+            blended.insert(name.to_owned(), tensor);
+        }
+        Ok(blended.get_mut(name).unwrap())
+    };
+
+    for name in lora.names().into_iter().sorted() {
+        if name.ends_with(".lora.1") {
+            continue;
+        }
+        if let Some(base) = name.strip_suffix(".lora.0") {
+            let target = format!("{base}.weight");
+            let Ok(weight) = model.tensor(&target) else {
+                bail!("LoRA matrix {base} has no {target} to blend into");
+            };
+            let a = lora.tensor(name)?;
+            let b = lora
+                .tensor(&format!("{base}.lora.1"))
+                .with_context(|| format!("LoRA matrix {base} has no {base}.lora.1"))?;
+            let [rows, cols] = matrix_shape(&target, &weight)?;
+            let [rank, a_cols] = matrix_shape(name, &a)?;
+            let [b_rows, b_rank] = matrix_shape(&format!("{base}.lora.1"), &b)?;
+            if a_cols != cols || b_rows != rows || b_rank != rank {
+                bail!(
+                    "LoRA matrix {base} of [{b_rows}, {b_rank}] x [{rank}, {a_cols}] \
+                     does not fit {target} of [{rows}, {cols}]"
+                );
+            }
+            let (a, b) = (decode(&a)?, decode(&b)?);
+            let weight = fetch(&target)?;
+            add_low_rank(weight, &b, &a, cols, rank, alpha / rank.max(1) as f32);
+            continue;
+        }
+
+        let Ok(tensor) = model.tensor(name) else {
+            tracing::warn!(event = "lora_tensor_skipped", tensor = %name);
+            continue;
+        };
+        let delta = lora.tensor(name)?;
+        if delta.shape() != tensor.shape() {
+            bail!(
+                "LoRA tensor {name} has shape {:?}, but the model's has {:?}",
+                delta.shape(),
+                tensor.shape()
+            );
+        }
+        let delta = decode(&delta)?;
+        let weight = fetch(name)?;
+        weight
+            .iter_mut()
+            .zip(delta)
+            .for_each(|(w, d)| *w += alpha * d);
+    }
+    Ok(())
+}
+
+/// The SafeTensors model at `model_path` with `loras` blended in, serialized.
+pub fn blend_model(model_path: &Path, loras: &[reload::Lora]) -> Result<Vec<u8>> {
+    let file = std::fs::File::open(model_path)?;
+    let data = unsafe { Mmap::map(&file)? };
+    let model = SafeTensors::deserialize(&data)
+        .context("LoRA on the HIP backend needs a SafeTensors model")?;
+
+    let mut blended = HashMap::new();
+    for lora in loras {
+        let file = std::fs::File::open(&lora.path)
+            .with_context(|| format!("failed to open LoRA {}", lora.path.display()))?;
+        let data = unsafe { Mmap::map(&file)? };
+        let tensors = SafeTensors::deserialize(&data)
+            .with_context(|| format!("LoRA {} is not SafeTensors", lora.path.display()))?;
+        blend(&model, &tensors, lora.alpha, &mut blended)
+            .with_context(|| format!("failed to blend LoRA {}", lora.path.display()))?;
+    }
+
+    let blended: HashMap<_, _> = blended
+        .into_iter()
+        .map(|(name, data)| -> Result<_> {
+            let dtype = model.tensor(&name)?.dtype();
+            Ok((name, encode(&data, dtype)?))
+        })
+        .try_collect()?;
+    let tensors: Vec<_> = model
+        .tensors()
+        .into_iter()
+        .map(|(name, tensor)| -> Result<_> {
+            let tensor = match blended.get(&name) {
+                Some(data) => TensorView::new(tensor.dtype(), tensor.shape().to_vec(), data)?,
+                None => tensor,
+            };
+            Ok((name, tensor))
+        })
+        .try_collect()?;
+    Ok(safetensors::serialize(tensors, None)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SafeTensors of f32 `tensors`, serialized.
+    fn serialize(tensors: &[(&str, Vec<usize>, Vec<f32>)]) -> Vec<u8> {
+        let data = tensors
+            .iter()
+            .map(|(_, _, data)| encode(data, Dtype::F32).unwrap())
+            .collect_vec();
+        let views = tensors.iter().zip(&data).map(|((name, shape, _), data)| {
+            let view = TensorView::new(Dtype::F32, shape.clone(), data).unwrap();
+            (name.to_string(), view)
+        });
+        safetensors::serialize(views, None).unwrap()
+    }
+
+    #[test]
+    fn test_add_low_rank() {
+        let mut weight = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        // B of [2, 2] and A of [2, 3], so that B A = A
+        let b = [1.0, 0.0, 0.0, 1.0];
+        let a = [1.0, 0.0, -1.0, 0.0, 1.0, 0.0];
+        // alpha 4 over rank 2
+        add_low_rank(&mut weight, &b, &a, 3, 2, 2.0);
+        assert_eq!(weight, [3.0, 2.0, 1.0, 4.0, 7.0, 6.0]);
+    }
+
+    #[test]
+    fn test_blend() {
+        let model = serialize(&[
+            (
+                "att.key.weight",
+                vec![2, 3],
+                vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0],
+            ),
+            ("att.x_k", vec![3], vec![0.5; 3]),
+        ]);
+        let model = SafeTensors::deserialize(&model).unwrap();
+        let lora = serialize(&[
+            ("att.key.lora.0", vec![1, 3], vec![1.0, 0.0, -1.0]),
+            ("att.key.lora.1", vec![2, 1], vec![1.0, 2.0]),
+            ("att.x_k", vec![3], vec![1.0, 2.0, 3.0]),
+        ]);
+        let lora = SafeTensors::deserialize(&lora).unwrap();
+
+        let mut blended = HashMap::new();
+        blend(&model, &lora, 2.0, &mut blended).unwrap();
+        assert_eq!(blended["att.key.weight"], [3.0, 2.0, 1.0, 8.0, 5.0, 2.0]);
+        assert_eq!(blended["att.x_k"], [2.5, 4.5, 6.5]);
+    }
+
+    #[test]
+    fn test_blend_shape_mismatch() {
+        let model = serialize(&[("att.key.weight", vec![2, 3], vec![0.0; 6])]);
+        let model = SafeTensors::deserialize(&model).unwrap();
+
+        // B has a row too many for the weight
+        let lora = serialize(&[
+            ("att.key.lora.0", vec![1, 3], vec![0.0; 3]),
+            ("att.key.lora.1", vec![3, 1], vec![0.0; 3]),
+        ]);
+        let lora = SafeTensors::deserialize(&lora).unwrap();
+        let err = blend(&model, &lora, 1.0, &mut HashMap::new()).unwrap_err();
+        assert!(err.to_string().contains("does not fit att.key.weight"));
+
+        // and a matrix without B cannot be blended at all
+        let lora = serialize(&[("att.key.lora.0", vec![1, 3], vec![0.0; 3])]);
+        let lora = SafeTensors::deserialize(&lora).unwrap();
+        assert!(blend(&model, &lora, 1.0, &mut HashMap::new()).is_err());
+    }
+}
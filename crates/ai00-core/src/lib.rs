@@ -1,7 +1,7 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{atomic::AtomicBool, Arc},
 };
 
 use anyhow::{bail, Result};
@@ -11,7 +11,7 @@ use futures::future::join_all;
 use half::f16;
 use itertools::Itertools;
 use memmap2::Mmap;
-use reload::{AdapterOption, Backend, BnfOption, Precision};
+use reload::{AdapterOption, Backend, BnfOption, FairnessOption, Precision, TrafficClass};
 use safetensors::SafeTensors;
 use salvo::oapi::ToSchema;
 use serde::{de::DeserializeSeed, Deserialize, Serialize};
@@ -22,25 +22,33 @@ use tokio::{
     time::Duration,
 };
 use web_rwkv::{
-    context::{Context, ContextBuilder, ContextError, InstanceExt},
+    context::Context,
     runtime::{
-        infer::Rnn,
+        infer::{Rnn, RnnInput, RnnInputBatch, RnnOption},
         loader::{Loader, Lora, LoraBlend, Reader},
-        model::{Bundle, ContextAutoLimits, ModelBuilder, ModelInfo, ModelVersion, Quant, State},
+        model::{Bundle, ModelBuilder, ModelInfo, ModelVersion, Quant, State},
         v4, v5, v6, v7, Runtime, TokioRuntime,
     },
-    tensor::{serialization::Seed, TensorCpu, TensorError, TensorInit},
+    tensor::{serialization::Seed, TensorCpu, TensorError, TensorInit, TensorShape},
     tokenizer::Tokenizer,
-    wgpu::{Backends, PowerPreference},
 };
 
-use crate::{run::GenerateContext, sampler::Sampler};
+use crate::{
+    run::GenerateContext,
+    sampler::{Sampler, SamplerAdjustment},
+};
 
+mod backend;
+#[cfg(feature = "hip")]
+pub mod hip_lora;
 #[cfg(feature = "hip")]
 pub mod hip_state;
+pub mod loader;
+pub mod preflight;
 pub mod reload;
 pub mod run;
 pub mod sampler;
+pub mod stats;
 
 pub const MAX_TOKENS: usize = usize::MAX;
 
@@ -51,9 +59,28 @@ pub enum Token {
     Stop(FinishReason, TokenCounter),
     Embed(Vec<f32>, [usize; 4]),
     Choose(Vec<f32>),
+    /// The generation failed; [`Token::Done`] follows without a [`Token::Stop`].
+    Error(GenerateError),
+    /// How the grammar constrained a sampled token, sent before the text of the token.
+    GrammarTrace(GrammarTrace),
     Done,
 }
 
+/// How the grammar of a request constrained one sampled token.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GrammarTrace {
+    /// The sampled token.
+    pub token: u32,
+    /// Text of the token.
+    pub text: String,
+    /// Number of tokens the grammar allowed.
+    pub allowed: usize,
+    /// Number of tokens the grammar masked.
+    pub masked: usize,
+    /// State of the grammar engine after accepting the token.
+    pub grammar_state: String,
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
 pub struct TokenCounter {
     #[serde(alias = "prompt_tokens")]
@@ -63,9 +90,39 @@ pub struct TokenCounter {
     #[serde(alias = "total_tokens")]
     pub total: usize,
     pub duration: Duration,
+    /// Prompt tokens prefilled and pinned in the cache as a prefix.
+    #[serde(default)]
+    pub cache_creation: usize,
+    /// Prompt tokens whose state was checked out of the cache instead of being prefilled.
+    #[serde(default)]
+    pub cache_read: usize,
+    /// Time waiting for a slot, after the request was queued.
+    #[serde(default)]
+    pub queue_wait: Duration,
+    /// Time prefilling the prompt tokens that missed the cache.
+    #[serde(default)]
+    pub prefill: Duration,
+    /// Time generating the output, after the prefill.
+    #[serde(default)]
+    pub decode: Duration,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, ToSchema)]
+/// Usage of consecutive generations, e.g. the rounds of a tool loop.
+impl std::ops::AddAssign for TokenCounter {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt += other.prompt;
+        self.completion += other.completion;
+        self.total += other.total;
+        self.duration += other.duration;
+        self.cache_creation += other.cache_creation;
+        self.cache_read += other.cache_read;
+        self.queue_wait += other.queue_wait;
+        self.prefill += other.prefill;
+        self.decode += other.decode;
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, ToSchema)]
 #[serde(rename_all = "snake_case")]
 #[allow(dead_code)]
 pub enum FinishReason {
@@ -75,36 +132,176 @@ pub enum FinishReason {
     Length,
     /// Omitted content due to a flag from our content filters.
     ContentFilter,
+    /// Generation stopped at the wall-clock limit of the request.
+    Timeout,
+    /// Model output ended at the contained stop sequence. Serialized as `stop`.
+    #[serde(untagged, serialize_with = "serialize_stop_sequence")]
+    StopSequence(String),
     /// API response still in progress or incomplete.
     #[default]
     #[serde(untagged)]
     Null,
 }
 
+impl FinishReason {
+    /// The stop sequence that ended the output, if any.
+    pub fn stop_sequence(&self) -> Option<&str> {
+        match self {
+            FinishReason::StopSequence(stop) => Some(stop),
+            _ => None,
+        }
+    }
+}
+
+fn serialize_stop_sequence<S: serde::Serializer>(
+    _: &str,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("stop")
+}
+
 #[derive(Debug, Clone)]
 pub enum ThreadRequest {
     /// Acquire a list of current available adapters.
     Adapter(Sender<AdapterList>),
-    /// Get the current runtime info.
+    /// Get the runtime info of the default model.
     Info(Sender<RuntimeInfo>),
+    /// Get the runtime info of the model that serves requests for the given name, or `None` if
+    /// no model is registered under it. An empty name means the default model.
+    InfoOf {
+        model: String,
+        sender: Sender<Option<RuntimeInfo>>,
+    },
+    /// Get the runtime info of all loaded models.
+    Models(Sender<Vec<RuntimeInfo>>),
+    /// Get the live state of the slots and caches of all loaded models.
+    Runtime(Sender<Vec<RuntimeSnapshot>>),
     /// Request the runtime to complement a prompt.
     Generate {
         request: Box<GenerateRequest>,
         tokenizer: Arc<Tokenizer>,
         sender: Sender<Token>,
     },
+    /// Sample several independent completions of the same prompt.
+    /// The prompt is prefilled once and the decoding of each completion is spread across slots.
+    GenerateMany {
+        requests: Vec<(Box<GenerateRequest>, Sender<Token>)>,
+        tokenizer: Arc<Tokenizer>,
+    },
     /// Reload the runtime with custom config.
     Reload {
         request: Box<ReloadRequest>,
         sender: Option<Sender<bool>>,
     },
-    /// Unload the runtime.
-    Unload,
+    /// Unload the named model, or all models if `None`.
+    Unload { model: Option<String> },
+    /// Register a LoRA adapter of the named model, or the default one, as a model of its own.
+    /// Unload it like any other model.
+    LoadLoraAdapter {
+        model: Option<String>,
+        adapter: reload::LoraAdapter,
+        sender: Option<Sender<bool>>,
+    },
     /// Save the current model with config.
     Save {
         request: SaveRequest,
         sender: Sender<bool>,
     },
+    /// Put the prompts of the cache snapshot of a state back into the cache of the named model,
+    /// or the default one. Answers how many prompts were restored.
+    RestoreCache {
+        model: Option<String>,
+        state: StateId,
+        sender: Sender<Result<usize, String>>,
+    },
+    /// Get the progress of the latest model load.
+    LoadProgress(Sender<LoadProgress>),
+    /// Change sampler parameters of an in-flight generation.
+    AdjustSampler {
+        request_id: String,
+        adjustment: SamplerAdjustment,
+        sender: Sender<AdjustSamplerResult>,
+    },
+}
+
+/// Outcome of [`ThreadRequest::AdjustSampler`].
+#[derive(Debug, Clone)]
+pub enum AdjustSamplerResult {
+    /// The new parameters apply from the next sampled token.
+    Adjusted,
+    /// No in-flight generation has this request ID.
+    NotFound,
+    /// The sampler refused the adjustment.
+    Rejected(String),
+}
+
+/// Samplers of in-flight generations, keyed by request ID.
+///
+/// Only weak handles are kept, so entries die with their generation.
+#[derive(Default, Clone)]
+struct SamplerRegistry(
+    Arc<std::sync::Mutex<HashMap<String, std::sync::Weak<RwLock<dyn Sampler + Send + Sync>>>>>,
+);
+
+impl SamplerRegistry {
+    fn insert(&self, request_id: String, sampler: &Arc<RwLock<dyn Sampler + Send + Sync>>) {
+        let mut map = self.0.lock().unwrap();
+        map.retain(|_, sampler| sampler.strong_count() > 0);
+        map.insert(request_id, Arc::downgrade(sampler));
+    }
+
+    fn get(&self, request_id: &str) -> Option<Arc<RwLock<dyn Sampler + Send + Sync>>> {
+        let map = self.0.lock().unwrap();
+        map.get(request_id).and_then(|sampler| sampler.upgrade())
+    }
+}
+
+/// All loaded models, keyed by name.
+#[derive(Default)]
+struct Environments {
+    models: HashMap<String, Arc<RwLock<Environment>>>,
+    /// Model that serves requests naming no model.
+    default: Option<String>,
+}
+
+impl Environments {
+    /// Select the model by name, or the default model if no name is given.
+    /// An empty name also selects the default model; an unknown name selects nothing.
+    fn select(&self, name: Option<&str>) -> Option<Arc<RwLock<Environment>>> {
+        match name.filter(|name| !name.is_empty()) {
+            Some(name) => self.models.get(name),
+            None => self.default.as_ref().and_then(|name| self.models.get(name)),
+        }
+        .cloned()
+    }
+
+    /// Name a reload is registered under. An unnamed reload replaces the default model.
+    fn reload_name(&self, request: &ReloadRequest) -> String {
+        match (&request.name, &self.default) {
+            (Some(name), _) => name.clone(),
+            (None, Some(name)) => name.clone(),
+            (None, None) => request
+                .model_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into())
+                .unwrap_or_else(|| "default".into()),
+        }
+    }
+
+    fn entry(&mut self, name: &str) -> Arc<RwLock<Environment>> {
+        if self.default.is_none() {
+            self.default = Some(name.to_string());
+        }
+        self.models.entry(name.to_string()).or_default().clone()
+    }
+
+    fn remove(&mut self, name: &str) -> Option<Arc<RwLock<Environment>>> {
+        let env = self.models.remove(name);
+        if self.default.as_deref() == Some(name) {
+            self.default = self.models.keys().min().cloned();
+        }
+        env
+    }
 }
 
 #[derive(Default)]
@@ -113,9 +310,13 @@ pub enum Environment {
         info: RuntimeInfo,
         runtime: Arc<dyn Runtime<Rnn> + Send + Sync>,
         /// The serializable model handle.  `None` for backends that do not
-        /// support model serialization (e.g. HIP).
+        /// support model serialization.
         model: Option<Arc<dyn ModelSerialize + Send + Sync>>,
+        /// The WebGPU context of the model, to build its weights again for a save.
+        /// `None` for backends without one.
+        context: Option<Context>,
         sender: Sender<GenerateContext>,
+        monitor: run::RuntimeMonitor,
     },
     #[default]
     None,
@@ -124,10 +325,443 @@ pub enum Environment {
 #[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct RuntimeInfo {
+    /// Name that requests use to select this model.
+    pub name: String,
     pub reload: Arc<ReloadRequest>,
     pub info: ModelInfo,
     pub states: Vec<InitState>,
     pub tokenizer: Arc<Tokenizer>,
+    /// Results of the token chunk size benchmark, if it was run on load.
+    pub chunk_benchmark: Vec<ChunkBenchmark>,
+}
+
+impl RuntimeInfo {
+    /// Shape of the state of one batch, `[num_emb, head_size + 2, num_layer, 1]`.
+    /// `None` if the model version does not support init states.
+    pub fn state_shape(&self) -> Option<[usize; 4]> {
+        let info = &self.info;
+        let head_size = info.num_emb / info.num_head.max(1);
+        match info.version {
+            ModelVersion::V4 => None,
+            _ => Some([info.num_emb, head_size + 2, info.num_layer, 1]),
+        }
+    }
+}
+
+/// Candidate `token_chunk_size`s tried when tuning on load.
+const TOKEN_CHUNK_SIZE_CANDIDATES: [usize; 4] = [64, 128, 256, 512];
+/// Number of prompt tokens fed to the model for each candidate.
+const TOKEN_CHUNK_BENCHMARK_TOKENS: usize = 1024;
+
+/// Prefill throughput measured for one `token_chunk_size`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ChunkBenchmark {
+    pub token_chunk_size: usize,
+    pub tokens_per_second: f32,
+}
+
+/// Feed `tokens` through batch 0 of the runtime with the given chunk size.
+async fn prefill(
+    runtime: &(dyn Runtime<Rnn> + Send + Sync),
+    tokens: Vec<u32>,
+    max_batch: usize,
+    token_chunk_size: usize,
+) -> Result<()> {
+    let mut batches = vec![RnnInputBatch::default(); max_batch];
+    batches[0] = RnnInputBatch::new(tokens, RnnOption::Last);
+    let mut input = RnnInput::new(batches, token_chunk_size);
+    while input.num_token() > 0 {
+        let (next, _) = runtime.infer(input).await?;
+        input = next;
+    }
+    Ok(())
+}
+
+/// Measure prefill throughput of each candidate chunk size.
+///
+/// This clobbers the state of batch 0, which is fine since slots load their state
+/// before each generation.
+async fn benchmark_token_chunk_size(
+    runtime: &(dyn Runtime<Rnn> + Send + Sync),
+    info: &ModelInfo,
+    max_batch: usize,
+    tracker: &LoadTracker,
+) -> Result<Vec<ChunkBenchmark>> {
+    let tokens = (0..TOKEN_CHUNK_BENCHMARK_TOKENS)
+        .map(|index| (index % (info.num_vocab - 1) + 1) as u32)
+        .collect_vec();
+
+    tracker.phase(LoadPhase::Benchmark, TOKEN_CHUNK_SIZE_CANDIDATES.len());
+    let mut results = Vec::with_capacity(TOKEN_CHUNK_SIZE_CANDIDATES.len());
+    for token_chunk_size in TOKEN_CHUNK_SIZE_CANDIDATES {
+        // warm up so that shader compilation is not measured
+        let warmup = tokens[..token_chunk_size].to_vec();
+        prefill(runtime, warmup, max_batch, token_chunk_size).await?;
+
+        let start = std::time::Instant::now();
+        prefill(runtime, tokens.clone(), max_batch, token_chunk_size).await?;
+        let elapsed = start.elapsed().as_secs_f32().max(f32::EPSILON);
+
+        let result = ChunkBenchmark {
+            token_chunk_size,
+            tokens_per_second: tokens.len() as f32 / elapsed,
+        };
+        tracing::info!(
+            event = "chunk_benchmark",
+            token_chunk_size,
+            tokens_per_second = result.tokens_per_second,
+            "Token chunk size benchmarked"
+        );
+        results.push(result);
+        tracker.step();
+    }
+    Ok(results)
+}
+
+/// Phase of a model load.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadPhase {
+    /// No load has been requested yet.
+    #[default]
+    Idle,
+    /// Mapping the model file and reading its header.
+    Mmap,
+    /// Loading the tokenizer.
+    Tokenizer,
+    /// Creating the GPU context.
+    Context,
+    /// Loading initial states.
+    State,
+    /// Loading LoRA files.
+    Lora,
+    /// Quantizing and uploading weights to the device.
+    Quant,
+    /// Uploading weights to the device.
+    Upload,
+    /// Creating the runtime.
+    Runtime,
+    /// Benchmarking candidate token chunk sizes.
+    Benchmark,
+    /// Running warmup generations through every slot.
+    Warmup,
+    /// The model is loaded and ready.
+    Loaded,
+    /// The load failed.
+    Failed,
+}
+
+impl LoadPhase {
+    /// Overall fraction of the load that is done when entering this phase.
+    fn start(self) -> f32 {
+        match self {
+            LoadPhase::Idle | LoadPhase::Mmap => 0.0,
+            LoadPhase::Tokenizer => 0.02,
+            LoadPhase::Context => 0.04,
+            LoadPhase::State => 0.08,
+            LoadPhase::Lora => 0.15,
+            LoadPhase::Quant | LoadPhase::Upload => 0.2,
+            LoadPhase::Runtime => 0.95,
+            LoadPhase::Benchmark => 0.97,
+            LoadPhase::Warmup => 0.98,
+            LoadPhase::Loaded | LoadPhase::Failed => 1.0,
+        }
+    }
+
+    /// Overall fraction of the load that is done when leaving this phase.
+    fn end(self) -> f32 {
+        match self {
+            LoadPhase::Idle => 0.0,
+            LoadPhase::Mmap => LoadPhase::Tokenizer.start(),
+            LoadPhase::Tokenizer => LoadPhase::Context.start(),
+            LoadPhase::Context => LoadPhase::State.start(),
+            LoadPhase::State => LoadPhase::Lora.start(),
+            LoadPhase::Lora => LoadPhase::Upload.start(),
+            LoadPhase::Quant | LoadPhase::Upload => LoadPhase::Runtime.start(),
+            LoadPhase::Runtime => LoadPhase::Benchmark.start(),
+            LoadPhase::Benchmark => LoadPhase::Warmup.start(),
+            LoadPhase::Warmup | LoadPhase::Loaded | LoadPhase::Failed => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LoadProgress {
+    /// Path of the model being loaded.
+    #[salvo(schema(value_type = String))]
+    pub model_path: PathBuf,
+    /// Current phase.
+    pub phase: LoadPhase,
+    /// Overall progress in `[0, 1]`.
+    pub progress: f32,
+    /// Items (states, LoRA files, etc.) finished in the current phase.
+    pub current: usize,
+    /// Total items in the current phase.
+    pub total: usize,
+    /// Time elapsed since the load started.
+    pub elapsed: Duration,
+    /// Error message if the load failed.
+    pub error: Option<String>,
+    /// Reason of the failure, if the loader refused the model.
+    pub cause: Option<LoadError>,
+}
+
+/// Live state of the slots and caches of a loaded model.
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct RuntimeSnapshot {
+    /// Name that requests use to select this model.
+    pub name: String,
+    pub slots: Vec<SlotSnapshot>,
+    /// Prompt caches, one per state.
+    pub caches: Vec<CacheSnapshot>,
+    pub memory: MemoryEstimate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotStatus {
+    /// Free to take a request; keeps the state of its last one for reuse.
+    Idle,
+    /// Runs a request.
+    Busy,
+    /// Being assigned a request or released.
+    Locked,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SlotSnapshot {
+    pub index: usize,
+    pub status: SlotStatus,
+    /// Request the slot runs, if busy.
+    pub request_id: Option<String>,
+    /// Tokens in the state of the slot: processed so far if busy, kept for reuse if idle.
+    pub tokens: usize,
+    /// Time since the slot turned busy or idle.
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CacheSnapshot {
+    /// State the prompts are cached for; `None` for the default state.
+    pub state: Option<StateId>,
+    /// Number of cached prompts.
+    pub items: usize,
+    /// Number of cached prompts exempt from eviction.
+    pub pinned: usize,
+    /// Tokens of all cached prompts.
+    pub tokens: usize,
+    /// Estimated host memory of the cached states and outputs.
+    pub bytes: usize,
+}
+
+/// Estimated memory of the states a model keeps.
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct MemoryEstimate {
+    /// State of one slot.
+    pub state_bytes: usize,
+    /// States of all slots, on the device.
+    pub slot_bytes: usize,
+    /// States and outputs of all caches, in host memory.
+    pub cache_bytes: usize,
+}
+
+/// Reasons a model refuses to load.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LoadError {
+    /// The tokenizer and the model disagree on the vocabulary size.
+    VocabMismatch { tokenizer: usize, model: usize },
+    /// A buffer of the model is larger than the adapter allows, in bytes.
+    BufferTooLarge {
+        size: u64,
+        limit: u64,
+        suggestion: Option<LoadSuggestion>,
+    },
+    /// The model is estimated to take more device memory than `vram_budget`, in bytes.
+    InsufficientMemory {
+        required: u64,
+        budget: u64,
+        suggestion: Option<LoadSuggestion>,
+    },
+}
+
+/// Load options that are estimated to fit, for a model refused by the preflight check.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LoadSuggestion {
+    pub quant: usize,
+    #[salvo(schema(value_type = sealed::Quant))]
+    pub quant_type: Quant,
+    pub max_batch: usize,
+}
+
+impl std::fmt::Display for LoadSuggestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "try `quant = {}`, `quant_type = \"{:?}\"` and `max_batch = {}`",
+            self.quant, self.quant_type, self.max_batch
+        )
+    }
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::VocabMismatch { tokenizer, model } if tokenizer > model => write!(
+                f,
+                "tokenizer vocab ({tokenizer}) is larger than model vocab ({model}); \
+                 the tokenizer does not belong to this model"
+            ),
+            LoadError::VocabMismatch { tokenizer, model } => write!(
+                f,
+                "tokenizer vocab ({tokenizer}) is smaller than model vocab ({model}); \
+                 set `pad_vocab` to mask the padding logits"
+            ),
+            LoadError::BufferTooLarge {
+                size,
+                limit,
+                suggestion,
+            } => {
+                write!(
+                    f,
+                    "model needs a buffer of {size} bytes, over the adapter limit of {limit}"
+                )?;
+                match suggestion {
+                    Some(suggestion) => write!(f, "; {suggestion}"),
+                    None => Ok(()),
+                }
+            }
+            LoadError::InsufficientMemory {
+                required,
+                budget,
+                suggestion,
+            } => {
+                write!(
+                    f,
+                    "model is estimated to take {required} bytes of device memory, \
+                     over the budget of {budget}"
+                )?;
+                match suggestion {
+                    Some(suggestion) => write!(f, "; {suggestion}"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Reasons a generation fails after it started.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GenerateError {
+    /// The grammar or regex of the request allows no token after the output so far.
+    GrammarBlocked {
+        /// Tokens generated before the grammar blocked.
+        output_tokens: usize,
+        /// State of the grammar engine, with the nonterminals it was in the middle of.
+        grammar_state: String,
+        /// The tokens the model rated highest, all of which the grammar blocked.
+        blocked: Vec<BlockedToken>,
+    },
+}
+
+/// A token that the grammar of a request blocked.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BlockedToken {
+    pub token: u32,
+    pub text: String,
+    pub logit: f32,
+}
+
+impl std::fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GenerateError::GrammarBlocked {
+                output_tokens,
+                blocked,
+                ..
+            } => {
+                let blocked = blocked.iter().map(|token| format!("{:?}", token.text));
+                write!(
+                    f,
+                    "the grammar allows no token after {output_tokens} output tokens; \
+                     the model preferred {}",
+                    blocked.take(3).join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for GenerateError {}
+
+/// Shared handle that the reload task writes progress into.
+#[derive(Debug, Default, Clone)]
+struct LoadTracker(Arc<std::sync::RwLock<(LoadProgress, Option<std::time::Instant>)>>);
+
+impl LoadTracker {
+    fn update(&self, f: impl FnOnce(&mut LoadProgress)) {
+        let mut lock = self.0.write().unwrap();
+        let (progress, start) = &mut *lock;
+        f(progress);
+        progress.elapsed = start.map(|x| x.elapsed()).unwrap_or_default();
+    }
+
+    fn begin(&self, model_path: PathBuf) {
+        let mut lock = self.0.write().unwrap();
+        *lock = (
+            LoadProgress {
+                model_path,
+                phase: LoadPhase::Mmap,
+                ..Default::default()
+            },
+            Some(std::time::Instant::now()),
+        );
+    }
+
+    fn phase(&self, phase: LoadPhase, total: usize) {
+        tracing::debug!(event = "model_load_phase", phase = ?phase, total, "Model load phase");
+        self.update(|progress| {
+            progress.phase = phase;
+            progress.progress = phase.start();
+            progress.current = 0;
+            progress.total = total;
+        });
+    }
+
+    fn step(&self) {
+        self.update(|progress| {
+            let phase = progress.phase;
+            progress.current = (progress.current + 1).min(progress.total);
+            let ratio = match progress.total {
+                0 => 1.0,
+                total => progress.current as f32 / total as f32,
+            };
+            progress.progress = phase.start() + (phase.end() - phase.start()) * ratio;
+        });
+    }
+
+    fn fail(&self, err: &anyhow::Error) {
+        let error = err.to_string();
+        let cause = err.downcast_ref::<LoadError>().cloned();
+        self.update(|progress| {
+            progress.phase = LoadPhase::Failed;
+            progress.error = Some(error);
+            progress.cause = cause;
+        });
+    }
+
+    fn get(&self) -> LoadProgress {
+        let lock = self.0.read().unwrap();
+        let (progress, start) = &*lock;
+        let mut progress = progress.clone();
+        if !matches!(progress.phase, LoadPhase::Loaded | LoadPhase::Failed) {
+            progress.elapsed = start.map(|x| x.elapsed()).unwrap_or_default();
+        }
+        progress
+    }
 }
 
 struct Model<M>(M);
@@ -172,6 +806,8 @@ pub enum GenerateKind {
         choices: Vec<String>,
         calibrate: bool,
     },
+    /// Deterministic beam search instead of sampling. The output arrives in one piece.
+    Beam(sampler::beam::BeamParams),
 }
 
 #[derive(Clone, Derivative)]
@@ -179,16 +815,26 @@ pub enum GenerateKind {
 pub struct GenerateRequest {
     /// The prompt for the model.
     pub prompt: String,
+    /// Leading part of the prompt to prefill on its own and pin in the cache,
+    /// so that later prompts starting with it skip its prefill.
+    pub cache_prefix: Option<String>,
     /// All text the model output earlier.
     pub model_text: String,
     /// Output token limit.
     pub max_tokens: usize,
     /// Stop indicators.
     pub stop: Vec<String>,
+    /// Token IDs that stop the generation, besides the end of text (token 0).
+    /// The stop token itself is not part of the output.
+    pub stop_tokens: Vec<u32>,
+    /// Raw byte sequences that stop the generation, for stops that are not valid UTF-8.
+    pub stop_bytes: Vec<Vec<u8>>,
     /// Bias added to tokens before sampling.
     pub bias: Arc<HashMap<u32, f32>>,
     /// Optional BNF schema for formatted generation.
     pub bnf_schema: Option<String>,
+    /// Optional regular expression the whole output must match.
+    pub regex: Option<String>,
     /// Sampler parameters.
     #[derivative(
         Debug = "ignore",
@@ -203,12 +849,86 @@ pub struct GenerateRequest {
     pub request_id: Option<String>,
     /// Trace ID (from x-request-id header, for cross-service correlation).
     pub trace_id: Option<String>,
+    /// Name of the model to generate with. Falls back to the default model.
+    pub model: Option<String>,
+    /// Traffic class the decoded tokens are accounted to.
+    pub traffic_class: TrafficClass,
+    /// Wall-clock limit of the generation, counted from when it is queued.
+    /// The output so far is kept, and the state is cached as on a normal stop.
+    pub timeout: Option<Duration>,
+    /// Client the request is attributed to. Slots are shared fairly between clients
+    /// when requests wait for them; requests without one are grouped by their state.
+    pub client: Option<String>,
+    /// Seed of the sampler's random number generator. The same seed, prompt and sampler
+    /// parameters sample the same tokens. Seeded at random if not given.
+    pub seed: Option<u64>,
+    /// Phrases the output must not contain. Output that may be starting one is held back;
+    /// once it completes one, generation backtracks to the token the phrase started with and
+    /// samples again without that token. Ignored with a BNF schema or regex.
+    pub banned_phrases: Vec<String>,
+    /// Set by the caller to end the generation with [`FinishReason::ContentFilter`],
+    /// e.g. once moderation flags the output so far.
+    pub content_filter: Arc<AtomicBool>,
+    /// Every this many output tokens, the state is stored in the cache under the text so far,
+    /// so that long generations can be continued without prefilling them again. The state is
+    /// checked for drift at the same time, which ends the generation with
+    /// [`FinishReason::Length`].
+    pub checkpoint_interval: Option<usize>,
+    /// Root mean square of the state at a checkpoint above which it counts as drifted. States
+    /// holding NaN or infinity always do.
+    pub max_state_rms: Option<f32>,
+    /// Send a [`Token::GrammarTrace`] for every token sampled under the BNF schema or regex.
+    pub grammar_trace: bool,
+    /// Most output tokens generated per second. Decode steps of the generation are held back
+    /// to stay under it, leaving the time to the other slots. Unlimited if not set.
+    pub max_tokens_per_second: Option<f32>,
+    /// Span of the request the generation is for, which the spans of its queueing, prefill,
+    /// decode and cache operations are children of. They are left out if it is disabled.
+    #[derivative(Default(value = "tracing::Span::none()"))]
+    pub span: tracing::Span,
+    /// Called with the request and its final token counts once the generation stops, right
+    /// before [`Token::Stop`] is sent, e.g. to account its usage.
+    #[derivative(Debug = "ignore")]
+    pub on_stop: Vec<StopHook>,
+}
+
+/// A hook of [`GenerateRequest::on_stop`].
+pub type StopHook = Arc<dyn Fn(&GenerateRequest, &TokenCounter) + Send + Sync>;
+
+impl GenerateRequest {
+    /// Call the [`GenerateRequest::on_stop`] hooks with the final token counts.
+    pub fn stopped(&self, counter: &TokenCounter) {
+        self.on_stop.iter().for_each(|hook| hook(self, counter));
+    }
+
+    /// The least time between two decode steps under `max_tokens_per_second`, if it is set
+    /// and positive.
+    pub fn decode_interval(&self) -> Option<Duration> {
+        let rate = self.max_tokens_per_second?;
+        // no interval for rates of zero or less, whose inverses are infinite or negative
+        Duration::try_from_secs_f32(rate.recip()).ok()
+    }
+
+    /// All stop indicators as bytes, textual and raw ones alike.
+    pub fn stop_sequences(&self) -> impl Iterator<Item = &[u8]> {
+        let stop = self.stop.iter().map(String::as_bytes);
+        let stop_bytes = self.stop_bytes.iter().map(Vec::as_slice);
+        stop.chain(stop_bytes)
+    }
+
+    /// Check if the generation stops at `token`.
+    pub fn is_stop_token(&self, token: u32) -> bool {
+        token == 0 || self.stop_tokens.contains(&token)
+    }
 }
 
 #[derive(Debug, Derivative, Clone, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct ReloadRequest {
+    /// Name that requests use to select this model.
+    /// If not given, the model replaces the default model.
+    pub name: Option<String>,
     /// Path to the model.
     #[salvo(schema(value_type = String))]
     pub model_path: PathBuf,
@@ -226,9 +946,24 @@ pub struct ReloadRequest {
     /// Maximum tokens to be processed in parallel at once.
     #[derivative(Default(value = "128"))]
     pub token_chunk_size: usize,
+    /// Benchmark candidate chunk sizes on load and use the fastest instead of `token_chunk_size`.
+    pub tune_token_chunk_size: bool,
+    /// Most prompt tokens of a slot prefilled between decode steps of the other slots, so that
+    /// a long prompt delays them by at most a chunk. Prompts are prefilled at once if not set.
+    pub max_prefill_chunk_tokens: Option<usize>,
+    /// Bytes of device memory the model may take. Loads estimated to take more are refused
+    /// before any weight is uploaded, with load options that would fit.
+    pub vram_budget: Option<u64>,
+    /// Accept a tokenizer whose vocab is smaller than the model's, masking the padding logits.
+    /// If disabled, any vocab size mismatch fails the load.
+    #[derivative(Default(value = "true"))]
+    pub pad_vocab: bool,
     /// Number of states that are cached on GPU.
     #[derivative(Default(value = "8"))]
     pub max_batch: usize,
+    /// Read SafeTensors weights from disk one tensor at a time instead of mapping the whole file,
+    /// bounding host memory during the load by the largest tensor.
+    pub stream_load: bool,
     /// Path to the tokenizer.
     #[salvo(schema(value_type = String))]
     pub tokenizer_path: PathBuf,
@@ -236,7 +971,15 @@ pub struct ReloadRequest {
     pub bnf: BnfOption,
     /// Adapter selection.
     pub adapter: AdapterOption,
-    /// Backend to use for inference (`WebGpu` or `Hip`).
+    /// Decode throughput shares between traffic classes.
+    pub fairness: FairnessOption,
+    /// Generation run through every slot after loading. No warmup if not set.
+    pub warmup: Option<reload::Warmup>,
+    /// Snapshots of the prompt caches written to disk while idle. No snapshots if not set.
+    pub cache_snapshot: Option<reload::CacheSnapshotOption>,
+    /// Name of the model this one is an adapter of, if it was registered as one.
+    pub adapter_of: Option<String>,
+    /// Backend to use for inference (`WebGpu`, `Hip` or `Cpu`).
     #[serde(default)]
     pub backend: Backend,
 }
@@ -248,6 +991,29 @@ pub struct SaveRequest {
     #[serde(alias = "model_path")]
     #[salvo(schema(value_type = String))]
     pub path: PathBuf,
+    /// Name of the model to save. Defaults to the default model.
+    pub model: Option<String>,
+    /// Quantization to bake into the prefab, so that it loads without quantizing.
+    /// Defaults to that of the loaded model.
+    pub quant: Option<SaveQuant>,
+}
+
+/// Quantization of the layers of a saved prefab.
+///
+/// The weights are built again from the source of the model, which must be SafeTensors or GGUF,
+/// with its LoRAs, and take as much device memory as the loaded model while they are saved.
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct SaveQuant {
+    /// Quantization type (`Int8`, `NF4` or `SF4`).
+    #[derivative(Default(value = "Quant::Int8"))]
+    #[salvo(schema(value_type = sealed::Quant))]
+    pub quant_type: Quant,
+    /// First layer to quantize.
+    pub start: usize,
+    /// Layer after the last one to quantize. Defaults to the number of layers of the model.
+    pub end: Option<usize>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -258,6 +1024,7 @@ struct Prefab {
 #[derive(Debug, Clone, Copy)]
 enum LoadType {
     SafeTensors,
+    Gguf,
     Prefab,
 }
 
@@ -347,50 +1114,54 @@ impl TryFrom<StateValue> for InitState {
     }
 }
 
-async fn list_adapters() -> AdapterList {
-    let backends = Backends::all();
-    let instance = web_rwkv::wgpu::Instance::default();
-    #[allow(unused_mut)]
-    let mut list: Vec<String> = instance
-        .enumerate_adapters(backends)
-        .await
-        .into_iter()
-        .map(|adapter| adapter.get_info())
-        .map(|info| format!("{} ({:?})", info.name, info.backend))
-        .collect();
-
-    #[cfg(feature = "hip")]
-    {
-        if let Ok(count) = hip_rwkv::hip::get_device_count() {
-            for id in 0..count {
-                let name = hip_rwkv::hip::get_device_name(id)
-                    .unwrap_or_else(|_| format!("HIP Device {}", id));
-                list.push(format!("{} (HIP)", name));
-            }
+impl From<&InitState> for StateValue {
+    fn from(state: &InitState) -> Self {
+        Self {
+            name: state.name.clone(),
+            id: state.id,
+            data: state.data.to_vec(),
+            shape: state.data.shape().into(),
         }
     }
-
-    AdapterList(list)
 }
 
-async fn create_context(adapter: AdapterOption, info: &ModelInfo) -> Result<Context> {
-    let backends = Backends::all();
-    let instance = web_rwkv::wgpu::Instance::default();
-    let adapter = match adapter {
-        AdapterOption::Auto => instance.adapter(PowerPreference::HighPerformance).await,
-        AdapterOption::Economical => instance.adapter(PowerPreference::LowPower).await,
-        AdapterOption::Manual(selection) => Ok(instance
-            .enumerate_adapters(backends)
-            .await
-            .into_iter()
-            .nth(selection)
-            .ok_or(ContextError::RequestAdapterFailed)?),
-    }?;
-    let context = ContextBuilder::new(adapter)
-        .auto_limits(info)
-        .build()
-        .await?;
-    Ok(context)
+impl StateValue {
+    /// Weighted sum of states of the same shape, e.g. `0.7 * persona_a + 0.3 * persona_b`.
+    pub fn blend(name: String, id: StateId, states: &[(&StateValue, f32)]) -> Result<Self> {
+        let Some(&(first, _)) = states.first() else {
+            bail!("no state to blend");
+        };
+        let shape = first.shape;
+        let len = shape.iter().product::<usize>();
+        let mut data = vec![0.0f32; len];
+        for (state, weight) in states {
+            if state.shape != shape {
+                bail!(
+                    "state {} has shape {:?}, expected {:?}",
+                    state.name,
+                    state.shape,
+                    shape
+                );
+            }
+            if state.data.len() != len {
+                bail!(
+                    "state {} has {} values, expected {}",
+                    state.name,
+                    state.data.len(),
+                    len
+                );
+            }
+            for (x, y) in data.iter_mut().zip(state.data.iter()) {
+                *x += weight * y;
+            }
+        }
+        Ok(Self {
+            name,
+            id,
+            data,
+            shape,
+        })
+    }
 }
 
 async fn load_tokenizer(path: impl AsRef<Path>) -> Result<Tokenizer> {
@@ -401,6 +1172,29 @@ async fn load_tokenizer(path: impl AsRef<Path>) -> Result<Tokenizer> {
     Ok(Tokenizer::new(&contents)?)
 }
 
+/// Make sure every token the tokenizer produces has a logit, and vice versa unless padding is allowed.
+fn check_vocab(tokenizer: &Tokenizer, info: &ModelInfo, pad_vocab: bool) -> Result<()> {
+    let num_token = tokenizer.token_index_to_bytes().len();
+    let num_vocab = info.num_vocab;
+    match num_token.cmp(&num_vocab) {
+        std::cmp::Ordering::Equal => Ok(()),
+        std::cmp::Ordering::Less if pad_vocab => {
+            tracing::warn!(
+                event = "vocab_padded",
+                tokenizer_vocab = num_token,
+                model_vocab = num_vocab,
+                "Tokenizer vocab is smaller than model vocab; padding logits are masked"
+            );
+            Ok(())
+        }
+        _ => Err(LoadError::VocabMismatch {
+            tokenizer: num_token,
+            model: num_vocab,
+        }
+        .into()),
+    }
+}
+
 async fn load_model_state<R: Reader>(
     context: &Context,
     info: &ModelInfo,
@@ -419,6 +1213,7 @@ async fn load_runtime(
     info: &ModelInfo,
     request: &ReloadRequest,
     load: LoadType,
+    tracker: &LoadTracker,
 ) -> Result<(
     Vec<InitState>,
     Arc<dyn Runtime<Rnn> + Send + Sync>,
@@ -436,8 +1231,10 @@ async fn load_runtime(
         ..
     } = request.clone();
 
+    tracker.phase(LoadPhase::State, state.len());
     let mut states = Vec::with_capacity(state.len());
     for state in state.into_iter() {
+        tracker.step();
         let reload::State {
             path,
             name,
@@ -483,82 +1280,29 @@ async fn load_runtime(
         }
     }
 
+    if matches!(load, LoadType::SafeTensors) && request.stream_load {
+        let read = || loader::stream::StreamedSafeTensors::open(&model_path);
+        return load_reader_runtime(context, info, request, read, states, tracker).await;
+    }
+
     let file = File::open(model_path).await?;
     let data = unsafe { Mmap::map(&file) }?;
 
     match load {
         LoadType::SafeTensors => {
-            let model = SafeTensors::deserialize(&data)?;
-            if let Ok(data) = load_model_state(context, info, model).await {
-                let name = "internal".into();
-                let id = StateId::new();
-                let state = InitState {
-                    name,
-                    id,
-                    data,
-                    default: true,
-                };
-                states.push(state);
-            }
-
-            let model = SafeTensors::deserialize(&data)?;
-            let quant = (0..quant).map(|layer| (layer, quant_type)).collect();
-            let lora: Vec<Result<_>> = join_all(lora.iter().map(|lora| async move {
-                let reload::Lora { path, alpha } = lora;
-                let file = File::open(path).await?;
-                let data = unsafe { Mmap::map(&file)? };
-                let blend = LoraBlend::full(*alpha);
-                Ok((data, blend))
-            }))
-            .await;
-            let lora: Vec<_> = lora.into_iter().try_collect()?;
-            let lora: Vec<_> = lora
-                .iter()
-                .map(|(data, blend)| -> Result<_> {
-                    let data = SafeTensors::deserialize(data)?;
-                    let blend = blend.clone();
-                    Ok(Lora { data, blend })
-                })
-                .try_collect()?;
-
-            let builder = ModelBuilder::new(context, model).quant(quant);
-            let builder = lora.into_iter().fold(builder, |builder, x| builder.lora(x));
-
-            macro_rules! match_safe_tensors {
-                (($v:expr, $p:expr), { $(($version:path, $precision:path, $model:ty, $build:ident, $bundle:ty)),+ }) => {
-                    match ($v, $p) {
-                        $(
-                            ($version, $precision) => {
-                                let model = builder.$build().await?;
-                                let bundle = <$bundle>::new(model, max_batch);
-                                let state = Arc::new(bundle.state());
-                                let model = Arc::new(Model(bundle.model()));
-                                let runtime = Arc::new(TokioRuntime::<Rnn>::new(bundle).await);
-                                Ok((states, runtime, state, model))
-                            }
-                        )+
-                    }
-                }
-            }
-            match_safe_tensors!(
-                (info.version, precision),
-                {
-                    (ModelVersion::V4, Precision::Fp16, v4::Model, build_v4, v4::Bundle::<f16>),
-                    (ModelVersion::V5, Precision::Fp16, v5::Model, build_v5, v5::Bundle::<f16>),
-                    (ModelVersion::V6, Precision::Fp16, v6::Model, build_v6, v6::Bundle::<f16>),
-                    (ModelVersion::V7, Precision::Fp16, v7::Model, build_v7, v7::Bundle::<f16>),
-                    (ModelVersion::V4, Precision::Fp32, v4::Model, build_v4, v4::Bundle::<f32>),
-                    (ModelVersion::V5, Precision::Fp32, v5::Model, build_v5, v5::Bundle::<f32>),
-                    (ModelVersion::V6, Precision::Fp32, v6::Model, build_v6, v6::Bundle::<f32>),
-                    (ModelVersion::V7, Precision::Fp32, v7::Model, build_v7, v7::Bundle::<f32>)
-                }
-            )
+            let read = || Ok(SafeTensors::deserialize(&data)?);
+            load_reader_runtime(context, info, request, read, states, tracker).await
+        }
+        LoadType::Gguf => {
+            let read = || loader::gguf::Gguf::parse(&data);
+            load_reader_runtime(context, info, request, read, states, tracker).await
         }
         LoadType::Prefab => {
             use cbor4ii::{core::utils::SliceReader, serde::Deserializer};
 
             let reader = SliceReader::new(&data);
             let mut deserializer = Deserializer::new(reader);
+            tracker.phase(LoadPhase::Upload, 0);
 
             macro_rules! match_prefab {
                 (($v:expr, $p:expr), { $(($version:path, $precision:path, $model:ty, $bundle:ty)),+ }) => {
@@ -567,6 +1311,7 @@ async fn load_runtime(
                             ($version, $precision) => {
                                 let seed: Seed<_, $model> = Seed::new(context);
                                 let model = seed.deserialize(&mut deserializer)?;
+                                tracker.phase(LoadPhase::Runtime, 0);
                                 let bundle = <$bundle>::new(model, max_batch);
                                 let state = Arc::new(bundle.state());
                                 let model = Arc::new(Model(bundle.model()));
@@ -594,6 +1339,164 @@ async fn load_runtime(
     }
 }
 
+/// Map the LoRAs of a load, a step of `tracker` each.
+async fn open_loras(
+    lora: &[reload::Lora],
+    tracker: &LoadTracker,
+) -> Result<Vec<(Mmap, LoraBlend)>> {
+    let lora: Vec<Result<_>> = join_all(lora.iter().map(|lora| async move {
+        let reload::Lora { path, alpha } = lora;
+        let file = File::open(path).await?;
+        let data = unsafe { Mmap::map(&file)? };
+        let blend = LoraBlend::full(*alpha);
+        tracker.step();
+        Ok((data, blend))
+    }))
+    .await;
+    lora.into_iter().try_collect()
+}
+
+/// Add the LoRAs that [`open_loras`] mapped to the weights `builder` builds.
+fn blend_loras<R: Reader>(
+    builder: ModelBuilder<R>,
+    lora: &[(Mmap, LoraBlend)],
+) -> Result<ModelBuilder<R>> {
+    lora.iter().try_fold(builder, |builder, (data, blend)| {
+        let data = SafeTensors::deserialize(data)?;
+        let blend = blend.clone();
+        Ok(builder.lora(Lora { data, blend }))
+    })
+}
+
+/// Build the weights of `model` again with LoRAs of `request` and the layers of `quant`.
+async fn build_quantized<R: Reader>(
+    context: &Context,
+    info: &ModelInfo,
+    request: &ReloadRequest,
+    model: R,
+    quant: HashMap<usize, Quant>,
+) -> Result<Arc<dyn ModelSerialize + Send + Sync>> {
+    let lora = open_loras(&request.lora, &LoadTracker::default()).await?;
+    let builder = ModelBuilder::new(context, model).quant(quant);
+    let builder = blend_loras(builder, &lora)?;
+    let model: Arc<dyn ModelSerialize + Send + Sync> = match info.version {
+        ModelVersion::V4 => Arc::new(Model(builder.build_v4().await?)),
+        ModelVersion::V5 => Arc::new(Model(builder.build_v5().await?)),
+        ModelVersion::V6 => Arc::new(Model(builder.build_v6().await?)),
+        ModelVersion::V7 => Arc::new(Model(builder.build_v7().await?)),
+    };
+    Ok(model)
+}
+
+/// Build the weights of a loaded model again from its source with `quant` baked in, for a
+/// prefab that loads without quantizing.
+async fn quantize_model(
+    context: &Context,
+    info: &ModelInfo,
+    request: &ReloadRequest,
+    quant: &SaveQuant,
+) -> Result<Arc<dyn ModelSerialize + Send + Sync>> {
+    let start = quant.start;
+    let end = quant.end.unwrap_or(info.num_layer).min(info.num_layer);
+    if start >= end {
+        bail!("no layer in {start}..{end} to quantize");
+    }
+    let layers = (start..end)
+        .map(|layer| (layer, quant.quant_type))
+        .collect();
+
+    let file = File::open(&request.model_path).await?;
+    let data = unsafe { Mmap::map(&file)? };
+    if loader::gguf::is_gguf(&data) {
+        let model = loader::gguf::Gguf::parse(&data)?;
+        return build_quantized(context, info, request, model, layers).await;
+    }
+    match SafeTensors::deserialize(&data) {
+        Ok(model) => build_quantized(context, info, request, model, layers).await,
+        Err(_) => bail!("baking quantization into a prefab needs a SafeTensors or GGUF source"),
+    }
+}
+
+/// Build the runtime from a model that `read` opens, blending LoRAs and quantizing on the way.
+/// The model is opened twice, once for its internal state and once for its weights.
+async fn load_reader_runtime<R: Reader>(
+    context: &Context,
+    info: &ModelInfo,
+    request: &ReloadRequest,
+    read: impl Fn() -> Result<R>,
+    mut states: Vec<InitState>,
+    tracker: &LoadTracker,
+) -> Result<(
+    Vec<InitState>,
+    Arc<dyn Runtime<Rnn> + Send + Sync>,
+    Arc<dyn State + Send + Sync>,
+    Arc<dyn ModelSerialize + Send + Sync>,
+)> {
+    let ReloadRequest {
+        lora,
+        quant,
+        quant_type,
+        precision,
+        max_batch,
+        ..
+    } = request.clone();
+
+    if let Ok(data) = load_model_state(context, info, read()?).await {
+        let name = "internal".into();
+        let id = StateId::new();
+        let state = InitState {
+            name,
+            id,
+            data,
+            default: true,
+        };
+        states.push(state);
+    }
+
+    let model = read()?;
+    let quant = (0..quant).map(|layer| (layer, quant_type)).collect();
+    tracker.phase(LoadPhase::Lora, lora.len());
+    let lora = open_loras(&lora, tracker).await?;
+    let builder = ModelBuilder::new(context, model).quant(quant);
+    let builder = blend_loras(builder, &lora)?;
+
+    match request.quant {
+        0 => tracker.phase(LoadPhase::Upload, 0),
+        _ => tracker.phase(LoadPhase::Quant, 0),
+    }
+
+    macro_rules! match_safe_tensors {
+        (($v:expr, $p:expr), { $(($version:path, $precision:path, $model:ty, $build:ident, $bundle:ty)),+ }) => {
+            match ($v, $p) {
+                $(
+                    ($version, $precision) => {
+                        let model = builder.$build().await?;
+                        tracker.phase(LoadPhase::Runtime, 0);
+                        let bundle = <$bundle>::new(model, max_batch);
+                        let state = Arc::new(bundle.state());
+                        let model = Arc::new(Model(bundle.model()));
+                        let runtime = Arc::new(TokioRuntime::<Rnn>::new(bundle).await);
+                        Ok((states, runtime, state, model))
+                    }
+                )+
+            }
+        }
+    }
+    match_safe_tensors!(
+        (info.version, precision),
+        {
+            (ModelVersion::V4, Precision::Fp16, v4::Model, build_v4, v4::Bundle::<f16>),
+            (ModelVersion::V5, Precision::Fp16, v5::Model, build_v5, v5::Bundle::<f16>),
+            (ModelVersion::V6, Precision::Fp16, v6::Model, build_v6, v6::Bundle::<f16>),
+            (ModelVersion::V7, Precision::Fp16, v7::Model, build_v7, v7::Bundle::<f16>),
+            (ModelVersion::V4, Precision::Fp32, v4::Model, build_v4, v4::Bundle::<f32>),
+            (ModelVersion::V5, Precision::Fp32, v5::Model, build_v5, v5::Bundle::<f32>),
+            (ModelVersion::V6, Precision::Fp32, v6::Model, build_v6, v6::Bundle::<f32>),
+            (ModelVersion::V7, Precision::Fp32, v7::Model, build_v7, v7::Bundle::<f32>)
+        }
+    )
+}
+
 /// Convert HIP model info into the shared `ModelInfo` type.
 ///
 /// This constructs a `ModelInfo` from `Rwkv7ModelInfo` and `LoraDims` so that
@@ -628,91 +1531,358 @@ pub fn hip_to_model_info(
     }
 }
 
-/// Load an RWKV model using the HIP backend (AMD GPU via ROCm).
-///
-/// Only supports V7 models. Loads the model weights into HIP device memory
-/// via `Rwkv7Hip::load`, then creates a `HipRuntime` for inference and a
-/// `HipStateAdapter` for state management.
-///
-/// Does not return a serializable model handle because HIP models cannot be
-/// saved to CBOR prefab format.  The caller should set `model = None` in
-/// `Environment::Loaded`.
-#[cfg(feature = "hip")]
-async fn load_runtime_hip(
-    info: &ModelInfo,
-    request: &ReloadRequest,
-) -> Result<(
-    Vec<InitState>,
-    Arc<dyn Runtime<Rnn> + Send + Sync>,
-    Arc<dyn State + Send + Sync>,
-)> {
-    use web_rwkv::runtime::model::ModelVersion;
+/// Run the warmup generation in every slot of a freshly loaded runtime and wait for all of them,
+/// but no longer than the timeout of the warmup.
+async fn run_warmup(
+    sender: &Sender<GenerateContext>,
+    info: &RuntimeInfo,
+    warmup: &reload::Warmup,
+) -> Result<()> {
+    let timeout = Duration::from_secs(warmup.timeout_secs);
+    let mut receivers = Vec::with_capacity(info.reload.max_batch);
+    for _ in 0..info.reload.max_batch {
+        let request = GenerateRequest {
+            prompt: warmup.prompt.clone(),
+            max_tokens: warmup.tokens,
+            model: Some(info.name.clone()),
+            request_id: Some(format!("warmup-{}", uuid::Uuid::new_v4())),
+            timeout: Some(timeout),
+            ..Default::default()
+        };
+        let (token_sender, token_receiver) = flume::unbounded();
+        let context = GenerateContext::new(request, token_sender, &info.tokenizer).await?;
+        sender
+            .send_async(context)
+            .await
+            .map_err(|_| anyhow::anyhow!("runtime stopped before warmup"))?;
+        receivers.push(token_receiver);
+    }
+    let wait = async {
+        for receiver in receivers {
+            while let Ok(token) = receiver.recv_async().await {
+                if matches!(token, Token::Stop(..) | Token::Done) {
+                    break;
+                }
+            }
+        }
+    };
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| anyhow::anyhow!("warmup did not finish within {}s", warmup.timeout_secs))
+}
 
-    if info.version != ModelVersion::V7 {
-        bail!(
-            "HIP backend only supports RWKV v7 models, got {:?}",
-            info.version
+/// The reload request of an adapter of the named model, or the default one, with `lora` blended
+/// on top of those of the model. The tokenizer, states and tuned chunk size are kept as loaded.
+async fn adapter_request(
+    envs: &RwLock<Environments>,
+    model: Option<&str>,
+    lora: Vec<reload::Lora>,
+) -> Option<Box<ReloadRequest>> {
+    let env = envs.read().await.select(model)?;
+    let env = env.read().await;
+    let Environment::Loaded { info, .. } = &*env else {
+        tracing::warn!("[lora] no model loaded");
+        return None;
+    };
+    let mut request = ReloadRequest::clone(&info.reload);
+    request.name = Some(info.name.clone());
+    request.tune_token_chunk_size = false;
+    request.lora.extend(lora);
+    tracing::info!(
+        event = "lora_adapter",
+        name = %info.name,
+        lora = ?request.lora.iter().map(|x| x.path.display().to_string()).collect_vec(),
+        "Loading LoRA adapter"
+    );
+    Some(Box::new(request))
+}
+
+/// Load the model of `request`, replacing the one registered under the same name.
+async fn reload(
+    envs: Arc<RwLock<Environments>>,
+    tracker: LoadTracker,
+    mut request: Box<ReloadRequest>,
+    sender: Option<Sender<bool>>,
+) -> Result<()> {
+    let name = envs.read().await.reload_name(&request);
+    let env = envs.write().await.entry(&name);
+    tracker.begin(request.model_path.clone());
+    let task_tracker = tracker.clone();
+    let handle = tokio::spawn(async move {
+        let tracker = task_tracker;
+        let load_start = std::time::Instant::now();
+        let file = File::open(&request.model_path).await?;
+        let data = unsafe { Mmap::map(&file)? };
+        let (info, load) = if loader::gguf::is_gguf(&data) {
+            let model = loader::gguf::Gguf::parse(&data)?;
+            tracing::info!(
+                event = "gguf_header",
+                architecture = ?model.architecture,
+                "GGUF header parsed"
+            );
+            (Loader::info(&model)?, LoadType::Gguf)
+        } else {
+            let st = SafeTensors::deserialize(&data);
+            let prefab = cbor4ii::serde::from_slice::<Prefab>(&data);
+            match (st, prefab) {
+                (Ok(model), _) => (Loader::info(&model)?, LoadType::SafeTensors),
+                (_, Ok(prefab)) => (prefab.info, LoadType::Prefab),
+                _ => bail!("failed to read model info"),
+            }
+        };
+        tracing::info!(
+            event = "model_load",
+            name = %name,
+            path = %request.model_path.display(),
+            tokenizer_path = %request.tokenizer_path.display(),
+            batch_size = request.max_batch,
+            chunk_size = request.token_chunk_size,
+            quant_type = ?request.quant_type,
+            precision = ?request.precision,
+            "Loading model"
+        );
+        tracing::info!(
+            event = "model_metadata",
+            version = ?info.version,
+            layers = info.num_layer,
+            embed_size = info.num_emb,
+            hidden_size = info.num_hidden,
+            vocab_size = info.num_vocab,
+            heads = info.num_head,
+            "Model metadata"
+        );
+        tracing::info!(
+            event = "model_format",
+            format = ?load,
+            "Model format detected"
         );
-    }
 
-    let model_path = request.model_path.clone();
-    let token_chunk_size = request.token_chunk_size;
-    let max_batch = request.max_batch;
+        tracing::info!(event = "env_lock", "Acquiring env write lock...");
+        let mut env = env.write().await;
+        tracing::info!(
+            event = "env_lock_acquired",
+            "Env write lock acquired, clearing env..."
+        );
+        let _ = std::mem::take(&mut *env);
 
-    // Load model weights on a blocking thread (file I/O + GPU upload)
-    log::info!("[hip] loading model weights from {:?}...", model_path);
-    let hip_model = tokio::task::spawn_blocking(move || {
-        log::info!("[hip] spawn_blocking: calling Rwkv7Hip::load...");
-        let result = hip_rwkv::hip::Rwkv7Hip::load(&model_path);
-        log::info!(
-            "[hip] spawn_blocking: Rwkv7Hip::load returned {:?}",
-            result.is_ok()
+        tracker.phase(LoadPhase::Tokenizer, 0);
+        tracing::info!(
+            event = "tokenizer_load",
+            path = %request.tokenizer_path.display(),
+            "Loading tokenizer"
         );
-        result
-    })
-    .await?
-    .map_err(|e| anyhow::anyhow!("HIP model load failed: {}", e))?;
-
-    log::info!("[hip] model loaded, creating runtime...");
-    // Create runtime with configuration matching the request
-    let config = hip_rwkv::hip::HipRuntimeConfig::new(token_chunk_size, max_batch);
-    let hip_runtime = hip_rwkv::hip::HipRuntime::with_config(hip_model, config)
-        .map_err(|e| anyhow::anyhow!("HIP runtime init failed: {}", e))?;
-    log::info!("[hip] runtime created successfully");
-
-    let runtime = Arc::new(hip_runtime);
-    let state: Arc<dyn State + Send + Sync> =
-        Arc::new(hip_state::HipStateAdapter::new(runtime.clone(), max_batch));
-
-    // HIP path does not support loading initial states from SafeTensors files
-    // (that requires a wgpu Context). Return empty states list.
-    let states = Vec::new();
-
-    log::info!(
-        "HIP runtime created: max_batch={}, chunk_size={}",
-        max_batch,
-        token_chunk_size
-    );
+        let tokenizer = Arc::new(load_tokenizer(&request.tokenizer_path).await?);
+        check_vocab(&tokenizer, &info, request.pad_vocab)?;
+        tracing::info!(
+            event = "backend_dispatch",
+            backend = ?request.backend,
+            "Dispatching to backend"
+        );
+
+        let mut backend = backend::select(request.backend)?;
+        tracker.phase(LoadPhase::Context, 0);
+        backend.create_context(&name, &info, &request).await?;
+        let backend::LoadedRuntime {
+            states,
+            runtime,
+            state,
+            model,
+        } = backend
+            .load_runtime(&info, &request, load, &tracker)
+            .await?;
+        let context = backend.context();
+
+        let chunk_benchmark = match request.tune_token_chunk_size {
+            true => {
+                let results = benchmark_token_chunk_size(
+                    runtime.as_ref(),
+                    &info,
+                    request.max_batch,
+                    &tracker,
+                )
+                .await?;
+                if let Some(best) = results
+                    .iter()
+                    .max_by(|x, y| x.tokens_per_second.total_cmp(&y.tokens_per_second))
+                {
+                    tracing::info!(
+                        event = "token_chunk_size_tuned",
+                        token_chunk_size = best.token_chunk_size,
+                        previous = request.token_chunk_size,
+                        "Token chunk size tuned"
+                    );
+                    request.token_chunk_size = best.token_chunk_size;
+                }
+                results
+            }
+            false => vec![],
+        };
+
+        let reload = Arc::new(*request);
+        let info = RuntimeInfo {
+            name,
+            reload,
+            info,
+            states,
+            tokenizer,
+            chunk_benchmark,
+        };
+
+        let (sender, monitor) = {
+            let runtime = Arc::downgrade(&runtime);
+            let (sender, receiver) = flume::unbounded();
+            let monitor = crate::run::run(
+                backend.into(),
+                runtime,
+                state,
+                receiver,
+                sender.downgrade(),
+                info.clone(),
+            )
+            .await;
+            (sender, monitor)
+        };
 
-    Ok((states, runtime, state))
+        let warmup_ms = match &info.reload.warmup {
+            Some(warmup) => {
+                tracker.phase(LoadPhase::Warmup, 0);
+                let start = std::time::Instant::now();
+                run_warmup(&sender, &info, warmup).await?;
+                Some(start.elapsed().as_millis() as u64)
+            }
+            None => None,
+        };
+
+        tracing::info!(
+            event = "model_loaded",
+            load_ms = load_start.elapsed().as_millis() as u64,
+            warmup_ms = ?warmup_ms,
+            "Model loaded successfully"
+        );
+        tracker.update(|progress| {
+            progress.phase = LoadPhase::Loaded;
+            progress.progress = 1.0;
+        });
+
+        let _ = std::mem::replace(
+            &mut *env,
+            Environment::Loaded {
+                info,
+                runtime,
+                model,
+                context,
+                sender,
+                monitor,
+            },
+        );
+        Ok(())
+    });
+
+    if let Some(sender) = sender {
+        let _ = match handle.await? {
+            Ok(_) => sender.send(true),
+            Err(err) => {
+                tracing::error!(
+                    event = "model_load_failed",
+                    error = %err,
+                    "Model reload failed"
+                );
+                tracker.fail(&err);
+                sender.send(false)
+            }
+        };
+    } else {
+        // Fire-and-forget initial load: log errors from the background task
+        tokio::spawn(async move {
+            match handle.await {
+                Ok(Ok(())) => {
+                    tracing::info!("[reload] background load completed successfully")
+                }
+                Ok(Err(err)) => {
+                    tracing::error!("[reload] background load FAILED: {err:#?}");
+                    tracker.fail(&err);
+                }
+                Err(join_err) => {
+                    tracing::error!("[reload] background task panicked: {join_err:#?}");
+                    tracker.fail(&join_err.into());
+                }
+            }
+        });
+    }
+    Ok(())
 }
 
-async fn process(env: Arc<RwLock<Environment>>, request: ThreadRequest) -> Result<()> {
+async fn process(
+    envs: Arc<RwLock<Environments>>,
+    tracker: LoadTracker,
+    samplers: SamplerRegistry,
+    request: ThreadRequest,
+) -> Result<()> {
     match request {
         ThreadRequest::Adapter(sender) => {
-            let _ = sender.send(list_adapters().await);
+            let _ = sender.send(backend::list_adapters().await);
         }
         ThreadRequest::Info(sender) => {
+            let Some(env) = envs.read().await.select(None) else {
+                return Ok(());
+            };
             let env = env.read().await;
             if let Environment::Loaded { info, .. } = &*env {
                 let _ = sender.send(info.clone());
             }
         }
+        ThreadRequest::InfoOf { model, sender } => {
+            let Some(env) = envs.read().await.select(Some(&model)) else {
+                let _ = sender.send(None);
+                return Ok(());
+            };
+            let env = env.read().await;
+            if let Environment::Loaded { info, .. } = &*env {
+                let _ = sender.send(Some(info.clone()));
+            }
+        }
+        ThreadRequest::Models(sender) => {
+            let models = envs.read().await.models.values().cloned().collect_vec();
+            let mut infos = Vec::with_capacity(models.len());
+            for env in models {
+                // skip models that are still loading
+                if let Ok(env) = env.try_read() {
+                    if let Environment::Loaded { info, .. } = &*env {
+                        infos.push(info.clone());
+                    }
+                }
+            }
+            infos.sort_by(|x, y| x.name.cmp(&y.name));
+            let _ = sender.send(infos);
+        }
+        ThreadRequest::Runtime(sender) => {
+            let models = envs.read().await.models.values().cloned().collect_vec();
+            let mut snapshots = Vec::with_capacity(models.len());
+            for env in models {
+                // skip models that are still loading
+                let monitor = match env.try_read() {
+                    Ok(env) => match &*env {
+                        Environment::Loaded { monitor, .. } => monitor.clone(),
+                        Environment::None => continue,
+                    },
+                    Err(_) => continue,
+                };
+                snapshots.push(monitor.snapshot().await);
+            }
+            snapshots.sort_by(|x, y| x.name.cmp(&y.name));
+            let _ = sender.send(snapshots);
+        }
         ThreadRequest::Generate {
             request,
             tokenizer,
             sender,
         } => {
+            if let Some(request_id) = &request.request_id {
+                samplers.insert(request_id.clone(), &request.sampler);
+            }
+            let Some(env) = envs.read().await.select(request.model.as_deref()) else {
+                return Ok(());
+            };
             let context = GenerateContext::new(*request, sender, &tokenizer).await?;
 
             let env = env.read().await;
@@ -720,186 +1890,169 @@ async fn process(env: Arc<RwLock<Environment>>, request: ThreadRequest) -> Resul
                 let _ = sender.send(context);
             }
         }
+        ThreadRequest::GenerateMany {
+            requests,
+            tokenizer,
+        } => {
+            let Some((first, _)) = requests.first() else {
+                return Ok(());
+            };
+            let Some(env) = envs.read().await.select(first.model.as_deref()) else {
+                return Ok(());
+            };
+
+            let mut contexts = Vec::with_capacity(requests.len());
+            for (request, sender) in requests {
+                if let Some(request_id) = &request.request_id {
+                    samplers.insert(request_id.clone(), &request.sampler);
+                }
+                contexts.push(GenerateContext::new(*request, sender, &tokenizer).await?);
+            }
+            // the first context prefills the prompt; the rest continue from its cache
+            let mut context = contexts.remove(0);
+            context.siblings = contexts;
+
+            let env = env.read().await;
+            if let Environment::Loaded { sender, .. } = &*env {
+                let _ = sender.send(context);
+            }
+        }
         ThreadRequest::Reload { request, sender } => {
-            let handle = tokio::spawn(async move {
-                let file = File::open(&request.model_path).await?;
-                let data = unsafe { Mmap::map(&file)? };
-                let (info, load) = {
-                    let st = SafeTensors::deserialize(&data);
-                    let prefab = cbor4ii::serde::from_slice::<Prefab>(&data);
-                    match (st, prefab) {
-                        (Ok(model), _) => (Loader::info(&model)?, LoadType::SafeTensors),
-                        (_, Ok(prefab)) => (prefab.info, LoadType::Prefab),
-                        _ => bail!("failed to read model info"),
+            reload(envs, tracker, request, sender).await?;
+        }
+        ThreadRequest::LoadLoraAdapter {
+            model,
+            adapter,
+            sender,
+        } => {
+            let reload::LoraAdapter { name, lora } = adapter;
+            let request = adapter_request(&envs, model.as_deref(), lora);
+            match request.await {
+                Some(mut request) if request.name.as_ref() != Some(&name) => {
+                    request.adapter_of = request.name.replace(name);
+                    reload(envs, tracker, request, sender).await?
+                }
+                _ => {
+                    if let Some(sender) = sender {
+                        let _ = sender.send(false);
                     }
-                };
-                tracing::info!(
-                    event = "model_load",
-                    path = %request.model_path.display(),
-                    tokenizer_path = %request.tokenizer_path.display(),
-                    batch_size = request.max_batch,
-                    chunk_size = request.token_chunk_size,
-                    quant_type = ?request.quant_type,
-                    precision = ?request.precision,
-                    "Loading model"
-                );
-                tracing::info!(
-                    event = "model_metadata",
-                    version = ?info.version,
-                    layers = info.num_layer,
-                    embed_size = info.num_emb,
-                    hidden_size = info.num_hidden,
-                    vocab_size = info.num_vocab,
-                    heads = info.num_head,
-                    "Model metadata"
-                );
-                tracing::info!(
-                    event = "model_format",
-                    format = ?load,
-                    "Model format detected"
-                );
-
-                tracing::info!(event = "env_lock", "Acquiring env write lock...");
-                let mut env = env.write().await;
-                tracing::info!(
-                    event = "env_lock_acquired",
-                    "Env write lock acquired, clearing env..."
-                );
-                let _ = std::mem::take(&mut *env);
-
-                tracing::info!(
-                    event = "tokenizer_load",
-                    path = %request.tokenizer_path.display(),
-                    "Loading tokenizer"
-                );
-                let tokenizer = Arc::new(load_tokenizer(&request.tokenizer_path).await?);
-                tracing::info!(
-                    event = "backend_dispatch",
-                    backend = ?request.backend,
-                    "Dispatching to backend"
-                );
-
-                // Dispatch based on backend selection
-                let (states, runtime, state, model, softmax_backend) = match request.backend {
-                    Backend::WebGpu => {
-                        let context = create_context(request.adapter, &info).await?;
-                        let adapter_info = context.adapter.get_info();
+                }
+            }
+        }
+        ThreadRequest::LoadProgress(sender) => {
+            let _ = sender.send(tracker.get());
+        }
+        ThreadRequest::AdjustSampler {
+            request_id,
+            adjustment,
+            sender,
+        } => {
+            let result = match samplers.get(&request_id) {
+                Some(sampler) => match sampler.write().await.adjust(&adjustment) {
+                    Ok(_) => {
                         tracing::info!(
-                            event = "gpu_context",
-                            adapter_name = %adapter_info.name,
-                            vendor = adapter_info.vendor,
-                            device = adapter_info.device,
-                            device_type = ?adapter_info.device_type,
-                            driver = %adapter_info.driver,
-                            driver_info = %adapter_info.driver_info,
-                            backend = ?adapter_info.backend,
-                            "GPU context created"
+                            event = "sampler_adjusted",
+                            request_id = %request_id,
+                            adjustment = ?adjustment,
+                            "Sampler adjusted"
                         );
-
-                        let (states, runtime, state, model) =
-                            load_runtime(&context, &info, &request, load).await?;
-                        let softmax_backend = crate::run::SoftmaxBackend::WebGpu(context);
-                        (states, runtime, state, Some(model), softmax_backend)
-                    }
-                    #[cfg(feature = "hip")]
-                    Backend::Hip => {
-                        tracing::info!("loading model with HIP backend");
-                        let (states, runtime, state) = load_runtime_hip(&info, &request).await?;
-                        let softmax_backend = crate::run::SoftmaxBackend::Hip;
-                        // HIP backend does not support model serialization (Save)
-                        (states, runtime, state, None, softmax_backend)
+                        AdjustSamplerResult::Adjusted
                     }
-                    #[cfg(not(feature = "hip"))]
-                    Backend::Hip => {
-                        bail!("HIP backend requested but the 'hip' feature is not enabled");
-                    }
-                };
-
-                let reload = Arc::new(*request);
-                let info = RuntimeInfo {
-                    reload,
-                    info,
-                    states,
-                    tokenizer,
-                };
-
-                let sender = {
-                    let runtime = Arc::downgrade(&runtime);
-                    let (sender, receiver) = flume::unbounded();
-                    tokio::spawn(crate::run::run(
-                        softmax_backend,
-                        runtime,
-                        state,
-                        receiver,
-                        info.clone(),
-                    ));
-                    sender
-                };
-
-                tracing::info!(event = "model_loaded", "Model loaded successfully");
-
-                let _ = std::mem::replace(
-                    &mut *env,
-                    Environment::Loaded {
-                        info,
-                        runtime,
-                        model,
-                        sender,
-                    },
-                );
-                Ok(())
-            });
-
-            if let Some(sender) = sender {
-                let _ = match handle.await? {
-                    Ok(_) => sender.send(true),
-                    Err(err) => {
-                        tracing::error!(
-                            event = "model_load_failed",
-                            error = %err,
-                            "Model reload failed"
-                        );
-                        sender.send(false)
-                    }
-                };
-            } else {
-                // Fire-and-forget initial load: log errors from the background task
-                tokio::spawn(async move {
-                    match handle.await {
-                        Ok(Ok(())) => {
-                            tracing::info!("[reload] background load completed successfully")
-                        }
-                        Ok(Err(err)) => {
-                            tracing::error!("[reload] background load FAILED: {err:#?}")
-                        }
-                        Err(join_err) => {
-                            tracing::error!("[reload] background task panicked: {join_err:#?}")
-                        }
+                    Err(err) => AdjustSamplerResult::Rejected(err.to_string()),
+                },
+                None => AdjustSamplerResult::NotFound,
+            };
+            let _ = sender.send(result);
+        }
+        ThreadRequest::Unload { model } => {
+            let removed = {
+                let mut envs = envs.write().await;
+                match model {
+                    Some(name) => envs
+                        .remove(&name)
+                        .map(|env| (name, env))
+                        .into_iter()
+                        .collect(),
+                    None => {
+                        envs.default = None;
+                        envs.models.drain().collect_vec()
                     }
-                });
+                }
+            };
+            for (name, env) in removed {
+                let mut env = env.write().await;
+                let _ = std::mem::take(&mut *env);
+                tracing::info!(event = "model_unload", name = %name, "Model unloaded");
             }
         }
-        ThreadRequest::Unload => {
-            let mut env = env.write().await;
-            let _ = std::mem::take(&mut *env);
-            tracing::info!(event = "model_unload", "Model unloaded");
+        ThreadRequest::RestoreCache {
+            model,
+            state,
+            sender,
+        } => {
+            let Some(env) = envs.read().await.select(model.as_deref()) else {
+                let _ = sender.send(Err("no model loaded".into()));
+                return Ok(());
+            };
+            let env = env.read().await;
+            let result = match &*env {
+                Environment::Loaded { monitor, .. } => monitor.restore(state).await,
+                Environment::None => Err(anyhow::anyhow!("no model loaded")),
+            };
+            let _ = sender.send(result.map_err(|err| err.to_string()));
         }
         ThreadRequest::Save { request, sender } => {
+            let Some(env) = envs.read().await.select(request.model.as_deref()) else {
+                tracing::warn!("[save] no model loaded");
+                let _ = sender.send(false);
+                return Ok(());
+            };
             let env = env.read().await;
             if let Environment::Loaded {
-                model: Some(model), ..
+                info,
+                model: Some(model),
+                context,
+                ..
             } = &*env
             {
                 let output_path = request.path.display().to_string();
                 tracing::info!(
                     event = "model_save",
                     output_path = %output_path,
+                    quant = ?request.quant,
                     "Serializing model"
                 );
-                let model = model.clone();
+                let model = match (&request.quant, context) {
+                    (None, _) => model.clone(),
+                    (Some(quant), Some(context)) => {
+                        match quantize_model(context, &info.info, &info.reload, quant).await {
+                            Ok(model) => model,
+                            Err(err) => {
+                                tracing::error!(
+                                    event = "model_save_failed",
+                                    error = %err,
+                                    "Model save failed"
+                                );
+                                let _ = sender.send(false);
+                                return Ok(());
+                            }
+                        }
+                    }
+                    (Some(_), None) => {
+                        tracing::warn!("[save] the backend cannot bake quantization into a save");
+                        let _ = sender.send(false);
+                        return Ok(());
+                    }
+                };
+                // write next to the target and move it over when done,
+                // so that an interrupted save never leaves a truncated prefab behind
                 let handle = tokio::task::spawn_blocking(move || {
-                    let file = std::fs::File::create(request.path)?;
-                    model.serialize(file)
+                    let mut temp = request.path.clone().into_os_string();
+                    temp.push(".partial");
+                    let file = std::fs::File::create(&temp)?;
+                    model.serialize(file)?;
+                    std::fs::rename(&temp, &request.path)?;
+                    anyhow::Ok(())
                 });
                 drop(env);
 
@@ -924,9 +2077,11 @@ async fn process(env: Arc<RwLock<Environment>>, request: ThreadRequest) -> Resul
 }
 
 pub async fn serve(receiver: Receiver<ThreadRequest>) {
-    let env: Arc<RwLock<Environment>> = Default::default();
+    let envs: Arc<RwLock<Environments>> = Default::default();
+    let tracker = LoadTracker::default();
+    let samplers = SamplerRegistry::default();
     while let Ok(request) = receiver.recv_async().await {
-        let future = process(env.clone(), request);
+        let future = process(envs.clone(), tracker.clone(), samplers.clone(), request);
         tokio::spawn(future);
     }
 }
@@ -955,3 +2110,63 @@ mod sealed {
         Gpu,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use web_rwkv::runtime::{model::ModelCustomInfo, v7};
+
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../assets/tokenizer/rwkv_vocab_v20230424.json"
+        );
+        Tokenizer::new(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn info(num_vocab: usize) -> ModelInfo {
+        ModelInfo {
+            version: ModelVersion::V7,
+            num_layer: 2,
+            num_emb: 64,
+            num_hidden: 256,
+            num_vocab,
+            num_head: 1,
+            custom: ModelCustomInfo::V7(v7::CustomInfo {
+                w: 8,
+                a: 8,
+                g: 8,
+                v: 8,
+            }),
+        }
+    }
+
+    fn mismatch(result: Result<()>) -> (usize, usize) {
+        match result.unwrap_err().downcast::<LoadError>().unwrap() {
+            LoadError::VocabMismatch { tokenizer, model } => (tokenizer, model),
+            err => panic!("unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn test_check_vocab() {
+        let tokenizer = tokenizer();
+        let num_token = tokenizer.token_index_to_bytes().len();
+
+        // the same vocab loads with or without padding
+        assert!(check_vocab(&tokenizer, &info(num_token), false).is_ok());
+        assert!(check_vocab(&tokenizer, &info(num_token), true).is_ok());
+
+        // a model padded past the tokenizer only loads when the padding is masked
+        assert!(check_vocab(&tokenizer, &info(num_token + 64), true).is_ok());
+        let result = check_vocab(&tokenizer, &info(num_token + 64), false);
+        assert_eq!(mismatch(result), (num_token, num_token + 64));
+
+        // a tokenizer larger than the model never loads
+        let result = check_vocab(&tokenizer, &info(num_token - 64), true);
+        assert_eq!(mismatch(result), (num_token, num_token - 64));
+        let result = check_vocab(&tokenizer, &info(num_token - 64), false);
+        assert_eq!(mismatch(result), (num_token, num_token - 64));
+    }
+}
@@ -0,0 +1,619 @@
+//! Reader of GGUF model files.
+//!
+//! Tensors are handed to the model builder as `F16`, dequantizing `F32`, `BF16`, `Q8_0` and `Q4_0`
+//! on the fly. Other quantization types are rejected.
+//!
+//! Tensor names of llama.cpp are translated to those of RWKV checkpoints; other names are kept
+//! as they are. For `rwkv7` files the layout llama.cpp converts to is undone as well:
+//! `time_mix_lerp_fused` is split into the `x_*` mixes, the LoRA matrices are transposed back,
+//! and squeezed vectors get their checkpoint shapes again. Fused tensors of older architectures
+//! have no counterpart, so such files fail to load with the name of the missing RWKV tensor.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::{bail, Result};
+use half::{bf16, f16};
+use safetensors::{Dtype, SafeTensorError};
+use web_rwkv::runtime::loader::{Reader, ReaderTensor};
+
+/// `GGUF` in little endian.
+const MAGIC: u32 = 0x4655_4747;
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// Elements per block of the `Q8_0` and `Q4_0` quantization types.
+const QK: usize = 32;
+
+/// Bytes of the smallest tensor info: an empty name, no dimensions, the type and the offset.
+const MIN_TENSOR_INFO_SIZE: usize = 8 + 4 + 4 + 8;
+
+/// The mixes stacked in the `time_mix_lerp_fused` tensor of `rwkv7`, in order.
+const RWKV7_LERP: [&str; 6] = ["x_r", "x_w", "x_k", "x_v", "x_a", "x_g"];
+/// LoRA matrices of `rwkv7` that llama.cpp stores transposed.
+const RWKV7_LORA: [&str; 8] = ["w1", "w2", "a1", "a2", "v1", "v2", "g1", "g2"];
+/// Vectors of `rwkv7` that llama.cpp squeezes from `[1, 1, C]`.
+const RWKV7_SQUEEZED: [&str; 6] = [
+    "att.w0", "att.a0", "att.v0", "att.k_k", "att.k_a", "ffn.x_k",
+];
+
+/// Whether `data` starts like a GGUF file.
+pub fn is_gguf(data: &[u8]) -> bool {
+    data.get(..4)
+        .is_some_and(|magic| u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]]) == MAGIC)
+}
+
+/// Tensor types of GGML that can be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GgmlType {
+    F32,
+    F16,
+    Q4_0,
+    Q8_0,
+    BF16,
+}
+
+impl GgmlType {
+    fn from_id(id: u32) -> Result<Self> {
+        match id {
+            0 => Ok(Self::F32),
+            1 => Ok(Self::F16),
+            2 => Ok(Self::Q4_0),
+            8 => Ok(Self::Q8_0),
+            30 => Ok(Self::BF16),
+            id => bail!("unsupported GGML tensor type {id}"),
+        }
+    }
+
+    /// Size in bytes of `len` elements.
+    fn size(self, len: usize) -> Result<usize> {
+        let (len, bytes) = match self {
+            Self::F32 => (len, 4),
+            Self::F16 | Self::BF16 => (len, 2),
+            Self::Q4_0 | Self::Q8_0 if len % QK != 0 => {
+                bail!("{len} elements do not fill whole {self:?} blocks")
+            }
+            Self::Q4_0 => (len / QK, 2 + QK / 2),
+            Self::Q8_0 => (len / QK, 2 + QK),
+        };
+        match len.checked_mul(bytes) {
+            Some(size) => Ok(size),
+            None => bail!("{self:?} tensor of {len} elements is too large"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TensorInfo {
+    /// Shape in row-major order, i.e. the GGUF dimensions reversed.
+    shape: Vec<usize>,
+    ty: GgmlType,
+    /// Offset from the start of the file.
+    offset: usize,
+    size: usize,
+    /// Rows and columns of a matrix stored transposed to `shape`.
+    transposed: Option<[usize; 2]>,
+}
+
+/// A parsed GGUF file borrowing the tensor data.
+pub struct Gguf<'a> {
+    data: &'a [u8],
+    /// Value of `general.architecture`, e.g. `rwkv7`.
+    pub architecture: Option<String>,
+    tensors: HashMap<String, TensorInfo>,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len());
+        let Some(end) = end else {
+            bail!("unexpected end of GGUF header at byte {}", self.pos);
+        };
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u64()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    /// Skip a metadata value of type `ty`, returning it if it is a string or an integer.
+    fn value(&mut self, ty: u32) -> Result<MetadataValue> {
+        let fixed = |cursor: &mut Self, len: usize| cursor.take(len).map(|_| MetadataValue::Other);
+        match ty {
+            0 | 1 | 7 => fixed(self, 1),
+            2 | 3 => fixed(self, 2),
+            4 => self.u32().map(|x| MetadataValue::Int(x as u64)),
+            5 | 6 => fixed(self, 4),
+            10 => self.u64().map(MetadataValue::Int),
+            11 | 12 => fixed(self, 8),
+            8 => self.string().map(MetadataValue::String),
+            9 => {
+                let ty = self.u32()?;
+                let len = self.u64()?;
+                for _ in 0..len {
+                    self.value(ty)?;
+                }
+                Ok(MetadataValue::Other)
+            }
+            ty => bail!("unknown GGUF metadata type {ty}"),
+        }
+    }
+}
+
+enum MetadataValue {
+    Int(u64),
+    String(String),
+    Other,
+}
+
+impl<'a> Gguf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let mut cursor = Cursor { data, pos: 0 };
+        if cursor.u32()? != MAGIC {
+            bail!("not a GGUF file");
+        }
+        let version = cursor.u32()?;
+        if !(2..=3).contains(&version) {
+            bail!("unsupported GGUF version {version}");
+        }
+        let num_tensors = cursor.u64()?;
+        let num_metadata = cursor.u64()?;
+
+        let mut alignment = DEFAULT_ALIGNMENT;
+        let mut architecture = None;
+        let mut head_size = None;
+        for _ in 0..num_metadata {
+            let key = cursor.string()?;
+            let ty = cursor.u32()?;
+            match (key.as_str(), cursor.value(ty)?) {
+                ("general.alignment", MetadataValue::Int(value)) if value > 0 => alignment = value,
+                ("general.architecture", MetadataValue::String(value)) => {
+                    architecture = Some(value)
+                }
+                (key, MetadataValue::Int(value))
+                    if key.ends_with(".wkv.head_size") && value > 0 =>
+                {
+                    head_size = Some(value as usize)
+                }
+                _ => {}
+            }
+        }
+
+        // the count is untrusted, but every tensor info takes some bytes of the header
+        let max_tensors = (data.len() - cursor.pos) / MIN_TENSOR_INFO_SIZE;
+        let mut infos = Vec::with_capacity((num_tensors as usize).min(max_tensors));
+        for _ in 0..num_tensors {
+            let name = cursor.string()?;
+            let num_dims = cursor.u32()?;
+            let mut shape: Vec<usize> = (0..num_dims)
+                .map(|_| cursor.u64().map(|x| x as usize))
+                .collect::<Result<_>>()?;
+            shape.reverse();
+            let ty = GgmlType::from_id(cursor.u32()?)
+                .map_err(|err| anyhow::anyhow!("tensor {name}: {err}"))?;
+            let offset = cursor.u64()? as usize;
+            infos.push((name, shape, ty, offset));
+        }
+
+        let alignment = alignment as usize;
+        let Some(start) = cursor.pos.div_ceil(alignment).checked_mul(alignment) else {
+            bail!("invalid GGUF alignment {alignment}");
+        };
+        let rwkv7 = architecture.as_deref() == Some("rwkv7");
+        let mut tensors = HashMap::with_capacity(infos.len());
+        for (name, shape, ty, offset) in infos {
+            let len = shape
+                .iter()
+                .try_fold(1usize, |len, &dim| len.checked_mul(dim));
+            let Some(len) = len else {
+                bail!("tensor {name} has too many elements");
+            };
+            let size = ty.size(len)?;
+            let end = start
+                .checked_add(offset)
+                .and_then(|offset| offset.checked_add(size));
+            if end.map_or(true, |end| end > data.len()) {
+                bail!("tensor {name} exceeds the end of the file");
+            }
+            let info = TensorInfo {
+                shape,
+                ty,
+                offset: start + offset,
+                size,
+                transposed: None,
+            };
+            match rwkv7 {
+                true => unfuse_rwkv7(&mut tensors, &name, info, head_size)?,
+                false => {
+                    tensors.insert(rwkv_name(&name, false), info);
+                }
+            }
+        }
+
+        Ok(Self {
+            data,
+            architecture,
+            tensors,
+        })
+    }
+
+    fn info(&self, name: &str) -> Result<&TensorInfo, SafeTensorError> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| SafeTensorError::TensorNotFound(name.to_string()))
+    }
+}
+
+/// Add a tensor of an `rwkv7` file under the name and in the layout of RWKV checkpoints.
+fn unfuse_rwkv7(
+    tensors: &mut HashMap<String, TensorInfo>,
+    name: &str,
+    mut info: TensorInfo,
+    head_size: Option<usize>,
+) -> Result<()> {
+    let name = rwkv_name(name, true);
+    let Some((block, param)) = name
+        .strip_prefix("blocks.")
+        .and_then(|name| name.split_once('.'))
+    else {
+        tensors.insert(name, info);
+        return Ok(());
+    };
+
+    // the mixes are stacked into `[n, 1, 1, C]`
+    if param == "att.time_mix_lerp_fused.weight" {
+        let count = info.shape.first().copied().unwrap_or_default();
+        if count == 0 || count > RWKV7_LERP.len() {
+            bail!("tensor {name} does not stack up to 6 mixes");
+        }
+        let len = info.shape.iter().product::<usize>() / count;
+        let size = info.ty.size(len)?;
+        for (index, mix) in RWKV7_LERP.into_iter().take(count).enumerate() {
+            let part = TensorInfo {
+                shape: vec![1, 1, len],
+                offset: info.offset + index * size,
+                size,
+                ..info.clone()
+            };
+            tensors.insert(format!("blocks.{block}.att.{mix}"), part);
+        }
+        return Ok(());
+    }
+
+    let lora = param
+        .strip_prefix("att.")
+        .is_some_and(|param| RWKV7_LORA.contains(&param));
+    match info.shape[..] {
+        [rows, cols] if lora => {
+            info.shape = vec![cols, rows];
+            info.transposed = Some([rows, cols]);
+        }
+        [len] if RWKV7_SQUEEZED.contains(&param) => info.shape = vec![1, 1, len],
+        [len] if param == "att.r_k" => {
+            if let Some(head_size) = head_size.filter(|&size| len % size == 0) {
+                info.shape = vec![len / head_size, head_size];
+            }
+        }
+        _ => {}
+    }
+    tensors.insert(name, info);
+    Ok(())
+}
+
+/// Translate a tensor name of llama.cpp to that of RWKV checkpoints, if there is a direct one.
+///
+/// The tensors of `rwkv7` that differ from those of earlier versions are translated if `rwkv7`.
+fn rwkv_name(name: &str, rwkv7: bool) -> String {
+    const GLOBAL: [(&str, &str); 4] = [
+        ("token_embd.", "emb."),
+        ("token_embd_norm.", "blocks.0.ln0."),
+        ("output_norm.", "ln_out."),
+        ("output.", "head."),
+    ];
+    const BLOCK: [(&str, &str); 12] = [
+        ("attn_norm.", "ln1."),
+        ("attn_norm_2.", "ln2."),
+        ("time_mix_key.", "att.key."),
+        ("time_mix_value.", "att.value."),
+        ("time_mix_receptance.", "att.receptance."),
+        ("time_mix_gate.", "att.gate."),
+        ("time_mix_output.", "att.output."),
+        ("time_mix_ln.", "att.ln_x."),
+        ("channel_mix_key.", "ffn.key."),
+        ("channel_mix_value.", "ffn.value."),
+        ("channel_mix_receptance.", "ffn.receptance."),
+        ("time_mix_first.", "att.time_faaaa."),
+    ];
+    const BLOCK_RWKV7: [(&str, &str); 16] = [
+        ("time_mix_w0.weight", "att.w0"),
+        ("time_mix_w1.weight", "att.w1"),
+        ("time_mix_w2.weight", "att.w2"),
+        ("time_mix_a0.weight", "att.a0"),
+        ("time_mix_a1.weight", "att.a1"),
+        ("time_mix_a2.weight", "att.a2"),
+        ("time_mix_v0.weight", "att.v0"),
+        ("time_mix_v1.weight", "att.v1"),
+        ("time_mix_v2.weight", "att.v2"),
+        ("time_mix_g1.weight", "att.g1"),
+        ("time_mix_g2.weight", "att.g2"),
+        ("time_mix_k_k.weight", "att.k_k"),
+        ("time_mix_k_a.weight", "att.k_a"),
+        ("time_mix_r_k.weight", "att.r_k"),
+        ("time_mix_lerp_fused.", "att.time_mix_lerp_fused."),
+        ("channel_mix_lerp_k.weight", "ffn.x_k"),
+    ];
+
+    for (from, to) in GLOBAL {
+        if let Some(rest) = name.strip_prefix(from) {
+            return format!("{to}{rest}");
+        }
+    }
+    if let Some((layer, rest)) = name
+        .strip_prefix("blk.")
+        .and_then(|name| name.split_once('.'))
+    {
+        let extra = BLOCK_RWKV7.iter().filter(|_| rwkv7);
+        for &(from, to) in BLOCK.iter().chain(extra) {
+            if let Some(suffix) = rest.strip_prefix(from) {
+                return format!("blocks.{layer}.{to}{suffix}");
+            }
+        }
+    }
+    name.to_string()
+}
+
+fn f16_bytes(values: impl Iterator<Item = f16>) -> Vec<u8> {
+    values.flat_map(|x| x.to_le_bytes()).collect()
+}
+
+/// Dequantize `Q8_0` blocks: an `f16` scale followed by 32 `i8`.
+fn dequantize_q8_0(data: &[u8]) -> Vec<u8> {
+    let values = data.chunks_exact(2 + QK).flat_map(|block| {
+        let scale = f16::from_le_bytes([block[0], block[1]]).to_f32();
+        block[2..]
+            .iter()
+            .map(move |&q| f16::from_f32(q as i8 as f32 * scale))
+    });
+    f16_bytes(values)
+}
+
+/// Dequantize `Q4_0` blocks: an `f16` scale followed by 32 nibbles offset by 8,
+/// the low nibbles holding the first half of the block.
+fn dequantize_q4_0(data: &[u8]) -> Vec<u8> {
+    let values = data.chunks_exact(2 + QK / 2).flat_map(|block| {
+        let scale = f16::from_le_bytes([block[0], block[1]]).to_f32();
+        let quants = &block[2..];
+        let low = quants.iter().map(|&q| q & 0x0f);
+        let high = quants.iter().map(|&q| q >> 4);
+        low.chain(high)
+            .map(move |q| f16::from_f32((q as i32 - 8) as f32 * scale))
+    });
+    f16_bytes(values)
+}
+
+/// Transpose a `rows` × `cols` matrix of `f16` bytes.
+fn transpose_f16(data: &[u8], [rows, cols]: [usize; 2]) -> Vec<u8> {
+    let values: Vec<[u8; 2]> = data.chunks_exact(2).map(|x| [x[0], x[1]]).collect();
+    (0..cols)
+        .flat_map(|col| (0..rows).map(move |row| row * cols + col))
+        .flat_map(|index| values[index])
+        .collect()
+}
+
+impl Reader for Gguf<'_> {
+    fn names(&self) -> Vec<&str> {
+        self.tensors.keys().map(String::as_str).collect()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        Ok(self.info(name)?.shape.clone())
+    }
+
+    fn tensor(&self, name: &str) -> Result<ReaderTensor<'_>, SafeTensorError> {
+        let info = self.info(name)?;
+        let data = &self.data[info.offset..info.offset + info.size];
+        let data = match info.ty {
+            GgmlType::F16 => Cow::Borrowed(data),
+            GgmlType::F32 => {
+                Cow::Owned(f16_bytes(data.chunks_exact(4).map(|x| {
+                    f16::from_f32(f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                })))
+            }
+            GgmlType::BF16 => {
+                Cow::Owned(f16_bytes(data.chunks_exact(2).map(|x| {
+                    f16::from_f32(bf16::from_le_bytes([x[0], x[1]]).to_f32())
+                })))
+            }
+            GgmlType::Q8_0 => Cow::Owned(dequantize_q8_0(data)),
+            GgmlType::Q4_0 => Cow::Owned(dequantize_q4_0(data)),
+        };
+        let data = match info.transposed {
+            Some(shape) => Cow::Owned(transpose_f16(&data, shape)),
+            None => data,
+        };
+        Ok((Dtype::F16, info.shape.clone(), data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a GGUF file: the metadata are `(key, type, value)`, the tensors
+    /// `(name, GGUF dimensions, type, data)`.
+    fn gguf(
+        metadata: &[(&str, u32, Vec<u8>)],
+        tensors: &[(&str, Vec<u64>, u32, Vec<u8>)],
+    ) -> Vec<u8> {
+        let string = |file: &mut Vec<u8>, text: &str| {
+            file.extend((text.len() as u64).to_le_bytes());
+            file.extend(text.as_bytes());
+        };
+        let mut file = vec![];
+        file.extend(MAGIC.to_le_bytes());
+        file.extend(3u32.to_le_bytes());
+        file.extend((tensors.len() as u64).to_le_bytes());
+        file.extend((metadata.len() as u64).to_le_bytes());
+        for (key, ty, value) in metadata {
+            string(&mut file, key);
+            file.extend(ty.to_le_bytes());
+            file.extend(value);
+        }
+        let mut offset = 0u64;
+        for (name, dims, ty, data) in tensors {
+            string(&mut file, name);
+            file.extend((dims.len() as u32).to_le_bytes());
+            dims.iter().for_each(|dim| file.extend(dim.to_le_bytes()));
+            file.extend(ty.to_le_bytes());
+            file.extend(offset.to_le_bytes());
+            offset += data.len() as u64;
+        }
+        file.resize(file.len().div_ceil(32) * 32, 0);
+        tensors.iter().for_each(|(.., data)| file.extend(data));
+        file
+    }
+
+    fn architecture(name: &str) -> (&'static str, u32, Vec<u8>) {
+        let mut value = (name.len() as u64).to_le_bytes().to_vec();
+        value.extend(name.as_bytes());
+        ("general.architecture", 8, value)
+    }
+
+    fn f32_data(values: impl IntoIterator<Item = f32>) -> Vec<u8> {
+        values.into_iter().flat_map(f32::to_le_bytes).collect()
+    }
+
+    fn f16_values(data: &[u8]) -> Vec<f32> {
+        data.chunks_exact(2)
+            .map(|x| f16::from_le_bytes([x[0], x[1]]).to_f32())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_rwkv7_layout() {
+        let metadata = [
+            architecture("rwkv7"),
+            ("rwkv7.wkv.head_size", 4, 2u32.to_le_bytes().to_vec()),
+        ];
+        let tensors = [
+            (
+                "token_embd.weight",
+                vec![4, 2],
+                0,
+                f32_data((0..8).map(|x| x as f32)),
+            ),
+            (
+                "blk.0.time_mix_lerp_fused.weight",
+                vec![4, 1, 1, 6],
+                0,
+                f32_data((0..24).map(|x| x as f32)),
+            ),
+            // `[D, C]` as stored by llama.cpp, `[C, D]` in the checkpoint
+            (
+                "blk.0.time_mix_w1.weight",
+                vec![4, 2],
+                0,
+                f32_data((0..8).map(|x| x as f32)),
+            ),
+            ("blk.0.time_mix_r_k.weight", vec![4], 0, f32_data([0.0; 4])),
+            ("blk.0.time_mix_w0.weight", vec![4], 0, f32_data([0.0; 4])),
+            (
+                "blk.0.channel_mix_lerp_k.weight",
+                vec![4],
+                0,
+                f32_data([0.0; 4]),
+            ),
+        ];
+        let file = gguf(&metadata, &tensors);
+        let model = Gguf::parse(&file).unwrap();
+        assert_eq!(model.architecture.as_deref(), Some("rwkv7"));
+
+        assert_eq!(model.shape("emb.weight").unwrap(), [2, 4]);
+        for (index, mix) in RWKV7_LERP.iter().enumerate() {
+            let name = format!("blocks.0.att.{mix}");
+            let (_, shape, data) = model.tensor(&name).unwrap();
+            assert_eq!(shape, [1, 1, 4]);
+            let start = index as f32 * 4.0;
+            assert_eq!(
+                f16_values(&data),
+                [start, start + 1.0, start + 2.0, start + 3.0]
+            );
+        }
+        assert!(!model.contains("blocks.0.att.time_mix_lerp_fused.weight"));
+
+        let (_, shape, data) = model.tensor("blocks.0.att.w1").unwrap();
+        assert_eq!(shape, [4, 2]);
+        assert_eq!(f16_values(&data), [0.0, 4.0, 1.0, 5.0, 2.0, 6.0, 3.0, 7.0]);
+        assert_eq!(model.shape("blocks.0.att.r_k").unwrap(), [2, 2]);
+        assert_eq!(model.shape("blocks.0.att.w0").unwrap(), [1, 1, 4]);
+        assert_eq!(model.shape("blocks.0.ffn.x_k").unwrap(), [1, 1, 4]);
+    }
+
+    #[test]
+    fn test_parse_other_layouts_by_name() {
+        let tensors = [("blk.3.time_mix_w1.weight", vec![4, 2], 1, vec![0; 16])];
+        let file = gguf(&[architecture("rwkv6")], &tensors);
+        let model = Gguf::parse(&file).unwrap();
+        assert_eq!(model.names(), ["blk.3.time_mix_w1.weight"]);
+        assert_eq!(model.shape("blk.3.time_mix_w1.weight").unwrap(), [2, 4]);
+    }
+
+    #[test]
+    fn test_reject_malformed_header() {
+        let tensors = [("token_embd.weight", vec![4], 0, f32_data([0.0; 4]))];
+        let file = gguf(&[architecture("rwkv7")], &tensors);
+        assert!(Gguf::parse(&file).is_ok());
+
+        // truncated anywhere in the header
+        for len in [0, 3, 8, 20, 40, 60] {
+            assert!(Gguf::parse(&file[..len]).is_err(), "parsed {len} bytes");
+        }
+        // not GGUF
+        let mut bad = file.clone();
+        bad[0] = b'X';
+        assert!(Gguf::parse(&bad).is_err());
+
+        // a huge tensor count is not allocated up front
+        let mut bad = file.clone();
+        bad[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Gguf::parse(&bad).is_err());
+
+        // element counts and offsets that overflow
+        let tensors = [("huge", vec![u64::MAX, 2], 0, vec![])];
+        assert!(Gguf::parse(&gguf(&[], &tensors)).is_err());
+        let tensors = [("huge", vec![u64::MAX / 2], 0, vec![])];
+        assert!(Gguf::parse(&gguf(&[], &tensors)).is_err());
+        let mut bad = gguf(&[], &[("far", vec![4], 0, f32_data([0.0; 4]))]);
+        // header, then the name, dimension count, dimension and type of the tensor
+        let offset = 24 + (8 + 3) + 4 + 8 + 4;
+        bad[offset..offset + 8].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        assert!(Gguf::parse(&bad).is_err());
+
+        // data cut short
+        let file = gguf(&[], &[("short", vec![8], 0, f32_data([0.0; 4]))]);
+        assert!(Gguf::parse(&file).is_err());
+    }
+}
@@ -0,0 +1,4 @@
+//! Readers of model formats other than mapped SafeTensors and prefabs.
+
+pub mod gguf;
+pub mod stream;
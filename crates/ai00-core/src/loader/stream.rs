@@ -0,0 +1,184 @@
+//! Reader of SafeTensors files that reads each tensor from disk when the model builder asks for it.
+//!
+//! Mapping the whole file leaves every page of the weights resident until the load is over, which
+//! can exhaust the RAM of small hosts before the last layer is uploaded. This reader stages a
+//! single tensor at a time instead: its buffer is dropped as soon as the builder has uploaded or
+//! quantized it, so host memory peaks at the largest tensor, usually the embedding or the head.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{bail, Result};
+use safetensors::{Dtype, SafeTensorError};
+use serde::Deserialize;
+use web_rwkv::runtime::loader::{Reader, ReaderTensor};
+
+/// Largest header accepted, as in the `safetensors` crate.
+const MAX_HEADER_SIZE: u64 = 100_000_000;
+
+#[derive(Debug, Clone, Deserialize)]
+struct TensorInfo {
+    dtype: Dtype,
+    shape: Vec<usize>,
+    /// Range of the data, relative to the end of the header.
+    data_offsets: (u64, u64),
+}
+
+pub struct StreamedSafeTensors {
+    file: Mutex<File>,
+    /// Offset of the data from the start of the file.
+    start: u64,
+    tensors: HashMap<String, TensorInfo>,
+}
+
+impl StreamedSafeTensors {
+    /// Read the header of the file at `path`, leaving the tensors on disk.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut len = [0; 8];
+        file.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        if len > MAX_HEADER_SIZE {
+            bail!("SafeTensors header of {len} bytes is too large");
+        }
+        let mut header = vec![0; len as usize];
+        file.read_exact(&mut header)?;
+
+        let mut header: HashMap<String, serde_json::Value> = serde_json::from_slice(&header)?;
+        header.remove("__metadata__");
+        let tensors: HashMap<String, TensorInfo> = header
+            .into_iter()
+            .map(|(name, info)| Ok((name, serde_json::from_value(info)?)))
+            .collect::<Result<_>>()?;
+
+        let start = 8 + len;
+        let size = file.metadata()?.len();
+        for (name, info) in &tensors {
+            let (begin, end) = info.data_offsets;
+            let within = start.checked_add(end).is_some_and(|end| end <= size);
+            if begin > end || !within {
+                bail!("tensor {name} exceeds the end of the file");
+            }
+        }
+
+        Ok(Self {
+            file: Mutex::new(file),
+            start,
+            tensors,
+        })
+    }
+
+    fn info(&self, name: &str) -> Result<&TensorInfo, SafeTensorError> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| SafeTensorError::TensorNotFound(name.to_string()))
+    }
+}
+
+impl Reader for StreamedSafeTensors {
+    fn names(&self) -> Vec<&str> {
+        self.tensors.keys().map(String::as_str).collect()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        Ok(self.info(name)?.shape.clone())
+    }
+
+    fn tensor(&self, name: &str) -> Result<ReaderTensor<'_>, SafeTensorError> {
+        let info = self.info(name)?;
+        let (begin, end) = info.data_offsets;
+        let mut data = vec![0; (end - begin) as usize];
+        let mut file = self.file.lock().expect("model file poisoned");
+        file.seek(SeekFrom::Start(self.start + begin))
+            .and_then(|_| file.read_exact(&mut data))
+            .map_err(SafeTensorError::IoError)?;
+        Ok((info.dtype, info.shape.clone(), Cow::Owned(data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use safetensors::tensor::TensorView;
+
+    use super::*;
+
+    /// Write `data` to a file that is removed when the returned guard is dropped.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(data: &[u8]) -> Self {
+            let name = format!("ai00-stream-{}.st", uuid::Uuid::new_v4());
+            let path = std::env::temp_dir().join(name);
+            std::fs::write(&path, data).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// A SafeTensors file with a header of `tensors` and `len` bytes of data.
+    fn raw_file(tensors: serde_json::Value, len: usize) -> Vec<u8> {
+        let header = tensors.to_string();
+        let mut file = (header.len() as u64).to_le_bytes().to_vec();
+        file.extend(header.as_bytes());
+        file.resize(file.len() + len, 0);
+        file
+    }
+
+    #[test]
+    fn test_read_serialized_tensors() {
+        let a: Vec<u8> = [1.0f32, 2.0, 3.0, 4.0]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let b = vec![7u8, 8, 9];
+        let tensors = [
+            ("a", TensorView::new(Dtype::F32, vec![2, 2], &a).unwrap()),
+            ("b", TensorView::new(Dtype::U8, vec![3], &b).unwrap()),
+        ];
+        let metadata = HashMap::from([("format".to_string(), "pt".to_string())]);
+        let file = TempFile::new(&safetensors::serialize(tensors, Some(metadata)).unwrap());
+
+        let model = StreamedSafeTensors::open(&file.0).unwrap();
+        let mut names = model.names();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+        assert!(!model.contains("__metadata__"));
+        assert_eq!(model.shape("a").unwrap(), [2, 2]);
+
+        let (dtype, shape, data) = model.tensor("a").unwrap();
+        assert_eq!((dtype, shape, &data[..]), (Dtype::F32, vec![2, 2], &a[..]));
+        let (dtype, shape, data) = model.tensor("b").unwrap();
+        assert_eq!((dtype, shape, &data[..]), (Dtype::U8, vec![3], &b[..]));
+        assert!(model.tensor("c").is_err());
+    }
+
+    #[test]
+    fn test_reject_invalid_offsets() {
+        let tensor = |begin: u64, end: u64| serde_json::json!({"a": {"dtype": "U8", "shape": [4], "data_offsets": [begin, end]}});
+        let file = TempFile::new(&raw_file(tensor(0, 4), 4));
+        assert!(StreamedSafeTensors::open(&file.0).is_ok());
+
+        for (begin, end) in [(0, 5), (4, 0), (0, u64::MAX), (u64::MAX, u64::MAX)] {
+            let file = TempFile::new(&raw_file(tensor(begin, end), 4));
+            assert!(
+                StreamedSafeTensors::open(&file.0).is_err(),
+                "accepted offsets {begin}..{end}"
+            );
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Estimates of the device memory a model takes, checked before its weights are uploaded.
+//!
+//! The estimates count the matrices of every layer, the head, the states of the batch slots and
+//! the buffers of one token chunk. Vectors, the embedding (kept on the CPU) and the overhead of
+//! the driver are left out, so they are a lower bound rather than an exact figure.
+
+use web_rwkv::{
+    runtime::model::{ModelInfo, ModelVersion, Quant},
+    wgpu::Limits,
+};
+
+use crate::{reload::Precision, LoadError, LoadSuggestion, ReloadRequest};
+
+/// Estimated device memory of a model under a load configuration, in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoadEstimate {
+    /// Matrices of the layers and the head.
+    pub weights: u64,
+    /// States of all batch slots.
+    pub states: u64,
+    /// Activations and outputs of one token chunk.
+    pub buffers: u64,
+    /// The largest single buffer, which must fit the buffer size limit of the adapter.
+    pub largest_buffer: u64,
+}
+
+impl LoadEstimate {
+    pub fn total(&self) -> u64 {
+        self.weights + self.states + self.buffers
+    }
+}
+
+/// Bytes of a matrix of `params` weights stored as `quant`.
+fn matrix_bytes(params: u64, quant: Quant) -> u64 {
+    match quant {
+        Quant::None => params * 2,
+        // a byte per weight, plus the range of every block
+        Quant::Int8 => params + params / 16,
+        // half a byte per weight, plus the scale of every block
+        _ => params / 2 + params / 16,
+    }
+}
+
+/// Estimate the device memory of `info` with `quant` layers quantized as `quant_type`.
+pub fn estimate(
+    info: &ModelInfo,
+    quant: usize,
+    quant_type: Quant,
+    max_batch: usize,
+    token_chunk_size: usize,
+    precision: Precision,
+) -> LoadEstimate {
+    let emb = info.num_emb as u64;
+    let hidden = info.num_hidden as u64;
+    let vocab = info.num_vocab as u64;
+    let layers = info.num_layer as u64;
+    let batch = max_batch.max(1) as u64;
+    let chunk = token_chunk_size.max(1) as u64;
+
+    // square matrices of the time mix and channel mix, and the two of the hidden layer
+    let square = match info.version {
+        ModelVersion::V4 => 5,
+        ModelVersion::V5 | ModelVersion::V6 => 6,
+        ModelVersion::V7 => 4,
+    };
+    let layer = |quant: Quant| {
+        matrix_bytes(square * emb * emb, quant) + 2 * matrix_bytes(emb * hidden, quant)
+    };
+    let quantized = (quant as u64).min(layers);
+    let head = matrix_bytes(vocab * emb, Quant::None);
+    let weights = quantized * layer(quant_type) + (layers - quantized) * layer(Quant::None) + head;
+
+    let state = match info.version {
+        ModelVersion::V4 => 5 * emb * layers,
+        _ => emb * (emb / info.num_head.max(1) as u64 + 2) * layers,
+    };
+    let states = state * batch * 4;
+
+    let float = match precision {
+        Precision::Fp16 => 2,
+        Precision::Fp32 => 4,
+    };
+    let activations = chunk * (hidden + 8 * emb) * float;
+    let logits = chunk.max(batch) * vocab * 4;
+    let buffers = activations + logits;
+
+    // the head is uploaded in chunks, so it is not one buffer
+    let largest_buffer = [
+        matrix_bytes(emb * hidden, Quant::None),
+        states,
+        activations,
+        logits,
+    ]
+    .into_iter()
+    .max()
+    .unwrap_or_default();
+
+    LoadEstimate {
+        weights,
+        states,
+        buffers,
+        largest_buffer,
+    }
+}
+
+fn estimate_request(
+    info: &ModelInfo,
+    request: &ReloadRequest,
+    quant: usize,
+    quant_type: Quant,
+    max_batch: usize,
+) -> LoadEstimate {
+    estimate(
+        info,
+        quant,
+        quant_type,
+        max_batch,
+        request.token_chunk_size,
+        request.precision,
+    )
+}
+
+/// The closest load configuration that passes `fits`: fewer batch slots only when quantizing
+/// every layer is not enough.
+fn suggest(
+    info: &ModelInfo,
+    request: &ReloadRequest,
+    fits: impl Fn(&LoadEstimate) -> bool,
+) -> Option<LoadSuggestion> {
+    let quant_type = match request.quant_type {
+        Quant::None => Quant::Int8,
+        quant_type => quant_type,
+    };
+    let quants = [
+        (request.quant, request.quant_type),
+        (info.num_layer, quant_type),
+        (info.num_layer, Quant::NF4),
+    ];
+    let batches = std::iter::successors(Some(request.max_batch.max(1)), |&batch| {
+        (batch > 1).then_some(batch / 2)
+    });
+    batches
+        .flat_map(|max_batch| quants.map(|(quant, quant_type)| (quant, quant_type, max_batch)))
+        .find(|&(quant, quant_type, max_batch)| {
+            fits(&estimate_request(
+                info, request, quant, quant_type, max_batch,
+            ))
+        })
+        .map(|(quant, quant_type, max_batch)| LoadSuggestion {
+            quant,
+            quant_type,
+            max_batch,
+        })
+}
+
+/// Refuse a load that cannot fit before any weight is uploaded: one with a buffer over the
+/// adapter's `limits`, or estimated to take more than `vram_budget` of the request.
+pub fn check(
+    info: &ModelInfo,
+    request: &ReloadRequest,
+    limits: Option<&Limits>,
+) -> Result<LoadEstimate, LoadError> {
+    let estimate = estimate_request(
+        info,
+        request,
+        request.quant,
+        request.quant_type,
+        request.max_batch,
+    );
+
+    let max_buffer = limits.map(|limits| {
+        limits
+            .max_buffer_size
+            .min(limits.max_storage_buffer_binding_size as u64)
+    });
+    if let Some(limit) = max_buffer.filter(|&limit| estimate.largest_buffer > limit) {
+        return Err(LoadError::BufferTooLarge {
+            size: estimate.largest_buffer,
+            limit,
+            suggestion: suggest(info, request, |estimate| estimate.largest_buffer <= limit),
+        });
+    }
+
+    if let Some(budget) = request
+        .vram_budget
+        .filter(|&budget| estimate.total() > budget)
+    {
+        let fits = |estimate: &LoadEstimate| {
+            estimate.total() <= budget
+                && !max_buffer.is_some_and(|limit| estimate.largest_buffer > limit)
+        };
+        return Err(LoadError::InsufficientMemory {
+            required: estimate.total(),
+            budget,
+            suggestion: suggest(info, request, fits),
+        });
+    }
+
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use web_rwkv::runtime::{model::ModelCustomInfo, v7};
+
+    use super::*;
+
+    fn info() -> ModelInfo {
+        ModelInfo {
+            version: ModelVersion::V7,
+            num_layer: 2,
+            num_emb: 64,
+            num_hidden: 256,
+            num_vocab: 1000,
+            num_head: 1,
+            custom: ModelCustomInfo::V7(v7::CustomInfo {
+                w: 8,
+                a: 8,
+                g: 8,
+                v: 8,
+            }),
+        }
+    }
+
+    fn request(vram_budget: Option<u64>) -> ReloadRequest {
+        ReloadRequest {
+            quant: 0,
+            quant_type: Quant::None,
+            max_batch: 2,
+            token_chunk_size: 4,
+            precision: Precision::Fp16,
+            vram_budget,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_estimate() {
+        let estimate = estimate(&info(), 0, Quant::None, 2, 4, Precision::Fp16);
+        assert_eq!(
+            estimate,
+            LoadEstimate {
+                // 2 layers of 4 square and 2 hidden matrices in fp16, and the head
+                weights: 2 * (4 * 64 * 64 + 2 * 64 * 256) * 2 + 1000 * 64 * 2,
+                // 2 slots of 2 layers of 64 heads of 64 + 2
+                states: 64 * 66 * 2 * 2 * 4,
+                // fp16 activations and fp32 logits of a chunk of 4
+                buffers: 4 * (256 + 8 * 64) * 2 + 4 * 1000 * 4,
+                largest_buffer: 64 * 66 * 2 * 2 * 4,
+            }
+        );
+        assert_eq!(estimate.total(), 324608 + 67584 + 22144);
+
+        // a byte per weight and the ranges of its blocks
+        let quantized = super::estimate(&info(), 2, Quant::Int8, 2, 4, Precision::Fp16);
+        assert_eq!(quantized.weights, 2 * (17408 + 2 * 17408) + 1000 * 64 * 2);
+        assert_eq!(quantized.states, estimate.states);
+    }
+
+    #[test]
+    fn test_check_budget() {
+        assert!(check(&info(), &request(None), None).is_ok());
+
+        let request = request(Some(300_000));
+        let Err(LoadError::InsufficientMemory {
+            required,
+            budget,
+            suggestion: Some(suggestion),
+        }) = check(&info(), &request, None)
+        else {
+            panic!("the load must not fit the budget");
+        };
+        assert_eq!((required, budget), (414_336, 300_000));
+        // quantizing every layer to int8 is not enough
+        assert_eq!((suggestion.quant, suggestion.max_batch), (2, 2));
+        assert!(matches!(suggestion.quant_type, Quant::NF4));
+
+        let estimate = estimate_request(
+            &info(),
+            &request,
+            suggestion.quant,
+            suggestion.quant_type,
+            suggestion.max_batch,
+        );
+        assert!(estimate.total() <= budget);
+    }
+
+    #[test]
+    fn test_check_buffer_limit() {
+        let limits = Limits {
+            max_buffer_size: 40_000,
+            max_storage_buffer_binding_size: 40_000,
+            ..Default::default()
+        };
+        let request = request(None);
+        let Err(LoadError::BufferTooLarge {
+            size,
+            limit,
+            suggestion: Some(suggestion),
+        }) = check(&info(), &request, Some(&limits))
+        else {
+            panic!("the states must not fit a buffer");
+        };
+        assert_eq!((size, limit), (67_584, 40_000));
+        // only fewer slots shrink the states
+        assert_eq!(suggestion.max_batch, 1);
+
+        let estimate = estimate_request(
+            &info(),
+            &request,
+            suggestion.quant,
+            suggestion.quant_type,
+            suggestion.max_batch,
+        );
+        assert!(estimate.largest_buffer <= limit);
+    }
+}
@@ -30,10 +30,22 @@ pub struct Model {
     /// Maximum tokens to be processed in parallel at once.
     #[derivative(Default(value = "128"))]
     pub token_chunk_size: usize,
+    /// Benchmark candidate chunk sizes on load and use the fastest instead of `token_chunk_size`.
+    pub tune_token_chunk_size: bool,
+    /// Most prompt tokens of a slot prefilled between decode steps of the other slots.
+    /// Prompts are prefilled at once if not set.
+    pub max_prefill_chunk_tokens: Option<usize>,
+    /// Bytes of device memory the model may take. Loads estimated to take more are refused.
+    pub vram_budget: Option<u64>,
+    /// Accept a tokenizer whose vocab is smaller than the model's, masking the padding logits.
+    #[derivative(Default(value = "true"))]
+    pub pad_vocab: bool,
     /// Number of states that are cached on GPU.
     #[derivative(Default(value = "8"))]
     pub max_batch: usize,
-    /// Backend to use for inference (`WebGpu` or `Hip`).
+    /// Read SafeTensors weights from disk one tensor at a time instead of mapping the whole file.
+    pub stream_load: bool,
+    /// Backend to use for inference (`WebGpu`, `Hip` or `Cpu`).
     #[serde(default)]
     pub backend: Backend,
 }
@@ -51,6 +63,20 @@ pub struct Lora {
     pub alpha: f32,
 }
 
+/// A named set of LoRAs that requests select with `adapter`.
+///
+/// Each adapter is served by its own copy of the base model with the LoRAs blended on top,
+/// with its own weights and batch slots, so requests of different adapters never share a slot.
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct LoraAdapter {
+    /// Name requests select the adapter by.
+    pub name: String,
+    /// LoRAs blended on top of those of the base model.
+    pub lora: Vec<Lora>,
+}
+
 /// State-tuned initial state.
 #[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
@@ -87,6 +113,107 @@ pub struct BnfOption {
     /// The initial nonterminal of the BNF schemas.
     #[derivative(Default(value = "\"start\".into()"))]
     pub start_nonterminal: String,
+    /// Number of compiled grammars kept for requests with the same grammar.
+    #[derivative(Default(value = "16"))]
+    pub cache_size: usize,
+    /// Grammars compiled when the model loads, so that the first requests using them do not
+    /// wait for them to compile.
+    pub precompile: Vec<String>,
+}
+
+/// Shares of decode throughput between traffic classes.
+///
+/// Shares are only enforced while both classes are generating; a class running alone uses
+/// the full throughput.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct FairnessOption {
+    /// Relative share of decoded tokens reserved for interactive (streaming) traffic.
+    #[derivative(Default(value = "0.8"))]
+    pub interactive: f32,
+    /// Relative share of decoded tokens allowed to batch traffic.
+    #[derivative(Default(value = "0.2"))]
+    pub batch: f32,
+    /// Length of the sliding window over which shares are measured, in milliseconds.
+    #[derivative(Default(value = "2000"))]
+    pub window_ms: u64,
+}
+
+impl FairnessOption {
+    /// Normalized share of `class`.
+    pub fn share(&self, class: TrafficClass) -> f32 {
+        let interactive = self.interactive.max(0.0);
+        let batch = self.batch.max(0.0);
+        let total = interactive + batch;
+        if total <= 0.0 {
+            return 1.0;
+        }
+        match class {
+            TrafficClass::Interactive => interactive / total,
+            TrafficClass::Batch => batch / total,
+        }
+    }
+}
+
+/// Generation run through every batch slot right after loading, so that GPU pipelines
+/// and shader caches are compiled before the first real request.
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct Warmup {
+    /// Prompt of the warmup generations.
+    #[derivative(Default(value = "\"User: Hello!\n\nAssistant:\".into()"))]
+    pub prompt: String,
+    /// Tokens generated in each slot.
+    #[derivative(Default(value = "16"))]
+    pub tokens: usize,
+    /// Seconds to wait for the warmup generations before the load fails.
+    #[derivative(Default(value = "120"))]
+    pub timeout_secs: u64,
+}
+
+/// Snapshots of the prompt caches written to disk while the slots are idle, so that a restarted
+/// server warms its caches again without clients resending their prompts.
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct CacheSnapshotOption {
+    /// Folder of the snapshots, with a file per model and state.
+    #[derivative(Default(value = "\"assets/cache\".into()"))]
+    #[salvo(schema(value_type = String))]
+    pub path: PathBuf,
+    /// Seconds between snapshots. Caches that did not change since the last one are skipped.
+    #[derivative(Default(value = "60"))]
+    pub interval: u64,
+    /// Most recently used prompts written per state.
+    #[derivative(Default(value = "16"))]
+    pub items: usize,
+    /// Restore the snapshots of the model when it loads, unless they were written for other files
+    /// of the model or its LoRAs.
+    #[derivative(Default(value = "true"))]
+    pub restore: bool,
+}
+
+/// Traffic class of a generation, for sharing decode throughput.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficClass {
+    /// Latency sensitive, e.g. streamed to a user.
+    #[default]
+    Interactive,
+    /// Throughput oriented, e.g. offline jobs.
+    Batch,
+}
+
+impl TrafficClass {
+    /// Default class of a request: streamed responses are interactive, others batch.
+    pub fn from_stream(stream: bool) -> Self {
+        match stream {
+            true => TrafficClass::Interactive,
+            false => TrafficClass::Batch,
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, ToSchema)]
@@ -101,6 +228,9 @@ pub enum Backend {
     #[default]
     WebGpu,
     Hip,
+    /// WebGPU on the software adapter of the platform, for hosts without a GPU.
+    /// Only practical for small models.
+    Cpu,
 }
 
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, ToSchema)]
@@ -110,3 +240,38 @@ pub enum AdapterOption {
     Economical,
     Manual(usize),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fairness_share() {
+        let fairness = FairnessOption::default();
+        assert!((fairness.share(TrafficClass::Interactive) - 0.8).abs() < 1e-6);
+        assert!((fairness.share(TrafficClass::Batch) - 0.2).abs() < 1e-6);
+
+        // shares are relative to each other
+        let fairness = FairnessOption {
+            interactive: 3.0,
+            batch: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(fairness.share(TrafficClass::Interactive), 0.75);
+        assert_eq!(fairness.share(TrafficClass::Batch), 0.25);
+
+        // negative shares count as 0, and no share at all leaves every class unthrottled
+        let fairness = FairnessOption {
+            interactive: 1.0,
+            batch: -1.0,
+            ..Default::default()
+        };
+        assert_eq!(fairness.share(TrafficClass::Batch), 0.0);
+        let fairness = FairnessOption {
+            interactive: 0.0,
+            batch: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(fairness.share(TrafficClass::Batch), 1.0);
+    }
+}
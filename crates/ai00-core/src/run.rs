@@ -1,26 +1,28 @@
 use std::{
     cmp::Ordering,
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
     ops::Deref,
-    sync::{Arc, Weak},
+    sync::{atomic, Arc, Weak},
     time::Duration,
 };
 
 use anyhow::{bail, Result};
 use derivative::Derivative;
-use flume::{Receiver, Sender, TryRecvError};
+use flume::{Receiver, Sender, TryRecvError, WeakSender};
 use itertools::Itertools;
 use memmap2::Mmap;
+use metrics::{counter, gauge, histogram};
 use qp_trie::Trie;
 use safetensors::SafeTensors;
+use serde::{Deserialize, Serialize};
 use tokio::{
-    sync::{Mutex, RwLock},
+    sync::{Mutex, Notify, RwLock},
     task::JoinHandle,
     time::Instant,
 };
+use tracing::{field::Empty, Instrument, Span};
 use web_rwkv::{
-    context::Context,
     runtime::{
         infer::{Rnn, RnnInput, RnnInputBatch, RnnOption, RnnOutputBatch},
         model::{ModelInfo, State},
@@ -30,26 +32,47 @@ use web_rwkv::{
     tokenizer::Tokenizer,
 };
 
-/// Backend for softmax computation.
-///
-/// The WebGPU path uses the wgpu Context for GPU-accelerated softmax.
-/// The HIP path uses hip-rwkv's GPU softmax kernel on AMD hardware.
-#[derive(Clone)]
-pub enum SoftmaxBackend {
-    WebGpu(Context),
-    #[cfg(feature = "hip")]
-    Hip,
-}
-
 use crate::{
-    load_model_state,
-    sampler::{bnf::BnfSampler, Formatter, Sampler},
-    FinishReason, GenerateKind, GenerateRequest, InitState, InputState, ReloadRequest, RuntimeInfo,
-    StateId, Token, TokenCounter,
+    backend::InferenceBackend,
+    reload::{CacheSnapshotOption, TrafficClass},
+    sampler::{
+        beam::{BeamParams, BeamSearch},
+        bnf::{BnfCache, BnfSampler},
+        regex::RegexSampler,
+        Formatter, Sampler,
+    },
+    stats, BlockedToken, CacheSnapshot, FinishReason, GenerateError, GenerateKind, GenerateRequest,
+    GrammarTrace, InitState, InputState, MemoryEstimate, ReloadRequest, RuntimeInfo,
+    RuntimeSnapshot, SlotSnapshot, SlotStatus, StateId, Token, TokenCounter,
 };
 
 const MIN_PROMPT_CACHE_TOKENS: usize = 32;
+/// Bytes of the grammar state reported when it blocks every token.
+const MAX_BLOCKED_STATE_LEN: usize = 16384;
+/// Bytes of the grammar state traced after each token.
+const MAX_TRACE_STATE_LEN: usize = 2048;
 const MAX_CACHE_ITEMS: usize = 256;
+/// Maximum number of states checked in by requests; the least recently used are dropped first.
+const MAX_CHECKED_IN_STATES: usize = 64;
+/// How long a pinned prompt prefix stays exempt from eviction after its last use.
+const PIN_DURATION: Duration = Duration::from_secs(300);
+/// Maximum number of pinned prompt prefixes per state; the ones expiring first are unpinned.
+const MAX_PINNED_ITEMS: usize = 64;
+/// Most times a generation backtracks out of banned phrases; later ones are let through.
+const MAX_BACKTRACKS: usize = 64;
+/// Output tokens covered by each decode span of a traced generation.
+const TRACE_DECODE_TOKENS: usize = 32;
+
+/// A span under `parent`, or a disabled one if `parent` is disabled, so that requests that are
+/// not traced cost nothing.
+macro_rules! trace_span {
+    ($parent:expr, $name:literal $($fields:tt)*) => {
+        match $parent.is_disabled() {
+            true => Span::none(),
+            false => tracing::info_span!(parent: $parent, $name $($fields)*),
+        }
+    };
+}
 
 #[repr(transparent)]
 #[derive(Debug, Default, Clone)]
@@ -132,6 +155,12 @@ impl AsTokenSlice for [u32] {
 pub struct GenerateContext {
     /// Tokens that are provided at first.
     pub prompt_tokens: Vec<u32>,
+    /// Number of leading prompt tokens that are prefilled on their own and pinned in the cache.
+    pub pinned_tokens: usize,
+    /// Prompt tokens written to the cache as a pinned prefix by this request.
+    pub cache_creation_tokens: usize,
+    /// Prompt tokens checked out of the cache, a pinned prefix or otherwise.
+    pub cache_read_tokens: usize,
     /// Whether the prompt has already been processed and cached.
     pub prompt_cached: CachedPrompt,
     /// Tokens that have been computed and cached.
@@ -148,11 +177,17 @@ pub struct GenerateContext {
     pub buffer: Vec<u8>,
     /// Tokens that are output by the model.
     pub model_tokens: Vec<u32>,
+    /// Other samples of the same prompt, queued once this context has cached the prompt.
+    pub siblings: Vec<GenerateContext>,
     /// Compiled BNF schema, if any.
     #[derivative(Debug = "ignore")]
     pub formatters: Vec<Arc<RwLock<dyn Formatter + Send + Sync>>>,
     /// For measuring time used.
     pub instant: Option<Instant>,
+    /// Span of the whole generation, under the span of the request.
+    pub span: Span,
+    /// Span of the phase the generation is in: queueing, prefill or a decode segment.
+    pub phase: Span,
     /// When this context was created (for queue wait time calculation).
     pub enqueue_time: Instant,
     /// Time spent on cache checkout + GPU state load in microseconds (set during slot assignment).
@@ -161,9 +196,98 @@ pub struct GenerateContext {
     pub request: GenerateRequest,
     /// To send back generated tokens.
     pub sender: Sender<Token>,
+    /// Where the output may be starting a banned phrase, to backtrack to if it completes one.
+    #[derivative(Debug = "ignore")]
+    pub checkpoint: Option<BanCheckpoint>,
+    /// Tokens excluded at output positions that were backtracked to.
+    pub banned_tokens: HashMap<usize, HashSet<u32>>,
+    /// Keeps the generation counted in [`stats::in_flight`].
+    in_flight: stats::InFlight,
+}
+
+/// The generation right before the token that may start a banned phrase.
+#[derive(Debug, Clone)]
+pub struct BanCheckpoint {
+    /// State of the slot before the token, copied on the device; `None` if the slot has not
+    /// moved on from there.
+    pub state: Option<TensorGpu<f32, ReadWrite>>,
+    /// Logits the token was sampled from.
+    pub logits: TensorCpu<f32>,
+    pub token: u32,
+    /// Lengths of the prefix, output tokens and output text before the token.
+    pub prefix_len: usize,
+    pub position: usize,
+    pub text_len: usize,
+}
+
+/// Check if `text` contains any of `phrases`.
+fn contains_phrase(text: &[u8], phrases: &[String]) -> bool {
+    phrases
+        .iter()
+        .map(|phrase| phrase.as_bytes())
+        .filter(|phrase| !phrase.is_empty())
+        .any(|phrase| text.windows(phrase.len()).any(|window| window == phrase))
+}
+
+/// Check if `text` ends with the beginning of any of `phrases`.
+fn ends_with_phrase_start(text: &[u8], phrases: &[String]) -> bool {
+    (0..text.len()).any(|start| {
+        phrases
+            .iter()
+            .any(|phrase| phrase.as_bytes().starts_with(&text[start..]))
+    })
+}
+
+/// How many bytes of the unsent `buffer` at the end of an output of `text_len` bytes can be sent,
+/// holding back those from `checkpoint_len` on.
+fn sendable_len(buffer_len: usize, text_len: usize, checkpoint_len: Option<usize>) -> usize {
+    match checkpoint_len {
+        Some(checkpoint_len) => (checkpoint_len + buffer_len).saturating_sub(text_len),
+        None => buffer_len,
+    }
+}
+
+/// Rewind the output `text` and its unsent tail `buffer` to `text_len` bytes.
+fn rewind_output(text: &mut Vec<u8>, buffer: &mut Vec<u8>, text_len: usize) {
+    let buffer_start = text.len() - buffer.len();
+    text.truncate(text_len);
+    buffer.truncate(text_len.saturating_sub(buffer_start));
 }
 
 impl GenerateContext {
+    /// Whether the request ran past its timeout, counted from when it was queued.
+    pub fn timed_out(&self) -> bool {
+        self.request
+            .timeout
+            .is_some_and(|timeout| self.enqueue_time.elapsed() >= timeout)
+    }
+
+    /// Answer the request, and those of its siblings, with a timeout before anything is
+    /// generated; only the prompt read so far is counted.
+    pub fn expire(&mut self) {
+        let siblings = std::mem::take(&mut self.siblings);
+        for context in std::iter::once(&*self).chain(&siblings) {
+            let prompt = context.prefix.len();
+            let counter = TokenCounter {
+                prompt,
+                total: prompt,
+                duration: context.enqueue_time.elapsed(),
+                ..Default::default()
+            };
+            tracing::info!(
+                event = "generation_timeout",
+                request_id = ?context.request.request_id,
+                prompt_tokens = prompt,
+                output_tokens = 0,
+            );
+            context.request.stopped(&counter);
+            let _ = context
+                .sender
+                .send(Token::Stop(FinishReason::Timeout, counter));
+            let _ = context.sender.send(Token::Done);
+        }
+    }
+
     pub async fn new(
         request: GenerateRequest,
         sender: Sender<Token>,
@@ -172,12 +296,45 @@ impl GenerateContext {
         // Prefix with token 0 (EOS) for RWKV performance optimization
         // See: https://huggingface.co/BlinkDL/rwkv7-g1
         let mut token_vec = vec![0u32];
-        token_vec.extend(tokenizer.encode(request.prompt.as_bytes())?);
+        // the pinned prefix is encoded on its own, so its tokens are the same for every prompt
+        let pinned = request
+            .cache_prefix
+            .as_deref()
+            .filter(|prefix| !prefix.is_empty())
+            .and_then(|prefix| Some((prefix, request.prompt.strip_prefix(prefix)?)));
+        let pinned_tokens = match pinned {
+            Some((prefix, rest)) => {
+                token_vec.extend(tokenizer.encode(prefix.as_bytes())?);
+                let len = token_vec.len();
+                token_vec.extend(tokenizer.encode(rest.as_bytes())?);
+                len
+            }
+            None => {
+                token_vec.extend(tokenizer.encode(request.prompt.as_bytes())?);
+                0
+            }
+        };
         let tokens = Tokens(token_vec);
         let model_tokens = Tokens(tokenizer.encode(request.model_text.as_bytes())?);
 
         // init sampler state here
-        request.sampler.write().await.init(&model_tokens);
+        {
+            let mut sampler = request.sampler.write().await;
+            sampler.init(&model_tokens);
+            if let Some(seed) = request.seed {
+                sampler.seed(seed);
+            }
+        }
+
+        let span = trace_span!(
+            &request.span,
+            "generate",
+            model = ?request.model,
+            prompt_tokens = tokens.len(),
+            output_tokens = Empty,
+            finish_reason = Empty,
+        );
+        let phase = trace_span!(&span, "queue");
 
         let choices = match &request.kind {
             GenerateKind::Choose { choices, .. } => {
@@ -191,6 +348,9 @@ impl GenerateContext {
         };
         Ok(Self {
             prompt_tokens: tokens.to_vec(),
+            pinned_tokens,
+            cache_creation_tokens: 0,
+            cache_read_tokens: 0,
             prompt_cached: Default::default(),
             prefix: Default::default(),
             suffix: tokens,
@@ -199,12 +359,18 @@ impl GenerateContext {
             model_text: Vec::new(),
             buffer: Vec::new(),
             model_tokens: Vec::new(),
+            siblings: Vec::new(),
             formatters: Vec::new(),
             instant: None,
+            span,
+            phase,
             enqueue_time: Instant::now(),
             cache_fetch_us: None,
             request,
             sender,
+            checkpoint: None,
+            banned_tokens: HashMap::new(),
+            in_flight: stats::InFlight::new(),
         })
     }
 }
@@ -243,6 +409,71 @@ impl CachedItem {
     }
 }
 
+/// A cached prompt as written to a snapshot.
+#[derive(Serialize, Deserialize)]
+struct SnapshotItem {
+    tokens: Vec<u32>,
+    state: TensorCpu<f32>,
+    output: TensorCpu<f32>,
+}
+
+/// The cached prompts of a cache, for the model files of `fingerprint`.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    fingerprint: u64,
+    items: Vec<SnapshotItem>,
+}
+
+/// Fingerprint of the files the model of `reload` is loaded from, so that states cached for
+/// other weights are not restored, even under the same model name.
+fn model_fingerprint(reload: &ReloadRequest) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = rustc_hash::FxHasher::default();
+    let files =
+        std::iter::once(&reload.model_path).chain(reload.lora.iter().map(|lora| &lora.path));
+    for path in files {
+        path.hash(&mut hasher);
+        if let Ok(metadata) = std::fs::metadata(path) {
+            metadata.len().hash(&mut hasher);
+            metadata.modified().ok().hash(&mut hasher);
+        }
+    }
+    for lora in &reload.lora {
+        lora.alpha.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// File of the snapshot of the cache named `key` for the model `name`.
+fn snapshot_path(option: &CacheSnapshotOption, name: &str, key: &str) -> std::path::PathBuf {
+    option.path.join(name).join(format!("{key}.cache"))
+}
+
+/// Write `snapshot` to `path`, through a temporary file so that a crash never leaves half of one.
+fn write_snapshot(path: &std::path::Path, snapshot: &Snapshot) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let data = cbor4ii::serde::to_vec(vec![], snapshot)
+        .map_err(|err| anyhow::anyhow!("failed to encode the snapshot: {err:?}"))?;
+    let mut temp = path.to_owned().into_os_string();
+    temp.push(".partial");
+    std::fs::write(&temp, data)?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// The prompts of the snapshot `data`, if it was written for the model files of `fingerprint`.
+fn read_snapshot(data: &[u8], fingerprint: u64) -> Result<Vec<SnapshotItem>> {
+    let snapshot: Snapshot = cbor4ii::serde::from_slice(data)
+        .map_err(|err| anyhow::anyhow!("failed to decode the snapshot: {err:?}"))?;
+    if snapshot.fingerprint != fingerprint {
+        bail!("the snapshot was written for other model files");
+    }
+    Ok(snapshot.items)
+}
+
 struct CacheCheckout {
     prefix: Vec<u32>,
     state: TensorCpu<f32>,
@@ -253,18 +484,125 @@ struct CacheCheckout {
 struct Cache {
     state: Option<InitState>,
     cache: Trie<Tokens, tokio::sync::watch::Sender<Option<CachedItem>>>,
+    /// Prompt prefixes exempt from eviction, with when they expire.
+    pinned: HashMap<Vec<u32>, Instant>,
 }
 
 impl Cache {
+    fn snapshot(&self, state: Option<StateId>) -> CacheSnapshot {
+        let mut snapshot = CacheSnapshot {
+            state,
+            items: 0,
+            pinned: self.pinned.len(),
+            tokens: 0,
+            bytes: 0,
+        };
+        for (tokens, item) in self.cache.iter() {
+            // prompts still being processed hold no state yet
+            if let Some(item) = &*item.borrow() {
+                snapshot.items += 1;
+                snapshot.tokens += tokens.len();
+                snapshot.bytes +=
+                    (item.state.len() + item.output.len()) * std::mem::size_of::<f32>();
+            }
+        }
+        snapshot
+    }
+
+    /// Name of the snapshot of the cache of `id`, after the initial state it starts from rather
+    /// than `id`, which configured states without one get anew on every start.
+    fn snapshot_key(&self, id: StateId) -> String {
+        let name = self.state.as_ref().map(|state| state.name.as_str());
+        let key = match (id == StateId::default(), name) {
+            (true, None) => "default".to_string(),
+            (true, Some(name)) => format!("default.{name}"),
+            (false, name) => format!("state.{}", name.unwrap_or_default()),
+        };
+        key.chars()
+            .map(|c| match c.is_ascii_alphanumeric() || "._-".contains(c) {
+                true => c,
+                false => '_',
+            })
+            .collect()
+    }
+
+    /// When a prompt of the cache was last used.
+    fn newest(&self) -> Option<Instant> {
+        self.cache
+            .iter()
+            .filter_map(|(_, item)| item.borrow().as_ref().map(|item| item.instant))
+            .max()
+    }
+
+    /// The `count` most recently used prompts that hold a state.
+    fn recent(&self, count: usize) -> Vec<SnapshotItem> {
+        self.cache
+            .iter()
+            .filter_map(|(tokens, item)| item.borrow().clone().map(|item| (tokens, item)))
+            .sorted_unstable_by_key(|(_, item)| item.instant.elapsed())
+            .take(count)
+            .map(|(tokens, item)| SnapshotItem {
+                tokens: tokens.0.clone(),
+                state: item.state,
+                output: item.output,
+            })
+            .collect()
+    }
+
+    /// Cache the prompts of a snapshot that are not cached yet. Returns how many were added.
+    fn restore(&mut self, items: Vec<SnapshotItem>) -> usize {
+        let mut count = 0;
+        for SnapshotItem {
+            tokens,
+            state,
+            output,
+        } in items
+        {
+            if self.cache.contains_key(tokens.as_token_slice()) {
+                continue;
+            }
+            let (item, _) = tokio::sync::watch::channel(Some(CachedItem::new(state, output)));
+            self.cache.insert(Tokens(tokens), item);
+            count += 1;
+        }
+        count
+    }
+
+    /// Exempt the cached `tokens` from eviction for [`PIN_DURATION`] from now.
+    fn pin(&mut self, tokens: &[u32]) {
+        self.pinned
+            .insert(tokens.to_vec(), Instant::now() + PIN_DURATION);
+        while self.pinned.len() > MAX_PINNED_ITEMS {
+            let first = self
+                .pinned
+                .iter()
+                .min_by_key(|(_, expiry)| **expiry)
+                .map(|(tokens, _)| tokens.clone());
+            if let Some(tokens) = first {
+                self.pinned.remove(&tokens);
+            }
+        }
+    }
+
+    /// Forget the pending item of `tokens` and its pin, after its prefill failed.
+    fn abandon(&mut self, tokens: &[u32]) {
+        self.cache.remove(tokens.as_token_slice());
+        self.pinned.remove(tokens);
+    }
+
     fn maintain(&mut self) {
+        let now = Instant::now();
+        self.pinned.retain(|_, expiry| *expiry > now);
+
         let cache = &mut self.cache;
-        if cache.count() <= MAX_CACHE_ITEMS {
+        if cache.count() <= MAX_CACHE_ITEMS + self.pinned.len() {
             return;
         }
 
         let mut remove = vec![];
         for (tokens, _) in cache
             .iter()
+            .filter(|(tokens, _)| !self.pinned.contains_key(&tokens.0))
             .filter_map(|(tokens, item)| item.borrow().clone().map(|item| (tokens, item)))
             .sorted_unstable_by_key(|(_, item)| item.instant.elapsed())
             .skip(MAX_CACHE_ITEMS)
@@ -282,15 +620,158 @@ impl Cache {
 struct CacheHub {
     backed: HashMap<StateId, Cache>,
     default: Cache,
+    /// States checked in by requests rather than by the model config, least recently used first.
+    checked_in: VecDeque<StateId>,
 }
 
 impl CacheHub {
+    /// Every cache with its state; that of the default state under `StateId::default()`.
+    fn iter(&self) -> impl Iterator<Item = (StateId, &Cache)> {
+        let default = std::iter::once((StateId::default(), &self.default));
+        default.chain(self.backed.iter().map(|(id, cache)| (*id, cache)))
+    }
+
+    /// The cache of `id`, unlike [`CacheHub::fetch`] without falling back to the default one.
+    fn get_mut(&mut self, id: StateId) -> Option<&mut Cache> {
+        match id == StateId::default() {
+            true => Some(&mut self.default),
+            false => self.backed.get_mut(&id),
+        }
+    }
+
     fn fetch(&mut self, id: StateId) -> &mut Cache {
         match self.backed.get_mut(&id) {
             Some(item) => item,
             None => &mut self.default,
         }
     }
+
+    /// Mark a state checked in by a request as used.
+    /// Returns `true` if the state and its prompt cache are still around.
+    fn touch(&mut self, id: StateId) -> bool {
+        match self.checked_in.iter().position(|&x| x == id) {
+            Some(index) => {
+                self.checked_in.remove(index);
+                self.checked_in.push_back(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Back a state checked in by a request, dropping the least recently used ones over the limit.
+    fn check_in(&mut self, id: StateId, state: InitState) {
+        self.checked_in.retain(|&x| x != id);
+        self.checked_in.push_back(id);
+        while self.checked_in.len() > MAX_CHECKED_IN_STATES {
+            if let Some(id) = self.checked_in.pop_front() {
+                self.backed.remove(&id);
+            }
+        }
+        self.backed.insert(
+            id,
+            Cache {
+                state: Some(state),
+                cache: Trie::new(),
+                pinned: HashMap::new(),
+            },
+        );
+    }
+}
+
+/// Decoded tokens of each traffic class in a sliding window.
+#[derive(Debug, Default)]
+struct TrafficMeter {
+    tokens: HashMap<TrafficClass, VecDeque<Instant>>,
+    /// Generations of each class in the slots.
+    active: HashMap<TrafficClass, usize>,
+}
+
+impl TrafficMeter {
+    fn start(&mut self, class: TrafficClass) {
+        *self.active.entry(class).or_default() += 1;
+    }
+
+    /// A generation of `class` left its slot. Once the class has none left, its tokens no
+    /// longer count against the others.
+    fn finish(&mut self, class: TrafficClass) {
+        let Some(active) = self.active.get_mut(&class) else {
+            return;
+        };
+        *active = active.saturating_sub(1);
+        if *active == 0 {
+            self.active.remove(&class);
+            self.tokens.remove(&class);
+        }
+    }
+
+    /// When the oldest token of `class` leaves the window.
+    fn next_expiry(&self, class: TrafficClass, window: Duration) -> Option<Instant> {
+        let tokens = self.tokens.get(&class)?;
+        tokens.front().map(|&x| x + window)
+    }
+
+    fn expire(&mut self, window: Duration) {
+        let now = Instant::now();
+        for tokens in self.tokens.values_mut() {
+            while tokens.front().is_some_and(|&x| now - x > window) {
+                tokens.pop_front();
+            }
+        }
+    }
+
+    fn count(&self, class: TrafficClass) -> usize {
+        self.tokens
+            .get(&class)
+            .map(VecDeque::len)
+            .unwrap_or_default()
+    }
+
+    fn total(&self) -> usize {
+        self.tokens.values().map(VecDeque::len).sum()
+    }
+
+    fn record(&mut self, class: TrafficClass) {
+        self.tokens
+            .entry(class)
+            .or_default()
+            .push_back(Instant::now());
+    }
+
+    /// Whether `class` decoded more than its `share` of the tokens in the window.
+    /// A class decoding alone is never over its share.
+    fn over_share(&self, class: TrafficClass, share: f32) -> bool {
+        let count = self.count(class);
+        let total = self.total();
+        count < total && count as f32 > share * total as f32
+    }
+}
+
+/// The traffic meter of a runtime, notifying throttled decode steps whenever it changes.
+#[derive(Debug, Default)]
+struct Traffic {
+    meter: std::sync::Mutex<TrafficMeter>,
+    changed: Notify,
+}
+
+/// Counts a generation in its traffic class while it holds a slot.
+struct ActiveTraffic {
+    traffic: Arc<Traffic>,
+    class: TrafficClass,
+}
+
+impl ActiveTraffic {
+    fn new(traffic: Arc<Traffic>, class: TrafficClass) -> Self {
+        traffic.meter.lock().unwrap().start(class);
+        Self { traffic, class }
+    }
+}
+
+impl Drop for ActiveTraffic {
+    fn drop(&mut self) {
+        self.traffic.meter.lock().unwrap().finish(self.class);
+        self.traffic.changed.notify_waiters();
+    }
 }
 
 /// The result of trying to queuing a task.
@@ -322,6 +803,16 @@ impl Default for SlotState {
     }
 }
 
+/// The request a busy slot runs, for [`RuntimeMonitor::snapshot`].
+#[derive(Debug, Clone)]
+struct SlotActivity {
+    request_id: Option<String>,
+    /// When the slot took the request.
+    instant: Instant,
+    /// Tokens in the state of the slot.
+    tokens: usize,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum SlotChoice {
     Continue(usize, usize),
@@ -351,6 +842,22 @@ impl std::cmp::PartialOrd for SlotChoice {
     }
 }
 
+/// Who a queued request is scheduled fairly against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Tenant {
+    Client(String),
+    State(StateId),
+}
+
+impl Tenant {
+    fn of(request: &GenerateRequest) -> Self {
+        match &request.client {
+            Some(client) => Self::Client(client.clone()),
+            None => Self::State(request.state.id()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 enum InferBatch {
     Run {
@@ -383,18 +890,24 @@ struct SoftmaxBatch {
     sender: Sender<TensorCpu<f32>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
 struct RuntimeSender {
     infer: Sender<InferBatch>,
     softmax: Sender<SoftmaxBatch>,
+    /// Hands contexts back to the queue. Weak so that the runtime stops once all callers are gone.
+    #[derivative(Debug = "ignore")]
+    queue: WeakSender<GenerateContext>,
 }
 
 #[derive(Derivative, Clone)]
 #[derivative(Debug)]
 struct CoreRuntime {
-    /// WebGPU context, present for the WebGPU backend. The HIP backend
-    /// does not need a wgpu context and sets this to `None`.
-    context: Option<Context>,
+    /// Name that requests use to select the model, used to label metrics.
+    name: String,
+    /// Backend the model runs on, which also reads state files.
+    #[derivative(Debug = "ignore")]
+    backend: Arc<dyn InferenceBackend>,
     info: ModelInfo,
     reload: Arc<ReloadRequest>,
     #[derivative(Debug = "ignore")]
@@ -402,7 +915,164 @@ struct CoreRuntime {
     sender: RuntimeSender,
     tokenizer: Arc<Tokenizer>,
     slots: Arc<Mutex<Vec<SlotState>>>,
+    activity: Arc<std::sync::Mutex<Vec<Option<SlotActivity>>>>,
+    caches: Arc<Mutex<CacheHub>>,
+    traffic: Arc<Traffic>,
+    bnf: Arc<std::sync::Mutex<BnfCache>>,
+}
+
+/// Handle to the slots and caches of a runtime, to inspect them while it serves requests.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct RuntimeMonitor {
+    name: String,
+    max_batch: usize,
+    #[derivative(Debug = "ignore")]
+    state: Arc<dyn State + Send + Sync>,
+    slots: Arc<Mutex<Vec<SlotState>>>,
+    activity: Arc<std::sync::Mutex<Vec<Option<SlotActivity>>>>,
     caches: Arc<Mutex<CacheHub>>,
+    cache_snapshot: Option<CacheSnapshotOption>,
+    /// Fingerprint of the model files, which snapshots must have been written for.
+    fingerprint: u64,
+}
+
+impl RuntimeMonitor {
+    /// Put the prompts of the snapshot of `state` back into its cache.
+    /// Returns how many prompts were restored.
+    pub async fn restore(&self, state: StateId) -> Result<usize> {
+        let Some(option) = &self.cache_snapshot else {
+            bail!("cache snapshots are not enabled");
+        };
+        let key = match self.caches.lock().await.get_mut(state) {
+            Some(cache) => cache.snapshot_key(state),
+            None => bail!("state {state:?} is not loaded"),
+        };
+        let path = snapshot_path(option, &self.name, &key);
+        let data = tokio::fs::read(&path).await?;
+        let items = read_snapshot(&data, self.fingerprint)
+            .map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+
+        // prompts of another model would have states of another shape
+        let shape = self.state.init().shape();
+        let items = items
+            .into_iter()
+            .filter(|item| item.state.shape() == shape)
+            .collect();
+
+        let mut caches = self.caches.lock().await;
+        let Some(cache) = caches.get_mut(state) else {
+            bail!("state {state:?} is not loaded");
+        };
+        let count = cache.restore(items);
+        tracing::info!(
+            event = "cache_restored",
+            model = %self.name,
+            state = ?state,
+            prompts = count,
+            "Prompt cache restored"
+        );
+        Ok(count)
+    }
+
+    /// Restore the snapshots of every state that has one.
+    async fn restore_all(&self) {
+        let Some(option) = &self.cache_snapshot else {
+            return;
+        };
+        let states = self
+            .caches
+            .lock()
+            .await
+            .iter()
+            .map(|(id, cache)| (id, cache.snapshot_key(id)))
+            .collect_vec();
+        for (state, key) in states {
+            if !snapshot_path(option, &self.name, &key).is_file() {
+                continue;
+            }
+            if let Err(err) = self.restore(state).await {
+                tracing::warn!(
+                    event = "cache_restore_failed",
+                    model = %self.name,
+                    state = ?state,
+                    error = %err,
+                    "Prompt cache restore failed"
+                );
+            }
+        }
+    }
+
+    /// The current state of every slot and cache, with estimates of the memory they take.
+    pub async fn snapshot(&self) -> RuntimeSnapshot {
+        let slots = {
+            let slots = self.slots.lock().await;
+            let activity = self.activity.lock().unwrap();
+            slots
+                .iter()
+                .enumerate()
+                .map(
+                    |(index, slot)| match (slot, activity.get(index).cloned().flatten()) {
+                        (SlotState::Idle(tokens, instant), _) => SlotSnapshot {
+                            index,
+                            status: SlotStatus::Idle,
+                            request_id: None,
+                            tokens: tokens.len(),
+                            elapsed: instant.elapsed(),
+                        },
+                        (SlotState::Busy(_), Some(activity)) => SlotSnapshot {
+                            index,
+                            status: SlotStatus::Busy,
+                            request_id: activity.request_id,
+                            tokens: activity.tokens,
+                            elapsed: activity.instant.elapsed(),
+                        },
+                        (SlotState::Busy(_), None) => SlotSnapshot {
+                            index,
+                            status: SlotStatus::Busy,
+                            request_id: None,
+                            tokens: 0,
+                            elapsed: Duration::ZERO,
+                        },
+                        (SlotState::Locked, _) => SlotSnapshot {
+                            index,
+                            status: SlotStatus::Locked,
+                            request_id: None,
+                            tokens: 0,
+                            elapsed: Duration::ZERO,
+                        },
+                    },
+                )
+                .collect_vec()
+        };
+
+        let caches = {
+            let caches = self.caches.lock().await;
+            let default = std::iter::once((None, &caches.default));
+            let backed = caches
+                .backed
+                .iter()
+                .sorted_by_key(|(id, _)| id.0)
+                .map(|(id, cache)| (Some(*id), cache));
+            default
+                .chain(backed)
+                .map(|(state, cache)| cache.snapshot(state))
+                .collect_vec()
+        };
+
+        let state_bytes = self.state.init().len() * std::mem::size_of::<f32>();
+        let memory = MemoryEstimate {
+            state_bytes,
+            slot_bytes: state_bytes * self.max_batch,
+            cache_bytes: caches.iter().map(|cache| cache.bytes).sum(),
+        };
+        RuntimeSnapshot {
+            name: self.name.clone(),
+            slots,
+            caches,
+            memory,
+        }
+    }
 }
 
 impl CoreRuntime {
@@ -412,15 +1082,12 @@ impl CoreRuntime {
             InputState::Key(id) => Ok(*id),
             InputState::Value(value) => {
                 let id = value.id;
-                let state = InitState::try_from(value.clone())?;
                 let mut caches = self.caches.lock().await;
-                caches.backed.insert(
-                    id,
-                    Cache {
-                        state: Some(state),
-                        cache: Trie::new(),
-                    },
-                );
+                // keep the prompt cache of a state that is sent again
+                if !caches.touch(id) {
+                    let state = InitState::try_from(value.clone())?;
+                    caches.check_in(id, state);
+                }
                 Ok(id)
             }
             InputState::File(file) => {
@@ -435,12 +1102,7 @@ impl CoreRuntime {
                 let prefab = cbor4ii::serde::from_slice::<InitState>(&data);
                 let state = match (st, prefab) {
                     (Ok(model), _) => {
-                        let context = self.context.as_ref().ok_or_else(|| {
-                            anyhow::anyhow!(
-                                "loading state files from SafeTensors requires a WebGPU context"
-                            )
-                        })?;
-                        let data = load_model_state(context, &self.info, model).await?;
+                        let data = self.backend.read_state(&self.info, model).await?;
                         InitState {
                             name,
                             id,
@@ -453,13 +1115,7 @@ impl CoreRuntime {
                 };
 
                 let mut caches = self.caches.lock().await;
-                caches.backed.insert(
-                    id,
-                    Cache {
-                        state: Some(state),
-                        cache: Trie::new(),
-                    },
-                );
+                caches.check_in(id, state);
 
                 Ok(id)
             }
@@ -471,7 +1127,7 @@ impl CoreRuntime {
     async fn checkout(&self, id: StateId, tokens: &[u32]) -> CacheCheckout {
         let mut caches = self.caches.lock().await;
 
-        let Cache { state, cache } = caches.fetch(id);
+        let Cache { state, cache, .. } = caches.fetch(id);
         let prefix = cache.longest_common_prefix(tokens.as_token_slice());
         let len = (1..=prefix.len())
             .rev()
@@ -519,14 +1175,20 @@ impl CoreRuntime {
             tokens => tokens,
         };
 
-        // compile the BNF schema.
+        // compile the BNF schema and the regex.
         let mut formatters = Vec::<Arc<RwLock<dyn Formatter + Send + Sync>>>::new();
         if let Some(schema) = context.request.bnf_schema.clone() {
-            match BnfSampler::new(&self.tokenizer, &schema) {
+            match BnfSampler::cached(&self.bnf, &self.tokenizer, &schema) {
                 Ok(bnf) => formatters.push(Arc::new(RwLock::new(bnf))),
                 Err(err) => return SlotResult::Error(err.into()),
             }
         }
+        if let Some(regex) = context.request.regex.clone() {
+            match RegexSampler::new(&self.tokenizer, &regex) {
+                Ok(regex) => formatters.push(Arc::new(RwLock::new(regex))),
+                Err(err) => return SlotResult::Error(err.into()),
+            }
+        }
 
         // find the best idle slot by:
         // 1. find the slot that matches the context (continue)
@@ -590,12 +1252,14 @@ impl CoreRuntime {
             Some(SlotChoice::Back(batch)) => {
                 let state = check_in_state(context.request.state.clone(), batch).await;
 
+                let fetch_span = trace_span!(&context.span, "cache_checkout", hit_tokens = Empty);
                 let fetch_start = Instant::now();
                 let checkout = self.checkout(state, &tokens).await;
                 self.load(batch, checkout.state).await;
                 let cache_fetch_us = fetch_start.elapsed().as_micros() as u64;
 
                 let len = checkout.prefix.len();
+                fetch_span.record("hit_tokens", len);
                 assert!(len == 0 || (len > 0 && checkout.output.is_some()));
                 tracing::info!(
                     event = "slot_assigned",
@@ -613,9 +1277,11 @@ impl CoreRuntime {
                     output: checkout.output,
                     formatters,
                     cache_fetch_us: Some(cache_fetch_us),
+                    phase: Span::none(),
                     ..context
                 };
-                let handle = tokio::spawn(self.clone().process(batch, context));
+                let span = context.span.clone();
+                let handle = tokio::spawn(self.clone().process(batch, context).instrument(span));
                 let mut slots = self.slots.lock().await;
                 slots[batch] = SlotState::Busy(handle);
                 SlotResult::Fault(batch)
@@ -624,12 +1290,14 @@ impl CoreRuntime {
             Some(SlotChoice::Empty(batch)) => {
                 let state = check_in_state(context.request.state.clone(), batch).await;
 
+                let fetch_span = trace_span!(&context.span, "cache_checkout", hit_tokens = Empty);
                 let fetch_start = Instant::now();
                 let checkout = self.checkout(state, &tokens).await;
                 self.load(batch, checkout.state).await;
                 let cache_fetch_us = fetch_start.elapsed().as_micros() as u64;
 
                 let len = checkout.prefix.len();
+                fetch_span.record("hit_tokens", len);
                 assert!(len == 0 || (len > 0 && checkout.output.is_some()));
                 tracing::info!(
                     event = "slot_assigned",
@@ -647,9 +1315,11 @@ impl CoreRuntime {
                     output: checkout.output,
                     formatters,
                     cache_fetch_us: Some(cache_fetch_us),
+                    phase: Span::none(),
                     ..context
                 };
-                let handle = tokio::spawn(self.clone().process(batch, context));
+                let span = context.span.clone();
+                let handle = tokio::spawn(self.clone().process(batch, context).instrument(span));
                 let mut slots = self.slots.lock().await;
                 slots[batch] = SlotState::Busy(handle);
                 SlotResult::Success(batch)
@@ -657,12 +1327,14 @@ impl CoreRuntime {
             Some(SlotChoice::Continue(batch, ..)) => {
                 let state = check_in_state(context.request.state.clone(), batch).await;
 
+                let fetch_span = trace_span!(&context.span, "cache_checkout", hit_tokens = Empty);
                 let fetch_start = Instant::now();
                 let checkout = self.checkout(state, &tokens).await;
                 self.load(batch, checkout.state).await;
                 let cache_fetch_us = fetch_start.elapsed().as_micros() as u64;
 
                 let len = checkout.prefix.len();
+                fetch_span.record("hit_tokens", len);
                 assert!(len == 0 || (len > 0 && checkout.output.is_some()));
                 tracing::info!(
                     event = "slot_assigned",
@@ -680,9 +1352,11 @@ impl CoreRuntime {
                     output: checkout.output,
                     formatters,
                     cache_fetch_us: Some(cache_fetch_us),
+                    phase: Span::none(),
                     ..context
                 };
-                let handle = tokio::spawn(self.clone().process(batch, context));
+                let span = context.span.clone();
+                let handle = tokio::spawn(self.clone().process(batch, context).instrument(span));
                 let mut slots = self.slots.lock().await;
                 slots[batch] = SlotState::Busy(handle);
                 SlotResult::Success(batch)
@@ -690,6 +1364,13 @@ impl CoreRuntime {
         }
     }
 
+    /// Update the tokens in the state of the busy slot `batch`, for [`RuntimeMonitor`].
+    fn record_progress(&self, batch: usize, tokens: usize) {
+        if let Some(activity) = &mut self.activity.lock().unwrap()[batch] {
+            activity.tokens = tokens;
+        }
+    }
+
     /// Reset finished slots to `idle`. Cache current states of finished slots.
     async fn update(&self) {
         let update = |handle: JoinHandle<_>| async move {
@@ -730,6 +1411,13 @@ impl CoreRuntime {
             let mut slots = self.slots.lock().await;
             slots[batch] = updated;
         }
+
+        let slots = self.slots.lock().await;
+        let busy = slots
+            .iter()
+            .filter(|slot| matches!(slot, SlotState::Busy(_)))
+            .count();
+        gauge!(stats::SLOTS_BUSY, "model" => self.name.clone()).set(busy as f64);
     }
 
     async fn sample(
@@ -738,19 +1426,42 @@ impl CoreRuntime {
         sampler: Arc<RwLock<dyn Sampler + Send + Sync>>,
         formatters: Vec<Arc<RwLock<dyn Formatter + Send + Sync>>>,
         bias: Arc<HashMap<u32, f32>>,
-    ) -> Result<(u32, TensorCpu<f32>)> {
+    ) -> Result<(u32, TensorCpu<f32>, usize)> {
         // process raw model outputs
         let num_vocab = self.info.num_vocab;
+        let mut masked = 0;
         let input = {
             let mut data = output.to_vec();
             assert_eq!(data.len(), num_vocab);
+            let raw = (!formatters.is_empty()).then(|| data.clone());
 
             sampler.read().await.transform(&mut data);
-            for formatter in formatters {
+            for formatter in &formatters {
                 formatter.read().await.transform(&mut data);
             }
+            // a grammar that allows no token leaves nothing to sample
+            if let Some(raw) = raw {
+                let num_token = self.tokenizer.token_index_to_bytes().len().min(num_vocab);
+                masked = data[..num_token]
+                    .iter()
+                    .filter(|&&x| x == f32::NEG_INFINITY)
+                    .count();
+                if masked == num_token {
+                    return Err(self
+                        .grammar_blocked(&raw[..num_token], &formatters)
+                        .await
+                        .into());
+                }
+            }
             for (token, bias) in bias.iter() {
-                data[*token as usize] += *bias;
+                if let Some(logit) = data.get_mut(*token as usize) {
+                    *logit += *bias;
+                }
+            }
+            // mask logits of padding tokens that the tokenizer cannot decode
+            let num_token = self.tokenizer.token_index_to_bytes().len();
+            if let Some(padding) = data.get_mut(num_token..) {
+                padding.fill(f32::NEG_INFINITY);
             }
 
             TensorCpu::from_data([num_vocab, 1, 1, 1], data)?
@@ -764,7 +1475,206 @@ impl CoreRuntime {
         // sample tokens
         assert_eq!(output.len(), num_vocab);
         let token = sampler.write().await.sample(&output);
-        Ok((token, output))
+        Ok((token, output, masked))
+    }
+
+    /// Describe a grammar that blocked all tokens, with the tokens the model rated highest.
+    async fn grammar_blocked(
+        &self,
+        logits: &[f32],
+        formatters: &[Arc<RwLock<dyn Formatter + Send + Sync>>],
+    ) -> GenerateError {
+        const NUM_BLOCKED: usize = 10;
+
+        let grammar_state = describe_formatters(formatters, MAX_BLOCKED_STATE_LEN).await;
+        let blocked = logits
+            .iter()
+            .enumerate()
+            .sorted_by(|(_, x), (_, y)| y.total_cmp(x))
+            .take(NUM_BLOCKED)
+            .map(|(token, &logit)| BlockedToken {
+                token: token as u32,
+                text: String::from_utf8_lossy(&self.tokenizer.token_index_to_bytes()[token])
+                    .into_owned(),
+                logit,
+            })
+            .collect();
+        GenerateError::GrammarBlocked {
+            output_tokens: 0,
+            grammar_state,
+            blocked,
+        }
+    }
+
+    /// Search for the most likely continuation of the prompt that has just been read into `batch`.
+    ///
+    /// Every live hypothesis is run one token at a time, swapping its state in and out of the slot.
+    /// The slot is restored to the prompt state afterwards.
+    async fn beam_search(
+        &self,
+        batch: usize,
+        context: &mut GenerateContext,
+        output: TensorCpu<f32>,
+        params: BeamParams,
+    ) -> Result<()> {
+        if !context.formatters.is_empty() {
+            bail!("beam search does not support BNF schemas");
+        }
+
+        let instant = *context.instant.get_or_insert(Instant::now());
+        let max_tokens = context.request.max_tokens;
+        let bias = context.request.bias.clone();
+        let request = context.request.clone();
+
+        // a stop sequence completed by the last token lies within this many bytes of the end
+        let max_token_len = self
+            .tokenizer
+            .token_index_to_bytes()
+            .iter()
+            .map(Vec::len)
+            .max()
+            .unwrap_or_default();
+        // only the last token of each beam is new
+        let decode = |search: &mut BeamSearch<TensorGpu<f32, ReadWrite>>| {
+            for beam in &mut search.beams {
+                let token = beam.tokens.last().copied().unwrap_or_default();
+                let bytes = self.tokenizer.decode(&[token]).unwrap_or_default();
+                beam.text.extend(bytes);
+            }
+        };
+
+        let init = self.read(batch).await?;
+        let probs = self.probs(output, &bias).await?;
+        let mut search = BeamSearch::new(params, init.clone());
+        search.step(vec![(init.clone(), probs)]);
+        decode(&mut search);
+
+        let mut timed_out = false;
+        loop {
+            search.retire(|beam| {
+                let text = &beam.text;
+                beam.tokens
+                    .last()
+                    .is_some_and(|&token| request.is_stop_token(token))
+                    || beam.tokens.len() >= max_tokens
+                    || request.stop_sequences().any(|stop| {
+                        let start = text.len().saturating_sub(stop.len() + max_token_len);
+                        contains(&text[start..], stop)
+                    })
+            });
+            if search.is_done() {
+                break;
+            }
+            if context.sender.is_disconnected() {
+                self.write(batch, init).await;
+                return Ok(());
+            }
+            if context.timed_out() {
+                tracing::info!(
+                    event = "generation_timeout",
+                    request_id = ?context.request.request_id,
+                    slot = batch,
+                    output_tokens = search.beams[0].tokens.len(),
+                );
+                timed_out = true;
+                break;
+            }
+
+            let mut steps = Vec::with_capacity(search.beams.len());
+            for beam in &search.beams {
+                let token = beam.tokens.last().copied().unwrap_or_default();
+                self.write(batch, beam.state.clone()).await;
+
+                let (sender, receiver) = flume::bounded(1);
+                let _ = self
+                    .sender
+                    .infer
+                    .send_async(InferBatch::Run {
+                        batch,
+                        tokens: vec![token],
+                        option: RnnOption::Last,
+                        sender,
+                    })
+                    .await;
+                let output = receiver.recv_async().await?;
+                let state = self.read(batch).await?;
+                steps.push((state, self.probs(output, &bias).await?));
+            }
+            search.step(steps);
+            decode(&mut search);
+        }
+        self.write(batch, init).await;
+
+        let best = search.best().map(|beam| beam.tokens).unwrap_or_default();
+        let (tokens, mut reason) = match best.last() {
+            Some(&token) if request.is_stop_token(token) => {
+                (&best[..best.len() - 1], FinishReason::Stop)
+            }
+            _ if best.len() >= max_tokens => (&best[..], FinishReason::Length),
+            _ if timed_out => (&best[..], FinishReason::Timeout),
+            _ => (&best[..], FinishReason::Stop),
+        };
+        let mut text = self.tokenizer.decode(tokens)?;
+        if let Some((index, stop)) = request
+            .stop_sequences()
+            .filter_map(|stop| find(&text, stop).map(|index| (index, stop)))
+            .min_by_key(|(index, _)| *index)
+        {
+            text.truncate(index);
+            reason = FinishReason::StopSequence(String::from_utf8_lossy(stop).into_owned());
+        }
+
+        context.model_tokens = tokens.to_vec();
+        context.model_text = text.clone();
+
+        let counter = {
+            let prompt = context.prompt_tokens.len();
+            let completion = context.model_tokens.len();
+            TokenCounter {
+                prompt,
+                completion,
+                total: prompt + completion,
+                duration: instant.elapsed(),
+                cache_creation: context.cache_creation_tokens,
+                cache_read: context.cache_read_tokens,
+                ..Default::default()
+            }
+        };
+        tracing::debug!(
+            event = "beam_search_complete",
+            request_id = ?context.request.request_id,
+            slot = batch,
+            output_tokens = counter.completion,
+            "Beam search complete"
+        );
+
+        let text = String::from_utf8_lossy(&text).into_owned();
+        let _ = context.sender.send(Token::Content(text));
+        context.request.stopped(&counter);
+        let _ = context.sender.send(Token::Stop(reason, counter));
+        let _ = context.sender.send(Token::Done);
+        Ok(())
+    }
+
+    /// Next token probabilities from raw model output, without sampler transforms.
+    async fn probs(&self, output: TensorCpu<f32>, bias: &HashMap<u32, f32>) -> Result<Vec<f32>> {
+        let num_vocab = self.info.num_vocab;
+        let mut data = output.to_vec();
+        for (token, bias) in bias.iter() {
+            if let Some(logit) = data.get_mut(*token as usize) {
+                *logit += *bias;
+            }
+        }
+        let num_token = self.tokenizer.token_index_to_bytes().len();
+        if let Some(padding) = data.get_mut(num_token..) {
+            padding.fill(f32::NEG_INFINITY);
+        }
+        let input = TensorCpu::from_data([num_vocab, 1, 1, 1], data)?;
+
+        let (sender, receiver) = flume::bounded(1);
+        let _ = self.sender.softmax.send(SoftmaxBatch { input, sender });
+        let output = receiver.recv_async().await?;
+        Ok(output.to_vec())
     }
 
     async fn perplexity(&self, batch: usize, tokens: &[u32], head: Option<f32>) -> Result<f32> {
@@ -855,19 +1765,121 @@ impl CoreRuntime {
         Ok(tensor)
     }
 
+    /// Queue sibling contexts that share the prompt of a running one.
+    fn fork(&self, siblings: Vec<GenerateContext>) {
+        let Some(queue) = self.sender.queue.upgrade() else {
+            return;
+        };
+        tracing::debug!(
+            event = "siblings_forked",
+            count = siblings.len(),
+            "Sibling samples queued"
+        );
+        for sibling in siblings {
+            let _ = queue.send(sibling);
+        }
+    }
+
+    /// Prefill the pinned prefix of the prompt on its own and cache it, unless it is cached already.
+    async fn prefill_pinned(&self, batch: usize, context: &mut GenerateContext) -> Result<()> {
+        let pinned = context.pinned_tokens;
+        let tokens = &context.prompt_tokens[..pinned];
+
+        let sender = {
+            let mut caches = self.caches.lock().await;
+            let cache = caches.fetch(context.request.state.id());
+            if context.prefix.len() >= pinned {
+                // a hit refreshes the pin
+                cache.pin(tokens);
+                return Ok(());
+            }
+            if cache.cache.contains_key(tokens.as_token_slice()) {
+                return Ok(());
+            }
+            let (sender, _) = tokio::sync::watch::channel(None);
+            cache.cache.insert(Tokens(tokens.to_vec()), sender.clone());
+            cache.pin(tokens);
+            sender
+        };
+        let _span = trace_span!(&context.span, "cache_pin", pinned_tokens = pinned);
+
+        let (tx, rx) = flume::bounded(1);
+        let _ = self
+            .sender
+            .infer
+            .send_async(InferBatch::Run {
+                batch,
+                tokens: tokens[context.prefix.len()..].to_vec(),
+                option: RnnOption::Last,
+                sender: tx,
+            })
+            .await;
+        let prefilled = match rx.recv_async().await {
+            Ok(output) => self.back(batch).await.map(|backed| (output, backed)),
+            Err(err) => Err(err.into()),
+        };
+        let (output, backed) = match prefilled {
+            Ok(prefilled) => prefilled,
+            Err(err) => {
+                // waiters see the sender dropped and prefill the prompt themselves
+                let mut caches = self.caches.lock().await;
+                caches.fetch(context.request.state.id()).abandon(tokens);
+                return Err(err);
+            }
+        };
+        sender.send_replace(Some(CachedItem::new(backed, output.clone())));
+
+        context.prefix = Tokens(tokens.to_vec());
+        context.suffix = Tokens(context.prompt_tokens[pinned..].to_vec());
+        context.output = Some(output);
+        context.cache_creation_tokens = pinned - context.cache_read_tokens;
+
+        tracing::debug!(
+            event = "cache_prefix_pinned",
+            request_id = ?context.request.request_id,
+            slot = batch,
+            pinned_tokens = pinned,
+            "Prompt prefix prefilled and pinned"
+        );
+        Ok(())
+    }
+
     /// Read in the prompt of a batch and continuously sample it until it is done.
     async fn process(self, batch: usize, mut context: GenerateContext) -> Result<GenerateContext> {
         // Track timing phases
         let process_start = Instant::now();
         let cache_hit_tokens = context.prefix.len();
         let mut prefill_end: Option<Instant> = None;
+        context.cache_read_tokens = cache_hit_tokens;
+
+        self.activity.lock().unwrap()[batch] = Some(SlotActivity {
+            request_id: context.request.request_id.clone(),
+            instant: process_start,
+            tokens: cache_hit_tokens,
+        });
+        context.phase = trace_span!(
+            &context.span,
+            "prefill",
+            cached_tokens = cache_hit_tokens,
+            prompt_tokens = context.prompt_tokens.len(),
+        );
+        let _traffic = ActiveTraffic::new(self.traffic.clone(), context.request.traffic_class);
+        let mut decode_segment = None;
+        let decode_interval = context.request.decode_interval();
+        let mut last_decode: Option<Instant> = None;
+
+        if context.pinned_tokens > 0 {
+            self.prefill_pinned(batch, &mut context).await?;
+        }
 
         // schedule a future cache slot for the prompt
         {
             let mut caches = self.caches.lock().await;
             let cache = &mut caches.fetch(context.request.state.id()).cache;
 
-            let enable = context.prompt_tokens.len() > MIN_PROMPT_CACHE_TOKENS;
+            // siblings rely on the cache to skip the prefill, so always cache for them
+            let enable = context.prompt_tokens.len() > MIN_PROMPT_CACHE_TOKENS
+                || !context.siblings.is_empty();
             let enable = enable && !cache.contains_key(context.prompt_tokens.as_token_slice());
             if enable {
                 let (sender, _) = tokio::sync::watch::channel(None);
@@ -896,25 +1908,57 @@ impl CoreRuntime {
                     output
                 }
                 _ => {
+                    // only decode steps are shaped; prefill is never held back
+                    if !context.model_tokens.is_empty() {
+                        self.throttle(context.request.traffic_class).await;
+                        if let Some((interval, last)) = decode_interval.zip(last_decode) {
+                            tokio::time::sleep_until(last + interval).await;
+                        }
+                        last_decode = Some(Instant::now());
+                    }
+
+                    // a prompt with a deadline is prefilled a chunk at a time, so that it stops
+                    // between chunks once it runs out of time
+                    let prefill = context.model_tokens.is_empty();
+                    if prefill && context.timed_out() {
+                        if let CachedPrompt::Future(_) = context.prompt_cached {
+                            let mut caches = self.caches.lock().await;
+                            let id = context.request.state.id();
+                            caches.fetch(id).abandon(&context.prompt_tokens);
+                            context.prompt_cached = CachedPrompt::None;
+                        }
+                        context.expire();
+                        break;
+                    }
+                    let len = match (prefill, context.request.timeout) {
+                        (true, Some(_)) => context.suffix.len().min(self.reload.token_chunk_size),
+                        _ => context.suffix.len(),
+                    };
+
                     let (sender, receiver) = flume::bounded(1);
                     let _ = self
                         .sender
                         .infer
                         .send_async(InferBatch::Run {
                             batch,
-                            tokens: context.suffix.to_vec(),
+                            tokens: context.suffix[..len].to_vec(),
                             option: RnnOption::Last,
                             sender,
                         })
                         .await;
 
                     let prefix = std::mem::take(&mut context.prefix);
-                    let suffix = std::mem::take(&mut context.suffix);
+                    let mut suffix = std::mem::take(&mut context.suffix);
+                    let rest = suffix.0.split_off(len);
 
                     context.prefix = Tokens([prefix.0, suffix.0].concat());
-                    context.suffix = Tokens(vec![]);
+                    context.suffix = Tokens(rest);
+                    self.record_progress(batch, context.prefix.len());
 
                     let output = receiver.recv_async().await?;
+                    if !context.suffix.is_empty() {
+                        continue;
+                    }
                     // Mark end of prefill phase (first inference call completed)
                     if prefill_end.is_none() {
                         prefill_end = Some(Instant::now());
@@ -923,9 +1967,22 @@ impl CoreRuntime {
                 }
             };
 
+            // the prefill ends with the first output; the decode is traced in segments
+            let segment = context.model_tokens.len() / TRACE_DECODE_TOKENS;
+            if decode_segment != Some(segment) {
+                decode_segment = Some(segment);
+                context.phase = trace_span!(
+                    &context.span,
+                    "decode",
+                    from_token = segment * TRACE_DECODE_TOKENS,
+                );
+            }
+
             // cache the prompt if being asked
             if let CachedPrompt::Future(sender) = context.prompt_cached.clone() {
                 assert_eq!(context.prefix.len(), context.prompt_tokens.len());
+                let _span =
+                    trace_span!(&context.span, "cache_store", tokens = context.prefix.len());
 
                 let backed = self.back(batch).await?;
                 let output = output.clone();
@@ -941,16 +1998,72 @@ impl CoreRuntime {
                 );
             }
 
-            let (token, output) = {
+            // the prompt is in the cache now, so siblings can start decoding right away
+            if !context.siblings.is_empty() {
+                self.fork(std::mem::take(&mut context.siblings));
+            }
+
+            if let GenerateKind::Beam(params) = context.request.kind.clone() {
+                self.beam_search(batch, &mut context, output, params)
+                    .await?;
+                break;
+            }
+
+            // banned phrases are only checked without formatters, whose states cannot go back
+            let banning =
+                !context.request.banned_phrases.is_empty() && context.formatters.is_empty();
+            let logits = banning.then(|| output.clone());
+
+            let (token, output, masked) = {
                 let output = output.clone();
                 let sampler = context.request.sampler.clone();
                 let formatters = context.formatters.clone();
-                let bias = context.request.bias.clone();
-                self.sample(output, sampler, formatters, bias).await?
+                let bias = match context.banned_tokens.get(&context.model_tokens.len()) {
+                    Some(banned) => {
+                        let mut bias = context.request.bias.as_ref().clone();
+                        bias.extend(banned.iter().map(|&token| (token, f32::NEG_INFINITY)));
+                        Arc::new(bias)
+                    }
+                    None => context.request.bias.clone(),
+                };
+                match self.sample(output, sampler, formatters, bias).await {
+                    Ok(sampled) => sampled,
+                    Err(err) => match err.downcast::<GenerateError>() {
+                        Ok(GenerateError::GrammarBlocked {
+                            grammar_state,
+                            blocked,
+                            ..
+                        }) => {
+                            let error = GenerateError::GrammarBlocked {
+                                output_tokens: context.model_tokens.len(),
+                                grammar_state,
+                                blocked,
+                            };
+                            // everything needed to reproduce the generation up to here
+                            tracing::error!(
+                                event = "grammar_blocked",
+                                request_id = ?context.request.request_id,
+                                slot = batch,
+                                prompt = %context.request.prompt,
+                                seed = ?context.request.seed,
+                                bnf_schema = ?context.request.bnf_schema,
+                                regex = ?context.request.regex,
+                                output = %String::from_utf8_lossy(&context.model_text),
+                                error = ?error,
+                            );
+                            let _ = context.sender.send(Token::Error(error));
+                            let _ = context.sender.send(Token::Done);
+                            break;
+                        }
+                        Err(err) => return Err(err),
+                    },
+                }
             };
 
-            let mut stop_token = token == 0;
+            let mut stop_token = context.request.is_stop_token(token);
             let mut word = match self.tokenizer.decode(&[token]) {
+                // the stop token is not part of the output
+                Ok(_) if stop_token => Vec::new(),
                 Ok(word) => word,
                 Err(err) => {
                     tracing::warn!(
@@ -965,12 +2078,96 @@ impl CoreRuntime {
                 }
             };
 
+            let traffic = &self.traffic;
+            traffic
+                .meter
+                .lock()
+                .unwrap()
+                .record(context.request.traffic_class);
+            traffic.changed.notify_waiters();
+
             context.output = Some(output.clone());
             context.suffix.0.push(token);
             context.model_tokens.push(token);
+            let word_start = context.model_text.len();
             context.model_text.extend(&word);
             context.buffer.append(&mut word);
 
+            if let Some(logits) = logits {
+                let start = context
+                    .checkpoint
+                    .as_ref()
+                    .map_or(word_start, |checkpoint| checkpoint.text_len);
+                let pending = &context.model_text[start..];
+                let phrases = &context.request.banned_phrases;
+                let backtracks: usize = context.banned_tokens.values().map(HashSet::len).sum();
+
+                if contains_phrase(pending, phrases) && backtracks < MAX_BACKTRACKS {
+                    // the token is not fed to the model yet, so the slot is still right before it
+                    let checkpoint = context.checkpoint.take().unwrap_or(BanCheckpoint {
+                        state: None,
+                        logits,
+                        token,
+                        prefix_len: context.prefix.len(),
+                        position: context.model_tokens.len() - 1,
+                        text_len: word_start,
+                    });
+                    if let Some(state) = checkpoint.state {
+                        self.write(batch, state).await;
+                    }
+                    tracing::debug!(
+                        event = "banned_phrase_backtrack",
+                        request_id = ?context.request.request_id,
+                        slot = batch,
+                        position = checkpoint.position,
+                        token = checkpoint.token,
+                    );
+
+                    context.prefix.0.truncate(checkpoint.prefix_len);
+                    context.suffix = Tokens(vec![]);
+                    context.model_tokens.truncate(checkpoint.position);
+                    rewind_output(
+                        &mut context.model_text,
+                        &mut context.buffer,
+                        checkpoint.text_len,
+                    );
+                    context.output = Some(checkpoint.logits);
+                    // bans further on were for the text that was rewound
+                    context
+                        .banned_tokens
+                        .retain(|&position, _| position <= checkpoint.position);
+                    context
+                        .banned_tokens
+                        .entry(checkpoint.position)
+                        .or_default()
+                        .insert(checkpoint.token);
+                    continue;
+                }
+
+                let partial = ends_with_phrase_start(pending, phrases);
+                if context.checkpoint.is_none() && partial {
+                    context.checkpoint = Some(BanCheckpoint {
+                        state: Some(self.read(batch).await?),
+                        logits,
+                        token,
+                        prefix_len: context.prefix.len(),
+                        position: context.model_tokens.len() - 1,
+                        text_len: word_start,
+                    });
+                } else if !partial {
+                    context.checkpoint = None;
+                }
+            }
+            // output from the checkpoint on is held back, as it may be rewound
+            let sendable = sendable_len(
+                context.buffer.len(),
+                context.model_text.len(),
+                context
+                    .checkpoint
+                    .as_ref()
+                    .map(|checkpoint| checkpoint.text_len),
+            );
+
             let instant = context.instant.get_or_insert(Instant::now());
             let mut done = false;
             let mut stop = |reason| {
@@ -979,11 +2176,21 @@ impl CoreRuntime {
                     let completion = context.model_tokens.len();
                     let total = prompt + completion;
                     let duration = instant.elapsed();
+                    let now = Instant::now();
+                    let prefill_end = prefill_end.unwrap_or(now);
+                    let cache_fetch = Duration::from_micros(context.cache_fetch_us.unwrap_or(0));
                     TokenCounter {
                         prompt,
                         completion,
                         total,
                         duration,
+                        cache_creation: context.cache_creation_tokens,
+                        cache_read: context.cache_read_tokens,
+                        queue_wait: process_start
+                            .duration_since(context.enqueue_time)
+                            .saturating_sub(cache_fetch),
+                        prefill: prefill_end.duration_since(process_start),
+                        decode: now.duration_since(prefill_end),
                     }
                 };
 
@@ -1001,6 +2208,7 @@ impl CoreRuntime {
                     "Model I/O complete"
                 );
 
+                context.request.stopped(&counter);
                 let _ = context.sender.send(Token::Stop(reason, counter));
                 let _ = context.sender.send(Token::Done);
                 done = true;
@@ -1012,14 +2220,24 @@ impl CoreRuntime {
                 let mut formatter = formatter.write().await;
                 halt |= formatter.update(token);
             }
+            if context.request.grammar_trace && !context.formatters.is_empty() {
+                let num_token = self.tokenizer.token_index_to_bytes().len();
+                let trace = GrammarTrace {
+                    token,
+                    text: String::from_utf8_lossy(&context.model_text[word_start..]).into_owned(),
+                    allowed: num_token.saturating_sub(masked),
+                    masked,
+                    grammar_state: describe_formatters(&context.formatters, MAX_TRACE_STATE_LEN)
+                        .await,
+                };
+                let _ = context.sender.send(Token::GrammarTrace(trace));
+            }
 
             // here we detect if there is a stop word in our buffer
             let ((head, tail), stop_matched) = context
                 .request
-                .stop
-                .iter()
+                .stop_sequences()
                 .map(|stop| {
-                    let stop = stop.as_bytes();
                     let mut index_safe = 0;
                     let mut index_unsafe = 0;
                     while index_unsafe < context.buffer.len() {
@@ -1027,7 +2245,7 @@ impl CoreRuntime {
                         let index_stop = index_unsafe - index_safe;
                         if index_stop >= stop.len() {
                             // we have a total match
-                            return (index_safe, true);
+                            return (index_safe, Some(stop));
                         }
 
                         let output = context.buffer[index_unsafe];
@@ -1038,15 +2256,76 @@ impl CoreRuntime {
                             index_safe = index_unsafe;
                         }
                     }
-                    (index_safe, index_unsafe - index_safe >= stop.len())
+                    let matched = index_unsafe - index_safe >= stop.len();
+                    (index_safe, matched.then_some(stop))
                 })
-                .min_by(|x, y| match (x.1, y.1) {
+                .min_by(|x, y| match (x.1.is_some(), y.1.is_some()) {
                     (true, false) => Ordering::Less,
                     (false, true) => Ordering::Greater,
                     _ => x.0.cmp(&y.0),
                 })
-                .map(|(mid, matched)| (context.buffer.split_at(mid), matched))
-                .unwrap_or(((&context.buffer[..], &[]), false));
+                .map(|(mid, matched)| {
+                    let matched = matched.map(|stop| String::from_utf8_lossy(stop).into_owned());
+                    (context.buffer.split_at(mid), matched)
+                })
+                .unwrap_or(((&context.buffer[..], &[]), None));
+
+            let timed_out = context.timed_out();
+            if timed_out {
+                tracing::info!(
+                    event = "generation_timeout",
+                    request_id = ?context.request.request_id,
+                    slot = batch,
+                    output_tokens = context.model_tokens.len(),
+                );
+            }
+
+            let filtered = context
+                .request
+                .content_filter
+                .load(atomic::Ordering::Acquire);
+
+            // long generations keep their state in the cache every so often, and end once it
+            // drifts to values the model cannot continue from
+            let checkpoint = context
+                .request
+                .checkpoint_interval
+                .is_some_and(|interval| interval > 0 && context.model_tokens.len() % interval == 0);
+            let mut drifted = false;
+            if let Some(output) = context.output.clone().filter(|_| checkpoint) {
+                let _span = trace_span!(
+                    &context.span,
+                    "cache_checkpoint",
+                    tokens = context.prefix.len(),
+                );
+                let backed = self.back(batch).await?;
+                let rms = state_rms(&backed);
+                let max_rms = context.request.max_state_rms;
+                drifted = !is_stable(rms, max_rms) || !is_finite(&output);
+                if drifted {
+                    tracing::warn!(
+                        event = "state_drift",
+                        request_id = ?context.request.request_id,
+                        slot = batch,
+                        output_tokens = context.model_tokens.len(),
+                        state_rms = rms,
+                        max_state_rms = ?max_rms,
+                    );
+                } else {
+                    let mut caches = self.caches.lock().await;
+                    let cache = &mut caches.fetch(context.request.state.id()).cache;
+                    let item = CachedItem::new(backed, output);
+                    let (item, _) = tokio::sync::watch::channel(Some(item));
+                    cache.insert(context.prefix.clone(), item);
+
+                    tracing::debug!(
+                        event = "generation_checkpoint",
+                        request_id = ?context.request.request_id,
+                        slot = batch,
+                        cached_tokens = context.prefix.len(),
+                    );
+                }
+            }
 
             if context.sender.is_disconnected() {
                 done = true;
@@ -1104,12 +2383,19 @@ impl CoreRuntime {
                 let shape = backed.shape().into();
                 let _ = context.sender.send(Token::Embed(embed, shape));
                 done = true;
-            } else if halt || stop_matched || stop_token {
+            } else if halt || stop_matched.is_some() || stop_token || timed_out || filtered {
                 let output = String::from_utf8_lossy(head);
                 let _ = context.sender.send(Token::Content(output.into()));
-                stop(FinishReason::Stop);
+                stop(match stop_matched {
+                    _ if filtered => FinishReason::ContentFilter,
+                    Some(sequence) => FinishReason::StopSequence(sequence),
+                    None if halt || stop_token => FinishReason::Stop,
+                    None => FinishReason::Timeout,
+                });
 
                 if let Some(output) = context.output.clone() {
+                    let _span =
+                        trace_span!(&context.span, "cache_store", tokens = context.prefix.len());
                     let backed = self.back(batch).await?;
                     let mut caches = self.caches.lock().await;
                     let cache = &mut caches.fetch(context.request.state.id()).cache;
@@ -1125,11 +2411,14 @@ impl CoreRuntime {
                         "Response state cached"
                     );
                 }
-            } else if context.model_tokens.len() >= context.request.max_tokens {
+            } else if drifted || context.model_tokens.len() >= context.request.max_tokens {
                 stop(FinishReason::Length);
-            } else if let Ok(word) = String::from_utf8(head.to_vec()) {
-                let _ = context.sender.send(Token::Content(word));
-                context.buffer = tail.to_vec();
+            } else {
+                let (head, rest) = head.split_at(sendable.min(head.len()));
+                if let Ok(word) = String::from_utf8(head.to_vec()) {
+                    let _ = context.sender.send(Token::Content(word));
+                    context.buffer = [rest, tail].concat();
+                }
             }
 
             if done {
@@ -1154,6 +2443,25 @@ impl CoreRuntime {
                 } else {
                     "stop"
                 };
+                context.phase = Span::none();
+                context
+                    .span
+                    .record("output_tokens", context.model_tokens.len());
+                context.span.record("finish_reason", finish_reason);
+
+                let model = self.name.clone();
+                counter!(stats::PROMPT_TOKENS, "model" => model.clone())
+                    .increment(context.prompt_tokens.len() as u64);
+                counter!(stats::GENERATED_TOKENS, "model" => model.clone())
+                    .increment(context.model_tokens.len() as u64);
+                counter!(stats::CACHE_HIT_TOKENS, "model" => model.clone())
+                    .increment(cache_hit_tokens as u64);
+                histogram!(stats::QUEUE_WAIT_SECONDS, "model" => model.clone())
+                    .record(queue_wait_ms as f64 / 1000.0);
+                histogram!(stats::PREFILL_SECONDS, "model" => model.clone())
+                    .record(prefill_ms as f64 / 1000.0);
+                histogram!(stats::DECODE_SECONDS, "model" => model)
+                    .record(decode_ms as f64 / 1000.0);
 
                 tracing::info!(
                     event = "inference_batch",
@@ -1178,6 +2486,37 @@ impl CoreRuntime {
         Ok(context)
     }
 
+    /// Hold back a decode step of `class` while it is over its share of the recent throughput.
+    ///
+    /// It waits until other classes decode or leave their slots, or its own oldest token leaves
+    /// the window.
+    async fn throttle(&self, class: TrafficClass) {
+        let fairness = &self.reload.fairness;
+        let window = Duration::from_millis(fairness.window_ms);
+        let share = fairness.share(class);
+        loop {
+            let changed = self.traffic.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            let expiry = {
+                let mut meter = self.traffic.meter.lock().unwrap();
+                meter.expire(window);
+                if !meter.over_share(class, share) {
+                    return;
+                }
+                meter.next_expiry(class, window)
+            };
+            match expiry {
+                Some(expiry) => tokio::select! {
+                    _ = changed => {},
+                    _ = tokio::time::sleep_until(expiry) => {},
+                },
+                None => changed.await,
+            }
+        }
+    }
+
     /// Keep the items in the cache less then [`MAX_CACHE_ITEMS`].
     async fn maintain_cache(&self) {
         let mut caches = self.caches.lock().await;
@@ -1186,19 +2525,142 @@ impl CoreRuntime {
     }
 }
 
+/// The states of `formatters`, each cut to `max_len` bytes.
+async fn describe_formatters(
+    formatters: &[Arc<RwLock<dyn Formatter + Send + Sync>>],
+    max_len: usize,
+) -> String {
+    let mut states = vec![];
+    for formatter in formatters {
+        let mut state = formatter.read().await.describe();
+        if state.len() > max_len {
+            let end = (0..=max_len)
+                .rev()
+                .find(|&end| state.is_char_boundary(end))
+                .unwrap_or(0);
+            state.truncate(end);
+            state += "…";
+        }
+        states.push(state);
+    }
+    states.join("\n")
+}
+
+/// Check that a logits tensor holds no NaN or infinity.
+fn is_finite(tensor: &TensorCpu<f32>) -> bool {
+    tensor.to_vec().iter().all(|x| x.is_finite())
+}
+
+/// Root mean square of the values of a state; NaN or infinite if any of them is.
+fn state_rms(state: &TensorCpu<f32>) -> f32 {
+    let values = state.to_vec();
+    let sum: f64 = values.iter().map(|&x| x as f64 * x as f64).sum();
+    (sum / values.len().max(1) as f64).sqrt() as f32
+}
+
+/// Check that a state of root mean square `rms` has not drifted: it is finite and at most
+/// `max_rms`, if given.
+fn is_stable(rms: f32, max_rms: Option<f32>) -> bool {
+    rms.is_finite() && max_rms.map_or(true, |max_rms| rms <= max_rms)
+}
+
+/// Byte offset of the first occurrence of `pattern` in `text`.
+fn find(text: &[u8], pattern: &[u8]) -> Option<usize> {
+    match pattern.is_empty() {
+        true => None,
+        false => text.windows(pattern.len()).position(|x| x == pattern),
+    }
+}
+
+fn contains(text: &[u8], pattern: &[u8]) -> bool {
+    find(text, pattern).is_some()
+}
+
+/// Order `queue` for a pass over the slots, given the slots `served` to each tenant while requests
+/// wait, so that one with a long shared prefix and many requests cannot keep the slots from the
+/// others.
+///
+/// Tenants granted the fewest slots go first, and the requests of a tenant take turns with those
+/// of the others rather than following each other. A tenant that starts waiting is even with the
+/// least served one, not owed what it missed while away.
+fn order_fairly<T>(
+    queue: &mut Vec<T>,
+    served: &mut HashMap<Tenant, usize>,
+    tenant: impl Fn(&T) -> Tenant,
+) {
+    let floor = queue
+        .iter()
+        .filter_map(|item| served.get(&tenant(item)))
+        .min()
+        .copied()
+        .unwrap_or_default();
+    let mut ranks = HashMap::<Tenant, usize>::new();
+    let mut items = queue
+        .drain(..)
+        .map(|item| {
+            let tenant = tenant(&item);
+            let served = *served.entry(tenant.clone()).or_insert(floor);
+            let rank = ranks.entry(tenant).or_default();
+            *rank += 1;
+            (served + *rank, item)
+        })
+        .collect_vec();
+    // stable, so requests of tenants even with each other keep their order of arrival
+    items.sort_by_key(|(turn, _)| *turn);
+    queue.extend(items.into_iter().map(|(_, item)| item));
+}
+
 async fn enqueue(runtime: CoreRuntime, receiver: Receiver<GenerateContext>, timer: Duration) {
     let mut queue = Vec::<GenerateContext>::new();
+    let mut served = HashMap::<Tenant, usize>::new();
+
+    let depth = gauge!(stats::QUEUE_DEPTH, "model" => runtime.name.clone());
 
     'outer: while let Ok(context) = receiver.recv_async().await {
         queue.push(context);
+        depth.increment(1.0);
+        stats::queue_push();
 
         'inner: loop {
             runtime.maintain_cache().await;
             runtime.update().await;
 
+            order_fairly(&mut queue, &mut served, |context| {
+                Tenant::of(&context.request)
+            });
+
             let mut temp = Vec::new();
-            for context in queue.drain(..) {
-                match runtime.queue(context).await {
+            for mut context in queue.drain(..) {
+                // the client gave up waiting, so don't spend a slot on it
+                if context.sender.is_disconnected()
+                    && context.siblings.iter().all(|x| x.sender.is_disconnected())
+                {
+                    depth.decrement(1.0);
+                    stats::queue_pop();
+                    tracing::debug!(
+                        event = "enqueue_abandoned",
+                        request_id = ?context.request.request_id,
+                        "Request dropped from the queue after its client went away"
+                    );
+                    continue;
+                }
+                // it ran out of time waiting, so answer it before it takes a slot
+                if context.timed_out() {
+                    depth.decrement(1.0);
+                    stats::queue_pop();
+                    context.expire();
+                    continue;
+                }
+                let tenant = Tenant::of(&context.request);
+                let result = runtime.queue(context).await;
+                if !matches!(result, SlotResult::Failure(_)) {
+                    depth.decrement(1.0);
+                    stats::queue_pop();
+                }
+                if matches!(result, SlotResult::Success(_) | SlotResult::Fault(_)) {
+                    *served.entry(tenant).or_default() += 1;
+                }
+                match result {
                     SlotResult::Failure(context) => temp.push(*context),
                     SlotResult::Success(batch) => tracing::debug!(
                         event = "enqueue_success",
@@ -1220,11 +2682,17 @@ async fn enqueue(runtime: CoreRuntime, receiver: Receiver<GenerateContext>, time
             std::mem::swap(&mut queue, &mut temp);
 
             if queue.is_empty() {
+                // nobody waits, so nobody is owed a slot
+                served.clear();
                 break 'inner;
             }
 
             match receiver.try_recv() {
-                Ok(context) => queue.push(context),
+                Ok(context) => {
+                    queue.push(context);
+                    depth.increment(1.0);
+                    stats::queue_push();
+                }
                 Err(TryRecvError::Empty) => tokio::time::sleep(timer).await,
                 Err(TryRecvError::Disconnected) => break 'outer,
             }
@@ -1232,6 +2700,75 @@ async fn enqueue(runtime: CoreRuntime, receiver: Receiver<GenerateContext>, time
     }
 }
 
+/// Write the most recently used prompts of every cache to disk while all slots are idle,
+/// skipping caches that did not change since their last snapshot.
+async fn snapshot_caches(
+    runtime: CoreRuntime,
+    receiver: Receiver<GenerateContext>,
+    option: CacheSnapshotOption,
+    fingerprint: u64,
+) {
+    let interval = Duration::from_secs(option.interval.max(1));
+    let mut written = HashMap::<StateId, Instant>::new();
+
+    while !receiver.is_disconnected() {
+        tokio::time::sleep(interval).await;
+
+        let idle = {
+            let slots = runtime.slots.lock().await;
+            slots.iter().all(|slot| matches!(slot, SlotState::Idle(..)))
+        };
+        if !idle {
+            continue;
+        }
+
+        let snapshots = {
+            let caches = runtime.caches.lock().await;
+            caches
+                .iter()
+                .filter_map(|(id, cache)| Some((id, cache.newest()?, cache)))
+                .filter(|(id, newest, _)| written.get(id) != Some(newest))
+                .map(|(id, newest, cache)| {
+                    let key = cache.snapshot_key(id);
+                    (id, newest, key, cache.recent(option.items))
+                })
+                .collect_vec()
+        };
+        for (id, newest, key, items) in snapshots {
+            let path = snapshot_path(&option, &runtime.name, &key);
+            let count = items.len();
+            let snapshot = Snapshot { fingerprint, items };
+            let task = move || write_snapshot(&path, &snapshot);
+            match tokio::task::spawn_blocking(task).await {
+                Ok(Ok(())) => {
+                    written.insert(id, newest);
+                    tracing::debug!(
+                        event = "cache_snapshot",
+                        model = %runtime.name,
+                        state = ?id,
+                        prompts = count,
+                        "Prompt cache written"
+                    );
+                }
+                Ok(Err(err)) => tracing::warn!(
+                    event = "cache_snapshot_failed",
+                    model = %runtime.name,
+                    state = ?id,
+                    error = %err,
+                    "Prompt cache snapshot failed"
+                ),
+                Err(err) => tracing::warn!(
+                    event = "cache_snapshot_failed",
+                    model = %runtime.name,
+                    state = ?id,
+                    error = %err,
+                    "Prompt cache snapshot failed"
+                ),
+            }
+        }
+    }
+}
+
 async fn finalize(runtime: CoreRuntime, receiver: Receiver<GenerateContext>, timer: Duration) {
     while !receiver.is_disconnected() {
         runtime.maintain_cache().await;
@@ -1240,13 +2777,56 @@ async fn finalize(runtime: CoreRuntime, receiver: Receiver<GenerateContext>, tim
     }
 }
 
+type Batch = (Vec<u32>, RnnOption, Sender<TensorCpu<f32>>);
+
+/// Take the tokens of every slot for the next round of inference, with the senders of the slots
+/// whose output ends the round.
+///
+/// While other slots decode, prompts are prefilled a chunk of at most `max_prefill_chunk_tokens`
+/// per round, so that their steps wait for a chunk instead of a whole prompt; the rest of a prompt
+/// stays queued for the next rounds.
+fn next_round(
+    batches: &mut HashMap<usize, VecDeque<Batch>>,
+    max_batch: usize,
+    max_prefill_chunk_tokens: Option<usize>,
+) -> (Vec<RnnInputBatch>, HashMap<usize, Sender<TensorCpu<f32>>>) {
+    let decoding = batches.values().any(|deque| {
+        deque
+            .front()
+            .is_some_and(|(tokens, _, _)| tokens.len() == 1)
+    });
+    let max_chunk = max_prefill_chunk_tokens.filter(|_| decoding);
+
+    let mut inference = vec![Default::default(); max_batch];
+    let mut senders = HashMap::new();
+
+    for (&batch, deque) in batches.iter_mut() {
+        let Some((tokens, option, sender)) = deque.pop_front() else {
+            continue;
+        };
+        match max_chunk {
+            // only the output of the last chunk is needed
+            Some(max_chunk) if tokens.len() > max_chunk && matches!(option, RnnOption::Last) => {
+                let rest = tokens[max_chunk..].to_vec();
+                let chunk = tokens[..max_chunk].to_vec();
+                inference[batch] = RnnInputBatch::new(chunk, RnnOption::Last);
+                deque.push_front((rest, RnnOption::Last, sender));
+            }
+            _ => {
+                inference[batch] = RnnInputBatch::new(tokens, option);
+                senders.insert(batch, sender);
+            }
+        }
+    }
+    (inference, senders)
+}
+
 async fn infer(
     reload: Arc<ReloadRequest>,
     runtime: Weak<dyn Runtime<Rnn> + Send + Sync>,
     state: Arc<dyn State + Send + Sync>,
     receiver: Receiver<InferBatch>,
 ) -> Result<()> {
-    type Batch = (Vec<u32>, RnnOption, Sender<TensorCpu<f32>>);
     let mut batches: HashMap<usize, VecDeque<Batch>> = HashMap::new();
 
     async fn schedule(
@@ -1289,17 +2869,16 @@ async fn infer(
         }
 
         while batches.values().map(|x| x.len()).sum::<usize>() > 0 {
-            let mut inference = vec![Default::default(); reload.max_batch];
-            let mut senders = HashMap::new();
-
-            for (&batch, deque) in batches.iter_mut() {
-                let Some((tokens, option, sender)) = deque.pop_front() else {
-                    continue;
-                };
-                inference[batch] = RnnInputBatch::new(tokens, option);
-                senders.insert(batch, sender);
+            // decode steps sent during the last round join this one
+            for batch in receiver.drain() {
+                schedule(&mut batches, state.clone(), batch).await?;
             }
 
+            let (inference, senders) = next_round(
+                &mut batches,
+                reload.max_batch,
+                reload.max_prefill_chunk_tokens,
+            );
             let mut inference = Some(RnnInput::new(inference, reload.token_chunk_size));
 
             while inference
@@ -1334,7 +2913,7 @@ async fn infer(
 
 async fn softmax(
     reload: Arc<ReloadRequest>,
-    backend: SoftmaxBackend,
+    backend: Arc<dyn InferenceBackend>,
     receiver: Receiver<SoftmaxBatch>,
 ) -> Result<()> {
     let mut batches = Vec::with_capacity(reload.max_batch);
@@ -1348,20 +2927,7 @@ async fn softmax(
 
         let input: Vec<TensorCpu<f32>> = batches.iter().map(|batch| batch.input.clone()).collect();
 
-        let output = match &backend {
-            SoftmaxBackend::WebGpu(context) => {
-                web_rwkv::runtime::softmax::softmax(context, input).await?
-            }
-            #[cfg(feature = "hip")]
-            SoftmaxBackend::Hip => {
-                // GPU softmax on HIP device -- synchronous but runs in its own task
-                tokio::task::spawn_blocking(move || {
-                    hip_rwkv::hip::softmax_hip_batch(input)
-                        .map_err(|e| anyhow::anyhow!("HIP softmax error: {}", e))
-                })
-                .await??
-            }
-        };
+        let output = backend.softmax(input).await?;
 
         for (batch, tensor) in batches.iter().zip_eq(output.into_iter()) {
             let _ = batch.sender.send(tensor);
@@ -1374,23 +2940,28 @@ async fn softmax(
     Ok(())
 }
 
-pub async fn run(
-    softmax_backend: SoftmaxBackend,
+pub(crate) async fn run(
+    backend: Arc<dyn InferenceBackend>,
     runtime: Weak<dyn Runtime<Rnn> + Send + Sync>,
     state: Arc<dyn State + Send + Sync>,
     receiver: Receiver<GenerateContext>,
+    queue: WeakSender<GenerateContext>,
     RuntimeInfo {
+        name,
         reload,
         info,
         states,
         tokenizer,
         ..
     }: RuntimeInfo,
-) {
+) -> RuntimeMonitor {
+    gauge!(stats::SLOTS, "model" => name.clone()).set(reload.max_batch as f64);
+
     let slots = std::iter::repeat_with(Default::default)
         .take(reload.max_batch)
         .collect();
     let slots = Arc::new(Mutex::new(slots));
+    let activity = Arc::new(std::sync::Mutex::new(vec![None; reload.max_batch]));
 
     let caches = {
         let mut caches = CacheHub::default();
@@ -1404,20 +2975,13 @@ pub async fn run(
             let item = Cache {
                 state: Some(state),
                 cache: Trie::new(),
+                pinned: HashMap::new(),
             };
             caches.backed.insert(id, item);
         }
         Arc::new(Mutex::new(caches))
     };
 
-    // Extract the wgpu Context from the softmax backend if available.
-    // The HIP backend does not have a wgpu context.
-    let context = match &softmax_backend {
-        SoftmaxBackend::WebGpu(ctx) => Some(ctx.clone()),
-        #[cfg(feature = "hip")]
-        SoftmaxBackend::Hip => None,
-    };
-
     let max_batch = reload.max_batch;
     let runtime = {
         let infer = {
@@ -1427,24 +2991,362 @@ pub async fn run(
         };
         let softmax = {
             let (sender, receiver) = flume::unbounded();
-            tokio::spawn(softmax(reload.clone(), softmax_backend, receiver));
+            tokio::spawn(softmax(reload.clone(), backend.clone(), receiver));
             sender
         };
-        let sender = RuntimeSender { infer, softmax };
+        let sender = RuntimeSender {
+            infer,
+            softmax,
+            queue,
+        };
+        let bnf = BnfCache::new(reload.bnf.cache_size);
+        let bnf = Arc::new(std::sync::Mutex::new(bnf));
         CoreRuntime {
-            context,
+            name,
+            backend,
             info,
             reload,
             state,
             sender,
             tokenizer,
             slots,
+            activity,
             caches,
+            traffic: Default::default(),
+            bnf,
         }
     };
-    let timer = Duration::from_secs_f32(1.0);
-    for _ in 0..max_batch {
-        tokio::spawn(enqueue(runtime.clone(), receiver.clone(), timer));
+    if !runtime.reload.bnf.precompile.is_empty() {
+        let cache = runtime.bnf.clone();
+        let tokenizer = runtime.tokenizer.clone();
+        let schemas = runtime.reload.bnf.precompile.clone();
+        tokio::task::spawn_blocking(move || {
+            for schema in schemas {
+                if let Err(err) = BnfSampler::cached(&cache, &tokenizer, &schema) {
+                    tracing::warn!(event = "bnf_precompile_failed", error = %err);
+                }
+            }
+        });
     }
+    let monitor = RuntimeMonitor {
+        name: runtime.name.clone(),
+        max_batch,
+        state: runtime.state.clone(),
+        slots: runtime.slots.clone(),
+        activity: runtime.activity.clone(),
+        caches: runtime.caches.clone(),
+        cache_snapshot: runtime.reload.cache_snapshot.clone(),
+        fingerprint: model_fingerprint(&runtime.reload),
+    };
+    if let Some(option) = &runtime.reload.cache_snapshot {
+        if option.restore {
+            monitor.restore_all().await;
+        }
+        let fingerprint = monitor.fingerprint;
+        let task = snapshot_caches(
+            runtime.clone(),
+            receiver.clone(),
+            option.clone(),
+            fingerprint,
+        );
+        tokio::spawn(task);
+    }
+    let timer = Duration::from_secs_f32(1.0);
+    // a single scheduler sees every waiting request, so that it can share the slots fairly
+    tokio::spawn(enqueue(runtime.clone(), receiver.clone(), timer));
     tokio::spawn(finalize(runtime, receiver, timer));
+    monitor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traffic_share() {
+        let mut traffic = TrafficMeter::default();
+        (0..8).for_each(|_| traffic.record(TrafficClass::Batch));
+        // batch decoding alone takes the full throughput
+        assert!(!traffic.over_share(TrafficClass::Batch, 0.2));
+
+        (0..2).for_each(|_| traffic.record(TrafficClass::Interactive));
+        assert_eq!(traffic.count(TrafficClass::Batch), 8);
+        assert_eq!(traffic.total(), 10);
+        assert!(traffic.over_share(TrafficClass::Batch, 0.2));
+        assert!(!traffic.over_share(TrafficClass::Interactive, 0.8));
+    }
+
+    #[test]
+    fn test_banned_phrases() {
+        let phrases = ["the end".to_string(), String::new()];
+        assert!(contains_phrase(b"and that is the end.", &phrases));
+        assert!(!contains_phrase(b"the ending", &phrases[1..]));
+        assert!(!contains_phrase(b"then", &phrases));
+
+        assert!(ends_with_phrase_start(b"and th", &phrases));
+        assert!(ends_with_phrase_start(b"the e", &phrases));
+        assert!(!ends_with_phrase_start(b"the x", &phrases));
+        assert!(!ends_with_phrase_start(b"", &phrases));
+    }
+
+    #[test]
+    fn test_rewind_output() {
+        // "Say" was sent; " th" may start "the end" and is held back
+        let mut text = b"Say th".to_vec();
+        let mut buffer = b" th".to_vec();
+        assert_eq!(sendable_len(buffer.len(), text.len(), Some(3)), 0);
+        assert_eq!(sendable_len(buffer.len(), text.len(), None), 3);
+
+        // "e end" completes it: back to the checkpoint, dropping the held text
+        text.extend(b"e end");
+        buffer.extend(b"e end");
+        rewind_output(&mut text, &mut buffer, 3);
+        assert_eq!(text, b"Say");
+        assert!(buffer.is_empty());
+
+        // text before the checkpoint still waiting in the buffer is kept
+        let mut text = b"Say th".to_vec();
+        let mut buffer = b"ay th".to_vec();
+        assert_eq!(sendable_len(buffer.len(), text.len(), Some(3)), 2);
+        rewind_output(&mut text, &mut buffer, 3);
+        assert_eq!((&text[..], &buffer[..]), (&b"Say"[..], &b"ay"[..]));
+
+        // text already sent past the checkpoint cannot be rewound from the buffer
+        let mut text = b"Say then".to_vec();
+        let mut buffer = b"en".to_vec();
+        assert_eq!(sendable_len(buffer.len(), text.len(), Some(3)), 0);
+        rewind_output(&mut text, &mut buffer, 3);
+        assert_eq!(text, b"Say");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_state_drift() {
+        let state = TensorCpu::from_data([4, 1, 1, 1], vec![3.0, -3.0, 3.0, -3.0]).unwrap();
+        let rms = state_rms(&state);
+        assert_eq!(rms, 3.0);
+        assert!(is_stable(rms, None));
+        assert!(is_stable(rms, Some(3.0)));
+        assert!(!is_stable(rms, Some(2.0)));
+
+        let state = TensorCpu::from_data([2, 1, 1, 1], vec![1.0, f32::NAN]).unwrap();
+        assert!(!is_stable(state_rms(&state), None));
+        let state = TensorCpu::from_data([2, 1, 1, 1], vec![1.0, f32::INFINITY]).unwrap();
+        assert!(!is_stable(state_rms(&state), Some(f32::MAX)));
+    }
+
+    #[test]
+    fn test_pin_expiry() {
+        let mut cache = Cache::default();
+        cache.pin(&[1, 2, 3]);
+        cache
+            .pinned
+            .insert(vec![4, 5], Instant::now() - Duration::from_secs(1));
+        cache.maintain();
+        assert!(cache.pinned.contains_key([1, 2, 3].as_slice()));
+        assert!(!cache.pinned.contains_key([4, 5].as_slice()));
+
+        cache.abandon(&[1, 2, 3]);
+        assert!(cache.pinned.is_empty());
+    }
+
+    #[test]
+    fn test_pin_eviction() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        for token in 0..MAX_PINNED_ITEMS as u32 {
+            let expiry = now + Duration::from_secs(token as u64);
+            cache.pinned.insert(vec![token], expiry);
+        }
+        // the pin expiring first makes way
+        cache.pin(&[u32::MAX]);
+        assert_eq!(cache.pinned.len(), MAX_PINNED_ITEMS);
+        assert!(!cache.pinned.contains_key([0].as_slice()));
+        assert!(cache.pinned.contains_key([1].as_slice()));
+        assert!(cache.pinned.contains_key([u32::MAX].as_slice()));
+    }
+
+    #[test]
+    fn test_chunked_prefill() {
+        fn push(
+            batches: &mut HashMap<usize, VecDeque<Batch>>,
+            batch: usize,
+            len: u32,
+            option: RnnOption,
+        ) {
+            let (sender, _) = flume::unbounded();
+            let tokens = (0..len).collect();
+            batches
+                .entry(batch)
+                .or_default()
+                .push_back((tokens, option, sender));
+        }
+        let mut batches = HashMap::new();
+
+        // nothing decodes, so prompts are prefilled whole
+        push(&mut batches, 0, 10, RnnOption::Last);
+        push(&mut batches, 1, 10, RnnOption::Full);
+        let (_, senders) = next_round(&mut batches, 4, Some(4));
+        assert_eq!(senders.len(), 2);
+        assert!(batches.values().all(VecDeque::is_empty));
+
+        // the prompt waiting for its last output is cut into chunks, the others are not
+        push(&mut batches, 0, 10, RnnOption::Last);
+        push(&mut batches, 1, 10, RnnOption::Full);
+        push(&mut batches, 2, 1, RnnOption::Last);
+        let (_, senders) = next_round(&mut batches, 4, Some(4));
+        assert!(!senders.contains_key(&0));
+        assert!(senders.contains_key(&1) && senders.contains_key(&2));
+        assert_eq!(batches[&0].front().unwrap().0, (4..10).collect_vec());
+
+        push(&mut batches, 2, 1, RnnOption::Last);
+        let (_, senders) = next_round(&mut batches, 4, Some(4));
+        assert!(!senders.contains_key(&0));
+        assert_eq!(batches[&0].front().unwrap().0, (8..10).collect_vec());
+
+        // once no slot decodes, the rest goes in at once
+        let (_, senders) = next_round(&mut batches, 4, Some(4));
+        assert!(senders.contains_key(&0));
+        assert!(batches.values().all(VecDeque::is_empty));
+
+        // nor is anything cut without a limit
+        push(&mut batches, 0, 10, RnnOption::Last);
+        push(&mut batches, 2, 1, RnnOption::Last);
+        let (_, senders) = next_round(&mut batches, 4, None);
+        assert!(senders.contains_key(&0));
+    }
+
+    #[test]
+    fn test_order_fairly() {
+        let tenant = |name: &str| Tenant::Client(name.into());
+        let mut served = HashMap::new();
+        let mut queue = ["a", "a", "a", "b", "a", "c"]
+            .into_iter()
+            .enumerate()
+            .collect_vec();
+        order_fairly(&mut queue, &mut served, |(_, name)| tenant(name));
+        // tenants take turns, each in order of arrival
+        assert_eq!(
+            queue.iter().map(|(index, _)| *index).collect_vec(),
+            [0, 3, 5, 1, 2, 4]
+        );
+
+        // one that was served more waits for the others to catch up
+        served.insert(tenant("a"), 2);
+        served.insert(tenant("b"), 0);
+        served.remove(&tenant("c"));
+        order_fairly(&mut queue, &mut served, |(_, name)| tenant(name));
+        assert_eq!(
+            queue.iter().map(|(_, name)| *name).collect_vec(),
+            ["b", "c", "a", "a", "a", "a"]
+        );
+        // a tenant that starts waiting is even with the least served one
+        assert_eq!(served[&tenant("c")], 0);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let tensor = |x: f32| TensorCpu::from_data([2, 1, 1, 1], vec![x, -x]).unwrap();
+        let mut cache = Cache::default();
+        let items = vec![
+            SnapshotItem {
+                tokens: vec![1, 2, 3],
+                state: tensor(1.0),
+                output: tensor(2.0),
+            },
+            SnapshotItem {
+                tokens: vec![1, 2],
+                state: tensor(3.0),
+                output: tensor(4.0),
+            },
+        ];
+        assert_eq!(cache.restore(items), 2);
+
+        let items = cache.recent(16);
+        assert_eq!(items.len(), 2);
+        let path = std::env::temp_dir()
+            .join(format!("ai00-snapshot-{}", uuid::Uuid::new_v4()))
+            .join("default.cache");
+        write_snapshot(
+            &path,
+            &Snapshot {
+                fingerprint: 7,
+                items,
+            },
+        )
+        .unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+
+        // snapshots of other model files are not restored
+        assert!(read_snapshot(&data, 8).is_err());
+        let mut restored = Cache::default();
+        assert_eq!(restored.restore(read_snapshot(&data, 7).unwrap()), 2);
+        let item = restored.cache.get([1u32, 2, 3].as_token_slice()).unwrap();
+        let item = item.borrow().clone().unwrap();
+        assert_eq!(item.state.to_vec(), [1.0, -1.0]);
+        assert_eq!(item.output.to_vec(), [2.0, -2.0]);
+        // prompts cached already are kept
+        assert_eq!(restored.restore(read_snapshot(&data, 7).unwrap()), 0);
+    }
+
+    #[test]
+    fn test_snapshot_key() {
+        let state = |name: &str| InitState {
+            name: name.into(),
+            id: StateId::new(),
+            default: false,
+            data: TensorCpu::from_data([1, 1, 1, 1], vec![0.0]).unwrap(),
+        };
+        let id = StateId::new();
+        assert_eq!(Cache::default().snapshot_key(StateId::default()), "default");
+
+        let cache = Cache {
+            state: Some(state("chat.st")),
+            ..Default::default()
+        };
+        // the same on every start, unlike the id of the state
+        assert_eq!(cache.snapshot_key(id), "state.chat.st");
+        assert_eq!(cache.snapshot_key(StateId::default()), "default.chat.st");
+
+        let cache = Cache {
+            state: Some(state("../a b")),
+            ..Default::default()
+        };
+        assert_eq!(cache.snapshot_key(id), "state..._a_b");
+    }
+
+    #[test]
+    fn test_traffic_finish() {
+        let mut traffic = TrafficMeter::default();
+        traffic.start(TrafficClass::Interactive);
+        traffic.start(TrafficClass::Batch);
+        (0..8).for_each(|_| traffic.record(TrafficClass::Interactive));
+        (0..8).for_each(|_| traffic.record(TrafficClass::Batch));
+        assert!(traffic.over_share(TrafficClass::Batch, 0.2));
+
+        // batch is not held back by interactive traffic that went idle
+        traffic.finish(TrafficClass::Interactive);
+        assert_eq!(traffic.count(TrafficClass::Interactive), 0);
+        assert!(!traffic.over_share(TrafficClass::Batch, 0.2));
+        let window = Duration::from_secs(60);
+        assert!(traffic.next_expiry(TrafficClass::Batch, window).is_some());
+        assert!(traffic
+            .next_expiry(TrafficClass::Interactive, window)
+            .is_none());
+    }
+
+    #[test]
+    fn test_traffic_expire() {
+        let mut traffic = TrafficMeter::default();
+        traffic.record(TrafficClass::Batch);
+        traffic.expire(Duration::from_secs(60));
+        assert_eq!(traffic.total(), 1);
+
+        std::thread::sleep(Duration::from_millis(100));
+        traffic.record(TrafficClass::Interactive);
+        traffic.expire(Duration::from_millis(50));
+        assert_eq!(traffic.count(TrafficClass::Batch), 0);
+        assert_eq!(traffic.count(TrafficClass::Interactive), 1);
+    }
 }
@@ -0,0 +1,197 @@
+use derivative::Derivative;
+use itertools::Itertools;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct BeamParams {
+    /// Number of hypotheses kept at every step.
+    #[derivative(Default(value = "4"))]
+    pub beam_width: usize,
+    /// Exponent of the length normalization. Larger values favor longer outputs.
+    #[derivative(Default(value = "1.0"))]
+    pub length_penalty: f32,
+}
+
+/// One hypothesis of a beam search.
+///
+/// `state` is the model state right before the last token in `tokens`, so that the last token
+/// is the next one to be fed into the model.
+#[derive(Debug, Clone)]
+pub struct Beam<S> {
+    pub tokens: Vec<u32>,
+    /// Decoded bytes of `tokens`, which the caller extends as tokens are added.
+    pub text: Vec<u8>,
+    pub log_prob: f32,
+    pub state: S,
+}
+
+impl<S> Beam<S> {
+    pub fn new(state: S) -> Self {
+        Self {
+            tokens: vec![],
+            text: vec![],
+            log_prob: 0.0,
+            state,
+        }
+    }
+
+    /// Length-normalized score used to rank finished hypotheses.
+    pub fn score(&self, length_penalty: f32) -> f32 {
+        let len = self.tokens.len().max(1) as f32;
+        self.log_prob / len.powf(length_penalty)
+    }
+}
+
+/// Bookkeeping of a beam search: the live hypotheses and the finished ones.
+#[derive(Debug, Clone)]
+pub struct BeamSearch<S> {
+    pub params: BeamParams,
+    pub beams: Vec<Beam<S>>,
+    pub finished: Vec<Beam<S>>,
+}
+
+impl<S: Clone> BeamSearch<S> {
+    pub fn new(params: BeamParams, state: S) -> Self {
+        Self {
+            params,
+            beams: vec![Beam::new(state)],
+            finished: vec![],
+        }
+    }
+
+    /// The search is over when no hypothesis is alive or enough of them have finished.
+    pub fn is_done(&self) -> bool {
+        self.beams.is_empty() || self.finished.len() >= self.params.beam_width.max(1)
+    }
+
+    /// Extend every live beam with its most likely tokens and keep the best `beam_width` of them.
+    ///
+    /// `steps` pairs each live beam, in order, with its state after consuming its last token
+    /// and the probabilities of the next token.
+    pub fn step(&mut self, steps: Vec<(S, Vec<f32>)>) {
+        let width = self.params.beam_width.max(1);
+        let beams = std::mem::take(&mut self.beams);
+        self.beams = beams
+            .into_iter()
+            .zip_eq(steps)
+            .flat_map(|(beam, (state, probs))| {
+                probs
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, p)| *p > 0.0)
+                    .k_largest_by(width, |(_, x), (_, y)| x.total_cmp(y))
+                    .map(move |(token, p)| Beam {
+                        tokens: [&beam.tokens[..], &[token as u32]].concat(),
+                        text: beam.text.clone(),
+                        log_prob: beam.log_prob + p.ln(),
+                        state: state.clone(),
+                    })
+                    .collect_vec()
+            })
+            .k_largest_by(width, |x, y| x.log_prob.total_cmp(&y.log_prob))
+            .collect();
+    }
+
+    /// Move the live beams that satisfy `finished` to the finished list.
+    pub fn retire(&mut self, mut finished: impl FnMut(&Beam<S>) -> bool) {
+        let (done, live): (Vec<_>, Vec<_>) = std::mem::take(&mut self.beams)
+            .into_iter()
+            .partition(|beam| finished(beam));
+        self.beams = live;
+        self.finished.extend(done);
+    }
+
+    /// The best hypothesis by length-normalized score, finished or not.
+    pub fn best(self) -> Option<Beam<S>> {
+        let length_penalty = self.params.length_penalty;
+        self.finished
+            .into_iter()
+            .chain(self.beams)
+            .max_by(|x, y| x.score(length_penalty).total_cmp(&y.score(length_penalty)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beam_search(beam_width: usize, length_penalty: f32) -> BeamSearch<()> {
+        let params = BeamParams {
+            beam_width,
+            length_penalty,
+        };
+        BeamSearch::new(params, ())
+    }
+
+    #[test]
+    fn test_step_keeps_most_likely_beams() {
+        let mut search = beam_search(2, 1.0);
+        search.step(vec![((), vec![0.1, 0.6, 0.0, 0.3])]);
+        let tokens = search
+            .beams
+            .iter()
+            .map(|beam| beam.tokens.clone())
+            .collect_vec();
+        assert_eq!(tokens, vec![vec![1], vec![3]]);
+
+        // every beam is extended, and the best two of all extensions survive
+        search.step(vec![
+            ((), vec![0.4, 0.3, 0.0, 0.0]),
+            ((), vec![0.0, 0.0, 0.9, 0.1]),
+        ]);
+        let tokens = search
+            .beams
+            .iter()
+            .map(|beam| beam.tokens.clone())
+            .collect_vec();
+        assert_eq!(tokens, vec![vec![3, 2], vec![1, 0]]);
+        assert!((search.beams[0].log_prob - (0.3f32.ln() + 0.9f32.ln())).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_step_skips_impossible_tokens() {
+        let mut search = beam_search(4, 1.0);
+        search.step(vec![((), vec![0.0, 1.0, 0.0])]);
+        assert_eq!(search.beams.len(), 1);
+        assert_eq!(search.beams[0].tokens, vec![1]);
+    }
+
+    #[test]
+    fn test_retire_and_done() {
+        let mut search = beam_search(2, 1.0);
+        assert!(!search.is_done());
+        search.step(vec![((), vec![0.6, 0.4])]);
+        search.retire(|beam| beam.tokens.last() == Some(&0));
+        assert_eq!(search.finished.len(), 1);
+        assert_eq!(search.beams.len(), 1);
+        assert!(!search.is_done());
+
+        search.retire(|_| true);
+        assert!(search.is_done());
+    }
+
+    #[test]
+    fn test_best_normalizes_length() {
+        let beam = |tokens: Vec<u32>, log_prob: f32| Beam {
+            tokens,
+            text: vec![],
+            log_prob,
+            state: (),
+        };
+        let short = beam(vec![1], -1.0);
+        let long = beam(vec![1, 2, 3, 4], -2.0);
+
+        // without the penalty the raw log probability wins
+        let mut search = beam_search(2, 0.0);
+        search.finished = vec![short.clone(), long.clone()];
+        assert_eq!(search.best().unwrap().tokens, vec![1]);
+
+        // normalized by length, the longer hypothesis scores -0.5 against -1.0
+        let mut search = beam_search(2, 1.0);
+        search.finished = vec![short, long];
+        assert_eq!(search.best().unwrap().tokens, vec![1, 2, 3, 4]);
+    }
+}
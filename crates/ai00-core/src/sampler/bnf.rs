@@ -1,3 +1,10 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::Instant,
+};
+
 use anyhow::Result;
 use kbnf::{
     engine_like::AcceptTokenError, AcceptTokenResult, Engine, EngineLike, Token, Vocabulary,
@@ -9,27 +16,116 @@ use super::Formatter;
 #[derive(Debug)]
 pub struct BnfSampler(Engine);
 
+/// The vocabulary of `tokenizer` in the form KBNF engines take.
+pub(super) fn vocabulary(tokenizer: &Tokenizer) -> Result<Vocabulary> {
+    let tokens = tokenizer
+        .token_index_to_bytes()
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| (k as u32, Token(v.clone().into_boxed_slice())))
+        .collect();
+    let strings = tokenizer
+        .token_index_to_bytes()
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| (k as u32, String::from_utf8_lossy(v).to_string()))
+        .collect();
+    Ok(Vocabulary::new(tokens, strings)?)
+}
+
+fn hash_of(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Compiled grammars, so that requests with a grammar seen before skip compiling it.
+///
+/// Grammars are keyed by their hash and the hash of the vocabulary they are compiled for. The
+/// least recently used are dropped beyond the capacity.
+#[derive(Debug)]
+pub struct BnfCache {
+    capacity: usize,
+    /// The vocabulary of the tokenizer and its hash, built on first use.
+    vocab: Option<(u64, Vocabulary)>,
+    engines: HashMap<(u64, u64), (Engine, Instant)>,
+}
+
+impl BnfCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            vocab: None,
+            engines: HashMap::new(),
+        }
+    }
+
+    /// Number of cached grammars.
+    pub fn len(&self) -> usize {
+        self.engines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.engines.is_empty()
+    }
+
+    fn vocabulary(&mut self, tokenizer: &Tokenizer) -> Result<&(u64, Vocabulary)> {
+        if self.vocab.is_none() {
+            let hash = hash_of(&tokenizer.token_index_to_bytes());
+            self.vocab = Some((hash, vocabulary(tokenizer)?));
+        }
+        Ok(self.vocab.as_ref().unwrap())
+    }
+
+    fn insert(&mut self, key: (u64, u64), engine: Engine) {
+        while self.engines.len() >= self.capacity && !self.engines.contains_key(&key) {
+            let oldest = self
+                .engines
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| *key);
+            match oldest {
+                Some(oldest) => self.engines.remove(&oldest),
+                None => break,
+            };
+        }
+        if self.capacity > 0 {
+            self.engines.insert(key, (engine, Instant::now()));
+        }
+    }
+}
+
 impl BnfSampler {
     pub fn new(tokenizer: &Tokenizer, schema: &str) -> Result<Self> {
-        let tokens = tokenizer
-            .token_index_to_bytes()
-            .iter()
-            .enumerate()
-            .filter(|(_, v)| !v.is_empty())
-            .map(|(k, v)| (k as u32, Token(v.clone().into_boxed_slice())))
-            .collect();
-        let strings = tokenizer
-            .token_index_to_bytes()
-            .iter()
-            .enumerate()
-            .filter(|(_, v)| !v.is_empty())
-            .map(|(k, v)| (k as u32, String::from_utf8_lossy(v).to_string()))
-            .collect();
-        let vocab = Vocabulary::new(tokens, strings)?;
+        let vocab = vocabulary(tokenizer)?;
+        Self::compile(schema, vocab)
+    }
+
+    fn compile(schema: &str, vocab: Vocabulary) -> Result<Self> {
         let mut engine = Engine::new(schema, vocab)?;
         engine.compute_allowed_token_ids();
         Ok(Self(engine))
     }
+
+    /// A sampler for `schema`, copied from `cache` if it was compiled before.
+    ///
+    /// The cache is not locked while compiling, so that other grammars can be taken from it.
+    pub fn cached(cache: &Mutex<BnfCache>, tokenizer: &Tokenizer, schema: &str) -> Result<Self> {
+        let (key, vocab) = {
+            let mut cache = cache.lock().unwrap();
+            let key = (hash_of(&schema), cache.vocabulary(tokenizer)?.0);
+            if let Some((engine, used)) = cache.engines.get_mut(&key) {
+                *used = Instant::now();
+                return Ok(Self(engine.clone()));
+            }
+            (key, cache.vocabulary(tokenizer)?.1.clone())
+        };
+        let sampler = Self::compile(schema, vocab)?;
+        cache.lock().unwrap().insert(key, sampler.0.clone());
+        Ok(sampler)
+    }
 }
 
 impl Formatter for BnfSampler {
@@ -51,4 +147,8 @@ impl Formatter for BnfSampler {
         self.0.compute_allowed_token_ids();
         halt
     }
+
+    fn describe(&self) -> String {
+        self.0.to_string()
+    }
 }
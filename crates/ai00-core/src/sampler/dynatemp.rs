@@ -0,0 +1,133 @@
+use anyhow::Result;
+use derivative::Derivative;
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    filter,
+    nucleus::{NucleusParams, NucleusSampler},
+    Sampler, SamplerAdjustment,
+};
+
+#[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct DynaTempParams {
+    /// Parameters of the wrapped nucleus sampler. Its `temperature` is the center of the range.
+    #[serde(flatten)]
+    pub nucleus: NucleusParams,
+    /// Temperature varies within `temperature ± dynatemp_range`. Disabled at 0.
+    #[derivative(Default(value = "0.0"))]
+    pub dynatemp_range: f32,
+    /// Shape of the mapping from normalized entropy to temperature.
+    #[derivative(Default(value = "1.0"))]
+    pub dynatemp_exponent: f32,
+}
+
+/// Nucleus sampling with a temperature scaled by the entropy of each step's distribution:
+/// confident steps are sampled cold and uncertain ones hot.
+#[derive(Debug, Default, Clone)]
+pub struct DynaTempSampler {
+    pub inner: NucleusSampler,
+    pub dynatemp_range: f32,
+    pub dynatemp_exponent: f32,
+}
+
+impl DynaTempSampler {
+    pub fn new(params: DynaTempParams) -> Self {
+        let DynaTempParams {
+            nucleus,
+            dynatemp_range,
+            dynatemp_exponent,
+        } = params;
+        Self {
+            inner: NucleusSampler::new(nucleus),
+            dynatemp_range,
+            dynatemp_exponent,
+        }
+    }
+
+    /// Temperature of a step with distribution `probs`, given the base temperature.
+    fn temperature(&self, probs: &[f32], temperature: f32) -> f32 {
+        let count = probs.iter().filter(|&&x| x > 0.0).count();
+        if self.dynatemp_range <= 0.0 || count < 2 {
+            return temperature;
+        }
+        let min = (temperature - self.dynatemp_range).max(f32::EPSILON);
+        let max = temperature + self.dynatemp_range;
+        let entropy = filter::entropy(probs) / (count as f32).ln();
+        let entropy = entropy.clamp(0.0, 1.0);
+        min + (max - min) * entropy.powf(self.dynatemp_exponent)
+    }
+}
+
+impl Sampler for DynaTempSampler {
+    fn init(&mut self, model_tokens: &[u32]) {
+        self.inner.init(model_tokens);
+    }
+
+    fn transform(&self, output: &mut [f32]) {
+        self.inner.transform(output);
+    }
+
+    fn sample(&mut self, probs: &[f32]) -> u32 {
+        let temperature = self.inner.params.temperature;
+        self.inner.params.temperature = self.temperature(probs, temperature);
+        let token = self.inner.sample(probs);
+        self.inner.params.temperature = temperature;
+        token
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.inner.seed(seed);
+    }
+
+    fn adjust(&mut self, adjustment: &SamplerAdjustment) -> Result<()> {
+        self.inner.adjust(adjustment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler(dynatemp_range: f32, dynatemp_exponent: f32) -> DynaTempSampler {
+        DynaTempSampler::new(DynaTempParams {
+            dynatemp_range,
+            dynatemp_exponent,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_temperature_follows_entropy() {
+        let sampler = sampler(0.5, 1.0);
+        // a uniform distribution has the largest entropy and is sampled hottest
+        assert!((sampler.temperature(&[0.25; 4], 1.0) - 1.5).abs() < 1e-5);
+        // a confident one is sampled close to the coldest
+        let cold = sampler.temperature(&[0.97, 0.01, 0.01, 0.01], 1.0);
+        assert!(cold > 0.5 && cold < 0.75);
+    }
+
+    #[test]
+    fn test_temperature_exponent() {
+        let probs = [0.7, 0.1, 0.1, 0.1];
+        let linear = sampler(0.5, 1.0).temperature(&probs, 1.0);
+        let convex = sampler(0.5, 2.0).temperature(&probs, 1.0);
+        assert!(convex < linear);
+    }
+
+    #[test]
+    fn test_temperature_disabled() {
+        let probs = [0.25; 4];
+        assert_eq!(sampler(0.0, 1.0).temperature(&probs, 0.8), 0.8);
+        // one possible token has no entropy to measure
+        assert_eq!(sampler(0.5, 1.0).temperature(&[1.0, 0.0], 0.8), 0.8);
+    }
+
+    #[test]
+    fn test_temperature_stays_positive() {
+        let temperature = sampler(2.0, 1.0).temperature(&[0.999, 0.001], 0.5);
+        assert!(temperature > 0.0);
+    }
+}
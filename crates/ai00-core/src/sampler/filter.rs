@@ -0,0 +1,94 @@
+//! Filters over candidate tokens, applied after sorting them by probability.
+
+use itertools::Itertools;
+
+/// Entropy (in nats) of a probability distribution.
+pub fn entropy(probs: &[f32]) -> f32 {
+    probs
+        .iter()
+        .filter(|&&x| x > 0.0)
+        .map(|&x| -x * x.ln())
+        .sum()
+}
+
+/// Keep candidates whose probability is at least `min_p` times that of the most likely one.
+///
+/// `candidates` are `(token, probability)` pairs sorted by descending probability.
+pub fn min_p(candidates: &mut Vec<(usize, f32)>, min_p: f32) {
+    let Some(&(_, max)) = candidates.first() else {
+        return;
+    };
+    if min_p <= 0.0 {
+        return;
+    }
+    let threshold = max * min_p;
+    candidates.retain(|&(_, x)| x >= threshold);
+}
+
+/// Locally typical filtering: keep the candidates whose surprisal is closest to `entropy`,
+/// until their total probability reaches `typical_p`. The order of `candidates` is preserved.
+pub fn typical_p(candidates: &mut Vec<(usize, f32)>, entropy: f32, typical_p: f32) {
+    if typical_p >= 1.0 || candidates.is_empty() {
+        return;
+    }
+    let deviation = |x: f32| (-x.ln() - entropy).abs();
+    let order = (0..candidates.len())
+        .sorted_by(|&a, &b| deviation(candidates[a].1).total_cmp(&deviation(candidates[b].1)));
+
+    let mut keep = vec![false; candidates.len()];
+    let mut cum = 0.0;
+    for index in order {
+        keep[index] = true;
+        cum += candidates[index].1;
+        if cum >= typical_p {
+            break;
+        }
+    }
+
+    let mut keep = keep.into_iter();
+    candidates.retain(|_| keep.next().unwrap_or_default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy(&[1.0, 0.0]), 0.0);
+        let uniform = entropy(&[0.25; 4]);
+        assert!((uniform - 4f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_min_p() {
+        let mut candidates = vec![(3, 0.5), (1, 0.3), (0, 0.15), (2, 0.05)];
+        min_p(&mut candidates, 0.2);
+        assert_eq!(candidates, vec![(3, 0.5), (1, 0.3), (0, 0.15)]);
+
+        // disabled at 0
+        let mut candidates = vec![(0, 0.9), (1, 0.1)];
+        min_p(&mut candidates, 0.0);
+        assert_eq!(candidates.len(), 2);
+
+        let mut candidates = vec![];
+        min_p(&mut candidates, 0.5);
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn test_typical_p() {
+        let probs = [0.5, 0.3, 0.15, 0.05];
+        let entropy = entropy(&probs);
+        let mut candidates = probs.into_iter().enumerate().collect_vec();
+        typical_p(&mut candidates, entropy, 0.5);
+        // surprisals are 0.69, 1.20, 1.90, 3.00 against an entropy of 1.14: the second and
+        // first candidates are the most typical, and already reach the mass of 0.5
+        assert_eq!(candidates, vec![(0, 0.5), (1, 0.3)]);
+
+        // disabled at 1
+        let mut candidates = probs.into_iter().enumerate().collect_vec();
+        typical_p(&mut candidates, entropy, 1.0);
+        assert_eq!(candidates.len(), 4);
+    }
+}
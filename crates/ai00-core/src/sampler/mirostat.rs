@@ -1,4 +1,5 @@
-use super::{radix, Sampler};
+use super::{radix, Sampler, SamplerAdjustment};
+use anyhow::{bail, Result};
 use derivative::Derivative;
 use itertools::Itertools;
 use salvo::oapi::ToSchema;
@@ -19,6 +20,7 @@ pub struct MirostatParams {
 #[derive(Debug, Clone, Default)]
 pub struct MirostatState {
     pub max_surprise: f32,
+    pub rng: fastrand::Rng,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -31,6 +33,7 @@ impl MirostatSampler {
     pub fn new(params: MirostatParams) -> Self {
         let state = MirostatState {
             max_surprise: params.tau * 2.0,
+            rng: Default::default(),
         };
         Self { params, state }
     }
@@ -75,7 +78,7 @@ impl Sampler for MirostatSampler {
 
         // normalize the probs
         let sum = sorted.last().map(|(_, x, _)| *x).unwrap();
-        let rand = fastrand::f32() * sum;
+        let rand = state.rng.f32() * sum;
         let (token, _, prob) = sorted
             .into_iter()
             .find_or_first(|&(_, cum, _)| rand <= cum)
@@ -88,4 +91,26 @@ impl Sampler for MirostatSampler {
 
         token as u32
     }
+
+    fn seed(&mut self, seed: u64) {
+        self.state.rng.seed(seed);
+    }
+
+    fn adjust(&mut self, adjustment: &SamplerAdjustment) -> Result<()> {
+        let SamplerAdjustment {
+            tau,
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+        } = adjustment
+        else {
+            bail!("mirostat sampler only supports adjusting tau");
+        };
+        if let Some(tau) = *tau {
+            self.params.tau = tau;
+        }
+        Ok(())
+    }
 }
@@ -1,10 +1,18 @@
+pub mod beam;
 pub mod bnf;
+pub mod dynatemp;
+pub mod filter;
 pub mod mirostat;
 pub mod nucleus;
+pub mod regex;
 pub mod typical;
 
 mod radix;
 
+use anyhow::{bail, Result};
+use salvo::oapi::ToSchema;
+use serde::{Deserialize, Serialize};
+
 pub trait Sampler {
     /// Initialize the sampler state.
     fn init(&mut self, model_tokens: &[u32]);
@@ -12,6 +20,61 @@ pub trait Sampler {
     fn transform(&self, output: &mut [f32]);
     /// Select one token from the distribution, and also update the state.
     fn sample(&mut self, probs: &[f32]) -> u32;
+    /// Seed the random number generator, so that the same inputs always sample the same tokens.
+    fn seed(&mut self, _seed: u64) {}
+    /// Change parameters of a sampler that may already be in use.
+    /// Fails without changing anything if a parameter does not apply to this sampler.
+    fn adjust(&mut self, _adjustment: &SamplerAdjustment) -> Result<()> {
+        bail!("sampler does not support adjustment")
+    }
+}
+
+/// Sampler parameters that can be changed while a generation is in flight.
+///
+/// Fields left as `None` are not touched. Changes take effect from the next sampled token,
+/// so a generation adjusted midway cannot be reproduced by a single request.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct SamplerAdjustment {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<usize>,
+    pub tau: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+impl SamplerAdjustment {
+    /// Check that all given values are in range.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(x) = self.temperature {
+            if !(x.is_finite() && x > 0.0) {
+                bail!("temperature must be positive");
+            }
+        }
+        if let Some(x) = self.top_p {
+            if !(x > 0.0 && x <= 1.0) {
+                bail!("top_p must be in (0, 1]");
+            }
+        }
+        if self.top_k == Some(0) {
+            bail!("top_k must be at least 1");
+        }
+        if let Some(x) = self.tau {
+            if !(x.is_finite() && x > 0.0) {
+                bail!("tau must be positive");
+            }
+        }
+        for (name, x) in [
+            ("presence_penalty", self.presence_penalty),
+            ("frequency_penalty", self.frequency_penalty),
+        ] {
+            if x.is_some_and(|x| !x.is_finite()) {
+                bail!("{name} must be finite");
+            }
+        }
+        Ok(())
+    }
 }
 
 pub trait Formatter {
@@ -19,4 +82,8 @@ pub trait Formatter {
     fn transform(&self, output: &mut [f32]);
     /// Update the internal state after a token is chosen. Return if the state machine is halt.
     fn update(&mut self, token: u32) -> bool;
+    /// Describe the internal state, for reports of outputs that it blocked completely.
+    fn describe(&self) -> String {
+        String::new()
+    }
 }
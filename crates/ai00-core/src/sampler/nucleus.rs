@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use super::{radix, Sampler};
+use super::{filter, radix, Sampler, SamplerAdjustment};
+use anyhow::{bail, Result};
 use derivative::Derivative;
 use itertools::Itertools;
 use salvo::oapi::ToSchema;
@@ -17,17 +18,42 @@ pub struct NucleusParams {
     pub top_k: usize,
     #[derivative(Default(value = "1.0"))]
     pub temperature: f32,
+    /// Drop tokens less likely than `min_p` times the most likely one. Disabled at 0.
+    #[derivative(Default(value = "0.0"))]
+    pub min_p: f32,
+    /// Keep only the most locally typical tokens up to this mass. Disabled at 1.
+    #[derivative(Default(value = "1.0"))]
+    pub typical_p: f32,
     #[derivative(Default(value = "0.3"))]
     pub presence_penalty: f32,
     #[derivative(Default(value = "0.3"))]
     pub frequency_penalty: f32,
     #[derivative(Default(value = "0.99654026"))]
     pub penalty_decay: f32,
+    /// Divide positive logits (multiply negative ones) of recent tokens by this. Disabled at 1.
+    /// The penalty of a token fades by `penalty_decay` with each token since it last appeared.
+    #[derivative(Default(value = "1.0"))]
+    pub repetition_penalty: f32,
+    /// Number of most recent tokens the repetition penalty looks at.
+    #[derivative(Default(value = "64"))]
+    pub repetition_window: usize,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct NucleusState {
     pub penalties: HashMap<u32, f32>,
+    /// The last `repetition_window` tokens, most recent at the back.
+    pub recent: VecDeque<u32>,
+    pub rng: fastrand::Rng,
+}
+
+impl NucleusState {
+    fn push_recent(&mut self, token: u32, window: usize) {
+        self.recent.push_back(token);
+        while self.recent.len() > window {
+            self.recent.pop_front();
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -56,6 +82,12 @@ impl Sampler for NucleusSampler {
             penalty += af * ad.powf(index as f32);
             state.penalties.insert(*token, penalty);
         }
+
+        let window = params.repetition_window;
+        let skip = model_tokens.len().saturating_sub(window);
+        for &token in &model_tokens[skip..] {
+            state.push_recent(token, window);
+        }
     }
 
     fn transform(&self, output: &mut [f32]) {
@@ -63,7 +95,29 @@ impl Sampler for NucleusSampler {
             .penalties
             .iter()
             // .filter(|(token, _)| !penalty_free_tokens.contains(token))
-            .for_each(|(token, penalty)| output[*token as usize] -= penalty)
+            .for_each(|(token, penalty)| output[*token as usize] -= penalty);
+
+        let NucleusSampler { params, state } = self;
+        if params.repetition_penalty == 1.0 {
+            return;
+        }
+        let mut seen = HashSet::new();
+        for (age, &token) in state.recent.iter().rev().enumerate() {
+            // only the most recent occurrence counts
+            if !seen.insert(token) {
+                continue;
+            }
+            let Some(logit) = output.get_mut(token as usize) else {
+                continue;
+            };
+            let penalty = params
+                .repetition_penalty
+                .powf(params.penalty_decay.powi(age as i32));
+            match *logit > 0.0 {
+                true => *logit /= penalty,
+                false => *logit *= penalty,
+            }
+        }
     }
 
     fn sample(&mut self, probs: &[f32]) -> u32 {
@@ -75,11 +129,17 @@ impl Sampler for NucleusSampler {
             .map(|(id, x)| radix::F32WithIndex(id, x))
             .collect_vec();
         sorted.voracious_sort();
-        let sorted = sorted
+        let mut sorted = sorted
             .into_iter()
             .rev()
             .take(params.top_k)
-            .scan((0, 0.0, 0.0), |(_, cum, _), radix::F32WithIndex(id, x)| {
+            .map(|radix::F32WithIndex(id, x)| (id, x))
+            .collect_vec();
+        filter::typical_p(&mut sorted, filter::entropy(probs), params.typical_p);
+        filter::min_p(&mut sorted, params.min_p);
+        let sorted = sorted
+            .into_iter()
+            .scan((0, 0.0, 0.0), |(_, cum, _), (id, x)| {
                 if *cum > params.top_p {
                     None
                 } else {
@@ -99,7 +159,7 @@ impl Sampler for NucleusSampler {
                 Some((id, *cum))
             })
             .collect_vec();
-        let rand = fastrand::f32();
+        let rand = state.rng.f32();
         let token = sorted
             .into_iter()
             .find_or_first(|&(_, cum)| rand <= cum)
@@ -117,7 +177,35 @@ impl Sampler for NucleusSampler {
             None => params.presence_penalty,
         };
         state.penalties.insert(token, penalty);
+        state.push_recent(token, params.repetition_window);
 
         token
     }
+
+    fn seed(&mut self, seed: u64) {
+        self.state.rng.seed(seed);
+    }
+
+    fn adjust(&mut self, adjustment: &SamplerAdjustment) -> Result<()> {
+        if adjustment.tau.is_some() {
+            bail!("nucleus sampler does not use tau");
+        }
+        let params = &mut self.params;
+        if let Some(x) = adjustment.temperature {
+            params.temperature = x;
+        }
+        if let Some(x) = adjustment.top_p {
+            params.top_p = x;
+        }
+        if let Some(x) = adjustment.top_k {
+            params.top_k = x;
+        }
+        if let Some(x) = adjustment.presence_penalty {
+            params.presence_penalty = x;
+        }
+        if let Some(x) = adjustment.frequency_penalty {
+            params.frequency_penalty = x;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use kbnf::{AcceptTokenError, AcceptTokenResult, Engine, EngineLike};
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::{bnf::vocabulary, Formatter};
+
+/// Constrains the output to match a regular expression.
+///
+/// The expression compiles to a KBNF grammar with a single regex rule, so the engine walks
+/// its automaton. As with [`super::bnf::BnfSampler`], a complete match may go on as long as the
+/// expression allows it (`a+` keeps accepting `a`); generation only halts once no token can follow.
+#[derive(Debug)]
+pub struct RegexSampler(Engine);
+
+impl RegexSampler {
+    /// Whether the match is complete and cannot be extended by any token.
+    fn exhausted(&self) -> bool {
+        self.0.is_finished()
+            && self
+                .0
+                .allowed_token_ids_from_last_computation()
+                .count_ones(..)
+                == 0
+    }
+
+    pub fn new(tokenizer: &Tokenizer, regex: &str) -> Result<Self> {
+        let schema = format!("start::=#'{}';", escape(regex));
+        let vocab = vocabulary(tokenizer)?;
+        let mut engine = Engine::new(&schema, vocab)?;
+        engine.compute_allowed_token_ids();
+        Ok(Self(engine))
+    }
+}
+
+/// Escape a regex for a single-quoted KBNF string.
+fn escape(regex: &str) -> String {
+    regex.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+impl Formatter for RegexSampler {
+    fn transform(&self, output: &mut [f32]) {
+        let output = &mut output[..self.0.vocab().vocab_size()];
+        self.0.mask_logits(output).expect("regex transform error")
+    }
+
+    fn update(&mut self, token: u32) -> bool {
+        let rejected = match self.0.try_accept_new_token(token) {
+            Ok(AcceptTokenResult::Finished) => false,
+            Ok(AcceptTokenResult::Ongoing) => false,
+            Err(AcceptTokenError::Finished) => false,
+            Err(_) => true,
+        };
+        self.0.compute_allowed_token_ids();
+        rejected || self.exhausted()
+    }
+
+    fn describe(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../assets/tokenizer/rwkv_vocab_v20230424.json"
+        );
+        Tokenizer::new(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn token(tokenizer: &Tokenizer, text: &str) -> u32 {
+        let tokens = tokenizer.encode(text.as_bytes()).unwrap();
+        assert_eq!(tokens.len(), 1);
+        tokens[0]
+    }
+
+    #[test]
+    fn test_open_quantifier() {
+        let tokenizer = tokenizer();
+        let a = token(&tokenizer, "a");
+        let mut sampler = RegexSampler::new(&tokenizer, "a+").unwrap();
+        // every `a` completes a match, but more may follow
+        for _ in 0..4 {
+            assert!(!sampler.update(a));
+        }
+
+        let mut sampler = RegexSampler::new(&tokenizer, "[0-9]*x").unwrap();
+        assert!(!sampler.update(token(&tokenizer, "1")));
+        assert!(!sampler.update(token(&tokenizer, "2")));
+        assert!(sampler.update(token(&tokenizer, "x")));
+    }
+
+    #[test]
+    fn test_closed_match() {
+        let tokenizer = tokenizer();
+        let mut sampler = RegexSampler::new(&tokenizer, "ab").unwrap();
+        assert!(!sampler.update(token(&tokenizer, "a")));
+        assert!(sampler.update(token(&tokenizer, "b")));
+    }
+
+    #[test]
+    fn test_rejected_token() {
+        let tokenizer = tokenizer();
+        let mut sampler = RegexSampler::new(&tokenizer, "a+").unwrap();
+        assert!(!sampler.update(token(&tokenizer, "a")));
+        assert!(sampler.update(token(&tokenizer, "b")));
+    }
+}
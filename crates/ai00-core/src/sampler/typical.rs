@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 
+use anyhow::{bail, Result};
 use derivative::Derivative;
 use itertools::Itertools;
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
 use voracious_radix_sort::RadixSort;
 
-use super::{radix, Sampler};
+use super::{radix, Sampler, SamplerAdjustment};
 
 #[derive(Debug, Clone, Derivative, Serialize, Deserialize, ToSchema)]
 #[derivative(Default)]
@@ -29,6 +30,7 @@ pub struct TypicalParams {
 #[derive(Debug, Default, Clone)]
 pub struct TypicalState {
     pub penalties: HashMap<u32, f32>,
+    pub rng: fastrand::Rng,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -107,7 +109,7 @@ impl Sampler for TypicalSampler {
             })
             .collect_vec();
 
-        let rand = fastrand::f32();
+        let rand = state.rng.f32();
         let token = sorted
             .into_iter()
             .find_or_first(|&(_, cum)| rand <= cum)
@@ -128,4 +130,31 @@ impl Sampler for TypicalSampler {
 
         token
     }
+
+    fn seed(&mut self, seed: u64) {
+        self.state.rng.seed(seed);
+    }
+
+    fn adjust(&mut self, adjustment: &SamplerAdjustment) -> Result<()> {
+        if adjustment.top_p.is_some() {
+            bail!("typical sampler does not use top_p");
+        }
+        let params = &mut self.params;
+        if let Some(x) = adjustment.temperature {
+            params.temperature = x;
+        }
+        if let Some(x) = adjustment.tau {
+            params.tau = x;
+        }
+        if let Some(x) = adjustment.top_k {
+            params.top_k = x;
+        }
+        if let Some(x) = adjustment.presence_penalty {
+            params.presence_penalty = x;
+        }
+        if let Some(x) = adjustment.frequency_penalty {
+            params.frequency_penalty = x;
+        }
+        Ok(())
+    }
 }
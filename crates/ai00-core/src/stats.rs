@@ -0,0 +1,103 @@
+//! Metrics recorded through the `metrics` facade.
+//!
+//! Nothing is recorded unless the application installs a recorder,
+//! such as the Prometheus exporter of the server.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+
+pub const PROMPT_TOKENS: &str = "ai00_prompt_tokens_total";
+pub const GENERATED_TOKENS: &str = "ai00_generated_tokens_total";
+pub const CACHE_HIT_TOKENS: &str = "ai00_cache_hit_tokens_total";
+pub const QUEUE_WAIT_SECONDS: &str = "ai00_queue_wait_seconds";
+pub const PREFILL_SECONDS: &str = "ai00_prefill_seconds";
+pub const DECODE_SECONDS: &str = "ai00_decode_seconds";
+pub const SLOTS: &str = "ai00_slots";
+pub const SLOTS_BUSY: &str = "ai00_slots_busy";
+pub const QUEUE_DEPTH: &str = "ai00_queue_depth";
+pub const ADAPTER_INFO: &str = "ai00_adapter_info";
+
+/// Generations waiting for a slot across all models, counted even without a recorder.
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of generations waiting for a slot across all models.
+pub fn queued() -> usize {
+    QUEUED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn queue_push() {
+    QUEUED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn queue_pop() {
+    QUEUED.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Generations accepted by the runtime and not yet finished, queued or in a slot.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of generations accepted by the runtime and not yet finished, across all models.
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+/// Counts a generation as in flight for as long as it is alive.
+#[derive(Debug)]
+pub(crate) struct InFlight(());
+
+impl InFlight {
+    pub fn new() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Clone for InFlight {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Register the descriptions of the metrics above with the installed recorder.
+pub fn describe() {
+    describe_counter!(
+        PROMPT_TOKENS,
+        Unit::Count,
+        "Prompt tokens of finished generations."
+    );
+    describe_counter!(GENERATED_TOKENS, Unit::Count, "Tokens generated.");
+    describe_counter!(
+        CACHE_HIT_TOKENS,
+        Unit::Count,
+        "Prompt tokens served from the prompt cache instead of being prefilled."
+    );
+    describe_histogram!(
+        QUEUE_WAIT_SECONDS,
+        Unit::Seconds,
+        "Time generations waited for a slot."
+    );
+    describe_histogram!(
+        PREFILL_SECONDS,
+        Unit::Seconds,
+        "Prefill time of generations."
+    );
+    describe_histogram!(DECODE_SECONDS, Unit::Seconds, "Decode time of generations.");
+    describe_gauge!(
+        SLOTS,
+        Unit::Count,
+        "Slots of the runtime, i.e. `max_batch`."
+    );
+    describe_gauge!(SLOTS_BUSY, Unit::Count, "Slots processing a generation.");
+    describe_gauge!(QUEUE_DEPTH, Unit::Count, "Generations waiting for a slot.");
+    describe_gauge!(
+        ADAPTER_INFO,
+        "GPU adapter the model runs on, as labels. The value is always 1."
+    );
+}
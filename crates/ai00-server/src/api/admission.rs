@@ -0,0 +1,235 @@
+//! Admission control of generation requests.
+//!
+//! At most `admission.max_concurrent_requests` generation requests are handled at once.
+//! Others wait for one of them to finish, but no longer than `admission.max_queue_wait_ms`;
+//! then they are rejected with `overloaded_error` instead of piling up in front of the runtime.
+//!
+//! Before shutting down, on `SIGTERM` or `POST /admin/drain`, the server drains: it admits no more
+//! generation requests and waits up to `admission.drain_timeout` for in-flight ones to finish.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use ai00_core::{GenerateRequest, StopHook, TokenCounter};
+use salvo::prelude::*;
+use serde::Serialize;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use super::{error::ApiErrorResponse, usage::Attribution};
+use crate::config::AdmissionOption;
+
+/// Interval between checks whether in-flight generations finished while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct Admission {
+    semaphore: Option<Arc<Semaphore>>,
+    max_concurrent_requests: usize,
+    max_queue_wait: Duration,
+    drain_timeout: Duration,
+    draining: Arc<AtomicBool>,
+    drain_requested: Arc<Notify>,
+}
+
+impl Admission {
+    pub fn new(option: &AdmissionOption) -> Self {
+        Self {
+            semaphore: option
+                .max_concurrent_requests
+                .map(|permits| Arc::new(Semaphore::new(permits))),
+            max_concurrent_requests: option.max_concurrent_requests.unwrap_or_default(),
+            max_queue_wait: Duration::from_millis(option.max_queue_wait_ms),
+            drain_timeout: Duration::from_secs(option.drain_timeout),
+            draining: Default::default(),
+            drain_requested: Default::default(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Stop admitting requests and wake up [`Self::drain_requested`].
+    pub fn request_drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.drain_requested.notify_one();
+    }
+
+    /// Resolve once a drain is requested through [`Self::request_drain`].
+    pub async fn drain_requested(&self) {
+        self.drain_requested.notified().await
+    }
+
+    /// Stop admitting requests and wait for in-flight ones to finish, at most `drain_timeout`.
+    /// Returns the number of generations still in flight when it gave up.
+    pub async fn drain(&self) -> usize {
+        self.draining.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + self.drain_timeout;
+        loop {
+            let pending = self.in_flight().max(ai00_core::stats::in_flight());
+            if pending == 0 || Instant::now() >= deadline {
+                return pending;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Number of requests being handled.
+    pub fn in_flight(&self) -> usize {
+        match &self.semaphore {
+            Some(semaphore) => self.max_concurrent_requests - semaphore.available_permits(),
+            None => 0,
+        }
+    }
+
+    /// Wait for a permit to handle a request. `None` if there is no limit.
+    pub async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, ApiErrorResponse> {
+        if self.is_draining() {
+            return Err(ApiErrorResponse::overloaded("server is shutting down"));
+        }
+        let Some(semaphore) = &self.semaphore else {
+            return Ok(None);
+        };
+        match tokio::time::timeout(self.max_queue_wait, semaphore.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            Ok(Err(_)) => Err(ApiErrorResponse::api_error("admission is closed")),
+            Err(_) => Err(ApiErrorResponse::overloaded(format!(
+                "server is at capacity with {} requests in progress and {} generations waiting for a slot",
+                self.max_concurrent_requests,
+                ai00_core::stats::queued(),
+            ))),
+        }
+    }
+}
+
+/// A stop hook holding `permit`, which is released once every generation carrying it is done
+/// and the request is no longer able to send any.
+fn hold(permit: OwnedSemaphorePermit) -> StopHook {
+    let permit = Arc::new(permit);
+    Arc::new(move |_: &GenerateRequest, _: &TokenCounter| {
+        let _ = &permit;
+    })
+}
+
+/// Wait for a permit to generate, which the generations of the request of `depot` then hold.
+///
+/// Requests answering several generations, such as batches and WebSocket sessions, call this
+/// for each of them instead of going through [`admit`].
+pub async fn admit_generation(depot: &mut Depot) -> Result<(), ApiErrorResponse> {
+    #[cfg(feature = "chaos")]
+    if let Some(err) = super::chaos::slot_error(depot).await {
+        return Err(err);
+    }
+    let Ok(admission) = depot.obtain::<Admission>().cloned() else {
+        return Ok(());
+    };
+    if let Some(permit) = admission.acquire().await? {
+        Attribution::add_hook(depot, hold(permit));
+    }
+    Ok(())
+}
+
+/// Admit generation requests within the configured concurrency, or reject them with
+/// `overloaded_error` once they waited too long.
+#[handler]
+pub async fn admit(depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    if let Err(err) = admit_generation(depot).await {
+        err.respond(res);
+        ctrl.skip_rest();
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DrainResponse {
+    pub draining: bool,
+    /// Generations accepted by the runtime and not yet finished.
+    pub in_flight: usize,
+}
+
+/// Stop admitting generation requests, let in-flight ones finish and then shut down.
+///
+/// `/admin/drain`.
+#[endpoint(responses((status_code = 202, body = DrainResponse)))]
+pub async fn drain(depot: &mut Depot, res: &mut Response) {
+    let Ok(admission) = depot.obtain::<Admission>() else {
+        ApiErrorResponse::api_error("admission control is not available").respond(res);
+        return;
+    };
+    admission.request_drain();
+    res.status_code(StatusCode::ACCEPTED)
+        .render(Json(DrainResponse {
+            draining: true,
+            in_flight: ai00_core::stats::in_flight(),
+        }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rejects_after_queue_wait() {
+        let admission = Admission::new(&AdmissionOption {
+            max_concurrent_requests: Some(1),
+            max_queue_wait_ms: 10,
+            ..Default::default()
+        });
+        let permit = admission.acquire().await.unwrap();
+        assert!(permit.is_some());
+        assert_eq!(admission.in_flight(), 1);
+        assert!(admission.acquire().await.is_err());
+
+        drop(permit);
+        assert_eq!(admission.in_flight(), 0);
+        assert!(admission.acquire().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_admits_each_generation() {
+        let admission = Admission::new(&AdmissionOption {
+            max_concurrent_requests: Some(1),
+            max_queue_wait_ms: 10,
+            ..Default::default()
+        });
+        let depot = || {
+            let mut depot = Depot::new();
+            depot.inject(admission.clone());
+            depot
+        };
+
+        let mut first = depot();
+        assert!(admit_generation(&mut first).await.is_ok());
+        assert_eq!(admission.in_flight(), 1);
+        // the permit is held by the generations of the first request
+        assert!(admit_generation(&mut depot()).await.is_err());
+
+        let mut request = GenerateRequest::default();
+        Attribution::of(&first).apply(&mut request);
+        drop(first);
+        assert_eq!(admission.in_flight(), 1);
+        drop(request);
+        assert_eq!(admission.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_while_draining() {
+        let admission = Admission::new(&AdmissionOption::default());
+        admission.request_drain();
+        assert!(admission.is_draining());
+        assert!(admission.acquire().await.is_err());
+        // resolves immediately, as the drain was requested before
+        admission.drain_requested().await;
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_without_max_concurrent_requests() {
+        let admission = Admission::new(&AdmissionOption::default());
+        assert!(admission.acquire().await.unwrap().is_none());
+        assert_eq!(admission.in_flight(), 0);
+    }
+}
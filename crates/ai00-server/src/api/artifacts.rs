@@ -0,0 +1,495 @@
+//! Management of the files in `model.path`: models, LoRAs, initial states, prefab saves and
+//! unfinished uploads, which pile up on a long-running server.
+//!
+//! `GET /admin/files` lists them with their sizes and when they were last used, least recently
+//! used first. A file is used when a model is loaded with it, when it is saved, and by every
+//! generation of a model loaded with it; uses are tracked in memory, so they start over with the
+//! server. `DELETE /admin/files?name=` deletes a file, but never one a loaded model uses, and
+//! one the config refers to only with `force`, since the server would not start without it.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ai00_core::{
+    loader::gguf, reload::Lora, GenerateRequest, ReloadRequest, RuntimeInfo, StopHook,
+    TokenCounter,
+};
+use memmap2::Mmap;
+use safetensors::SafeTensors;
+use salvo::{oapi::extract::QueryParam, prelude::*};
+use serde::Serialize;
+use web_rwkv::runtime::loader::Loader;
+
+use super::{error::ApiErrorResponse, request_models, try_request_info_of};
+use crate::{build_path, check_path_permitted, config::Config, types::ThreadSender};
+
+/// Directories whose files may be deleted.
+const MANAGED_PATHS: [&str; 1] = ["assets/models"];
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|x| x.as_secs())
+}
+
+/// Paths compare equal however they were written, if the file exists.
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactKind {
+    Model,
+    Lora,
+    State,
+    Prefab,
+    /// Chunks of a file that is still being uploaded.
+    Upload,
+    Other,
+}
+
+/// The files of `lora`.
+pub fn lora_files(lora: &[Lora]) -> Vec<(PathBuf, ArtifactKind)> {
+    lora.iter()
+        .map(|x| (x.path.clone(), ArtifactKind::Lora))
+        .collect()
+}
+
+/// The files a model loaded with `request` uses.
+pub fn files_of(request: &ReloadRequest) -> Vec<(PathBuf, ArtifactKind)> {
+    let model = (request.model_path.clone(), ArtifactKind::Model);
+    let state = request
+        .state
+        .iter()
+        .map(|x| (x.path.clone(), ArtifactKind::State));
+    std::iter::once(model)
+        .chain(lora_files(&request.lora))
+        .chain(state)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FileUse {
+    kind: ArtifactKind,
+    last_used: u64,
+}
+
+/// When the files in `model.path` and the loaded models were last used, shared between requests.
+#[derive(Debug, Clone, Default)]
+pub struct ArtifactUsage {
+    files: Arc<Mutex<HashMap<PathBuf, FileUse>>>,
+    /// Last generation of each model by the name requests select it with; empty for the default.
+    models: Arc<Mutex<HashMap<String, u64>>>,
+    /// Kinds of files never used, detected from their contents, by when they were last changed.
+    kinds: Arc<Mutex<HashMap<PathBuf, (SystemTime, ArtifactKind)>>>,
+}
+
+impl ArtifactUsage {
+    /// Record that the files were used just now.
+    pub fn touch(&self, files: impl IntoIterator<Item = (PathBuf, ArtifactKind)>) {
+        let last_used = now();
+        let mut map = self.files.lock().unwrap();
+        for (path, kind) in files {
+            map.insert(canonical(&path), FileUse { kind, last_used });
+        }
+    }
+
+    fn touch_model(&self, model: Option<String>) {
+        let mut models = self.models.lock().unwrap();
+        models.insert(model.unwrap_or_default(), now());
+    }
+
+    /// The stop hook recording a generation as a use of the files of its model.
+    pub fn hook(&self) -> StopHook {
+        let artifacts = self.clone();
+        Arc::new(move |request: &GenerateRequest, _: &TokenCounter| {
+            artifacts.touch_model(request.model.clone())
+        })
+    }
+
+    /// What the file at `path`, last changed at `modified`, holds; only detected again once it
+    /// changes.
+    fn kind(&self, path: &Path, modified: Option<SystemTime>) -> ArtifactKind {
+        let Some(modified) = modified else {
+            return detect_kind(path);
+        };
+        if let Some((time, kind)) = self.kinds.lock().unwrap().get(path) {
+            if *time == modified {
+                return *kind;
+            }
+        }
+        let kind = detect_kind(path);
+        let mut kinds = self.kinds.lock().unwrap();
+        kinds.insert(path.to_path_buf(), (modified, kind));
+        kind
+    }
+
+    fn file(&self, path: &Path) -> Option<FileUse> {
+        self.files.lock().unwrap().get(path).copied()
+    }
+
+    fn model(&self, name: &str) -> Option<u64> {
+        self.models.lock().unwrap().get(name).copied()
+    }
+}
+
+/// What the file at `path` holds, judged by its contents.
+fn detect_kind(path: &Path) -> ArtifactKind {
+    let extension = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default();
+    if extension == "part" {
+        return ArtifactKind::Upload;
+    }
+    let Some(data) = File::open(path)
+        .ok()
+        .and_then(|file| unsafe { Mmap::map(&file) }.ok())
+    else {
+        return ArtifactKind::Other;
+    };
+    if gguf::is_gguf(&data) {
+        return ArtifactKind::Model;
+    }
+    match SafeTensors::deserialize(&data) {
+        Ok(tensors) if tensors.names().iter().any(|name| name.contains(".lora.")) => {
+            ArtifactKind::Lora
+        }
+        Ok(tensors) if Loader::info(&tensors).is_ok() => ArtifactKind::Model,
+        Ok(_) => ArtifactKind::State,
+        // models are saved as prefabs in their own format
+        Err(_) if ["st", "prefab", "bin"].contains(&extension) => ArtifactKind::Prefab,
+        Err(_) => ArtifactKind::Other,
+    }
+}
+
+/// All files under `root`, recursively.
+fn walk(root: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|x| x.ok()) {
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => dirs.push(entry.path()),
+                Ok(file_type) if file_type.is_file() => files.push(entry.path()),
+                _ => {}
+            }
+        }
+    }
+    files
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ArtifactInfo {
+    /// Path of the file under `model.path`.
+    pub name: String,
+    pub kind: ArtifactKind,
+    /// Size in bytes.
+    pub size: u64,
+    /// Unix timestamp of the last change to the file.
+    pub modified: Option<u64>,
+    /// Unix timestamp of the last use of the file since the server started.
+    pub last_used: Option<u64>,
+    /// Loaded models that use the file.
+    pub loaded_by: Vec<String>,
+    /// Whether the config refers to the file, so that the server needs it to start.
+    pub configured: bool,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ArtifactsResponse {
+    #[salvo(schema(value_type = String))]
+    pub path: PathBuf,
+    /// Size of all files in bytes.
+    pub total_size: u64,
+    /// Files, least recently used first.
+    pub files: Vec<ArtifactInfo>,
+}
+
+/// What is known of the use of the files: the loaded models, the last generation of each, and
+/// the files the config refers to.
+struct Uses {
+    models: Vec<(RuntimeInfo, Option<u64>)>,
+    configured: Vec<PathBuf>,
+}
+
+impl Uses {
+    async fn collect(depot: &Depot) -> Self {
+        let sender = depot.obtain::<ThreadSender>().unwrap();
+        let config = depot.obtain::<Arc<Config>>().unwrap();
+        let artifacts = depot.obtain::<ArtifactUsage>().unwrap();
+
+        let default = try_request_info_of(sender.clone(), "")
+            .await
+            .ok()
+            .flatten()
+            .map(|info| info.name);
+        let models = request_models(sender.clone())
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|info| {
+                let named = artifacts.model(&info.name);
+                let default = default
+                    .as_ref()
+                    .filter(|name| **name == info.name)
+                    .and_then(|_| artifacts.model(""));
+                let last_used = named.max(default);
+                (info, last_used)
+            })
+            .collect();
+
+        let mut configured = vec![];
+        if let Ok(request) = ReloadRequest::try_from(Config::clone(config)) {
+            configured.extend(files_of(&request).into_iter().map(|(path, _)| path));
+        }
+        if let Ok(adapters) = config.lora_adapters() {
+            let lora = adapters.into_iter().flat_map(|adapter| adapter.lora);
+            configured.extend(lora.map(|lora| lora.path));
+        }
+        let configured = configured.iter().map(|path| canonical(path)).collect();
+
+        Self { models, configured }
+    }
+
+    fn loaded_by(&self, path: &Path) -> Vec<(String, Option<u64>)> {
+        self.models
+            .iter()
+            .filter(|(info, _)| {
+                files_of(&info.reload)
+                    .iter()
+                    .any(|(file, _)| canonical(file) == path)
+            })
+            .map(|(info, last_used)| (info.name.clone(), *last_used))
+            .collect()
+    }
+
+    fn configured(&self, path: &Path) -> bool {
+        self.configured.iter().any(|file| file == path)
+    }
+}
+
+fn artifact_info(
+    root: &Path,
+    path: &Path,
+    uses: &Uses,
+    artifacts: &ArtifactUsage,
+) -> Option<ArtifactInfo> {
+    let meta = path.metadata().ok()?;
+    let canonical = canonical(path);
+    let name = path.strip_prefix(root).unwrap_or(path);
+    let file = artifacts.file(&canonical);
+    let loaded_by = uses.loaded_by(&canonical);
+    let last_used = loaded_by
+        .iter()
+        .map(|(_, last_used)| *last_used)
+        .chain(std::iter::once(file.map(|x| x.last_used)))
+        .max()
+        .flatten();
+    let modified = meta.modified().ok();
+    let kind = match file {
+        Some(file) => file.kind,
+        None => artifacts.kind(&canonical, modified),
+    };
+    Some(ArtifactInfo {
+        name: name.to_string_lossy().into(),
+        kind,
+        size: meta.len(),
+        modified: modified.and_then(unix_secs),
+        last_used,
+        loaded_by: loaded_by.into_iter().map(|(name, _)| name).collect(),
+        configured: uses.configured(&canonical),
+    })
+}
+
+/// List the models, LoRAs, states and prefabs in `model.path`, with their sizes and last uses.
+///
+/// `/admin/files`.
+#[endpoint(responses(
+    (status_code = 200, body = ArtifactsResponse),
+    (status_code = 500, body = ApiErrorResponse),
+))]
+pub async fn list(depot: &mut Depot) -> Result<Json<ArtifactsResponse>, ApiErrorResponse> {
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let artifacts = depot.obtain::<ArtifactUsage>().unwrap().clone();
+    let root = config.model.path.clone();
+    let uses = Uses::collect(depot).await;
+
+    let files = {
+        let root = root.clone();
+        tokio::task::spawn_blocking(move || {
+            walk(&root)
+                .iter()
+                .filter_map(|path| artifact_info(&root, path, &uses, &artifacts))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(|err| ApiErrorResponse::api_error(format!("cannot list files: {err}")))?
+    };
+    let mut files = files;
+    // files never used by the order they were last changed
+    files.sort_by_key(|file| (file.last_used.or(file.modified), file.name.clone()));
+    let total_size = files.iter().map(|file| file.size).sum();
+
+    Ok(Json(ArtifactsResponse {
+        path: root,
+        total_size,
+        files,
+    }))
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DeleteResponse {
+    pub name: String,
+    /// Bytes freed.
+    pub size: u64,
+}
+
+/// Delete a file in `model.path` that no loaded model uses.
+///
+/// `/admin/files`.
+#[endpoint(responses(
+    (status_code = 200, body = DeleteResponse),
+    (status_code = 400, body = ApiErrorResponse),
+    (status_code = 403, body = ApiErrorResponse),
+    (status_code = 404, body = ApiErrorResponse),
+))]
+pub async fn delete(
+    depot: &mut Depot,
+    name: QueryParam<String, true>,
+    force: QueryParam<bool, false>,
+) -> Result<Json<DeleteResponse>, ApiErrorResponse> {
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let name = name.into_inner();
+    let force = force.into_inner().unwrap_or_default();
+
+    let path = build_path(&config.model.path, &name)
+        .map_err(|err| ApiErrorResponse::invalid_request(err.to_string()).with_param("name"))?;
+    if !path.is_file() {
+        let err = ApiErrorResponse::not_found(format!("file `{name}` does not exist"));
+        return Err(err.with_param("name"));
+    }
+    if check_path_permitted(&path, &MANAGED_PATHS).is_err() {
+        return Err(ApiErrorResponse::permission(
+            "only files in assets/models can be deleted",
+        ));
+    }
+
+    let canonical = canonical(&path);
+    let uses = Uses::collect(depot).await;
+    let loaded_by: Vec<_> = uses
+        .loaded_by(&canonical)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    if !loaded_by.is_empty() {
+        let message = format!("file `{name}` is used by loaded models; unload them first");
+        let err = ApiErrorResponse::invalid_request(message).with_param("name");
+        return Err(err.with_details(serde_json::json!({ "loaded_by": loaded_by })));
+    }
+    if uses.configured(&canonical) && !force {
+        let message = format!("the config refers to file `{name}`; set `force` to delete it");
+        return Err(ApiErrorResponse::invalid_request(message).with_param("force"));
+    }
+
+    let size = path.metadata().map(|meta| meta.len()).unwrap_or_default();
+    std::fs::remove_file(&path)
+        .map_err(|err| ApiErrorResponse::api_error(format!("cannot delete file: {err}")))?;
+    if let Ok(artifacts) = depot.obtain::<ArtifactUsage>() {
+        artifacts.files.lock().unwrap().remove(&canonical);
+        artifacts.kinds.lock().unwrap().remove(&canonical);
+    }
+    tracing::info!(
+        event = "file_deleted",
+        path = %path.display(),
+        size,
+        force,
+        "File deleted"
+    );
+    Ok(Json(DeleteResponse { name, size }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_walk() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("lora/style")).unwrap();
+        std::fs::write(root.join("model.st"), b"").unwrap();
+        std::fs::write(root.join("lora/style/a.st"), b"").unwrap();
+
+        let mut files = walk(root);
+        files.sort();
+        assert_eq!(files, [root.join("lora/style/a.st"), root.join("model.st")]);
+    }
+
+    #[test]
+    fn test_detect_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("model.st.part"), b"").unwrap();
+        std::fs::write(root.join("model.prefab"), b"\xa0").unwrap();
+        std::fs::write(root.join("notes.txt"), b"hello").unwrap();
+
+        let kind = |name: &str| detect_kind(&root.join(name));
+        assert_eq!(kind("model.st.part"), ArtifactKind::Upload);
+        assert_eq!(kind("model.prefab"), ArtifactKind::Prefab);
+        assert_eq!(kind("notes.txt"), ArtifactKind::Other);
+        assert_eq!(kind("missing.st"), ArtifactKind::Other);
+    }
+
+    #[test]
+    fn test_touch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.st");
+        std::fs::write(&path, b"").unwrap();
+
+        let artifacts = ArtifactUsage::default();
+        // the same file, however the path was written
+        let other = dir.path().join(".").join("state.st");
+        artifacts.touch([(other, ArtifactKind::State)]);
+        let file = artifacts.file(&canonical(&path)).unwrap();
+        assert_eq!(file.kind, ArtifactKind::State);
+        assert!(file.last_used > 0);
+
+        artifacts.touch_model(None);
+        assert!(artifacts.model("").is_some());
+        assert!(artifacts.model("other").is_none());
+    }
+
+    #[test]
+    fn test_kind_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.prefab");
+        std::fs::write(&path, b"\xa0").unwrap();
+
+        let artifacts = ArtifactUsage::default();
+        let modified = path.metadata().unwrap().modified().unwrap();
+        assert_eq!(artifacts.kind(&path, Some(modified)), ArtifactKind::Prefab);
+        // not read again while unchanged
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(artifacts.kind(&path, Some(modified)), ArtifactKind::Prefab);
+        // detected again once changed
+        let later = modified + Duration::from_secs(1);
+        assert_eq!(artifacts.kind(&path, Some(later)), ArtifactKind::Other);
+    }
+}
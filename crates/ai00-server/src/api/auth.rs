@@ -1,13 +1,31 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
 use anyhow::Result;
 use jsonwebtoken::{EncodingKey, Header};
 use salvo::{
-    http::cookie::time::{Duration, OffsetDateTime},
+    http::{
+        cookie::time::{Duration, OffsetDateTime},
+        header::AUTHORIZATION,
+    },
+    jwt_auth::{JwtAuthDepotExt, JwtAuthState},
     oapi::extract::JsonBody,
     prelude::*,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{logging, types::JwtClaims};
+use super::error::ApiErrorResponse;
+use crate::{
+    config::{ApiKey, Config, KeyScope},
+    logging,
+    types::JwtClaims,
+};
+
+/// Window of the per-key request limits.
+const RATE_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
 
 #[derive(Serialize, Deserialize, Debug, ToParameters, ToSchema)]
 #[salvo(extract(
@@ -37,7 +55,7 @@ struct AuthResponse {
 )]
 pub fn exchange(depot: &mut Depot, req: JsonBody<AppKeyRequest>, res: &mut Response) {
     // let listen_option = depot.get::<ListenerOption>("listen").unwrap();
-    let config = depot.obtain::<crate::config::Config>().unwrap();
+    let config = depot.obtain::<Arc<crate::config::Config>>().unwrap();
     let auth = req.0;
     if config
         .listen
@@ -83,3 +101,217 @@ pub fn exchange(depot: &mut Depot, req: JsonBody<AppKeyRequest>, res: &mut Respo
             }));
     }
 }
+
+/// The key presented in the `x-api-key` header, or else as `Authorization: Bearer`.
+pub fn presented_key(req: &Request) -> Option<&str> {
+    let headers = req.headers();
+    headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        })
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+}
+
+/// Compare without short-circuiting on the first differing byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The configured key matching `key`, if any.
+pub fn find_key<'a>(keys: &'a [ApiKey], key: &str) -> Option<&'a ApiKey> {
+    keys.iter()
+        .find(|api_key| constant_time_eq(api_key.key.as_bytes(), key.as_bytes()))
+}
+
+/// Record a request of `name` at `now` if it is within `limit` requests per minute.
+/// Otherwise returns the seconds until the oldest request leaves the window.
+fn admit(
+    windows: &mut HashMap<String, VecDeque<Instant>>,
+    name: &str,
+    limit: u32,
+    now: Instant,
+) -> Result<(), u64> {
+    let window = windows.entry(name.to_string()).or_default();
+    while window
+        .front()
+        .is_some_and(|&time| now.duration_since(time) >= RATE_WINDOW)
+    {
+        window.pop_front();
+    }
+    if window.len() >= limit as usize {
+        let oldest = window.front().copied().unwrap_or(now);
+        let wait = RATE_WINDOW.saturating_sub(now.duration_since(oldest));
+        return Err(wait.as_secs_f64().ceil().max(1.0) as u64);
+    }
+    window.push_back(now);
+    Ok(())
+}
+
+/// Require one of the `api_keys` of the config with at least `scope`.
+///
+/// For the admin scope, a valid admin token from [`exchange`] is accepted as well; it is all the
+/// admin scope requires if no key is configured, unless `listen.force_pass` is set. The keys are
+/// read per request, so this follows reloads of the config.
+#[derive(Debug, Clone)]
+pub struct ApiKeyAuth {
+    scope: KeyScope,
+    windows: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+}
+
+impl ApiKeyAuth {
+    pub fn new(scope: KeyScope) -> Self {
+        Self {
+            scope,
+            windows: Default::default(),
+        }
+    }
+
+    fn check(&self, req: &Request, depot: &Depot) -> Result<(), ApiErrorResponse> {
+        // without the config the keys are unknown, so nothing is let through
+        let Ok(config) = depot.obtain::<Arc<Config>>() else {
+            return Err(ApiErrorResponse::api_error("the config is not available"));
+        };
+        let admin = self.scope == KeyScope::Admin;
+        if admin && depot.jwt_auth_state() == JwtAuthState::Authorized {
+            return Ok(());
+        }
+        if config.api_keys.is_empty() {
+            if admin && !config.listen.force_pass.unwrap_or_default() {
+                return Err(ApiErrorResponse::authentication(
+                    "missing or invalid admin token",
+                ));
+            }
+            return Ok(());
+        }
+
+        let Some(key) = presented_key(req) else {
+            return Err(ApiErrorResponse::authentication(
+                "missing API key: set the `x-api-key` header or `Authorization: Bearer`",
+            ));
+        };
+        let Some(api_key) = find_key(&config.api_keys, key) else {
+            return Err(ApiErrorResponse::authentication("invalid API key"));
+        };
+        if !api_key.scope.allows(self.scope) {
+            return Err(ApiErrorResponse::permission(format!(
+                "API key `{}` is not allowed to access this resource",
+                api_key.name
+            )));
+        }
+        if let Some(limit) = api_key.requests_per_minute {
+            let mut windows = self.windows.lock().expect("rate windows poisoned");
+            if let Err(retry_after) = admit(&mut windows, &api_key.name, limit, Instant::now()) {
+                return Err(ApiErrorResponse::rate_limit(format!(
+                    "API key `{}` exceeded {limit} requests per minute",
+                    api_key.name
+                ))
+                .with_retry_after(retry_after));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Handler for ApiKeyAuth {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        if let Err(err) = self.check(req, depot) {
+            err.respond(res);
+            ctrl.skip_rest();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str, scope: KeyScope) -> ApiKey {
+        ApiKey {
+            key: key.into(),
+            name: key.into(),
+            scope,
+            requests_per_minute: None,
+            max_tokens_per_second: None,
+        }
+    }
+
+    #[test]
+    fn test_find_key() {
+        let keys = [
+            key("sk-a", KeyScope::Inference),
+            key("sk-b", KeyScope::Admin),
+        ];
+        assert_eq!(
+            find_key(&keys, "sk-b").map(|k| k.scope),
+            Some(KeyScope::Admin)
+        );
+        assert!(find_key(&keys, "sk-").is_none());
+        assert!(find_key(&keys, "sk-c").is_none());
+    }
+
+    #[test]
+    fn test_scope() {
+        assert!(KeyScope::Admin.allows(KeyScope::Admin));
+        assert!(KeyScope::Admin.allows(KeyScope::Inference));
+        assert!(KeyScope::Inference.allows(KeyScope::Inference));
+        assert!(!KeyScope::Inference.allows(KeyScope::Admin));
+    }
+
+    #[test]
+    fn test_admin_without_keys() {
+        let req = Request::new();
+        let mut config = Config::default();
+        let mut depot = Depot::new();
+        // nothing passes without the config
+        assert!(ApiKeyAuth::new(KeyScope::Admin)
+            .check(&req, &depot)
+            .is_err());
+        depot.inject(Arc::new(config.clone()));
+        // without keys, the admin scope still takes the admin token
+        assert!(ApiKeyAuth::new(KeyScope::Inference)
+            .check(&req, &depot)
+            .is_ok());
+        assert!(ApiKeyAuth::new(KeyScope::Admin)
+            .check(&req, &depot)
+            .is_err());
+
+        config.listen.force_pass = Some(true);
+        depot.inject(Arc::new(config.clone()));
+        assert!(ApiKeyAuth::new(KeyScope::Admin).check(&req, &depot).is_ok());
+
+        // reloaded keys apply to admin requests at once
+        config.api_keys = vec![key("sk-a", KeyScope::Admin)];
+        depot.inject(Arc::new(config));
+        assert!(ApiKeyAuth::new(KeyScope::Admin)
+            .check(&req, &depot)
+            .is_err());
+    }
+
+    #[test]
+    fn test_admit() {
+        let mut windows = HashMap::new();
+        let start = Instant::now();
+        assert!(admit(&mut windows, "a", 2, start).is_ok());
+        assert!(admit(&mut windows, "a", 2, start).is_ok());
+        assert_eq!(admit(&mut windows, "a", 2, start), Err(60));
+        assert!(admit(&mut windows, "b", 2, start).is_ok());
+
+        let later = start + std::time::Duration::from_secs(45);
+        assert_eq!(admit(&mut windows, "a", 2, later), Err(15));
+        let later = start + RATE_WINDOW;
+        assert!(admit(&mut windows, "a", 2, later).is_ok());
+    }
+}
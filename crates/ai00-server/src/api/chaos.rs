@@ -0,0 +1,182 @@
+//! Fault injection for resilience testing, enabled with the `chaos` feature.
+//!
+//! Faults are off until configured through `/admin/chaos`. They are injected at two points:
+//! - Admission to a slot rejects generation requests as if no slot were available.
+//! - A relay between the API and the runtime delays, cuts off or garbles the generated tokens.
+
+use std::{sync::Arc, time::Duration};
+
+use ai00_core::{ThreadRequest, Token};
+use flume::Sender;
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::error::ApiErrorResponse;
+use crate::types::ThreadSender;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct ChaosConfig {
+    /// Master switch. No fault is injected when false.
+    pub enabled: bool,
+    /// Probability that a generation request is rejected as if no slot were available.
+    pub slot_error_rate: f64,
+    /// Delay in milliseconds added before every generated token, emulating a slow GPU.
+    pub latency_ms: u64,
+    /// Random extra delay in milliseconds, up to this value, added on top of `latency_ms`.
+    pub latency_jitter_ms: u64,
+    /// Probability, per generated token, that the stream is cut off without finishing.
+    pub drop_rate: f64,
+    /// Probability that a generation emits garbage, as if its cached prompt state were corrupted.
+    pub cache_corruption_rate: f64,
+}
+
+impl ChaosConfig {
+    fn roll(&self, rate: f64) -> bool {
+        self.enabled && rate > 0.0 && fastrand::f64() < rate
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        if !self.enabled {
+            return None;
+        }
+        let jitter = match self.latency_jitter_ms {
+            0 => 0,
+            x => fastrand::u64(0..=x),
+        };
+        match self.latency_ms + jitter {
+            0 => None,
+            x => Some(Duration::from_millis(x)),
+        }
+    }
+}
+
+/// Shared fault injection settings.
+#[derive(Debug, Default, Clone)]
+pub struct Chaos(Arc<RwLock<ChaosConfig>>);
+
+impl Chaos {
+    pub async fn config(&self) -> ChaosConfig {
+        self.0.read().await.clone()
+    }
+
+    pub async fn set_config(&self, config: ChaosConfig) {
+        *self.0.write().await = config;
+    }
+
+    /// Put a relay in front of the runtime, which injects faults into generated token streams.
+    pub fn intercept(&self, sender: ThreadSender) -> ThreadSender {
+        let (relay_sender, relay_receiver) = flume::unbounded::<ThreadRequest>();
+        let chaos = self.clone();
+        tokio::spawn(async move {
+            while let Ok(request) = relay_receiver.recv_async().await {
+                let request = match request {
+                    ThreadRequest::Generate {
+                        request,
+                        tokenizer,
+                        sender: token_sender,
+                    } => ThreadRequest::Generate {
+                        request,
+                        tokenizer,
+                        sender: chaos.relay(token_sender),
+                    },
+                    ThreadRequest::GenerateMany {
+                        requests,
+                        tokenizer,
+                    } => ThreadRequest::GenerateMany {
+                        requests: requests
+                            .into_iter()
+                            .map(|(request, token_sender)| (request, chaos.relay(token_sender)))
+                            .collect(),
+                        tokenizer,
+                    },
+                    request => request,
+                };
+                if sender.send_async(request).await.is_err() {
+                    break;
+                }
+            }
+        });
+        relay_sender
+    }
+
+    /// Forward tokens to `sender` through a new channel, with the faults currently configured.
+    fn relay(&self, sender: Sender<Token>) -> Sender<Token> {
+        let (relay_sender, relay_receiver) = flume::unbounded();
+        let chaos = self.clone();
+        tokio::spawn(async move {
+            let config = chaos.config().await;
+            let corrupted = config.roll(config.cache_corruption_rate);
+            while let Ok(token) = relay_receiver.recv_async().await {
+                let token = match token {
+                    Token::Content(text) => {
+                        if let Some(latency) = config.latency() {
+                            tokio::time::sleep(latency).await;
+                        }
+                        if config.roll(config.drop_rate) {
+                            // dropping both ends cuts the response off mid-stream
+                            break;
+                        }
+                        match corrupted {
+                            true => Token::Content(garble(&text)),
+                            false => Token::Content(text),
+                        }
+                    }
+                    token => token,
+                };
+                if sender.send_async(token).await.is_err() {
+                    break;
+                }
+            }
+        });
+        relay_sender
+    }
+}
+
+/// Replace every character with a random printable one, keeping whitespace.
+fn garble(text: &str) -> String {
+    text.chars()
+        .map(|ch| match ch.is_whitespace() {
+            true => ch,
+            false => fastrand::char('!'..='~'),
+        })
+        .collect()
+}
+
+/// The error rejecting a generation request at the configured rate, as if no slot were available.
+///
+/// Called by [`admit_generation`](super::admission::admit_generation), so only generations are
+/// affected.
+pub async fn slot_error(depot: &Depot) -> Option<ApiErrorResponse> {
+    let chaos = depot.obtain::<Chaos>().ok()?;
+    let config = chaos.config().await;
+    config
+        .roll(config.slot_error_rate)
+        .then(|| ApiErrorResponse::overloaded("no slot available (injected fault)"))
+}
+
+/// `/admin/chaos`.
+#[endpoint]
+pub async fn chaos(depot: &mut Depot) -> Json<ChaosConfig> {
+    let chaos = depot.obtain::<Chaos>().unwrap();
+    Json(chaos.config().await)
+}
+
+/// `/admin/chaos`.
+#[endpoint]
+pub async fn set_chaos(depot: &mut Depot, req: JsonBody<ChaosConfig>) -> StatusCode {
+    let chaos = depot.obtain::<Chaos>().unwrap();
+    let config = req.0;
+    let rates = [
+        config.slot_error_rate,
+        config.drop_rate,
+        config.cache_corruption_rate,
+    ];
+    if rates.iter().any(|x| !(0.0..=1.0).contains(x)) {
+        return StatusCode::BAD_REQUEST;
+    }
+    tracing::warn!(event = "chaos_configured", ?config);
+    chaos.set_config(config).await;
+    StatusCode::OK
+}
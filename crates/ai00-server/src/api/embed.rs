@@ -0,0 +1,289 @@
+//! Management of the fastembed model of the `embed` feature.
+//!
+//! The embed model lives on a worker thread of its own, which runs embedding and loading jobs
+//! one at a time in the order they come in, so that neither blocks the async runtime. Loading
+//! another model with `/api/embed/load` waits for the jobs before it; jobs after it use the new
+//! model. The previous model stays loaded if the new one fails to load.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+};
+
+use anyhow::{anyhow, bail, Result};
+use fastembed::{EmbeddingModel, ExecutionProviderDispatch, TextEmbedding};
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use super::error::ApiErrorResponse;
+use crate::{
+    config::{self, EmbedDevice, EmbedOption},
+    logging, TextEmbed,
+};
+
+type EmbedJob = Box<dyn FnOnce(&mut Option<TextEmbed>) + Send>;
+
+/// The embed model that is loaded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct LoadedEmbed {
+    /// Hugging Face repository of the model, e.g. `intfloat/multilingual-e5-small`.
+    pub model: String,
+    pub device: EmbedDevice,
+}
+
+/// Queue of the jobs of the embed model, shared between requests.
+#[derive(Clone)]
+pub struct EmbedQueue {
+    sender: flume::Sender<EmbedJob>,
+    loaded: Arc<RwLock<Option<LoadedEmbed>>>,
+    /// Loads queued or running.
+    loading: Arc<AtomicUsize>,
+}
+
+impl EmbedQueue {
+    /// Start the worker thread, loading the model of `option` first if set.
+    pub fn new(option: Option<EmbedOption>) -> Self {
+        let (sender, receiver) = flume::unbounded::<EmbedJob>();
+        std::thread::spawn(move || {
+            let mut embed = None;
+            while let Ok(job) = receiver.recv() {
+                job(&mut embed);
+            }
+        });
+        let queue = Self {
+            sender,
+            loaded: Default::default(),
+            loading: Default::default(),
+        };
+        if let Some(option) = option {
+            // the load is waited for by the jobs after it, not by the startup
+            let _ = queue.submit(queue.load_job(option));
+        }
+        queue
+    }
+
+    /// The model that is loaded, if any.
+    pub fn loaded(&self) -> Option<LoadedEmbed> {
+        self.loaded.read().unwrap().clone()
+    }
+
+    /// Whether a model is loaded or being loaded, which jobs wait for.
+    pub fn is_available(&self) -> bool {
+        self.loading.load(Ordering::Acquire) > 0 || self.loaded().is_some()
+    }
+
+    fn submit<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut Option<TextEmbed>) -> T + Send + 'static,
+    ) -> Result<flume::Receiver<T>> {
+        let (sender, receiver) = flume::bounded(1);
+        let job: EmbedJob = Box::new(move |embed| {
+            let _ = sender.send(job(embed));
+        });
+        self.sender
+            .send(job)
+            .map_err(|_| anyhow!("the embed worker has stopped"))?;
+        Ok(receiver)
+    }
+
+    /// Run `job` with the loaded model once the jobs before it are done.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&TextEmbed) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let receiver = self.submit(move |embed| match embed {
+            Some(embed) => job(embed),
+            None => bail!("no embed model is loaded"),
+        })?;
+        receiver.recv_async().await?
+    }
+
+    /// Embed `texts`, in order.
+    pub async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.run(move |embed| embed.model.embed(texts, None)).await
+    }
+
+    fn load_job(
+        &self,
+        option: EmbedOption,
+    ) -> impl FnOnce(&mut Option<TextEmbed>) -> Result<()> + Send + 'static {
+        let loaded = self.loaded.clone();
+        let loading = self.loading.clone();
+        loading.fetch_add(1, Ordering::AcqRel);
+        move |embed| {
+            let device = option.device;
+            let result = match load_embed(option) {
+                Ok(model) => {
+                    *loaded.write().unwrap() = Some(LoadedEmbed {
+                        model: model.info.model_code.clone(),
+                        device,
+                    });
+                    *embed = Some(model);
+                    Ok(())
+                }
+                Err(err) => {
+                    logging::errors::model_load_failed("embed", &err.to_string());
+                    Err(err)
+                }
+            };
+            loading.fetch_sub(1, Ordering::AcqRel);
+            result
+        }
+    }
+
+    /// Load the model of `option` in place of the loaded one, once the jobs before are done.
+    pub async fn load(&self, option: EmbedOption) -> Result<()> {
+        self.submit(self.load_job(option))?.recv_async().await?
+    }
+}
+
+fn execution_providers(device: EmbedDevice) -> Vec<ExecutionProviderDispatch> {
+    use ort::execution_providers::CUDAExecutionProvider;
+
+    match device {
+        EmbedDevice::Cpu => vec![],
+        EmbedDevice::Gpu => vec![CUDAExecutionProvider::default().build().error_on_failure()],
+    }
+}
+
+/// Load the embed model of `embed`, downloading it if needed. Blocks until done.
+pub fn load_embed(embed: EmbedOption) -> Result<TextEmbed> {
+    use fastembed::InitOptions;
+    use hf_hub::api::sync::Api;
+
+    std::env::set_var("HF_ENDPOINT", embed.endpoint);
+    std::env::set_var("HF_HOME", embed.home);
+    #[cfg(target_os = "windows")]
+    std::env::set_var("ORT_DYLIB_PATH", embed.lib);
+
+    let api = Api::new()?;
+    let info = TextEmbedding::get_model_info(&embed.model)?.clone();
+    tracing::info!("loading embed model: {} on {:?}", embed.model, embed.device);
+
+    let options = InitOptions::new(embed.model)
+        .with_execution_providers(execution_providers(embed.device))
+        .with_show_download_progress(true);
+    let model = TextEmbedding::try_new(options)?;
+
+    let file = api.model(info.model_code.clone()).get("tokenizer.json")?;
+    let tokenizer = tokenizers::Tokenizer::from_file(file).map_err(|err| anyhow!("{err}"))?;
+
+    Ok(TextEmbed {
+        tokenizer,
+        model,
+        info,
+    })
+}
+
+/// Parse an embed model from its repository (`intfloat/multilingual-e5-small`) or its name in
+/// the config (`MultilingualE5Small`).
+fn parse_model(name: &str) -> Option<EmbeddingModel> {
+    name.parse().ok().or_else(|| {
+        config::EmbeddingModel::deserialize(serde_json::Value::String(name.into())).ok()
+    })
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EmbedModelInfo {
+    /// Hugging Face repository of the model.
+    pub model: String,
+    /// Name of the model in the `[embed]` config.
+    pub name: String,
+    pub description: String,
+    /// Dimensions of the embeddings.
+    pub dim: usize,
+    pub loaded: bool,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EmbedModelsResponse {
+    pub loaded: Option<LoadedEmbed>,
+    pub models: Vec<EmbedModelInfo>,
+}
+
+/// List the embed models available and the one that is loaded.
+///
+/// `/api/embed/models`.
+#[endpoint(responses((status_code = 200, body = EmbedModelsResponse)))]
+pub async fn models(depot: &mut Depot) -> Json<EmbedModelsResponse> {
+    let loaded = depot.obtain::<EmbedQueue>().unwrap().loaded();
+    let mut models: Vec<_> = TextEmbedding::list_supported_models()
+        .into_iter()
+        .map(|info| EmbedModelInfo {
+            loaded: loaded.as_ref().is_some_and(|x| x.model == info.model_code),
+            name: format!("{:?}", info.model),
+            model: info.model_code,
+            description: info.description,
+            dim: info.dim,
+        })
+        .collect();
+    models.sort_by(|x, y| x.model.cmp(&y.model));
+    Json(EmbedModelsResponse { loaded, models })
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[salvo(schema(example = json!({"model": "intfloat/multilingual-e5-small", "device": "Cpu"})))]
+pub struct EmbedLoadRequest {
+    /// Repository or config name of the model, as `/api/embed/models` lists them.
+    pub model: String,
+    #[serde(default)]
+    pub device: EmbedDevice,
+}
+
+/// Load an embed model in place of the loaded one, with the rest of the `[embed]` config.
+///
+/// `/api/embed/load`.
+#[endpoint(responses(
+    (status_code = 200, body = LoadedEmbed),
+    (status_code = 400, body = ApiErrorResponse),
+))]
+pub async fn load(
+    depot: &mut Depot,
+    body: JsonBody<EmbedLoadRequest>,
+) -> Result<Json<LoadedEmbed>, ApiErrorResponse> {
+    let request = body.0;
+    let model = parse_model(&request.model).ok_or_else(|| {
+        ApiErrorResponse::invalid_request(format!("unknown embed model `{}`", request.model))
+            .with_param("model")
+    })?;
+    let config = depot.obtain::<Arc<config::Config>>().unwrap();
+    let option = EmbedOption {
+        model,
+        device: request.device,
+        ..config.embed.clone().unwrap_or_default()
+    };
+
+    let queue = depot.obtain::<EmbedQueue>().unwrap();
+    queue
+        .load(option)
+        .await
+        .map_err(|err| ApiErrorResponse::api_error(format!("failed to load embed model: {err}")))?;
+    let loaded = queue
+        .loaded()
+        .ok_or_else(|| ApiErrorResponse::api_error("failed to load embed model"))?;
+    Ok(Json(loaded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_model() {
+        let model = Some(EmbeddingModel::MultilingualE5Small);
+        assert_eq!(parse_model("intfloat/multilingual-e5-small"), model);
+        assert_eq!(parse_model("MultilingualE5Small"), model);
+        assert_eq!(parse_model("unknown"), None);
+    }
+
+    #[test]
+    fn test_load_request_device() {
+        let request: EmbedLoadRequest =
+            serde_json::from_value(serde_json::json!({"model": "BGESmallENV15"})).unwrap();
+        assert_eq!(request.device, EmbedDevice::Cpu);
+        let request: EmbedLoadRequest =
+            serde_json::from_value(serde_json::json!({"model": "BGESmallENV15", "device": "Gpu"}))
+                .unwrap();
+        assert_eq!(request.device, EmbedDevice::Gpu);
+    }
+}
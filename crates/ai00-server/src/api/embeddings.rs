@@ -0,0 +1,252 @@
+//! Embeddings from the loaded language model, in the shape of the OpenAI embeddings API.
+//!
+//! Each input is prefilled as with `/api/oai/states`, and the embedding is pooled from the state
+//! of one layer after the last token: with `hidden` pooling it is the input of the layer at the
+//! last token, which the layer keeps for its token shift; with `state` pooling it is the mean
+//! over the rows of the layer's state. The state is `[num_emb, rows, num_layer, 1]`, so either
+//! has `num_emb` dimensions. Unlike the `embed` feature, no second model is needed, and the
+//! embeddings follow the model that generates. All inputs are prefilled in one batch.
+
+use ai00_core::{reload::TrafficClass, GenerateKind, GenerateRequest, ThreadRequest, Token};
+use futures_util::future::join_all;
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use super::{error::ApiErrorResponse, request_info_of, usage::Attribution};
+use crate::{
+    types::{Array, ThreadSender},
+    SLEEP,
+};
+
+/// Most inputs in one request.
+const MAX_INPUTS: usize = 256;
+
+/// What part of the state of a layer becomes the embedding.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingPooling {
+    /// The input of the layer at the last token.
+    #[default]
+    Hidden,
+    /// The mean over the rows of the state of the layer, which sums up all tokens.
+    State,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[salvo(schema(example = json!({"input": ["The Eiffel Tower is in Paris."], "layer": -1})))]
+pub struct EmbeddingsRequest {
+    /// Model to embed with; the default model if not set.
+    #[serde(default)]
+    pub model: Option<String>,
+    pub input: Array<String>,
+    /// Layer to pool, counting from the end if negative; the last layer if not set.
+    #[serde(default)]
+    pub layer: Option<isize>,
+    #[serde(default)]
+    pub pooling: EmbeddingPooling,
+    /// Scale the embeddings to unit length, so that dot products are cosine similarities.
+    #[serde(default = "default_normalize")]
+    pub normalize: bool,
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EmbeddingData {
+    pub object: String,
+    /// Index of the input in the request.
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct EmbeddingsUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EmbeddingsResponse {
+    pub object: String,
+    pub model: String,
+    pub data: Vec<EmbeddingData>,
+    pub usage: EmbeddingsUsage,
+}
+
+/// The layer `layer` picks out of `num_layer`, if there is one.
+fn resolve_layer(layer: Option<isize>, num_layer: usize) -> Option<usize> {
+    let layer = layer.unwrap_or(-1);
+    let layer = match layer < 0 {
+        true => num_layer.checked_sub(layer.unsigned_abs())?,
+        false => layer as usize,
+    };
+    (layer < num_layer).then_some(layer)
+}
+
+/// Pool the embedding of `layer` out of a state of `shape`.
+fn pool(data: &[f32], shape: [usize; 4], layer: usize, pooling: EmbeddingPooling) -> Vec<f32> {
+    let [channels, rows, _, _] = shape;
+    let start = channels * rows * layer;
+    let Some(state) = data.get(start..start + channels * rows) else {
+        return vec![];
+    };
+    match pooling {
+        EmbeddingPooling::Hidden => state[..channels].to_vec(),
+        EmbeddingPooling::State => (0..channels)
+            .map(|channel| {
+                let sum: f32 = state.iter().skip(channel).step_by(channels).sum();
+                sum / rows as f32
+            })
+            .collect(),
+    }
+}
+
+fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Embed the inputs with the loaded model.
+///
+/// `/api/v1/embeddings`.
+#[endpoint(responses(
+    (status_code = 200, body = EmbeddingsResponse),
+    (status_code = 400, body = ApiErrorResponse),
+))]
+pub async fn embeddings(
+    depot: &mut Depot,
+    body: JsonBody<EmbeddingsRequest>,
+) -> Result<Json<EmbeddingsResponse>, ApiErrorResponse> {
+    let request = body.0;
+    let inputs: Vec<String> = request.input.into();
+    if inputs.is_empty() || inputs.iter().any(String::is_empty) {
+        return Err(ApiErrorResponse::invalid_request("input cannot be empty").with_param("input"));
+    }
+    if inputs.len() > MAX_INPUTS {
+        let message = format!("at most {MAX_INPUTS} inputs can be embedded at once");
+        return Err(ApiErrorResponse::invalid_request(message).with_param("input"));
+    }
+
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let info = request_info_of(
+        sender.clone(),
+        request.model.as_deref().unwrap_or_default(),
+        SLEEP,
+    )
+    .await?;
+    let num_layer = info.info.num_layer;
+    let layer = resolve_layer(request.layer, num_layer).ok_or_else(|| {
+        let message = format!(
+            "layer must be between -{num_layer} and {}",
+            num_layer.saturating_sub(1)
+        );
+        ApiErrorResponse::invalid_request(message).with_param("layer")
+    })?;
+
+    let attribution = Attribution::of(depot);
+    let receivers: Vec<_> = inputs
+        .into_iter()
+        .map(|prompt| {
+            let (token_sender, token_receiver) = flume::unbounded();
+            let mut generate = GenerateRequest {
+                prompt,
+                max_tokens: 1,
+                kind: GenerateKind::State,
+                model: request.model.clone(),
+                traffic_class: TrafficClass::Batch,
+                ..Default::default()
+            };
+            attribution.apply(&mut generate);
+            let _ = sender.send(ThreadRequest::Generate {
+                request: Box::new(generate),
+                tokenizer: info.tokenizer.clone(),
+                sender: token_sender,
+            });
+            token_receiver
+        })
+        .collect();
+
+    let states = join_all(receivers.into_iter().map(|receiver| async move {
+        let mut state = None;
+        let mut tokens = 0;
+        while let Ok(token) = receiver.recv_async().await {
+            match token {
+                Token::Embed(data, shape) => state = Some((data, shape)),
+                Token::Stop(_, counter) => tokens = counter.prompt,
+                Token::Done => break,
+                _ => {}
+            }
+        }
+        state.map(|state| (state, tokens))
+    }))
+    .await;
+
+    let mut data = Vec::with_capacity(states.len());
+    let mut usage = EmbeddingsUsage::default();
+    for (index, state) in states.into_iter().enumerate() {
+        let ((state, shape), tokens) =
+            state.ok_or_else(|| ApiErrorResponse::api_error("failed to embed input"))?;
+        let mut embedding = pool(&state, shape, layer, request.pooling);
+        if request.normalize {
+            normalize(&mut embedding);
+        }
+        usage.prompt_tokens += tokens;
+        data.push(EmbeddingData {
+            object: "embedding".into(),
+            index,
+            embedding,
+        });
+    }
+    usage.total_tokens = usage.prompt_tokens;
+
+    Ok(Json(EmbeddingsResponse {
+        object: "list".into(),
+        model: info.name,
+        data,
+        usage,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_layer() {
+        assert_eq!(resolve_layer(None, 12), Some(11));
+        assert_eq!(resolve_layer(Some(0), 12), Some(0));
+        assert_eq!(resolve_layer(Some(-12), 12), Some(0));
+        assert_eq!(resolve_layer(Some(-13), 12), None);
+        assert_eq!(resolve_layer(Some(12), 12), None);
+    }
+
+    #[test]
+    fn test_pool() {
+        // 2 channels, 3 rows, 2 layers
+        let data: Vec<f32> = (0..12).map(|x| x as f32).collect();
+        let shape = [2, 3, 2, 1];
+        assert_eq!(pool(&data, shape, 0, EmbeddingPooling::Hidden), [0.0, 1.0]);
+        assert_eq!(pool(&data, shape, 1, EmbeddingPooling::Hidden), [6.0, 7.0]);
+        assert_eq!(pool(&data, shape, 1, EmbeddingPooling::State), [8.0, 9.0]);
+        assert!(pool(&data, shape, 2, EmbeddingPooling::Hidden).is_empty());
+    }
+
+    #[test]
+    fn test_request_defaults() {
+        let request: EmbeddingsRequest =
+            serde_json::from_value(json!({"input": "hello", "pooling": "state"})).unwrap();
+        assert_eq!(request.pooling, EmbeddingPooling::State);
+        assert!(request.normalize);
+        assert_eq!(request.layer, None);
+
+        let mut embedding = vec![3.0, 4.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, [0.6, 0.8]);
+    }
+}
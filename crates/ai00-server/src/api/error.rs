@@ -3,9 +3,21 @@
 //! This module provides error types that match Anthropic's API error format
 //! for compatibility with Claude API clients.
 
-use salvo::prelude::*;
+use ai00_core::GenerateError;
+use salvo::{
+    http::{header::RETRY_AFTER, ResBody},
+    prelude::*,
+};
 use serde::{Deserialize, Serialize};
 
+use super::request_id::ANTHROPIC_REQUEST_ID_HEADER;
+
+/// Seconds clients are told to wait before retrying a rate limited or overloaded request.
+pub const DEFAULT_RETRY_AFTER: u64 = 1;
+
+/// Status code of `overloaded_error`, specific to Anthropic's API.
+pub const STATUS_OVERLOADED: u16 = 529;
+
 /// Top-level error response wrapper.
 ///
 /// Matches Claude API error format:
@@ -15,7 +27,8 @@ use serde::{Deserialize, Serialize};
 ///   "error": {
 ///     "type": "invalid_request_error",
 ///     "message": "..."
-///   }
+///   },
+///   "request_id": "..."
 /// }
 /// ```
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -25,6 +38,12 @@ pub struct ApiErrorResponse {
     pub error_type: &'static str,
     /// The error details
     pub error: ApiErrorDetail,
+    /// ID of the failed request, same as the `request-id` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Seconds to wait before retrying, sent as the `retry-after` header
+    #[serde(skip)]
+    pub retry_after: Option<u64>,
 }
 
 /// Detailed error information.
@@ -38,6 +57,9 @@ pub struct ApiErrorDetail {
     /// Optional parameter that caused the error
     #[serde(skip_serializing_if = "Option::is_none")]
     pub param: Option<String>,
+    /// Optional machine-readable details of the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
 }
 
 /// Error categories matching Claude API error types.
@@ -56,10 +78,27 @@ pub enum ApiErrorKind {
     RateLimitError,
     /// Internal server error
     ApiError,
+    /// Request body exceeds the size limit
+    RequestTooLarge,
     /// Server overloaded
     OverloadedError,
 }
 
+impl From<StatusCode> for ApiErrorKind {
+    fn from(status: StatusCode) -> Self {
+        match status.as_u16() {
+            401 => ApiErrorKind::AuthenticationError,
+            403 => ApiErrorKind::PermissionError,
+            404 => ApiErrorKind::NotFoundError,
+            413 => ApiErrorKind::RequestTooLarge,
+            429 => ApiErrorKind::RateLimitError,
+            503 | STATUS_OVERLOADED => ApiErrorKind::OverloadedError,
+            code if code >= 500 => ApiErrorKind::ApiError,
+            _ => ApiErrorKind::InvalidRequestError,
+        }
+    }
+}
+
 impl ApiErrorResponse {
     /// Create a new error response.
     pub fn new(kind: ApiErrorKind, message: impl Into<String>) -> Self {
@@ -69,7 +108,10 @@ impl ApiErrorResponse {
                 kind,
                 message: message.into(),
                 param: None,
+                details: None,
             },
+            request_id: None,
+            retry_after: None,
         }
     }
 
@@ -108,12 +150,29 @@ impl ApiErrorResponse {
         Self::new(ApiErrorKind::OverloadedError, message)
     }
 
+    /// Create a request too large error.
+    pub fn request_too_large(message: impl Into<String>) -> Self {
+        Self::new(ApiErrorKind::RequestTooLarge, message)
+    }
+
+    /// Tell the client how many seconds to wait before retrying.
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
     /// Add parameter information to the error.
     pub fn with_param(mut self, param: impl Into<String>) -> Self {
         self.error.param = Some(param.into());
         self
     }
 
+    /// Add machine-readable details to the error.
+    pub fn with_details(mut self, details: impl Serialize) -> Self {
+        self.error.details = serde_json::to_value(details).ok();
+        self
+    }
+
     /// Get the appropriate HTTP status code for this error.
     pub fn status_code(&self) -> StatusCode {
         match self.error.kind {
@@ -121,22 +180,123 @@ impl ApiErrorResponse {
             ApiErrorKind::AuthenticationError => StatusCode::UNAUTHORIZED,
             ApiErrorKind::PermissionError => StatusCode::FORBIDDEN,
             ApiErrorKind::NotFoundError => StatusCode::NOT_FOUND,
+            ApiErrorKind::RequestTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
             ApiErrorKind::RateLimitError => StatusCode::TOO_MANY_REQUESTS,
-            ApiErrorKind::OverloadedError => StatusCode::SERVICE_UNAVAILABLE,
+            ApiErrorKind::OverloadedError => StatusCode::from_u16(STATUS_OVERLOADED).unwrap(),
             ApiErrorKind::ApiError => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    /// Whether clients should retry the request later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.error.kind,
+            ApiErrorKind::RateLimitError | ApiErrorKind::OverloadedError
+        )
+    }
+
+    /// Write the error into `res` with its status code and headers.
+    ///
+    /// The request ID is taken from the `request-id` response header set by the request ID middleware,
+    /// or generated if the middleware did not run (e.g. unmatched routes).
+    pub fn respond(mut self, res: &mut Response) {
+        let request_id = self.request_id.take().or_else(|| {
+            res.headers()
+                .get(ANTHROPIC_REQUEST_ID_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        });
+        let request_id = request_id.unwrap_or_else(|| uuid::Uuid::now_v7().to_string());
+        if let Ok(value) = request_id.parse() {
+            res.headers_mut().insert(ANTHROPIC_REQUEST_ID_HEADER, value);
+        }
+        self.request_id = Some(request_id);
+        if self.is_retryable() {
+            let seconds = self.retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+            res.headers_mut().insert(RETRY_AFTER, seconds.into());
+        }
+        res.status_code(self.status_code());
+        res.render(Json(self));
+    }
+}
+
+impl From<&StatusError> for ApiErrorResponse {
+    fn from(err: &StatusError) -> Self {
+        let message = err.detail.clone().unwrap_or_else(|| err.brief.clone());
+        Self::new(err.code.into(), message)
+    }
+}
+
+/// A generation that failed part way, with the details of the failure (e.g. for a grammar that
+/// blocked every token, its state and the tokens the model preferred).
+impl From<GenerateError> for ApiErrorResponse {
+    fn from(err: GenerateError) -> Self {
+        Self::api_error(err.to_string()).with_details(err)
+    }
 }
 
 /// Implement Salvo's Writer trait for automatic response rendering.
 #[async_trait]
 impl Writer for ApiErrorResponse {
     async fn write(self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
-        res.status_code(self.status_code());
-        res.render(Json(self));
+        self.respond(res);
     }
 }
 
+/// Rewrite error responses produced outside the API handlers, e.g. by failed body extraction,
+/// into the Claude API error format, so that SDKs can parse them and apply their retry logic.
+#[handler]
+pub async fn error_parity(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    ctrl.call_next(req, depot, res).await;
+    normalize_error(res);
+}
+
+/// Same as [`error_parity`], for errors caught outside any route (unknown paths, wrong methods).
+#[handler]
+pub async fn catch_error(res: &mut Response, ctrl: &mut FlowCtrl) {
+    if normalize_error(res) {
+        ctrl.skip_rest();
+    }
+}
+
+/// Convert a bare or salvo-rendered error response. Returns whether the body was replaced.
+fn normalize_error(res: &mut Response) -> bool {
+    let Some(status) = res.status_code else {
+        return false;
+    };
+    if !(status.is_client_error() || status.is_server_error()) {
+        return false;
+    }
+    let error = match &res.body {
+        ResBody::Error(err) => ApiErrorResponse::from(err),
+        ResBody::None => {
+            let message = status.canonical_reason().unwrap_or("error");
+            ApiErrorResponse::new(status.into(), message)
+        }
+        _ => {
+            // already rendered by a handler; only make sure retryable errors carry `retry-after`
+            let retryable = matches!(
+                ApiErrorKind::from(status),
+                ApiErrorKind::RateLimitError | ApiErrorKind::OverloadedError
+            );
+            if retryable && !res.headers().contains_key(RETRY_AFTER) {
+                res.headers_mut()
+                    .insert(RETRY_AFTER, DEFAULT_RETRY_AFTER.into());
+            }
+            return false;
+        }
+    };
+    // keep the original status, e.g. 405 stays 405 though reported as invalid_request_error
+    error.respond(res);
+    res.status_code(status);
+    true
+}
+
 /// Implement Scribe for OpenAPI documentation.
 impl EndpointOutRegister for ApiErrorResponse {
     fn register(
@@ -165,6 +325,24 @@ mod tests {
         assert_eq!(err.error.param, Some("temperature".to_string()));
     }
 
+    #[test]
+    fn test_generate_error_details() {
+        let err = ApiErrorResponse::from(GenerateError::GrammarBlocked {
+            output_tokens: 3,
+            grammar_state: "value ::= '{' . members '}'".into(),
+            blocked: vec![ai00_core::BlockedToken {
+                token: 11,
+                text: "\"".into(),
+                logit: 4.5,
+            }],
+        });
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        let details = err.error.details.unwrap();
+        assert_eq!(details["type"], "grammar_blocked");
+        assert_eq!(details["output_tokens"], 3);
+        assert_eq!(details["blocked"][0]["text"], "\"");
+    }
+
     #[test]
     fn test_error_serialization() {
         let err = ApiErrorResponse::invalid_request("test error");
@@ -197,8 +375,78 @@ mod tests {
             StatusCode::INTERNAL_SERVER_ERROR
         );
         assert_eq!(
-            ApiErrorResponse::overloaded("").status_code(),
-            StatusCode::SERVICE_UNAVAILABLE
+            ApiErrorResponse::overloaded("").status_code().as_u16(),
+            STATUS_OVERLOADED
+        );
+        assert_eq!(
+            ApiErrorResponse::request_too_large("").status_code(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn test_kind_from_status() {
+        assert_eq!(
+            ApiErrorKind::from(StatusCode::METHOD_NOT_ALLOWED),
+            ApiErrorKind::InvalidRequestError
+        );
+        assert_eq!(
+            ApiErrorKind::from(StatusCode::SERVICE_UNAVAILABLE),
+            ApiErrorKind::OverloadedError
+        );
+        assert_eq!(
+            ApiErrorKind::from(StatusCode::BAD_GATEWAY),
+            ApiErrorKind::ApiError
         );
+        for kind in [
+            ApiErrorKind::InvalidRequestError,
+            ApiErrorKind::AuthenticationError,
+            ApiErrorKind::PermissionError,
+            ApiErrorKind::NotFoundError,
+            ApiErrorKind::RequestTooLarge,
+            ApiErrorKind::RateLimitError,
+            ApiErrorKind::ApiError,
+            ApiErrorKind::OverloadedError,
+        ] {
+            let status = ApiErrorResponse::new(kind, "").status_code();
+            assert_eq!(ApiErrorKind::from(status), kind);
+        }
+    }
+
+    #[test]
+    fn test_respond_headers() {
+        let mut res = Response::new();
+        res.headers_mut()
+            .insert(ANTHROPIC_REQUEST_ID_HEADER, "req_123".parse().unwrap());
+        ApiErrorResponse::rate_limit("slow down")
+            .with_retry_after(30)
+            .respond(&mut res);
+        assert_eq!(res.status_code, Some(StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "30");
+        assert_eq!(
+            res.headers().get(ANTHROPIC_REQUEST_ID_HEADER).unwrap(),
+            "req_123"
+        );
+
+        let mut res = Response::new();
+        ApiErrorResponse::overloaded("busy").respond(&mut res);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "1");
+        assert!(res.headers().contains_key(ANTHROPIC_REQUEST_ID_HEADER));
+
+        let mut res = Response::new();
+        ApiErrorResponse::invalid_request("bad").respond(&mut res);
+        assert!(res.headers().get(RETRY_AFTER).is_none());
+    }
+
+    #[test]
+    fn test_normalize_bare_error() {
+        let mut res = Response::new();
+        res.status_code(StatusCode::NOT_FOUND);
+        assert!(normalize_error(&mut res));
+        assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+
+        let mut res = Response::new();
+        res.status_code(StatusCode::OK);
+        assert!(!normalize_error(&mut res));
     }
 }
@@ -2,8 +2,10 @@ use std::{
     fs::{File, Metadata},
     io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use ai00_core::loader::gguf;
 use anyhow::Result;
 use itertools::Itertools;
 use memmap2::Mmap;
@@ -111,10 +113,13 @@ async fn dir_inner(
 
                     let file = File::open(&path).ok()?;
                     let data = unsafe { Mmap::map(&file) }.ok()?;
-                    let info = SafeTensors::deserialize(&data)
-                        .map_err(Into::into)
-                        .and_then(|model| Loader::info(&model))
-                        .ok();
+                    let info = match gguf::is_gguf(&data) {
+                        true => gguf::Gguf::parse(&data).and_then(|model| Loader::info(&model)),
+                        false => SafeTensors::deserialize(&data)
+                            .map_err(Into::into)
+                            .and_then(|model| Loader::info(&model)),
+                    }
+                    .ok();
 
                     Some(FileInfo {
                         path,
@@ -166,7 +171,7 @@ pub async fn dir(depot: &mut Depot, req: &mut Request, res: &mut Response) {
 /// `/api/models/list`.
 #[handler]
 pub async fn models(depot: &mut Depot, res: &mut Response) {
-    let config = depot.obtain::<crate::config::Config>().unwrap();
+    let config = depot.obtain::<Arc<crate::config::Config>>().unwrap();
     let request = FileInfoRequest {
         path: config.model.path.clone(),
         is_sha: true,
@@ -0,0 +1,160 @@
+//! Liveness and readiness probes.
+//!
+//! `/healthz` answers as long as the process serves HTTP. `/readyz` answers 200 only if the server
+//! is not draining and every loaded model generates a token within [`SELF_TEST_TIMEOUT`].
+//! Self-test results are reused for [`SELF_TEST_TTL`], so that frequent probes don't occupy slots.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ai00_core::{GenerateRequest, ThreadRequest, Token};
+use salvo::prelude::*;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::{admission::Admission, request_models};
+use crate::types::ThreadSender;
+
+/// Time the runtime has to answer a probe, including the self-test generation.
+pub const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a self-test result is reused for.
+pub const SELF_TEST_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ModelReadiness {
+    pub name: String,
+    pub ready: bool,
+    /// Why the self-test failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Milliseconds the self-test took.
+    pub latency_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ReadyResponse {
+    /// `ready`, `not_ready` or `draining`.
+    pub status: String,
+    pub models: Vec<ModelReadiness>,
+}
+
+/// Runs the self-tests of `/readyz`.
+///
+/// Holds the sender to the runtime directly, so that probes are not accounted as usage.
+#[derive(Clone)]
+pub struct Readiness {
+    sender: ThreadSender,
+    last: Arc<Mutex<Option<(Instant, Vec<ModelReadiness>)>>>,
+}
+
+impl Readiness {
+    pub fn new(sender: ThreadSender) -> Self {
+        Self {
+            sender,
+            last: Default::default(),
+        }
+    }
+
+    /// Generate a single token with the named model.
+    async fn self_test(&self, name: String, tokenizer: Arc<Tokenizer>) -> ModelReadiness {
+        let start = Instant::now();
+        let (token_sender, token_receiver) = flume::unbounded();
+        let request = GenerateRequest {
+            prompt: "\n".into(),
+            max_tokens: 1,
+            request_id: Some(format!("readyz-{}", uuid::Uuid::now_v7())),
+            model: Some(name.clone()),
+            ..Default::default()
+        };
+        let _ = self.sender.send(ThreadRequest::Generate {
+            request: Box::new(request),
+            tokenizer,
+            sender: token_sender,
+        });
+
+        let generate = async {
+            while let Ok(token) = token_receiver.recv_async().await {
+                if let Token::Stop(..) = token {
+                    return Ok(());
+                }
+            }
+            Err("runtime dropped the generation".to_string())
+        };
+        let error = match tokio::time::timeout(SELF_TEST_TIMEOUT, generate).await {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err),
+            Err(_) => Some(format!("no token within {}s", SELF_TEST_TIMEOUT.as_secs())),
+        };
+        ModelReadiness {
+            name,
+            ready: error.is_none(),
+            error,
+            latency_ms: start.elapsed().as_millis() as u64,
+        }
+    }
+
+    /// Self-test all loaded models, or reuse the results of a recent run.
+    pub async fn check(&self) -> Vec<ModelReadiness> {
+        let mut last = self.last.lock().await;
+        if let Some((time, models)) = last.as_ref() {
+            if time.elapsed() < SELF_TEST_TTL {
+                return models.clone();
+            }
+        }
+
+        let infos = tokio::time::timeout(SELF_TEST_TIMEOUT, request_models(self.sender.clone()));
+        let models = match infos.await {
+            Ok(Ok(infos)) => {
+                let tests = infos
+                    .into_iter()
+                    .map(|info| self.self_test(info.name, info.tokenizer));
+                futures_util::future::join_all(tests).await
+            }
+            _ => vec![],
+        };
+        *last = Some((Instant::now(), models.clone()));
+        models
+    }
+}
+
+/// Liveness probe. Succeeds as long as the server answers.
+///
+/// `/healthz`.
+#[endpoint(responses((status_code = 200, description = "The server is alive")))]
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness probe. Succeeds if models are loaded and each of them generates a token.
+///
+/// `/readyz`.
+#[endpoint(responses(
+    (status_code = 200, body = ReadyResponse),
+    (status_code = 503, body = ReadyResponse),
+))]
+pub async fn readyz(depot: &mut Depot, res: &mut Response) {
+    let draining = depot
+        .obtain::<Admission>()
+        .is_ok_and(|admission| admission.is_draining());
+    let models = match depot.obtain::<Readiness>() {
+        Ok(readiness) if !draining => readiness.check().await,
+        _ => vec![],
+    };
+
+    let ready = !models.is_empty() && models.iter().all(|model| model.ready);
+    let status = match (draining, ready) {
+        (true, _) => "draining",
+        (false, true) => "ready",
+        (false, false) => "not_ready",
+    };
+    if status != "ready" {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    res.render(Json(ReadyResponse {
+        status: status.into(),
+        models,
+    }));
+}
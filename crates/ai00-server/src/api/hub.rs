@@ -0,0 +1,506 @@
+//! Downloads of model files from the Hugging Face Hub.
+//!
+//! Model, LoRA and state paths of the form `hf://<owner>/<repo>[@<revision>]/<file>` are fetched
+//! from `hub.endpoint` into `<model.path>/hub/<owner>/<repo>/<revision>/<file>` before loading.
+//! An interrupted download resumes from the `.partial` file it left behind. Files kept in Git LFS,
+//! as weights are, must match the SHA-256 reported by the Hub before they are moved in place, so a
+//! finished file of the expected size is reused without downloading it again.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_LENGTH, ETAG, LOCATION, RANGE},
+    redirect::Policy,
+    Client, Url,
+};
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+
+use super::error::ApiErrorResponse;
+use crate::{
+    config::{Config, HubOption},
+    logging,
+};
+
+pub const SCHEME: &str = "hf://";
+const DEFAULT_REVISION: &str = "main";
+/// Redirects followed while resolving a file, e.g. of renamed repositories.
+const MAX_REDIRECTS: usize = 5;
+
+/// A file in a model repository of the Hub.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HubFile {
+    /// `<owner>/<repo>`.
+    pub repo: String,
+    pub revision: String,
+    /// Path of the file within the repository.
+    pub file: String,
+}
+
+impl Display for HubFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            repo,
+            revision,
+            file,
+        } = self;
+        write!(f, "{SCHEME}{repo}@{revision}/{file}")
+    }
+}
+
+/// Whether `name` is a single, normal path component.
+fn is_component(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.contains('\\')
+}
+
+impl HubFile {
+    /// Parse `hf://<owner>/<repo>[@<revision>]/<file>`; `None` if `path` is not a Hub path.
+    pub fn parse(path: impl AsRef<Path>) -> Option<Result<Self>> {
+        let rest = path.as_ref().to_str()?.strip_prefix(SCHEME)?;
+        let mut parts = rest.splitn(3, '/');
+        let (Some(owner), Some(name), Some(file)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Some(Err(anyhow::anyhow!(
+                "expected {SCHEME}<owner>/<repo>[@<revision>]/<file>, got {SCHEME}{rest}"
+            )));
+        };
+        let (name, revision) = name.split_once('@').unwrap_or((name, DEFAULT_REVISION));
+        let valid = [owner, name, revision].into_iter().all(is_component)
+            && !file.is_empty()
+            && file.split('/').all(is_component);
+        if !valid {
+            return Some(Err(anyhow::anyhow!("invalid hub path {SCHEME}{rest}")));
+        }
+        Some(Ok(Self {
+            repo: format!("{owner}/{name}"),
+            revision: revision.into(),
+            file: file.into(),
+        }))
+    }
+
+    /// Whether `path` is a Hub path, failing if it is one that cannot be parsed.
+    pub fn check(path: impl AsRef<Path>) -> Result<bool> {
+        Self::parse(path).transpose().map(|file| file.is_some())
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        let endpoint = endpoint.trim_end_matches('/');
+        format!(
+            "{endpoint}/{}/resolve/{}/{}",
+            self.repo, self.revision, self.file
+        )
+    }
+
+    /// Where the file is stored under the model directory `models`.
+    pub fn local_path(&self, models: impl AsRef<Path>) -> PathBuf {
+        let mut path = models.as_ref().join("hub").join(&self.repo);
+        path.push(&self.revision);
+        path.extend(self.file.split('/'));
+        path
+    }
+}
+
+/// What the Hub tells about a file before downloading it.
+#[derive(Debug, Clone)]
+struct Remote {
+    /// Where the content is served, possibly a CDN.
+    url: Url,
+    size: Option<u64>,
+    sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadStatus {
+    Resolving,
+    Downloading,
+    Verifying,
+    Done,
+    Failed,
+}
+
+impl DownloadStatus {
+    fn is_active(self) -> bool {
+        !matches!(self, Self::Done | Self::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DownloadProgress {
+    /// The `hf://` path, with its revision.
+    pub source: String,
+    /// Where the file is stored.
+    pub path: String,
+    pub status: DownloadStatus,
+    /// Bytes on disk, including those of an earlier, interrupted download.
+    pub downloaded: u64,
+    /// Size of the file, if the Hub reports it.
+    pub total: Option<u64>,
+    /// Why the download failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Downloads `hf://` paths and keeps track of their progress.
+#[derive(Debug, Clone)]
+pub struct Hub {
+    option: HubOption,
+    token: Option<String>,
+    models: PathBuf,
+    /// Asks the Hub about files without following redirects, which drop their metadata.
+    resolver: Client,
+    client: Client,
+    downloads: Arc<Mutex<HashMap<String, DownloadProgress>>>,
+}
+
+impl Hub {
+    pub fn new(config: &Config) -> Result<Self> {
+        let option = config.hub.clone();
+        let token = option
+            .token
+            .clone()
+            .or_else(|| std::env::var("HF_TOKEN").ok())
+            .filter(|token| !token.is_empty());
+        Ok(Self {
+            option,
+            token,
+            models: config.model.path.clone(),
+            resolver: Client::builder().redirect(Policy::none()).build()?,
+            client: Client::new(),
+            downloads: Default::default(),
+        })
+    }
+
+    /// Progress of all downloads since startup, ordered by source.
+    pub fn downloads(&self) -> Vec<DownloadProgress> {
+        let downloads = self.downloads.lock().expect("hub downloads poisoned");
+        let mut downloads: Vec<_> = downloads.values().cloned().collect();
+        downloads.sort_by(|x, y| x.source.cmp(&y.source));
+        downloads
+    }
+
+    fn update(&self, source: &str, f: impl FnOnce(&mut DownloadProgress)) {
+        let mut downloads = self.downloads.lock().expect("hub downloads poisoned");
+        if let Some(progress) = downloads.get_mut(source) {
+            f(progress)
+        }
+    }
+
+    /// Download the file if `path` is a Hub path and return where it is stored;
+    /// return other paths as they are.
+    pub async fn resolve(&self, path: PathBuf) -> Result<PathBuf> {
+        match HubFile::parse(&path) {
+            Some(file) => self.download(&file?).await,
+            None => Ok(path),
+        }
+    }
+
+    /// Download the Hub paths of the model, LoRA, states and LoRA adapters in `config`,
+    /// replacing them with where they are stored.
+    pub async fn resolve_config(&self, mut config: Config) -> Result<Config> {
+        let name = self.resolve(config.model.name.clone()).await?;
+        config.model.name = name;
+        for lora in config.lora.iter_mut() {
+            lora.path = self.resolve(lora.path.clone()).await?;
+        }
+        for state in config.state.iter_mut() {
+            state.path = self.resolve(state.path.clone()).await?;
+        }
+        for lora in config
+            .lora_adapters
+            .iter_mut()
+            .flat_map(|adapter| adapter.lora.iter_mut())
+        {
+            lora.path = self.resolve(lora.path.clone()).await?;
+        }
+        Ok(config)
+    }
+
+    pub async fn download(&self, file: &HubFile) -> Result<PathBuf> {
+        let source = file.to_string();
+        let path = file.local_path(&self.models);
+        {
+            let mut downloads = self.downloads.lock().expect("hub downloads poisoned");
+            if downloads
+                .get(&source)
+                .is_some_and(|progress| progress.status.is_active())
+            {
+                bail!("{source} is already being downloaded");
+            }
+            let progress = DownloadProgress {
+                source: source.clone(),
+                path: path.to_string_lossy().into(),
+                status: DownloadStatus::Resolving,
+                downloaded: 0,
+                total: None,
+                error: None,
+            };
+            downloads.insert(source.clone(), progress);
+        }
+
+        let result = self.fetch(file, &source, &path).await;
+        self.update(&source, |progress| match &result {
+            Ok(_) => progress.status = DownloadStatus::Done,
+            Err(err) => {
+                progress.status = DownloadStatus::Failed;
+                progress.error = Some(err.to_string());
+            }
+        });
+        match result {
+            Ok(_) => Ok(path),
+            Err(err) => {
+                logging::errors::model_download_failed(&source, &err.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    async fn remote(&self, file: &HubFile) -> Result<Remote> {
+        let mut url = Url::parse(&file.url(&self.option.endpoint))?;
+        for _ in 0..MAX_REDIRECTS {
+            let mut request = self.resolver.head(url.clone());
+            if let Some(token) = &self.token {
+                request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+            }
+            let response = request.send().await?;
+            let status = response.status();
+            let header = |name: &str| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.trim_start_matches("W/").trim_matches('"').to_string())
+            };
+            let sha256 = header("x-linked-etag")
+                .or_else(|| header(ETAG.as_str()))
+                .filter(|etag| etag.len() == 64 && etag.chars().all(|c| c.is_ascii_hexdigit()));
+            let linked_size = header("x-linked-size").and_then(|size| size.parse().ok());
+
+            if status.is_redirection() {
+                let Some(location) = header(LOCATION.as_str()) else {
+                    bail!("{file}: the hub redirected without a location");
+                };
+                let location = url.join(&location)?;
+                // LFS files redirect to their storage along with their metadata
+                if linked_size.is_some() {
+                    return Ok(Remote {
+                        url: location,
+                        size: linked_size,
+                        sha256,
+                    });
+                }
+                url = location;
+                continue;
+            }
+            if !status.is_success() {
+                bail!("{file}: the hub answered {status}");
+            }
+            let size = linked_size
+                .or_else(|| header(CONTENT_LENGTH.as_str()).and_then(|size| size.parse().ok()));
+            return Ok(Remote { url, size, sha256 });
+        }
+        bail!("{file}: too many redirects")
+    }
+
+    async fn fetch(&self, file: &HubFile, source: &str, path: &Path) -> Result<()> {
+        let remote = match self.remote(file).await {
+            Ok(remote) => remote,
+            // a finished download is verified already, so it is usable offline
+            Err(err) if path.is_file() => {
+                tracing::warn!(%source, %err, "hub unreachable, using the downloaded file");
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        self.update(source, |progress| progress.total = remote.size);
+
+        if let Ok(meta) = fs::metadata(path).await {
+            if remote.size.map_or(true, |size| size == meta.len()) {
+                self.update(source, |progress| progress.downloaded = meta.len());
+                return Ok(());
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut partial = path.to_path_buf().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        let mut offset = fs::metadata(&partial)
+            .await
+            .map(|meta| meta.len())
+            .unwrap_or_default();
+        if remote.size.is_some_and(|size| offset > size) {
+            fs::remove_file(&partial).await?;
+            offset = 0;
+        }
+        logging::model::model_download(source, &path.to_string_lossy(), offset);
+
+        if remote.size != Some(offset) {
+            self.update(source, |progress| {
+                progress.status = DownloadStatus::Downloading;
+                progress.downloaded = offset;
+            });
+            let mut request = self.client.get(remote.url.clone());
+            if offset > 0 {
+                request = request.header(RANGE, format!("bytes={offset}-"));
+            }
+            let mut response = request.send().await?.error_for_status()?;
+            let mut output = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&partial)
+                .await?;
+            // the server may ignore the range and send the whole file
+            let mut downloaded = match response.status() {
+                StatusCode::PARTIAL_CONTENT => offset,
+                _ => {
+                    output.set_len(0).await?;
+                    0
+                }
+            };
+            while let Some(chunk) = response.chunk().await? {
+                output.write_all(&chunk).await?;
+                downloaded += chunk.len() as u64;
+                self.update(source, |progress| progress.downloaded = downloaded);
+            }
+            output.sync_all().await?;
+        }
+
+        let len = fs::metadata(&partial).await?.len();
+        if let Some(size) = remote.size.filter(|&size| size != len) {
+            fs::remove_file(&partial).await?;
+            bail!("{source}: got {len} bytes, expected {size}");
+        }
+        if let Some(expected) = &remote.sha256 {
+            self.update(source, |progress| {
+                progress.status = DownloadStatus::Verifying
+            });
+            let file = partial.clone();
+            let actual = tokio::task::spawn_blocking(move || sha256(file)).await??;
+            if &actual != expected {
+                fs::remove_file(&partial).await?;
+                bail!("{source}: checksum mismatch, expected {expected}, got {actual}");
+            }
+        }
+        fs::rename(&partial, path).await?;
+        logging::model::model_downloaded(source, &path.to_string_lossy(), len);
+        Ok(())
+    }
+}
+
+fn sha256(path: impl AsRef<Path>) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut sha = Sha256::new();
+    std::io::copy(&mut file, &mut sha)?;
+    Ok(format!("{:x}", sha.finalize()))
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct DownloadRequest {
+    /// `hf://<owner>/<repo>[@<revision>]/<file>`.
+    pub path: String,
+}
+
+/// Report the progress of downloads from the Hugging Face Hub since startup.
+///
+/// `/api/models/download/progress`.
+#[endpoint(responses((status_code = 200, body = Vec<DownloadProgress>)))]
+pub async fn progress(depot: &mut Depot) -> Json<Vec<DownloadProgress>> {
+    let downloads = depot
+        .obtain::<Hub>()
+        .map(Hub::downloads)
+        .unwrap_or_default();
+    Json(downloads)
+}
+
+/// Start downloading a file from the Hugging Face Hub without loading it.
+///
+/// `/admin/models/download`.
+#[endpoint(responses((status_code = 202, description = "The download started")))]
+pub async fn download(depot: &mut Depot, req: JsonBody<DownloadRequest>, res: &mut Response) {
+    let Ok(hub) = depot.obtain::<Hub>().cloned() else {
+        ApiErrorResponse::api_error("hub downloads are not available").respond(res);
+        return;
+    };
+    let file = match HubFile::parse(&req.0.path) {
+        Some(Ok(file)) => file,
+        Some(Err(err)) => {
+            ApiErrorResponse::invalid_request(err.to_string()).respond(res);
+            return;
+        }
+        None => {
+            let message = format!("expected a path starting with {SCHEME}");
+            ApiErrorResponse::invalid_request(message).respond(res);
+            return;
+        }
+    };
+    tokio::spawn(async move { hub.download(&file).await });
+    res.status_code(StatusCode::ACCEPTED);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hub_paths() {
+        let file = HubFile::parse("hf://BlinkDL/rwkv7-g1/rwkv7-g1a-0.1b.pth")
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.repo, "BlinkDL/rwkv7-g1");
+        assert_eq!(file.revision, "main");
+        assert_eq!(file.file, "rwkv7-g1a-0.1b.pth");
+        assert_eq!(
+            file.url("https://huggingface.co/"),
+            "https://huggingface.co/BlinkDL/rwkv7-g1/resolve/main/rwkv7-g1a-0.1b.pth"
+        );
+        assert_eq!(
+            file.local_path("assets/models"),
+            Path::new("assets/models/hub/BlinkDL/rwkv7-g1/main/rwkv7-g1a-0.1b.pth")
+        );
+
+        let file = HubFile::parse("hf://owner/repo@v1/nested/model.st")
+            .unwrap()
+            .unwrap();
+        assert_eq!(file.revision, "v1");
+        assert_eq!(file.file, "nested/model.st");
+        assert_eq!(file.to_string(), "hf://owner/repo@v1/nested/model.st");
+    }
+
+    #[test]
+    fn test_rejects_escaping_hub_paths() {
+        assert!(HubFile::parse("model.st").is_none());
+        assert!(HubFile::parse("hf://owner/repo").unwrap().is_err());
+        assert!(HubFile::parse("hf://owner/repo/../../etc/passwd")
+            .unwrap()
+            .is_err());
+        assert!(HubFile::parse("hf://owner/..@main/model.st")
+            .unwrap()
+            .is_err());
+        assert!(HubFile::parse("hf://owner/repo//model.st")
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_hub_paths() {
+        assert!(!HubFile::check("model.st").unwrap());
+        assert!(HubFile::check("hf://owner/repo/model.st").unwrap());
+        assert!(HubFile::check("hf://owner/repo").is_err());
+    }
+}
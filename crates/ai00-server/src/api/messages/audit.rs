@@ -0,0 +1,381 @@
+//! Audit log of Messages requests, appended as JSONL to a rotating file or POSTed to an endpoint.
+//!
+//! A record is the request as a `/v1/messages` body with the answer appended as its last message,
+//! so that `make-binidx` reads the log as is. Under `audit` it carries the request id, the client,
+//! the final prompt, the raw output, the tool calls, the usage and the timings of the generation.
+//! Fields named in `audit.redact` are removed, masked or hashed before the record leaves.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ai00_core::TokenCounter;
+use anyhow::Result;
+use flume::Sender;
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Client,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::types::{ContentBlock, MessagesRequest, StopReason, Usage};
+use crate::config::{AuditOption, Redact};
+
+/// What a request produced, for its audit record.
+#[derive(Debug, Clone)]
+pub struct AuditEntry<'a> {
+    pub request_id: &'a str,
+    /// The prompt of the last generation.
+    pub prompt: &'a str,
+    /// The text the last generation output.
+    pub output: &'a str,
+    /// The answer, parsed from the output.
+    pub content: &'a [ContentBlock],
+    pub stop_reason: StopReason,
+    pub counter: &'a TokenCounter,
+}
+
+/// Sink of audit records, handed to each request with the client it is attributed to.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    redact: Arc<Vec<(Vec<String>, Redact)>>,
+    sinks: Vec<Sender<Arc<String>>>,
+    client: Option<String>,
+}
+
+impl AuditLog {
+    /// Open the sinks of `option`; `None` if it has neither a file nor an endpoint.
+    pub fn new(option: &AuditOption) -> Result<Option<Self>> {
+        let mut sinks = vec![];
+        if let Some(path) = &option.path {
+            let file = RotatingFile::open(path.clone(), option.max_bytes, option.max_files)?;
+            sinks.push(file.spawn());
+        }
+        if let Some(url) = &option.url {
+            let client = Client::builder()
+                .timeout(Duration::from_secs(option.timeout))
+                .build()?;
+            sinks.push(spawn_http(client, url.clone(), option.token.clone()));
+        }
+        if sinks.is_empty() {
+            return Ok(None);
+        }
+
+        let mut redact: Vec<_> = option
+            .redact
+            .iter()
+            .map(|(path, &action)| (path.split('.').map(String::from).collect(), action))
+            .collect();
+        redact.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(Some(Self {
+            redact: Arc::new(redact),
+            sinks,
+            client: None,
+        }))
+    }
+
+    /// The log of requests of `client`.
+    pub fn with_client(&self, client: String) -> Self {
+        Self {
+            client: Some(client),
+            ..self.clone()
+        }
+    }
+
+    fn to_record(&self, request: &MessagesRequest, entry: AuditEntry) -> Result<Value> {
+        let mut record = serde_json::to_value(request)?;
+        if let Some(messages) = record["messages"].as_array_mut() {
+            messages.push(json!({ "role": "assistant", "content": entry.content }));
+        }
+        let tool_calls: Vec<_> = entry
+            .content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::ToolUse { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        record["audit"] = json!({
+            "request_id": entry.request_id,
+            "timestamp": timestamp,
+            "client": self.client,
+            "prompt": entry.prompt,
+            "output": entry.output,
+            "stop_reason": entry.stop_reason,
+            "tool_calls": tool_calls,
+            "usage": Usage::from(entry.counter.clone()),
+            "timings": {
+                "queue_wait_ms": millis(entry.counter.queue_wait),
+                "prefill_ms": millis(entry.counter.prefill),
+                "decode_ms": millis(entry.counter.decode),
+                "total_ms": millis(entry.counter.duration),
+            },
+        });
+
+        for (path, action) in self.redact.iter() {
+            let path: Vec<_> = path.iter().map(String::as_str).collect();
+            redact(&mut record, &path, *action);
+        }
+        Ok(record)
+    }
+
+    /// Write the record of a finished request.
+    pub fn record(&self, request: &MessagesRequest, entry: AuditEntry) {
+        let line = match self.to_record(request, entry) {
+            Ok(record) => Arc::new(record.to_string()),
+            Err(err) => {
+                tracing::warn!(event = "audit_record_failed", error = %err);
+                return;
+            }
+        };
+        for sink in &self.sinks {
+            let _ = sink.send(line.clone());
+        }
+    }
+}
+
+/// Apply `action` to the fields at `path`, where `*` matches every item of an array or object.
+fn redact(value: &mut Value, path: &[&str], action: Redact) {
+    let Some((&key, rest)) = path.split_first() else {
+        return;
+    };
+    let redacted = |value: &Value| match action {
+        Redact::Remove => Value::Null,
+        Redact::Mask => "[redacted]".into(),
+        Redact::Hash => {
+            let text = match value {
+                Value::String(text) => text.clone(),
+                value => value.to_string(),
+            };
+            format!("sha256:{:x}", Sha256::digest(text.as_bytes())).into()
+        }
+    };
+
+    match value {
+        Value::Object(map) if rest.is_empty() && action == Redact::Remove => {
+            map.retain(|name, _| key != "*" && name != key);
+        }
+        Value::Object(map) => {
+            for (name, child) in map.iter_mut() {
+                match (key == "*" || name == key, rest.is_empty()) {
+                    (true, true) => *child = redacted(child),
+                    (true, false) => redact(child, rest, action),
+                    _ => {}
+                }
+            }
+        }
+        Value::Array(items) => {
+            let matches = |index: usize| key == "*" || key.parse::<usize>().ok() == Some(index);
+            if rest.is_empty() && action == Redact::Remove {
+                let mut index = 0;
+                items.retain(|_| {
+                    index += 1;
+                    !matches(index - 1)
+                });
+                return;
+            }
+            for (index, child) in items.iter_mut().enumerate() {
+                match (matches(index), rest.is_empty()) {
+                    (true, true) => *child = redacted(child),
+                    (true, false) => redact(child, rest, action),
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A JSONL file rotated to `<path>.1`, `<path>.2` and so on once it grows past `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                std::fs::rename(from, self.rotated(index + 1))?;
+            }
+        }
+        match self.max_files {
+            0 => std::fs::remove_file(&self.path)?,
+            _ => std::fs::rename(&self.path, self.rotated(1))?,
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Append lines sent to the returned channel to the file, on a dedicated thread.
+    fn spawn(mut self) -> Sender<Arc<String>> {
+        let (sender, receiver) = flume::unbounded::<Arc<String>>();
+        std::thread::spawn(move || {
+            while let Ok(line) = receiver.recv() {
+                if let Err(err) = self.append(&line) {
+                    tracing::warn!(event = "audit_write_failed", path = %self.path.display(), error = %err);
+                }
+            }
+        });
+        sender
+    }
+}
+
+/// POST lines sent to the returned channel to `url`, one record per request.
+fn spawn_http(client: Client, url: String, token: Option<String>) -> Sender<Arc<String>> {
+    let (sender, receiver) = flume::unbounded::<Arc<String>>();
+    tokio::spawn(async move {
+        while let Ok(line) = receiver.recv_async().await {
+            let mut request = client
+                .post(&url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(line.to_string());
+            if let Some(token) = &token {
+                request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+            }
+            let result = match request.send().await {
+                Ok(response) if !response.status().is_success() => {
+                    Err(response.status().to_string())
+                }
+                Ok(_) => Ok(()),
+                Err(err) => Err(err.to_string()),
+            };
+            if let Err(err) = result {
+                tracing::warn!(event = "audit_post_failed", error = %err);
+            }
+        }
+    });
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_paths() {
+        let mut record = json!({
+            "system": "secret",
+            "messages": [
+                { "role": "user", "content": "my email is a@b.c" },
+                { "role": "assistant", "content": "noted" }
+            ],
+            "metadata": { "user_id": "u1" },
+            "audit": { "prompt": "...", "client": "key_1" }
+        });
+        redact(&mut record, &["messages", "*", "content"], Redact::Mask);
+        redact(&mut record, &["audit", "prompt"], Redact::Remove);
+        redact(&mut record, &["metadata", "user_id"], Redact::Hash);
+        redact(&mut record, &["missing", "field"], Redact::Remove);
+
+        assert_eq!(record["system"], "secret");
+        assert_eq!(record["messages"][0]["content"], "[redacted]");
+        assert_eq!(record["messages"][1]["content"], "[redacted]");
+        assert_eq!(record["messages"][1]["role"], "assistant");
+        assert!(record["audit"].get("prompt").is_none());
+        assert_eq!(record["audit"]["client"], "key_1");
+        let hash = record["metadata"]["user_id"].as_str().unwrap();
+        assert!(hash.starts_with("sha256:") && !hash.contains("u1"));
+    }
+
+    #[test]
+    fn test_record_reads_as_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let option = AuditOption {
+            path: Some(dir.path().join("audit.jsonl")),
+            redact: [("audit.prompt".to_string(), Redact::Remove)].into(),
+            ..Default::default()
+        };
+        let audit = AuditLog::new(&option)
+            .unwrap()
+            .unwrap()
+            .with_client("key_1".into());
+
+        let request: MessagesRequest = serde_json::from_value(json!({
+            "model": "rwkv",
+            "max_tokens": 16,
+            "messages": [{ "role": "user", "content": "Hi" }]
+        }))
+        .unwrap();
+        let content = [ContentBlock::Text {
+            text: "Hello!".into(),
+        }];
+        let entry = AuditEntry {
+            request_id: "req_1",
+            prompt: "User: Hi",
+            output: "Hello!",
+            content: &content,
+            stop_reason: StopReason::EndTurn,
+            counter: &TokenCounter::default(),
+        };
+        let record = audit.to_record(&request, entry).unwrap();
+        assert_eq!(record["audit"]["client"], "key_1");
+        assert!(record["audit"].get("prompt").is_none());
+
+        let parsed: MessagesRequest = serde_json::from_value(record).unwrap();
+        assert_eq!(parsed.messages.len(), 2);
+        assert_eq!(parsed.messages[1].content.to_text(), "Hello!");
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut file = RotatingFile::open(path.clone(), 8, 1).unwrap();
+        for line in ["{\"a\":1}", "{\"a\":2}", "{\"a\":3}"] {
+            file.append(line).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"a\":3}\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("audit.jsonl.1")).unwrap(),
+            "{\"a\":2}\n"
+        );
+        assert!(!dir.path().join("audit.jsonl.2").exists());
+    }
+}
@@ -0,0 +1,287 @@
+//! Batch inference: a JSONL file of Messages requests answered in one call.
+//!
+//! `POST /v1/batches` takes one request per line, `{"custom_id": "...", "params": {...}}` with
+//! `params` a `/v1/messages` request, and answers with one result per line as soon as each is
+//! done, so in completion order rather than input order:
+//! `{"custom_id": "...", "result": {"type": "succeeded", "message": {...}}}`, or
+//! `{"type": "errored", "error": {...}}` for a request that failed. Requests are generated
+//! concurrently to keep every slot of the runtime busy, without the round trips of one HTTP
+//! request each. Every request of the batch is rate limited and admitted on its own.
+
+use std::{collections::HashSet, convert::Infallible, sync::Arc};
+
+use futures_util::StreamExt;
+use salvo::{http::header::CONTENT_TYPE, hyper::body::Bytes, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    audit::AuditLog,
+    handler::complete_request,
+    types::{MessagesRequest, MessagesResponse},
+};
+use crate::{
+    api::{
+        admission, current_request_id,
+        error::ApiErrorResponse,
+        rate_limit::RateClient,
+        request_info,
+        shared::SharedState,
+        usage::{client_id, Attribution},
+    },
+    config::Config,
+    logging::RequestContext,
+    types::ThreadSender,
+    SLEEP,
+};
+
+/// A line of the input of a batch.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct BatchRequest {
+    /// Identifies the result of the request; unique within the batch.
+    pub custom_id: String,
+    pub params: MessagesRequest,
+}
+
+/// A line of the output of a batch.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BatchResult {
+    pub custom_id: String,
+    pub result: BatchOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BatchOutcome {
+    Succeeded { message: MessagesResponse },
+    Errored { error: ApiErrorResponse },
+}
+
+/// Parse the JSONL `body` of a batch, skipping empty lines.
+fn parse_batch(body: &str, max_requests: usize) -> Result<Vec<BatchRequest>, ApiErrorResponse> {
+    let mut requests: Vec<BatchRequest> = vec![];
+    let mut ids = HashSet::new();
+    let lines = body
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    for (index, line) in lines {
+        let request: BatchRequest = serde_json::from_str(line).map_err(|err| {
+            ApiErrorResponse::invalid_request(format!("line {}: {err}", index + 1))
+                .with_param(format!("lines.{index}"))
+        })?;
+        if !ids.insert(request.custom_id.clone()) {
+            let message = format!("line {}: duplicate custom_id", index + 1);
+            return Err(ApiErrorResponse::invalid_request(message)
+                .with_param(format!("lines.{index}.custom_id")));
+        }
+        requests.push(request);
+        if requests.len() > max_requests {
+            return Err(ApiErrorResponse::request_too_large(format!(
+                "a batch holds at most {max_requests} requests"
+            )));
+        }
+    }
+    match requests.is_empty() {
+        true => Err(ApiErrorResponse::invalid_request(
+            "the batch has no requests",
+        )),
+        false => Ok(requests),
+    }
+}
+
+/// Copy the `T` of `from`, if any, to `to`.
+fn copy<T: Clone + Send + Sync + 'static>(from: &Depot, to: &mut Depot) {
+    if let Ok(value) = from.obtain::<T>() {
+        to.inject(value.clone());
+    }
+}
+
+/// A depot for one request of the batch, with the shared state and what the hoops of the batch
+/// request injected on top of it: the runtime sender relaying to its trace and usage, the live
+/// config, the audit log of the client and its rate limit bucket.
+pub(super) fn request_depot(depot: &Depot, trace_id: Option<String>) -> Depot {
+    let mut request_depot = Depot::new();
+    if let Ok(state) = depot.obtain::<SharedState>() {
+        state.fill(&mut request_depot);
+    }
+    copy::<ThreadSender>(depot, &mut request_depot);
+    copy::<Arc<Config>>(depot, &mut request_depot);
+    copy::<AuditLog>(depot, &mut request_depot);
+    copy::<RateClient>(depot, &mut request_depot);
+    copy::<Attribution>(depot, &mut request_depot);
+    request_depot.insert("request_context", RequestContext::new(trace_id));
+    request_depot
+}
+
+/// Admit a request of a batch or WebSocket session built with [`request_depot`]: take it from the
+/// rate limit bucket of the client and wait for admission, as for a request of its own.
+pub(super) async fn admit_request(depot: &mut Depot) -> Result<(), ApiErrorResponse> {
+    if let Ok(client) = depot.obtain::<RateClient>() {
+        client.admit()?;
+    }
+    admission::admit_generation(depot).await
+}
+
+/// Admit and answer one request of the batch, with its depot from [`request_depot`].
+async fn answer(request: BatchRequest, mut depot: Depot) -> BatchResult {
+    let BatchRequest { custom_id, params } = request;
+    let completed = match admit_request(&mut depot).await {
+        Ok(()) => complete_request(&mut depot, params).await,
+        Err(err) => Err(err),
+    };
+    let result = match completed {
+        Ok(message) => BatchOutcome::Succeeded { message },
+        Err(error) => BatchOutcome::Errored { error },
+    };
+    BatchResult { custom_id, result }
+}
+
+fn to_line(result: &BatchResult) -> Bytes {
+    let mut line = serde_json::to_vec(result).unwrap_or_default();
+    line.push(b'\n');
+    Bytes::from(line)
+}
+
+/// Answer a JSONL file of Messages requests, streaming a JSONL line per result.
+///
+/// Results come in completion order; match them to requests by `custom_id`.
+///
+/// `/v1/batches`.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 200, description = "JSONL of BatchResult", body = BatchResult),
+        (status_code = 400, description = "Invalid batch", body = ApiErrorResponse),
+        (status_code = 413, description = "Batch too large", body = ApiErrorResponse),
+    )
+)]
+pub async fn batches(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let config = depot.obtain::<Arc<Config>>().unwrap().clone();
+    let body = match req.payload_with_max_size(config.batch.max_bytes).await {
+        Ok(body) => body.clone(),
+        Err(err) => {
+            let message = format!("failed to read the batch: {err}");
+            return ApiErrorResponse::request_too_large(message).respond(res);
+        }
+    };
+    let Ok(body) = std::str::from_utf8(&body) else {
+        return ApiErrorResponse::invalid_request("the batch is not UTF-8").respond(res);
+    };
+    let requests = match parse_batch(body, config.batch.max_requests) {
+        Ok(requests) => requests,
+        Err(err) => return err.respond(res),
+    };
+
+    if let Ok(audit) = depot.obtain::<AuditLog>() {
+        let audit = audit.with_client(client_id(req, &config));
+        depot.inject(audit);
+    }
+    let concurrency = match config.batch.concurrency {
+        Some(concurrency) => concurrency.max(1),
+        None => {
+            let sender = depot.obtain::<ThreadSender>().unwrap();
+            request_info(sender.clone(), SLEEP)
+                .await
+                .reload
+                .max_batch
+                .max(1)
+        }
+    };
+
+    let batch_id = current_request_id(depot);
+    tracing::info!(
+        event = "batch_started",
+        request_id = batch_id.as_deref().unwrap_or_default(),
+        requests = requests.len(),
+        concurrency,
+    );
+
+    let requests: Vec<_> = requests
+        .into_iter()
+        .map(|request| (request, request_depot(depot, batch_id.clone())))
+        .collect();
+    let results = futures_util::stream::iter(requests)
+        .map(|(request, depot)| async move {
+            Ok::<_, Infallible>(to_line(&answer(request, depot).await))
+        })
+        .buffer_unordered(concurrency);
+
+    res.headers_mut()
+        .insert(CONTENT_TYPE, "application/x-ndjson".parse().unwrap());
+    res.stream(results);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::messages::Moderator, config::ModerationOption};
+
+    const REQUEST: &str =
+        r#"{"model": "rwkv", "max_tokens": 16, "messages": [{"role": "user", "content": "Hi"}]}"#;
+
+    #[test]
+    fn test_parse_batch() {
+        let body = format!(
+            "{{\"custom_id\": \"a\", \"params\": {REQUEST}}}\n\n{{\"custom_id\": \"b\", \"params\": {REQUEST}}}\n"
+        );
+        let requests = parse_batch(&body, 10).unwrap();
+        let ids: Vec<_> = requests.iter().map(|r| r.custom_id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+
+        let err = parse_batch(&body, 1).unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let duplicate = format!("{{\"custom_id\": \"a\", \"params\": {REQUEST}}}\n").repeat(2);
+        let err = parse_batch(&duplicate, 10).unwrap_err();
+        assert_eq!(err.error.param.as_deref(), Some("lines.1.custom_id"));
+
+        let err = parse_batch("{\"custom_id\": \"a\"}", 10).unwrap_err();
+        assert_eq!(err.error.param.as_deref(), Some("lines.0"));
+        assert!(parse_batch("\n", 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_request() {
+        let option = ModerationOption {
+            keywords: vec!["launch codes".into()],
+            ..Default::default()
+        };
+        let moderator = Moderator::new(&option).unwrap();
+        let mut depot = Depot::new();
+        SharedState::default()
+            .inject(Arc::new(Config::default()))
+            .inject(moderator)
+            .fill(&mut depot);
+
+        let line = r#"{"model": "rwkv", "max_tokens": 16, "messages": [{"role": "user", "content": "the launch codes are"}]}"#;
+        let request = BatchRequest {
+            custom_id: "a".into(),
+            params: serde_json::from_str(line).unwrap(),
+        };
+        let result = answer(request, request_depot(&depot, None)).await;
+        assert_eq!(result.custom_id, "a");
+        let BatchOutcome::Errored { error } = result.result else {
+            panic!("a blocked prompt is generated from");
+        };
+        assert_eq!(error.error.param.as_deref(), Some("messages"));
+    }
+
+    #[test]
+    fn test_result_line() {
+        let result = BatchResult {
+            custom_id: "a".into(),
+            result: BatchOutcome::Errored {
+                error: ApiErrorResponse::invalid_request("max_tokens must be greater than 0"),
+            },
+        };
+        let line = to_line(&result);
+        assert!(line.ends_with(b"\n"));
+        let value: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(value["custom_id"], "a");
+        assert_eq!(value["result"]["type"], "errored");
+        assert_eq!(
+            value["result"]["error"]["error"]["type"],
+            "invalid_request_error"
+        );
+    }
+}
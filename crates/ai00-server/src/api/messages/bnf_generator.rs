@@ -4,9 +4,32 @@
 //! KBNF grammar rules, enabling schema-specific constrained decoding.
 
 use serde_json::Value;
-use std::collections::HashSet;
-
-use super::types::Tool;
+use std::collections::{HashMap, HashSet};
+
+use super::types::{ResponseFormat, Tool};
+
+/// Most optional array items spelled out for `maxItems`; larger ranges only keep `minItems`.
+const MAX_OPTIONAL_ITEMS: u64 = 32;
+
+/// Most integers of a `minimum`..`maximum` range listed one by one.
+const MAX_LISTED_INTEGERS: i64 = 64;
+
+/// Patterns of the string `format`s that grammars enforce.
+const STRING_FORMATS: &[(&str, &str)] = &[
+    (
+        "date-time",
+        r"[0-9]{4}-[0-9]{2}-[0-9]{2}[Tt][0-9]{2}:[0-9]{2}:[0-9]{2}(\.[0-9]+)?([Zz]|[+-][0-9]{2}:[0-9]{2})",
+    ),
+    ("date", r"[0-9]{4}-[0-9]{2}-[0-9]{2}"),
+    (
+        "time",
+        r"[0-9]{2}:[0-9]{2}:[0-9]{2}(\.[0-9]+)?([Zz]|[+-][0-9]{2}:[0-9]{2})?",
+    ),
+    (
+        "uuid",
+        r"[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}",
+    ),
+];
 
 /// Context for generating unique rule names during recursive schema conversion.
 #[derive(Debug, Default)]
@@ -15,6 +38,10 @@ pub struct GeneratorContext {
     rule_counter: usize,
     /// Accumulated grammar rules
     rules: Vec<String>,
+    /// Schema that `$ref`s point into
+    root: Value,
+    /// Rules of the `$ref`s converted so far, so that recursive schemas terminate
+    refs: HashMap<String, String>,
 }
 
 impl GeneratorContext {
@@ -23,6 +50,12 @@ impl GeneratorContext {
         Self::default()
     }
 
+    /// Resolve `$ref`s of the schemas converted from now on against `root`.
+    pub fn set_root(&mut self, root: &Value) {
+        self.root = root.clone();
+        self.refs.clear();
+    }
+
     /// Generate a unique rule name with the given prefix.
     pub fn unique_rule(&mut self, prefix: &str) -> String {
         let name = format!("{}_{}", prefix, self.rule_counter);
@@ -44,12 +77,19 @@ impl GeneratorContext {
 /// Convert a JSON Schema to KBNF grammar rules.
 ///
 /// Supports:
-/// - `type: "object"` with `properties` and `required`
-/// - `type: "string"` with optional `enum`
-/// - `type: "number"` and `type: "integer"`
+/// - `type: "object"` with `properties` and `required`; other properties only as far as
+///   `additionalProperties` allows them, and not at all if it is missing
+/// - `type: "string"` with optional `enum`, or a `format` of `date-time`, `date`, `time` or `uuid`
+/// - `type: "number"` and `type: "integer"`, with `minimum`/`maximum` and their exclusive forms
 /// - `type: "boolean"`
-/// - `type: "array"` with optional `items`
+/// - `type: "array"` with optional `items`, `minItems` and `maxItems`
+/// - a list of types, e.g. `["string", "null"]`
 /// - `anyOf` and `oneOf` (converted to alternation)
+/// - `$ref`, resolved against the root given to [`GeneratorContext::set_root`] (e.g. into
+///   `$defs`)
+///
+/// Numeric ranges are approximated by the sign and number of digits, except for short integer
+/// ranges whose values are listed.
 ///
 /// # Arguments
 /// * `schema` - The JSON Schema value
@@ -59,6 +99,10 @@ impl GeneratorContext {
 /// # Returns
 /// The name of the generated rule (may be same as input or a reference to base rules)
 pub fn json_schema_to_kbnf(schema: &Value, rule_name: &str, ctx: &mut GeneratorContext) -> String {
+    if let Some(reference) = schema.get("$ref").and_then(|v| v.as_str()) {
+        return handle_ref(reference, rule_name, ctx);
+    }
+
     // Handle anyOf/oneOf first
     if let Some(any_of) = schema.get("anyOf").and_then(|v| v.as_array()) {
         return handle_any_of(any_of, rule_name, ctx);
@@ -67,13 +111,24 @@ pub fn json_schema_to_kbnf(schema: &Value, rule_name: &str, ctx: &mut GeneratorC
         return handle_any_of(one_of, rule_name, ctx); // Same handling
     }
 
+    // A list of types is an alternation of the schema with each type
+    if let Some(types) = schema.get("type").and_then(|t| t.as_array()) {
+        let variants: Vec<Value> = types
+            .iter()
+            .map(|kind| {
+                let mut variant = schema.clone();
+                variant["type"] = kind.clone();
+                variant
+            })
+            .collect();
+        return handle_any_of(&variants, rule_name, ctx);
+    }
+
     match schema.get("type").and_then(|t| t.as_str()) {
         Some("object") => handle_object(schema, rule_name, ctx),
         Some("string") => handle_string(schema, rule_name, ctx),
-        Some("number") | Some("integer") => {
-            ctx.add_rule(format!("{}::=number;", rule_name));
-            rule_name.to_string()
-        }
+        Some("number") => handle_number(schema, rule_name, false, ctx),
+        Some("integer") => handle_number(schema, rule_name, true, ctx),
         Some("boolean") => {
             ctx.add_rule(format!("{}::='true' | 'false';", rule_name));
             rule_name.to_string()
@@ -91,6 +146,32 @@ pub fn json_schema_to_kbnf(schema: &Value, rule_name: &str, ctx: &mut GeneratorC
     }
 }
 
+/// Handle `$ref` by referring to the rule of the schema it points to, converted once.
+fn handle_ref(reference: &str, rule_name: &str, ctx: &mut GeneratorContext) -> String {
+    let target = match ctx.refs.get(reference) {
+        Some(target) => target.clone(),
+        None => {
+            let target = ctx.unique_rule("ref");
+            // registered before converting, for schemas that refer to themselves
+            ctx.refs.insert(reference.to_string(), target.clone());
+            let resolved = reference
+                .strip_prefix('#')
+                .and_then(|pointer| ctx.root.pointer(pointer))
+                .cloned();
+            match resolved {
+                Some(schema) => {
+                    json_schema_to_kbnf(&schema, &target, ctx);
+                }
+                // Unresolvable (e.g. remote) reference - allow any JSON value
+                None => ctx.add_rule(format!("{}::=json_value;", target)),
+            }
+            target
+        }
+    };
+    ctx.add_rule(format!("{}::={};", rule_name, target));
+    rule_name.to_string()
+}
+
 /// Handle anyOf/oneOf by converting to alternation.
 fn handle_any_of(variants: &[Value], rule_name: &str, ctx: &mut GeneratorContext) -> String {
     let mut variant_rules = Vec::new();
@@ -114,65 +195,98 @@ fn handle_object(schema: &Value, rule_name: &str, ctx: &mut GeneratorContext) ->
         .map(|arr| arr.iter().filter_map(|v| v.as_str()).collect())
         .unwrap_or_default();
 
+    // Members for properties other than the listed ones
+    let additional = schema.get("additionalProperties");
+    let extra = match additional {
+        Some(Value::Bool(true)) => Some("pair".to_string()),
+        Some(extra_schema @ Value::Object(_)) => {
+            let value_rule = ctx.unique_rule(&format!("{}_additional", rule_name));
+            json_schema_to_kbnf(extra_schema, &value_rule, ctx);
+            Some(format!("string ws ':' ws {}", value_rule))
+        }
+        _ => None,
+    };
+
     let props = match props {
         Some(p) if !p.is_empty() => p,
         _ => {
-            // Empty object or no properties - allow any JSON object
-            ctx.add_rule(format!("{}::=json_object;", rule_name));
+            let rule = match (&extra, additional) {
+                (Some(extra), _) => format!(
+                    "{}::='{{' ws ({} (ws ',' ws {})*)? ws '}}';",
+                    rule_name, extra, extra
+                ),
+                // No properties allowed at all
+                (None, Some(Value::Bool(false))) => format!("{}::='{{' ws '}}';", rule_name),
+                // Empty object or no properties - allow any JSON object
+                (None, _) => format!("{}::=json_object;", rule_name),
+            };
+            ctx.add_rule(rule);
             return rule_name.to_string();
         }
     };
 
-    // Separate required and optional properties
-    let mut required_props: Vec<(&String, &Value)> = Vec::new();
-    let mut optional_props: Vec<(&String, &Value)> = Vec::new();
-
-    for (key, value) in props {
-        if required.contains(key.as_str()) {
-            required_props.push((key, value));
-        } else {
-            optional_props.push((key, value));
-        }
-    }
-
-    // Generate rules for each property value
-    let mut property_parts = Vec::new();
-
-    // Required properties first
-    for (i, (key, prop_schema)) in required_props.iter().enumerate() {
+    // Generate rules for each property value, required properties first
+    let mut required_members = Vec::new();
+    let mut optional_members = Vec::new();
+    for (key, prop_schema) in props {
         let value_rule = ctx.unique_rule(&format!("{}_prop_{}", rule_name, key));
         json_schema_to_kbnf(prop_schema, &value_rule, ctx);
 
-        let comma = if i > 0 { "',' ws " } else { "" };
-        property_parts.push(format!("{}'\"{}\"' ws ':' ws {}", comma, key, value_rule));
+        let member = format!("'\"{}\"' ws ':' ws {}", escape_kbnf_string(key), value_rule);
+        match required.contains(key.as_str()) {
+            true => required_members.push(member),
+            false => optional_members.push(member),
+        }
     }
+    let extras = extra
+        .as_ref()
+        .map(|extra| format!("(ws ',' ws {})*", extra));
 
-    // Optional properties (wrapped in (...)?)
-    for (key, prop_schema) in optional_props.iter() {
-        let value_rule = ctx.unique_rule(&format!("{}_prop_{}", rule_name, key));
-        json_schema_to_kbnf(prop_schema, &value_rule, ctx);
-
-        // Optional property needs comma handling
-        let comma_prefix = if !property_parts.is_empty() || !required_props.is_empty() {
-            "',' ws "
-        } else {
-            ""
-        };
-        property_parts.push(format!(
-            "({}'\"{}\"' ws ':' ws {})?",
-            comma_prefix, key, value_rule
-        ));
-    }
+    // Members after the first are preceded by a comma
+    let rest = |members: &[String]| -> Vec<String> {
+        members
+            .iter()
+            .map(|member| format!("(ws ',' ws {})?", member))
+            .chain(extras.clone())
+            .collect()
+    };
+    let members = match required_members.is_empty() {
+        false => {
+            let mut parts = vec![required_members.join(" ws ',' ws ")];
+            parts.extend(rest(&optional_members));
+            parts.join(" ")
+        }
+        // Any of the optional members may come first, or none at all
+        true => {
+            let mut firsts: Vec<String> = optional_members
+                .iter()
+                .enumerate()
+                .map(|(i, member)| {
+                    let mut parts = vec![member.clone()];
+                    parts.extend(rest(&optional_members[i + 1..]));
+                    parts.join(" ")
+                })
+                .collect();
+            if let (Some(extra), Some(extras)) = (&extra, &extras) {
+                firsts.push(format!("{} {}", extra, extras));
+            }
+            format!("({})?", firsts.join(" | "))
+        }
+    };
 
     // Build the object rule
-    let members = property_parts.join(" ws ");
     ctx.add_rule(format!("{}::='{{' ws {} ws '}}';", rule_name, members));
 
     rule_name.to_string()
 }
 
-/// Handle string type with optional enum constraint.
+/// Handle string type with optional enum or format constraint.
 fn handle_string(schema: &Value, rule_name: &str, ctx: &mut GeneratorContext) -> String {
+    let format = schema
+        .get("format")
+        .and_then(|f| f.as_str())
+        .and_then(|format| STRING_FORMATS.iter().find(|(name, _)| *name == format));
+
     if let Some(enum_vals) = schema.get("enum").and_then(|e| e.as_array()) {
         // Enum: generate alternation of literal strings
         let vals: Vec<String> = enum_vals
@@ -186,6 +300,12 @@ fn handle_string(schema: &Value, rule_name: &str, ctx: &mut GeneratorContext) ->
         } else {
             ctx.add_rule(format!("{}::={};", rule_name, vals.join(" | ")));
         }
+    } else if let Some((_, pattern)) = format {
+        ctx.add_rule(format!(
+            "{}::='\"' {} '\"';",
+            rule_name,
+            regex_terminal(pattern)
+        ));
     } else {
         ctx.add_rule(format!("{}::=string;", rule_name));
     }
@@ -193,31 +313,160 @@ fn handle_string(schema: &Value, rule_name: &str, ctx: &mut GeneratorContext) ->
     rule_name.to_string()
 }
 
-/// Handle array type with optional items schema.
+/// A bound of a number: the tighter of its inclusive and exclusive keyword, and whether it is
+/// exclusive. `tighter(a, b)` tells whether `a` is tighter than `b`.
+fn numeric_bound(
+    schema: &Value,
+    inclusive: &str,
+    exclusive: &str,
+    tighter: fn(f64, f64) -> bool,
+) -> Option<(f64, bool)> {
+    let inclusive = schema.get(inclusive).and_then(|v| v.as_f64());
+    match schema.get(exclusive) {
+        // draft 4 marks the inclusive bound as exclusive
+        Some(Value::Bool(true)) => inclusive.map(|bound| (bound, true)),
+        Some(value) => match (value.as_f64(), inclusive) {
+            (Some(exclusive), Some(inclusive)) if tighter(inclusive, exclusive) => {
+                Some((inclusive, false))
+            }
+            (Some(exclusive), _) => Some((exclusive, true)),
+            (None, inclusive) => inclusive.map(|bound| (bound, false)),
+        },
+        None => inclusive.map(|bound| (bound, false)),
+    }
+}
+
+/// Pattern of the integer part of numbers up to `limit` in magnitude, if limited.
+fn magnitude_pattern(limit: Option<f64>) -> String {
+    match limit {
+        Some(limit) => {
+            let digits = (limit.abs().floor() as u64).to_string().len();
+            match digits {
+                1 => "[0-9]".to_string(),
+                _ => format!("(0|[1-9][0-9]{{0,{}}})", digits - 1),
+            }
+        }
+        None => "(0|[1-9][0-9]*)".to_string(),
+    }
+}
+
+/// Handle number and integer types with optional `minimum`/`maximum` constraints.
+fn handle_number(
+    schema: &Value,
+    rule_name: &str,
+    integer: bool,
+    ctx: &mut GeneratorContext,
+) -> String {
+    let low = numeric_bound(schema, "minimum", "exclusiveMinimum", |a, b| a > b);
+    let high = numeric_bound(schema, "maximum", "exclusiveMaximum", |a, b| a < b);
+    if low.is_none() && high.is_none() {
+        ctx.add_rule(format!("{}::=number;", rule_name));
+        return rule_name.to_string();
+    }
+
+    // Short integer ranges are listed exactly
+    if let (true, Some((low, low_exclusive)), Some((high, high_exclusive))) = (integer, low, high) {
+        let first = match low_exclusive {
+            true => low.floor() as i64 + 1,
+            false => low.ceil() as i64,
+        };
+        let last = match high_exclusive {
+            true => high.ceil() as i64 - 1,
+            false => high.floor() as i64,
+        };
+        if first <= last && last - first < MAX_LISTED_INTEGERS {
+            let values: Vec<String> = (first..=last).map(|n| format!("'{}'", n)).collect();
+            ctx.add_rule(format!("{}::={};", rule_name, values.join(" | ")));
+            return rule_name.to_string();
+        }
+    }
+
+    // Otherwise only the sign and the number of digits are kept
+    let low = low.map(|(bound, _)| bound);
+    let high = high.map(|(bound, _)| bound);
+    let mut alternatives = Vec::new();
+    if high.map_or(true, |high| high >= 0.0) {
+        alternatives.push(magnitude_pattern(high));
+    }
+    if low.map_or(true, |low| low < 0.0) {
+        alternatives.push(format!("-{}", magnitude_pattern(low)));
+    }
+    let fraction = match integer {
+        true => "",
+        false => r"(\.[0-9]+)?",
+    };
+    let pattern = format!("({}){}", alternatives.join("|"), fraction);
+    ctx.add_rule(format!("{}::={};", rule_name, regex_terminal(&pattern)));
+
+    rule_name.to_string()
+}
+
+/// Handle array type with optional items schema and `minItems`/`maxItems`.
 fn handle_array(schema: &Value, rule_name: &str, ctx: &mut GeneratorContext) -> String {
-    if let Some(items_schema) = schema.get("items") {
-        // Generate rule for array items
-        let items_rule = ctx.unique_rule(&format!("{}_items", rule_name));
-        json_schema_to_kbnf(items_schema, &items_rule, ctx);
+    let min_items = schema.get("minItems").and_then(|v| v.as_u64()).unwrap_or(0);
+    let max_items = schema
+        .get("maxItems")
+        .and_then(|v| v.as_u64())
+        .filter(|&max| max >= min_items && max - min_items <= MAX_OPTIONAL_ITEMS);
+
+    let items_rule = match schema.get("items") {
+        Some(items_schema) => {
+            // Generate rule for array items
+            let items_rule = ctx.unique_rule(&format!("{}_items", rule_name));
+            json_schema_to_kbnf(items_schema, &items_rule, ctx);
+            items_rule
+        }
+        None if min_items == 0 && max_items.is_none() => {
+            // No constraints - allow any JSON array
+            ctx.add_rule(format!("{}::=json_array;", rule_name));
+            return rule_name.to_string();
+        }
+        None => "json_value".to_string(),
+    };
 
-        // Array with typed items
-        let elements_rule = ctx.unique_rule(&format!("{}_elements", rule_name));
-        ctx.add_rule(format!(
-            "{}::={} (',' ws {})*;",
-            elements_rule, items_rule, items_rule
-        ));
-        ctx.add_rule(format!("{}::='[' ws {}? ws ']';", rule_name, elements_rule));
-    } else {
-        // No items schema - allow any JSON array
-        ctx.add_rule(format!("{}::=json_array;", rule_name));
+    // Array with typed items
+    let elements_rule = ctx.unique_rule(&format!("{}_elements", rule_name));
+    let elements = match (min_items, max_items) {
+        (0, Some(0)) => None,
+        (0, None) => Some(format!("({} (',' ws {})*)?", items_rule, items_rule)),
+        (min, None) => {
+            let required = vec![items_rule.as_str(); min as usize].join(" ',' ws ");
+            Some(format!("{} (',' ws {})*", required, items_rule))
+        }
+        (min, Some(max)) => {
+            // optional items nest, so that each one needs the ones before
+            let mut elements = String::new();
+            for index in (min..max).rev() {
+                let comma = if index == 0 { "" } else { "',' ws " };
+                let rest = match elements.is_empty() {
+                    true => String::new(),
+                    false => format!(" {}", elements),
+                };
+                elements = format!("({}{}{})?", comma, items_rule, rest);
+            }
+            let required = vec![items_rule.as_str(); min as usize].join(" ',' ws ");
+            Some(format!("{} {}", required, elements))
+        }
+    };
+    match elements {
+        Some(elements) => {
+            ctx.add_rule(format!("{}::={};", elements_rule, elements.trim()));
+            ctx.add_rule(format!("{}::='[' ws {} ws ']';", rule_name, elements_rule));
+        }
+        None => ctx.add_rule(format!("{}::='[' ws ']';", rule_name)),
     }
 
     rule_name.to_string()
 }
 
+/// A KBNF regex terminal matching `pattern`.
+fn regex_terminal(pattern: &str) -> String {
+    format!("#'{}'", pattern.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
 /// Escape special characters in a string for KBNF literal.
 fn escape_kbnf_string(s: &str) -> String {
-    s.replace('\\', "\\\\").replace('"', "\\\"")
+    super::bnf_grammars::escape_kbnf_literal(s)
 }
 
 /// Convenience function to convert a JSON Schema to a complete KBNF grammar.
@@ -228,6 +477,7 @@ pub fn schema_to_grammar(schema: &Value, start_rule: &str) -> String {
     use super::bnf_grammars::GRAMMAR_JSON_PRIMITIVES;
 
     let mut ctx = GeneratorContext::new();
+    ctx.set_root(schema);
     json_schema_to_kbnf(schema, start_rule, &mut ctx);
 
     let mut grammar = String::new();
@@ -237,6 +487,24 @@ pub fn schema_to_grammar(schema: &Value, start_rule: &str) -> String {
     grammar
 }
 
+/// Generate the grammar of a `response_format`.
+///
+/// # Returns
+/// `Some(grammar)` whose `start` rule matches a single JSON value, or `None` for plain text
+pub fn generate_response_format_grammar(format: &ResponseFormat) -> Option<String> {
+    use super::bnf_grammars::GRAMMAR_JSON_PRIMITIVES;
+
+    match format {
+        ResponseFormat::Text => None,
+        ResponseFormat::JsonObject => {
+            Some(format!("{GRAMMAR_JSON_PRIMITIVES}\nstart::=json_object;"))
+        }
+        ResponseFormat::JsonSchema { json_schema } => {
+            Some(schema_to_grammar(&json_schema.schema, "start"))
+        }
+    }
+}
+
 /// Generate tool name alternatives from tool definitions.
 ///
 /// Creates a grammar rule that matches any of the provided tool names.
@@ -279,6 +547,7 @@ pub fn generate_tool_grammars(tools: &[Tool]) -> String {
         let input_rule = format!("{}_input", tool.name);
 
         // Generate input schema rule using json_schema_to_kbnf
+        ctx.set_root(&tool.input_schema);
         json_schema_to_kbnf(&tool.input_schema, &input_rule, &mut ctx);
 
         // Tool call rule: {"name": "tool_name", "arguments": ...}
@@ -352,6 +621,65 @@ text_response::=#'.*' terminator;
     grammar
 }
 
+/// Generate a grammar whose output must begin with a function calls block.
+///
+/// Implements `tool_choice: "any"` (`tool` is `None`) and a forced specific tool: text may not
+/// precede the block, which holds at least one `<invoke>`, or exactly one if `single` is set.
+/// An optional thinking block is still allowed first. Tool names are restricted to `tools`, or to
+/// `tool` if given; with `schema_aware`, inputs are also checked against their `input_schema`.
+pub fn generate_forced_tool_grammar(
+    tools: &[Tool],
+    tool: Option<&str>,
+    schema_aware: bool,
+    single: bool,
+) -> String {
+    use super::bnf_grammars::GRAMMAR_JSON_PRIMITIVES;
+
+    let tools: Vec<Tool> = tools
+        .iter()
+        .filter(|t| tool.map_or(true, |name| t.name == name))
+        .cloned()
+        .collect();
+    let invokes = match single {
+        true => "invoke",
+        false => "invoke+",
+    };
+
+    let mut grammar = String::new();
+    grammar.push_str(GRAMMAR_JSON_PRIMITIVES);
+    grammar.push('\n');
+    grammar.push_str(&format!(
+        r#"
+start::=thinking? function_calls;
+thinking::='<think>' #ex'</think>' '</think>' ws;
+function_calls::='<ai00:function_calls>\n' invokes '</ai00:function_calls>';
+invokes::={invokes};
+"#
+    ));
+    match schema_aware {
+        true => grammar.push_str(
+            r#"invoke::='  <invoke name="' tool_name '">\n' '    <parameter name="input">' tool_call '</parameter>\n' '  </invoke>\n';
+"#,
+        ),
+        false => grammar.push_str(
+            r#"invoke::='  <invoke name="' tool_name '">\n' params '  </invoke>\n';
+params::=param*;
+param::='    <parameter name="' param_name '">' param_value '</parameter>\n';
+param_name::=#'[a-zA-Z0-9_]+';
+param_value::=#ex'</parameter>';
+"#,
+        ),
+    }
+    grammar.push_str(&generate_tool_name_grammar(&tools));
+    grammar.push('\n');
+    if schema_aware {
+        grammar.push_str(&generate_tool_grammars(&tools));
+        grammar.push('\n');
+    }
+
+    grammar
+}
+
 /// Main entry point for BNF schema generation based on request parameters.
 ///
 /// This is the function that should be called from the handler to generate
@@ -409,6 +737,32 @@ mod tests {
     use super::*;
     use serde_json::json;
 
+    #[test]
+    fn test_response_format_grammar() {
+        use super::super::types::JsonSchemaFormat;
+
+        assert!(generate_response_format_grammar(&ResponseFormat::Text).is_none());
+
+        let grammar = generate_response_format_grammar(&ResponseFormat::JsonObject).unwrap();
+        assert!(grammar.contains("start::=json_object;"));
+        assert!(grammar.contains("json_object::="));
+
+        let format = ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaFormat {
+                name: Some("answer".into()),
+                schema: json!({
+                    "type": "object",
+                    "properties": {"answer": {"type": "integer"}},
+                    "required": ["answer"]
+                }),
+                strict: None,
+            },
+        };
+        let grammar = generate_response_format_grammar(&format).unwrap();
+        assert!(grammar.contains("start::="));
+        assert!(grammar.contains("answer"));
+    }
+
     #[test]
     fn test_simple_string() {
         let schema = json!({"type": "string"});
@@ -573,6 +927,143 @@ mod tests {
         assert!(grammar.contains(" | "));
     }
 
+    #[test]
+    fn test_ref_to_defs() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"node": {"$ref": "#/$defs/node"}},
+            "required": ["node"],
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {"next": {"$ref": "#/$defs/node"}}
+                }
+            }
+        });
+        let grammar = schema_to_grammar(&schema, "start");
+
+        // The recursive definition is converted once and referred to by both uses
+        assert_eq!(grammar.matches("ref_1::=").count(), 1);
+        assert!(grammar.contains("start_prop_node_0::=ref_1;"));
+        assert!(grammar.contains("ref_1_prop_next_2::=ref_1;"));
+
+        let mut ctx = GeneratorContext::new();
+        json_schema_to_kbnf(&json!({"$ref": "#/$defs/missing"}), "missing", &mut ctx);
+        let grammar = ctx.into_grammar();
+        assert!(grammar.contains("::=json_value;"));
+    }
+
+    #[test]
+    fn test_type_list() {
+        let schema = json!({"type": ["string", "null"]});
+        let mut ctx = GeneratorContext::new();
+        json_schema_to_kbnf(&schema, "maybe", &mut ctx);
+        let grammar = ctx.into_grammar();
+        assert!(grammar.contains("maybe::=maybe_var_0 | maybe_var_1;"));
+        assert!(grammar.contains("maybe_var_1::='null';"));
+    }
+
+    #[test]
+    fn test_array_item_counts() {
+        let mut ctx = GeneratorContext::new();
+        let schema =
+            json!({"type": "array", "items": {"type": "number"}, "minItems": 1, "maxItems": 3});
+        json_schema_to_kbnf(&schema, "arr", &mut ctx);
+        let grammar = ctx.into_grammar();
+        assert!(grammar
+            .contains("arr_elements_1::=arr_items_0 (',' ws arr_items_0 (',' ws arr_items_0)?)?;"));
+
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({"type": "array", "items": {"type": "number"}, "minItems": 2});
+        json_schema_to_kbnf(&schema, "arr", &mut ctx);
+        let grammar = ctx.into_grammar();
+        assert!(grammar
+            .contains("arr_elements_1::=arr_items_0 ',' ws arr_items_0 (',' ws arr_items_0)*;"));
+
+        let mut ctx = GeneratorContext::new();
+        json_schema_to_kbnf(&json!({"type": "array", "maxItems": 0}), "arr", &mut ctx);
+        assert!(ctx.into_grammar().contains("arr::='[' ws ']';"));
+    }
+
+    #[test]
+    fn test_numeric_ranges() {
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({"type": "integer", "minimum": 1, "exclusiveMaximum": 4});
+        json_schema_to_kbnf(&schema, "small", &mut ctx);
+        assert!(ctx.into_grammar().contains("small::='1' | '2' | '3';"));
+
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({"type": "integer", "minimum": 0, "maximum": 1000});
+        json_schema_to_kbnf(&schema, "big", &mut ctx);
+        assert!(ctx
+            .into_grammar()
+            .contains(r"big::=#'((0|[1-9][0-9]{0,3}))';"));
+
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({"type": "number", "exclusiveMinimum": 0});
+        json_schema_to_kbnf(&schema, "positive", &mut ctx);
+        assert!(ctx
+            .into_grammar()
+            .contains(r"positive::=#'((0|[1-9][0-9]*))(\\.[0-9]+)?';"));
+    }
+
+    #[test]
+    fn test_string_format() {
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({"type": "string", "format": "date-time"});
+        json_schema_to_kbnf(&schema, "when", &mut ctx);
+        let grammar = ctx.into_grammar();
+        assert!(grammar.starts_with(r#"when::='"' #'[0-9]{4}-"#));
+
+        // Unknown formats are plain strings
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({"type": "string", "format": "hostname"});
+        json_schema_to_kbnf(&schema, "host", &mut ctx);
+        assert!(ctx.into_grammar().contains("host::=string;"));
+    }
+
+    #[test]
+    fn test_additional_properties() {
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({"type": "object", "additionalProperties": false});
+        json_schema_to_kbnf(&schema, "empty", &mut ctx);
+        assert!(ctx.into_grammar().contains("empty::='{' ws '}';"));
+
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({"type": "object", "additionalProperties": {"type": "integer"}});
+        json_schema_to_kbnf(&schema, "counts", &mut ctx);
+        let grammar = ctx.into_grammar();
+        assert!(grammar.contains("counts_additional_0::=number;"));
+        assert!(grammar.contains("counts::='{' ws (string ws ':' ws counts_additional_0"));
+
+        let mut ctx = GeneratorContext::new();
+        let schema = json!({
+            "type": "object",
+            "properties": {"id": {"type": "string"}},
+            "required": ["id"],
+            "additionalProperties": true
+        });
+        json_schema_to_kbnf(&schema, "open", &mut ctx);
+        let grammar = ctx.into_grammar();
+        assert!(grammar.contains("(ws ',' ws pair)*"));
+    }
+
+    #[test]
+    fn test_optional_properties_only() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"a": {"type": "string"}, "b": {"type": "string"}}
+        });
+        let mut ctx = GeneratorContext::new();
+        json_schema_to_kbnf(&schema, "opt", &mut ctx);
+        let grammar = ctx.into_grammar();
+
+        // Either property may come first without a comma, or none at all
+        assert!(grammar.contains(
+            r#"opt::='{' ws ('"a"' ws ':' ws opt_prop_a_0 (ws ',' ws '"b"' ws ':' ws opt_prop_b_1)? | '"b"' ws ':' ws opt_prop_b_1)? ws '}';"#
+        ));
+    }
+
     #[test]
     fn test_unknown_type_fallback() {
         let schema = json!({"description": "any value"});
@@ -598,6 +1089,7 @@ mod tests {
         assert_eq!(escape_kbnf_string("hello"), "hello");
         assert_eq!(escape_kbnf_string(r#"say "hi""#), r#"say \"hi\""#);
         assert_eq!(escape_kbnf_string(r"path\to\file"), r"path\\to\\file");
+        assert_eq!(escape_kbnf_string("it's"), r"it\'s");
     }
 
     #[test]
@@ -965,4 +1457,26 @@ mod tests {
         // Should also have thinking (unified grammar)
         assert!(grammar.contains("<think>"));
     }
+
+    #[test]
+    fn test_generate_forced_tool_grammar() {
+        let tools = vec![
+            make_tool("get_weather", json!({"type": "object"})),
+            make_tool("search", json!({"type": "object"})),
+        ];
+
+        // "any": no text before the block, at least one call of a known tool
+        let grammar = generate_forced_tool_grammar(&tools, None, false, false);
+        assert!(grammar.contains("start::=thinking? function_calls;"));
+        assert!(!grammar.contains("text_response"));
+        assert!(grammar.contains("invokes::=invoke+;"));
+        assert!(grammar.contains("tool_name::='get_weather' | 'search';"));
+
+        // specific tool, schema-aware, single call
+        let grammar = generate_forced_tool_grammar(&tools, Some("search"), true, true);
+        assert!(grammar.contains("invokes::=invoke;"));
+        assert!(grammar.contains("tool_name::='search';"));
+        assert!(grammar.contains("search_input"));
+        assert!(!grammar.contains("get_weather"));
+    }
 }
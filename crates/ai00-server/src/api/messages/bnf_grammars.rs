@@ -108,29 +108,44 @@ thinking_block::='<think>' #ex'</think>' '</think>' ws;
 ws::=#'[ \\t\\n\\r]*';
 "#;
 
+/// Escape `s` for a single-quoted KBNF string literal, e.g. `'</s>'`.
+///
+/// Quotes and backslashes are escaped, as are line breaks, tabs and the other control
+/// characters, which the grammar could not contain otherwise. Regex metacharacters need
+/// no escaping in a literal.
+pub fn escape_kbnf_literal(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\'' => escaped.push_str("\\'"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 /// Build the terminator rule from stop sequences.
 ///
 /// Generates a rule like: `terminator::='\n\n' | '</s>' | '\n';`
+/// Empty stop sequences are skipped, as an empty terminator would end any text.
 pub fn build_terminator_rule(stop_sequences: &[String]) -> String {
-    if stop_sequences.is_empty() {
-        // Default to double newline if no stop sequences
-        return "terminator::='\\n\\n';\n".to_string();
-    }
-
     let alternatives: Vec<String> = stop_sequences
         .iter()
-        .map(|s| {
-            // Escape special characters for KBNF
-            let escaped = s
-                .replace('\\', "\\\\")
-                .replace('\'', "\\'")
-                .replace('\n', "\\n")
-                .replace('\r', "\\r")
-                .replace('\t', "\\t");
-            format!("'{}'", escaped)
-        })
+        .filter(|s| !s.is_empty())
+        .map(|s| format!("'{}'", escape_kbnf_literal(s)))
         .collect();
 
+    if alternatives.is_empty() {
+        // Default to double newline if no stop sequences
+        return "terminator::='\\n\\n';\n".to_string();
+    }
+
     format!("terminator::={};\n", alternatives.join(" | "))
 }
 
@@ -170,6 +185,15 @@ pub fn build_structural_grammar(
     grammar
 }
 
+/// Restrict a generated grammar to at most one `<invoke>` per function calls block.
+///
+/// Applies to the structural and the schema-aware grammars alike, which share the `invokes`
+/// rule; the schema-aware grammar still checks the name and input of that one call. User-provided
+/// grammars are left as they are.
+pub fn limit_to_single_invoke(grammar: &str) -> String {
+    grammar.replace("invokes::=invoke*;", "invokes::=invoke?;")
+}
+
 /// Wrap a user-provided grammar with thinking support.
 ///
 /// Renames the user's `start` rule to `user_start` and prepends
@@ -283,6 +307,14 @@ mod tests {
         assert!(grammar.contains("'</s>'"));
     }
 
+    #[test]
+    fn test_limit_to_single_invoke() {
+        let grammar = build_structural_grammar(false, true, &[]);
+        let limited = limit_to_single_invoke(&grammar);
+        assert!(limited.contains("invokes::=invoke?;"));
+        assert!(!limited.contains("invoke*"));
+    }
+
     #[test]
     fn test_build_terminator_rule_empty() {
         let rule = build_terminator_rule(&[]);
@@ -298,6 +330,34 @@ mod tests {
         assert!(rule.contains(" | "));
     }
 
+    #[test]
+    fn test_build_terminator_rule_escapes() {
+        let stop_seqs = vec![
+            "\"".to_string(),
+            "\\".to_string(),
+            "end\n".to_string(),
+            "it's".to_string(),
+            "[.*]".to_string(),
+            String::new(),
+        ];
+        let rule = build_terminator_rule(&stop_seqs);
+        assert_eq!(
+            rule,
+            r#"terminator::='\"' | '\\' | 'end\n' | 'it\'s' | '[.*]';"#.to_string() + "\n"
+        );
+
+        // only empty stops fall back to the default
+        let rule = build_terminator_rule(&[String::new()]);
+        assert_eq!(rule, "terminator::='\\n\\n';\n");
+    }
+
+    #[test]
+    fn test_escape_kbnf_literal_control() {
+        assert_eq!(escape_kbnf_literal("a\r\tb"), r"a\r\tb");
+        assert_eq!(escape_kbnf_literal("\u{1b}[0m"), r"\u{1b}[0m");
+        assert_eq!(escape_kbnf_literal("héllo"), "héllo");
+    }
+
     #[test]
     fn test_wrap_grammar_with_thinking() {
         let user_grammar = r#"start::=greeting;
@@ -0,0 +1,329 @@
+//! States of conversations continued across `/v1/messages` requests.
+//!
+//! A request naming `metadata.conversation_id` has its answer prefilled, together with the rest
+//! of the conversation, into a state kept under that id. The next request of the conversation
+//! sends the whole history again; if its prompt starts with exactly the text the kept state
+//! covers, only the rest of the prompt (the new user turn) is prefilled on top of the state.
+//! A prompt that does not match, e.g. because the client edited the history, is prefilled in
+//! full as usual.
+//!
+//! The state covers nothing but the text of the prompt, so a client guessing the id of another
+//! conversation only gets a state it could have prefilled from its own prompt.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ai00_core::{GenerateRequest, InputState, StateId, Token};
+use salvo::Depot;
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::{
+    handler::parse_output,
+    prompt::build_training_prompt,
+    state::prefill_state,
+    types::{MessageContent, MessageParam, MessageRole, MessagesRequest},
+};
+use crate::{
+    api::usage::Attribution,
+    config::{ConversationOption, PromptsConfig},
+    types::ThreadSender,
+};
+
+/// The state after the last turn of a conversation.
+#[derive(Debug, Clone)]
+struct Conversation {
+    /// Model (or adapter) the state was prefilled with.
+    model: Option<String>,
+    /// Stored state the conversation started from, if any.
+    origin: Option<StateId>,
+    /// Text of the prompt that the state covers.
+    prompt: String,
+    state: Arc<InputState>,
+    used: Instant,
+}
+
+/// Conversation states shared between requests.
+#[derive(Debug, Default, Clone)]
+pub struct ConversationStore {
+    option: ConversationOption,
+    conversations: Arc<Mutex<HashMap<String, Conversation>>>,
+}
+
+impl ConversationStore {
+    pub fn new(option: ConversationOption) -> Self {
+        Self {
+            option,
+            conversations: Default::default(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.option.max_conversations > 0
+    }
+
+    fn expire(&self, conversations: &mut HashMap<String, Conversation>) {
+        let ttl = Duration::from_secs(self.option.ttl);
+        conversations.retain(|_, conversation| conversation.used.elapsed() < ttl);
+    }
+
+    /// Get a conversation and mark it as used, which postpones its expiry.
+    fn get(&self, id: &str) -> Option<Conversation> {
+        let mut conversations = self.conversations.lock().unwrap();
+        self.expire(&mut conversations);
+        let conversation = conversations.get_mut(id)?;
+        conversation.used = Instant::now();
+        Some(conversation.clone())
+    }
+
+    fn insert(&self, id: String, conversation: Conversation) {
+        let mut conversations = self.conversations.lock().unwrap();
+        self.expire(&mut conversations);
+        while conversations.len() >= self.option.max_conversations.max(1)
+            && !conversations.contains_key(&id)
+        {
+            let oldest = conversations
+                .iter()
+                .min_by_key(|(_, conversation)| conversation.used)
+                .map(|(id, _)| id.clone());
+            match oldest {
+                Some(oldest) => conversations.remove(&oldest),
+                None => break,
+            };
+        }
+        conversations.insert(id, conversation);
+    }
+
+    /// Start `generate` from the state of the conversation if its prompt continues the text
+    /// the state covers. Returns whether it does.
+    fn resume(&self, id: &str, origin: Option<StateId>, generate: &mut GenerateRequest) -> bool {
+        let Some(conversation) = self.get(id) else {
+            return false;
+        };
+        let rest = match generate.prompt.strip_prefix(&conversation.prompt) {
+            Some(rest)
+                if !rest.is_empty()
+                    && conversation.model == generate.model
+                    && conversation.origin == origin =>
+            {
+                rest.to_string()
+            }
+            _ => {
+                tracing::info!(event = "conversation_mismatch", conversation = %id);
+                return false;
+            }
+        };
+        tracing::debug!(
+            event = "conversation_resumed",
+            conversation = %id,
+            skipped = conversation.prompt.len(),
+        );
+        generate.prompt = rest;
+        generate.cache_prefix = None;
+        generate.state = conversation.state;
+        true
+    }
+}
+
+/// Continue the generation from the state of the previous turn of `request`'s conversation,
+/// if it names one and its prompt matches.
+///
+/// Must be called after the full prompt was taken for auditing, as the prompt is cut to the
+/// part that the state does not cover.
+pub(super) fn resume_conversation(
+    depot: &Depot,
+    request: &MessagesRequest,
+    generate: &mut GenerateRequest,
+) {
+    let Some(id) = request.conversation_id() else {
+        return;
+    };
+    if let Ok(store) = depot.obtain::<ConversationStore>() {
+        if store.is_enabled() {
+            store.resume(id, request.state_id, generate);
+        }
+    }
+}
+
+/// Pass the tokens of a turn through, and once it stops, prefill the conversation including
+/// the answer in the background, for the next turn of the conversation to continue from.
+///
+/// `state` is the state the request started from before [`resume_conversation`]. Turns that
+/// continue an assistant message are not tracked.
+pub(super) fn track_conversation(
+    depot: &Depot,
+    request: &MessagesRequest,
+    generate: &GenerateRequest,
+    state: Arc<InputState>,
+    prompts: &PromptsConfig,
+    tokenizer: Arc<Tokenizer>,
+    receiver: flume::Receiver<Token>,
+) -> flume::Receiver<Token> {
+    let Some(id) = request.conversation_id().map(String::from) else {
+        return receiver;
+    };
+    let Ok(store) = depot.obtain::<ConversationStore>() else {
+        return receiver;
+    };
+    let prefill = request.messages.last().map(|m| m.role) == Some(MessageRole::Assistant);
+    if !store.is_enabled() || prefill {
+        return receiver;
+    }
+
+    let store = store.clone();
+    let thread = depot.obtain::<ThreadSender>().unwrap().clone();
+    let attribution = Attribution::of(depot);
+    let request = request.clone();
+    let model = generate.model.clone();
+    let prompts = prompts.clone();
+    let (sender, tracked) = flume::unbounded();
+    tokio::spawn(async move {
+        let mut text = String::new();
+        let mut finish_reason = None;
+        while let Ok(token) = receiver.recv_async().await {
+            match &token {
+                Token::Content(content) => text += content,
+                Token::Stop(reason, _) => finish_reason = Some(reason.clone()),
+                _ => {}
+            }
+            if sender.send(token).is_err() {
+                break;
+            }
+        }
+        drop(sender);
+        let Some(finish_reason) = finish_reason else {
+            return;
+        };
+
+        let (content, _) = parse_output(&request, text, finish_reason);
+        let mut messages = request.messages.clone();
+        messages.push(MessageParam {
+            role: MessageRole::Assistant,
+            content: MessageContent::Blocks(content),
+        });
+        let covered = match build_training_prompt(
+            request.system.as_deref(),
+            &messages,
+            request.tools.as_deref(),
+            request.thinking.as_ref(),
+            &prompts,
+        ) {
+            Ok(covered) => covered,
+            Err(err) => {
+                tracing::warn!(event = "conversation_failed", conversation = %id, error = %err);
+                return;
+            }
+        };
+
+        // prefill on top of the previous turn where it still matches
+        let origin = request.state_id;
+        let (state, rest) = match store.get(&id) {
+            Some(conversation)
+                if conversation.model == model
+                    && conversation.origin == origin
+                    && covered.starts_with(&conversation.prompt) =>
+            {
+                let rest = covered[conversation.prompt.len()..].to_string();
+                (conversation.state, rest)
+            }
+            _ => (state, covered.clone()),
+        };
+        if rest.is_empty() {
+            return;
+        }
+        let mut generate = GenerateRequest {
+            prompt: rest,
+            model: model.clone(),
+            state,
+            ..Default::default()
+        };
+        attribution.apply(&mut generate);
+        match prefill_state(&thread, tokenizer, generate).await {
+            Some((_, state, _)) => {
+                let conversation = Conversation {
+                    model,
+                    origin,
+                    prompt: covered,
+                    state: Arc::new(state),
+                    used: Instant::now(),
+                };
+                store.insert(id, conversation);
+            }
+            None => {
+                tracing::warn!(event = "conversation_failed", conversation = %id);
+            }
+        }
+    });
+    tracked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(max_conversations: usize) -> ConversationStore {
+        ConversationStore::new(ConversationOption {
+            max_conversations,
+            ..Default::default()
+        })
+    }
+
+    fn conversation(prompt: &str) -> Conversation {
+        Conversation {
+            model: Some("rwkv".into()),
+            origin: None,
+            prompt: prompt.into(),
+            state: Default::default(),
+            used: Instant::now(),
+        }
+    }
+
+    fn generate(prompt: &str) -> GenerateRequest {
+        GenerateRequest {
+            prompt: prompt.into(),
+            cache_prefix: Some("<ai00:system>".into()),
+            model: Some("rwkv".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resume_matching_prompt() {
+        let store = store(4);
+        let covered =
+            "<ai00:user>\nHi\n</ai00:user>\n\n<ai00:assistant>\nHello!\n</ai00:assistant>";
+        store.insert("chat".into(), conversation(covered));
+
+        let mut request = generate(&format!("{covered}\n\n<ai00:user>\nHow are you?"));
+        assert!(store.resume("chat", None, &mut request));
+        assert_eq!(request.prompt, "\n\n<ai00:user>\nHow are you?");
+        assert_eq!(request.cache_prefix, None);
+
+        // an edited history, another model or a different starting state are prefilled in full
+        let prompt = "<ai00:user>\nHey\n</ai00:user>\n\n<ai00:assistant>\n";
+        let mut request = generate(prompt);
+        assert!(!store.resume("chat", None, &mut request));
+        assert_eq!(request.prompt, prompt);
+
+        let next = format!("{covered}\n\n<ai00:user>\nHow are you?");
+        let mut request = generate(&next);
+        request.model = Some("other".into());
+        assert!(!store.resume("chat", None, &mut request));
+        assert!(!store.resume("chat", Some(StateId::new()), &mut generate(&next)));
+        assert!(!store.resume("unknown", None, &mut generate(&next)));
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let store = store(2);
+        store.insert("a".into(), conversation("a"));
+        store.insert("b".into(), conversation("b"));
+        store.get("a");
+        store.insert("c".into(), conversation("c"));
+        assert!(store.get("a").is_some());
+        assert!(store.get("b").is_none());
+        assert!(store.get("c").is_some());
+    }
+}
@@ -1,32 +1,69 @@
 //! Request handlers for Claude-compatible Messages API.
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use ai00_core::{GenerateRequest, ThreadRequest, Token, MAX_TOKENS};
-use futures_util::StreamExt;
+use ai00_core::{
+    reload::TrafficClass, GenerateRequest, InputState, ThreadRequest, Token, MAX_TOKENS,
+};
+use futures_util::{stream::BoxStream, StreamExt};
 use salvo::{oapi::extract::JsonBody, prelude::*, sse::SseEvent};
 use tokio::sync::RwLock;
+use web_rwkv::tokenizer::Tokenizer;
 
-use super::bnf_generator::generate_bnf_schema;
-use super::bnf_grammars::wrap_grammar_with_thinking;
-use super::prompt::build_prompt;
+use super::audit::{AuditEntry, AuditLog};
+use super::bnf_generator::{
+    generate_bnf_schema, generate_forced_tool_grammar, generate_response_format_grammar,
+};
+use super::bnf_grammars::{limit_to_single_invoke, wrap_grammar_with_thinking};
+use super::conversation::{resume_conversation, track_conversation};
+use super::interleaved::{StreamPiece, ThinkingToolStreamParser};
+use super::moderation::{moderate_output, moderate_prompt, Moderator};
+use super::prompt::{build_prompt, system_turn};
+use super::rag::retrieve_context;
+use super::resume::{with_heartbeat, StreamBacklogs, LAST_EVENT_ID_HEADER};
+use super::server_tools::ServerTools;
+use super::session::{Session, SessionStore};
+use super::state::StateStore;
 use super::streaming::*;
 use super::thinking_extractor::{
     generate_thinking_signature, ThinkingExtractor, ThinkingStreamParser,
 };
-use super::tool_parser::Ai00FunctionCallsParser;
+use super::tool_parser::{Ai00FunctionCallsParser, ToolStreamEvent};
+use super::tool_validation::{check_tool_inputs, validate_tool_use};
+use super::truncation::{checkpoint_interval, fit_context};
 use super::types::{
-    BnfValidationLevel, ContentBlock, MessageRole, MessagesRequest, MessagesResponse, StopReason,
+    validate_tool_name, BnfValidationLevel, ContentBlock, CountTokensRequest, CountTokensResponse,
+    MessageContent, MessageParam, MessageRole, MessagesRequest, MessagesResponse, PromptPreview,
+    ResponseFormat, ResponseMetadata, ResponseTimings, StopReason, ToolChoice, ToolChoiceSimple,
+    ToolInputValidation, Usage,
 };
+use super::vision::{caption_images, Captioner};
 use crate::{
-    api::{error::ApiErrorResponse, request_info},
+    api::{
+        error::ApiErrorResponse,
+        request_info_of, try_request_info_of,
+        usage::{client_id, Attribution},
+    },
     config::{Config, PromptsConfig},
     logging::{RequestContext, StreamLogContext},
     types::ThreadSender,
     SLEEP,
 };
 
-use ai00_core::sampler::nucleus::{NucleusParams, NucleusSampler};
+use ai00_core::sampler::{
+    dynatemp::{DynaTempParams, DynaTempSampler},
+    nucleus::NucleusParams,
+};
+
+/// Most model turns answered with canned tool results in one request.
+const MAX_TOOL_PREVIEW_ROUNDS: usize = 8;
+/// Most regenerations of a turn with invalid tool calls that `tool_call_retries` may ask for.
+const MAX_TOOL_CALL_RETRIES: usize = 4;
 
 /// Determine the effective BNF validation level and schema.
 ///
@@ -35,10 +72,12 @@ use ai00_core::sampler::nucleus::{NucleusParams, NucleusSampler};
 /// 2. If `bnf_validation` is None and tools/thinking present, auto-enable Structural
 /// 3. If raw `bnf_schema` is provided, use that (only when validation is None)
 ///
+/// Text responses of generated grammars end with one of `terminators`.
+///
 /// Returns (effective_level, schema_to_use).
 fn resolve_bnf_config(
     req: &MessagesRequest,
-    stop_sequences: &[String],
+    terminators: &[String],
 ) -> (BnfValidationLevel, Option<String>) {
     let has_tools = req.tools.as_ref().map(|t| !t.is_empty()).unwrap_or(false);
     let has_thinking = req
@@ -47,6 +86,19 @@ fn resolve_bnf_config(
         .map(|t| t.is_enabled())
         .unwrap_or(false);
 
+    // JSON mode replaces any other grammar
+    if let Some(grammar) = req
+        .response_format
+        .as_ref()
+        .and_then(generate_response_format_grammar)
+    {
+        let grammar = match has_thinking {
+            true => wrap_grammar_with_thinking(&grammar),
+            false => grammar,
+        };
+        return (BnfValidationLevel::None, Some(grammar));
+    }
+
     // Determine effective validation level
     let effective_level = match req.bnf_validation {
         // Explicitly set - use that
@@ -79,35 +131,111 @@ fn resolve_bnf_config(
             }
         }
         BnfValidationLevel::Structural | BnfValidationLevel::SchemaAware => {
-            // Generate grammar based on validation level, with stop sequences for terminator
-            generate_bnf_schema(
-                req.tools.as_deref(),
-                has_thinking,
-                effective_level,
-                stop_sequences,
-            )
+            // Generate grammar based on validation level, with the terminators of text
+            let single = req
+                .tool_choice
+                .as_ref()
+                .is_some_and(ToolChoice::is_single_tool_use);
+            match req.tool_choice.as_ref() {
+                // the model must call a tool, so text may not come first
+                Some(choice) if has_tools && choice.forces_tool_use() => {
+                    Some(generate_forced_tool_grammar(
+                        req.tools.as_deref().unwrap_or_default(),
+                        choice.tool_name(),
+                        effective_level == BnfValidationLevel::SchemaAware,
+                        single,
+                    ))
+                }
+                _ => {
+                    let schema = generate_bnf_schema(
+                        req.tools.as_deref(),
+                        has_thinking,
+                        effective_level,
+                        terminators,
+                    );
+                    match single {
+                        true => schema.map(|schema| limit_to_single_invoke(&schema)),
+                        false => schema,
+                    }
+                }
+            }
         }
     };
 
     (effective_level, schema)
 }
 
+/// Build sampler parameters from the request.
+fn sampler_params(req: &MessagesRequest) -> DynaTempParams {
+    let defaults = NucleusParams::default();
+    let nucleus = NucleusParams {
+        top_p: req.top_p.unwrap_or(0.5),
+        top_k: req.top_k.unwrap_or(128),
+        temperature: req.temperature.unwrap_or(1.0),
+        min_p: req.min_p.unwrap_or(defaults.min_p),
+        typical_p: req.typical_p.unwrap_or(defaults.typical_p),
+        presence_penalty: req.presence_penalty.unwrap_or(defaults.presence_penalty),
+        frequency_penalty: req.frequency_penalty.unwrap_or(defaults.frequency_penalty),
+        repetition_penalty: req
+            .repetition_penalty
+            .unwrap_or(defaults.repetition_penalty),
+        repetition_window: req.repetition_window.unwrap_or(defaults.repetition_window),
+        ..defaults
+    };
+    let defaults = DynaTempParams::default();
+    DynaTempParams {
+        nucleus,
+        dynatemp_range: req.dynatemp_range.unwrap_or(defaults.dynatemp_range),
+        dynatemp_exponent: req.dynatemp_exponent.unwrap_or(defaults.dynatemp_exponent),
+    }
+}
+
+/// Header naming the prompt profile, for clients that cannot set `metadata.prompt_profile`.
+pub(super) const PROMPT_PROFILE_HEADER: &str = "x-prompt-profile";
+
+/// The prompt profile named `name`, or the default one.
+pub(super) fn select_prompts<'a>(
+    config: &'a Config,
+    name: Option<&str>,
+) -> Result<&'a PromptsConfig, ApiErrorResponse> {
+    config.prompts.get(name).ok_or_else(|| {
+        ApiErrorResponse::invalid_request(format!(
+            "unknown prompt profile `{}`",
+            name.unwrap_or_default()
+        ))
+        .with_param("metadata.prompt_profile")
+    })
+}
+
+/// The chat template rejected the conversation.
+pub(super) fn prompt_error(err: anyhow::Error) -> ApiErrorResponse {
+    ApiErrorResponse::invalid_request(format!("failed to build prompt: {err}"))
+        .with_param("messages")
+}
+
 /// Convert MessagesRequest to GenerateRequest.
 fn to_generate_request(
     req: &MessagesRequest,
     prompts: &PromptsConfig,
+    state: Arc<InputState>,
     request_id: Option<String>,
     trace_id: Option<String>,
-) -> GenerateRequest {
-    let prompt = build_prompt(
-        req.system.as_deref(),
-        &req.messages,
-        req.tools.as_deref(),
-        req.thinking.as_ref(),
-        prompts,
-    );
+) -> Result<GenerateRequest, ApiErrorResponse> {
+    let span = tracing::info_span!("prompt_build", messages = req.messages.len());
+    let prompt = span
+        .in_scope(|| {
+            build_prompt(
+                req.system.as_deref(),
+                &req.messages,
+                req.tools.as_deref(),
+                req.thinking.as_ref(),
+                prompts,
+            )
+        })
+        .map_err(prompt_error)?;
 
-    // Extract model text from previous assistant messages
+    // Extract model text from previous assistant messages, ending with the prefill if any,
+    // which is in the prompt without its trailing whitespace
     let model_text = req
         .messages
         .iter()
@@ -115,6 +243,10 @@ fn to_generate_request(
         .map(|m| m.content.to_text())
         .collect::<Vec<_>>()
         .join("\n\n");
+    let model_text = match req.messages.last().map(|m| m.role) {
+        Some(MessageRole::Assistant) => model_text.trim_end().to_string(),
+        _ => model_text,
+    };
 
     let max_tokens = req.max_tokens.min(MAX_TOKENS);
 
@@ -123,31 +255,118 @@ fn to_generate_request(
         .clone()
         .unwrap_or_else(|| prompts.default_stop_sequences.clone());
 
-    // Build sampler from request parameters
-    let temperature = req.temperature.unwrap_or(1.0);
-    let top_p = req.top_p.unwrap_or(0.5);
-    let top_k = req.top_k.unwrap_or(128);
-
-    let sampler = Arc::new(RwLock::new(NucleusSampler::new(NucleusParams {
-        top_p,
-        top_k,
-        temperature,
-        ..Default::default()
-    })));
+    let sampler = Arc::new(RwLock::new(DynaTempSampler::new(sampler_params(req))));
 
     // Resolve BNF validation level and get effective schema
-    let (_effective_level, bnf_schema) = resolve_bnf_config(req, &stop);
+    let terminators = req.grammar_terminators.as_deref().unwrap_or(&stop);
+    let (_effective_level, bnf_schema) = resolve_bnf_config(req, terminators);
+    if !req.banned_phrases.is_empty() && (bnf_schema.is_some() || req.regex.is_some()) {
+        return Err(ApiErrorResponse::invalid_request(
+            "banned_phrases cannot be combined with grammars",
+        )
+        .with_param("banned_phrases"));
+    }
 
-    GenerateRequest {
+    // tools are part of the system turn, so a breakpoint on either caches all of it
+    let cache_control = req
+        .system
+        .as_ref()
+        .is_some_and(|system| system.cache_control)
+        || req
+            .tools
+            .iter()
+            .flatten()
+            .any(|tool| tool.cache_control.is_some());
+    let cache_prefix = cache_control
+        .then(|| system_turn(&prompt, prompts))
+        .flatten()
+        .map(String::from);
+
+    Ok(GenerateRequest {
         prompt,
+        cache_prefix,
         model_text,
         max_tokens,
         stop,
+        stop_tokens: req.stop_tokens.clone().unwrap_or_default(),
+        stop_bytes: req.stop_bytes.clone().unwrap_or_default(),
+        banned_phrases: req.banned_phrases.clone(),
         sampler,
         bnf_schema,
+        regex: req.regex.clone(),
         request_id,
         trace_id,
+        model: Some(req.adapter.clone().unwrap_or_else(|| req.model.clone())),
+        traffic_class: req
+            .traffic_class
+            .unwrap_or(TrafficClass::from_stream(req.stream)),
+        timeout: req.timeout_ms.map(Duration::from_millis),
+        max_tokens_per_second: req.max_tokens_per_second,
+        seed: req.seed,
+        state,
         ..Default::default()
+    })
+}
+
+/// Look up the stored state the request starts from, or the model's initial state if none.
+fn resolve_state(
+    depot: &Depot,
+    req: &MessagesRequest,
+) -> Result<Arc<InputState>, ApiErrorResponse> {
+    let Some(id) = req.state_id else {
+        return Ok(Default::default());
+    };
+    let store = depot
+        .obtain::<StateStore>()
+        .map_err(|_| ApiErrorResponse::api_error("states are not available"))?;
+    match store.get(id) {
+        Some(state) if state.model == req.model => Ok(state.state),
+        Some(state) => {
+            let message = format!("state was created with model `{}`", state.model);
+            Err(ApiErrorResponse::invalid_request(message).with_param("state_id"))
+        }
+        None => {
+            let message = format!("state {id:?} not found or expired");
+            Err(ApiErrorResponse::not_found(message).with_param("state_id"))
+        }
+    }
+}
+
+/// Remember the turn so that it can be continued later, if sessions are enabled.
+fn track_session(
+    depot: &Depot,
+    id: String,
+    session: Session,
+    token_receiver: flume::Receiver<Token>,
+) -> flume::Receiver<Token> {
+    match depot.obtain::<SessionStore>() {
+        Ok(store) => {
+            store.insert(id.clone(), session);
+            store.track(id, token_receiver)
+        }
+        Err(_) => token_receiver,
+    }
+}
+
+/// Check that `adapter` is a LoRA adapter registered on `model`.
+async fn check_adapter(
+    sender: &ThreadSender,
+    model: &str,
+    adapter: &str,
+) -> Result<(), ApiErrorResponse> {
+    let not_found = || {
+        ApiErrorResponse::not_found(format!("adapter `{adapter}` is not loaded for `{model}`"))
+            .with_param("adapter")
+    };
+    let base = request_info_of(sender.clone(), model, SLEEP).await?;
+    let info = try_request_info_of(sender.clone(), adapter)
+        .await
+        .ok()
+        .flatten()
+        .ok_or_else(not_found)?;
+    match info.name == adapter && info.reload.adapter_of.as_ref() == Some(&base.name) {
+        true => Ok(()),
+        false => Err(not_found()),
     }
 }
 
@@ -160,9 +379,9 @@ fn validate_request(req: &MessagesRequest) -> Result<(), ApiErrorResponse> {
 
     // Validate messages array
     if req.messages.is_empty() {
-        return Err(ApiErrorResponse::invalid_request(
-            "messages cannot be empty",
-        ));
+        return Err(
+            ApiErrorResponse::invalid_request("messages cannot be empty").with_param("messages"),
+        );
     }
 
     // First message must be from user (Claude API requirement)
@@ -173,6 +392,15 @@ fn validate_request(req: &MessagesRequest) -> Result<(), ApiErrorResponse> {
         );
     }
 
+    // A trailing assistant message is prefilled, which would open the thinking block with it
+    let prefilled = req.messages.last().map(|m| m.role) == Some(MessageRole::Assistant);
+    if prefilled && req.thinking.as_ref().is_some_and(|t| t.is_enabled()) {
+        return Err(ApiErrorResponse::invalid_request(
+            "a final assistant message cannot be prefilled with thinking enabled",
+        )
+        .with_param(format!("messages.{}", req.messages.len() - 1)));
+    }
+
     // Validate max_tokens
     if req.max_tokens == 0 {
         return Err(
@@ -181,6 +409,23 @@ fn validate_request(req: &MessagesRequest) -> Result<(), ApiErrorResponse> {
         );
     }
 
+    if req.timeout_ms == Some(0) {
+        return Err(
+            ApiErrorResponse::invalid_request("timeout_ms must be greater than 0")
+                .with_param("timeout_ms"),
+        );
+    }
+
+    if req
+        .max_tokens_per_second
+        .is_some_and(|rate| !(rate.is_finite() && rate > 0.0))
+    {
+        return Err(ApiErrorResponse::invalid_request(
+            "max_tokens_per_second must be greater than 0",
+        )
+        .with_param("max_tokens_per_second"));
+    }
+
     // Validate temperature range
     if let Some(temp) = req.temperature {
         if !(0.0..=2.0).contains(&temp) {
@@ -211,6 +456,76 @@ fn validate_request(req: &MessagesRequest) -> Result<(), ApiErrorResponse> {
         }
     }
 
+    // Validate min_p range
+    if let Some(min_p) = req.min_p {
+        if !(0.0..=1.0).contains(&min_p) {
+            return Err(
+                ApiErrorResponse::invalid_request("min_p must be between 0.0 and 1.0")
+                    .with_param("min_p"),
+            );
+        }
+    }
+
+    // Validate typical_p range
+    if let Some(typical_p) = req.typical_p {
+        if !(typical_p > 0.0 && typical_p <= 1.0) {
+            return Err(ApiErrorResponse::invalid_request(
+                "typical_p must be greater than 0.0 and at most 1.0",
+            )
+            .with_param("typical_p"));
+        }
+    }
+
+    // Validate penalties
+    for (name, penalty) in [
+        ("presence_penalty", req.presence_penalty),
+        ("frequency_penalty", req.frequency_penalty),
+    ] {
+        if penalty.is_some_and(|x| !(-2.0..=2.0).contains(&x)) {
+            return Err(ApiErrorResponse::invalid_request(format!(
+                "{name} must be between -2.0 and 2.0"
+            ))
+            .with_param(name));
+        }
+    }
+    if let Some(penalty) = req.repetition_penalty {
+        if !(penalty.is_finite() && penalty > 0.0) {
+            return Err(
+                ApiErrorResponse::invalid_request("repetition_penalty must be positive")
+                    .with_param("repetition_penalty"),
+            );
+        }
+    }
+
+    // Validate dynamic temperature
+    if let Some(range) = req.dynatemp_range {
+        if !(range.is_finite() && range >= 0.0) {
+            return Err(
+                ApiErrorResponse::invalid_request("dynatemp_range must be non-negative")
+                    .with_param("dynatemp_range"),
+            );
+        }
+    }
+    if let Some(exponent) = req.dynatemp_exponent {
+        if !(exponent.is_finite() && exponent > 0.0) {
+            return Err(
+                ApiErrorResponse::invalid_request("dynatemp_exponent must be positive")
+                    .with_param("dynatemp_exponent"),
+            );
+        }
+    }
+
+    if let Some((text, _)) = req
+        .logit_bias
+        .iter()
+        .find(|(_, bias)| !(-100.0..=100.0).contains(*bias))
+    {
+        return Err(ApiErrorResponse::invalid_request(
+            "logit_bias values must be between -100 and 100",
+        )
+        .with_param(format!("logit_bias.{text}")));
+    }
+
     // Validate stop_sequences if provided
     if let Some(ref stop_seqs) = req.stop_sequences {
         if stop_seqs.len() > 8 {
@@ -229,6 +544,195 @@ fn validate_request(req: &MessagesRequest) -> Result<(), ApiErrorResponse> {
         }
     }
 
+    if let Some(ref terminators) = req.grammar_terminators {
+        if terminators.is_empty() || terminators.len() > 8 {
+            return Err(ApiErrorResponse::invalid_request(
+                "grammar_terminators must have between 1 and 8 items",
+            )
+            .with_param("grammar_terminators"));
+        }
+        if let Some(index) = terminators.iter().position(String::is_empty) {
+            return Err(ApiErrorResponse::invalid_request(
+                "grammar_terminators cannot contain empty strings",
+            )
+            .with_param(format!("grammar_terminators.{index}")));
+        }
+    }
+
+    if let Some(index) = req.stop_bytes.iter().flatten().position(Vec::is_empty) {
+        return Err(
+            ApiErrorResponse::invalid_request("stop_bytes cannot contain empty sequences")
+                .with_param(format!("stop_bytes.{index}")),
+        );
+    }
+
+    if let Some(index) = req.banned_phrases.iter().position(String::is_empty) {
+        return Err(ApiErrorResponse::invalid_request(
+            "banned_phrases cannot contain empty phrases",
+        )
+        .with_param(format!("banned_phrases.{index}")));
+    }
+
+    // Validate tool definitions if provided
+    for (i, tool) in req.tools.iter().flatten().enumerate() {
+        if let Err(msg) = tool.validate() {
+            let field = match validate_tool_name(&tool.name) {
+                true => "input_schema",
+                false => "name",
+            };
+            return Err(
+                ApiErrorResponse::invalid_request(msg).with_param(format!("tools.{i}.{field}"))
+            );
+        }
+    }
+
+    // Validate tool_choice if provided
+    if let Some(choice) = &req.tool_choice {
+        let tools = req.tools.as_deref().unwrap_or_default();
+        match choice {
+            ToolChoice::Specific(specific) => {
+                if specific.choice_type != "tool" {
+                    return Err(ApiErrorResponse::invalid_request(format!(
+                        "unknown tool_choice type: {}",
+                        specific.choice_type
+                    ))
+                    .with_param("tool_choice.type"));
+                }
+                if !tools.iter().any(|tool| tool.name == specific.name) {
+                    return Err(ApiErrorResponse::invalid_request(format!(
+                        "tool_choice names tool `{}`, which is not in tools",
+                        specific.name
+                    ))
+                    .with_param("tool_choice.name"));
+                }
+                // a forced tool is called exactly once
+                if specific.disable_parallel_tool_use == Some(false) {
+                    return Err(ApiErrorResponse::invalid_request(
+                        "disable_parallel_tool_use cannot be false when a specific tool is forced",
+                    )
+                    .with_param("tool_choice.disable_parallel_tool_use"));
+                }
+            }
+            _ if choice.simple() == Some(ToolChoiceSimple::Any) && tools.is_empty() => {
+                return Err(
+                    ApiErrorResponse::invalid_request("tool_choice any requires tools")
+                        .with_param("tool_choice"),
+                );
+            }
+            _ if choice.simple() == Some(ToolChoiceSimple::None)
+                && choice.disable_parallel_tool_use().is_some() =>
+            {
+                return Err(ApiErrorResponse::invalid_request(
+                    "disable_parallel_tool_use cannot be combined with tool_choice none",
+                )
+                .with_param("tool_choice.disable_parallel_tool_use"));
+            }
+            _ => {}
+        }
+        if choice.forces_tool_use() && req.bnf_validation == Some(BnfValidationLevel::None) {
+            return Err(ApiErrorResponse::invalid_request(
+                "tool_choice any or tool cannot be combined with bnf_validation none",
+            )
+            .with_param("tool_choice"));
+        }
+    }
+
+    // Validate regex if provided
+    if let Some(regex) = &req.regex {
+        if req.tools.as_ref().is_some_and(|t| !t.is_empty()) {
+            return Err(
+                ApiErrorResponse::invalid_request("regex cannot be combined with tools")
+                    .with_param("regex"),
+            );
+        }
+        if let Err(err) = regex::Regex::new(regex) {
+            return Err(
+                ApiErrorResponse::invalid_request(format!("invalid regex: {err}"))
+                    .with_param("regex"),
+            );
+        }
+    }
+
+    // Validate response_format if provided
+    if let Some(format) = req.response_format.as_ref().filter(|f| f.is_json()) {
+        if req.tools.as_ref().is_some_and(|t| !t.is_empty()) {
+            return Err(ApiErrorResponse::invalid_request(
+                "response_format cannot be combined with tools",
+            )
+            .with_param("response_format"));
+        }
+        if req.bnf_schema.is_some() {
+            return Err(ApiErrorResponse::invalid_request(
+                "response_format cannot be combined with bnf_schema",
+            )
+            .with_param("response_format"));
+        }
+        if let ResponseFormat::JsonSchema { json_schema } = format {
+            if !json_schema.schema.is_object() {
+                return Err(ApiErrorResponse::invalid_request(
+                    "response_format.json_schema.schema must be an object",
+                )
+                .with_param("response_format.json_schema.schema"));
+            }
+        }
+    }
+
+    if req.agentic {
+        if req.stream {
+            return Err(ApiErrorResponse::invalid_request(
+                "agentic mode is not supported with streaming",
+            )
+            .with_param("agentic"));
+        }
+        if req.tool_results_preview.is_some() {
+            return Err(ApiErrorResponse::invalid_request(
+                "agentic mode cannot be combined with tool_results_preview",
+            )
+            .with_param("agentic"));
+        }
+    }
+
+    // Validate tool_results_preview if provided
+    if let Some(previews) = &req.tool_results_preview {
+        if req.stream {
+            return Err(ApiErrorResponse::invalid_request(
+                "tool_results_preview is not supported with streaming",
+            )
+            .with_param("tool_results_preview"));
+        }
+        let tools = req.tools.as_deref().unwrap_or_default();
+        if let Some(name) = previews
+            .keys()
+            .find(|name| !tools.iter().any(|tool| &tool.name == *name))
+        {
+            return Err(ApiErrorResponse::invalid_request(format!(
+                "tool_results_preview names unknown tool '{name}'"
+            ))
+            .with_param(format!("tool_results_preview.{name}")));
+        }
+    }
+
+    if req.stream && req.tool_input_validation == ToolInputValidation::Strict {
+        return Err(ApiErrorResponse::invalid_request(
+            "strict tool_input_validation is not supported with streaming",
+        )
+        .with_param("tool_input_validation"));
+    }
+    if let Some(retries) = req.tool_call_retries {
+        if req.stream {
+            return Err(ApiErrorResponse::invalid_request(
+                "tool_call_retries is not supported with streaming",
+            )
+            .with_param("tool_call_retries"));
+        }
+        if retries > MAX_TOOL_CALL_RETRIES {
+            return Err(ApiErrorResponse::invalid_request(format!(
+                "tool_call_retries must be at most {MAX_TOOL_CALL_RETRIES}"
+            ))
+            .with_param("tool_call_retries"));
+        }
+    }
+
     // Validate thinking configuration if provided
     if let Some(ref thinking) = req.thinking {
         if let Err(msg) = thinking.validate(req.max_tokens) {
@@ -270,55 +774,88 @@ fn validate_request(req: &MessagesRequest) -> Result<(), ApiErrorResponse> {
     Ok(())
 }
 
-/// Handle non-streaming messages request.
-async fn respond_one(
-    depot: &mut Depot,
-    request: MessagesRequest,
-    res: &mut Response,
-) -> Result<(), ApiErrorResponse> {
-    // Get or create request context for logging (must be first to avoid borrow conflicts)
-    let mut ctx = depot
-        .remove::<RequestContext>("request_context")
-        .unwrap_or_else(|_| RequestContext::new(None));
-
-    let sender = depot.obtain::<ThreadSender>().unwrap();
-    let config = depot.obtain::<Config>().unwrap();
-    let prompts = &config.prompts;
-
-    // Populate request context with request metadata
-    let has_tools = request
-        .tools
-        .as_ref()
-        .map(|t| !t.is_empty())
-        .unwrap_or(false);
-    let has_thinking = request
-        .thinking
-        .as_ref()
-        .map(|t| t.is_enabled())
-        .unwrap_or(false);
-    ctx.model = request.model.clone();
-    ctx.stream = false;
-    ctx.max_tokens = request.max_tokens;
-    ctx.has_tools = has_tools;
-    ctx.has_thinking = has_thinking;
-    ctx.message_count = request.messages.len();
+/// The bias of the tokens of the `logit_bias` strings, with the tokenizer of the model.
+fn token_bias(
+    request: &MessagesRequest,
+    tokenizer: &Tokenizer,
+) -> Result<Arc<HashMap<u32, f32>>, ApiErrorResponse> {
+    match request.token_bias(tokenizer) {
+        Ok(bias) => Ok(Arc::new(bias)),
+        Err(err) => Err(
+            ApiErrorResponse::invalid_request(format!("invalid logit_bias: {err}"))
+                .with_param("logit_bias"),
+        ),
+    }
+}
 
-    let info = request_info(sender.clone(), SLEEP).await;
-    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+/// Forward tokens from `receiver`, reporting only the stop sequences the client asked for.
+///
+/// The default stop sequences terminate the turn, so hitting one of them is a plain stop.
+pub(super) fn report_stop_sequences(
+    stop_sequences: Vec<String>,
+    receiver: flume::Receiver<Token>,
+) -> flume::Receiver<Token> {
+    let (sender, reported) = flume::unbounded();
+    tokio::spawn(async move {
+        while let Ok(token) = receiver.recv_async().await {
+            let token = match token {
+                Token::Stop(ai00_core::FinishReason::StopSequence(stop), counter)
+                    if !stop_sequences.contains(&stop) =>
+                {
+                    Token::Stop(ai00_core::FinishReason::Stop, counter)
+                }
+                token => token,
+            };
+            if sender.send(token).is_err() {
+                break;
+            }
+        }
+    });
+    reported
+}
 
-    let (token_sender, token_receiver) = flume::unbounded();
-    let gen_request = Box::new(to_generate_request(
-        &request,
-        prompts,
-        Some(ctx.request_id.clone()),
-        ctx.trace_id.clone(),
-    ));
-    let _ = sender.send(ThreadRequest::Generate {
-        request: gen_request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
+/// Pass the tokens of a streamed turn through, writing the audit record of the request once it
+/// stops. The record is lost if the client goes away first.
+fn audit_stream(
+    audit: AuditLog,
+    request: MessagesRequest,
+    request_id: String,
+    prompt: String,
+    receiver: flume::Receiver<Token>,
+) -> flume::Receiver<Token> {
+    let (sender, audited) = flume::unbounded();
+    tokio::spawn(async move {
+        let mut text = String::new();
+        while let Ok(token) = receiver.recv_async().await {
+            match &token {
+                Token::Content(content) => text += content,
+                Token::Stop(finish_reason, counter) => {
+                    let (content, stop_reason) =
+                        parse_output(&request, text.clone(), finish_reason.clone());
+                    let entry = AuditEntry {
+                        request_id: &request_id,
+                        prompt: &prompt,
+                        output: &text,
+                        content: &content,
+                        stop_reason,
+                        counter,
+                    };
+                    audit.record(&request, entry);
+                }
+                _ => {}
+            }
+            if sender.send(token).is_err() {
+                break;
+            }
+        }
     });
+    audited
+}
 
+/// Read the generated text of a turn.
+async fn collect_output(
+    token_receiver: flume::Receiver<Token>,
+) -> Result<(String, ai00_core::FinishReason, ai00_core::TokenCounter), ApiErrorResponse> {
     let mut token_counter = ai00_core::TokenCounter::default();
     let mut finish_reason = ai00_core::FinishReason::Null;
     let mut text = String::new();
@@ -335,24 +872,36 @@ async fn respond_one(
                 token_counter = counter;
                 break;
             }
+            Token::Error(error) => return Err(error.into()),
             Token::Done => break,
             _ => {}
         }
     }
 
-    // Check if thinking is enabled
-    let thinking_enabled = request
-        .thinking
-        .as_ref()
-        .map(|t| t.is_enabled())
-        .unwrap_or(false);
+    Ok((text, finish_reason, token_counter))
+}
+
+/// Split the generated text of a turn into content blocks.
+pub(super) fn parse_output(
+    request: &MessagesRequest,
+    text: String,
+    finish_reason: ai00_core::FinishReason,
+) -> (Vec<ContentBlock>, StopReason) {
+    // Check if thinking is enabled (raw mode returns the markup as text instead)
+    let thinking_enabled = !request.raw_mode
+        && request
+            .thinking
+            .as_ref()
+            .map(|t| t.is_enabled())
+            .unwrap_or(false);
 
     // Check if tools are enabled
-    let has_tools = request
-        .tools
-        .as_ref()
-        .map(|t| !t.is_empty())
-        .unwrap_or(false);
+    let has_tools = !request.raw_mode
+        && request
+            .tools
+            .as_ref()
+            .map(|t| !t.is_empty())
+            .unwrap_or(false);
 
     // Extract thinking if enabled (do this before tool parsing)
     let (thinking_block, text_for_parsing) = if thinking_enabled {
@@ -372,7 +921,7 @@ async fn respond_one(
         (None, text)
     };
 
-    let (content, stop_reason) = if has_tools {
+    if has_tools {
         // Parse the output for tool_call blocks
         let mut parser = Ai00FunctionCallsParser::new();
         let result = parser.feed(&text_for_parsing);
@@ -397,6 +946,13 @@ async fn respond_one(
         // Add tool_use blocks
         let mut all_tools: Vec<_> = result.tool_uses;
         all_tools.extend(final_result.tool_uses);
+        if request
+            .tool_choice
+            .as_ref()
+            .is_some_and(ToolChoice::is_single_tool_use)
+        {
+            all_tools.truncate(1);
+        }
 
         for tool_use in all_tools.iter() {
             content_blocks.push(ContentBlock::ToolUse {
@@ -432,6 +988,334 @@ async fn respond_one(
         }
 
         (content_blocks, finish_reason.into())
+    }
+}
+
+/// Canned results of the tool calls in `blocks`, if the turn stopped to call tools that all
+/// have an entry in `tool_results_preview`.
+fn preview_tool_results(
+    request: &MessagesRequest,
+    blocks: &[ContentBlock],
+    stop_reason: StopReason,
+) -> Option<Vec<ContentBlock>> {
+    let previews = request.tool_results_preview.as_ref()?;
+    if stop_reason != StopReason::ToolUse {
+        return None;
+    }
+    let results = blocks
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { id, name, .. } => Some((id, name)),
+            _ => None,
+        })
+        .map(|(id, name)| {
+            previews.get(name).map(|content| ContentBlock::ToolResult {
+                tool_use_id: id.clone(),
+                content: content.clone(),
+                is_error: false,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match results.is_empty() {
+        true => None,
+        false => Some(results),
+    }
+}
+
+/// Results of the tool calls in `blocks`, if the turn stopped to call tools that all belong to
+/// the server.
+async fn run_server_tools(
+    tools: &ServerTools,
+    blocks: &[ContentBlock],
+    stop_reason: StopReason,
+) -> Option<Vec<ContentBlock>> {
+    if stop_reason != StopReason::ToolUse {
+        return None;
+    }
+    let calls: Vec<_> = blocks
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { id, name, input } => Some((id, name, input)),
+            _ => None,
+        })
+        .collect();
+    for (_, name, _) in &calls {
+        if !tools.contains(name).await {
+            return None;
+        }
+    }
+    let results = calls
+        .into_iter()
+        .map(|(id, name, input)| tools.run(id, name, input));
+    let results: Option<Vec<_>> = futures_util::future::join_all(results)
+        .await
+        .into_iter()
+        .collect();
+    results.filter(|results| !results.is_empty())
+}
+
+/// Offer the tools of the server to an agentic request, next to its own.
+async fn add_server_tools(
+    depot: &Depot,
+    mut request: MessagesRequest,
+) -> Result<MessagesRequest, ApiErrorResponse> {
+    let Some(server_tools) = depot
+        .obtain::<ServerTools>()
+        .ok()
+        .filter(|t| t.is_enabled())
+    else {
+        return Err(ApiErrorResponse::invalid_request(
+            "agentic mode requires tools configured on the server",
+        )
+        .with_param("agentic"));
+    };
+    let server_tools = server_tools.tools().await.map_err(|err| {
+        ApiErrorResponse::api_error(format!("failed to list server tools: {err}"))
+    })?;
+    let tools = request.tools.get_or_insert_with(Vec::new);
+    for (index, tool) in tools.iter().enumerate() {
+        if server_tools.iter().any(|t| t.name == tool.name) {
+            return Err(ApiErrorResponse::invalid_request(format!(
+                "tool `{}` clashes with a tool of the server",
+                tool.name
+            ))
+            .with_param(format!("tools.{index}.name")));
+        }
+    }
+    tools.extend(server_tools);
+    Ok(request)
+}
+
+/// Why the tool calls of a model turn cannot be used, if they cannot.
+///
+/// `text` is the raw output of the turn, whose `<invoke>` tags are counted to catch calls that
+/// the parser dropped.
+fn check_tool_calls(
+    request: &MessagesRequest,
+    text: &str,
+    blocks: &[ContentBlock],
+) -> Result<(), String> {
+    let tools = request.tools.as_deref().unwrap_or_default();
+    let calls: Vec<_> = blocks
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { name, input, .. } => Some((name, input)),
+            _ => None,
+        })
+        .collect();
+    let invokes = text.matches("<invoke").count();
+    if invokes > calls.len() {
+        return Err(format!(
+            "{} of your {invokes} tool calls could not be parsed",
+            invokes - calls.len()
+        ));
+    }
+    for (name, input) in calls {
+        validate_tool_use(tools, name, input)
+            .map_err(|err| format!("the call of `{name}` is invalid: {err}"))?;
+    }
+    Ok(())
+}
+
+/// Generate the whole answer of a non-streaming messages request.
+async fn respond_one(
+    depot: &mut Depot,
+    request: MessagesRequest,
+    state: Arc<InputState>,
+    mut metadata: ResponseMetadata,
+) -> Result<MessagesResponse, ApiErrorResponse> {
+    // Get or create request context for logging (must be first to avoid borrow conflicts)
+    let mut ctx = depot
+        .remove::<RequestContext>("request_context")
+        .unwrap_or_else(|_| RequestContext::new(None));
+
+    let server_tools = match request.agentic {
+        true => depot.obtain::<ServerTools>().ok().cloned(),
+        false => None,
+    };
+    let audit = depot.obtain::<AuditLog>().ok().cloned();
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let prompts = select_prompts(config, request.prompt_profile())?;
+
+    // Populate request context with request metadata
+    let has_tools = request
+        .tools
+        .as_ref()
+        .map(|t| !t.is_empty())
+        .unwrap_or(false);
+    let has_thinking = request
+        .thinking
+        .as_ref()
+        .map(|t| t.is_enabled())
+        .unwrap_or(false);
+    ctx.model = request.model.clone();
+    ctx.stream = false;
+    ctx.max_tokens = request.max_tokens;
+    ctx.has_tools = has_tools;
+    ctx.has_thinking = has_thinking;
+    ctx.message_count = request.messages.len();
+
+    let info = request_info_of(sender.clone(), &request.model, SLEEP).await?;
+    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+
+    let mut request = request;
+    let mut content = Vec::new();
+    let mut token_counter = ai00_core::TokenCounter::default();
+    let mut server_tool_requests = 0;
+    let mut round = 0;
+    let mut retries = 0;
+    let max_retries = match request.raw_mode {
+        true => 0,
+        false => request.tool_call_retries.unwrap_or_default(),
+    };
+    let stop_sequences = request.stop_sequences.clone().unwrap_or_default();
+    // retries and rounds of tool calls share the time of the request
+    let deadline = request
+        .timeout_ms
+        .map(|timeout| Instant::now() + Duration::from_millis(timeout));
+    let moderator = depot.obtain::<Moderator>().ok().cloned();
+    let bias = token_bias(&request, &info.tokenizer)?;
+    let (stop_reason, stop_sequence) = loop {
+        let (token_sender, token_receiver) = flume::unbounded();
+        let mut gen_request = Box::new(to_generate_request(
+            &request,
+            prompts,
+            state.clone(),
+            Some(ctx.request_id.clone()),
+            ctx.trace_id.clone(),
+        )?);
+        gen_request.timeout =
+            deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        gen_request.bias = bias.clone();
+        gen_request.checkpoint_interval = checkpoint_interval(&config.generation, &request);
+        gen_request.max_state_rms = Some(config.generation.max_state_rms);
+        Attribution::of(depot).apply(&mut gen_request);
+        let prompt = gen_request.prompt.clone();
+        resume_conversation(depot, &request, &mut gen_request);
+        let session = Session::new(&gen_request, sampler_params(&request))
+            .with_stop_sequences(stop_sequences.clone());
+        let token_receiver = moderate_output(moderator.as_ref(), &gen_request, token_receiver);
+        let token_receiver = track_conversation(
+            depot,
+            &request,
+            &gen_request,
+            state.clone(),
+            prompts,
+            info.tokenizer.clone(),
+            token_receiver,
+        );
+        let _ = sender.send(ThreadRequest::Generate {
+            request: gen_request,
+            tokenizer: info.tokenizer.clone(),
+            sender: token_sender,
+        });
+        let token_receiver = report_stop_sequences(stop_sequences.clone(), token_receiver);
+        // only the first turn is a plain continuation of the request
+        let token_receiver = match (round, retries) {
+            (0, 0) => track_session(depot, ctx.request_id.clone(), session, token_receiver),
+            _ => token_receiver,
+        };
+
+        let (text, finish_reason, counter) = collect_output(token_receiver).await?;
+        token_counter += counter;
+
+        let stop_sequence = finish_reason.stop_sequence().map(String::from);
+        let (mut blocks, mut stop_reason) = parse_output(&request, text.clone(), finish_reason);
+
+        let cut_off = matches!(stop_reason, StopReason::Timeout | StopReason::Refusal);
+        if retries < max_retries && !cut_off {
+            if let Err(error) = check_tool_calls(&request, &text, &blocks) {
+                tracing::debug!(
+                    event = "tool_call_retry",
+                    request_id = %ctx.request_id,
+                    retry = retries,
+                    error = %error,
+                );
+                request.messages.push(MessageParam {
+                    role: MessageRole::Assistant,
+                    content: MessageContent::Text(text),
+                });
+                request.messages.push(MessageParam {
+                    role: MessageRole::User,
+                    content: MessageContent::Text(format!(
+                        "Error: {error}. Call the tool again with input that matches its input_schema."
+                    )),
+                });
+                retries += 1;
+                continue;
+            }
+        }
+        let results = match &server_tools {
+            Some(tools) if round < tools.max_rounds() => {
+                run_server_tools(tools, &blocks, stop_reason).await
+            }
+            Some(_) => None,
+            None if round < MAX_TOOL_PREVIEW_ROUNDS => {
+                preview_tool_results(&request, &blocks, stop_reason)
+            }
+            None => None,
+        };
+        // calls of the last turn are returned to the client, which needs to know if they are
+        // invalid
+        if results.is_none() {
+            let tools = request.tools.as_deref().unwrap_or_default();
+            let strict = request.tool_input_validation == ToolInputValidation::Strict;
+            let errors = check_tool_inputs(tools, &mut blocks, strict);
+            for error in &errors {
+                tracing::info!(
+                    event = "tool_input_invalid",
+                    request_id = %ctx.request_id,
+                    tool = %error.name,
+                    error = %error.error,
+                );
+            }
+            let called = blocks
+                .iter()
+                .any(|block| matches!(block, ContentBlock::ToolUse { .. }));
+            if stop_reason == StopReason::ToolUse && !called {
+                stop_reason = StopReason::EndTurn;
+            }
+            metadata.tool_input_errors.extend(errors);
+        }
+        content.extend(blocks.iter().cloned());
+
+        let Some(results) = results else {
+            if let Some(audit) = &audit {
+                let entry = AuditEntry {
+                    request_id: &ctx.request_id,
+                    prompt: &prompt,
+                    output: &text,
+                    content: &blocks,
+                    stop_reason,
+                    counter: &token_counter,
+                };
+                audit.record(&request, entry);
+            }
+            let stop_sequence = stop_sequence.filter(|_| stop_reason == StopReason::StopSequence);
+            break (stop_reason, stop_sequence);
+        };
+        if server_tools.is_some() {
+            server_tool_requests += results.len();
+        }
+        tracing::debug!(
+            event = match server_tools {
+                Some(_) => "server_tool_results",
+                None => "tool_results_previewed",
+            },
+            request_id = %ctx.request_id,
+            round,
+            results = results.len(),
+        );
+        request.messages.push(MessageParam {
+            role: MessageRole::Assistant,
+            content: MessageContent::Blocks(blocks),
+        });
+        request.messages.push(MessageParam {
+            role: MessageRole::User,
+            content: MessageContent::Blocks(results),
+        });
+        round += 1;
     };
 
     // Record token counts and finish reason
@@ -442,23 +1326,82 @@ async fn respond_one(
     // Emit canonical log line
     ctx.emit_canonical_log();
 
-    let response = MessagesResponse::new(model_name, content, token_counter.into())
-        .with_stop_reason(stop_reason);
+    if request.return_timings {
+        metadata.timings = Some(ResponseTimings::from(&token_counter));
+    }
 
-    res.render(Json(response));
-    Ok(())
+    let usage = Usage::from(token_counter).with_server_tool_requests(server_tool_requests);
+    let response = MessagesResponse::new(model_name, content, usage)
+        .with_stop_reason(stop_reason)
+        .with_metadata(metadata);
+    let response = match stop_sequence {
+        Some(stop_sequence) => response.with_stop_sequence(stop_sequence),
+        None => response,
+    };
+    Ok(response)
 }
 
+/// Claude-style events of a streaming request, as SSE events.
+pub(super) type EventStream = BoxStream<'static, Result<SseEvent, Infallible>>;
+
 /// Handle streaming messages request with Claude-style SSE events.
-async fn respond_stream(depot: &mut Depot, request: MessagesRequest, res: &mut Response) {
+async fn respond_stream(
+    depot: &mut Depot,
+    request: MessagesRequest,
+    state: Arc<InputState>,
+    metadata: ResponseMetadata,
+    res: &mut Response,
+) {
+    match stream_events(depot, request, state, metadata).await {
+        Ok(events) => {
+            let config = depot.obtain::<Arc<Config>>().unwrap();
+            let window = Duration::from_secs(config.stream.resume_window);
+            let heartbeat = Duration::from_secs(config.stream.heartbeat_interval);
+            let events = match depot.obtain::<StreamBacklogs>() {
+                Ok(backlogs) => backlogs.spawn(events, window),
+                Err(_) => events,
+            };
+            salvo::sse::stream(res, with_heartbeat(events, heartbeat))
+        }
+        Err(err) => err.respond(res),
+    }
+}
+
+/// Resume a stream from the event after `last_event_id`.
+fn resume_stream(depot: &mut Depot, last_event_id: &str, res: &mut Response) {
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let heartbeat = Duration::from_secs(config.stream.heartbeat_interval);
+    let events = depot
+        .obtain::<StreamBacklogs>()
+        .ok()
+        .and_then(|backlogs| backlogs.resume(last_event_id));
+    match events {
+        Some(events) => salvo::sse::stream(res, with_heartbeat(events, heartbeat)),
+        None => ApiErrorResponse::not_found(format!(
+            "Stream of event `{last_event_id}` not found or expired"
+        ))
+        .with_param(LAST_EVENT_ID_HEADER)
+        .respond(res),
+    }
+}
+
+/// Start generating a streaming request, and return its events.
+async fn stream_events(
+    depot: &mut Depot,
+    request: MessagesRequest,
+    state: Arc<InputState>,
+    metadata: ResponseMetadata,
+) -> Result<EventStream, ApiErrorResponse> {
     // Get or create request context for logging (must be first to avoid borrow conflicts)
     let mut ctx = depot
         .remove::<RequestContext>("request_context")
         .unwrap_or_else(|_| RequestContext::new(None));
 
+    let audit = depot.obtain::<AuditLog>().ok().cloned();
     let sender = depot.obtain::<ThreadSender>().unwrap();
-    let config = depot.obtain::<Config>().unwrap();
-    let prompts = &config.prompts;
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let prompts = select_prompts(config, request.prompt_profile())?;
+    let max_event_size = config.stream.max_event_size;
 
     // Populate request context with request metadata
     let has_tools_early = request
@@ -481,21 +1424,55 @@ async fn respond_stream(depot: &mut Depot, request: MessagesRequest, res: &mut R
     // Convert to StreamLogContext for passing to stream handlers
     let log_ctx = ctx.to_stream_log_context();
 
-    let info = request_info(sender.clone(), SLEEP).await;
+    let info = request_info_of(sender.clone(), &request.model, SLEEP).await?;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
     let (token_sender, token_receiver) = flume::unbounded();
-    let gen_request = Box::new(to_generate_request(
+    let mut gen_request = Box::new(to_generate_request(
         &request,
         prompts,
+        state.clone(),
         Some(log_ctx.request_id.clone()),
         log_ctx.trace_id.clone(),
-    ));
+    )?);
+    gen_request.bias = token_bias(&request, &info.tokenizer)?;
+    gen_request.checkpoint_interval = checkpoint_interval(&config.generation, &request);
+    gen_request.max_state_rms = Some(config.generation.max_state_rms);
+    Attribution::of(depot).apply(&mut gen_request);
+    gen_request.grammar_trace = request.grammar_trace();
+    let stop_sequences = request.stop_sequences.clone().unwrap_or_default();
+    let prompt = gen_request.prompt.clone();
+    resume_conversation(depot, &request, &mut gen_request);
+    let session = Session::new(&gen_request, sampler_params(&request))
+        .with_stop_sequences(stop_sequences.clone());
+    let moderator = depot.obtain::<Moderator>().ok();
+    let token_receiver = moderate_output(moderator, &gen_request, token_receiver);
+    let token_receiver = track_conversation(
+        depot,
+        &request,
+        &gen_request,
+        state,
+        prompts,
+        info.tokenizer.clone(),
+        token_receiver,
+    );
     let _ = sender.send(ThreadRequest::Generate {
         request: gen_request,
         tokenizer: info.tokenizer.clone(),
         sender: token_sender,
     });
+    let token_receiver = report_stop_sequences(stop_sequences, token_receiver);
+    let token_receiver = match audit {
+        Some(audit) => audit_stream(
+            audit,
+            request.clone(),
+            log_ctx.request_id.clone(),
+            prompt,
+            token_receiver,
+        ),
+        None => token_receiver,
+    };
+    let token_receiver = track_session(depot, log_ctx.request_id.clone(), session, token_receiver);
 
     // Generate message ID
     let message_id = format!("msg_{}", uuid::Uuid::new_v4().simple());
@@ -507,6 +1484,7 @@ async fn respond_stream(depot: &mut Depot, request: MessagesRequest, res: &mut R
         .map(|m| m.content.to_text().len() / 4)
         .sum::<usize>()
         + request.system.as_ref().map(|s| s.len() / 4).unwrap_or(0);
+    let start = MessageStartData::new(message_id, model_name, input_tokens).with_metadata(metadata);
 
     // Check if tools and thinking are enabled
     let has_tools = request
@@ -520,111 +1498,127 @@ async fn respond_stream(depot: &mut Depot, request: MessagesRequest, res: &mut R
         .map(|t| t.is_enabled())
         .unwrap_or(false);
 
+    let single_tool_use = request
+        .tool_choice
+        .as_ref()
+        .is_some_and(ToolChoice::is_single_tool_use);
+
+    let return_timings = request.return_timings;
+
     // Stream handlers will emit the canonical log when Token::Stop is received
-    match (has_thinking, has_tools) {
-        (true, false) => {
-            // Thinking-aware streaming
-            respond_stream_with_thinking(
-                res,
-                token_receiver,
-                message_id,
-                model_name,
-                input_tokens,
-                log_ctx,
-            )
-            .await;
-        }
-        (false, true) => {
-            // Tool-aware streaming with Ai00FunctionCallsParser
-            respond_stream_with_tools(
-                res,
-                token_receiver,
-                message_id,
-                model_name,
-                input_tokens,
-                log_ctx,
-            )
-            .await;
-        }
-        (true, true) => {
-            // Both thinking and tools: use tool-aware streaming
-            // (thinking extraction in tool mode is handled during finalization)
-            respond_stream_with_tools(
-                res,
-                token_receiver,
-                message_id,
-                model_name,
-                input_tokens,
-                log_ctx,
-            )
-            .await;
-        }
-        (false, false) => {
-            // Streaming with optional thinking detection (model decides whether to think)
-            respond_stream_with_optional_thinking(
-                res,
-                token_receiver,
-                message_id,
-                model_name,
-                input_tokens,
-                log_ctx,
-            )
-            .await;
-        }
+    if request.raw_mode {
+        return Ok(respond_stream_simple(
+            token_receiver,
+            start,
+            log_ctx,
+            max_event_size,
+            return_timings,
+        ));
     }
+    let events = match (has_thinking, has_tools) {
+        // Thinking-aware streaming
+        (true, false) => respond_stream_with_thinking(
+            token_receiver,
+            start,
+            log_ctx,
+            max_event_size,
+            return_timings,
+        ),
+        // Tool-aware streaming with Ai00FunctionCallsParser
+        (false, true) => respond_stream_with_tools(
+            token_receiver,
+            start,
+            log_ctx,
+            max_event_size,
+            single_tool_use,
+            return_timings,
+        ),
+        // Both thinking and tools: thinking first, then text and tool calls
+        (true, true) => respond_stream_with_thinking_and_tools(
+            token_receiver,
+            start,
+            log_ctx,
+            max_event_size,
+            single_tool_use,
+            return_timings,
+        ),
+        // Streaming with optional thinking detection (model decides whether to think)
+        (false, false) => respond_stream_with_optional_thinking(
+            token_receiver,
+            start,
+            log_ctx,
+            max_event_size,
+            return_timings,
+        ),
+    };
     // Note: Canonical log is emitted by stream handlers when they receive Token::Stop
+    Ok(events)
 }
 
-/// Simple streaming handler without tool parsing.
-/// NOTE: Currently unused - kept for potential future use or debugging.
-#[allow(dead_code)]
-async fn respond_stream_simple(
-    res: &mut Response,
+/// The `message_metadata` event with the timings of the generation, if the request asks for them.
+fn emit_timings(return_timings: bool, counter: &ai00_core::TokenCounter) -> Option<SseEvent> {
+    return_timings.then(|| {
+        emit_message_metadata(ResponseMetadata {
+            timings: Some(counter.into()),
+            ..Default::default()
+        })
+    })
+}
+
+/// Simple streaming handler without thinking or tool parsing.
+///
+/// Used by `raw_mode`: every token is forwarded verbatim as a text delta of a single block.
+fn respond_stream_simple(
     token_receiver: flume::Receiver<Token>,
-    message_id: String,
-    model_name: String,
-    input_tokens: usize,
-) {
+    start: MessageStartData,
+    log_ctx: StreamLogContext,
+    max_event_size: Option<usize>,
+    return_timings: bool,
+) -> EventStream {
     let mut output_tokens = 0usize;
-    let mut start_token = true;
-
-    let stream = token_receiver.into_stream().map(
-        move |token| -> Result<SseEvent, std::convert::Infallible> {
-            match token {
-                Token::Start => Ok(emit_message_start(
-                    message_id.clone(),
-                    model_name.clone(),
-                    input_tokens,
-                )),
-                Token::Content(text) => {
-                    output_tokens += 1;
-
-                    if start_token {
-                        start_token = false;
-                        let trimmed = text.trim_start().to_string();
-                        if trimmed.is_empty() {
-                            return Ok(emit_content_block_start_text(0));
-                        }
-                        return Ok(emit_text_delta(0, trimmed));
-                    }
+    let mut block_started = false;
 
-                    if text.is_empty() {
-                        Ok(emit_ping())
-                    } else {
-                        Ok(emit_text_delta(0, text))
-                    }
+    let stream = token_receiver.into_stream().flat_map(move |token| {
+        let mut events: Vec<Result<SseEvent, std::convert::Infallible>> = Vec::new();
+        match token {
+            Token::Start => events.push(Ok(emit_message_start(start.clone()))),
+            Token::Content(text) => {
+                output_tokens += 1;
+                if !block_started {
+                    block_started = true;
+                    events.push(Ok(emit_content_block_start_text(0)));
                 }
-                Token::Stop(reason, _counter) => {
-                    let stop_reason: StopReason = reason.into();
-                    Ok(emit_message_delta(stop_reason, output_tokens))
+                if !text.is_empty() {
+                    events.extend(
+                        emit_text_deltas(0, text, max_event_size)
+                            .into_iter()
+                            .map(Ok),
+                    );
                 }
-                Token::Done => Ok(emit_message_stop()),
-                _ => Ok(emit_ping()),
             }
-        },
-    );
+            Token::Stop(reason, counter) => {
+                let stop_sequence = reason.stop_sequence().map(String::from);
+                let stop_reason: StopReason = reason.into();
+                log_ctx.emit_with_counter(&counter, &format!("{:?}", stop_reason));
+                if block_started {
+                    events.push(Ok(emit_content_block_stop(0)));
+                }
+                events.push(Ok(emit_message_delta(
+                    stop_reason,
+                    stop_sequence,
+                    output_tokens,
+                )));
+                events.extend(emit_timings(return_timings, &counter).map(Ok));
+            }
+            Token::Error(error) => events.push(Ok(emit_generate_error(error))),
+            Token::GrammarTrace(trace) => events.push(Ok(emit_grammar_trace(trace))),
+            Token::Done => events.push(Ok(emit_message_stop())),
+            _ => events.push(Ok(emit_ping())),
+        }
+        futures_util::stream::iter(events)
+    });
 
-    salvo::sse::stream(res, stream);
+    stream.boxed()
 }
 
 /// Streaming handler with optional thinking detection (model-initiated thinking).
@@ -639,14 +1633,13 @@ async fn respond_stream_simple(
 ///
 /// If the model outputs plain text (no thinking tags):
 /// - Only text block at index 0 with text_delta events
-async fn respond_stream_with_optional_thinking(
-    res: &mut Response,
+fn respond_stream_with_optional_thinking(
     token_receiver: flume::Receiver<Token>,
-    message_id: String,
-    model_name: String,
-    input_tokens: usize,
+    start: MessageStartData,
     log_ctx: StreamLogContext,
-) {
+    max_event_size: Option<usize>,
+    return_timings: bool,
+) -> EventStream {
     use std::cell::RefCell;
 
     // Shared state for the streaming handler
@@ -678,11 +1671,7 @@ async fn respond_stream_with_optional_thinking(
         match token {
             Token::Start => {
                 state.message_started = true;
-                events.push(Ok(emit_message_start(
-                    message_id.clone(),
-                    model_name.clone(),
-                    input_tokens,
-                )));
+                events.push(Ok(emit_message_start(start.clone())));
             }
             Token::Content(text) => {
                 state.output_tokens += 1;
@@ -699,7 +1688,11 @@ async fn respond_stream_with_optional_thinking(
                             events.push(Ok(emit_content_block_start_text(0)));
                             state.text_block_started = true;
                         }
-                        events.push(Ok(emit_text_delta(0, text_content.clone())));
+                        events.extend(
+                            emit_text_deltas(0, text_content.clone(), max_event_size)
+                                .into_iter()
+                                .map(Ok),
+                        );
                     }
                 }
 
@@ -715,7 +1708,11 @@ async fn respond_stream_with_optional_thinking(
                         events.push(Ok(emit_content_block_start_thinking(thinking_block_index)));
                         state.thinking_block_started = true;
                     }
-                    events.push(Ok(emit_thinking_delta(thinking_block_index, thinking_text)));
+                    events.extend(
+                        emit_thinking_deltas(thinking_block_index, thinking_text, max_event_size)
+                            .into_iter()
+                            .map(Ok),
+                    );
                 }
 
                 // Check if thinking just completed
@@ -736,12 +1733,17 @@ async fn respond_stream_with_optional_thinking(
                             events.push(Ok(emit_content_block_start_text(idx)));
                             state.text_block_started = true;
                         }
-                        events.push(Ok(emit_text_delta(idx, text_content)));
+                        events.extend(
+                            emit_text_deltas(idx, text_content, max_event_size)
+                                .into_iter()
+                                .map(Ok),
+                        );
                     }
                 }
             }
             Token::Stop(reason, counter) => {
                 // Emit canonical log with actual metrics
+                let stop_sequence = reason.stop_sequence().map(String::from);
                 let finish_reason: StopReason = reason.into();
                 state
                     .log_ctx
@@ -759,7 +1761,11 @@ async fn respond_stream_with_optional_thinking(
                         events.push(Ok(emit_content_block_start_thinking(thinking_block_index)));
                         state.thinking_block_started = true;
                     }
-                    events.push(Ok(emit_thinking_delta(thinking_block_index, thinking_text)));
+                    events.extend(
+                        emit_thinking_deltas(thinking_block_index, thinking_text, max_event_size)
+                            .into_iter()
+                            .map(Ok),
+                    );
                 }
 
                 // Close thinking block if still open
@@ -776,7 +1782,11 @@ async fn respond_stream_with_optional_thinking(
                             events.push(Ok(emit_content_block_start_text(final_text_index)));
                             state.text_block_started = true;
                         }
-                        events.push(Ok(emit_text_delta(final_text_index, text_content)));
+                        events.extend(
+                            emit_text_deltas(final_text_index, text_content, max_event_size)
+                                .into_iter()
+                                .map(Ok),
+                        );
                     }
                 }
 
@@ -787,7 +1797,18 @@ async fn respond_stream_with_optional_thinking(
                 }
 
                 // Emit message delta
-                events.push(Ok(emit_message_delta(finish_reason, state.output_tokens)));
+                events.push(Ok(emit_message_delta(
+                    finish_reason,
+                    stop_sequence,
+                    state.output_tokens,
+                )));
+                events.extend(emit_timings(return_timings, &counter).map(Ok));
+            }
+            Token::Error(error) => {
+                events.push(Ok(emit_generate_error(error)));
+            }
+            Token::GrammarTrace(trace) => {
+                events.push(Ok(emit_grammar_trace(trace)));
             }
             Token::Done => {
                 events.push(Ok(emit_message_stop()));
@@ -800,19 +1821,18 @@ async fn respond_stream_with_optional_thinking(
         futures_util::stream::iter(events)
     });
 
-    salvo::sse::stream(res, stream);
+    stream.boxed()
 }
 
 /// Streaming handler with thinking parsing.
 /// Detects <think>...</think> blocks and emits thinking_delta/signature_delta events.
-async fn respond_stream_with_thinking(
-    res: &mut Response,
+fn respond_stream_with_thinking(
     token_receiver: flume::Receiver<Token>,
-    message_id: String,
-    model_name: String,
-    input_tokens: usize,
+    start: MessageStartData,
     log_ctx: StreamLogContext,
-) {
+    max_event_size: Option<usize>,
+    return_timings: bool,
+) -> EventStream {
     use std::cell::RefCell;
 
     // Shared state for the streaming handler
@@ -845,11 +1865,7 @@ async fn respond_stream_with_thinking(
         match token {
             Token::Start => {
                 state.message_started = true;
-                events.push(Ok(emit_message_start(
-                    message_id.clone(),
-                    model_name.clone(),
-                    input_tokens,
-                )));
+                events.push(Ok(emit_message_start(start.clone())));
             }
             Token::Content(text) => {
                 state.output_tokens += 1;
@@ -866,10 +1882,15 @@ async fn respond_stream_with_thinking(
                         )));
                         state.thinking_block_started = true;
                     }
-                    events.push(Ok(emit_thinking_delta(
-                        state.thinking_block_index,
-                        thinking_text,
-                    )));
+                    events.extend(
+                        emit_thinking_deltas(
+                            state.thinking_block_index,
+                            thinking_text,
+                            max_event_size,
+                        )
+                        .into_iter()
+                        .map(Ok),
+                    );
                 }
 
                 // Check if thinking just completed
@@ -892,12 +1913,17 @@ async fn respond_stream_with_thinking(
                             events.push(Ok(emit_content_block_start_text(state.text_block_index)));
                             state.text_block_started = true;
                         }
-                        events.push(Ok(emit_text_delta(state.text_block_index, text_content)));
+                        events.extend(
+                            emit_text_deltas(state.text_block_index, text_content, max_event_size)
+                                .into_iter()
+                                .map(Ok),
+                        );
                     }
                 }
             }
             Token::Stop(reason, counter) => {
                 // Emit canonical log with actual metrics
+                let stop_sequence = reason.stop_sequence().map(String::from);
                 let finish_reason: StopReason = reason.into();
                 state
                     .log_ctx
@@ -914,10 +1940,15 @@ async fn respond_stream_with_thinking(
                         )));
                         state.thinking_block_started = true;
                     }
-                    events.push(Ok(emit_thinking_delta(
-                        state.thinking_block_index,
-                        thinking_text,
-                    )));
+                    events.extend(
+                        emit_thinking_deltas(
+                            state.thinking_block_index,
+                            thinking_text,
+                            max_event_size,
+                        )
+                        .into_iter()
+                        .map(Ok),
+                    );
                 }
 
                 // Close thinking block if still open
@@ -937,7 +1968,11 @@ async fn respond_stream_with_thinking(
                             events.push(Ok(emit_content_block_start_text(state.text_block_index)));
                             state.text_block_started = true;
                         }
-                        events.push(Ok(emit_text_delta(state.text_block_index, text_content)));
+                        events.extend(
+                            emit_text_deltas(state.text_block_index, text_content, max_event_size)
+                                .into_iter()
+                                .map(Ok),
+                        );
                     }
                 }
 
@@ -947,7 +1982,18 @@ async fn respond_stream_with_thinking(
                 }
 
                 // Emit message delta
-                events.push(Ok(emit_message_delta(finish_reason, state.output_tokens)));
+                events.push(Ok(emit_message_delta(
+                    finish_reason,
+                    stop_sequence,
+                    state.output_tokens,
+                )));
+                events.extend(emit_timings(return_timings, &counter).map(Ok));
+            }
+            Token::Error(error) => {
+                events.push(Ok(emit_generate_error(error)));
+            }
+            Token::GrammarTrace(trace) => {
+                events.push(Ok(emit_grammar_trace(trace)));
             }
             Token::Done => {
                 events.push(Ok(emit_message_stop()));
@@ -960,203 +2006,667 @@ async fn respond_stream_with_thinking(
         futures_util::stream::iter(events)
     });
 
-    salvo::sse::stream(res, stream);
+    stream.boxed()
 }
 
 /// Streaming handler with tool parsing.
-/// Detects <tool_call> blocks and emits tool_use content blocks.
-async fn respond_stream_with_tools(
-    res: &mut Response,
+/// Detects `<ai00:function_calls>` blocks and emits tool_use content blocks, with the input
+/// as `input_json_delta` chunks as its parameters complete.
+///
+/// With `single_tool_use`, tool calls after the first are dropped.
+fn respond_stream_with_tools(
     token_receiver: flume::Receiver<Token>,
-    message_id: String,
-    model_name: String,
-    input_tokens: usize,
+    start: MessageStartData,
     log_ctx: StreamLogContext,
-) {
+    max_event_size: Option<usize>,
+    single_tool_use: bool,
+    return_timings: bool,
+) -> EventStream {
     use std::cell::RefCell;
 
     // Shared state for the streaming handler
     struct StreamState {
         parser: Ai00FunctionCallsParser,
+        tool_uses: usize,
         output_tokens: usize,
         content_block_index: usize,
         text_block_started: bool,
+        /// If the events of the current tool call are dropped.
+        skip_tool_use: bool,
         message_started: bool,
         log_ctx: StreamLogContext,
     }
 
+    impl StreamState {
+        fn emit(
+            &mut self,
+            parsed: Vec<ToolStreamEvent>,
+            max_event_size: Option<usize>,
+            single_tool_use: bool,
+        ) -> Vec<SseEvent> {
+            let mut events = vec![];
+            for event in parsed {
+                match event {
+                    ToolStreamEvent::Text(text) => {
+                        // Start text block if needed
+                        if !self.text_block_started {
+                            events.push(emit_content_block_start_text(self.content_block_index));
+                            self.text_block_started = true;
+                        }
+                        events.extend(emit_text_deltas(
+                            self.content_block_index,
+                            text,
+                            max_event_size,
+                        ));
+                    }
+                    ToolStreamEvent::Start { id, name } => {
+                        self.skip_tool_use = single_tool_use && self.tool_uses > 0;
+                        if self.skip_tool_use {
+                            continue;
+                        }
+                        self.tool_uses += 1;
+
+                        // Close text block if open
+                        if self.text_block_started {
+                            events.push(emit_content_block_stop(self.content_block_index));
+                            self.content_block_index += 1;
+                            self.text_block_started = false;
+                        }
+                        events.push(emit_content_block_start_tool_use(
+                            self.content_block_index,
+                            id,
+                            name,
+                        ));
+                    }
+                    ToolStreamEvent::InputJson(json) if !self.skip_tool_use => {
+                        events.extend(emit_input_json_deltas(
+                            self.content_block_index,
+                            json,
+                            max_event_size,
+                        ));
+                    }
+                    ToolStreamEvent::Stop if !self.skip_tool_use => {
+                        events.push(emit_content_block_stop(self.content_block_index));
+                        self.content_block_index += 1;
+                    }
+                    ToolStreamEvent::InputJson(_) | ToolStreamEvent::Stop => {}
+                }
+            }
+            events
+        }
+    }
+
     let state = RefCell::new(StreamState {
         parser: Ai00FunctionCallsParser::new(),
+        tool_uses: 0,
         output_tokens: 0,
         content_block_index: 0,
         text_block_started: false,
+        skip_tool_use: false,
         message_started: false,
         log_ctx,
     });
 
     let stream = token_receiver.into_stream().flat_map(move |token| {
-        let mut events: Vec<Result<SseEvent, std::convert::Infallible>> = Vec::new();
+        let mut events: Vec<SseEvent> = Vec::new();
         let mut state = state.borrow_mut();
 
         match token {
             Token::Start => {
                 state.message_started = true;
-                events.push(Ok(emit_message_start(
-                    message_id.clone(),
-                    model_name.clone(),
-                    input_tokens,
-                )));
+                events.push(emit_message_start(start.clone()));
             }
             Token::Content(text) => {
                 state.output_tokens += 1;
 
                 // Feed token to parser
                 let result = state.parser.feed(&text);
-
-                // Emit text content if any
-                if let Some(text_content) = result.text {
-                    if !text_content.is_empty() {
-                        // Start text block if needed
-                        if !state.text_block_started {
-                            events
-                                .push(Ok(emit_content_block_start_text(state.content_block_index)));
-                            state.text_block_started = true;
-                        }
-                        events.push(Ok(emit_text_delta(state.content_block_index, text_content)));
-                    }
-                }
-
-                // Emit completed tool uses
-                for tool_use in result.tool_uses {
-                    // Close text block if open
-                    if state.text_block_started {
-                        events.push(Ok(emit_content_block_stop(state.content_block_index)));
-                        state.content_block_index += 1;
-                        state.text_block_started = false;
-                    }
-
-                    // Emit tool_use block
-                    events.push(Ok(emit_content_block_start_tool_use(
-                        state.content_block_index,
-                        tool_use.id,
-                        tool_use.name,
-                    )));
-
-                    // Emit the input JSON as a single delta
-                    let input_json = serde_json::to_string(&tool_use.input).unwrap_or_default();
-                    events.push(Ok(emit_input_json_delta(
-                        state.content_block_index,
-                        input_json,
-                    )));
-
-                    // Close tool_use block
-                    events.push(Ok(emit_content_block_stop(state.content_block_index)));
-                    state.content_block_index += 1;
-                }
+                events.extend(state.emit(result.events, max_event_size, single_tool_use));
             }
             Token::Stop(reason, counter) => {
                 // Determine stop reason (may be ToolUse if tools were parsed)
+                let stop_sequence = reason.stop_sequence().map(String::from);
                 let stop_reason = if state.parser.has_tool_use() {
                     StopReason::ToolUse
                 } else {
                     reason.into()
                 };
+                let stop_sequence =
+                    stop_sequence.filter(|_| stop_reason == StopReason::StopSequence);
 
                 // Emit canonical log with actual metrics
                 state
                     .log_ctx
                     .emit_with_counter(&counter, &format!("{:?}", stop_reason));
 
-                // Finalize parser
+                // Finalize parser, emitting any remaining text and tool calls
                 let final_result = state.parser.finalize();
-
-                // Emit any remaining text
-                if let Some(text_content) = final_result.text {
-                    if !text_content.is_empty() {
-                        if !state.text_block_started {
-                            events
-                                .push(Ok(emit_content_block_start_text(state.content_block_index)));
-                            state.text_block_started = true;
-                        }
-                        events.push(Ok(emit_text_delta(state.content_block_index, text_content)));
-                    }
-                }
-
-                // Emit any remaining tool uses
-                for tool_use in final_result.tool_uses {
-                    if state.text_block_started {
-                        events.push(Ok(emit_content_block_stop(state.content_block_index)));
-                        state.content_block_index += 1;
-                        state.text_block_started = false;
-                    }
-
-                    events.push(Ok(emit_content_block_start_tool_use(
-                        state.content_block_index,
-                        tool_use.id,
-                        tool_use.name,
-                    )));
-                    let input_json = serde_json::to_string(&tool_use.input).unwrap_or_default();
-                    events.push(Ok(emit_input_json_delta(
-                        state.content_block_index,
-                        input_json,
-                    )));
-                    events.push(Ok(emit_content_block_stop(state.content_block_index)));
-                    state.content_block_index += 1;
-                }
+                events.extend(state.emit(final_result.events, max_event_size, single_tool_use));
 
                 // Close any open text block
                 if state.text_block_started {
-                    events.push(Ok(emit_content_block_stop(state.content_block_index)));
+                    events.push(emit_content_block_stop(state.content_block_index));
                 }
 
-                events.push(Ok(emit_message_delta(stop_reason, state.output_tokens)));
+                events.push(emit_message_delta(
+                    stop_reason,
+                    stop_sequence,
+                    state.output_tokens,
+                ));
+                events.extend(emit_timings(return_timings, &counter));
+            }
+            Token::Error(error) => {
+                events.push(emit_generate_error(error));
+            }
+            Token::GrammarTrace(trace) => {
+                events.push(emit_grammar_trace(trace));
             }
             Token::Done => {
-                events.push(Ok(emit_message_stop()));
+                events.push(emit_message_stop());
             }
             _ => {
-                events.push(Ok(emit_ping()));
+                events.push(emit_ping());
             }
         }
 
-        futures_util::stream::iter(events)
+        futures_util::stream::iter(events.into_iter().map(Ok::<_, Infallible>))
+    });
+
+    stream.boxed()
+}
+
+/// Streaming handler for requests with both thinking and tools.
+///
+/// Emits the thinking block first, then text and tool_use blocks in the order the model writes
+/// them after `</think>`. With `single_tool_use`, tool calls after the first are dropped.
+fn respond_stream_with_thinking_and_tools(
+    token_receiver: flume::Receiver<Token>,
+    start: MessageStartData,
+    log_ctx: StreamLogContext,
+    max_event_size: Option<usize>,
+    single_tool_use: bool,
+    return_timings: bool,
+) -> EventStream {
+    use std::cell::RefCell;
+
+    /// The content block currently open.
+    #[derive(PartialEq, Eq)]
+    enum OpenBlock {
+        Thinking,
+        Text,
+    }
+
+    struct StreamState {
+        parser: ThinkingToolStreamParser,
+        tool_uses: usize,
+        output_tokens: usize,
+        /// Index of the open block, or of the next one if none is open.
+        content_block_index: usize,
+        open: Option<OpenBlock>,
+        /// If the events of the current tool call are dropped.
+        skip_tool_use: bool,
+        log_ctx: StreamLogContext,
+    }
+
+    impl StreamState {
+        fn close(&mut self, events: &mut Vec<SseEvent>) {
+            if self.open.take().is_some() {
+                events.push(emit_content_block_stop(self.content_block_index));
+                self.content_block_index += 1;
+            }
+        }
+
+        fn open(&mut self, block: OpenBlock, events: &mut Vec<SseEvent>) {
+            if self.open.as_ref() == Some(&block) {
+                return;
+            }
+            self.close(events);
+            let index = self.content_block_index;
+            events.push(match block {
+                OpenBlock::Thinking => emit_content_block_start_thinking(index),
+                OpenBlock::Text => emit_content_block_start_text(index),
+            });
+            self.open = Some(block);
+        }
+
+        fn emit(
+            &mut self,
+            pieces: Vec<StreamPiece>,
+            max_event_size: Option<usize>,
+            single_tool_use: bool,
+        ) -> Vec<SseEvent> {
+            let mut events = vec![];
+            for piece in pieces {
+                match piece {
+                    StreamPiece::Thinking(thinking) => {
+                        self.open(OpenBlock::Thinking, &mut events);
+                        let index = self.content_block_index;
+                        events.extend(emit_thinking_deltas(index, thinking, max_event_size));
+                    }
+                    StreamPiece::ThinkingComplete => {
+                        if self.open == Some(OpenBlock::Thinking) {
+                            let signature =
+                                generate_thinking_signature(self.parser.thinking_content());
+                            events.push(emit_signature_delta(self.content_block_index, signature));
+                            self.close(&mut events);
+                        }
+                    }
+                    StreamPiece::Text(text) => {
+                        self.open(OpenBlock::Text, &mut events);
+                        let index = self.content_block_index;
+                        events.extend(emit_text_deltas(index, text, max_event_size));
+                    }
+                    StreamPiece::Tool(ToolStreamEvent::Start { id, name }) => {
+                        self.skip_tool_use = single_tool_use && self.tool_uses > 0;
+                        if self.skip_tool_use {
+                            continue;
+                        }
+                        self.tool_uses += 1;
+                        self.close(&mut events);
+                        let index = self.content_block_index;
+                        events.push(emit_content_block_start_tool_use(index, id, name));
+                    }
+                    StreamPiece::Tool(ToolStreamEvent::InputJson(json)) if !self.skip_tool_use => {
+                        let index = self.content_block_index;
+                        events.extend(emit_input_json_deltas(index, json, max_event_size));
+                    }
+                    StreamPiece::Tool(ToolStreamEvent::Stop) if !self.skip_tool_use => {
+                        events.push(emit_content_block_stop(self.content_block_index));
+                        self.content_block_index += 1;
+                    }
+                    StreamPiece::Tool(_) => {}
+                }
+            }
+            events
+        }
+    }
+
+    let state = RefCell::new(StreamState {
+        parser: ThinkingToolStreamParser::new(),
+        tool_uses: 0,
+        output_tokens: 0,
+        content_block_index: 0,
+        open: None,
+        skip_tool_use: false,
+        log_ctx,
+    });
+
+    let stream = token_receiver.into_stream().flat_map(move |token| {
+        let mut events: Vec<SseEvent> = Vec::new();
+        let mut state = state.borrow_mut();
+
+        match token {
+            Token::Start => events.push(emit_message_start(start.clone())),
+            Token::Content(text) => {
+                state.output_tokens += 1;
+                let pieces = state.parser.feed(&text);
+                events.extend(state.emit(pieces, max_event_size, single_tool_use));
+            }
+            Token::Stop(reason, counter) => {
+                let pieces = state.parser.finalize();
+                events.extend(state.emit(pieces, max_event_size, single_tool_use));
+                state.close(&mut events);
+
+                let stop_sequence = reason.stop_sequence().map(String::from);
+                let stop_reason = match state.parser.has_tool_use() {
+                    true => StopReason::ToolUse,
+                    false => reason.into(),
+                };
+                let stop_sequence =
+                    stop_sequence.filter(|_| stop_reason == StopReason::StopSequence);
+                state
+                    .log_ctx
+                    .emit_with_counter(&counter, &format!("{:?}", stop_reason));
+
+                events.push(emit_message_delta(
+                    stop_reason,
+                    stop_sequence,
+                    state.output_tokens,
+                ));
+                events.extend(emit_timings(return_timings, &counter));
+            }
+            Token::Error(error) => events.push(emit_generate_error(error)),
+            Token::GrammarTrace(trace) => events.push(emit_grammar_trace(trace)),
+            Token::Done => events.push(emit_message_stop()),
+            _ => events.push(emit_ping()),
+        }
+
+        futures_util::stream::iter(events.into_iter().map(Ok::<_, Infallible>))
     });
 
-    salvo::sse::stream(res, stream);
+    stream.boxed()
+}
+
+/// Count the tokens of the prompt a messages request would be given, without generating.
+///
+/// `/v1/messages/count_tokens`.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 200, description = "Token count of the prompt", body = CountTokensResponse),
+        (status_code = 400, description = "Invalid request", body = ApiErrorResponse),
+        (status_code = 404, description = "Model not loaded", body = ApiErrorResponse),
+    )
+)]
+pub async fn count_tokens(
+    depot: &mut Depot,
+    req: &mut Request,
+    body: JsonBody<CountTokensRequest>,
+    res: &mut Response,
+) {
+    let request = body.0;
+    if request.model.is_empty() {
+        let err = ApiErrorResponse::invalid_request("model is required").with_param("model");
+        return err.respond(res);
+    }
+    if request.messages.is_empty() {
+        let err =
+            ApiErrorResponse::invalid_request("messages cannot be empty").with_param("messages");
+        return err.respond(res);
+    }
+    for (i, tool) in request.tools.iter().flatten().enumerate() {
+        if let Err(msg) = tool.validate() {
+            let err = ApiErrorResponse::invalid_request(msg).with_param(format!("tools.{i}"));
+            return err.respond(res);
+        }
+    }
+
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let profile = req
+        .headers()
+        .get(PROMPT_PROFILE_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let prompts = match select_prompts(config, profile) {
+        Ok(prompts) => prompts,
+        Err(err) => return err.respond(res),
+    };
+    let prompt = match build_prompt(
+        request.system.as_deref(),
+        &request.messages,
+        request.tools.as_deref(),
+        request.thinking.as_ref(),
+        prompts,
+    ) {
+        Ok(prompt) => prompt,
+        Err(err) => return prompt_error(err).respond(res),
+    };
+
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let info = match request_info_of(sender.clone(), &request.model, SLEEP).await {
+        Ok(info) => info,
+        Err(err) => return err.respond(res),
+    };
+    match info.tokenizer.encode(prompt.as_bytes()) {
+        Ok(tokens) => res.render(Json(CountTokensResponse {
+            input_tokens: tokens.len(),
+        })),
+        Err(err) => {
+            ApiErrorResponse::api_error(format!("failed to tokenize prompt: {err}")).respond(res)
+        }
+    }
 }
 
 /// Generate messages completion (Claude-compatible).
 ///
 /// This endpoint provides Claude Messages API compatibility for RWKV models.
+/// Streaming requests with a `Last-Event-ID` header resume the buffered events of an
+/// earlier stream instead of generating.
 #[endpoint(
     tags("messages"),
     responses(
         (status_code = 200, description = "Successful completion", body = MessagesResponse),
         (status_code = 400, description = "Invalid request", body = ApiErrorResponse),
+        (status_code = 404, description = "Model not loaded or resumed stream expired", body = ApiErrorResponse),
         (status_code = 500, description = "Server error", body = ApiErrorResponse),
     )
 )]
 pub async fn messages_handler(
     depot: &mut Depot,
-    req: JsonBody<MessagesRequest>,
+    req: &mut Request,
+    body: JsonBody<MessagesRequest>,
     res: &mut Response,
 ) {
-    let request = req.0;
+    let mut request = body.0;
 
     // Validate request
     if let Err(err) = validate_request(&request) {
-        res.status_code(err.status_code());
-        res.render(Json(err));
+        err.respond(res);
+        return;
+    }
+
+    let last_event_id = req
+        .headers()
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if let (true, Some(last_event_id)) = (request.stream, last_event_id) {
+        resume_stream(depot, last_event_id, res);
         return;
     }
 
+    let profile = req
+        .headers()
+        .get(PROMPT_PROFILE_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if let Some(profile) = profile {
+        request.set_default_prompt_profile(profile);
+    }
+    if let Ok(audit) = depot.obtain::<AuditLog>() {
+        let config = depot.obtain::<Arc<Config>>().unwrap();
+        let audit = audit.with_client(client_id(req, config));
+        depot.inject(audit);
+    }
+
+    let (request, state, metadata) = match prepare_request(depot, request).await {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            err.respond(res);
+            return;
+        }
+    };
+
     match request.stream {
-        true => respond_stream(depot, request, res).await,
-        false => {
-            if let Err(err) = respond_one(depot, request, res).await {
-                res.status_code(err.status_code());
-                res.render(Json(err));
+        true => respond_stream(depot, request, state, metadata, res).await,
+        false => match respond_one(depot, request, state, metadata).await {
+            Ok(response) => res.render(Json(response)),
+            Err(err) => err.respond(res),
+        },
+    }
+}
+
+/// Show what a messages request would be generated with, without generating: the formatted
+/// prompt, its token count, the stop sequences and the grammar.
+///
+/// The request goes through the same preparation as a real one, including image captions,
+/// retrieved context and server tools.
+///
+/// `/api/prompt/preview`.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 200, description = "What the request would be generated with", body = PromptPreview),
+        (status_code = 400, description = "Invalid request", body = ApiErrorResponse),
+        (status_code = 404, description = "Model not loaded", body = ApiErrorResponse),
+    )
+)]
+pub async fn preview_prompt(
+    depot: &mut Depot,
+    req: &mut Request,
+    body: JsonBody<MessagesRequest>,
+    res: &mut Response,
+) {
+    let mut request = body.0;
+    if let Err(err) = validate_request(&request) {
+        return err.respond(res);
+    }
+    let profile = req
+        .headers()
+        .get(PROMPT_PROFILE_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if let Some(profile) = profile {
+        request.set_default_prompt_profile(profile);
+    }
+
+    let (request, state, metadata) = match prepare_request(depot, request).await {
+        Ok(prepared) => prepared,
+        Err(err) => return err.respond(res),
+    };
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let prompts = match select_prompts(config, request.prompt_profile()) {
+        Ok(prompts) => prompts,
+        Err(err) => return err.respond(res),
+    };
+    let generate = match to_generate_request(&request, prompts, state, None, None) {
+        Ok(generate) => generate,
+        Err(err) => return err.respond(res),
+    };
+
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let info = match request_info_of(sender.clone(), &request.model, SLEEP).await {
+        Ok(info) => info,
+        Err(err) => return err.respond(res),
+    };
+    let input_tokens = match info.tokenizer.encode(generate.prompt.as_bytes()) {
+        Ok(tokens) => tokens.len(),
+        Err(err) => {
+            let err = ApiErrorResponse::api_error(format!("failed to tokenize prompt: {err}"));
+            return err.respond(res);
+        }
+    };
+    res.render(Json(PromptPreview {
+        prompt: generate.prompt,
+        cache_prefix: generate.cache_prefix,
+        input_tokens,
+        stop_sequences: generate.stop,
+        stop_tokens: generate.stop_tokens,
+        bnf_schema: generate.bnf_schema,
+        regex: generate.regex,
+        metadata,
+    }))
+}
+
+/// Check the prompt profile, caption images, retrieve context, and resolve the state, adapter
+/// and server tools of a validated request.
+///
+/// Also returns the metadata of the response, with the citations of the retrieved context and
+/// the seed of the sampler, chosen here if the request has none.
+async fn prepare_request(
+    depot: &Depot,
+    mut request: MessagesRequest,
+) -> Result<(MessagesRequest, Arc<InputState>, ResponseMetadata), ApiErrorResponse> {
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    select_prompts(config, request.prompt_profile())?;
+    request.timeout_ms = request.timeout_ms.or(config.generation.timeout_ms);
+
+    let captioner = depot.obtain::<Captioner>().ok();
+    caption_images(captioner, &mut request.messages).await?;
+    moderate_prompt(depot.obtain::<Moderator>().ok(), &request).await?;
+    let mut metadata = retrieve_context(depot, &mut request).await?;
+    metadata.seed = Some(*request.seed.get_or_insert_with(|| fastrand::u64(..)));
+
+    let state = resolve_state(depot, &request)?;
+
+    if let Some(adapter) = &request.adapter {
+        let sender = depot.obtain::<ThreadSender>().unwrap();
+        check_adapter(sender, &request.model, adapter).await?;
+    }
+
+    let mut request = match request.agentic {
+        true => add_server_tools(depot, request).await?,
+        false => request,
+    };
+    metadata.truncation = fit_context(depot, &mut request).await?;
+    Ok((request, state, metadata))
+}
+
+/// Answer one request of a batch, as a non-streaming request.
+///
+/// `depot` holds what [`messages_handler`] would find in its own.
+pub(super) async fn complete_request(
+    depot: &mut Depot,
+    mut request: MessagesRequest,
+) -> Result<MessagesResponse, ApiErrorResponse> {
+    validate_request(&request)?;
+    request.stream = false;
+    let (request, state, metadata) = prepare_request(depot, request).await?;
+    respond_one(depot, request, state, metadata).await
+}
+
+/// Start a streaming request for a transport other than SSE, and return its events.
+///
+/// `depot` holds what [`messages_handler`] would find in its own.
+pub(super) async fn stream_request(
+    depot: &mut Depot,
+    mut request: MessagesRequest,
+) -> Result<EventStream, ApiErrorResponse> {
+    validate_request(&request)?;
+    request.stream = true;
+    let (request, state, metadata) = prepare_request(depot, request).await?;
+    stream_events(depot, request, state, metadata).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages_request(previews: serde_json::Value) -> MessagesRequest {
+        serde_json::from_value(serde_json::json!({
+            "model": "rwkv",
+            "max_tokens": 64,
+            "messages": [{"role": "user", "content": "Weather in Paris?"}],
+            "tool_results_preview": previews,
+        }))
+        .unwrap()
+    }
+
+    fn tool_use(id: &str, name: &str) -> ContentBlock {
+        ContentBlock::ToolUse {
+            id: id.into(),
+            name: name.into(),
+            input: serde_json::json!({}),
+        }
+    }
+
+    #[test]
+    fn test_preview_tool_results() {
+        let request = messages_request(serde_json::json!({"weather": "sunny", "time": "noon"}));
+        let blocks = vec![
+            ContentBlock::Text {
+                text: "Let me check.".into(),
+            },
+            tool_use("toolu_1", "weather"),
+            tool_use("toolu_2", "time"),
+        ];
+        let results = preview_tool_results(&request, &blocks, StopReason::ToolUse).unwrap();
+        assert_eq!(results.len(), 2);
+        match &results[1] {
+            ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } => {
+                assert_eq!(tool_use_id, "toolu_2");
+                assert_eq!(content.to_text(), "noon");
+                assert!(!is_error);
             }
+            block => panic!("unexpected block {block:?}"),
         }
     }
+
+    #[test]
+    fn test_preview_tool_results_needs_every_tool() {
+        let request = messages_request(serde_json::json!({"weather": "sunny"}));
+        let blocks = vec![tool_use("toolu_1", "weather"), tool_use("toolu_2", "time")];
+        assert!(preview_tool_results(&request, &blocks, StopReason::ToolUse).is_none());
+    }
+
+    #[test]
+    fn test_preview_tool_results_only_on_tool_use() {
+        let request = messages_request(serde_json::json!({"weather": "sunny"}));
+        let blocks = vec![tool_use("toolu_1", "weather")];
+        assert!(preview_tool_results(&request, &blocks, StopReason::EndTurn).is_none());
+        assert!(preview_tool_results(&request, &[], StopReason::ToolUse).is_none());
+
+        let request = messages_request(serde_json::Value::Null);
+        assert!(preview_tool_results(&request, &blocks, StopReason::ToolUse).is_none());
+    }
 }
@@ -0,0 +1,211 @@
+//! Streaming parser for output with both thinking and tool calls.
+//!
+//! The thinking parser splits the output into thinking and response text, and the response text
+//! is fed to the ai00 function calls parser, so that a single stream yields the thinking, then
+//! the text and tool calls in the order the model wrote them.
+
+use super::{
+    thinking_extractor::{ThinkingStreamParser, ThinkingStreamResult},
+    tool_parser::{Ai00FunctionCallsParser, ParseResult, ToolStreamEvent},
+};
+
+/// A piece of output of [`ThinkingToolStreamParser`].
+#[derive(Debug, Clone)]
+pub enum StreamPiece {
+    /// Text of the thinking block.
+    Thinking(String),
+    /// The thinking block ended.
+    ThinkingComplete,
+    /// Response text outside of function calls.
+    Text(String),
+    /// A tool call, its input in chunks, or its end.
+    Tool(ToolStreamEvent),
+}
+
+/// Streaming parser for thinking followed by text and ai00 function calls.
+///
+/// Starts inside the thinking block, as the thinking prompt opens it. Whitespace between blocks,
+/// e.g. the newline after `</think>`, is only passed on as part of the text that follows it,
+/// so that it does not open text blocks of its own.
+#[derive(Debug, Default)]
+pub struct ThinkingToolStreamParser {
+    thinking: ThinkingStreamParser,
+    tools: Ai00FunctionCallsParser,
+    /// Whitespace held back until text that is not whitespace follows.
+    pending: String,
+    /// If text was passed on since the last thinking or tool call.
+    text_started: bool,
+}
+
+impl ThinkingToolStreamParser {
+    /// Create a new parser starting inside the thinking block.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All thinking content so far, for the signature of the thinking block.
+    pub fn thinking_content(&self) -> &str {
+        self.thinking.thinking_content()
+    }
+
+    /// Check if the output had any tool call.
+    pub fn has_tool_use(&self) -> bool {
+        self.tools.has_tool_use()
+    }
+
+    /// Feed a token and return the pieces it completes.
+    pub fn feed(&mut self, token: &str) -> Vec<StreamPiece> {
+        let result = self.thinking.feed(token);
+        let mut pieces = vec![];
+        self.split(result, &mut pieces);
+        pieces
+    }
+
+    /// Finish parsing and return the remaining pieces.
+    pub fn finalize(&mut self) -> Vec<StreamPiece> {
+        let result = self.thinking.finalize();
+        let mut pieces = vec![];
+        self.split(result, &mut pieces);
+        let result = self.tools.finalize();
+        self.push_tools(result, &mut pieces);
+        pieces
+    }
+
+    fn split(&mut self, result: ThinkingStreamResult, pieces: &mut Vec<StreamPiece>) {
+        if let Some(thinking) = result.thinking.filter(|thinking| !thinking.is_empty()) {
+            pieces.push(StreamPiece::Thinking(thinking));
+        }
+        if result.thinking_complete {
+            pieces.push(StreamPiece::ThinkingComplete);
+            self.text_started = false;
+        }
+        if let Some(text) = result.text {
+            let result = self.tools.feed(&text);
+            self.push_tools(result, pieces);
+        }
+    }
+
+    fn push_tools(&mut self, result: ParseResult, pieces: &mut Vec<StreamPiece>) {
+        for event in result.events {
+            match event {
+                ToolStreamEvent::Text(text) if self.text_started || !text.trim().is_empty() => {
+                    let text = std::mem::take(&mut self.pending) + &text;
+                    pieces.push(StreamPiece::Text(text));
+                    self.text_started = true;
+                }
+                ToolStreamEvent::Text(text) => self.pending.push_str(&text),
+                event => {
+                    self.pending.clear();
+                    self.text_started = false;
+                    pieces.push(StreamPiece::Tool(event));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `output` a few characters at a time, and name the pieces for comparison, with
+    /// the text trimmed.
+    fn parse(output: &str) -> Vec<String> {
+        let mut parser = ThinkingToolStreamParser::new();
+        let chars: Vec<char> = output.chars().collect();
+        let mut pieces: Vec<_> = chars
+            .chunks(3)
+            .flat_map(|chunk| parser.feed(&chunk.iter().collect::<String>()))
+            .collect();
+        pieces.extend(parser.finalize());
+
+        // merge consecutive deltas, which depend on how the output is split
+        let mut names: Vec<String> = vec![];
+        for piece in pieces {
+            let name = match piece {
+                StreamPiece::Thinking(text) => format!("thinking:{text}"),
+                StreamPiece::ThinkingComplete => "thinking_complete".into(),
+                StreamPiece::Text(text) => format!("text:{text}"),
+                StreamPiece::Tool(ToolStreamEvent::Start { name, .. }) => {
+                    format!("tool_use:{name}")
+                }
+                StreamPiece::Tool(ToolStreamEvent::InputJson(json)) => format!("input:{json}"),
+                StreamPiece::Tool(ToolStreamEvent::Stop) => "tool_use_stop".into(),
+                StreamPiece::Tool(ToolStreamEvent::Text(_)) => unreachable!(),
+            };
+            match names.last_mut() {
+                Some(last) if last.starts_with("thinking:") && name.starts_with("thinking:") => {
+                    last.push_str(&name["thinking:".len()..])
+                }
+                Some(last) if last.starts_with("text:") && name.starts_with("text:") => {
+                    last.push_str(&name["text:".len()..])
+                }
+                Some(last) if last.starts_with("input:") && name.starts_with("input:") => {
+                    last.push_str(&name["input:".len()..])
+                }
+                _ => names.push(name),
+            }
+        }
+        names
+            .into_iter()
+            .map(|name| match name.strip_prefix("text:") {
+                Some(text) => format!("text:{}", text.trim()),
+                None => name,
+            })
+            .collect()
+    }
+
+    const CALL: &str = "<ai00:function_calls>\n  <invoke name=\"search\">\n    \
+        <parameter name=\"query\">rwkv</parameter>\n  </invoke>\n</ai00:function_calls>";
+
+    #[test]
+    fn test_thinking_then_tool_use() {
+        let output = format!("I should search.</think>\n{CALL}");
+        assert_eq!(
+            parse(&output),
+            [
+                "thinking:I should search.",
+                "thinking_complete",
+                "tool_use:search",
+                r#"input:{"query":"rwkv"}"#,
+                "tool_use_stop"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_thinking_text_and_tool_use() {
+        let output = format!("Plan.</think>\nLet me look that up.\n{CALL}");
+        assert_eq!(
+            parse(&output),
+            [
+                "thinking:Plan.",
+                "thinking_complete",
+                "text:Let me look that up.",
+                "tool_use:search",
+                r#"input:{"query":"rwkv"}"#,
+                "tool_use_stop"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_thinking_and_text_only() {
+        assert_eq!(
+            parse("Easy.</think>\n\nThe answer is 4."),
+            [
+                "thinking:Easy.",
+                "thinking_complete",
+                "text:The answer is 4."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unfinished_thinking() {
+        assert_eq!(
+            parse("Still thinking"),
+            ["thinking:Still thinking", "thinking_complete"]
+        );
+    }
+}
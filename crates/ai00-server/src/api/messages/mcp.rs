@@ -0,0 +1,224 @@
+//! Client of MCP (Model Context Protocol) servers over the streamable HTTP transport.
+//!
+//! Every JSON-RPC message is POSTed to the server URL. The server answers a request either with a
+//! JSON body or with an event stream whose `data` lines carry the response. The session is
+//! initialized on first use and its `Mcp-Session-Id` sent with every later message.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    Client,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+
+use crate::config::McpServer;
+
+const PROTOCOL_VERSION: &str = "2025-03-26";
+const SESSION_HEADER: &str = "Mcp-Session-Id";
+
+/// A tool offered by an MCP server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpTool {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default = "empty_schema")]
+    pub input_schema: Value,
+}
+
+fn empty_schema() -> Value {
+    json!({ "type": "object" })
+}
+
+/// Output of a tool call: its text content, and whether the tool reported an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct McpOutput {
+    pub text: String,
+    pub is_error: bool,
+}
+
+pub struct McpClient {
+    pub server: McpServer,
+    client: Client,
+    /// Session id assigned on initialization, if the server assigns one.
+    session: OnceCell<Option<String>>,
+    next_id: AtomicU64,
+}
+
+impl McpClient {
+    pub fn new(server: McpServer, client: Client) -> Self {
+        Self {
+            server,
+            client,
+            session: OnceCell::new(),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    async fn post(&self, session: Option<&str>, message: &Value) -> Result<reqwest::Response> {
+        let mut request = self
+            .client
+            .post(&self.server.url)
+            .header(ACCEPT, "application/json, text/event-stream")
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(message)?);
+        for (name, value) in &self.server.headers {
+            request = request.header(name, value);
+        }
+        if let Some(session) = session {
+            request = request.header(SESSION_HEADER, session);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            bail!(
+                "MCP server {} answered {}",
+                self.server.name,
+                response.status()
+            );
+        }
+        Ok(response)
+    }
+
+    /// Send a request and wait for its result, along with the session id the server assigned.
+    async fn exchange(
+        &self,
+        session: Option<&str>,
+        method: &str,
+        params: Value,
+    ) -> Result<(Value, Option<String>)> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let response = self.post(session, &message).await?;
+        let session_id = response
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let is_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        let body = response.bytes().await?;
+
+        let response = match is_stream {
+            true => String::from_utf8_lossy(&body)
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .filter_map(|data| serde_json::from_str::<Value>(data.trim()).ok())
+                .find(|message| message["id"] == id)
+                .ok_or_else(|| anyhow!("MCP server closed the stream without answering"))?,
+            false => serde_json::from_slice(&body)?,
+        };
+        if let Some(error) = response.get("error") {
+            bail!("MCP {method} failed: {}", error["message"]);
+        }
+        Ok((response["result"].clone(), session_id))
+    }
+
+    /// Send a request and wait for its result.
+    async fn send(&self, session: Option<&str>, method: &str, params: Value) -> Result<Value> {
+        let (result, _) = self.exchange(session, method, params).await?;
+        Ok(result)
+    }
+
+    /// The session, initialized if this is the first use.
+    async fn session(&self) -> Result<Option<&str>> {
+        let session = self
+            .session
+            .get_or_try_init(|| async {
+                let params = json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "ai00-server", "version": env!("CARGO_PKG_VERSION") },
+                });
+                let (_, session) = self.exchange(None, "initialize", params).await?;
+                let notification =
+                    json!({ "jsonrpc": "2.0", "method": "notifications/initialized" });
+                self.post(session.as_deref(), &notification).await?;
+                anyhow::Ok(session)
+            })
+            .await?;
+        Ok(session.as_deref())
+    }
+
+    /// List all tools of the server.
+    pub async fn list_tools(&self) -> Result<Vec<McpTool>> {
+        let session = self.session().await?;
+        let mut tools = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut result = self.send(session, "tools/list", params).await?;
+            let page: Vec<McpTool> = serde_json::from_value(result["tools"].take())?;
+            tools.extend(page);
+            cursor = result["nextCursor"].as_str().map(String::from);
+            if cursor.is_none() {
+                break Ok(tools);
+            }
+        }
+    }
+
+    /// Call the tool `name` of the server.
+    pub async fn call_tool(&self, name: &str, arguments: &Value) -> Result<McpOutput> {
+        let session = self.session().await?;
+        let params = json!({ "name": name, "arguments": arguments });
+        let result = self.send(session, "tools/call", params).await?;
+        Ok(parse_output(&result))
+    }
+}
+
+/// Join the text items of a `tools/call` result; other items are described by their type.
+fn parse_output(result: &Value) -> McpOutput {
+    let items = result["content"].as_array().into_iter().flatten();
+    let text = items
+        .map(|item| match item["type"].as_str() {
+            Some("text") => item["text"].as_str().unwrap_or_default().to_string(),
+            Some(ty) => format!("[{ty} content]"),
+            None => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    McpOutput {
+        text,
+        is_error: result["isError"].as_bool().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let result = json!({
+            "content": [
+                { "type": "text", "text": "18°C" },
+                { "type": "image", "data": "", "mimeType": "image/png" }
+            ],
+            "isError": false
+        });
+        assert_eq!(
+            parse_output(&result),
+            McpOutput {
+                text: "18°C\n[image content]".into(),
+                is_error: false
+            }
+        );
+        assert!(parse_output(&json!({ "content": [], "isError": true })).is_error);
+    }
+
+    #[test]
+    fn test_tool_defaults() {
+        let tool: McpTool = serde_json::from_value(json!({ "name": "echo" })).unwrap();
+        assert_eq!(tool.input_schema, json!({ "type": "object" }));
+    }
+}
@@ -3,20 +3,61 @@
 //! This module provides a `/v1/messages` endpoint compatible with
 //! Anthropic's Claude Messages API format.
 
+mod audit;
+mod batch;
 pub mod bnf_generator;
 pub mod bnf_grammars;
+mod conversation;
 mod handler;
+mod interleaved;
+mod mcp;
+mod moderation;
 pub mod prompt;
+mod rag;
+mod resume;
+mod server_tools;
+mod session;
+mod state;
 mod streaming;
+pub mod template;
 mod thinking_extractor;
 mod tool_parser;
+mod tool_validation;
+mod truncation;
 mod types;
+mod vision;
+mod ws;
 
-pub use handler::messages_handler;
-pub use streaming::{emit_error, StreamErrorData, StreamErrorEvent};
+pub use audit::{AuditEntry, AuditLog};
+pub use batch::{batches, BatchOutcome, BatchRequest, BatchResult};
+pub use conversation::ConversationStore;
+pub use handler::{count_tokens, messages_handler, preview_prompt};
+pub use interleaved::{StreamPiece, ThinkingToolStreamParser};
+pub use mcp::{McpClient, McpOutput, McpTool};
+pub use moderation::Moderator;
+pub use rag::Retriever;
+pub use resume::StreamBacklogs;
+pub use server_tools::ServerTools;
+pub use session::{continue_session, ContinueRequest, Session, SessionStore};
+pub use state::{
+    blend_state, create_state, decode_state, delete_state, encode_state, export_state,
+    import_state, BlendComponent, BlendStateRequest, CreateStateRequest, CreateStateResponse,
+    StateStore, StoredState,
+};
+pub use streaming::{
+    emit_error, emit_generate_error, emit_grammar_trace, emit_message_metadata, event_data,
+    split_delta, ContentBlockDeltaEvent, ContentBlockStartEvent, ContentBlockStopEvent,
+    ContentDelta, MessageDeltaData, MessageDeltaEvent, MessageMetadataEvent, MessageStartData,
+    MessageStartEvent, MessageStopEvent, OutputUsage, PingEvent, StreamErrorData, StreamErrorEvent,
+};
 pub use thinking_extractor::{
     generate_thinking_signature, ThinkingExtractor, ThinkingResult, ThinkingStreamParser,
     ThinkingStreamResult, ThinkingStreamState,
 };
-pub use tool_parser::{Ai00FunctionCallsParser, ParseResult, ParsedToolUse, ToolParser};
+pub use tool_parser::{
+    Ai00FunctionCallsParser, ParseResult, ParsedToolUse, ToolParser, ToolStreamEvent,
+};
+pub use tool_validation::{check_tool_inputs, validate_input, validate_tool_use};
 pub use types::*;
+pub use vision::Captioner;
+pub use ws::messages_ws;
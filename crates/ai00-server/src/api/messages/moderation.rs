@@ -0,0 +1,306 @@
+//! Moderation of prompts and output against patterns, keywords or an external endpoint.
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use ai00_core::{FinishReason, GenerateRequest, Token};
+use anyhow::{bail, Result};
+use regex::Regex;
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+use super::types::MessagesRequest;
+use crate::{api::error::ApiErrorResponse, config::ModerationOption};
+
+#[derive(Debug, Serialize)]
+struct ModerationRequest<'a> {
+    input: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    flagged: bool,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Checks text against the rules of `[moderation]`.
+#[derive(Clone)]
+pub struct Moderator {
+    option: ModerationOption,
+    patterns: Vec<Regex>,
+    keywords: Vec<String>,
+    client: Client,
+}
+
+impl Moderator {
+    pub fn new(option: &ModerationOption) -> Result<Self> {
+        let patterns = option
+            .patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()?;
+        let keywords = option
+            .keywords
+            .iter()
+            .map(|keyword| keyword.to_lowercase())
+            .collect();
+        let client = Client::builder()
+            .timeout(Duration::from_secs(option.timeout))
+            .build()?;
+        Ok(Self {
+            option: option.clone(),
+            patterns,
+            keywords,
+            client,
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.patterns.is_empty() || !self.keywords.is_empty() || self.option.endpoint.is_some()
+    }
+
+    /// Why the patterns or keywords flag `text`, if they do.
+    fn match_rules(&self, text: &str) -> Option<String> {
+        if let Some(pattern) = self.patterns.iter().find(|pattern| pattern.is_match(text)) {
+            return Some(format!("matches pattern `{pattern}`"));
+        }
+        let text = text.to_lowercase();
+        self.keywords
+            .iter()
+            .find(|keyword| text.contains(keyword.as_str()))
+            .map(|keyword| format!("contains keyword `{keyword}`"))
+    }
+
+    /// Why the endpoint flags `text`, if it does.
+    async fn ask_endpoint(&self, text: &str) -> Result<Option<String>> {
+        let Some(url) = &self.option.endpoint else {
+            return Ok(None);
+        };
+        let mut request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&ModerationRequest { input: text })?);
+        if let Some(token) = &self.option.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            bail!("moderation endpoint answered {}", response.status());
+        }
+        let response: ModerationResponse = serde_json::from_slice(&response.bytes().await?)?;
+        Ok(response.flagged.then(|| {
+            response
+                .reason
+                .unwrap_or_else(|| "flagged by endpoint".into())
+        }))
+    }
+
+    /// Why `text` is flagged, if it is.
+    async fn check(&self, text: &str) -> Result<Option<String>> {
+        match self.match_rules(text) {
+            Some(reason) => Ok(Some(reason)),
+            None => self.ask_endpoint(text).await,
+        }
+    }
+}
+
+/// Reject a request whose system prompt or messages are flagged.
+///
+/// Fails with `api_error` if the endpoint cannot be reached, so that nothing unchecked is
+/// generated from.
+pub async fn moderate_prompt(
+    moderator: Option<&Moderator>,
+    request: &MessagesRequest,
+) -> Result<(), ApiErrorResponse> {
+    let Some(moderator) = moderator.filter(|m| m.option.prompts && m.is_enabled()) else {
+        return Ok(());
+    };
+    let system = request.system.iter().map(|system| system.text.clone());
+    let messages = request.messages.iter().map(|m| m.content.to_text());
+    let text = system.chain(messages).collect::<Vec<_>>().join("\n\n");
+
+    match moderator.check(&text).await {
+        Ok(None) => Ok(()),
+        Ok(Some(reason)) => {
+            tracing::info!(event = "prompt_flagged", reason = %reason);
+            Err(
+                ApiErrorResponse::invalid_request(format!("The prompt was flagged: {reason}"))
+                    .with_param("messages"),
+            )
+        }
+        Err(err) => Err(ApiErrorResponse::api_error(format!(
+            "failed to moderate the prompt: {err}"
+        ))),
+    }
+}
+
+/// Pass the tokens of a generation through, scanning the output over a sliding window.
+///
+/// Once the output is flagged, the rest of it is dropped and `request` is told to stop, which
+/// ends it with [`FinishReason::ContentFilter`]. Failures of the endpoint are logged and let
+/// the output through.
+pub fn moderate_output(
+    moderator: Option<&Moderator>,
+    request: &GenerateRequest,
+    receiver: flume::Receiver<Token>,
+) -> flume::Receiver<Token> {
+    let Some(moderator) = moderator.filter(|m| m.option.output && m.is_enabled()) else {
+        return receiver;
+    };
+    let moderator = moderator.clone();
+    let content_filter = request.content_filter.clone();
+    let (sender, moderated) = flume::unbounded();
+    tokio::spawn(async move {
+        let mut window = OutputWindow::new(moderator.option.window);
+        let mut flagged = false;
+        while let Ok(token) = receiver.recv_async().await {
+            let token = match token {
+                Token::Content(_) if flagged => continue,
+                Token::Content(content) => {
+                    window.push(&content);
+                    let reason = match moderator.match_rules(window.text()) {
+                        Some(reason) => Some(reason),
+                        None if window.unchecked >= moderator.option.endpoint_interval => {
+                            window.unchecked = 0;
+                            ask_endpoint(&moderator, window.text()).await
+                        }
+                        None => None,
+                    };
+                    if let Some(reason) = reason {
+                        tracing::info!(event = "output_flagged", reason = %reason);
+                        content_filter.store(true, Ordering::Release);
+                        flagged = true;
+                        continue;
+                    }
+                    Token::Content(content)
+                }
+                Token::Stop(reason, counter) => {
+                    // the end of the output may not have been seen by the endpoint yet
+                    if !flagged && window.unchecked > 0 {
+                        flagged = ask_endpoint(&moderator, window.text()).await.is_some();
+                    }
+                    match flagged {
+                        true => Token::Stop(FinishReason::ContentFilter, counter),
+                        false => Token::Stop(reason, counter),
+                    }
+                }
+                token => token,
+            };
+            if sender.send(token).is_err() {
+                break;
+            }
+        }
+    });
+    moderated
+}
+
+/// Ask the endpoint about the output, letting it through if that fails.
+async fn ask_endpoint(moderator: &Moderator, text: &str) -> Option<String> {
+    match moderator.ask_endpoint(text).await {
+        Ok(reason) => reason,
+        Err(err) => {
+            tracing::warn!(event = "moderation_failed", error = %err);
+            None
+        }
+    }
+}
+
+/// The latest output, up to a number of bytes.
+struct OutputWindow {
+    text: String,
+    size: usize,
+    /// Bytes pushed since the endpoint last checked the window.
+    unchecked: usize,
+}
+
+impl OutputWindow {
+    fn new(size: usize) -> Self {
+        Self {
+            text: String::new(),
+            size,
+            unchecked: 0,
+        }
+    }
+
+    fn push(&mut self, content: &str) {
+        self.text.push_str(content);
+        self.unchecked += content.len();
+        if self.text.len() > self.size {
+            let mut start = self.text.len() - self.size;
+            while !self.text.is_char_boundary(start) {
+                start += 1;
+            }
+            self.text.drain(..start);
+        }
+    }
+
+    fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moderator(patterns: &[&str], keywords: &[&str]) -> Moderator {
+        Moderator::new(&ModerationOption {
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_match_rules() {
+        let moderator = moderator(&[r"\b\d{3}-\d{2}-\d{4}\b"], &["Launch Codes"]);
+        assert!(moderator.match_rules("nothing to see").is_none());
+        assert!(moderator.match_rules("my ssn is 123-45-6789").is_some());
+        assert_eq!(
+            moderator.match_rules("the LAUNCH CODES are").unwrap(),
+            "contains keyword `launch codes`"
+        );
+    }
+
+    #[test]
+    fn test_output_window() {
+        let mut window = OutputWindow::new(8);
+        window.push("hello ");
+        window.push("wörld!");
+        assert_eq!(window.text(), "örld!");
+        assert_eq!(window.unchecked, 13);
+    }
+
+    #[tokio::test]
+    async fn test_flagged_output_is_cut() {
+        let moderator = moderator(&[], &["secret"]);
+        let request = GenerateRequest::default();
+        let (sender, receiver) = flume::unbounded();
+        let moderated = moderate_output(Some(&moderator), &request, receiver);
+        for content in ["The ", "sec", "ret ", "is ", "42"] {
+            sender.send(Token::Content(content.into())).unwrap();
+        }
+        sender
+            .send(Token::Stop(FinishReason::Stop, Default::default()))
+            .unwrap();
+        drop(sender);
+
+        let mut text = String::new();
+        let mut finish_reason = None;
+        while let Ok(token) = moderated.recv_async().await {
+            match token {
+                Token::Content(content) => text += &content,
+                Token::Stop(reason, _) => finish_reason = Some(reason),
+                _ => {}
+            }
+        }
+        assert_eq!(text, "The sec");
+        assert_eq!(finish_reason, Some(FinishReason::ContentFilter));
+        assert!(request.content_filter.load(Ordering::Acquire));
+    }
+}
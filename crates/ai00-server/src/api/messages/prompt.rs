@@ -3,8 +3,13 @@
 //! This module contains functions for building prompts from messages,
 //! used by both the HTTP server and CLI tools like make-binidx.
 
-use super::types::{generate_tool_system_prompt, MessageParam, MessageRole, ThinkingConfig, Tool};
-use crate::config::PromptsConfig;
+use anyhow::Result;
+
+use super::{
+    template::render_template,
+    types::{generate_tool_system_prompt, MessageParam, MessageRole, ThinkingConfig, Tool},
+};
+use crate::config::{PriorThinking, PromptsConfig};
 
 /// Build RWKV prompt from messages using ai00 chat format.
 ///
@@ -38,13 +43,19 @@ use crate::config::PromptsConfig;
 /// <ai00:assistant>
 /// <think>
 /// ```
+///
+/// If the last message is from the assistant, it is prefilled: its text follows the assistant
+/// prefix, and generation continues from it instead of starting a new turn.
+///
+/// If `prompts.template` is set, the prompt is rendered by that chat template instead,
+/// which fails if the template rejects the conversation.
 pub fn build_prompt(
     system: Option<&str>,
     messages: &[MessageParam],
     tools: Option<&[Tool]>,
     thinking: Option<&ThinkingConfig>,
     prompts: &PromptsConfig,
-) -> String {
+) -> Result<String> {
     build_prompt_inner(system, messages, tools, thinking, prompts, true)
 }
 
@@ -58,10 +69,55 @@ pub fn build_training_prompt(
     tools: Option<&[Tool]>,
     thinking: Option<&ThinkingConfig>,
     prompts: &PromptsConfig,
-) -> String {
+) -> Result<String> {
     build_prompt_inner(system, messages, tools, thinking, prompts, false)
 }
 
+/// Index of the message the current turn starts with: the last user message that is not only
+/// tool results. Earlier messages belong to prior turns.
+fn current_turn_start(messages: &[MessageParam]) -> usize {
+    messages
+        .iter()
+        .rposition(|m| m.role == MessageRole::User && !m.content.is_tool_result_only())
+        .unwrap_or_default()
+}
+
+/// Text of `messages[index]`, with the thinking of prior turns as `prompts.prior_thinking` says.
+pub(super) fn message_text(
+    messages: &[MessageParam],
+    index: usize,
+    prompts: &PromptsConfig,
+) -> String {
+    let content = &messages[index].content;
+    if index >= current_turn_start(messages) {
+        return content.to_text();
+    }
+    match prompts.prior_thinking {
+        PriorThinking::Keep => content.to_text(),
+        PriorThinking::Strip => content.to_text_with(|_| None),
+        PriorThinking::Render => content.to_text_with(|thinking| {
+            Some(
+                prompts
+                    .prior_thinking_template
+                    .replace("{thinking}", thinking),
+            )
+        }),
+    }
+}
+
+/// The system turn at the start of `prompt`, tool definitions included, if there is one.
+///
+/// This is the prefix that `cache_control` on system blocks or tools pins in the cache.
+pub fn system_turn<'a>(prompt: &'a str, prompts: &PromptsConfig) -> Option<&'a str> {
+    let open = format!("<ai00:{}>\n", prompts.role_system);
+    let close = format!("\n</ai00:{}>\n\n", prompts.role_system);
+    if !prompt.starts_with(&open) {
+        return None;
+    }
+    let end = prompt.find(&close)? + close.len();
+    Some(&prompt[..end])
+}
+
 fn build_prompt_inner(
     system: Option<&str>,
     messages: &[MessageParam],
@@ -69,7 +125,31 @@ fn build_prompt_inner(
     thinking: Option<&ThinkingConfig>,
     prompts: &PromptsConfig,
     include_assistant_prefix: bool,
-) -> String {
+) -> Result<String> {
+    // a trailing assistant message is continued rather than closed
+    let (messages, prefill) = match messages.split_last() {
+        Some((last, earlier))
+            if include_assistant_prefix && last.role == MessageRole::Assistant =>
+        {
+            let text = message_text(messages, messages.len() - 1, prompts);
+            (earlier, Some(text.trim_end().to_string()))
+        }
+        _ => (messages, None),
+    };
+
+    if let Some(template) = &prompts.template {
+        let prompt = render_template(
+            template,
+            system,
+            messages,
+            tools,
+            thinking,
+            prompts,
+            include_assistant_prefix,
+        )?;
+        return Ok(prompt + prefill.as_deref().unwrap_or_default());
+    }
+
     let mut prompt = String::new();
 
     // Add system prompt with XML turn markers
@@ -118,7 +198,7 @@ fn build_prompt_inner(
     };
 
     for (i, msg) in messages.iter().enumerate() {
-        let content = msg.content.to_text();
+        let content = message_text(messages, i, prompts);
 
         // Tool result messages are injected without turn wrappers
         // They appear immediately after </ai00:function_calls> in the assistant turn
@@ -240,10 +320,13 @@ fn build_prompt_inner(
             prompt.push_str(&prompts.assistant_prefix);
         }
     }
+    if let Some(prefill) = prefill {
+        prompt.push_str(&prefill);
+    }
 
     // RWKV requires no trailing whitespace or tokenizer may produce non-English output
     // See: https://huggingface.co/BlinkDL/rwkv7-g1
-    prompt.trim_end().to_string()
+    Ok(prompt.trim_end().to_string())
 }
 
 /// Get the thinking suffix to append to user message based on budget.
@@ -278,7 +361,8 @@ mod tests {
             content: MessageContent::Text("Hello".to_string()),
         }];
 
-        let prompt = build_prompt(Some("You are helpful."), &messages, None, None, &prompts);
+        let prompt =
+            build_prompt(Some("You are helpful."), &messages, None, None, &prompts).unwrap();
 
         // Verify XML turn format
         assert!(prompt.contains("<ai00:system>"));
@@ -290,6 +374,26 @@ mod tests {
         assert!(prompt.contains("<ai00:assistant>"));
     }
 
+    #[test]
+    fn test_system_turn() {
+        use super::super::types::{MessageContent, MessageParam, MessageRole};
+
+        let prompts = PromptsConfig::default();
+        let messages = vec![MessageParam {
+            role: MessageRole::User,
+            content: MessageContent::Text("Hello".to_string()),
+        }];
+
+        let prompt =
+            build_prompt(Some("You are helpful."), &messages, None, None, &prompts).unwrap();
+        let turn = system_turn(&prompt, &prompts).unwrap();
+        assert!(turn.ends_with("</ai00:system>\n\n"));
+        assert!(prompt[turn.len()..].starts_with("<ai00:user>"));
+
+        let prompt = build_prompt(None, &messages, None, None, &prompts).unwrap();
+        assert_eq!(system_turn(&prompt, &prompts), None);
+    }
+
     #[test]
     fn test_build_prompt_preserves_newlines() {
         use super::super::types::{MessageContent, MessageParam, MessageRole};
@@ -300,12 +404,38 @@ mod tests {
             content: MessageContent::Text("Line 1\n\nLine 2\n\n\nLine 3".to_string()),
         }];
 
-        let prompt = build_prompt(None, &messages, None, None, &prompts);
+        let prompt = build_prompt(None, &messages, None, None, &prompts).unwrap();
 
         // Verify newlines are preserved (no filtering)
         assert!(prompt.contains("Line 1\n\nLine 2\n\n\nLine 3"));
     }
 
+    #[test]
+    fn test_build_prompt_prefill() {
+        use super::super::types::{MessageContent, MessageParam, MessageRole};
+
+        let prompts = PromptsConfig::default();
+        let messages = vec![
+            MessageParam {
+                role: MessageRole::User,
+                content: MessageContent::Text("List three colors.".to_string()),
+            },
+            MessageParam {
+                role: MessageRole::Assistant,
+                content: MessageContent::Text("1. Red\n".to_string()),
+            },
+        ];
+
+        let prompt = build_prompt(None, &messages, None, None, &prompts).unwrap();
+        assert!(prompt.ends_with("</ai00:user>\n\n<ai00:assistant>\n1. Red"));
+        assert!(!prompt.contains("</ai00:assistant>"));
+
+        // training prompts keep the turn complete
+        let prompt = build_training_prompt(None, &messages, None, None, &prompts).unwrap();
+        assert!(prompt.contains("<ai00:assistant>\n1. Red"));
+        assert!(prompt.ends_with("</ai00:assistant>"));
+    }
+
     #[test]
     fn test_build_prompt_multi_turn() {
         use super::super::types::{MessageContent, MessageParam, MessageRole};
@@ -326,7 +456,7 @@ mod tests {
             },
         ];
 
-        let prompt = build_prompt(None, &messages, None, None, &prompts);
+        let prompt = build_prompt(None, &messages, None, None, &prompts).unwrap();
 
         // Verify turn order is preserved
         let user1_pos = prompt.find("<ai00:user>\nHello").unwrap();
@@ -384,7 +514,7 @@ mod tests {
             },
         ];
 
-        let prompt = build_prompt(None, &messages, None, None, &prompts);
+        let prompt = build_prompt(None, &messages, None, None, &prompts).unwrap();
 
         // Tool result should be injected WITHOUT <ai00:user> wrapper
         assert!(!prompt.contains("<ai00:user>\n<ai00:function_results>"));
@@ -458,7 +588,7 @@ mod tests {
         ];
 
         // Use build_training_prompt to avoid the inference prefix at the end
-        let prompt = build_training_prompt(None, &messages, None, None, &prompts);
+        let prompt = build_training_prompt(None, &messages, None, None, &prompts).unwrap();
 
         // Should NOT have consecutive </ai00:assistant>\n\n<ai00:assistant>
         assert!(
@@ -499,7 +629,7 @@ mod tests {
         ];
 
         // Use build_training_prompt to avoid the inference prefix at the end
-        let prompt = build_training_prompt(None, &messages, None, None, &prompts);
+        let prompt = build_training_prompt(None, &messages, None, None, &prompts).unwrap();
 
         // Should NOT have consecutive </ai00:user>\n\n<ai00:user>
         assert!(
@@ -508,4 +638,93 @@ mod tests {
             prompt
         );
     }
+
+    /// An agent transcript: a finished turn with thinking and a tool call, then a new question
+    /// whose turn is still in a tool loop.
+    fn agent_transcript() -> Vec<MessageParam> {
+        use super::super::types::{ContentBlock, MessageContent, ToolResultContent};
+
+        let thinking = |text: &str| ContentBlock::Thinking {
+            thinking: text.to_string(),
+            signature: "sig".to_string(),
+        };
+        let tool_use = |id: &str| ContentBlock::ToolUse {
+            id: id.to_string(),
+            name: "search".to_string(),
+            input: serde_json::json!({"query": "rwkv"}),
+        };
+        let tool_result = |id: &str| {
+            MessageContent::Blocks(vec![ContentBlock::ToolResult {
+                tool_use_id: id.to_string(),
+                content: ToolResultContent::Text("found".to_string()),
+                is_error: false,
+            }])
+        };
+        let message = |role, content| MessageParam { role, content };
+        vec![
+            message(MessageRole::User, MessageContent::Text("Find RWKV".into())),
+            message(
+                MessageRole::Assistant,
+                MessageContent::Blocks(vec![thinking("first plan"), tool_use("toolu_1")]),
+            ),
+            message(MessageRole::User, tool_result("toolu_1")),
+            message(
+                MessageRole::Assistant,
+                MessageContent::Blocks(vec![
+                    thinking("first summary"),
+                    ContentBlock::Text {
+                        text: "Found it.".into(),
+                    },
+                ]),
+            ),
+            message(MessageRole::User, MessageContent::Text("Again".into())),
+            message(
+                MessageRole::Assistant,
+                MessageContent::Blocks(vec![thinking("second plan"), tool_use("toolu_2")]),
+            ),
+            message(MessageRole::User, tool_result("toolu_2")),
+        ]
+    }
+
+    #[test]
+    fn test_prior_thinking() {
+        let messages = agent_transcript();
+        let mut prompts = PromptsConfig::default();
+
+        let prompt = build_prompt(None, &messages, None, None, &prompts).unwrap();
+        assert!(prompt.contains("<think>first plan</think>"));
+        assert!(prompt.contains("<think>first summary</think>"));
+        assert!(prompt.contains("<think>second plan</think>"));
+
+        prompts.prior_thinking = PriorThinking::Strip;
+        let prompt = build_prompt(None, &messages, None, None, &prompts).unwrap();
+        assert!(!prompt.contains("first plan"));
+        assert!(!prompt.contains("first summary"));
+        assert!(prompt.contains("\n\nFound it.\n</ai00:assistant>"));
+        // the turn in progress keeps its thinking
+        assert!(prompt.contains("<think>second plan</think>"));
+        assert_eq!(prompt.matches("<ai00:function_calls>").count(), 2);
+
+        prompts.prior_thinking = PriorThinking::Render;
+        prompts.prior_thinking_template = "(thought: {thinking})".into();
+        let prompt = build_prompt(None, &messages, None, None, &prompts).unwrap();
+        assert!(prompt.contains("(thought: first plan)"));
+        assert!(prompt.contains("(thought: first summary)\nFound it."));
+        assert!(prompt.contains("<think>second plan</think>"));
+    }
+
+    #[test]
+    fn test_prior_thinking_training_prompt() {
+        let mut messages = agent_transcript();
+        messages.truncate(4);
+        let prompts = PromptsConfig {
+            prior_thinking: PriorThinking::Strip,
+            ..Default::default()
+        };
+
+        // without a later question, the whole transcript is the current turn
+        let prompt = build_training_prompt(None, &messages, None, None, &prompts).unwrap();
+        assert!(prompt.contains("<think>first plan</think>"));
+        assert!(prompt.contains("<think>first summary</think>"));
+    }
 }
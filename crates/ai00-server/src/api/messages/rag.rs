@@ -0,0 +1,362 @@
+//! Context from a vector store, added to the system prompt of requests with `metadata.rag`.
+//!
+//! The last user message is embedded with the embed model, and the closest chunks of the
+//! collection of `[rag]` are searched in Qdrant. They are numbered and added to the system
+//! prompt through the template of `[rag]`, and returned as citations in the `metadata` of the
+//! response (of `message_start` when streaming). The collection must be indexed with the same
+//! embed model.
+//!
+//! `metadata.rag` is `true` for the defaults of `[rag]`, or an object that overrides some of
+//! them: `{"top_k": 8, "min_score": 0.5, "collection": "docs"}`. The collection must be one of
+//! `[rag]`, and `top_k` is capped at `max_top_k`.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::Client;
+use salvo::Depot;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::types::{
+    Citation, MessageParam, MessageRole, MessagesRequest, RagMetadata, ResponseMetadata,
+    SystemPrompt,
+};
+use crate::{api::error::ApiErrorResponse, config::RagOption};
+
+/// Overrides of `[rag]` by `metadata.rag`.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RagParams {
+    top_k: Option<usize>,
+    min_score: Option<f32>,
+    collection: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    result: Vec<ScoredPoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScoredPoint {
+    id: Value,
+    score: f32,
+    #[serde(default)]
+    payload: serde_json::Map<String, Value>,
+}
+
+/// Client of the vector store of `[rag]`.
+#[derive(Clone)]
+pub struct Retriever {
+    option: RagOption,
+    client: Client,
+}
+
+impl Retriever {
+    pub fn new(option: &RagOption) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(option.timeout))
+            .build()?;
+        Ok(Self {
+            option: option.clone(),
+            client,
+        })
+    }
+
+    /// Whether requests may search `collection`.
+    fn allows(&self, collection: &str) -> bool {
+        collection == self.option.collection
+            || self
+                .option
+                .collections
+                .iter()
+                .any(|name| name == collection)
+    }
+
+    /// Chunks closest to `vector`, numbered from 1.
+    async fn search(&self, vector: Vec<f32>, params: &RagParams) -> Result<Vec<Citation>> {
+        let Some(url) = &self.option.url else {
+            bail!("no vector store is configured");
+        };
+        let collection = params
+            .collection
+            .as_deref()
+            .unwrap_or(&self.option.collection);
+        if collection.is_empty() {
+            bail!("no collection is configured");
+        }
+        let mut url = reqwest::Url::parse(url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("the vector store url cannot be a base"))?
+            .pop_if_empty()
+            .extend(["collections", collection, "points", "search"]);
+        let top_k = params.top_k.unwrap_or(self.option.top_k);
+        let body = json!({
+            "vector": vector,
+            "limit": top_k.min(self.option.max_top_k),
+            "with_payload": true,
+            "score_threshold": params.min_score.or(self.option.min_score),
+        });
+
+        let mut request = self.client.post(url).json(&body);
+        if let Some(api_key) = &self.option.api_key {
+            request = request.header("api-key", api_key);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            bail!("vector store answered {}", response.status());
+        }
+        let SearchResponse { result } = serde_json::from_slice(&response.bytes().await?)?;
+        Ok(self.citations(result))
+    }
+
+    /// Citations of the points that have a text, numbered from 1.
+    fn citations(&self, points: Vec<ScoredPoint>) -> Vec<Citation> {
+        let field = |payload: &serde_json::Map<String, Value>, name: &str| {
+            payload.get(name).and_then(Value::as_str).map(str::to_owned)
+        };
+        points
+            .into_iter()
+            .filter_map(|point| {
+                let text = field(&point.payload, &self.option.text_field)?;
+                let source = field(&point.payload, &self.option.source_field);
+                Some((point, text, source))
+            })
+            .enumerate()
+            .map(|(index, (point, text, source))| Citation {
+                index: index + 1,
+                id: point.id,
+                score: point.score,
+                source,
+                text,
+            })
+            .collect()
+    }
+}
+
+/// The parameters of `metadata.rag`, or `None` if retrieval is not asked for.
+fn rag_params(request: &MessagesRequest) -> Result<Option<RagParams>, ApiErrorResponse> {
+    let Some(value) = request
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get("rag"))
+    else {
+        return Ok(None);
+    };
+    match value {
+        Value::Null | Value::Bool(false) => Ok(None),
+        Value::Bool(true) => Ok(Some(RagParams::default())),
+        value => match serde_json::from_value(value.clone()) {
+            Ok(params) => Ok(Some(params)),
+            Err(err) => Err(ApiErrorResponse::invalid_request(format!(
+                "metadata.rag must be a boolean or an object of top_k, min_score and collection: {err}"
+            ))
+            .with_param("metadata.rag")),
+        },
+    }
+}
+
+/// Text of the last user message, which is the query of the search.
+fn last_user_text(messages: &[MessageParam]) -> Option<String> {
+    messages
+        .iter()
+        .rev()
+        .find(|message| message.role == MessageRole::User)
+        .map(|message| message.content.to_text())
+        .filter(|text| !text.trim().is_empty())
+}
+
+/// The citations as numbered chunks, through `template`.
+fn format_context(template: &str, citations: &[Citation]) -> String {
+    let context = citations
+        .iter()
+        .map(|citation| match &citation.source {
+            Some(source) => format!("[{}] ({source}) {}", citation.index, citation.text.trim()),
+            None => format!("[{}] {}", citation.index, citation.text.trim()),
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    template.replace("{context}", &context)
+}
+
+#[cfg(feature = "embed")]
+async fn embed_query(depot: &Depot, query: String) -> Result<Vec<f32>, ApiErrorResponse> {
+    let embed = depot
+        .obtain::<crate::api::embed::EmbedQueue>()
+        .ok()
+        .filter(|embed| embed.is_available())
+        .ok_or_else(|| {
+            ApiErrorResponse::invalid_request("no embed model is loaded").with_param("metadata.rag")
+        })?;
+
+    embed
+        .embed(vec![query])
+        .await
+        .map_err(|err| ApiErrorResponse::api_error(format!("failed to embed: {err}")))?
+        .pop()
+        .ok_or_else(|| ApiErrorResponse::api_error("failed to embed"))
+}
+
+#[cfg(not(feature = "embed"))]
+async fn embed_query(_depot: &Depot, _query: String) -> Result<Vec<f32>, ApiErrorResponse> {
+    Err(
+        ApiErrorResponse::invalid_request("the server is built without the `embed` feature")
+            .with_param("metadata.rag"),
+    )
+}
+
+/// Add the context retrieved for a request with `metadata.rag` to its system prompt.
+///
+/// Returns the citations to put in the metadata of the response.
+pub async fn retrieve_context(
+    depot: &Depot,
+    request: &mut MessagesRequest,
+) -> Result<ResponseMetadata, ApiErrorResponse> {
+    let Some(params) = rag_params(request)? else {
+        return Ok(ResponseMetadata::default());
+    };
+    let Some(retriever) = depot
+        .obtain::<Retriever>()
+        .ok()
+        .filter(|retriever| retriever.option.url.is_some())
+    else {
+        return Err(
+            ApiErrorResponse::invalid_request("no vector store is configured")
+                .with_param("metadata.rag"),
+        );
+    };
+    if let Some(collection) = params
+        .collection
+        .as_deref()
+        .filter(|collection| !retriever.allows(collection))
+    {
+        return Err(ApiErrorResponse::invalid_request(format!(
+            "collection `{collection}` is not configured in [rag]"
+        ))
+        .with_param("metadata.rag.collection"));
+    }
+    let Some(query) = last_user_text(&request.messages) else {
+        return Err(
+            ApiErrorResponse::invalid_request("retrieval needs a user message with text")
+                .with_param("messages"),
+        );
+    };
+
+    let vector = embed_query(depot, format!("{}{query}", retriever.option.query_prefix)).await?;
+    let citations = retriever
+        .search(vector, &params)
+        .await
+        .map_err(|err| ApiErrorResponse::api_error(format!("failed to retrieve context: {err}")))?;
+    tracing::debug!(event = "context_retrieved", chunks = citations.len());
+
+    if !citations.is_empty() {
+        let context = format_context(&retriever.option.template, &citations);
+        let system = request.system.get_or_insert_with(SystemPrompt::default);
+        system.text = match system.text.is_empty() {
+            true => context,
+            false => format!("{}\n\n{context}", system.text),
+        };
+    }
+    Ok(ResponseMetadata {
+        rag: Some(RagMetadata { citations }),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(metadata: Value) -> MessagesRequest {
+        serde_json::from_value(json!({
+            "model": "rwkv",
+            "max_tokens": 16,
+            "messages": [
+                {"role": "user", "content": "What is RWKV?"},
+                {"role": "assistant", "content": "A model."},
+                {"role": "user", "content": "Who made it?"}
+            ],
+            "metadata": metadata
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rag_params() {
+        assert_eq!(rag_params(&request(json!({}))).unwrap(), None);
+        assert_eq!(rag_params(&request(json!({"rag": false}))).unwrap(), None);
+        assert_eq!(
+            rag_params(&request(json!({"rag": true}))).unwrap(),
+            Some(RagParams::default())
+        );
+        let params = rag_params(&request(json!({"rag": {"top_k": 2, "collection": "docs"}})))
+            .unwrap()
+            .unwrap();
+        assert_eq!(params.top_k, Some(2));
+        assert_eq!(params.collection.as_deref(), Some("docs"));
+
+        let err = rag_params(&request(json!({"rag": {"k": 2}}))).unwrap_err();
+        assert_eq!(err.error.param.as_deref(), Some("metadata.rag"));
+    }
+
+    #[test]
+    fn test_last_user_text() {
+        let request = request(json!({}));
+        assert_eq!(
+            last_user_text(&request.messages).as_deref(),
+            Some("Who made it?")
+        );
+    }
+
+    #[test]
+    fn test_allowed_collections() {
+        let retriever = Retriever::new(&RagOption {
+            collection: "docs".into(),
+            collections: vec!["faq".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(retriever.allows("docs"));
+        assert!(retriever.allows("faq"));
+        assert!(!retriever.allows("x/points/scroll?"));
+        assert!(!retriever.allows(""));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_other_collections() {
+        let mut depot = Depot::new();
+        depot.inject(
+            Retriever::new(&RagOption {
+                url: Some("http://localhost:6333".into()),
+                collection: "docs".into(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let mut request = request(json!({"rag": {"collection": "secrets"}}));
+        let err = retrieve_context(&depot, &mut request).await.unwrap_err();
+        assert_eq!(err.error.param.as_deref(), Some("metadata.rag.collection"));
+    }
+
+    #[test]
+    fn test_citations() {
+        let retriever = Retriever::new(&RagOption::default()).unwrap();
+        let points: Vec<ScoredPoint> = serde_json::from_value(json!([
+            {"id": 7, "score": 0.9, "payload": {"text": "RWKV is an RNN.", "source": "wiki"}},
+            {"id": "a", "score": 0.8, "payload": {}},
+            {"id": 9, "score": 0.7, "payload": {"text": "It was made by BlinkDL."}}
+        ]))
+        .unwrap();
+        let citations = retriever.citations(points);
+        assert_eq!(citations.len(), 2);
+        assert_eq!(citations[1].index, 2);
+        assert_eq!(citations[1].id, json!(9));
+
+        let context = format_context("Context:\n{context}", &citations);
+        assert_eq!(
+            context,
+            "Context:\n[1] (wiki) RWKV is an RNN.\n\n[2] It was made by BlinkDL."
+        );
+    }
+}
@@ -0,0 +1,306 @@
+//! Event ids, heartbeats and reconnection of streamed `/v1/messages` responses.
+//!
+//! Every event of a stream gets the id `<stream>:<sequence>`. Events are buffered while the
+//! generation runs and for `stream.resume_window` seconds after it ends; a request with the
+//! `Last-Event-ID` header of a buffered stream receives the events after that id instead of
+//! starting a new generation. The generation goes on without a connected client for up to
+//! `resume_window` seconds, after which it is cancelled.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream, StreamExt};
+use salvo::sse::SseEvent;
+use tokio::sync::Notify;
+
+use super::{
+    handler::EventStream,
+    streaming::{emit_ping, event_data},
+};
+
+/// Header a reconnecting client sends with the id of the last event it received.
+pub const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+/// An event kept for reconnecting clients.
+#[derive(Debug, Clone)]
+struct BufferedEvent {
+    name: Option<String>,
+    data: String,
+    /// Text of an event that is only a comment, e.g. a grammar trace.
+    comment: Option<String>,
+}
+
+impl BufferedEvent {
+    fn new(event: &SseEvent) -> Self {
+        let text = event.to_string();
+        let name = text
+            .lines()
+            .find_map(|line| line.strip_prefix("event:"))
+            .map(|name| name.trim().to_string());
+        let data = event_data(event);
+        let comment = match data {
+            Some(_) => None,
+            None => text
+                .lines()
+                .find_map(|line| line.strip_prefix(':'))
+                .map(|comment| comment.strip_prefix(' ').unwrap_or(comment).to_string()),
+        };
+        let data = data.unwrap_or_default();
+        Self {
+            name,
+            data,
+            comment,
+        }
+    }
+
+    fn to_event(&self, id: String) -> SseEvent {
+        if let Some(comment) = &self.comment {
+            return SseEvent::default().id(id).comment(comment.clone());
+        }
+        let event = SseEvent::default().id(id).text(self.data.clone());
+        match &self.name {
+            Some(name) => event.name(name.clone()),
+            None => event,
+        }
+    }
+}
+
+/// Events of a single stream.
+#[derive(Debug, Default)]
+struct Backlog {
+    events: Mutex<Vec<BufferedEvent>>,
+    /// Whether the generation ended and no more events follow.
+    done: AtomicBool,
+    notify: Notify,
+    /// Number of connected clients, and since when none is.
+    clients: Mutex<(usize, Option<Instant>)>,
+}
+
+impl Backlog {
+    fn push(&self, event: &SseEvent) {
+        self.events.lock().unwrap().push(BufferedEvent::new(event));
+        self.notify.notify_waiters();
+    }
+
+    fn finish(&self) {
+        self.done.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    fn get(&self, index: usize) -> Option<BufferedEvent> {
+        self.events.lock().unwrap().get(index).cloned()
+    }
+
+    /// Whether no client was connected for longer than `window`.
+    fn abandoned(&self, window: Duration) -> bool {
+        match *self.clients.lock().unwrap() {
+            (0, Some(since)) => since.elapsed() >= window,
+            _ => false,
+        }
+    }
+}
+
+/// Counts a connected client of a [`Backlog`] while alive.
+struct Client(Arc<Backlog>);
+
+impl Client {
+    fn new(backlog: Arc<Backlog>) -> Self {
+        backlog.clients.lock().unwrap().0 += 1;
+        Self(backlog)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let mut clients = self.0.clients.lock().unwrap();
+        clients.0 -= 1;
+        if clients.0 == 0 {
+            clients.1 = Some(Instant::now());
+        }
+    }
+}
+
+/// Buffered events of in-flight and recently finished streams.
+#[derive(Debug, Clone, Default)]
+pub struct StreamBacklogs(Arc<Mutex<HashMap<String, Arc<Backlog>>>>);
+
+impl StreamBacklogs {
+    /// Drive `events` in the background, buffering them for `window` after they end, and return
+    /// them with ids.
+    pub fn spawn(&self, events: EventStream, window: Duration) -> EventStream {
+        let key = uuid::Uuid::now_v7().simple().to_string();
+        let backlog = Arc::new(Backlog::default());
+        self.0.lock().unwrap().insert(key.clone(), backlog.clone());
+        let client = Client::new(backlog.clone());
+
+        let backlogs = self.clone();
+        let task_key = key.clone();
+        tokio::spawn(async move {
+            let mut events = events;
+            while let Some(Ok(event)) = events.next().await {
+                backlog.push(&event);
+                if backlog.abandoned(window) {
+                    tracing::info!(event = "stream_abandoned", stream = %task_key);
+                    break;
+                }
+            }
+            // dropping the events cancels a generation that is still running
+            drop(events);
+            backlog.finish();
+            tokio::time::sleep(window).await;
+            backlogs.0.lock().unwrap().remove(&task_key);
+        });
+
+        subscribe(key, client, 0)
+    }
+
+    /// The events after `last_event_id`, if its stream is still buffered.
+    pub fn resume(&self, last_event_id: &str) -> Option<EventStream> {
+        let (key, sequence) = parse_event_id(last_event_id)?;
+        let backlog = self.0.lock().unwrap().get(key).cloned()?;
+        Some(subscribe(
+            key.to_string(),
+            Client::new(backlog),
+            sequence + 1,
+        ))
+    }
+}
+
+/// Split an event id into its stream and sequence number.
+fn parse_event_id(id: &str) -> Option<(&str, usize)> {
+    let (key, sequence) = id.trim().rsplit_once(':')?;
+    Some((key, sequence.parse().ok()?))
+}
+
+/// Stream the events of a backlog from `start`, waiting for new ones until it is done.
+fn subscribe(key: String, client: Client, start: usize) -> EventStream {
+    stream::unfold((client, start), move |(client, index)| {
+        let key = key.clone();
+        async move {
+            let backlog = client.0.clone();
+            loop {
+                // registered before checking, so that no push in between is missed
+                let notified = backlog.notify.notified();
+                if let Some(event) = backlog.get(index) {
+                    let event = event.to_event(format!("{key}:{index}"));
+                    return Some((Ok(event), (client, index + 1)));
+                }
+                if backlog.done.load(Ordering::Acquire) {
+                    return None;
+                }
+                notified.await;
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Interleave `ping` events every `interval`, so that proxies do not close idle streams.
+pub fn with_heartbeat(events: EventStream, interval: Duration) -> EventStream {
+    if interval.is_zero() {
+        return events;
+    }
+    let events = events.map(Some).chain(stream::once(async { None }));
+    let start = tokio::time::Instant::now() + interval;
+    let pings = stream::unfold(
+        tokio::time::interval_at(start, interval),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((Some(Ok(emit_ping())), interval))
+        },
+    );
+    stream::select(events, pings)
+        .take_while(|event| std::future::ready(event.is_some()))
+        .filter_map(std::future::ready)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::messages::streaming::emit_content_block_stop;
+
+    fn events(count: usize) -> EventStream {
+        stream::iter((0..count).map(|index| Ok(emit_content_block_stop(index)))).boxed()
+    }
+
+    fn ids(events: &[SseEvent]) -> Vec<String> {
+        events
+            .iter()
+            .filter_map(|event| {
+                event
+                    .to_string()
+                    .lines()
+                    .find_map(|line| line.strip_prefix("id:"))
+                    .map(|id| id.trim().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_event_id() {
+        assert_eq!(parse_event_id("abc:12"), Some(("abc", 12)));
+        assert_eq!(parse_event_id("abc"), None);
+        assert_eq!(parse_event_id("abc:x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_ids_and_resume() {
+        let backlogs = StreamBacklogs::default();
+        let first: Vec<_> = backlogs
+            .spawn(events(4), Duration::from_secs(30))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let first_ids = ids(&first);
+        assert_eq!(first_ids.len(), 4);
+        let key = first_ids[0].strip_suffix(":0").unwrap();
+        assert_eq!(first_ids[3], format!("{key}:3"));
+
+        let resumed: Vec<_> = backlogs
+            .resume(&first_ids[1])
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(ids(&resumed), first_ids[2..]);
+        assert_eq!(event_data(&resumed[0]), event_data(&first[2]));
+        assert!(backlogs.resume("unknown:0").is_none());
+    }
+
+    #[test]
+    fn test_buffer_comment() {
+        let event = SseEvent::default().comment("grammar_trace {\"token\":11}");
+        let buffered = BufferedEvent::new(&event);
+        assert_eq!(
+            buffered.comment.as_deref(),
+            Some("grammar_trace {\"token\":11}")
+        );
+        let text = buffered.to_event("abc:0".into()).to_string();
+        let comment = text.lines().find(|line| line.starts_with(':')).unwrap();
+        assert!(comment.ends_with("grammar_trace {\"token\":11}"));
+        assert!(!text.contains("data:"));
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let slow = stream::once(async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(emit_content_block_stop(0))
+        })
+        .boxed();
+        let names: Vec<_> = with_heartbeat(slow, Duration::from_millis(40))
+            .map(|event| BufferedEvent::new(&event.unwrap()).name.unwrap())
+            .collect()
+            .await;
+        assert!(names.len() > 2);
+        assert!(names[..names.len() - 1].iter().all(|name| name == "ping"));
+        assert_eq!(names.last().unwrap(), "content_block_stop");
+    }
+}
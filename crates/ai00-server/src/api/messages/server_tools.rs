@@ -0,0 +1,557 @@
+//! Tools the server runs itself for requests in agentic mode.
+//!
+//! The built-in tools of `tools.builtin` keep their names; the tools of each server in
+//! `tools.mcp` are named `<server>__<tool>`. Agentic requests are offered all of them next to
+//! their own tools. The server answers the calls of its tools and generates again, until the
+//! model answers, calls a tool of the client, or `tools.max_rounds` is reached.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+    Client, Url,
+};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use super::{
+    mcp::{McpClient, McpOutput},
+    types::{validate_tool_name, ContentBlock, Tool, ToolResultContent},
+};
+use crate::config::{BuiltinTool, ToolsOption};
+
+/// Separates the server from the tool in the names of MCP tools.
+pub const MCP_SEPARATOR: &str = "__";
+/// Most bytes of a fetched page passed to the model; the rest is not downloaded.
+const MAX_FETCH_BYTES: usize = 64 * 1024;
+/// Redirects followed by `http_fetch`.
+const MAX_FETCH_REDIRECTS: usize = 10;
+/// Deepest nesting of parentheses, signs and powers in a calculator expression.
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+enum Target {
+    Builtin(BuiltinTool),
+    Mcp { server: usize, name: String },
+}
+
+impl BuiltinTool {
+    fn tool(self) -> Tool {
+        let (name, description, input_schema) = match self {
+            BuiltinTool::Calculator => (
+                "calculator",
+                "Evaluate an arithmetic expression with + - * / % ^ and parentheses.",
+                json!({
+                    "type": "object",
+                    "properties": { "expression": { "type": "string" } },
+                    "required": ["expression"]
+                }),
+            ),
+            BuiltinTool::HttpFetch => (
+                "http_fetch",
+                "Fetch a web page or API by URL with GET and return its body.",
+                json!({
+                    "type": "object",
+                    "properties": { "url": { "type": "string" } },
+                    "required": ["url"]
+                }),
+            ),
+        };
+        Tool {
+            name: name.into(),
+            description: Some(description.into()),
+            input_schema,
+            cache_control: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerTools {
+    option: ToolsOption,
+    /// Client of `http_fetch`, which only reaches public addresses.
+    fetcher: Client,
+    mcp: Arc<Vec<McpClient>>,
+    /// Tools listed so far, with what runs them.
+    tools: Arc<Mutex<Option<Vec<(Tool, Target)>>>>,
+}
+
+impl ServerTools {
+    pub fn new(option: &ToolsOption) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(option.timeout))
+            .build()?;
+        let mcp = option
+            .mcp
+            .iter()
+            .map(|server| McpClient::new(server.clone(), client.clone()))
+            .collect();
+        let allow = option.fetch_allow.clone();
+        let fetcher = Client::builder()
+            .timeout(Duration::from_secs(option.timeout))
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_FETCH_REDIRECTS {
+                    return attempt.error("too many redirects");
+                }
+                match check_fetch_url(attempt.url(), &allow) {
+                    Ok(_) => attempt.follow(),
+                    Err(err) => attempt.error(err),
+                }
+            }))
+            .build()?;
+        Ok(Self {
+            option: option.clone(),
+            fetcher,
+            mcp: Arc::new(mcp),
+            tools: Default::default(),
+        })
+    }
+
+    /// Whether any tool is configured.
+    pub fn is_enabled(&self) -> bool {
+        !self.option.builtin.is_empty() || !self.option.mcp.is_empty()
+    }
+
+    pub fn max_rounds(&self) -> usize {
+        self.option.max_rounds
+    }
+
+    async fn list(&self) -> Result<Vec<(Tool, Target)>> {
+        let mut tools: Vec<_> = self
+            .option
+            .builtin
+            .iter()
+            .map(|&builtin| (builtin.tool(), Target::Builtin(builtin)))
+            .collect();
+        for (index, client) in self.mcp.iter().enumerate() {
+            for tool in client.list_tools().await? {
+                let name = format!("{}{MCP_SEPARATOR}{}", client.server.name, tool.name);
+                if !validate_tool_name(&name) {
+                    tracing::warn!(event = "mcp_tool_skipped", tool = %name);
+                    continue;
+                }
+                let target = Target::Mcp {
+                    server: index,
+                    name: tool.name,
+                };
+                let tool = Tool {
+                    name,
+                    description: tool.description,
+                    input_schema: tool.input_schema,
+                    cache_control: None,
+                };
+                tools.push((tool, target));
+            }
+        }
+        Ok(tools)
+    }
+
+    /// All tools of the server, listed from the MCP servers on first use.
+    pub async fn tools(&self) -> Result<Vec<Tool>> {
+        let mut tools = self.tools.lock().await;
+        if tools.is_none() {
+            *tools = Some(self.list().await?);
+        }
+        let tools = tools.iter().flatten().map(|(tool, _)| tool.clone());
+        Ok(tools.collect())
+    }
+
+    async fn target(&self, name: &str) -> Option<Target> {
+        let tools = self.tools.lock().await;
+        tools
+            .iter()
+            .flatten()
+            .find(|(tool, _)| tool.name == name)
+            .map(|(_, target)| target.clone())
+    }
+
+    /// Whether `name` is a tool of the server.
+    pub async fn contains(&self, name: &str) -> bool {
+        self.target(name).await.is_some()
+    }
+
+    /// Run the tool call `id`, returning its result; `None` if `name` is not a tool of the server.
+    pub async fn run(&self, id: &str, name: &str, input: &Value) -> Option<ContentBlock> {
+        let target = self.target(name).await?;
+        let run = async {
+            match target {
+                Target::Builtin(BuiltinTool::Calculator) => calculate(input),
+                Target::Builtin(BuiltinTool::HttpFetch) => self.fetch(input).await,
+                Target::Mcp { server, name } => self.mcp[server].call_tool(&name, input).await,
+            }
+        };
+        let timeout = Duration::from_secs(self.option.timeout);
+        let output = match tokio::time::timeout(timeout, run).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(err)) => McpOutput {
+                text: format!("Error: {err}"),
+                is_error: true,
+            },
+            Err(_) => McpOutput {
+                text: format!("Error: the tool did not answer within {timeout:?}"),
+                is_error: true,
+            },
+        };
+        tracing::debug!(
+            event = "server_tool_run",
+            tool = name,
+            is_error = output.is_error
+        );
+        Some(ContentBlock::ToolResult {
+            tool_use_id: id.into(),
+            content: ToolResultContent::Text(output.text),
+            is_error: output.is_error,
+        })
+    }
+
+    async fn fetch(&self, input: &Value) -> Result<McpOutput> {
+        let Some(url) = input["url"].as_str() else {
+            bail!("url is required");
+        };
+        let url = Url::parse(url)?;
+        check_fetch_url(&url, &self.option.fetch_allow)?;
+        let mut response = self.fetcher.get(url).send().await?;
+        let status = response.status();
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            let len = chunk.len().min(MAX_FETCH_BYTES - body.len());
+            body.extend_from_slice(&chunk[..len]);
+            if body.len() == MAX_FETCH_BYTES {
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(&body).into_owned();
+        Ok(McpOutput {
+            text: match status.is_success() {
+                true => text,
+                false => format!("HTTP {status}\n\n{text}"),
+            },
+            is_error: !status.is_success(),
+        })
+    }
+}
+
+/// Whether `ip` can be reached from the internet, rather than being private, loopback,
+/// link-local or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // `0.0.0.0/8` and the shared address space `100.64.0.0/10`
+            let reserved = a == 0 || (a == 100 && b & 0xc0 == 64);
+            !(reserved
+                || ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let segment = ip.segments()[0];
+                // unique local `fc00::/7` and link-local `fe80::/10`
+                let local = segment & 0xfe00 == 0xfc00 || segment & 0xffc0 == 0xfe80;
+                !(local || ip.is_loopback() || ip.is_unspecified() || ip.is_multicast())
+            }
+        },
+    }
+}
+
+/// Fail unless `url` may be fetched: an `http` or `https` URL of a host in `allow` or one of
+/// its subdomains, or of any host if `allow` is empty, that is not a private address.
+///
+/// Host names are checked for private addresses when they are resolved, by [`PublicResolver`].
+fn check_fetch_url(url: &Url, allow: &[String]) -> Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("only http and https URLs can be fetched");
+    }
+    let Some(host) = url.host_str() else {
+        bail!("the URL has no host");
+    };
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    if ip.parse().is_ok_and(|ip| !is_public(ip)) {
+        bail!("{host} is not a public address");
+    }
+    let allowed = allow.is_empty()
+        || allow.iter().any(|allowed| {
+            let allowed = allowed.trim_start_matches('.').to_ascii_lowercase();
+            host == allowed || host.ends_with(&format!(".{allowed}"))
+        });
+    if !allowed {
+        bail!("{host} is not in tools.fetch_allow");
+    }
+    Ok(())
+}
+
+/// Resolves the hosts of `http_fetch`, refusing those with any private address, so that neither
+/// a URL nor a redirect reaches the network of the server.
+struct PublicResolver;
+
+impl PublicResolver {
+    async fn lookup(name: Name) -> Result<Addrs, Box<dyn std::error::Error + Send + Sync>> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+        if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+            let host = name.as_str();
+            let message = format!("{host} resolves to {}, which is not public", addr.ip());
+            return Err(message.into());
+        }
+        Ok(Box::new(addrs.into_iter()))
+    }
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(Self::lookup(name))
+    }
+}
+
+fn calculate(input: &Value) -> Result<McpOutput> {
+    let Some(expression) = input["expression"].as_str() else {
+        bail!("expression is required");
+    };
+    let value = evaluate(expression)?;
+    let text = match value.fract() == 0.0 && value.abs() < 1e15 {
+        true => (value as i64).to_string(),
+        false => format!("{value}"),
+    };
+    Ok(McpOutput {
+        text,
+        is_error: false,
+    })
+}
+
+/// Evaluate an arithmetic expression by recursive descent.
+fn evaluate(expression: &str) -> Result<f64> {
+    struct Parser<'a> {
+        chars: std::iter::Peekable<std::str::Chars<'a>>,
+        depth: usize,
+    }
+
+    impl Parser<'_> {
+        /// Parse a nested part with `f`, failing if the nesting is too deep for the stack.
+        fn nested(&mut self, f: impl FnOnce(&mut Self) -> Result<f64>) -> Result<f64> {
+            if self.depth == MAX_EXPRESSION_DEPTH {
+                bail!("the expression is nested too deeply");
+            }
+            self.depth += 1;
+            let value = f(self);
+            self.depth -= 1;
+            value
+        }
+
+        fn peek(&mut self) -> Option<char> {
+            while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+            self.chars.peek().copied()
+        }
+
+        fn eat(&mut self, c: char) -> bool {
+            self.peek() == Some(c) && self.chars.next().is_some()
+        }
+
+        /// `sum ::= product (('+' | '-') product)*`
+        fn sum(&mut self) -> Result<f64> {
+            let mut value = self.product()?;
+            loop {
+                match self.peek() {
+                    Some('+') if self.eat('+') => value += self.product()?,
+                    Some('-') if self.eat('-') => value -= self.product()?,
+                    _ => break Ok(value),
+                }
+            }
+        }
+
+        /// `product ::= power (('*' | '/' | '%') power)*`
+        fn product(&mut self) -> Result<f64> {
+            let mut value = self.power()?;
+            loop {
+                match self.peek() {
+                    Some('*') if self.eat('*') => value *= self.power()?,
+                    Some('/') if self.eat('/') => value /= self.power()?,
+                    Some('%') if self.eat('%') => value %= self.power()?,
+                    _ => break Ok(value),
+                }
+            }
+        }
+
+        /// `power ::= unary ('^' power)?`
+        fn power(&mut self) -> Result<f64> {
+            let base = self.unary()?;
+            match self.eat('^') {
+                true => Ok(base.powf(self.nested(Self::power)?)),
+                false => Ok(base),
+            }
+        }
+
+        /// `unary ::= ('-' | '+') unary | '(' sum ')' | number`
+        fn unary(&mut self) -> Result<f64> {
+            if self.eat('-') {
+                return Ok(-self.nested(Self::unary)?);
+            }
+            if self.eat('+') {
+                return self.nested(Self::unary);
+            }
+            if self.eat('(') {
+                let value = self.nested(Self::sum)?;
+                if !self.eat(')') {
+                    bail!("missing `)`");
+                }
+                return Ok(value);
+            }
+            let mut number = String::new();
+            while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+                number.push(c);
+            }
+            match number.parse() {
+                Ok(value) => Ok(value),
+                Err(_) => match self.peek() {
+                    Some(c) => bail!("unexpected `{c}`"),
+                    None => bail!("unexpected end of expression"),
+                },
+            }
+        }
+    }
+
+    let mut parser = Parser {
+        chars: expression.chars().peekable(),
+        depth: 0,
+    };
+    let value = parser.sum()?;
+    match parser.peek() {
+        Some(c) => bail!("unexpected `{c}`"),
+        None if value.is_finite() => Ok(value),
+        None => bail!("the result is not a finite number"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(evaluate("-4 / 8 + 10 % 4").unwrap(), 1.5);
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("(1 + 2").is_err());
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("2 x 3").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_depth() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(evaluate(&nested(MAX_EXPRESSION_DEPTH)).unwrap(), 1.0);
+        assert!(evaluate(&nested(MAX_EXPRESSION_DEPTH + 1)).is_err());
+        // deep enough to overflow the stack without the limit
+        assert!(evaluate(&nested(100_000)).is_err());
+        assert!(evaluate(&format!("{}1", "-".repeat(100_000))).is_err());
+        assert!(evaluate(&format!("{}1", "1^".repeat(100_000))).is_err());
+    }
+
+    #[test]
+    fn test_public_addresses() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:2800:220:1:248:1893:25c8:1946"));
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(ip), "{ip} is not public");
+        }
+    }
+
+    #[test]
+    fn test_check_fetch_url() {
+        let check = |url: &str, allow: &[&str]| {
+            let allow: Vec<_> = allow.iter().map(|host| host.to_string()).collect();
+            check_fetch_url(&Url::parse(url).unwrap(), &allow)
+        };
+        assert!(check("https://example.com/page", &[]).is_ok());
+        assert!(check("ftp://example.com/file", &[]).is_err());
+        assert!(check("http://127.0.0.1:8080/admin", &[]).is_err());
+        assert!(check("http://[::1]/", &[]).is_err());
+        assert!(check("http://169.254.169.254/latest/meta-data", &[]).is_err());
+
+        let allow = ["example.com"];
+        assert!(check("https://example.com/", &allow).is_ok());
+        assert!(check("https://api.example.com/", &allow).is_ok());
+        assert!(check("https://badexample.com/", &allow).is_err());
+        assert!(check("https://example.org/", &allow).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_refuses_private_hosts() {
+        let option = ToolsOption {
+            builtin: vec![BuiltinTool::HttpFetch],
+            ..Default::default()
+        };
+        let tools = ServerTools::new(&option).unwrap();
+        for url in ["http://127.0.0.1/", "http://localhost/"] {
+            assert!(tools.fetch(&json!({ "url": url })).await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_calculator_output() {
+        let output = calculate(&json!({ "expression": "6 * 7" })).unwrap();
+        assert_eq!(output.text, "42");
+        let output = calculate(&json!({ "expression": "1 / 4" })).unwrap();
+        assert_eq!(output.text, "0.25");
+        assert!(calculate(&json!({})).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_builtin_tools() {
+        let option = ToolsOption {
+            builtin: vec![BuiltinTool::Calculator],
+            ..Default::default()
+        };
+        let tools = ServerTools::new(&option).unwrap();
+        let names: Vec<_> = tools
+            .tools()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(names, ["calculator"]);
+        assert!(tools.contains("calculator").await);
+        assert!(tools
+            .run("toolu_1", "get_weather", &json!({}))
+            .await
+            .is_none());
+
+        let result = tools
+            .run("toolu_1", "calculator", &json!({ "expression": "2 + 2" }))
+            .await;
+        let Some(ContentBlock::ToolResult {
+            content, is_error, ..
+        }) = result
+        else {
+            panic!("expected a tool result");
+        };
+        assert_eq!(content.to_text(), "4");
+        assert!(!is_error);
+    }
+}
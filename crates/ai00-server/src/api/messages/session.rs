@@ -0,0 +1,432 @@
+//! Turn-level "continue" support for the Messages API.
+//!
+//! Every `/v1/messages` generation is remembered as a session keyed by its request ID
+//! (the `x-span-id` response header). `POST /api/sessions/{id}/continue` resumes the
+//! last assistant turn of that session: the prompt plus everything generated so far is
+//! sent again, which hits the runtime's prompt cache instead of re-prefilling.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use ai00_core::{
+    reload::TrafficClass,
+    sampler::dynatemp::{DynaTempParams, DynaTempSampler},
+    GenerateRequest, InputState, ThreadRequest, Token, TokenCounter, MAX_TOKENS,
+};
+use futures_util::StreamExt;
+use salvo::{
+    oapi::extract::{JsonBody, PathParam},
+    prelude::*,
+    sse::SseEvent,
+};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use super::handler::report_stop_sequences;
+use super::moderation::{moderate_output, Moderator};
+use super::streaming::*;
+use super::types::{ContentBlock, MessagesResponse, StopReason};
+use crate::{
+    api::{current_request_id, error::ApiErrorResponse, request_info_of, usage::Attribution},
+    config::Config,
+    types::ThreadSender,
+    SLEEP,
+};
+
+/// Maximum number of sessions remembered; the least recently used are dropped first.
+const MAX_SESSIONS: usize = 256;
+
+/// A finished or running assistant turn.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Model the turn was generated with.
+    pub model: Option<String>,
+    /// Prompt of the turn, ending right where the assistant output begins.
+    pub prompt: String,
+    /// Assistant text of earlier turns.
+    pub model_text: String,
+    /// Everything generated for this turn so far.
+    pub output: String,
+    pub stop: Vec<String>,
+    pub stop_tokens: Vec<u32>,
+    pub stop_bytes: Vec<Vec<u8>>,
+    /// Stop sequences the client asked for, which are reported when they fire.
+    pub stop_sequences: Vec<String>,
+    pub bias: Arc<HashMap<u32, f32>>,
+    pub banned_phrases: Vec<String>,
+    pub sampler: DynaTempParams,
+    pub max_tokens: usize,
+    /// State the turn started from.
+    pub state: Arc<InputState>,
+    /// Whether a generation for this session is still running.
+    pub busy: bool,
+    updated: Instant,
+}
+
+impl Session {
+    pub fn new(request: &GenerateRequest, sampler: DynaTempParams) -> Self {
+        Self {
+            model: request.model.clone(),
+            prompt: request.prompt.clone(),
+            model_text: request.model_text.clone(),
+            output: String::new(),
+            stop: request.stop.clone(),
+            stop_tokens: request.stop_tokens.clone(),
+            stop_bytes: request.stop_bytes.clone(),
+            stop_sequences: vec![],
+            bias: request.bias.clone(),
+            banned_phrases: request.banned_phrases.clone(),
+            sampler,
+            max_tokens: request.max_tokens,
+            state: request.state.clone(),
+            busy: true,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences;
+        self
+    }
+}
+
+/// Sessions shared between requests.
+#[derive(Debug, Default, Clone)]
+pub struct SessionStore(Arc<Mutex<HashMap<String, Session>>>);
+
+impl SessionStore {
+    pub fn get(&self, id: &str) -> Option<Session> {
+        self.0.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn insert(&self, id: String, session: Session) {
+        let mut sessions = self.0.lock().unwrap();
+        if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(&id) {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, session)| session.updated)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(id, session);
+    }
+
+    /// Mark the session busy for a continuation and return it, unless it is still generating.
+    ///
+    /// The check and the mark are made under one lock, so two continuations of a session cannot
+    /// both start.
+    pub fn claim(&self, id: &str) -> Result<Session, ApiErrorResponse> {
+        let mut sessions = self.0.lock().unwrap();
+        match sessions.get_mut(id) {
+            Some(session) if session.busy => Err(ApiErrorResponse::invalid_request(
+                "session is still generating",
+            )),
+            Some(session) => {
+                session.busy = true;
+                session.updated = Instant::now();
+                Ok(session.clone())
+            }
+            None => Err(ApiErrorResponse::not_found(format!(
+                "session {id} not found"
+            ))),
+        }
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Session)) {
+        if let Some(session) = self.0.lock().unwrap().get_mut(id) {
+            f(session);
+            session.updated = Instant::now();
+        }
+    }
+
+    /// Forward tokens from `receiver`, appending generated text to the session.
+    pub fn track(&self, id: String, receiver: flume::Receiver<Token>) -> flume::Receiver<Token> {
+        let (sender, tracked) = flume::unbounded();
+        let store = self.clone();
+        tokio::spawn(async move {
+            while let Ok(token) = receiver.recv_async().await {
+                if let Token::Content(text) = &token {
+                    store.update(&id, |session| session.output.push_str(text));
+                }
+                if sender.send(token).is_err() {
+                    break;
+                }
+            }
+            store.update(&id, |session| session.busy = false);
+        });
+        tracked
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct ContinueRequest {
+    /// Output token limit of the continuation. Defaults to the limit of the original turn.
+    pub max_tokens: Option<usize>,
+    pub stream: bool,
+}
+
+impl Session {
+    fn to_generate_request(&self, max_tokens: Option<usize>) -> GenerateRequest {
+        let prompt = format!("{}{}", self.prompt, self.output);
+        let model_text = match self.model_text.is_empty() {
+            true => self.output.clone(),
+            false => format!("{}\n\n{}", self.model_text, self.output),
+        };
+        let max_tokens = max_tokens.unwrap_or(self.max_tokens).min(MAX_TOKENS);
+        let sampler = Arc::new(RwLock::new(DynaTempSampler::new(self.sampler.clone())));
+        GenerateRequest {
+            prompt,
+            model_text,
+            max_tokens,
+            stop: self.stop.clone(),
+            stop_tokens: self.stop_tokens.clone(),
+            stop_bytes: self.stop_bytes.clone(),
+            bias: self.bias.clone(),
+            banned_phrases: self.banned_phrases.clone(),
+            sampler,
+            model: self.model.clone(),
+            state: self.state.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Continue the last assistant turn of a session.
+///
+/// The session ID is the `x-span-id` of the `/v1/messages` request that started the turn.
+/// The response contains only the newly generated text.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 200, description = "Continuation of the turn", body = MessagesResponse),
+        (status_code = 400, description = "Session is still generating", body = ApiErrorResponse),
+        (status_code = 404, description = "Unknown session", body = ApiErrorResponse),
+    )
+)]
+pub async fn continue_session(
+    depot: &mut Depot,
+    id: PathParam<String>,
+    req: JsonBody<ContinueRequest>,
+    res: &mut Response,
+) {
+    let id = id.into_inner();
+    let ContinueRequest { max_tokens, stream } = req.0;
+
+    let Ok(store) = depot.obtain::<SessionStore>() else {
+        let err = ApiErrorResponse::api_error("sessions are not available");
+        err.respond(res);
+        return;
+    };
+    let session = match store.claim(&id) {
+        Ok(session) => session,
+        Err(err) => {
+            err.respond(res);
+            return;
+        }
+    };
+
+    let max_event_size = depot
+        .obtain::<Arc<Config>>()
+        .ok()
+        .and_then(|config| config.stream.max_event_size);
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let model = session.model.clone().unwrap_or_default();
+    let info = match request_info_of(sender.clone(), &model, SLEEP).await {
+        Ok(info) => info,
+        Err(err) => {
+            store.update(&id, |session| session.busy = false);
+            err.respond(res);
+            return;
+        }
+    };
+    let model_name = info.reload.model_path.to_string_lossy().into_owned();
+
+    let mut request = session.to_generate_request(max_tokens);
+    request.request_id = current_request_id(depot);
+    request.traffic_class = TrafficClass::from_stream(stream);
+    Attribution::of(depot).apply(&mut request);
+    tracing::info!(
+        event = "session_continue",
+        session_id = %id,
+        output_len = session.output.len(),
+        max_tokens = request.max_tokens,
+        "Continuing session"
+    );
+
+    let (token_sender, token_receiver) = flume::unbounded();
+    let moderator = depot.obtain::<Moderator>().ok();
+    let token_receiver = moderate_output(moderator, &request, token_receiver);
+    let _ = sender.send(ThreadRequest::Generate {
+        request: Box::new(request),
+        tokenizer: info.tokenizer,
+        sender: token_sender,
+    });
+    let token_receiver = report_stop_sequences(session.stop_sequences.clone(), token_receiver);
+    let token_receiver = store.track(id, token_receiver);
+
+    match stream {
+        true => respond_stream(res, token_receiver, model_name, max_event_size),
+        false => respond_one(res, token_receiver, model_name).await,
+    }
+}
+
+async fn respond_one(res: &mut Response, token_receiver: flume::Receiver<Token>, model: String) {
+    let mut counter = TokenCounter::default();
+    let mut finish_reason = ai00_core::FinishReason::Null;
+    let mut text = String::new();
+    let mut stream = token_receiver.into_stream();
+
+    while let Some(token) = stream.next().await {
+        match token {
+            Token::Content(token) => text += &token,
+            Token::Stop(reason, token_counter) => {
+                finish_reason = reason;
+                counter = token_counter;
+            }
+            Token::Error(error) => return ApiErrorResponse::from(error).respond(res),
+            Token::Done => break,
+            _ => {}
+        }
+    }
+
+    let stop_sequence = finish_reason.stop_sequence().map(String::from);
+
+    let content = match text.is_empty() {
+        true => vec![],
+        false => vec![ContentBlock::Text { text }],
+    };
+    let response = MessagesResponse::new(model, content, counter.into())
+        .with_stop_reason(finish_reason.into());
+    let response = match stop_sequence {
+        Some(stop_sequence) => response.with_stop_sequence(stop_sequence),
+        None => response,
+    };
+    res.render(Json(response));
+}
+
+/// Stream the continuation as a single text block.
+///
+/// Unlike a fresh turn, leading whitespace is kept since the text is appended verbatim.
+fn respond_stream(
+    res: &mut Response,
+    token_receiver: flume::Receiver<Token>,
+    model: String,
+    max_event_size: Option<usize>,
+) {
+    let message_id = format!("msg_{}", uuid::Uuid::new_v4().simple());
+    let mut output_tokens = 0usize;
+    let mut block_started = false;
+
+    let stream = token_receiver.into_stream().flat_map(move |token| {
+        let mut events: Vec<Result<SseEvent, std::convert::Infallible>> = vec![];
+        match token {
+            Token::Start => events.push(Ok(emit_message_start(MessageStartData::new(
+                message_id.clone(),
+                model.clone(),
+                0,
+            )))),
+            Token::Content(text) => {
+                output_tokens += 1;
+                if !block_started {
+                    block_started = true;
+                    events.push(Ok(emit_content_block_start_text(0)));
+                }
+                if !text.is_empty() {
+                    events.extend(
+                        emit_text_deltas(0, text, max_event_size)
+                            .into_iter()
+                            .map(Ok),
+                    );
+                }
+            }
+            Token::Stop(reason, _) => {
+                if block_started {
+                    events.push(Ok(emit_content_block_stop(0)));
+                }
+                let stop_sequence = reason.stop_sequence().map(String::from);
+                let stop_reason: StopReason = reason.into();
+                events.push(Ok(emit_message_delta(
+                    stop_reason,
+                    stop_sequence,
+                    output_tokens,
+                )));
+            }
+            Token::Error(error) => events.push(Ok(emit_generate_error(error))),
+            Token::Done => events.push(Ok(emit_message_stop())),
+            _ => events.push(Ok(emit_ping())),
+        }
+        futures_util::stream::iter(events)
+    });
+
+    salvo::sse::stream(res, stream);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(output: &str) -> Session {
+        let request = GenerateRequest {
+            prompt: "User: Hi\n\nAssistant:".into(),
+            max_tokens: 64,
+            stop: vec!["\n\nUser:".into()],
+            ..Default::default()
+        };
+        let mut session = Session::new(&request, DynaTempParams::default());
+        session.output = output.into();
+        session
+    }
+
+    #[test]
+    fn test_continue_appends_output_to_prompt() {
+        let request = session(" Hello, how").to_generate_request(None);
+        assert_eq!(request.prompt, "User: Hi\n\nAssistant: Hello, how");
+        assert_eq!(request.model_text, " Hello, how");
+        assert_eq!(request.max_tokens, 64);
+        assert_eq!(request.stop, vec!["\n\nUser:".to_string()]);
+    }
+
+    #[test]
+    fn test_continue_overrides_max_tokens() {
+        let request = session("").to_generate_request(Some(256));
+        assert_eq!(request.max_tokens, 256);
+    }
+
+    #[test]
+    fn test_store_evicts_oldest_session() {
+        let store = SessionStore::default();
+        let start = Instant::now();
+        for index in 0..=MAX_SESSIONS {
+            let mut session = session("");
+            session.updated = start + std::time::Duration::from_millis(index as u64);
+            store.insert(index.to_string(), session);
+        }
+        assert!(store.get("0").is_none());
+        assert!(store.get(&MAX_SESSIONS.to_string()).is_some());
+    }
+
+    #[test]
+    fn test_claim_busy_session() {
+        let store = SessionStore::default();
+        let mut idle = session("");
+        idle.busy = false;
+        store.insert("idle".into(), idle);
+
+        assert!(store.claim("idle").is_ok());
+        assert!(store.get("idle").unwrap().busy);
+        let err = store.claim("idle").unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+
+        store.update("idle", |session| session.busy = false);
+        assert!(store.claim("idle").is_ok());
+
+        let err = store.claim("missing").unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+    }
+}
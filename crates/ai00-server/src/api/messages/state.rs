@@ -0,0 +1,689 @@
+//! Prefilled states that `/v1/messages` requests can start from.
+//!
+//! `POST /api/states` runs a conversation prefix (e.g. a long system prompt) through the model,
+//! backs the resulting state off the GPU and keeps it under a new `state_id`. A Messages request
+//! with that `state_id` starts from the stored state, so only its own messages are prefilled.
+//! States unused for `state_store.ttl` seconds are dropped, and `DELETE /api/states/{id}`
+//! drops one right away.
+//!
+//! `GET /api/states/{id}` downloads a state as safetensors and `POST /api/states/import`
+//! uploads one, so that prefilled personas can be moved between servers running the same model.
+//! `POST /api/states/blend` stores a weighted sum of states, e.g. of two personas.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ai00_core::{
+    GenerateKind, GenerateRequest, InputState, StateId, StateValue, ThreadRequest, Token,
+    TokenCounter,
+};
+use anyhow::{bail, Result};
+use futures_util::StreamExt;
+use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+use salvo::{
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    oapi::extract::{JsonBody, PathParam},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::prompt::build_training_prompt;
+use super::types::{deserialize_system, MessageParam, SystemPrompt, Tool};
+use crate::{
+    api::{error::ApiErrorResponse, request_info_of, usage::Attribution},
+    config::{Config, StateStoreOption},
+    types::ThreadSender,
+    SLEEP,
+};
+
+/// Name of the tensor holding the state in exported files.
+const STATE_TENSOR: &str = "state";
+/// Bytes allowed on top of the state data when importing, for the safetensors header.
+const MAX_HEADER_SIZE: usize = 1 << 16;
+
+/// A state prefilled through `/api/states`.
+#[derive(Debug, Clone)]
+pub struct StoredState {
+    /// Model the state was created with, as named in the request.
+    pub model: String,
+    pub state: Arc<InputState>,
+    /// Number of tokens prefilled into the state.
+    pub tokens: usize,
+    used: Instant,
+}
+
+impl StoredState {
+    pub fn new(model: String, state: InputState, tokens: usize) -> Self {
+        Self {
+            model,
+            state: Arc::new(state),
+            tokens,
+            used: Instant::now(),
+        }
+    }
+}
+
+/// States shared between requests.
+#[derive(Debug, Default, Clone)]
+pub struct StateStore {
+    option: StateStoreOption,
+    states: Arc<Mutex<HashMap<StateId, StoredState>>>,
+}
+
+impl StateStore {
+    pub fn new(option: StateStoreOption) -> Self {
+        Self {
+            option,
+            states: Default::default(),
+        }
+    }
+
+    fn expire(&self, states: &mut HashMap<StateId, StoredState>) {
+        let ttl = Duration::from_secs(self.option.ttl);
+        states.retain(|_, state| state.used.elapsed() < ttl);
+    }
+
+    /// Get a state and mark it as used, which postpones its expiry.
+    pub fn get(&self, id: StateId) -> Option<StoredState> {
+        let mut states = self.states.lock().unwrap();
+        self.expire(&mut states);
+        let state = states.get_mut(&id)?;
+        state.used = Instant::now();
+        Some(state.clone())
+    }
+
+    pub fn insert(&self, id: StateId, state: StoredState) {
+        let mut states = self.states.lock().unwrap();
+        self.expire(&mut states);
+        while states.len() >= self.option.max_states.max(1) && !states.contains_key(&id) {
+            let oldest = states
+                .iter()
+                .min_by_key(|(_, state)| state.used)
+                .map(|(id, _)| *id);
+            match oldest {
+                Some(oldest) => states.remove(&oldest),
+                None => break,
+            };
+        }
+        states.insert(id, state);
+    }
+
+    /// Drop a state. Returns `false` if there was no such state.
+    pub fn remove(&self, id: StateId) -> bool {
+        let mut states = self.states.lock().unwrap();
+        self.expire(&mut states);
+        states.remove(&id).is_some()
+    }
+}
+
+/// Conversation prefix to prefill into a new state.
+///
+/// It is formatted like the beginning of a `/v1/messages` prompt, without the assistant turn
+/// that a Messages request ends with. Requests continuing from the state should leave out
+/// what the state already covers, such as the system prompt.
+#[derive(Debug, Default, Clone, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct CreateStateRequest {
+    /// Model to prefill with. Requests continuing from the state must name the same model.
+    pub model: String,
+    #[serde(deserialize_with = "deserialize_system")]
+    #[salvo(schema(value_type = Option<String>))]
+    pub system: Option<SystemPrompt>,
+    pub messages: Vec<MessageParam>,
+    /// Tools injected into the system prompt.
+    pub tools: Option<Vec<Tool>>,
+    /// Prompt profile to format the prefix with; requests continuing from the state should
+    /// name the same one.
+    pub prompt_profile: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CreateStateResponse {
+    /// Pass this as `state_id` in `/v1/messages` requests.
+    pub id: StateId,
+    pub model: String,
+    /// Seconds the state is kept after it was last used.
+    pub ttl: u64,
+    #[serde(rename = "usage")]
+    pub counter: TokenCounter,
+}
+
+/// Prefill a conversation prefix and store the resulting state.
+///
+/// `/api/states`.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 200, description = "Stored state", body = CreateStateResponse),
+        (status_code = 400, description = "Nothing to prefill", body = ApiErrorResponse),
+        (status_code = 404, description = "Model not loaded", body = ApiErrorResponse),
+    )
+)]
+pub async fn create_state(
+    depot: &mut Depot,
+    req: JsonBody<CreateStateRequest>,
+) -> Result<Json<CreateStateResponse>, ApiErrorResponse> {
+    let CreateStateRequest {
+        model,
+        system,
+        messages,
+        tools,
+        prompt_profile,
+    } = req.0;
+
+    let store = depot
+        .obtain::<StateStore>()
+        .map_err(|_| ApiErrorResponse::api_error("states are not available"))?
+        .clone();
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let prompts = config
+        .prompts
+        .get(prompt_profile.as_deref())
+        .ok_or_else(|| {
+            ApiErrorResponse::invalid_request("unknown prompt profile").with_param("prompt_profile")
+        })?;
+    let prompt = build_training_prompt(
+        system.as_deref(),
+        &messages,
+        tools.as_deref(),
+        None,
+        prompts,
+    )
+    .map_err(|err| ApiErrorResponse::invalid_request(format!("failed to build prompt: {err}")))?;
+    if prompt.is_empty() {
+        let err = ApiErrorResponse::invalid_request("nothing to prefill").with_param("messages");
+        return Err(err);
+    }
+
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let info = request_info_of(sender.clone(), &model, SLEEP).await?;
+    let mut request = GenerateRequest {
+        prompt,
+        model: Some(model.clone()),
+        ..Default::default()
+    };
+    Attribution::of(depot).apply(&mut request);
+    let Some((id, state, counter)) = prefill_state(sender, info.tokenizer, request).await else {
+        return Err(ApiErrorResponse::api_error("failed to prefill the state"));
+    };
+    store.insert(id, StoredState::new(model.clone(), state, counter.prompt));
+    tracing::info!(
+        event = "state_created",
+        state_id = ?id,
+        prompt_tokens = counter.prompt,
+        "Stored prefilled state"
+    );
+
+    Ok(Json(CreateStateResponse {
+        id,
+        model,
+        ttl: store.option.ttl,
+        counter,
+    }))
+}
+
+/// Prefill the prompt of `request` on top of its state, and return the resulting state under a
+/// new id.
+pub(super) async fn prefill_state(
+    sender: &ThreadSender,
+    tokenizer: Arc<Tokenizer>,
+    request: GenerateRequest,
+) -> Option<(StateId, InputState, TokenCounter)> {
+    let request = GenerateRequest {
+        max_tokens: 1,
+        kind: GenerateKind::State,
+        ..request
+    };
+    let (token_sender, token_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Generate {
+        request: Box::new(request),
+        tokenizer,
+        sender: token_sender,
+    });
+
+    let mut counter = TokenCounter::default();
+    let mut embed = None;
+    let mut stream = token_receiver.into_stream();
+    while let Some(token) = stream.next().await {
+        match token {
+            Token::Stop(_, token_counter) => counter = token_counter,
+            Token::Embed(data, shape) => embed = Some((data, shape)),
+            Token::Done => break,
+            _ => {}
+        }
+    }
+    let (data, shape) = embed?;
+
+    let id = StateId::new();
+    let state = InputState::Value(StateValue {
+        name: format!("{id:?}"),
+        id,
+        data,
+        shape,
+    });
+    Some((id, state, counter))
+}
+
+/// Drop a stored state.
+///
+/// `/api/states/{id}`.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 204, description = "State dropped"),
+        (status_code = 404, description = "Unknown or expired state", body = ApiErrorResponse),
+    )
+)]
+pub async fn delete_state(
+    depot: &mut Depot,
+    id: PathParam<StateId>,
+) -> Result<StatusCode, ApiErrorResponse> {
+    let id = id.into_inner();
+    let store = depot
+        .obtain::<StateStore>()
+        .map_err(|_| ApiErrorResponse::api_error("states are not available"))?;
+    match store.remove(id) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiErrorResponse::not_found(format!(
+            "state {id:?} not found"
+        ))),
+    }
+}
+
+/// Serialize a state as safetensors with a single F32 tensor named `state`.
+///
+/// `shape` lists the fastest varying dimension first, so the tensor shape is its reverse.
+pub fn encode_state(value: &StateValue, metadata: HashMap<String, String>) -> Result<Vec<u8>> {
+    let data: Vec<u8> = value.data.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let [x, y, z, w] = value.shape;
+    let tensor = TensorView::new(Dtype::F32, vec![w, z, y, x], &data)?;
+    Ok(safetensors::serialize(
+        [(STATE_TENSOR, tensor)],
+        Some(metadata),
+    )?)
+}
+
+/// Read a state written by [`encode_state`], returning its data, shape and metadata.
+pub fn decode_state(data: &[u8]) -> Result<(Vec<f32>, [usize; 4], HashMap<String, String>)> {
+    let (_, metadata) = SafeTensors::read_metadata(data)?;
+    let metadata = metadata.metadata().clone().unwrap_or_default();
+    let tensors = SafeTensors::deserialize(data)?;
+    let tensor = tensors.tensor(STATE_TENSOR)?;
+    if tensor.dtype() != Dtype::F32 {
+        bail!(
+            "tensor `{STATE_TENSOR}` must be F32, found {:?}",
+            tensor.dtype()
+        );
+    }
+    let shape = match *tensor.shape() {
+        [w, z, y, x] => [x, y, z, w],
+        ref shape => bail!("tensor `{STATE_TENSOR}` must have 4 dimensions, found {shape:?}"),
+    };
+    let data = tensor
+        .data()
+        .chunks_exact(4)
+        .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect();
+    Ok((data, shape, metadata))
+}
+
+/// Download a stored state as safetensors.
+///
+/// `/api/states/{id}`.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 200, description = "State as a safetensors file"),
+        (status_code = 404, description = "Unknown or expired state", body = ApiErrorResponse),
+    )
+)]
+pub async fn export_state(
+    depot: &mut Depot,
+    id: PathParam<StateId>,
+    res: &mut Response,
+) -> Result<(), ApiErrorResponse> {
+    let id = id.into_inner();
+    let store = depot
+        .obtain::<StateStore>()
+        .map_err(|_| ApiErrorResponse::api_error("states are not available"))?;
+    let Some(stored) = store.get(id) else {
+        return Err(ApiErrorResponse::not_found(format!(
+            "state {id:?} not found"
+        )));
+    };
+    let InputState::Value(value) = stored.state.as_ref() else {
+        return Err(ApiErrorResponse::api_error("state data is not available"));
+    };
+
+    let metadata = HashMap::from([
+        ("name".to_string(), value.name.clone()),
+        ("model".to_string(), stored.model.clone()),
+        ("tokens".to_string(), stored.tokens.to_string()),
+    ]);
+    let data = encode_state(value, metadata)
+        .map_err(|err| ApiErrorResponse::api_error(format!("failed to encode state: {err}")))?;
+
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+    if let Ok(value) = format!("attachment; filename=\"{id:?}.st\"").parse() {
+        headers.insert(CONTENT_DISPOSITION, value);
+    }
+    let _ = res.write_body(data);
+    Ok(())
+}
+
+/// Upload a state exported by `/api/states/{id}` and store it under a new ID.
+///
+/// The body is the raw safetensors file. The required query parameter `model` selects the model
+/// the state is checked against, and `name` names the state; the name defaults to the exported
+/// one.
+///
+/// `/api/states/import`.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 200, description = "Stored state", body = CreateStateResponse),
+        (status_code = 400, description = "Missing model or invalid state file", body = ApiErrorResponse),
+        (status_code = 404, description = "Model not loaded", body = ApiErrorResponse),
+        (status_code = 413, description = "State file too large", body = ApiErrorResponse),
+    )
+)]
+pub async fn import_state(
+    depot: &mut Depot,
+    req: &mut Request,
+) -> Result<Json<CreateStateResponse>, ApiErrorResponse> {
+    let model = req
+        .query::<String>("model")
+        .filter(|model| !model.is_empty())
+        .ok_or_else(|| {
+            ApiErrorResponse::invalid_request("model is required").with_param("model")
+        })?;
+    let name = req.query::<String>("name");
+
+    let store = depot
+        .obtain::<StateStore>()
+        .map_err(|_| ApiErrorResponse::api_error("states are not available"))?
+        .clone();
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let info = request_info_of(sender.clone(), &model, SLEEP).await?;
+    let Some(expected) = info.state_shape() else {
+        let err = ApiErrorResponse::invalid_request("the model does not support init states");
+        return Err(err.with_param("model"));
+    };
+
+    // the body is read chunk by chunk and rejected as soon as it exceeds the largest valid file
+    let max_size =
+        expected.iter().product::<usize>() * std::mem::size_of::<f32>() + MAX_HEADER_SIZE;
+    let payload = req.payload_with_max_size(max_size).await.map_err(|err| {
+        ApiErrorResponse::request_too_large(format!("failed to read state file: {err}"))
+    })?;
+    let (data, shape, metadata) = decode_state(payload)
+        .map_err(|err| ApiErrorResponse::invalid_request(format!("invalid state file: {err}")))?;
+    if shape != expected {
+        let message =
+            format!("state shape {shape:?} does not match the model, expected {expected:?}");
+        return Err(ApiErrorResponse::invalid_request(message));
+    }
+
+    let id = StateId::new();
+    let name = name
+        .or_else(|| metadata.get("name").cloned())
+        .unwrap_or_else(|| format!("{id:?}"));
+    let tokens = metadata
+        .get("tokens")
+        .and_then(|tokens| tokens.parse().ok())
+        .unwrap_or_default();
+    let state = InputState::Value(StateValue {
+        name,
+        id,
+        data,
+        shape,
+    });
+    store.insert(id, StoredState::new(model.clone(), state, tokens));
+    tracing::info!(event = "state_imported", state_id = ?id, "Stored uploaded state");
+
+    Ok(Json(CreateStateResponse {
+        id,
+        model,
+        ttl: store.option.ttl,
+        counter: Default::default(),
+    }))
+}
+
+/// A state and its weight in a blend.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct BlendComponent {
+    /// A stored state, or an init state of the model from its config.
+    pub id: StateId,
+    pub weight: f32,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, ToSchema)]
+#[serde(default)]
+#[salvo(schema(
+    example = json!({
+        "model": "rwkv",
+        "states": [
+            {"id": "0b3e1c56-52a4-4c4d-9d43-1b0b3f8c1e2a", "weight": 0.7},
+            {"id": "7f1d2a9e-0c1b-4e7a-8a55-2f6c3d4b5a69", "weight": 0.3}
+        ]
+    })
+))]
+pub struct BlendStateRequest {
+    /// Model the states belong to.
+    pub model: String,
+    /// Name of the blended state.
+    pub name: Option<String>,
+    pub states: Vec<BlendComponent>,
+    /// Scale the weights so that they sum to 1.
+    pub normalize: bool,
+}
+
+/// Store the weighted sum of several states as a new state.
+///
+/// `/api/states/blend`.
+#[endpoint(
+    tags("messages"),
+    responses(
+        (status_code = 200, description = "Stored state", body = CreateStateResponse),
+        (status_code = 400, description = "Invalid weights or mismatched shapes", body = ApiErrorResponse),
+        (status_code = 404, description = "Unknown or expired state", body = ApiErrorResponse),
+    )
+)]
+pub async fn blend_state(
+    depot: &mut Depot,
+    req: JsonBody<BlendStateRequest>,
+) -> Result<Json<CreateStateResponse>, ApiErrorResponse> {
+    let BlendStateRequest {
+        model,
+        name,
+        states,
+        normalize,
+    } = req.0;
+
+    if states.is_empty() {
+        let err = ApiErrorResponse::invalid_request("no state to blend").with_param("states");
+        return Err(err);
+    }
+    if let Some(index) = states.iter().position(|x| !x.weight.is_finite()) {
+        let err = ApiErrorResponse::invalid_request("weight must be a finite number");
+        return Err(err.with_param(format!("states.{index}.weight")));
+    }
+    let total: f32 = states.iter().map(|x| x.weight).sum();
+    if normalize && total.abs() < f32::EPSILON {
+        let err = ApiErrorResponse::invalid_request("weights sum to zero and cannot be normalized");
+        return Err(err.with_param("states"));
+    }
+
+    let store = depot
+        .obtain::<StateStore>()
+        .map_err(|_| ApiErrorResponse::api_error("states are not available"))?
+        .clone();
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let info = request_info_of(sender.clone(), &model, SLEEP).await?;
+
+    let mut values = Vec::with_capacity(states.len());
+    for (index, BlendComponent { id, weight }) in states.iter().enumerate() {
+        let param = format!("states.{index}.id");
+        let value = match store.get(*id) {
+            Some(stored) if stored.model != model => {
+                let message = format!("state was created with model `{}`", stored.model);
+                return Err(ApiErrorResponse::invalid_request(message).with_param(param));
+            }
+            Some(stored) => match stored.state.as_ref() {
+                InputState::Value(value) => value.clone(),
+                _ => return Err(ApiErrorResponse::api_error("state data is not available")),
+            },
+            None => match info.states.iter().find(|state| state.id == *id) {
+                Some(state) => StateValue::from(state),
+                None => {
+                    let message = format!("state {id:?} not found");
+                    return Err(ApiErrorResponse::not_found(message).with_param(param));
+                }
+            },
+        };
+        let weight = match normalize {
+            true => weight / total,
+            false => *weight,
+        };
+        values.push((value, weight));
+    }
+
+    let id = StateId::new();
+    let name = name.unwrap_or_else(|| format!("{id:?}"));
+    let components: Vec<_> = values
+        .iter()
+        .map(|(value, weight)| (value, *weight))
+        .collect();
+    let value = StateValue::blend(name, id, &components)
+        .map_err(|err| ApiErrorResponse::invalid_request(err.to_string()).with_param("states"))?;
+    store.insert(
+        id,
+        StoredState::new(model.clone(), InputState::Value(value), 0),
+    );
+    tracing::info!(
+        event = "state_blended",
+        state_id = ?id,
+        components = components.len(),
+        "Stored blended state"
+    );
+
+    Ok(Json(CreateStateResponse {
+        id,
+        model,
+        ttl: store.option.ttl,
+        counter: Default::default(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(ttl: u64, max_states: usize) -> StateStore {
+        StateStore::new(StateStoreOption { ttl, max_states })
+    }
+
+    fn state() -> StoredState {
+        StoredState::new("rwkv".into(), InputState::default(), 0)
+    }
+
+    #[test]
+    fn test_store_evicts_least_recently_used_state() {
+        let store = store(3600, 2);
+        let ids = [StateId::new(), StateId::new(), StateId::new()];
+        let start = Instant::now();
+        for (index, id) in ids.iter().enumerate() {
+            let mut state = state();
+            state.used = start + Duration::from_millis(index as u64);
+            store.insert(*id, state);
+        }
+        assert!(store.get(ids[0]).is_none());
+        assert!(store.get(ids[1]).is_some());
+        assert!(store.get(ids[2]).is_some());
+    }
+
+    #[test]
+    fn test_store_expires_unused_state() {
+        let store = store(0, 2);
+        let id = StateId::new();
+        store.insert(id, state());
+        assert!(store.get(id).is_none());
+    }
+
+    #[test]
+    fn test_state_round_trips_through_safetensors() {
+        let value = StateValue {
+            name: "persona".into(),
+            id: StateId::new(),
+            data: (0..24).map(|x| x as f32 * 0.5).collect(),
+            shape: [4, 3, 2, 1],
+        };
+        let metadata = HashMap::from([("name".to_string(), "persona".to_string())]);
+        let file = encode_state(&value, metadata).unwrap();
+
+        let tensors = SafeTensors::deserialize(&file).unwrap();
+        assert_eq!(tensors.tensor(STATE_TENSOR).unwrap().shape(), &[1, 2, 3, 4]);
+
+        let (data, shape, metadata) = decode_state(&file).unwrap();
+        assert_eq!(data, value.data);
+        assert_eq!(shape, value.shape);
+        assert_eq!(metadata.get("name").map(String::as_str), Some("persona"));
+    }
+
+    #[test]
+    fn test_decode_rejects_other_files() {
+        let data = vec![0u8; 8];
+        let tensor = TensorView::new(Dtype::F32, vec![2], &data).unwrap();
+        let file = safetensors::serialize([("weight", tensor)], None).unwrap();
+        assert!(decode_state(&file).is_err());
+
+        let tensor = TensorView::new(Dtype::F32, vec![2], &data).unwrap();
+        let file = safetensors::serialize([(STATE_TENSOR, tensor)], None).unwrap();
+        assert!(decode_state(&file).is_err());
+    }
+
+    #[test]
+    fn test_blend_weights_states() {
+        let state = |data: Vec<f32>, shape| StateValue {
+            data,
+            shape,
+            ..Default::default()
+        };
+        let a = state(vec![1.0, 2.0], [2, 1, 1, 1]);
+        let b = state(vec![3.0, 4.0], [2, 1, 1, 1]);
+        let blend = StateValue::blend("ab".into(), StateId::new(), &[(&a, 0.5), (&b, 0.5)]);
+        assert_eq!(blend.unwrap().data, vec![2.0, 3.0]);
+
+        let c = state(vec![3.0, 4.0], [1, 2, 1, 1]);
+        let blend = StateValue::blend("ac".into(), StateId::new(), &[(&a, 0.5), (&c, 0.5)]);
+        assert!(blend.is_err());
+    }
+
+    #[test]
+    fn test_store_removes_state() {
+        let store = store(3600, 2);
+        let id = StateId::new();
+        store.insert(id, state());
+        assert!(store.remove(id));
+        assert!(!store.remove(id));
+    }
+
+    #[tokio::test]
+    async fn test_import_requires_model() {
+        use salvo::test::{ResponseExt, TestClient};
+
+        let router = Router::with_path("states/import").post(import_state);
+        let mut res = TestClient::post("http://127.0.0.1:5800/states/import")
+            .send(&Service::new(router))
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+        let body = res.take_string().await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["param"], "model");
+    }
+}
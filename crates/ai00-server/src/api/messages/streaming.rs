@@ -6,13 +6,16 @@
 //! - content_block_delta
 //! - content_block_stop
 //! - message_delta
+//! - message_metadata (server-specific, e.g. for `return_timings`)
 //! - message_stop
 //! - ping (keep-alive)
 
+use ai00_core::{GenerateError, GrammarTrace};
 use salvo::sse::SseEvent;
 use serde::{Deserialize, Serialize};
 
 use super::types::*;
+use crate::api::error::ApiErrorResponse;
 
 /// message_start event - includes full message object with empty content.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,6 +37,36 @@ pub struct MessageStartData {
     pub stop_reason: Option<StopReason>,
     pub stop_sequence: Option<String>,
     pub usage: Usage,
+    #[serde(default, skip_serializing_if = "ResponseMetadata::is_empty")]
+    pub metadata: ResponseMetadata,
+}
+
+impl MessageStartData {
+    pub fn new(id: String, model: String, input_tokens: usize) -> Self {
+        Self {
+            id,
+            object: "message",
+            role: "assistant",
+            model,
+            content: vec![],
+            stop_reason: None,
+            stop_sequence: None,
+            usage: Usage {
+                input_tokens,
+                output_tokens: 1,
+                cache_creation_input_tokens: 0,
+                cache_read_input_tokens: 0,
+                server_tool_use: None,
+            },
+            metadata: ResponseMetadata::default(),
+        }
+    }
+
+    /// Set the server-specific metadata.
+    pub fn with_metadata(mut self, metadata: ResponseMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
 }
 
 /// content_block_start event.
@@ -71,6 +104,18 @@ pub enum ContentDelta {
     InputJson { partial_json: String },
 }
 
+impl ContentDelta {
+    /// The streamed payload of the delta.
+    fn payload_mut(&mut self) -> &mut String {
+        match self {
+            ContentDelta::Text { text } => text,
+            ContentDelta::Thinking { thinking } => thinking,
+            ContentDelta::Signature { signature } => signature,
+            ContentDelta::InputJson { partial_json } => partial_json,
+        }
+    }
+}
+
 /// content_block_stop event.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContentBlockStopEvent {
@@ -101,6 +146,15 @@ pub struct OutputUsage {
     pub output_tokens: usize,
 }
 
+/// message_metadata event - server-specific information about the response that is only known
+/// once it is generated, beyond the Claude API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageMetadataEvent {
+    #[serde(rename = "type")]
+    pub event_type: &'static str,
+    pub metadata: ResponseMetadata,
+}
+
 /// message_stop event.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageStopEvent {
@@ -116,24 +170,10 @@ pub struct PingEvent {
 }
 
 /// Create a message_start SSE event.
-pub fn emit_message_start(id: String, model: String, input_tokens: usize) -> SseEvent {
+pub fn emit_message_start(message: MessageStartData) -> SseEvent {
     let event = MessageStartEvent {
         event_type: "message_start",
-        message: MessageStartData {
-            id,
-            object: "message",
-            role: "assistant",
-            model,
-            content: vec![],
-            stop_reason: None,
-            stop_sequence: None,
-            usage: Usage {
-                input_tokens,
-                output_tokens: 1,
-                cache_creation_input_tokens: 0,
-                cache_read_input_tokens: 0,
-            },
-        },
+        message,
     };
     SseEvent::default()
         .name("message_start")
@@ -170,30 +210,108 @@ pub fn emit_content_block_start_tool_use(index: usize, id: String, name: String)
         .text(serde_json::to_string(&event).unwrap())
 }
 
-/// Create a content_block_delta SSE event for text.
-pub fn emit_text_delta(index: usize, text: String) -> SseEvent {
-    let event = ContentBlockDeltaEvent {
-        event_type: "content_block_delta",
+/// Create content_block_delta SSE events for text, split to fit `max_event_size`.
+pub fn emit_text_deltas(
+    index: usize,
+    text: String,
+    max_event_size: Option<usize>,
+) -> Vec<SseEvent> {
+    emit_content_block_deltas(index, ContentDelta::Text { text }, max_event_size)
+}
+
+/// Create content_block_delta SSE events for tool input JSON, split to fit `max_event_size`.
+pub fn emit_input_json_deltas(
+    index: usize,
+    partial_json: String,
+    max_event_size: Option<usize>,
+) -> Vec<SseEvent> {
+    emit_content_block_deltas(
         index,
-        delta: ContentDelta::Text { text },
-    };
-    SseEvent::default()
-        .name("content_block_delta")
-        .text(serde_json::to_string(&event).unwrap())
+        ContentDelta::InputJson { partial_json },
+        max_event_size,
+    )
 }
 
-/// Create a content_block_delta SSE event for tool input JSON.
-pub fn emit_input_json_delta(index: usize, partial_json: String) -> SseEvent {
+fn emit_content_block_delta(index: usize, delta: ContentDelta) -> SseEvent {
     let event = ContentBlockDeltaEvent {
         event_type: "content_block_delta",
         index,
-        delta: ContentDelta::InputJson { partial_json },
+        delta,
     };
     SseEvent::default()
         .name("content_block_delta")
         .text(serde_json::to_string(&event).unwrap())
 }
 
+fn emit_content_block_deltas(
+    index: usize,
+    delta: ContentDelta,
+    max_event_size: Option<usize>,
+) -> Vec<SseEvent> {
+    match max_event_size {
+        Some(max_event_size) => split_delta(index, delta, max_event_size)
+            .into_iter()
+            .map(|event| emit_content_block_delta(event.index, event.delta))
+            .collect(),
+        None => vec![emit_content_block_delta(index, delta)],
+    }
+}
+
+/// Split a content_block_delta so that no serialized event is larger than `max_event_size` bytes.
+///
+/// Chunks end on character boundaries and concatenate back to the original payload. Every chunk
+/// holds at least one character, so a limit below the event overhead yields one event per character.
+pub fn split_delta(
+    index: usize,
+    mut delta: ContentDelta,
+    max_event_size: usize,
+) -> Vec<ContentBlockDeltaEvent> {
+    let payload = std::mem::take(delta.payload_mut());
+    let make = |chunk: &str| {
+        let mut delta = delta.clone();
+        *delta.payload_mut() = chunk.to_string();
+        ContentBlockDeltaEvent {
+            event_type: "content_block_delta",
+            index,
+            delta,
+        }
+    };
+
+    let overhead = serde_json::to_string(&make("")).map_or(0, |json| json.len());
+    let budget = max_event_size.saturating_sub(overhead);
+    if escaped_len(&payload) <= budget {
+        return vec![make(&payload)];
+    }
+
+    let mut events = vec![];
+    let mut start = 0;
+    let mut len = 0;
+    for (offset, ch) in payload.char_indices() {
+        let ch_len = escaped_char_len(ch);
+        if len + ch_len > budget && offset > start {
+            events.push(make(&payload[start..offset]));
+            start = offset;
+            len = 0;
+        }
+        len += ch_len;
+    }
+    events.push(make(&payload[start..]));
+    events
+}
+
+/// Length of a character once escaped inside a JSON string.
+fn escaped_char_len(ch: char) -> usize {
+    match ch {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{08}' | '\u{0c}' => 2,
+        ch if (ch as u32) < 0x20 => 6,
+        ch => ch.len_utf8(),
+    }
+}
+
+fn escaped_len(text: &str) -> usize {
+    text.chars().map(escaped_char_len).sum()
+}
+
 /// Create a content_block_start SSE event for thinking.
 pub fn emit_content_block_start_thinking(index: usize) -> SseEvent {
     let event = ContentBlockStartEvent {
@@ -209,28 +327,18 @@ pub fn emit_content_block_start_thinking(index: usize) -> SseEvent {
         .text(serde_json::to_string(&event).unwrap())
 }
 
-/// Create a content_block_delta SSE event for thinking.
-pub fn emit_thinking_delta(index: usize, thinking: String) -> SseEvent {
-    let event = ContentBlockDeltaEvent {
-        event_type: "content_block_delta",
-        index,
-        delta: ContentDelta::Thinking { thinking },
-    };
-    SseEvent::default()
-        .name("content_block_delta")
-        .text(serde_json::to_string(&event).unwrap())
+/// Create content_block_delta SSE events for thinking, split to fit `max_event_size`.
+pub fn emit_thinking_deltas(
+    index: usize,
+    thinking: String,
+    max_event_size: Option<usize>,
+) -> Vec<SseEvent> {
+    emit_content_block_deltas(index, ContentDelta::Thinking { thinking }, max_event_size)
 }
 
 /// Create a content_block_delta SSE event for thinking signature.
 pub fn emit_signature_delta(index: usize, signature: String) -> SseEvent {
-    let event = ContentBlockDeltaEvent {
-        event_type: "content_block_delta",
-        index,
-        delta: ContentDelta::Signature { signature },
-    };
-    SseEvent::default()
-        .name("content_block_delta")
-        .text(serde_json::to_string(&event).unwrap())
+    emit_content_block_delta(index, ContentDelta::Signature { signature })
 }
 
 /// Create a content_block_stop SSE event.
@@ -245,12 +353,16 @@ pub fn emit_content_block_stop(index: usize) -> SseEvent {
 }
 
 /// Create a message_delta SSE event.
-pub fn emit_message_delta(stop_reason: StopReason, output_tokens: usize) -> SseEvent {
+pub fn emit_message_delta(
+    stop_reason: StopReason,
+    stop_sequence: Option<String>,
+    output_tokens: usize,
+) -> SseEvent {
     let event = MessageDeltaEvent {
         event_type: "message_delta",
         delta: MessageDeltaData {
             stop_reason,
-            stop_sequence: None,
+            stop_sequence,
         },
         usage: OutputUsage { output_tokens },
     };
@@ -259,6 +371,17 @@ pub fn emit_message_delta(stop_reason: StopReason, output_tokens: usize) -> SseE
         .text(serde_json::to_string(&event).unwrap())
 }
 
+/// Create a message_metadata SSE event.
+pub fn emit_message_metadata(metadata: ResponseMetadata) -> SseEvent {
+    let event = MessageMetadataEvent {
+        event_type: "message_metadata",
+        metadata,
+    };
+    SseEvent::default()
+        .name("message_metadata")
+        .text(serde_json::to_string(&event).unwrap())
+}
+
 /// Create a message_stop SSE event.
 pub fn emit_message_stop() -> SseEvent {
     let event = MessageStopEvent {
@@ -296,6 +419,9 @@ pub struct StreamErrorData {
     /// Partial content blocks accumulated before error (if any)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub partial_content: Option<Vec<ContentBlock>>,
+    /// Machine-readable details of the error (if any)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
 }
 
 /// Create an error SSE event with optional partial content.
@@ -310,9 +436,48 @@ pub fn emit_error(
             error_type: error_type.to_string(),
             message: message.to_string(),
             partial_content,
+            details: None,
         },
     };
     SseEvent::default()
         .name("error")
         .text(serde_json::to_string(&event).unwrap())
 }
+
+/// Create an SSE comment tracing how the grammar constrained a sampled token.
+///
+/// Being a comment, it is ignored by clients that do not look for it.
+pub fn emit_grammar_trace(trace: GrammarTrace) -> SseEvent {
+    let trace = serde_json::to_string(&trace).unwrap();
+    SseEvent::default().comment(format!("grammar_trace {trace}"))
+}
+
+/// Create an error SSE event for a generation that failed part way.
+pub fn emit_generate_error(error: GenerateError) -> SseEvent {
+    let error = ApiErrorResponse::from(error).error;
+    let event = StreamErrorEvent {
+        event_type: "error",
+        error: StreamErrorData {
+            error_type: "api_error".into(),
+            message: error.message,
+            partial_content: None,
+            details: error.details,
+        },
+    };
+    SseEvent::default()
+        .name("error")
+        .text(serde_json::to_string(&event).unwrap())
+}
+
+/// The data of an SSE event, which is the JSON of its event object.
+///
+/// Lets transports other than SSE send the same events.
+pub fn event_data(event: &SseEvent) -> Option<String> {
+    let text = event.to_string();
+    let lines: Vec<_> = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
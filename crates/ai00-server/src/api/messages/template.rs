@@ -0,0 +1,157 @@
+//! Chat templates: prompts rendered by a Jinja template of `prompts.template`.
+//!
+//! Templates follow the conventions of Hugging Face chat templates, so that ChatML, Llama and
+//! other formats can be served without code changes. They see:
+//! - `system`: the system prompt, or none.
+//! - `messages`: the messages, each with `role`, `content` (the text of the message, tool calls
+//!   and results in ai00 format, thinking of prior turns as `prior_thinking` says) and `blocks`
+//!   (its content blocks as sent by the client).
+//! - `tools`: the tool definitions, and `tools_prompt`: their ai00 description, empty if none.
+//! - `thinking`: whether thinking is enabled, and `thinking_suffix`: the suffix of its budget.
+//! - `add_generation_prompt`: whether the prompt ends with an open assistant turn.
+//! - `roles`: the role names of `[prompts]`, as `roles.user`, `roles.assistant` and `roles.system`.
+//!
+//! Besides the builtins of minijinja, templates can use the `tojson` filter and
+//! `raise_exception(message)` to reject a conversation.
+
+use anyhow::Result;
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::Serialize;
+
+use super::{
+    prompt::{get_thinking_suffix, message_text},
+    types::{
+        generate_tool_system_prompt, ContentBlock, MessageContent, MessageParam, MessageRole,
+        ThinkingConfig, Tool,
+    },
+};
+use crate::config::PromptsConfig;
+
+#[derive(Serialize)]
+struct TemplateMessage<'a> {
+    role: MessageRole,
+    content: String,
+    blocks: Vec<&'a ContentBlock>,
+}
+
+fn raise_exception(message: String) -> Result<String, Error> {
+    Err(Error::new(ErrorKind::InvalidOperation, message))
+}
+
+fn environment<'source>() -> Environment<'source> {
+    let mut env = Environment::new();
+    env.add_function("raise_exception", raise_exception);
+    env
+}
+
+/// Check that `source` compiles.
+pub fn check_template(source: &str) -> Result<()> {
+    environment().template_from_str(source)?;
+    Ok(())
+}
+
+/// Render the chat template `source` for a conversation.
+pub fn render_template(
+    source: &str,
+    system: Option<&str>,
+    messages: &[MessageParam],
+    tools: Option<&[Tool]>,
+    thinking: Option<&ThinkingConfig>,
+    prompts: &PromptsConfig,
+    add_generation_prompt: bool,
+) -> Result<String> {
+    let messages: Vec<_> = messages
+        .iter()
+        .enumerate()
+        .map(|(index, message)| TemplateMessage {
+            role: message.role,
+            content: message_text(messages, index, prompts),
+            blocks: match &message.content {
+                MessageContent::Text(_) => vec![],
+                MessageContent::Blocks(blocks) => blocks.iter().collect(),
+            },
+        })
+        .collect();
+    let tools = tools.unwrap_or_default();
+    let tools_prompt = match tools.is_empty() {
+        true => String::new(),
+        false => generate_tool_system_prompt(
+            tools,
+            Some(&prompts.tool_header),
+            Some(&prompts.tool_footer),
+        ),
+    };
+
+    let env = environment();
+    let template = env.template_from_str(source)?;
+    let prompt = template.render(context! {
+        system,
+        messages,
+        tools,
+        tools_prompt,
+        thinking => thinking.is_some_and(|thinking| thinking.is_enabled()),
+        thinking_suffix => get_thinking_suffix(thinking, prompts),
+        add_generation_prompt,
+        roles => context! {
+            user => prompts.role_user,
+            assistant => prompts.role_assistant,
+            system => prompts.role_system,
+        },
+    })?;
+    Ok(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHATML: &str = "{% if system %}<|im_start|>system\n{{ system }}<|im_end|>\n{% endif %}\
+        {% for message in messages %}<|im_start|>{{ message.role }}\n{{ message.content }}<|im_end|>\n{% endfor %}\
+        {% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}";
+
+    fn messages() -> Vec<MessageParam> {
+        vec![
+            MessageParam {
+                role: MessageRole::User,
+                content: MessageContent::Text("Hi".into()),
+            },
+            MessageParam {
+                role: MessageRole::Assistant,
+                content: MessageContent::Text("Hello!".into()),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_render_chatml() {
+        let prompts = PromptsConfig::default();
+        let prompt = render_template(
+            CHATML,
+            Some("Be brief."),
+            &messages(),
+            None,
+            None,
+            &prompts,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_raise_exception() {
+        let source = "{% if messages[0].role != 'assistant' %}{{ raise_exception('assistant first') }}{% endif %}";
+        assert!(check_template(source).is_ok());
+        let prompts = PromptsConfig::default();
+        let err =
+            render_template(source, None, &messages(), None, None, &prompts, true).unwrap_err();
+        assert!(err.to_string().contains("assistant first"));
+        assert!(check_template("{% for %}").is_err());
+    }
+}
@@ -70,6 +70,23 @@ pub struct ParseResult {
     pub tool_uses: Vec<ParsedToolUse>,
     /// Whether we're currently inside a tool_call block
     pub in_tool_block: bool,
+    /// The text and tool calls in the order they were written, with the input of each call
+    /// in chunks as its parameters complete. Only filled by `Ai00FunctionCallsParser`.
+    pub events: Vec<ToolStreamEvent>,
+}
+
+/// Text or progress of a tool call, for streaming tool calls before they complete.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolStreamEvent {
+    /// Text outside of function calls.
+    Text(String),
+    /// A tool call started.
+    Start { id: String, name: String },
+    /// Next chunk of the input JSON of the started call. The chunks of a call join into
+    /// its complete input.
+    InputJson(String),
+    /// The started call ended.
+    Stop,
 }
 
 impl ToolParser {
@@ -396,6 +413,14 @@ pub struct Ai00FunctionCallsParser {
     tool_index: usize,
     /// Depth tracker for nested tags
     in_function_calls: bool,
+    /// ID of the invoke whose `Start` event was emitted
+    current_invoke_id: Option<String>,
+    /// Parameters of the current invoke emitted as input JSON so far
+    emitted_params: usize,
+    /// Events not returned yet
+    events: Vec<ToolStreamEvent>,
+    /// Text moved to `events` and not returned yet
+    emitted_text: String,
 }
 
 /// Parser state machine states for ai00 format.
@@ -430,18 +455,31 @@ impl Ai00FunctionCallsParser {
             self.process_char(ch);
         }
 
-        let mut result = ParseResult::default();
-
         // Emit accumulated text if not in function_calls block
-        if !self.text_buffer.is_empty() && !self.in_function_calls {
-            result.text = Some(std::mem::take(&mut self.text_buffer));
+        if !self.in_function_calls {
+            self.flush_text();
         }
+        self.take_result()
+    }
 
-        // Emit completed tools
-        result.tool_uses = std::mem::take(&mut self.completed_tools);
-        result.in_tool_block = self.in_function_calls;
+    /// Move the accumulated text to the events.
+    fn flush_text(&mut self) {
+        if !self.text_buffer.is_empty() {
+            let text = std::mem::take(&mut self.text_buffer);
+            self.emitted_text.push_str(&text);
+            self.events.push(ToolStreamEvent::Text(text));
+        }
+    }
 
-        result
+    /// Return the emitted text, completed tools and events.
+    fn take_result(&mut self) -> ParseResult {
+        let text = std::mem::take(&mut self.emitted_text);
+        ParseResult {
+            text: (!text.is_empty()).then_some(text),
+            tool_uses: std::mem::take(&mut self.completed_tools),
+            in_tool_block: self.in_function_calls,
+            events: std::mem::take(&mut self.events),
+        }
     }
 
     /// Process a single character through the state machine.
@@ -546,6 +584,7 @@ impl Ai00FunctionCallsParser {
                         // Store invoke name
                         self.current_invoke_name = std::mem::take(&mut self.current_param_name);
                         self.current_params.clear();
+                        self.start_invoke();
                     } else if self.tag_buffer == "parameter" {
                         // Store parameter name
                         // current_param_name already set
@@ -574,6 +613,7 @@ impl Ai00FunctionCallsParser {
     fn handle_open_tag_complete(&mut self) {
         match self.tag_buffer.as_str() {
             "ai00:function_calls" => {
+                self.flush_text();
                 self.in_function_calls = true;
             }
             "invoke" => {
@@ -593,6 +633,7 @@ impl Ai00FunctionCallsParser {
                 self.in_function_calls = false;
             }
             "invoke" => {
+                self.stop_invoke();
                 // Complete this invoke as a tool call
                 if !self.current_invoke_name.is_empty() {
                     let id = format!("toolu_{:012x}", self.tool_index);
@@ -620,6 +661,15 @@ impl Ai00FunctionCallsParser {
                         Value::String(value)
                     };
 
+                    if self.current_invoke_id.is_some() {
+                        let separator = match self.emitted_params {
+                            0 => '{',
+                            _ => ',',
+                        };
+                        self.emitted_params += 1;
+                        let chunk = format!("{separator}{}:{json_value}", Value::from(&*name));
+                        self.events.push(ToolStreamEvent::InputJson(chunk));
+                    }
                     self.current_params.insert(name, json_value);
                 }
             }
@@ -627,19 +677,42 @@ impl Ai00FunctionCallsParser {
         }
     }
 
+    /// Emit the start of the invoke named in `current_invoke_name`.
+    fn start_invoke(&mut self) {
+        if self.current_invoke_name.is_empty() {
+            return;
+        }
+        let id = format!("toolu_{:012x}", self.tool_index);
+        self.current_invoke_id = Some(id.clone());
+        self.emitted_params = 0;
+        self.events.push(ToolStreamEvent::Start {
+            id,
+            name: self.current_invoke_name.clone(),
+        });
+    }
+
+    /// Close the input JSON of the started invoke, if any, and emit its end.
+    fn stop_invoke(&mut self) {
+        if self.current_invoke_id.take().is_none() {
+            return;
+        }
+        let chunk = match self.emitted_params {
+            0 => "{}",
+            _ => "}",
+        };
+        self.events.push(ToolStreamEvent::InputJson(chunk.into()));
+        self.events.push(ToolStreamEvent::Stop);
+    }
+
     /// Finalize parsing and return any remaining content.
+    ///
+    /// An invoke cut off by the end of the output is not a tool use, but its events are closed
+    /// with the parameters parsed so far, since its start was already emitted.
     pub fn finalize(&mut self) -> ParseResult {
-        let mut result = ParseResult::default();
-
+        self.stop_invoke();
         // Emit any remaining text
-        if !self.text_buffer.is_empty() {
-            result.text = Some(std::mem::take(&mut self.text_buffer));
-        }
-
-        // Emit any completed tools
-        result.tool_uses = std::mem::take(&mut self.completed_tools);
-
-        result
+        self.flush_text();
+        self.take_result()
     }
 
     /// Check if the parser has detected any tool use in the stream.
@@ -837,4 +910,79 @@ mod ai00_parser_tests {
         assert!(parser.has_tool_use());
         assert_eq!(parser.tool_count(), 1);
     }
+
+    #[test]
+    fn test_ai00_streamed_input() {
+        let mut parser = Ai00FunctionCallsParser::new();
+        let output = r#"Checking.
+<ai00:function_calls>
+  <invoke name="get_weather">
+    <parameter name="city">Tokyo</parameter>
+    <parameter name="days">3</parameter>
+  </invoke>
+</ai00:function_calls>"#;
+
+        let mut events = vec![];
+        let mut tool_uses = vec![];
+        for chunk in output.as_bytes().chunks(5) {
+            let result = parser.feed(std::str::from_utf8(chunk).unwrap());
+            events.extend(result.events);
+            tool_uses.extend(result.tool_uses);
+        }
+        let result = parser.finalize();
+        events.extend(result.events);
+        tool_uses.extend(result.tool_uses);
+
+        let start = events
+            .iter()
+            .position(|event| matches!(event, ToolStreamEvent::Start { .. }))
+            .unwrap();
+        let text: String = events[..start]
+            .iter()
+            .map(|event| match event {
+                ToolStreamEvent::Text(text) => text.as_str(),
+                event => panic!("expected text before the call, got {event:?}"),
+            })
+            .collect();
+        assert_eq!(text, "Checking.\n");
+        let ToolStreamEvent::Start { id, name } = &events[start] else {
+            unreachable!()
+        };
+        assert_eq!(name, "get_weather");
+        assert_eq!(id, &tool_uses[0].id);
+
+        // the input arrives in a chunk per parameter, and joins into the parsed input
+        let chunks: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                ToolStreamEvent::InputJson(json) => Some(json.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(chunks, [r#"{"city":"Tokyo""#, r#","days":3"#, "}"]);
+        let input: Value = serde_json::from_str(&chunks.concat()).unwrap();
+        assert_eq!(input, tool_uses[0].input);
+        assert_eq!(events.last(), Some(&ToolStreamEvent::Stop));
+    }
+
+    #[test]
+    fn test_ai00_cut_off_call_is_closed() {
+        let mut parser = Ai00FunctionCallsParser::new();
+        let result = parser.feed(
+            r#"<ai00:function_calls>
+  <invoke name="search">
+    <parameter name="query">rw"#,
+        );
+        assert!(matches!(result.events[..], [ToolStreamEvent::Start { .. }]));
+
+        let result = parser.finalize();
+        assert!(result.tool_uses.is_empty());
+        assert_eq!(
+            result.events,
+            [
+                ToolStreamEvent::InputJson("{}".into()),
+                ToolStreamEvent::Stop
+            ]
+        );
+    }
 }
@@ -0,0 +1,364 @@
+//! Validation of tool call inputs against the `input_schema` of their tools.
+//!
+//! Covers the JSON Schema keywords that tool definitions use in practice: `type`, `enum`, `const`,
+//! `properties`, `required`, `additionalProperties`, `items`, `anyOf`/`oneOf`, `$ref` into the
+//! same schema, and the numeric, length and size bounds. Other keywords are accepted without
+//! being checked.
+
+use serde_json::Value;
+
+use super::types::{ContentBlock, Tool, ToolInputError};
+
+/// Check `input` against `schema`, returning the first violation found.
+pub fn validate_input(schema: &Value, input: &Value) -> Result<(), String> {
+    validate_at(schema, schema, input, "input")
+}
+
+/// Check that a call of the tool `name` with `input` is valid for one of `tools`.
+pub fn validate_tool_use(tools: &[Tool], name: &str, input: &Value) -> Result<(), String> {
+    let Some(tool) = tools.iter().find(|tool| tool.name == name) else {
+        return Err(format!("there is no tool named `{name}`"));
+    };
+    validate_input(&tool.input_schema, input)
+}
+
+/// Check the `tool_use` blocks of a turn against `tools`, returning the invalid calls.
+///
+/// With `strict`, the invalid calls are replaced by text blocks holding the call as JSON.
+pub fn check_tool_inputs(
+    tools: &[Tool],
+    blocks: &mut [ContentBlock],
+    strict: bool,
+) -> Vec<ToolInputError> {
+    let mut errors = Vec::new();
+    for block in blocks.iter_mut() {
+        let ContentBlock::ToolUse { id, name, input } = block else {
+            continue;
+        };
+        let Err(error) = validate_tool_use(tools, name, input) else {
+            continue;
+        };
+        errors.push(ToolInputError {
+            tool_use_id: id.clone(),
+            name: name.clone(),
+            error,
+        });
+        if strict {
+            let call = serde_json::json!({ "name": name, "input": input });
+            *block = ContentBlock::Text {
+                text: call.to_string(),
+            };
+        }
+    }
+    errors
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_i64() || number.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn is_type(value: &Value, ty: &str) -> bool {
+    match (ty, value) {
+        ("number", Value::Number(_)) => true,
+        ("integer", Value::Number(number)) => {
+            number.is_i64() || number.is_u64() || number.as_f64().is_some_and(|x| x.fract() == 0.0)
+        }
+        (ty, value) => type_name(value) == ty,
+    }
+}
+
+/// Check `value` at `path` against `schema`, a part of `root` that `$ref`s point into.
+fn validate_at(root: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        // `true` and `{}` accept anything; `false` nothing
+        return match schema {
+            Value::Bool(false) => Err(format!("{path} is not allowed")),
+            _ => Ok(()),
+        };
+    };
+
+    // references outside the schema are not followed
+    let target = schema
+        .get("$ref")
+        .and_then(Value::as_str)
+        .and_then(|reference| reference.strip_prefix('#'))
+        .and_then(|pointer| root.pointer(pointer));
+    if let Some(target) = target {
+        validate_at(root, target, value, path)?;
+    }
+
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(schemas) = schema.get(keyword).and_then(Value::as_array) {
+            if !schemas
+                .iter()
+                .any(|schema| validate_at(root, schema, value, path).is_ok())
+            {
+                return Err(format!("{path} matches none of the allowed schemas"));
+            }
+        }
+    }
+
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(ty)) => vec![ty.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    if !types.is_empty() && !types.iter().any(|ty| is_type(value, ty)) {
+        return Err(format!(
+            "{path} must be {}, got {}",
+            types.join(" or "),
+            type_name(value)
+        ));
+    }
+
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            let values: Vec<_> = values.iter().map(Value::to_string).collect();
+            return Err(format!("{path} must be one of {}", values.join(", ")));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("{path} must be {expected}"));
+        }
+    }
+
+    let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+    match value {
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if bound("minimum").is_some_and(|min| number < min) {
+                return Err(format!("{path} must be at least {}", schema["minimum"]));
+            }
+            if bound("maximum").is_some_and(|max| number > max) {
+                return Err(format!("{path} must be at most {}", schema["maximum"]));
+            }
+            if bound("exclusiveMinimum").is_some_and(|min| number <= min) {
+                return Err(format!(
+                    "{path} must be greater than {}",
+                    schema["exclusiveMinimum"]
+                ));
+            }
+            if bound("exclusiveMaximum").is_some_and(|max| number >= max) {
+                return Err(format!(
+                    "{path} must be less than {}",
+                    schema["exclusiveMaximum"]
+                ));
+            }
+        }
+        Value::String(string) => {
+            let len = string.chars().count() as f64;
+            if bound("minLength").is_some_and(|min| len < min) {
+                return Err(format!(
+                    "{path} must have at least {} characters",
+                    schema["minLength"]
+                ));
+            }
+            if bound("maxLength").is_some_and(|max| len > max) {
+                return Err(format!(
+                    "{path} must have at most {} characters",
+                    schema["maxLength"]
+                ));
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as f64;
+            if bound("minItems").is_some_and(|min| len < min) {
+                return Err(format!(
+                    "{path} must have at least {} items",
+                    schema["minItems"]
+                ));
+            }
+            if bound("maxItems").is_some_and(|max| len > max) {
+                return Err(format!(
+                    "{path} must have at most {} items",
+                    schema["maxItems"]
+                ));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate_at(root, item_schema, item, &format!("{path}[{index}]"))?;
+                }
+            }
+        }
+        Value::Object(object) => {
+            let required = schema.get("required").and_then(Value::as_array);
+            for name in required.into_iter().flatten().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    return Err(format!("{path}.{name} is required"));
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, value) in object {
+                let path = format!("{path}.{name}");
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(schema) => validate_at(root, schema, value, &path)?,
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            return Err(format!("{path} is not a known property"))
+                        }
+                        Some(schema) => validate_at(root, schema, value, &path)?,
+                        None => {}
+                    },
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn weather_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "location": { "type": "string", "minLength": 1 },
+                "unit": { "type": "string", "enum": ["celsius", "fahrenheit"] },
+                "days": { "type": "integer", "minimum": 1, "maximum": 7 },
+                "tags": { "type": "array", "items": { "type": "string" } }
+            },
+            "required": ["location"],
+            "additionalProperties": false
+        })
+    }
+
+    #[test]
+    fn test_accepts_valid_input() {
+        let input = json!({ "location": "Paris", "unit": "celsius", "days": 3, "tags": ["a"] });
+        assert_eq!(validate_input(&weather_schema(), &input), Ok(()));
+    }
+
+    #[test]
+    fn test_reports_violations_with_path() {
+        let schema = weather_schema();
+        let cases = [
+            (json!({}), "input.location is required"),
+            (
+                json!({ "location": 1 }),
+                "input.location must be string, got integer",
+            ),
+            (
+                json!({ "location": "Paris", "unit": "kelvin" }),
+                "input.unit must be one of \"celsius\", \"fahrenheit\"",
+            ),
+            (
+                json!({ "location": "Paris", "days": 9 }),
+                "input.days must be at most 7",
+            ),
+            (
+                json!({ "location": "Paris", "tags": [1] }),
+                "input.tags[0] must be string, got integer",
+            ),
+            (
+                json!({ "location": "Paris", "when": "now" }),
+                "input.when is not a known property",
+            ),
+        ];
+        for (input, error) in cases {
+            assert_eq!(validate_input(&schema, &input), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_refs_and_exclusive_bounds() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "owner": { "$ref": "#/$defs/person" } },
+            "$defs": {
+                "person": {
+                    "type": "object",
+                    "properties": { "age": { "type": "integer", "exclusiveMinimum": 0 } },
+                    "required": ["age"]
+                }
+            }
+        });
+        assert!(validate_input(&schema, &json!({ "owner": { "age": 30 } })).is_ok());
+        assert_eq!(
+            validate_input(&schema, &json!({ "owner": {} })),
+            Err("input.owner.age is required".into())
+        );
+        assert_eq!(
+            validate_input(&schema, &json!({ "owner": { "age": 0 } })),
+            Err("input.owner.age must be greater than 0".into())
+        );
+    }
+
+    #[test]
+    fn test_check_tool_inputs() {
+        let tools = [Tool {
+            name: "get_weather".into(),
+            description: None,
+            input_schema: weather_schema(),
+            cache_control: None,
+        }];
+        let blocks = vec![
+            ContentBlock::ToolUse {
+                id: "toolu_1".into(),
+                name: "get_weather".into(),
+                input: json!({ "location": "Paris" }),
+            },
+            ContentBlock::ToolUse {
+                id: "toolu_2".into(),
+                name: "get_weather".into(),
+                input: json!({ "location": "Paris", "days": 9 }),
+            },
+        ];
+        let error = ToolInputError {
+            tool_use_id: "toolu_2".into(),
+            name: "get_weather".into(),
+            error: "input.days must be at most 7".into(),
+        };
+
+        let mut warned = blocks.clone();
+        assert_eq!(
+            check_tool_inputs(&tools, &mut warned, false),
+            [error.clone()]
+        );
+        assert!(matches!(warned[1], ContentBlock::ToolUse { .. }));
+
+        let mut strict = blocks;
+        assert_eq!(check_tool_inputs(&tools, &mut strict, true), [error]);
+        assert!(matches!(strict[0], ContentBlock::ToolUse { .. }));
+        match &strict[1] {
+            ContentBlock::Text { text } => assert_eq!(
+                serde_json::from_str::<Value>(text).unwrap(),
+                json!({ "name": "get_weather", "input": { "location": "Paris", "days": 9 } })
+            ),
+            block => panic!("expected a text block, got {block:?}"),
+        }
+    }
+
+    #[test]
+    fn test_any_of_and_unknown_tools() {
+        let schema = json!({ "anyOf": [{ "type": "string" }, { "type": "null" }] });
+        assert!(validate_input(&schema, &json!(null)).is_ok());
+        assert!(validate_input(&schema, &json!(1)).is_err());
+
+        let tools = [Tool {
+            name: "get_weather".into(),
+            description: None,
+            input_schema: weather_schema(),
+            cache_control: None,
+        }];
+        let input = json!({ "location": "Paris" });
+        assert!(validate_tool_use(&tools, "get_weather", &input).is_ok());
+        assert_eq!(
+            validate_tool_use(&tools, "get_time", &input),
+            Err("there is no tool named `get_time`".into())
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Fitting the prompts of `/v1/messages` into `generation.max_context_tokens`.
+//!
+//! Prompts that are too long lose whole turns from the start of the conversation, a turn being a
+//! user message and everything up to the next one. The current turn is never dropped. With
+//! `truncation = "summarize"` the model writes a summary of the dropped turns, which takes their
+//! place at the start of the conversation.
+//!
+//! The output ends with `max_tokens` as the stop reason where the context does, unless the
+//! request sets `allow_exceed_context`: RWKV is recurrent and can go on past the context it was
+//! trained on, so such requests are only held to `max_exceed_tokens`, with their state
+//! checkpointed every `checkpoint_interval` tokens.
+
+use std::sync::Arc;
+
+use ai00_core::{GenerateRequest, ThreadRequest, Token};
+use futures_util::StreamExt;
+use salvo::Depot;
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::{
+    handler::{prompt_error, select_prompts},
+    prompt::build_prompt,
+    types::{MessageContent, MessageParam, MessageRole, MessagesRequest, TruncationMetadata},
+};
+use crate::{
+    api::{error::ApiErrorResponse, request_info_of, usage::Attribution},
+    config::{Config, GenerationOption, PromptsConfig, Truncation},
+    types::ThreadSender,
+    SLEEP,
+};
+
+/// Asks the model for the summary, followed by the dropped turns.
+const SUMMARY_INSTRUCTION: &str = "Summarize the following conversation in a few sentences, \
+    keeping the facts, names and decisions that later messages may refer to.";
+
+/// Counts the prompt tokens of a request.
+struct PromptCounter<'a> {
+    request: &'a MessagesRequest,
+    prompts: &'a PromptsConfig,
+    tokenizer: &'a Tokenizer,
+}
+
+impl PromptCounter<'_> {
+    /// Tokens of the prompt of the request with only `messages`.
+    fn count(&self, messages: &[MessageParam]) -> Result<usize, ApiErrorResponse> {
+        let prompt = build_prompt(
+            self.request.system.as_deref(),
+            messages,
+            self.request.tools.as_deref(),
+            self.request.thinking.as_ref(),
+            self.prompts,
+        )
+        .map_err(prompt_error)?;
+        let tokens = self.tokenizer.encode(prompt.as_bytes()).map_err(|err| {
+            ApiErrorResponse::api_error(format!("failed to tokenize prompt: {err}"))
+        })?;
+        Ok(tokens.len())
+    }
+}
+
+/// Indices of the messages that start a turn: user messages that are not only tool results.
+fn turn_starts(messages: &[MessageParam]) -> Vec<usize> {
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.role == MessageRole::User && !m.content.is_tool_result_only())
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// End the output at the end of the context, unless the request may exceed it.
+fn limit_output(request: &mut MessagesRequest, remaining: usize) {
+    if !request.allow_exceed_context {
+        request.max_tokens = request.max_tokens.min(remaining.max(1));
+    }
+}
+
+/// Output tokens between the state checkpoints of the request, if it exceeds the context.
+pub(super) fn checkpoint_interval(
+    option: &GenerationOption,
+    request: &MessagesRequest,
+) -> Option<usize> {
+    request
+        .allow_exceed_context
+        .then_some(option.checkpoint_interval)
+}
+
+/// Shorten the messages of `request` until its prompt fits the context, and its output to the
+/// rest of the context, as `generation` configures. Returns what was dropped, if anything.
+pub(super) async fn fit_context(
+    depot: &Depot,
+    request: &mut MessagesRequest,
+) -> Result<Option<TruncationMetadata>, ApiErrorResponse> {
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let option = &config.generation;
+    if request.allow_exceed_context {
+        request.max_tokens = request.max_tokens.min(option.max_exceed_tokens);
+    }
+    let Some(max_tokens) = option.max_context_tokens else {
+        return Ok(None);
+    };
+    let prompts = select_prompts(config, request.prompt_profile())?;
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let info = request_info_of(sender.clone(), &request.model, SLEEP).await?;
+
+    let counter = PromptCounter {
+        request,
+        prompts,
+        tokenizer: &info.tokenizer,
+    };
+    let original_tokens = counter.count(&request.messages)?;
+    if original_tokens <= max_tokens {
+        limit_output(request, max_tokens - original_tokens);
+        return Ok(None);
+    }
+    let too_long = |tokens: usize| {
+        ApiErrorResponse::invalid_request(format!(
+            "prompt has {tokens} tokens, more than the {max_tokens} of the context"
+        ))
+        .with_param("messages")
+    };
+
+    // room for the summary, which is only known once the turns to drop are
+    let reserved = match option.truncation {
+        Truncation::Error => return Err(too_long(original_tokens)),
+        Truncation::DropOldest => 0,
+        Truncation::Summarize => option.summary_max_tokens,
+    };
+    let mut start = None;
+    for index in turn_starts(&request.messages) {
+        if index > 0 && counter.count(&request.messages[index..])? + reserved <= max_tokens {
+            start = Some(index);
+            break;
+        }
+    }
+    let Some(start) = start else {
+        return Err(too_long(original_tokens));
+    };
+
+    let dropped: Vec<_> = request.messages.drain(..start).collect();
+    let summary = match option.truncation {
+        Truncation::Summarize => {
+            let summary = summarize(
+                sender,
+                &Attribution::of(depot),
+                info.tokenizer.clone(),
+                request.model.clone(),
+                &dropped,
+                prompts,
+                option.summary_max_tokens,
+            )
+            .await?;
+            let text = format!("Summary of the earlier conversation:\n{summary}");
+            request.messages.insert(
+                0,
+                MessageParam {
+                    role: MessageRole::User,
+                    content: MessageContent::Text(text),
+                },
+            );
+            Some(summary)
+        }
+        _ => None,
+    };
+
+    let counter = PromptCounter {
+        request,
+        prompts,
+        tokenizer: &info.tokenizer,
+    };
+    let prompt_tokens = counter.count(&request.messages)?;
+    if prompt_tokens > max_tokens {
+        return Err(too_long(prompt_tokens));
+    }
+    limit_output(request, max_tokens - prompt_tokens);
+    tracing::info!(
+        event = "prompt_truncated",
+        dropped_messages = dropped.len(),
+        original_tokens,
+        prompt_tokens,
+    );
+    Ok(Some(TruncationMetadata {
+        dropped_messages: dropped.len(),
+        original_tokens,
+        prompt_tokens,
+        summary,
+    }))
+}
+
+/// Have the model summarize `messages`.
+async fn summarize(
+    sender: &ThreadSender,
+    attribution: &Attribution,
+    tokenizer: Arc<Tokenizer>,
+    model: String,
+    messages: &[MessageParam],
+    prompts: &PromptsConfig,
+    max_tokens: usize,
+) -> Result<String, ApiErrorResponse> {
+    let transcript = messages
+        .iter()
+        .map(|m| {
+            let role = match m.role {
+                MessageRole::User => "User",
+                MessageRole::Assistant => "Assistant",
+            };
+            format!("{role}: {}", m.content.to_text())
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let message = MessageParam {
+        role: MessageRole::User,
+        content: MessageContent::Text(format!("{SUMMARY_INSTRUCTION}\n\n{transcript}")),
+    };
+    let prompt = build_prompt(None, &[message], None, None, prompts).map_err(prompt_error)?;
+    let mut request = GenerateRequest {
+        prompt,
+        max_tokens,
+        stop: prompts.default_stop_sequences.clone(),
+        model: Some(model),
+        ..Default::default()
+    };
+    attribution.apply(&mut request);
+
+    let (token_sender, token_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Generate {
+        request: Box::new(request),
+        tokenizer,
+        sender: token_sender,
+    });
+    let mut summary = String::new();
+    let mut stream = token_receiver.into_stream();
+    while let Some(token) = stream.next().await {
+        match token {
+            Token::Content(content) => summary += &content,
+            Token::Stop(..) | Token::Done => break,
+            _ => {}
+        }
+    }
+    match summary.trim() {
+        "" => Err(ApiErrorResponse::api_error(
+            "failed to summarize the dropped messages",
+        )),
+        summary => Ok(summary.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: MessageRole, text: &str) -> MessageParam {
+        MessageParam {
+            role,
+            content: MessageContent::Text(text.into()),
+        }
+    }
+
+    #[test]
+    fn test_turn_starts() {
+        let tool_result: MessageParam = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [{"type": "tool_result", "tool_use_id": "t1", "content": "22"}]
+        }))
+        .unwrap();
+        let messages = [
+            message(MessageRole::User, "Weather?"),
+            message(MessageRole::Assistant, "Let me check."),
+            tool_result,
+            message(MessageRole::Assistant, "It is 22."),
+            message(MessageRole::User, "Thanks"),
+        ];
+        assert_eq!(turn_starts(&messages), [0, 4]);
+    }
+
+    #[test]
+    fn test_count_shrinks_with_turns() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../assets/tokenizer/rwkv_vocab_v20230424.json"
+        );
+        let tokenizer = Tokenizer::new(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let request: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "rwkv",
+            "max_tokens": 16,
+            "messages": [
+                {"role": "user", "content": "Tell me a long story."},
+                {"role": "assistant", "content": "Once upon a time there was a raven."},
+                {"role": "user", "content": "Shorter."}
+            ]
+        }))
+        .unwrap();
+        let prompts = PromptsConfig::default();
+        let counter = PromptCounter {
+            request: &request,
+            prompts: &prompts,
+            tokenizer: &tokenizer,
+        };
+        let all = counter.count(&request.messages).unwrap();
+        let last = counter.count(&request.messages[2..]).unwrap();
+        assert!(last > 0 && last < all);
+    }
+
+    #[test]
+    fn test_limit_output() {
+        let mut request: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "rwkv",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": "Hi"}]
+        }))
+        .unwrap();
+        let option = GenerationOption::default();
+        assert_eq!(checkpoint_interval(&option, &request), None);
+
+        // the output ends with the context, leaving it at least a token
+        limit_output(&mut request, 1000);
+        assert_eq!(request.max_tokens, 100);
+        limit_output(&mut request, 40);
+        assert_eq!(request.max_tokens, 40);
+        limit_output(&mut request, 0);
+        assert_eq!(request.max_tokens, 1);
+
+        // unless the request exceeds it, with checkpoints on the way
+        request.max_tokens = 100;
+        request.allow_exceed_context = true;
+        limit_output(&mut request, 40);
+        assert_eq!(request.max_tokens, 100);
+        assert_eq!(
+            checkpoint_interval(&option, &request),
+            Some(option.checkpoint_interval)
+        );
+    }
+}
@@ -3,10 +3,14 @@
 //! These types match the Anthropic Messages API format for compatibility
 //! with Claude API clients (e.g., LibreChat with `defaultParamsEndpoint: 'anthropic'`).
 
+use std::collections::HashMap;
+
+use ai00_core::{reload::TrafficClass, StateId};
 use lazy_static::lazy_static;
 use regex::Regex;
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
+use web_rwkv::tokenizer::Tokenizer;
 
 lazy_static! {
     /// Regex for validating tool names: 1-64 chars, alphanumeric plus underscore/hyphen.
@@ -77,6 +81,28 @@ pub enum ContentBlock {
         /// Placeholder signature (hash-based, not Anthropic-compatible)
         signature: String,
     },
+
+    /// Image from the client.
+    ///
+    /// RWKV models read text only: images are rejected, or replaced by the caption of the
+    /// captioner configured in `[vision]` before the prompt is built.
+    #[serde(rename = "image")]
+    Image { source: ImageSource },
+}
+
+/// Source of an image block.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImageSource {
+    /// Inline image data
+    Base64 {
+        /// e.g. `image/png`
+        media_type: String,
+        /// Base64-encoded image
+        data: String,
+    },
+    /// Image to be fetched from a URL
+    Url { url: String },
 }
 
 /// Tool result content - can be string or array of content blocks.
@@ -146,19 +172,23 @@ impl MessageContent {
     /// - ToolResult becomes `<ai00:function_results><result name="...">...</result></ai00:function_results>`
     /// - Thinking becomes `<think>...</think>` for training data alignment
     pub fn to_text(&self) -> String {
+        // Wrap thinking in <think> tags for training data format
+        self.to_text_with(|thinking| Some(format!("<think>{}</think>", thinking)))
+    }
+
+    /// Like [`to_text`](Self::to_text), with thinking blocks rendered by `render_thinking`,
+    /// or left out where it returns `None`.
+    pub fn to_text_with(&self, render_thinking: impl Fn(&str) -> Option<String>) -> String {
         match self {
             MessageContent::Text(s) => s.clone(),
             MessageContent::Blocks(blocks) => blocks
                 .iter()
-                .map(|b| match b {
-                    ContentBlock::Text { text } => text.clone(),
-                    ContentBlock::Thinking { thinking, .. } => {
-                        // Wrap thinking in <think> tags for training data format
-                        format!("<think>{}</think>", thinking)
-                    }
+                .filter_map(|b| match b {
+                    ContentBlock::Text { text } => Some(text.clone()),
+                    ContentBlock::Thinking { thinking, .. } => render_thinking(thinking),
                     ContentBlock::ToolUse { name, input, .. } => {
                         // Format as ai00 function_calls for context in continued conversations
-                        format_tool_use_as_ai00(name, input)
+                        Some(format_tool_use_as_ai00(name, input))
                     }
                     ContentBlock::ToolResult {
                         tool_use_id,
@@ -166,8 +196,10 @@ impl MessageContent {
                         is_error,
                     } => {
                         // Format as ai00 function_results
-                        format_tool_result_as_ai00(tool_use_id, content, *is_error)
+                        Some(format_tool_result_as_ai00(tool_use_id, content, *is_error))
                     }
+                    // images are captioned or rejected before prompts are built
+                    ContentBlock::Image { .. } => Some("[image]".into()),
                 })
                 .collect::<Vec<_>>()
                 .join("\n"),
@@ -263,6 +295,10 @@ pub enum StopReason {
     StopSequence,
     /// Model invoked a tool
     ToolUse,
+    /// Wall-clock limit of the request reached
+    Timeout,
+    /// Output flagged by moderation
+    Refusal,
     /// API response still in progress
     #[default]
     #[serde(untagged)]
@@ -275,7 +311,9 @@ impl From<ai00_core::FinishReason> for StopReason {
         match reason {
             ai00_core::FinishReason::Stop => StopReason::EndTurn,
             ai00_core::FinishReason::Length => StopReason::MaxTokens,
-            ai00_core::FinishReason::ContentFilter => StopReason::EndTurn,
+            ai00_core::FinishReason::Timeout => StopReason::Timeout,
+            ai00_core::FinishReason::ContentFilter => StopReason::Refusal,
+            ai00_core::FinishReason::StopSequence(_) => StopReason::StopSequence,
             ai00_core::FinishReason::Null => StopReason::Null,
         }
     }
@@ -288,21 +326,44 @@ pub struct Usage {
     pub input_tokens: usize,
     /// Tokens in the output/completion
     pub output_tokens: usize,
-    /// Cache tokens (always 0 for RWKV - no caching)
+    /// Input tokens prefilled and pinned in the cache for the `cache_control` prefix
     #[serde(default)]
     pub cache_creation_input_tokens: usize,
-    /// Cache read tokens (always 0 for RWKV)
+    /// Input tokens whose state came from the prompt cache instead of being prefilled
     #[serde(default)]
     pub cache_read_input_tokens: usize,
+    /// Tools the server ran itself in agentic mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_tool_use: Option<ServerToolUsage>,
+}
+
+/// Usage of the tools of the server.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ServerToolUsage {
+    /// Tool calls the server answered
+    pub requests: usize,
 }
 
+impl Usage {
+    /// Count the calls the server answered; nothing is reported if there were none.
+    pub fn with_server_tool_requests(self, requests: usize) -> Self {
+        Self {
+            server_tool_use: (requests > 0).then_some(ServerToolUsage { requests }),
+            ..self
+        }
+    }
+}
+
+/// Like the Anthropic API, `input_tokens` leaves out the tokens counted as cache creation or read.
 impl From<ai00_core::TokenCounter> for Usage {
     fn from(counter: ai00_core::TokenCounter) -> Self {
+        let cached = counter.cache_creation + counter.cache_read;
         Self {
-            input_tokens: counter.prompt,
+            input_tokens: counter.prompt.saturating_sub(cached),
             output_tokens: counter.completion,
-            cache_creation_input_tokens: 0,
-            cache_read_input_tokens: 0,
+            cache_creation_input_tokens: counter.cache_creation,
+            cache_read_input_tokens: counter.cache_read,
+            server_tool_use: None,
         }
     }
 }
@@ -322,7 +383,7 @@ pub struct Tool {
     /// JSON Schema for the tool's input parameters
     pub input_schema: serde_json::Value,
 
-    /// Cache control settings (forward compatibility - ignored)
+    /// Cache control settings; any value caches the system prompt the tool is part of
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub cache_control: Option<serde_json::Value>,
 }
@@ -510,6 +571,41 @@ impl BnfValidationLevel {
     }
 }
 
+/// Output format of the response.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Free-form text (no constraint).
+    #[default]
+    Text,
+    /// Any JSON object.
+    JsonObject,
+    /// JSON matching the given schema.
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+/// Schema of a `json_schema` response format.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JsonSchemaFormat {
+    /// Name of the schema (informational)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// The JSON Schema the output must match
+    pub schema: serde_json::Value,
+
+    /// Accepted for compatibility; the output always follows the schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+impl ResponseFormat {
+    /// Check if the format constrains the output.
+    pub fn is_json(&self) -> bool {
+        !matches!(self, ResponseFormat::Text)
+    }
+}
+
 /// How the model should choose which tool to use.
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(untagged)]
@@ -518,6 +614,48 @@ pub enum ToolChoice {
     Simple(ToolChoiceSimple),
     /// Specific tool choice
     Specific(ToolChoiceSpecific),
+    /// Object form of the simple choices, as sent by the official SDKs: `{"type": "auto"}`
+    Typed(ToolChoiceTyped),
+}
+
+impl ToolChoice {
+    /// The simple choice this stands for; `None` for a specific tool.
+    pub fn simple(&self) -> Option<ToolChoiceSimple> {
+        match self {
+            ToolChoice::Simple(choice) => Some(*choice),
+            ToolChoice::Typed(typed) => Some(typed.choice_type),
+            ToolChoice::Specific(_) => None,
+        }
+    }
+
+    /// Value of `disable_parallel_tool_use`, if sent.
+    pub fn disable_parallel_tool_use(&self) -> Option<bool> {
+        match self {
+            ToolChoice::Simple(_) => None,
+            ToolChoice::Specific(specific) => specific.disable_parallel_tool_use,
+            ToolChoice::Typed(typed) => typed.disable_parallel_tool_use,
+        }
+    }
+
+    /// Whether the model must call a tool: `any` or a specific tool.
+    pub fn forces_tool_use(&self) -> bool {
+        matches!(self, ToolChoice::Specific(_)) || self.simple() == Some(ToolChoiceSimple::Any)
+    }
+
+    /// Name of the forced tool, if a specific one is.
+    pub fn tool_name(&self) -> Option<&str> {
+        match self {
+            ToolChoice::Specific(specific) => Some(&specific.name),
+            _ => None,
+        }
+    }
+
+    /// Whether a turn may call at most one tool.
+    ///
+    /// This is the case if `disable_parallel_tool_use` is set, or if a specific tool is forced.
+    pub fn is_single_tool_use(&self) -> bool {
+        matches!(self, ToolChoice::Specific(_)) || self.disable_parallel_tool_use() == Some(true)
+    }
 }
 
 impl Default for ToolChoice {
@@ -547,7 +685,18 @@ pub struct ToolChoiceSpecific {
     pub choice_type: String,
     /// Name of the tool to use
     pub name: String,
-    /// Whether to disable parallel tool use (forward compatibility)
+    /// Whether the model may call at most one tool in a turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disable_parallel_tool_use: Option<bool>,
+}
+
+/// Object form of a simple tool choice.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ToolChoiceTyped {
+    /// "auto", "none" or "any"
+    #[serde(rename = "type")]
+    pub choice_type: ToolChoiceSimple,
+    /// Whether the model may call at most one tool in a turn
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub disable_parallel_tool_use: Option<bool>,
 }
@@ -563,6 +712,87 @@ impl ToolChoiceSpecific {
     }
 }
 
+/// System prompt, and whether it is marked for prompt caching.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SystemPrompt {
+    pub text: String,
+    /// Whether a block of the system prompt carries `cache_control`.
+    pub cache_control: bool,
+}
+
+impl std::ops::Deref for SystemPrompt {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.text
+    }
+}
+
+impl From<String> for SystemPrompt {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            cache_control: false,
+        }
+    }
+}
+
+impl From<&str> for SystemPrompt {
+    fn from(text: &str) -> Self {
+        text.to_string().into()
+    }
+}
+
+impl Serialize for SystemPrompt {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.cache_control {
+            true => serde_json::json!([{
+                "type": "text",
+                "text": self.text,
+                "cache_control": { "type": "ephemeral" }
+            }])
+            .serialize(serializer),
+            false => self.text.serialize(serializer),
+        }
+    }
+}
+
+/// Deserialize the system prompt from either a string or an array of text blocks.
+///
+/// The official SDKs send the array form, e.g. when blocks carry `cache_control`.
+/// The blocks are joined with blank lines.
+pub(super) fn deserialize_system<'de, D>(deserializer: D) -> Result<Option<SystemPrompt>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum System {
+        Text(String),
+        Blocks(Vec<SystemBlock>),
+    }
+
+    #[derive(Deserialize)]
+    struct SystemBlock {
+        text: String,
+        #[serde(default)]
+        cache_control: Option<serde_json::Value>,
+    }
+
+    let system = Option::<System>::deserialize(deserializer)?.map(|system| match system {
+        System::Text(text) => text.into(),
+        System::Blocks(blocks) => SystemPrompt {
+            cache_control: blocks.iter().any(|block| block.cache_control.is_some()),
+            text: blocks
+                .into_iter()
+                .map(|block| block.text)
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+        },
+    });
+    Ok(system)
+}
+
 /// Messages API request.
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct MessagesRequest {
@@ -572,9 +802,11 @@ pub struct MessagesRequest {
     /// Conversation messages (roles: "user" | "assistant" only)
     pub messages: Vec<MessageParam>,
 
-    /// System prompt (top-level, NOT a message role)
-    #[serde(default)]
-    pub system: Option<String>,
+    /// System prompt (top-level, NOT a message role).
+    /// A string, or text blocks that may carry `cache_control`.
+    #[serde(default, deserialize_with = "deserialize_system")]
+    #[salvo(schema(value_type = Option<String>))]
+    pub system: Option<SystemPrompt>,
 
     /// Maximum tokens to generate (required)
     pub max_tokens: usize,
@@ -587,6 +819,23 @@ pub struct MessagesRequest {
     #[serde(default)]
     pub stop_sequences: Option<Vec<String>>,
 
+    /// Token IDs that stop the generation, e.g. `[0, 261]`.
+    /// The stop token is not part of the output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_tokens: Option<Vec<u32>>,
+
+    /// Raw byte sequences that stop the generation, for stops that are not valid UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_bytes: Option<Vec<Vec<u8>>>,
+
+    /// Phrases the output must not contain.
+    ///
+    /// Output that may be starting one is held back; when it completes one, the model goes
+    /// back to where the phrase started and samples again without that token. Cannot be
+    /// combined with grammars (`bnf_schema`, `regex`, `response_format` or tool grammars).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub banned_phrases: Vec<String>,
+
     /// Sampling temperature (0.0 - 1.0)
     #[serde(default)]
     pub temperature: Option<f32>,
@@ -599,11 +848,62 @@ pub struct MessagesRequest {
     #[serde(default)]
     pub top_k: Option<usize>,
 
+    /// Min-p sampling: drop tokens less likely than `min_p` times the most likely one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+
+    /// Locally typical sampling mass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typical_p: Option<f32>,
+
+    /// Penalty subtracted from the logits of tokens that already appeared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+
+    /// Penalty subtracted from the logits of tokens per appearance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+
+    /// Multiplicative penalty of recently generated tokens, fading with distance (1.0 disables)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+
+    /// Number of most recent tokens covered by `repetition_penalty`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repetition_window: Option<usize>,
+
+    /// Dynamic temperature: scale the temperature within `temperature ± dynatemp_range`
+    /// by the entropy of each step (0 disables)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dynatemp_range: Option<f32>,
+
+    /// Exponent applied to the normalized entropy when scaling the temperature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dynatemp_exponent: Option<f32>,
+
+    /// Bias added to the logits of the tokens of each string, from -100 (never) to 100.
+    ///
+    /// Strings are tokenized as they would appear in the output, so `"yes"` and `" yes"` are
+    /// different tokens.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub logit_bias: HashMap<String, f32>,
+
+    /// Tokens of a `logit_bias` string of several tokens that are biased
+    #[serde(default)]
+    pub logit_bias_tokens: LogitBiasTokens,
+
     /// Tools available for the model to use
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tools: Option<Vec<Tool>>,
 
     /// How the model should choose which tool to use
+    ///
+    /// `any` and a specific tool are enforced by a grammar that makes the output begin with a
+    /// function calls block, so they require `bnf_validation` other than `none`.
+    ///
+    /// With `disable_parallel_tool_use` or a specific tool, at most one `tool_use` block is
+    /// returned. Generated grammars (`Structural` and `SchemaAware`) then allow a single
+    /// `<invoke>`; with a custom `bnf_schema`, further calls are dropped after parsing.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<ToolChoice>,
 
@@ -611,7 +911,9 @@ pub struct MessagesRequest {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub thinking: Option<ThinkingConfig>,
 
-    /// Metadata for request tracking (forward compatibility - ignored)
+    /// Metadata for request tracking.
+    /// `prompt_profile` names the prompt profile to format the prompt with.
+    /// `conversation_id` continues the state of the conversation's previous turn.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub metadata: Option<serde_json::Value>,
 
@@ -636,6 +938,262 @@ pub struct MessagesRequest {
     /// Set explicitly to `none` to disable auto-generation.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub bnf_validation: Option<BnfValidationLevel>,
+
+    /// Text that may end a text response under a generated grammar, in place of the stop
+    /// sequences, e.g. `["</answer>"]`.
+    ///
+    /// The generated grammars end text responses with one of the stop sequences. This sets
+    /// the terminators of the grammar on their own, so `stop_sequences` keeps deciding where
+    /// the text is cut.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grammar_terminators: Option<Vec<String>>,
+
+    /// Regular expression the whole output must match, e.g. `\d{4}-\d{2}-\d{2}` for a date.
+    ///
+    /// Generation stops once the match is complete. Cannot be combined with tools.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+
+    /// Constrain the output to JSON, optionally matching a schema.
+    ///
+    /// A grammar is generated from the format, so it cannot be combined with tools or `bnf_schema`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+
+    /// Canned tool results by tool name, for dry runs of multi-step tool flows.
+    ///
+    /// When the model stops to call tools that all have an entry here, the results are injected
+    /// and generation continues within the same request, for a bounded number of rounds.
+    /// The response contains the content of every round. Not supported with streaming.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_results_preview: Option<HashMap<String, ToolResultContent>>,
+
+    /// Regenerate up to this many times when a tool call cannot be parsed or its input does not
+    /// match the tool's `input_schema`, telling the model what was wrong each time.
+    ///
+    /// Only the last attempt is returned; usage covers all of them. Not supported with streaming.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_retries: Option<usize>,
+
+    /// What to do with tool calls whose `input` does not match the tool's `input_schema`.
+    ///
+    /// The errors are listed in `metadata.tool_input_errors`. With `strict`, the invalid calls
+    /// are also returned as text blocks holding the call, and the stop reason becomes `end_turn`
+    /// if no valid call is left. Only checked without streaming.
+    #[serde(default)]
+    pub tool_input_validation: ToolInputValidation,
+
+    /// Traffic class for sharing decode throughput.
+    /// Defaults to `interactive` for streamed requests and `batch` otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic_class: Option<TrafficClass>,
+
+    /// Milliseconds the generation may take, from when it is queued. When they run out, the
+    /// output so far is returned with `stop_reason: "timeout"`. Defaults to `timeout_ms` of
+    /// `[generation]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+
+    /// Most output tokens generated per second. The generation is held back to stay under it,
+    /// and under `max_tokens_per_second` of the API key. Unlimited if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens_per_second: Option<f32>,
+
+    /// Keep generating past `max_context_tokens` of `[generation]`, up to `max_tokens` but no
+    /// more than its `max_exceed_tokens`. The state is checkpointed on the way, and the
+    /// generation ends with `max_tokens` as the stop reason if it drifts.
+    #[serde(default)]
+    pub allow_exceed_context: bool,
+
+    /// LoRA adapter of `model` that serves the request, as registered in `[[lora_adapters]]`
+    /// or through `/admin/models/adapters`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+
+    /// Start from a state stored through `/api/states` instead of the model's initial state.
+    /// Only the messages of this request are prefilled on top of it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_id: Option<StateId>,
+
+    /// Disable server-side parsing of thinking and tool calls.
+    ///
+    /// The model output, including `<think>` and `<ai00:function_calls>` markup, is returned
+    /// verbatim as text. Grammars still apply, so clients can run their own parsers.
+    #[serde(default)]
+    pub raw_mode: bool,
+
+    /// Let the server run the calls of its own tools, configured in `[tools]`.
+    ///
+    /// The server tools are offered next to those of the request. Their calls are answered and
+    /// the model generates again, until it answers or calls a tool of the client. The response
+    /// holds the `tool_use` blocks of all rounds. Not supported with streaming.
+    #[serde(default)]
+    pub agentic: bool,
+
+    /// Seed of the sampler, for reproducing a generation exactly.
+    ///
+    /// The same seed, prompt, model and sampling parameters produce the same output. A seed is
+    /// chosen at random if not given; either way it is returned in `metadata.seed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+
+    /// Debugging output of the generation, for developing grammars and prompts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug: Option<DebugOptions>,
+
+    /// Report where the time of the generation went in `metadata.timings`. Streaming responses
+    /// report it in a `message_metadata` event before `message_stop`.
+    #[serde(default)]
+    pub return_timings: bool,
+}
+
+impl MessagesRequest {
+    /// Tokenize the strings of `logit_bias` into the bias of their tokens.
+    ///
+    /// Tokens shared by several strings get the sum of their biases.
+    pub fn token_bias(&self, tokenizer: &Tokenizer) -> anyhow::Result<HashMap<u32, f32>> {
+        let mut bias = HashMap::new();
+        for (text, value) in &self.logit_bias {
+            let tokens = tokenizer
+                .encode(text.as_bytes())
+                .map_err(|err| anyhow::anyhow!("cannot tokenize `{text}`: {err}"))?;
+            let tokens = match self.logit_bias_tokens {
+                LogitBiasTokens::First => &tokens[..tokens.len().min(1)],
+                LogitBiasTokens::All => &tokens[..],
+            };
+            for &token in tokens {
+                *bias.entry(token).or_default() += value;
+            }
+        }
+        Ok(bias)
+    }
+
+    /// The prompt profile named in `metadata.prompt_profile`, if any.
+    pub fn prompt_profile(&self) -> Option<&str> {
+        self.metadata.as_ref()?.get("prompt_profile")?.as_str()
+    }
+
+    /// Whether the request asks for `debug.grammar_trace`.
+    pub fn grammar_trace(&self) -> bool {
+        self.debug.as_ref().is_some_and(|debug| debug.grammar_trace)
+    }
+
+    /// The conversation named in `metadata.conversation_id`, if any.
+    pub fn conversation_id(&self) -> Option<&str> {
+        self.metadata.as_ref()?.get("conversation_id")?.as_str()
+    }
+
+    /// Name the prompt profile in `metadata.prompt_profile`, unless the metadata names one.
+    pub fn set_default_prompt_profile(&mut self, name: &str) {
+        let metadata = self
+            .metadata
+            .get_or_insert_with(|| serde_json::Value::Object(Default::default()));
+        if let Some(metadata) = metadata.as_object_mut() {
+            metadata
+                .entry("prompt_profile")
+                .or_insert_with(|| name.into());
+        }
+    }
+}
+
+/// Handling of tool calls whose input does not match the `input_schema` of their tool.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolInputValidation {
+    /// Return the calls as they are
+    #[default]
+    Warn,
+    /// Return the calls as text blocks instead
+    Strict,
+}
+
+/// Debugging output a [`MessagesRequest`] asks for.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct DebugOptions {
+    /// Stream a `grammar_trace` SSE comment before the text of every token sampled under the
+    /// grammar (BNF schema, regex or tools), with the number of tokens the grammar allowed and
+    /// masked, and its state after accepting the token. Only streaming requests are traced.
+    pub grammar_trace: bool,
+}
+
+/// Tokens of a `logit_bias` string of several tokens that are biased.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogitBiasTokens {
+    /// Only the first token, which decides whether the string is started
+    #[default]
+    First,
+    /// Every token of the string
+    All,
+}
+
+/// Token counting request: the prompt fields of a [`MessagesRequest`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CountTokensRequest {
+    /// Model identifier
+    pub model: String,
+
+    /// Conversation messages (roles: "user" | "assistant" only)
+    pub messages: Vec<MessageParam>,
+
+    /// System prompt
+    #[serde(default, deserialize_with = "deserialize_system")]
+    #[salvo(schema(value_type = Option<String>))]
+    pub system: Option<SystemPrompt>,
+
+    /// Tool definitions, which are part of the system prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+
+    /// Accepted for compatibility; does not change the prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+
+    /// Extended thinking configuration, which changes the end of the prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<ThinkingConfig>,
+}
+
+/// Token counting response.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CountTokensResponse {
+    /// Tokens of the prompt that a request with these fields would be given
+    pub input_tokens: usize,
+}
+
+/// What a messages request would be generated with, answered by `/api/prompt/preview`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PromptPreview {
+    /// The prompt exactly as the model would be given it
+    pub prompt: String,
+
+    /// Leading part of the prompt pinned in the cache, from `cache_control` breakpoints
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_prefix: Option<String>,
+
+    /// Tokens of the prompt
+    pub input_tokens: usize,
+
+    /// Stop sequences, from the request or the prompt profile
+    pub stop_sequences: Vec<String>,
+
+    /// Token IDs that stop the generation
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_tokens: Vec<u32>,
+
+    /// Grammar the output is constrained to, given or generated for tools, thinking
+    /// and `response_format`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bnf_schema: Option<String>,
+
+    /// Regular expression the output must match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+
+    /// Retrieved context and seed, as a response would report them
+    #[serde(default, skip_serializing_if = "ResponseMetadata::is_empty")]
+    pub metadata: ResponseMetadata,
 }
 
 /// Messages API response.
@@ -666,6 +1224,112 @@ pub struct MessagesResponse {
 
     /// Token usage statistics
     pub usage: Usage,
+
+    /// How the server produced the response, beyond the Claude API
+    #[serde(default, skip_serializing_if = "ResponseMetadata::is_empty")]
+    pub metadata: ResponseMetadata,
+}
+
+/// Server-specific information about a response.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ResponseMetadata {
+    /// Context retrieved for a request with `metadata.rag`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rag: Option<RagMetadata>,
+    /// Seed the sampler was seeded with; pass it as `seed` to reproduce the response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Turns dropped to fit the prompt into the context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub truncation: Option<TruncationMetadata>,
+    /// Tool calls whose input does not match the `input_schema` of their tool
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_input_errors: Vec<ToolInputError>,
+    /// Where the time of the generation went, for a request with `return_timings`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<ResponseTimings>,
+}
+
+impl ResponseMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.rag.is_none()
+            && self.seed.is_none()
+            && self.truncation.is_none()
+            && self.tool_input_errors.is_empty()
+            && self.timings.is_none()
+    }
+}
+
+/// Time spent in each phase of the generation, summed over the rounds of tool calls.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ResponseTimings {
+    /// Waiting for a slot
+    pub queue_ms: u64,
+    /// Prefilling the prompt tokens that missed the cache
+    pub prefill_ms: u64,
+    /// Generating the output
+    pub decode_ms: u64,
+    /// Prefill and decode together, from when the generation got its slot
+    pub total_ms: u64,
+}
+
+impl From<&ai00_core::TokenCounter> for ResponseTimings {
+    fn from(counter: &ai00_core::TokenCounter) -> Self {
+        let prefill_ms = counter.prefill.as_millis() as u64;
+        let decode_ms = counter.decode.as_millis() as u64;
+        Self {
+            queue_ms: counter.queue_wait.as_millis() as u64,
+            prefill_ms,
+            decode_ms,
+            total_ms: prefill_ms + decode_ms,
+        }
+    }
+}
+
+/// A tool call whose input does not match the `input_schema` of its tool.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ToolInputError {
+    /// ID of the `tool_use` block, which no longer exists in `strict` mode
+    pub tool_use_id: String,
+    /// Name of the called tool
+    pub name: String,
+    /// The first violation of the schema, e.g. `input.days must be at most 7`
+    pub error: String,
+}
+
+/// How the messages of a request were shortened to fit the context.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TruncationMetadata {
+    /// Number of the oldest messages dropped
+    pub dropped_messages: usize,
+    /// Tokens of the prompt as requested
+    pub original_tokens: usize,
+    /// Tokens of the prompt after truncation
+    pub prompt_tokens: usize,
+    /// Summary of the dropped messages that took their place, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+/// Chunks retrieved from the vector store and added to the system prompt.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RagMetadata {
+    pub citations: Vec<Citation>,
+}
+
+/// A retrieved chunk, numbered as it appears in the system prompt.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Citation {
+    /// Number of the chunk in the context, from 1
+    pub index: usize,
+    /// Point ID in the vector store
+    pub id: serde_json::Value,
+    /// Similarity to the query
+    pub score: f32,
+    /// Source of the chunk, from its payload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub text: String,
 }
 
 impl MessagesResponse {
@@ -680,6 +1344,7 @@ impl MessagesResponse {
             stop_reason: StopReason::EndTurn,
             stop_sequence: None,
             usage,
+            metadata: ResponseMetadata::default(),
         }
     }
 
@@ -694,6 +1359,12 @@ impl MessagesResponse {
         self.stop_sequence = Some(sequence);
         self
     }
+
+    /// Set the server-specific metadata.
+    pub fn with_metadata(mut self, metadata: ResponseMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
 }
 
 #[cfg(test)]
@@ -759,4 +1430,108 @@ mod tests {
         assert!(text.contains("<result name=\"tool_123\">"));
         assert!(text.contains("\"temp\": 22"));
     }
+
+    #[test]
+    fn test_prompt_profile() {
+        let mut request: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "rwkv",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "Hi"}],
+            "metadata": {"prompt_profile": "chatml"}
+        }))
+        .unwrap();
+        assert_eq!(request.prompt_profile(), Some("chatml"));
+        request.set_default_prompt_profile("ai00");
+        assert_eq!(request.prompt_profile(), Some("chatml"));
+
+        request.metadata = None;
+        assert_eq!(request.prompt_profile(), None);
+        request.set_default_prompt_profile("ai00");
+        assert_eq!(request.prompt_profile(), Some("ai00"));
+    }
+
+    #[test]
+    fn test_token_bias() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../assets/tokenizer/rwkv_vocab_v20230424.json"
+        );
+        let tokenizer = Tokenizer::new(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let encode = |text: &str| tokenizer.encode(text.as_bytes()).unwrap();
+        let phrase = encode("Pneumonoultramicroscopic");
+        assert!(phrase.len() > 1);
+
+        let mut request: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "rwkv",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "Hi"}],
+            "logit_bias": {"Pneumonoultramicroscopic": -100, " yes": 5}
+        }))
+        .unwrap();
+        let bias = request.token_bias(&tokenizer).unwrap();
+        assert_eq!(bias.len(), 2);
+        assert_eq!(bias[&phrase[0]], -100.0);
+        assert_eq!(bias[&encode(" yes")[0]], 5.0);
+
+        request.logit_bias_tokens = LogitBiasTokens::All;
+        let bias = request.token_bias(&tokenizer).unwrap();
+        assert!(phrase.iter().all(|token| bias[token] == -100.0));
+    }
+
+    #[test]
+    fn test_seed_metadata() {
+        let request: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "rwkv",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "Hi"}],
+            "seed": 42
+        }))
+        .unwrap();
+        assert_eq!(request.seed, Some(42));
+
+        let metadata = ResponseMetadata::default();
+        assert!(metadata.is_empty());
+        let metadata = ResponseMetadata {
+            seed: request.seed,
+            ..Default::default()
+        };
+        assert!(!metadata.is_empty());
+        assert_eq!(
+            serde_json::to_value(&metadata).unwrap(),
+            serde_json::json!({"seed": 42})
+        );
+    }
+
+    #[test]
+    fn test_timings_metadata() {
+        let request: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "rwkv",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "Hi"}],
+            "return_timings": true
+        }))
+        .unwrap();
+        assert!(request.return_timings);
+
+        let counter = ai00_core::TokenCounter {
+            queue_wait: std::time::Duration::from_millis(5),
+            prefill: std::time::Duration::from_micros(120_400),
+            decode: std::time::Duration::from_millis(800),
+            ..Default::default()
+        };
+        let metadata = ResponseMetadata {
+            timings: Some(ResponseTimings::from(&counter)),
+            ..Default::default()
+        };
+        assert!(!metadata.is_empty());
+        assert_eq!(
+            serde_json::to_value(&metadata).unwrap(),
+            serde_json::json!({"timings": {
+                "queue_ms": 5,
+                "prefill_ms": 120,
+                "decode_ms": 800,
+                "total_ms": 920
+            }})
+        );
+    }
 }
@@ -0,0 +1,184 @@
+//! Image blocks in messages: rejected, or replaced by captions from an external captioner.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Client,
+};
+use serde::Deserialize;
+
+use super::types::{ContentBlock, ImageSource, MessageContent, MessageParam, ToolResultContent};
+use crate::{api::error::ApiErrorResponse, config::VisionOption};
+
+#[derive(Debug, Deserialize)]
+struct CaptionResponse {
+    caption: String,
+}
+
+/// Client of the captioner of `[vision]`.
+#[derive(Clone)]
+pub struct Captioner {
+    option: VisionOption,
+    client: Client,
+}
+
+impl Captioner {
+    pub fn new(option: &VisionOption) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(option.timeout))
+            .build()?;
+        Ok(Self {
+            option: option.clone(),
+            client,
+        })
+    }
+
+    /// Text that replaces `source`.
+    async fn caption(&self, source: &ImageSource) -> Result<String> {
+        let Some(url) = &self.option.captioner else {
+            bail!("no captioner is configured");
+        };
+        let mut request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(source)?);
+        if let Some(token) = &self.option.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            bail!("captioner answered {}", response.status());
+        }
+        let CaptionResponse { caption } = serde_json::from_slice(&response.bytes().await?)?;
+        Ok(self.option.template.replace("{caption}", caption.trim()))
+    }
+}
+
+/// Paths of the image blocks in `messages`, as `messages.<i>.content.<j>[.content.<k>]`.
+fn image_paths(messages: &[MessageParam]) -> Vec<String> {
+    let mut paths = vec![];
+    for (i, message) in messages.iter().enumerate() {
+        let MessageContent::Blocks(blocks) = &message.content else {
+            continue;
+        };
+        for (j, block) in blocks.iter().enumerate() {
+            match block {
+                ContentBlock::Image { .. } => paths.push(format!("messages.{i}.content.{j}")),
+                ContentBlock::ToolResult {
+                    content: ToolResultContent::Blocks(blocks),
+                    ..
+                } => {
+                    let images = blocks
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, block)| matches!(block, ContentBlock::Image { .. }));
+                    for (k, _) in images {
+                        paths.push(format!("messages.{i}.content.{j}.content.{k}"));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    paths
+}
+
+/// Caption the images of `blocks`, those of tool results included.
+async fn caption_blocks(captioner: &Captioner, blocks: &mut [ContentBlock]) -> Result<()> {
+    for block in blocks {
+        let inner = match block {
+            ContentBlock::ToolResult {
+                content: ToolResultContent::Blocks(blocks),
+                ..
+            } => blocks.iter_mut().collect(),
+            block => vec![block],
+        };
+        for block in inner {
+            if let ContentBlock::Image { source } = block {
+                let text = captioner.caption(source).await?;
+                *block = ContentBlock::Text { text };
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the image blocks of `messages` by their captions.
+///
+/// Fails with `invalid_request_error` if there are images but no captioner.
+pub async fn caption_images(
+    captioner: Option<&Captioner>,
+    messages: &mut [MessageParam],
+) -> Result<(), ApiErrorResponse> {
+    let paths = image_paths(messages);
+    let Some(path) = paths.first() else {
+        return Ok(());
+    };
+    let Some(captioner) = captioner.filter(|c| c.option.captioner.is_some()) else {
+        return Err(ApiErrorResponse::invalid_request(
+            "image content is not supported: the model reads text only",
+        )
+        .with_param(path.clone()));
+    };
+
+    for message in messages.iter_mut() {
+        if let MessageContent::Blocks(blocks) = &mut message.content {
+            caption_blocks(captioner, blocks).await.map_err(|err| {
+                ApiErrorResponse::api_error(format!("failed to caption image: {err}"))
+            })?;
+        }
+    }
+    tracing::debug!(event = "images_captioned", images = paths.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn messages() -> Vec<MessageParam> {
+        serde_json::from_value(json!([
+            {
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0K"}}
+                ]
+            },
+            {"role": "assistant", "content": "Let me look it up."},
+            {
+                "role": "user",
+                "content": [{
+                    "type": "tool_result",
+                    "tool_use_id": "toolu_1",
+                    "content": [{"type": "image", "source": {"type": "url", "url": "https://example.com/a.png"}}]
+                }]
+            }
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_image_paths() {
+        assert_eq!(
+            image_paths(&messages()),
+            ["messages.0.content.1", "messages.2.content.0.content.0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejects_images_without_captioner() {
+        let mut messages = messages();
+        let err = caption_images(None, &mut messages).await.unwrap_err();
+        assert_eq!(err.error.param.as_deref(), Some("messages.0.content.1"));
+
+        let mut text: Vec<MessageParam> =
+            serde_json::from_value(json!([{"role": "user", "content": "Hi"}])).unwrap();
+        assert!(caption_images(None, &mut text).await.is_ok());
+    }
+}
@@ -0,0 +1,169 @@
+//! WebSocket transport of streaming Messages requests, for clients that cannot read SSE.
+//!
+//! On `/v1/messages/ws`, the client sends a Messages request as a text frame, and the server
+//! answers with the events of its SSE stream (`message_start`, `content_block_delta`, ...), one
+//! JSON text frame each, up to `message_stop`. Another request can then be sent on the same
+//! socket. While a response streams, `{"type": "cancel"}` stops its generation, and the server
+//! sends `{"type": "cancelled"}` in place of the rest of its events. A request that fails before
+//! streaming is answered with an error frame, shaped as the body of an HTTP error. Every request
+//! is rate limited and admitted like one to `/v1/messages`.
+
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+use salvo::{
+    prelude::*,
+    websocket::{Message, WebSocket, WebSocketUpgrade},
+};
+use serde_json::Value;
+
+use super::{
+    audit::AuditLog,
+    batch::{admit_request, request_depot},
+    handler::{stream_request, EventStream, PROMPT_PROFILE_HEADER},
+    streaming::event_data,
+    types::MessagesRequest,
+};
+use crate::{
+    api::{current_request_id, error::ApiErrorResponse, usage::client_id},
+    config::Config,
+};
+
+/// A text frame from the client.
+#[derive(Debug)]
+enum ClientFrame {
+    Request(Box<MessagesRequest>),
+    Cancel,
+}
+
+fn parse_frame(text: &str) -> Result<ClientFrame, ApiErrorResponse> {
+    let value: Value = serde_json::from_str(text)
+        .map_err(|err| ApiErrorResponse::invalid_request(format!("invalid frame: {err}")))?;
+    if value.get("type").and_then(Value::as_str) == Some("cancel") {
+        return Ok(ClientFrame::Cancel);
+    }
+    match serde_json::from_value(value) {
+        Ok(request) => Ok(ClientFrame::Request(Box::new(request))),
+        Err(err) => Err(ApiErrorResponse::invalid_request(format!(
+            "invalid request: {err}"
+        ))),
+    }
+}
+
+/// Send `text`; false if the socket is closed.
+async fn send(ws: &mut WebSocket, text: String) -> bool {
+    ws.send(Message::text(text)).await.is_ok()
+}
+
+async fn send_error(ws: &mut WebSocket, err: ApiErrorResponse) -> bool {
+    send(ws, serde_json::to_string(&err).unwrap_or_default()).await
+}
+
+/// Forward `events` to the client until they end or the client cancels; false if the socket
+/// is closed.
+///
+/// Dropping `events` on cancel drops the receiver of the tokens, which stops generation.
+async fn forward(ws: &mut WebSocket, mut events: EventStream) -> bool {
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(event)) => {
+                    let Some(data) = event_data(&event) else {
+                        continue;
+                    };
+                    if !send(ws, data).await {
+                        return false;
+                    }
+                }
+                _ => return true,
+            },
+            message = ws.recv() => {
+                let message = match message {
+                    Some(Ok(message)) if !message.is_close() => message,
+                    _ => return false,
+                };
+                let Ok(text) = message.as_str() else {
+                    continue;
+                };
+                let open = match parse_frame(text) {
+                    Ok(ClientFrame::Cancel) => {
+                        tracing::info!(event = "stream_cancelled");
+                        return send(ws, r#"{"type":"cancelled"}"#.into()).await;
+                    }
+                    Ok(ClientFrame::Request(_)) => {
+                        let err = ApiErrorResponse::invalid_request(
+                            "a response is streaming; cancel it or wait for message_stop",
+                        );
+                        send_error(ws, err).await
+                    }
+                    Err(err) => send_error(ws, err).await,
+                };
+                if !open {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// Answer the requests of a socket, one at a time.
+async fn serve(mut ws: WebSocket, depot: Depot, trace_id: Option<String>, profile: Option<String>) {
+    while let Some(Ok(message)) = ws.recv().await {
+        if message.is_close() {
+            break;
+        }
+        let Ok(text) = message.as_str() else {
+            continue;
+        };
+        let open = match parse_frame(text) {
+            Ok(ClientFrame::Request(mut request)) => {
+                if let Some(profile) = &profile {
+                    request.set_default_prompt_profile(profile);
+                }
+                let mut depot = request_depot(&depot, trace_id.clone());
+                let events = match admit_request(&mut depot).await {
+                    Ok(()) => stream_request(&mut depot, *request).await,
+                    Err(err) => Err(err),
+                };
+                match events {
+                    Ok(events) => forward(&mut ws, events).await,
+                    Err(err) => send_error(&mut ws, err).await,
+                }
+            }
+            // Nothing is streaming.
+            Ok(ClientFrame::Cancel) => true,
+            Err(err) => send_error(&mut ws, err).await,
+        };
+        if !open {
+            break;
+        }
+    }
+}
+
+/// Stream Messages requests over a WebSocket, with the events of `/v1/messages` as frames.
+///
+/// `/v1/messages/ws`.
+#[handler]
+pub async fn messages_ws(
+    depot: &mut Depot,
+    req: &mut Request,
+    res: &mut Response,
+) -> Result<(), StatusError> {
+    let profile = req
+        .headers()
+        .get(PROMPT_PROFILE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    if let Ok(audit) = depot.obtain::<AuditLog>() {
+        let config = depot.obtain::<Arc<Config>>().unwrap();
+        let audit = audit.with_client(client_id(req, config));
+        depot.inject(audit);
+    }
+    // Every request of the socket is traced to the request that opened it.
+    let trace_id = current_request_id(depot);
+    let depot = request_depot(depot, trace_id.clone());
+
+    WebSocketUpgrade::new()
+        .upgrade(req, res, move |ws| serve(ws, depot, trace_id, profile))
+        .await
+}
@@ -0,0 +1,110 @@
+//! Prometheus exposition of the metrics recorded by the server and the runtime.
+//!
+//! The runtime records token counts, latencies, slot occupancy and queue depth
+//! (see [`ai00_core::stats`]); the server adds requests per endpoint. `/metrics` renders them all.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use salvo::prelude::*;
+
+pub const HTTP_REQUESTS: &str = "ai00_http_requests_total";
+pub const HTTP_REQUEST_SECONDS: &str = "ai00_http_request_duration_seconds";
+
+/// Buckets of every histogram, in seconds.
+const BUCKETS: [f64; 13] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+/// Interval between draining recorded histogram samples into their buckets.
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Handle to the installed Prometheus recorder.
+#[derive(Clone)]
+pub struct Metrics(PrometheusHandle);
+
+impl Metrics {
+    /// Install the Prometheus recorder as the global recorder of the `metrics` facade.
+    /// Must be called within a tokio runtime.
+    pub fn install() -> Result<Self> {
+        let handle = PrometheusBuilder::new()
+            .set_buckets(&BUCKETS)?
+            .install_recorder()?;
+
+        ai00_core::stats::describe();
+        describe_counter!(
+            HTTP_REQUESTS,
+            Unit::Count,
+            "HTTP requests by endpoint and status."
+        );
+        describe_histogram!(
+            HTTP_REQUEST_SECONDS,
+            Unit::Seconds,
+            "Time until the response head is ready. Streams keep running after that."
+        );
+
+        let upkeep = handle.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(UPKEEP_INTERVAL).await;
+                upkeep.run_upkeep();
+            }
+        });
+        Ok(Self(handle))
+    }
+}
+
+/// The path of the matched route with path parameters replaced by their names,
+/// e.g. `/api/states/{id}`, so that IDs don't blow up the number of label values.
+fn endpoint(req: &Request, res: &Response) -> String {
+    let params = req.params();
+    if params.is_empty() && res.status_code == Some(StatusCode::NOT_FOUND) {
+        return "unmatched".into();
+    }
+    let mut path = req.uri().path().to_string();
+    for (name, value) in params.iter() {
+        if !value.is_empty() {
+            path = path.replacen(value.as_str(), &format!("{{{name}}}"), 1);
+        }
+    }
+    path
+}
+
+/// Count requests and their latency by endpoint.
+#[handler]
+pub async fn track_requests(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let start = Instant::now();
+    ctrl.call_next(req, depot, res).await;
+
+    let method = req.method().to_string();
+    let endpoint = endpoint(req, res);
+    let status = res.status_code.unwrap_or(StatusCode::OK);
+    counter!(
+        HTTP_REQUESTS,
+        "method" => method.clone(),
+        "endpoint" => endpoint.clone(),
+        "status" => status.as_u16().to_string()
+    )
+    .increment(1);
+    histogram!(HTTP_REQUEST_SECONDS, "method" => method, "endpoint" => endpoint)
+        .record(start.elapsed().as_secs_f64());
+}
+
+/// Metrics in the Prometheus text format.
+///
+/// `/metrics`.
+#[endpoint(responses((status_code = 200, description = "Prometheus text exposition")))]
+pub async fn metrics(depot: &mut Depot, res: &mut Response) {
+    match depot.obtain::<Metrics>() {
+        Ok(metrics) => res.render(Text::Plain(metrics.0.render())),
+        Err(_) => res
+            .status_code(StatusCode::NOT_FOUND)
+            .render("metrics are not enabled"),
+    };
+}
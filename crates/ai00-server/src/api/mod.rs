@@ -1,22 +1,55 @@
 use std::time::Duration;
 
-use ai00_core::{RuntimeInfo, ThreadRequest};
+use ai00_core::{LoadProgress, RuntimeInfo, RuntimeSnapshot, ThreadRequest};
 use anyhow::Result;
 use flume::Sender;
+use salvo::Depot;
+
+use crate::{api::error::ApiErrorResponse, logging::RequestContext};
 
 pub mod adapter;
+pub mod admission;
+pub mod artifacts;
 pub mod auth;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(feature = "embed")]
+pub mod embed;
+pub mod embeddings;
 pub mod error;
 pub mod file;
+pub mod health;
+pub mod hub;
 pub mod messages;
+pub mod metrics;
 pub mod model;
 pub mod oai;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod rate_limit;
+pub mod reload;
 pub mod request_id;
+pub mod rerank;
+pub mod sampler;
+pub mod shared;
+pub mod similarity;
+pub mod tokenize;
+pub mod upload;
+pub mod usage;
+pub mod web;
 
 // pub use adapter::adapters;
 // pub use file::{dir, load_config, models, save_config, unzip};
 // pub use model::{info, load, load_state, save, state, unload};
 
+/// The ID assigned to the current request by [`request_id::request_id_handler`].
+pub fn current_request_id(depot: &Depot) -> Option<String> {
+    depot
+        .get::<RequestContext>("request_context")
+        .ok()
+        .map(|context| context.request_id.clone())
+}
+
 pub async fn try_request_info(sender: Sender<ThreadRequest>) -> Result<RuntimeInfo> {
     let (info_sender, info_receiver) = flume::unbounded();
     let _ = sender.send(ThreadRequest::Info(info_sender));
@@ -33,6 +66,52 @@ pub async fn request_info(sender: Sender<ThreadRequest>, sleep: Duration) -> Run
     }
 }
 
+/// The runtime info of the model serving `model`, or `None` if no model is registered under it.
+pub async fn try_request_info_of(
+    sender: Sender<ThreadRequest>,
+    model: &str,
+) -> Result<Option<RuntimeInfo>> {
+    let (info_sender, info_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::InfoOf {
+        model: model.to_string(),
+        sender: info_sender,
+    });
+    let _info = info_receiver.recv_async().await?;
+    Ok(_info)
+}
+
+/// Wait for the runtime info of the model serving `model`, or the default model if it is empty.
+/// Answers 404 if no model is registered under the name.
+pub async fn request_info_of(
+    sender: Sender<ThreadRequest>,
+    model: &str,
+    sleep: Duration,
+) -> Result<RuntimeInfo, ApiErrorResponse> {
+    loop {
+        match try_request_info_of(sender.clone(), model).await {
+            Ok(Some(info)) => break Ok(info),
+            Ok(None) => {
+                let err = ApiErrorResponse::not_found(format!("model `{model}` is not loaded"));
+                break Err(err.with_param("model"));
+            }
+            // the model is still loading
+            Err(_) => tokio::time::sleep(sleep).await,
+        }
+    }
+}
+
+pub async fn request_models(sender: Sender<ThreadRequest>) -> Result<Vec<RuntimeInfo>> {
+    let (models_sender, models_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Models(models_sender));
+    Ok(models_receiver.recv_async().await?)
+}
+
+pub async fn request_runtime(sender: Sender<ThreadRequest>) -> Result<Vec<RuntimeSnapshot>> {
+    let (runtime_sender, runtime_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::Runtime(runtime_sender));
+    Ok(runtime_receiver.recv_async().await?)
+}
+
 pub async fn request_info_stream(
     sender: Sender<ThreadRequest>,
     stream: Sender<RuntimeInfo>,
@@ -47,3 +126,21 @@ pub async fn request_info_stream(
         tokio::time::sleep(sleep).await;
     }
 }
+
+pub async fn request_load_progress_stream(
+    sender: Sender<ThreadRequest>,
+    stream: Sender<LoadProgress>,
+    sleep: Duration,
+) {
+    loop {
+        let (progress_sender, progress_receiver) = flume::unbounded();
+        let _ = sender.send(ThreadRequest::LoadProgress(progress_sender));
+        let Ok(progress) = progress_receiver.recv_async().await else {
+            break;
+        };
+        if stream.send(progress).is_err() {
+            break;
+        }
+        tokio::time::sleep(sleep).await;
+    }
+}
@@ -1,13 +1,31 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use ai00_core::{InitState, ReloadRequest, RuntimeInfo, SaveRequest, StateId, ThreadRequest};
+use ai00_core::{
+    reload::{Lora, LoraAdapter},
+    InitState, ReloadRequest, RuntimeInfo, RuntimeSnapshot, SaveRequest, StateId, ThreadRequest,
+};
 use futures_util::StreamExt;
-use salvo::{oapi::extract::JsonBody, prelude::*};
-use serde::Serialize;
-use web_rwkv::runtime::model::ModelInfo;
+use memmap2::Mmap;
+use safetensors::SafeTensors;
+use salvo::{
+    oapi::extract::{JsonBody, QueryParam},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use web_rwkv::runtime::{
+    loader::Loader,
+    model::{ModelInfo, Quant},
+};
 
 use super::*;
-use crate::{build_path, types::ThreadSender, SLEEP};
+use crate::{build_path, check_path_permitted, logging, types::ThreadSender, SLEEP};
+
+const TOKENIZER_PATHS: [&str; 2] = ["assets/tokenizer", "assets/models"];
 
 #[derive(Debug, Clone, Serialize)]
 struct InfoResponse {
@@ -78,62 +96,264 @@ pub async fn state(depot: &mut Depot, res: &mut Response) {
     salvo::sse::stream(res, stream);
 }
 
+/// Report the progress of the latest model load every half second.
+///
+/// `/api/models/load/progress`.
+#[handler]
+pub async fn load_progress(depot: &mut Depot, res: &mut Response) {
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let (progress_sender, progress_receiver) = flume::unbounded();
+    let task = request_load_progress_stream(sender.to_owned(), progress_sender, SLEEP);
+    tokio::task::spawn(task);
+
+    let stream = progress_receiver
+        .into_stream()
+        .map(|progress| SseEvent::default().json(progress));
+    salvo::sse::stream(res, stream);
+}
+
 /// Load a runtime with models, LoRA, initial states, etc.
 ///
+/// If any of the paths is an `hf://` path, the files are downloaded from the Hugging Face Hub in
+/// the background and the model is loaded once they are, answering `202 Accepted` right away;
+/// see `/api/models/download/progress` and `/api/models/load/progress` for how far they are.
+///
 /// `/api/models/load`.
 #[endpoint]
 pub async fn load(depot: &mut Depot, req: JsonBody<ReloadRequest>) -> StatusCode {
-    let sender = depot.obtain::<ThreadSender>().unwrap();
-    let config = depot.obtain::<crate::config::Config>().unwrap();
-    let (result_sender, result_receiver) = flume::unbounded();
+    let sender = depot.obtain::<ThreadSender>().unwrap().clone();
+    let config = depot.obtain::<Arc<crate::config::Config>>().unwrap();
+    let hub = depot.obtain::<hub::Hub>().unwrap().clone();
+    let artifacts = depot.obtain::<artifacts::ArtifactUsage>().unwrap().clone();
+    let models = config.model.path.clone();
     let mut request = req.0;
 
+    let paths = std::iter::once(&request.model_path)
+        .chain(request.lora.iter().map(|x| &x.path))
+        .chain(request.state.iter().map(|x| &x.path));
+    let mut download = false;
+    for path in paths {
+        match hub::HubFile::check(path) {
+            Ok(remote) => download |= remote,
+            Err(_) => return StatusCode::BAD_REQUEST,
+        }
+    }
+    if !download {
+        return reload(sender, artifacts, &models, request).await;
+    }
+
+    tokio::spawn(async move {
+        let paths = std::iter::once(&mut request.model_path)
+            .chain(request.lora.iter_mut().map(|x| &mut x.path))
+            .chain(request.state.iter_mut().map(|x| &mut x.path));
+        for path in paths {
+            // failures are reported by the download progress
+            match hub.resolve(std::mem::take(path)).await {
+                Ok(resolved) => *path = resolved,
+                Err(_) => return,
+            }
+        }
+        reload(sender, artifacts, &models, request).await;
+    });
+    StatusCode::ACCEPTED
+}
+
+/// Load a runtime with the local files of `request`, which must be under the model folder.
+async fn reload(
+    sender: ThreadSender,
+    artifacts: artifacts::ArtifactUsage,
+    models: &Path,
+    mut request: ReloadRequest,
+) -> StatusCode {
+    let (result_sender, result_receiver) = flume::unbounded();
+
     // make sure that we are not visiting un-permitted path.
-    request.model_path = match build_path(&config.model.path, request.model_path) {
+    request.model_path = match build_path(models, request.model_path) {
         Ok(path) => path,
         Err(_) => return StatusCode::NOT_FOUND,
     };
     for x in request.lora.iter_mut() {
-        x.path = match build_path(&config.model.path, &x.path) {
+        x.path = match build_path(models, &x.path) {
             Ok(path) => path,
             Err(_) => return StatusCode::NOT_FOUND,
         }
     }
     for x in request.state.iter_mut() {
-        x.path = match build_path(&config.model.path, &x.path) {
+        x.path = match build_path(models, &x.path) {
             Ok(path) => path,
             Err(_) => return StatusCode::NOT_FOUND,
         }
     }
 
+    let files = artifacts::files_of(&request);
     let _ = sender.send(ThreadRequest::Reload {
         request: Box::new(request),
         sender: Some(result_sender),
     });
     match result_receiver.recv_async().await.unwrap() {
-        true => StatusCode::OK,
+        true => {
+            artifacts.touch(files);
+            StatusCode::OK
+        }
         false => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
 
-/// Unload the current runtime.
+/// Unload the model given by the `model` query, or all models.
 ///
 /// `/api/models/unload`.
 #[endpoint]
-pub async fn unload(depot: &mut Depot) -> StatusCode {
+pub async fn unload(depot: &mut Depot, model: QueryParam<String, false>) -> StatusCode {
     let sender = depot.obtain::<ThreadSender>().unwrap();
-    let _ = sender.send(ThreadRequest::Unload);
-    while try_request_info(sender.clone()).await.is_ok() {}
+    let model = model.into_inner();
+    let all = model.is_none();
+    let _ = sender.send(ThreadRequest::Unload { model });
+    if all {
+        while try_request_info(sender.clone()).await.is_ok() {}
+    }
     StatusCode::OK
 }
 
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RuntimeResponse {
+    /// Generations waiting for a slot, across all models.
+    pub queued: usize,
+    /// Generations accepted by the runtime and not yet finished.
+    pub in_flight: usize,
+    pub models: Vec<RuntimeSnapshot>,
+}
+
+/// Report the slots and prompt caches of every loaded model: what each slot runs and for how
+/// long, what the caches hold, and estimates of the memory they take.
+///
+/// `/admin/runtime`.
+#[endpoint(responses(
+    (status_code = 200, body = RuntimeResponse),
+    (status_code = 500, body = error::ApiErrorResponse),
+))]
+pub async fn runtime(depot: &mut Depot) -> Result<Json<RuntimeResponse>, error::ApiErrorResponse> {
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let models = request_runtime(sender.clone())
+        .await
+        .map_err(|err| error::ApiErrorResponse::api_error(err.to_string()))?;
+    Ok(Json(RuntimeResponse {
+        queued: ai00_core::stats::queued(),
+        in_flight: ai00_core::stats::in_flight(),
+        models,
+    }))
+}
+
+#[derive(Debug, Default, Clone, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct RestoreCacheRequest {
+    /// Model whose cache to restore. Defaults to the default model.
+    pub model: Option<String>,
+    /// State whose prompt cache to restore. Defaults to the default state.
+    pub state: StateId,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RestoreCacheResponse {
+    /// Prompts put back into the cache.
+    pub restored: usize,
+}
+
+/// Put the prompts of the last cache snapshot of a state back into its prompt cache, so that
+/// they are prefilled no more. Needs `[cache_snapshot]`.
+///
+/// `/admin/models/cache/restore`.
+#[endpoint(responses(
+    (status_code = 200, body = RestoreCacheResponse),
+    (status_code = 400, body = error::ApiErrorResponse),
+))]
+pub async fn restore_cache(
+    depot: &mut Depot,
+    req: JsonBody<RestoreCacheRequest>,
+) -> Result<Json<RestoreCacheResponse>, error::ApiErrorResponse> {
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let (result_sender, result_receiver) = flume::unbounded();
+    let RestoreCacheRequest { model, state } = req.0;
+    let _ = sender.send(ThreadRequest::RestoreCache {
+        model,
+        state,
+        sender: result_sender,
+    });
+    match result_receiver.recv_async().await {
+        Ok(Ok(restored)) => Ok(Json(RestoreCacheResponse { restored })),
+        Ok(Err(err)) => Err(error::ApiErrorResponse::invalid_request(err).with_param("state")),
+        Err(err) => Err(error::ApiErrorResponse::api_error(err.to_string())),
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct LoraAdapterRequest {
+    /// Base model of the adapter. Defaults to the default model.
+    pub model: Option<String>,
+    /// Name requests select the adapter by.
+    pub name: String,
+    /// LoRAs blended on top of those of the base model, relative to the configured model folder.
+    pub lora: Vec<Lora>,
+}
+
+/// Register a LoRA adapter that requests select with `adapter`, served by its own copy of the
+/// base model. Unload it through `/admin/models/unload` with its name.
+///
+/// `/admin/models/adapters`.
+#[endpoint]
+pub async fn load_lora_adapter(depot: &mut Depot, req: JsonBody<LoraAdapterRequest>) -> StatusCode {
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let config = depot.obtain::<Arc<crate::config::Config>>().unwrap();
+    let hub = depot.obtain::<hub::Hub>().unwrap();
+    let (result_sender, result_receiver) = flume::unbounded();
+    let LoraAdapterRequest {
+        model,
+        name,
+        mut lora,
+    } = req.0;
+
+    if name.is_empty() || lora.is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+    if lora.iter().any(|x| hub::HubFile::check(&x.path).is_err()) {
+        return StatusCode::BAD_REQUEST;
+    }
+    for x in lora.iter_mut() {
+        x.path = match hub.resolve(std::mem::take(&mut x.path)).await {
+            Ok(path) => path,
+            Err(_) => return StatusCode::BAD_GATEWAY,
+        };
+        x.path = match build_path(&config.model.path, &x.path) {
+            Ok(path) if path.is_file() => path,
+            _ => return StatusCode::NOT_FOUND,
+        };
+    }
+
+    let files = artifacts::lora_files(&lora);
+    let _ = sender.send(ThreadRequest::LoadLoraAdapter {
+        model,
+        adapter: LoraAdapter { name, lora },
+        sender: Some(result_sender),
+    });
+    match result_receiver.recv_async().await.unwrap() {
+        true => {
+            depot
+                .obtain::<artifacts::ArtifactUsage>()
+                .unwrap()
+                .touch(files);
+            StatusCode::OK
+        }
+        false => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 /// Save the current model as a prefab.
 ///
 /// `/api/models/save`.
 #[endpoint]
 pub async fn save(depot: &mut Depot, req: JsonBody<SaveRequest>) -> StatusCode {
     let sender = depot.obtain::<ThreadSender>().unwrap();
-    let config = depot.obtain::<crate::config::Config>().unwrap();
+    let config = depot.obtain::<Arc<crate::config::Config>>().unwrap();
     let (result_sender, result_receiver) = flume::unbounded();
     let mut request = req.0;
 
@@ -143,12 +363,230 @@ pub async fn save(depot: &mut Depot, req: JsonBody<SaveRequest>) -> StatusCode {
         Err(_) => return StatusCode::NOT_FOUND,
     };
 
+    let files = [(request.path.clone(), artifacts::ArtifactKind::Prefab)];
     let _ = sender.send(ThreadRequest::Save {
         request,
         sender: result_sender,
     });
     match result_receiver.recv_async().await.unwrap() {
-        true => StatusCode::OK,
+        true => {
+            depot
+                .obtain::<artifacts::ArtifactUsage>()
+                .unwrap()
+                .touch(files);
+            StatusCode::OK
+        }
         false => StatusCode::INTERNAL_SERVER_ERROR,
     }
 }
+
+#[derive(Debug, Clone, Deserialize)]
+struct ValidateRequest {
+    /// Path to the model, relative to the configured model folder.
+    model_path: PathBuf,
+    /// Tokenizer to check against. Defaults to the configured tokenizer.
+    #[serde(default)]
+    tokenizer_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+struct ValidateResponse {
+    model_path: PathBuf,
+    /// Whether the model can be loaded with the given tokenizer.
+    valid: bool,
+    size: u64,
+    info: Option<ModelInfo>,
+    num_tensors: usize,
+    num_params: usize,
+    /// Estimated VRAM needed for the weights under each quantization type.
+    vram: Vec<VramEstimate>,
+    tokenizer: Option<TokenizerCheck>,
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct VramEstimate {
+    quant_type: Quant,
+    bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TokenizerCheck {
+    path: PathBuf,
+    vocab_size: usize,
+    compatible: bool,
+}
+
+/// Estimate weight memory in bytes, assuming all layers are quantized with `quant`.
+///
+/// Only 2D matrices inside the blocks are quantized; everything else stays in `f16`.
+fn estimate_vram(tensors: &[(String, Vec<usize>)], quant: Quant) -> u64 {
+    let bytes: f64 = tensors
+        .iter()
+        .map(|(name, shape)| {
+            let count = shape.iter().product::<usize>() as f64;
+            let quantized = name.starts_with("blocks.") && shape.len() == 2;
+            let per_param = match (quant, quantized) {
+                (_, false) | (Quant::None, _) => 2.0,
+                // 1 byte per weight plus a pair of `f16` min-max per block of 128.
+                (Quant::Int8, true) => 1.0 + 4.0 / 128.0,
+                // half a byte per weight plus an `f16` absmax per block of 64.
+                (Quant::NF4, true) | (Quant::SF4, true) => 0.5 + 2.0 / 64.0,
+            };
+            count * per_param
+        })
+        .sum();
+    bytes as u64
+}
+
+/// Read the vocabulary size of a JSON tokenizer, i.e., the largest token id plus one.
+fn tokenizer_vocab_size(path: &Path) -> anyhow::Result<usize> {
+    let contents = std::fs::read_to_string(path)?;
+    let vocab: HashMap<String, serde_json::Value> = serde_json::from_str(&contents)?;
+    let size = vocab
+        .keys()
+        .filter_map(|key| key.parse::<usize>().ok())
+        .max()
+        .map(|id| id + 1)
+        .unwrap_or_default();
+    Ok(size)
+}
+
+fn validate_inner(model_path: PathBuf, tokenizer_path: PathBuf) -> ValidateResponse {
+    let mut response = ValidateResponse {
+        model_path: model_path.clone(),
+        ..Default::default()
+    };
+
+    let data = match File::open(&model_path)
+        .and_then(|file| file.metadata().map(|meta| (file, meta)))
+        .and_then(|(file, meta)| {
+            response.size = meta.len();
+            unsafe { Mmap::map(&file) }
+        }) {
+        Ok(data) => data,
+        Err(err) => {
+            response.errors.push(format!("cannot open model: {err}"));
+            return response;
+        }
+    };
+
+    // only the header is parsed here; tensor data stays untouched in the mapping.
+    let model = match SafeTensors::deserialize(&data) {
+        Ok(model) => model,
+        Err(err) => {
+            response.errors.push(format!("invalid safetensors: {err}"));
+            return response;
+        }
+    };
+    let tensors = model
+        .tensors()
+        .into_iter()
+        .map(|(name, view)| (name, view.shape().to_vec()))
+        .collect::<Vec<_>>();
+    response.num_tensors = tensors.len();
+    response.num_params = tensors
+        .iter()
+        .map(|(_, shape)| shape.iter().product::<usize>())
+        .sum();
+    response.vram = [Quant::None, Quant::Int8, Quant::NF4, Quant::SF4]
+        .into_iter()
+        .map(|quant_type| VramEstimate {
+            quant_type,
+            bytes: estimate_vram(&tensors, quant_type),
+        })
+        .collect();
+
+    let info = match Loader::info(&model) {
+        Ok(info) => info,
+        Err(err) => {
+            response.errors.push(format!("unsupported model: {err}"));
+            return response;
+        }
+    };
+
+    match tokenizer_vocab_size(&tokenizer_path) {
+        Ok(vocab_size) => {
+            let compatible = vocab_size <= info.num_vocab;
+            if !compatible {
+                response.errors.push(format!(
+                    "tokenizer vocab size {vocab_size} exceeds model vocab size {}",
+                    info.num_vocab
+                ));
+            }
+            response.tokenizer = Some(TokenizerCheck {
+                path: tokenizer_path,
+                vocab_size,
+                compatible,
+            });
+        }
+        Err(err) => response
+            .errors
+            .push(format!("cannot read tokenizer: {err}")),
+    }
+
+    response.info = Some(info);
+    response.valid = response.errors.is_empty();
+    response
+}
+
+/// Check a model file without loading its weights.
+///
+/// `/admin/models/validate`.
+#[handler]
+pub async fn validate(depot: &mut Depot, req: &mut Request, res: &mut Response) {
+    let config = depot.obtain::<Arc<crate::config::Config>>().unwrap();
+    let request = match req.parse_json::<ValidateRequest>().await {
+        Ok(request) => request,
+        Err(err) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(err.to_string()));
+            return;
+        }
+    };
+
+    // make sure that we are not visiting un-permitted path.
+    let model_path = match build_path(&config.model.path, &request.model_path) {
+        Ok(path) => path,
+        Err(err) => {
+            logging::errors::path_validation_failed(
+                &request.model_path.to_string_lossy(),
+                &err.to_string(),
+            );
+            res.status_code(StatusCode::NOT_FOUND);
+            res.render("NOT_FOUND");
+            return;
+        }
+    };
+    let tokenizer_path = match request.tokenizer_path {
+        Some(path) => match check_path_permitted(&path, &TOKENIZER_PATHS) {
+            Ok(_) => path,
+            Err(err) => {
+                logging::errors::path_validation_failed(&path.to_string_lossy(), &err.to_string());
+                res.status_code(StatusCode::FORBIDDEN);
+                res.render("FORBIDDEN");
+                return;
+            }
+        },
+        None => config.tokenizer.path.clone(),
+    };
+
+    let response =
+        match tokio::task::spawn_blocking(move || validate_inner(model_path, tokenizer_path)).await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                res.render(Text::Plain(err.to_string()));
+                return;
+            }
+        };
+    tracing::info!(
+        event = "model_validated",
+        path = %response.model_path.display(),
+        valid = response.valid,
+        errors = response.errors.len(),
+        "Model validated"
+    );
+    res.render(Json(response));
+}
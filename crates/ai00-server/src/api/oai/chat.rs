@@ -1,10 +1,15 @@
 use std::{collections::HashMap, sync::Arc};
 
 use ai00_core::{
-    FinishReason, GenerateRequest, InputState, ThreadRequest, Token, TokenCounter, MAX_TOKENS,
+    reload::TrafficClass, FinishReason, GenerateRequest, InputState, Token, TokenCounter,
+    MAX_TOKENS,
 };
 use derivative::Derivative;
-use futures_util::StreamExt;
+use futures_util::{
+    future::{join_all, ready},
+    stream::select_all,
+    StreamExt,
+};
 use itertools::Itertools;
 use regex::Regex;
 use salvo::{oapi::extract::JsonBody, prelude::*, sse::SseEvent, Depot, Writer};
@@ -12,7 +17,7 @@ use serde::{Deserialize, Serialize};
 
 use super::*;
 use crate::{
-    api::request_info,
+    api::{current_request_id, error::ApiErrorResponse, request_info, usage::Attribution},
     types::{Array, ThreadSender},
     SLEEP,
 };
@@ -119,7 +124,7 @@ impl Default for ChatTemplate {
     }
 }
 
-#[derive(Debug, Derivative, Deserialize, ToSchema)]
+#[derive(Debug, Clone, Derivative, Deserialize, ToSchema)]
 #[derivative(Default)]
 #[serde(default)]
 #[salvo(schema(
@@ -173,10 +178,15 @@ struct ChatRequest {
     max_tokens: usize,
     #[derivative(Default(value = "Array::Item(\"\\n\\n\".into())"))]
     stop: Array<String>,
+    /// Token IDs that stop the generation. The stop token is not part of the output.
+    stop_tokens: Vec<u32>,
+    /// Raw byte sequences that stop the generation.
+    stop_bytes: Vec<Vec<u8>>,
     stream: bool,
     #[serde(alias = "logit_bias")]
     bias: HashMap<u32, f32>,
     bnf_schema: Option<String>,
+    regex: Option<String>,
     #[serde(alias = "sampler_override")]
     sampler: Option<SamplerParams>,
     #[derivative(Default(value = "0.5"))]
@@ -185,6 +195,15 @@ struct ChatRequest {
     top_k: usize,
     #[derivative(Default(value = "1.0"))]
     temperature: f32,
+    strategy: Strategy,
+    /// Number of hypotheses kept by beam search.
+    #[derivative(Default(value = "4"))]
+    beam_width: usize,
+    /// Length normalization exponent of beam search.
+    #[derivative(Default(value = "1.0"))]
+    length_penalty: f32,
+    /// Number of independent completions to sample for the prompt.
+    n: Option<usize>,
     // OpenAI compatibility - ignore these fields
     #[serde(default)]
     model: Option<String>,
@@ -193,8 +212,6 @@ struct ChatRequest {
     #[serde(default)]
     presence_penalty: Option<f32>,
     #[serde(default)]
-    n: Option<usize>,
-    #[serde(default)]
     user: Option<String>,
 }
 
@@ -207,15 +224,25 @@ impl From<ChatRequest> for GenerateRequest {
             state,
             max_tokens,
             stop,
+            stop_tokens,
+            stop_bytes,
             sampler,
             top_p,
             top_k,
             temperature,
+            strategy,
+            beam_width,
+            length_penalty,
             bias,
             bnf_schema,
+            regex,
+            stream,
             ..
         } = value;
 
+        let span = tracing::info_span!("prompt_build");
+        let guard = span.enter();
+
         let sep = template.sep;
         let re = Regex::new(r"\n(\s*\n)+").unwrap();
         let prompt = Vec::from(messages.clone())
@@ -250,6 +277,7 @@ impl From<ChatRequest> for GenerateRequest {
             .replace("{assistant}", &assistant)
             .replace("{user}", &user);
         let prompt = format!("{prompt}{sep}{prefix}");
+        drop(guard);
 
         let max_tokens = max_tokens.min(MAX_TOKENS);
         let stop = stop.into();
@@ -267,15 +295,22 @@ impl From<ChatRequest> for GenerateRequest {
 
         let state = state.into();
 
+        let kind = strategy.kind(beam_width, length_penalty);
+
         Self {
             prompt,
             model_text,
             max_tokens,
             stop,
+            stop_tokens,
+            stop_bytes,
             sampler,
             bias,
             bnf_schema,
+            regex,
+            kind,
             state,
+            traffic_class: TrafficClass::from_stream(stream),
             ..Default::default()
         }
     }
@@ -367,46 +402,35 @@ async fn respond_one(depot: &mut Depot, request: ChatRequest, res: &mut Response
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
-
-    let mut token_counter = TokenCounter::default();
-    let mut finish_reason = FinishReason::Null;
-    let mut text = String::new();
-    let mut stream = token_receiver.into_stream();
-
-    while let Some(token) = stream.next().await {
-        match token {
-            Token::Start => {}
-            Token::Content(token) => {
-                text += &token;
-            }
-            Token::Stop(reason, counter) => {
-                finish_reason = reason;
-                token_counter = counter;
-                break;
-            }
-            _ => unreachable!(),
-        }
-    }
-
-    let json = Json(ChatResponse {
-        object: "chat.completion".into(),
-        model: model_name,
-        choices: vec![ChatChoice {
+    let n = request.n.unwrap_or(1);
+    let request_id = current_request_id(depot);
+    let attribution = Attribution::of(depot);
+    let receivers = generate(sender, &attribution, request, n, request_id, info.tokenizer);
+    let outputs = join_all(receivers.into_iter().map(collect)).await;
+    let outputs = match outputs.into_iter().collect::<Result<Vec<_>, _>>() {
+        Ok(outputs) => outputs,
+        Err(err) => return err.respond(res),
+    };
+
+    let mut counters = Vec::with_capacity(outputs.len());
+    let mut choices = Vec::with_capacity(outputs.len());
+    for (index, (text, finish_reason, counter)) in outputs.into_iter().enumerate() {
+        counters.push(counter);
+        choices.push(ChatChoice {
             message: ChatRecord {
                 role: Role::Assistant,
                 content: text.trim().into(),
             },
-            index: 0,
+            index,
             finish_reason,
-        }],
-        counter: token_counter,
+        });
+    }
+
+    let json = Json(ChatResponse {
+        object: "chat.completion".into(),
+        model: model_name,
+        choices,
+        counter: merge_counters(counters),
     });
     res.render(json);
 }
@@ -416,48 +440,64 @@ async fn respond_stream(depot: &mut Depot, request: ChatRequest, res: &mut Respo
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
-
-    let mut start_token = true;
-    let stream = token_receiver.into_stream().map(move |token| {
+    let n = request.n.unwrap_or(1);
+    let request_id = current_request_id(depot);
+    let attribution = Attribution::of(depot);
+    let receivers = generate(sender, &attribution, request, n, request_id, info.tokenizer);
+
+    // chunks of all choices are interleaved; `[DONE]` is sent after the last choice finishes
+    let mut start_tokens = vec![true; receivers.len()];
+    let mut pending = receivers.len();
+    let streams = receivers
+        .into_iter()
+        .enumerate()
+        .map(|(index, receiver)| receiver.into_stream().map(move |token| (index, token)));
+    let stream = select_all(streams).filter_map(move |(index, token)| {
         let choice = match token {
             Token::Start => PartialChatChoice {
                 delta: PartialChatRecord::Role(Role::Assistant),
+                index,
                 ..Default::default()
             },
             Token::Content(token) => {
-                let token = match start_token {
+                let token = match start_tokens[index] {
                     true => token.trim_start().into(),
                     false => token,
                 };
-                start_token = false;
+                start_tokens[index] = false;
                 PartialChatChoice {
                     delta: PartialChatRecord::Content(token),
+                    index,
                     ..Default::default()
                 }
             }
             Token::Stop(finish_reason, _) => PartialChatChoice {
                 finish_reason,
+                index,
                 ..Default::default()
             },
-            Token::Done => return Ok(SseEvent::default().text("[DONE]")),
-            _ => unreachable!(),
+            Token::Done => {
+                pending -= 1;
+                let event = (pending == 0).then(|| Ok(SseEvent::default().text("[DONE]")));
+                return ready(event);
+            }
+            Token::Error(err) => {
+                let event = serde_json::to_string(&ApiErrorResponse::from(err))
+                    .map(|json_text| SseEvent::default().text(json_text));
+                return ready(Some(event));
+            }
+            _ => return ready(None),
         };
 
-        match serde_json::to_string(&PartialChatResponse {
+        let event = match serde_json::to_string(&PartialChatResponse {
             object: "chat.completion.chunk".into(),
             model: model_name.clone(),
             choices: vec![choice],
         }) {
             Ok(json_text) => Ok(SseEvent::default().text(json_text)),
             Err(err) => Err(err),
-        }
+        };
+        ready(Some(event))
     });
     salvo::sse::stream(res, stream);
 }
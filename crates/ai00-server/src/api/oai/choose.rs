@@ -8,7 +8,7 @@ use salvo::{
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    api::request_info,
+    api::{request_info, usage::Attribution},
     types::{Array, ThreadSender},
     SLEEP,
 };
@@ -117,9 +117,11 @@ pub async fn chooses(depot: &mut Depot, req: JsonBody<ChooseRequest>) -> Json<Ch
 
     let choices = request.choices.clone();
 
+    let mut request: GenerateRequest = request.into();
+    Attribution::of(depot).apply(&mut request);
     let (token_sender, token_receiver) = flume::unbounded();
     let _ = sender.send(ThreadRequest::Generate {
-        request: Box::new(request.into()),
+        request: Box::new(request),
         tokenizer: info.tokenizer,
         sender: token_sender,
     });
@@ -1,10 +1,15 @@
 use std::{collections::HashMap, sync::Arc};
 
 use ai00_core::{
-    FinishReason, GenerateRequest, InputState, ThreadRequest, Token, TokenCounter, MAX_TOKENS,
+    reload::TrafficClass, FinishReason, GenerateRequest, InputState, Token, TokenCounter,
+    MAX_TOKENS,
 };
 use derivative::Derivative;
-use futures_util::StreamExt;
+use futures_util::{
+    future::{join_all, ready},
+    stream::select_all,
+    StreamExt,
+};
 use salvo::{
     oapi::{extract::JsonBody, ToResponse, ToSchema},
     prelude::*,
@@ -15,12 +20,12 @@ use serde::{Deserialize, Serialize};
 
 use super::*;
 use crate::{
-    api::request_info,
+    api::{current_request_id, error::ApiErrorResponse, request_info, usage::Attribution},
     types::{Array, ThreadSender},
     SLEEP,
 };
 
-#[derive(Debug, Derivative, Deserialize, ToSchema)]
+#[derive(Debug, Clone, Derivative, Deserialize, ToSchema)]
 #[derivative(Default)]
 #[serde(default)]
 #[salvo(schema(
@@ -53,10 +58,15 @@ struct CompletionRequest {
     max_tokens: usize,
     #[derivative(Default(value = "Array::Item(\"\\n\\n\".into())"))]
     stop: Array<String>,
+    /// Token IDs that stop the generation. The stop token is not part of the output.
+    stop_tokens: Vec<u32>,
+    /// Raw byte sequences that stop the generation.
+    stop_bytes: Vec<Vec<u8>>,
     stream: bool,
     #[serde(alias = "logit_bias")]
     bias: HashMap<u32, f32>,
     bnf_schema: Option<String>,
+    regex: Option<String>,
     #[serde(alias = "sampler_override")]
     sampler: Option<SamplerParams>,
     #[derivative(Default(value = "0.5"))]
@@ -65,6 +75,15 @@ struct CompletionRequest {
     top_k: usize,
     #[derivative(Default(value = "1.0"))]
     temperature: f32,
+    strategy: Strategy,
+    /// Number of hypotheses kept by beam search.
+    #[derivative(Default(value = "4"))]
+    beam_width: usize,
+    /// Length normalization exponent of beam search.
+    #[derivative(Default(value = "1.0"))]
+    length_penalty: f32,
+    /// Number of independent completions to sample for the prompt.
+    n: Option<usize>,
 }
 
 impl From<CompletionRequest> for GenerateRequest {
@@ -74,12 +93,19 @@ impl From<CompletionRequest> for GenerateRequest {
             state,
             max_tokens,
             stop,
+            stop_tokens,
+            stop_bytes,
             sampler,
             top_p,
             top_k,
             temperature,
+            strategy,
+            beam_width,
+            length_penalty,
             bias,
             bnf_schema,
+            regex,
+            stream,
             ..
         } = value;
 
@@ -99,14 +125,21 @@ impl From<CompletionRequest> for GenerateRequest {
         };
         let state = state.into();
 
+        let kind = strategy.kind(beam_width, length_penalty);
+
         Self {
             prompt,
             max_tokens,
             stop,
+            stop_tokens,
+            stop_bytes,
             sampler,
             bias,
             bnf_schema,
+            regex,
+            kind,
             state,
+            traffic_class: TrafficClass::from_stream(stream),
             ..Default::default()
         }
     }
@@ -194,43 +227,32 @@ async fn respond_one(depot: &mut Depot, request: CompletionRequest, res: &mut Re
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
-
-    let mut token_counter = TokenCounter::default();
-    let mut finish_reason = FinishReason::Null;
-    let mut text = String::new();
-    let mut stream = token_receiver.into_stream();
+    let n = request.n.unwrap_or(1);
+    let request_id = current_request_id(depot);
+    let attribution = Attribution::of(depot);
+    let receivers = generate(sender, &attribution, request, n, request_id, info.tokenizer);
+    let outputs = join_all(receivers.into_iter().map(collect)).await;
+    let outputs = match outputs.into_iter().collect::<Result<Vec<_>, _>>() {
+        Ok(outputs) => outputs,
+        Err(err) => return err.respond(res),
+    };
 
-    while let Some(token) = stream.next().await {
-        match token {
-            Token::Start => {}
-            Token::Content(token) => {
-                text += &token;
-            }
-            Token::Stop(reason, counter) => {
-                finish_reason = reason;
-                token_counter = counter;
-                break;
-            }
-            _ => unreachable!(),
-        }
+    let mut counters = Vec::with_capacity(outputs.len());
+    let mut choices = Vec::with_capacity(outputs.len());
+    for (index, (text, finish_reason, counter)) in outputs.into_iter().enumerate() {
+        counters.push(counter);
+        choices.push(CompletionChoice {
+            text,
+            index,
+            finish_reason,
+        });
     }
 
     let json = Json(CompletionResponse {
         object: "text_completion".into(),
         model: model_name,
-        choices: vec![CompletionChoice {
-            text,
-            index: 0,
-            finish_reason,
-        }],
-        counter: token_counter,
+        choices,
+        counter: merge_counters(counters),
     });
     res.render(json);
 }
@@ -240,36 +262,52 @@ async fn respond_stream(depot: &mut Depot, request: CompletionRequest, res: &mut
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
-    let (token_sender, token_receiver) = flume::unbounded();
-    let request = Box::new(request.into());
-    let _ = sender.send(ThreadRequest::Generate {
-        request,
-        tokenizer: info.tokenizer,
-        sender: token_sender,
-    });
+    let n = request.n.unwrap_or(1);
+    let request_id = current_request_id(depot);
+    let attribution = Attribution::of(depot);
+    let receivers = generate(sender, &attribution, request, n, request_id, info.tokenizer);
 
-    let stream = token_receiver.into_stream().skip(1).map(move |token| {
+    // chunks of all choices are interleaved; `[DONE]` is sent after the last choice finishes
+    let mut pending = receivers.len();
+    let streams = receivers
+        .into_iter()
+        .enumerate()
+        .map(|(index, receiver)| receiver.into_stream().map(move |token| (index, token)));
+    let stream = select_all(streams).filter_map(move |(index, token)| {
         let choice = match token {
+            Token::Start => return ready(None),
             Token::Content(token) => PartialCompletionChoice {
                 delta: PartialCompletionRecord::Content(token),
+                index,
                 ..Default::default()
             },
             Token::Stop(finish_reason, _) => PartialCompletionChoice {
                 finish_reason,
+                index,
                 ..Default::default()
             },
-            Token::Done => return Ok(SseEvent::default().text("[DONE]")),
-            _ => unreachable!(),
+            Token::Done => {
+                pending -= 1;
+                let event = (pending == 0).then(|| Ok(SseEvent::default().text("[DONE]")));
+                return ready(event);
+            }
+            Token::Error(err) => {
+                let event = serde_json::to_string(&ApiErrorResponse::from(err))
+                    .map(|json_text| SseEvent::default().text(json_text));
+                return ready(Some(event));
+            }
+            _ => return ready(None),
         };
 
-        match serde_json::to_string(&PartialCompletionResponse {
+        let event = match serde_json::to_string(&PartialCompletionResponse {
             object: "text_completion.chunk".into(),
             model: model_name.clone(),
             choices: vec![choice],
         }) {
             Ok(json_text) => Ok(SseEvent::default().text(json_text)),
             Err(err) => Err(err),
-        }
+        };
+        ready(Some(event))
     });
     salvo::sse::stream(res, stream);
 }
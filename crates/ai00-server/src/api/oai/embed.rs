@@ -1,5 +1,3 @@
-use std::sync::Arc;
-
 use anyhow::Result;
 use derivative::Derivative;
 use salvo::{
@@ -9,6 +7,8 @@ use salvo::{
 use serde::{Deserialize, Serialize};
 use text_splitter::{ChunkConfig, TextSplitter};
 
+use crate::api::embed::EmbedQueue;
+
 #[derive(Debug, Serialize, ToSchema, ToResponse)]
 struct ChunkData {
     chunk: String,
@@ -53,22 +53,17 @@ pub async fn embeds(
     depot: &mut Depot,
     req: JsonBody<EmbedRequest>,
 ) -> Result<Json<EmbedResponse>, StatusCode> {
-    let embed = depot
-        .get::<Option<Arc<crate::TextEmbed>>>("embed")
-        .unwrap()
-        .clone();
-
-    let Some(embed) = embed else {
+    let embed = depot.obtain::<EmbedQueue>().unwrap();
+    if !embed.is_available() {
         return Err(StatusCode::BAD_REQUEST);
-    };
+    }
 
     if req.input.is_empty() {
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    let model = embed.info.model_code.clone();
-
-    let task = move || -> Result<_> {
+    let task = move |embed: &crate::TextEmbed| -> Result<_> {
+        let model = embed.info.model_code.clone();
         let max_tokens = req.max_tokens.clamp(1, 510);
         let splitter = TextSplitter::new(ChunkConfig::new(max_tokens).with_sizer(&embed.tokenizer));
 
@@ -81,12 +76,11 @@ pub async fn embeds(
             data.push(ChunkData { chunk, embed });
         }
 
-        Ok(data)
+        Ok((model, data))
     };
 
-    let handle = tokio::task::spawn_blocking(task);
-    match handle.await {
-        Ok(Ok(data)) => Ok(Json(EmbedResponse {
+    match embed.run(task).await {
+        Ok((model, data)) => Ok(Json(EmbedResponse {
             object: "embeds".into(),
             model,
             data: vec![EmbedData {
@@ -95,6 +89,6 @@ pub async fn embeds(
                 chunks: data,
             }],
         })),
-        _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
@@ -4,7 +4,11 @@ use salvo::{
 };
 use serde::Serialize;
 
-use crate::{api::request_info, types::ThreadSender, SLEEP};
+use crate::{
+    api::{request_info, request_models},
+    types::ThreadSender,
+    SLEEP,
+};
 
 /// Model capabilities for Claude API compatibility.
 #[derive(Debug, Clone, Serialize, ToSchema)]
@@ -49,32 +53,34 @@ struct ModelResponse {
     data: Vec<ModelChoice>,
 }
 
-/// Model name and id of the current choice.
+/// Names and ids of all loaded models.
 #[endpoint(responses((status_code = 200, body = ModelResponse)))]
 pub async fn models(depot: &mut Depot) -> Json<ModelResponse> {
     let sender = depot.obtain::<ThreadSender>().unwrap();
-    let info = request_info(sender.to_owned(), SLEEP).await;
-    let model_name = info
-        .reload
-        .model_path
-        .file_stem()
-        .map(|stem| stem.to_string_lossy())
-        .unwrap_or_default();
+    let infos = match request_models(sender.to_owned()).await {
+        Ok(infos) if !infos.is_empty() => infos,
+        _ => vec![request_info(sender.to_owned(), SLEEP).await],
+    };
+
+    let data = infos
+        .into_iter()
+        .map(|info| {
+            // Get model file creation time if available
+            let created = std::fs::metadata(&info.reload.model_path)
+                .ok()
+                .and_then(|m| m.created().ok())
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
 
-    // Get model file creation time if available
-    let created = std::fs::metadata(&info.reload.model_path)
-        .ok()
-        .and_then(|m| m.created().ok())
-        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-        .map(|d| d.as_secs());
+            ModelChoice {
+                object: "model".into(),
+                id: info.name,
+                created,
+                owned_by: Some("rwkv".into()),
+                capabilities: ModelCapabilities::default(),
+            }
+        })
+        .collect();
 
-    Json(ModelResponse {
-        data: vec![ModelChoice {
-            object: "model".into(),
-            id: model_name.into(),
-            created,
-            owned_by: Some("rwkv".into()),
-            capabilities: ModelCapabilities::default(),
-        }],
-    })
+    Json(ModelResponse { data })
 }
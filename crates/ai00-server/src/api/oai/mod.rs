@@ -1,14 +1,26 @@
 use std::sync::Arc;
 
-use ai00_core::sampler::{
-    mirostat::{MirostatParams, MirostatSampler},
-    nucleus::{NucleusParams, NucleusSampler},
-    typical::{TypicalParams, TypicalSampler},
-    Sampler,
+use ai00_core::{
+    sampler::{
+        beam::BeamParams,
+        dynatemp::{DynaTempParams, DynaTempSampler},
+        mirostat::{MirostatParams, MirostatSampler},
+        nucleus::{NucleusParams, NucleusSampler},
+        typical::{TypicalParams, TypicalSampler},
+        Sampler,
+    },
+    FinishReason, GenerateKind, GenerateRequest, ThreadRequest, Token, TokenCounter,
 };
+use futures_util::StreamExt;
 use salvo::oapi::ToSchema;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use web_rwkv::tokenizer::Tokenizer;
+
+use crate::{
+    api::{error::ApiErrorResponse, usage::Attribution},
+    types::ThreadSender,
+};
 
 mod chat;
 mod choose;
@@ -33,6 +45,30 @@ enum SamplerParams {
     Mirostat(MirostatParams),
     Typical(TypicalParams),
     Nucleus(NucleusParams),
+    DynaTemp(DynaTempParams),
+}
+
+/// How output tokens are chosen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum Strategy {
+    /// Sample tokens with the sampler.
+    #[default]
+    Sample,
+    /// Search for the most likely output with `beam_width` hypotheses. Ignores the sampler.
+    Beam,
+}
+
+impl Strategy {
+    fn kind(self, beam_width: usize, length_penalty: f32) -> GenerateKind {
+        match self {
+            Strategy::Sample => GenerateKind::None,
+            Strategy::Beam => GenerateKind::Beam(BeamParams {
+                beam_width,
+                length_penalty,
+            }),
+        }
+    }
 }
 
 impl Default for SamplerParams {
@@ -47,6 +83,122 @@ impl From<SamplerParams> for Arc<RwLock<dyn Sampler + Send + Sync>> {
             SamplerParams::Mirostat(params) => Arc::new(RwLock::new(MirostatSampler::new(params))),
             SamplerParams::Typical(params) => Arc::new(RwLock::new(TypicalSampler::new(params))),
             SamplerParams::Nucleus(params) => Arc::new(RwLock::new(NucleusSampler::new(params))),
+            SamplerParams::DynaTemp(params) => Arc::new(RwLock::new(DynaTempSampler::new(params))),
+        }
+    }
+}
+
+/// Upper limit of `n`, the number of completions sampled for one request.
+const MAX_CHOICES: usize = 16;
+
+/// Submit `n` independent samples of `request`, returning one token receiver per sample.
+///
+/// All samples share the prompt, which the runtime prefills only once.
+fn generate<R>(
+    sender: &ThreadSender,
+    attribution: &Attribution,
+    request: R,
+    n: usize,
+    request_id: Option<String>,
+    tokenizer: Arc<Tokenizer>,
+) -> Vec<flume::Receiver<Token>>
+where
+    R: Clone + Into<GenerateRequest>,
+{
+    let n = n.clamp(1, MAX_CHOICES);
+    let mut requests = Vec::with_capacity(n);
+    let mut receivers = Vec::with_capacity(n);
+    for index in 0..n {
+        let mut request: GenerateRequest = request.clone().into();
+        request.request_id = match index {
+            0 => request_id.clone(),
+            _ => request_id.as_ref().map(|id| format!("{id}-{index}")),
+        };
+        attribution.apply(&mut request);
+        let (token_sender, token_receiver) = flume::unbounded();
+        requests.push((Box::new(request), token_sender));
+        receivers.push(token_receiver);
+    }
+
+    let _ = match requests.len() {
+        1 => {
+            let (request, token_sender) = requests.remove(0);
+            sender.send(ThreadRequest::Generate {
+                request,
+                tokenizer,
+                sender: token_sender,
+            })
         }
+        _ => sender.send(ThreadRequest::GenerateMany {
+            requests,
+            tokenizer,
+        }),
+    };
+    receivers
+}
+
+/// Collect the whole text of one sample.
+async fn collect(
+    receiver: flume::Receiver<Token>,
+) -> Result<(String, FinishReason, TokenCounter), ApiErrorResponse> {
+    let mut token_counter = TokenCounter::default();
+    let mut finish_reason = FinishReason::Null;
+    let mut text = String::new();
+    let mut stream = receiver.into_stream();
+
+    while let Some(token) = stream.next().await {
+        match token {
+            Token::Start => {}
+            Token::Content(token) => {
+                text += &token;
+            }
+            Token::Stop(reason, counter) => {
+                finish_reason = reason;
+                token_counter = counter;
+                break;
+            }
+            Token::Error(err) => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok((text, finish_reason, token_counter))
+}
+
+/// Usage of several samples of one prompt: the prompt is counted once.
+fn merge_counters(counters: impl IntoIterator<Item = TokenCounter>) -> TokenCounter {
+    counters
+        .into_iter()
+        .reduce(|acc, counter| {
+            let completion = acc.completion + counter.completion;
+            TokenCounter {
+                prompt: acc.prompt,
+                completion,
+                total: acc.prompt + completion,
+                duration: acc.duration.max(counter.duration),
+                ..acc
+            }
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use ai00_core::GenerateError;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collect_generate_error() {
+        let (sender, receiver) = flume::unbounded();
+        let _ = sender.send(Token::Start);
+        let _ = sender.send(Token::Content("{".into()));
+        let _ = sender.send(Token::Error(GenerateError::GrammarBlocked {
+            output_tokens: 1,
+            grammar_state: String::new(),
+            blocked: vec![],
+        }));
+        let _ = sender.send(Token::Done);
+        let err = collect(receiver).await.unwrap_err();
+        assert_eq!(err.error.details.unwrap()["type"], "grammar_blocked");
     }
 }
@@ -7,7 +7,7 @@ use salvo::{
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    api::request_info,
+    api::{request_info, usage::Attribution},
     types::{Array, ThreadSender},
     SLEEP,
 };
@@ -64,9 +64,11 @@ pub async fn states(depot: &mut Depot, req: JsonBody<StateRequest>) -> Json<Stat
     let info = request_info(sender.clone(), SLEEP).await;
     let model_name = info.reload.model_path.to_string_lossy().into_owned();
 
+    let mut request: GenerateRequest = request.into();
+    Attribution::of(depot).apply(&mut request);
     let (token_sender, token_receiver) = flume::unbounded();
     let _ = sender.send(ThreadRequest::Generate {
-        request: Box::new(request.into()),
+        request: Box::new(request),
         tokenizer: info.tokenizer,
         sender: token_sender,
     });
@@ -0,0 +1,177 @@
+//! Export of request traces over OTLP, configured with `[otel]`.
+//!
+//! Every request gets a `request` span, continuing the trace of its `traceparent` header if it
+//! has one (W3C trace context). Generations sent to the runtime during the request carry the
+//! span, under which the runtime opens a `generate` span with its `queue`, `prefill`, `decode`
+//! (one for every 32 output tokens) and `cache_*` spans; prompts of the chat APIs are built in a
+//! `prompt_build` span.
+//!
+//! The layer exporting the spans has to be part of the subscriber before the config is read, so
+//! it starts out empty behind a [`reload::Layer`] and is filled in by [`install`].
+
+use std::time::Duration;
+
+use ai00_core::ThreadRequest;
+use anyhow::Result;
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    trace::TracerProvider as _,
+};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracer, SdkTracerProvider},
+    Resource,
+};
+use salvo::{http::header::HeaderMap, prelude::*};
+use tracing::{field::Empty, Instrument};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::{reload, Registry};
+
+use super::current_request_id;
+use crate::{config::OtelOption, types::ThreadSender};
+
+/// The layer exporting spans, once [`install`]ed.
+pub type OtelLayer = OpenTelemetryLayer<Registry, SdkTracer>;
+
+/// Start exporting spans to the collector of `option` through the layer behind `handle`.
+/// Returns the provider to shut down on exit, or `None` if no collector is set.
+pub fn install(
+    option: &OtelOption,
+    handle: &reload::Handle<Option<OtelLayer>, Registry>,
+) -> Result<Option<SdkTracerProvider>> {
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+
+    let Some(endpoint) = &option.endpoint else {
+        return Ok(None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .with_timeout(Duration::from_secs(option.timeout))
+        .build()?;
+    let sampler = Sampler::TraceIdRatioBased(option.sample_ratio.clamp(0.0, 1.0));
+    let resource = Resource::builder()
+        .with_service_name(option.service_name.clone())
+        .build();
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(sampler)))
+        .with_resource(resource)
+        .build();
+
+    let tracer = provider.tracer("ai00_server");
+    handle.reload(Some(tracing_opentelemetry::layer().with_tracer(tracer)))?;
+
+    tracing::info!(
+        event = "otel_installed",
+        endpoint = %endpoint,
+        service_name = %option.service_name,
+        sample_ratio = option.sample_ratio,
+        "Exporting traces over OTLP"
+    );
+    Ok(Some(provider))
+}
+
+/// Reads the trace context out of request headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Put a relay in front of the runtime, which makes `span` the parent of every generation sent
+/// through it.
+fn parent(span: tracing::Span, sender: ThreadSender) -> ThreadSender {
+    let (relay_sender, relay_receiver) = flume::unbounded::<ThreadRequest>();
+    tokio::spawn(async move {
+        while let Ok(mut request) = relay_receiver.recv_async().await {
+            match &mut request {
+                ThreadRequest::Generate { request, .. } => request.span = span.clone(),
+                ThreadRequest::GenerateMany { requests, .. } => requests
+                    .iter_mut()
+                    .for_each(|(request, _)| request.span = span.clone()),
+                _ => (),
+            }
+            if sender.send_async(request).await.is_err() {
+                break;
+            }
+        }
+    });
+    relay_sender
+}
+
+/// Trace the request, continuing the trace of its `traceparent` header.
+#[handler]
+pub async fn trace_request(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let cx = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let span = tracing::info_span!(
+        parent: None,
+        "request",
+        otel.name = %format!("{method} {path}"),
+        otel.kind = "server",
+        http.request.method = %method,
+        url.path = %path,
+        request_id = %current_request_id(depot).unwrap_or_default(),
+        http.response.status_code = Empty,
+    );
+    span.set_parent(cx);
+
+    if let Ok(sender) = depot.obtain::<ThreadSender>() {
+        let sender = parent(span.clone(), sender.clone());
+        depot.inject(sender);
+    }
+
+    ctrl.call_next(req, depot, res)
+        .instrument(span.clone())
+        .await;
+    let status = res.status_code.unwrap_or(StatusCode::OK);
+    span.record("http.response.status_code", status.as_u16());
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TraceContextExt;
+
+    use super::*;
+
+    #[test]
+    fn test_extract_traceparent() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+        let extractor = HeaderExtractor(&headers);
+        assert_eq!(extractor.keys(), ["traceparent"]);
+
+        let cx = TraceContextPropagator::new().extract(&extractor);
+        let span = cx.span();
+        let context = span.span_context();
+        assert!(context.is_remote() && context.is_sampled());
+        assert_eq!(
+            context.trace_id().to_string(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(context.span_id().to_string(), "b7ad6b7169203331");
+
+        // a malformed header starts a new trace
+        headers.insert("traceparent", "00-xyz-01".parse().unwrap());
+        let cx = TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+        assert!(!cx.span().span_context().is_valid());
+    }
+}
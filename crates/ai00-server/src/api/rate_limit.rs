@@ -0,0 +1,345 @@
+//! Token-bucket rate limiting of Messages requests per client.
+//!
+//! Every client, i.e. every API key or the IP of requests without one, has a bucket of requests
+//! and one of tokens, which refill continuously up to their per-minute limit. A request takes one
+//! from its request bucket and needs a non-empty token bucket; the tokens of its generations are
+//! charged when they finish, so a long generation may leave the token bucket in debt for a while.
+//! Batches and WebSocket sessions take one more request for each generation they carry.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use ai00_core::{GenerateRequest, StopHook, TokenCounter};
+use salvo::{http::HeaderValue, prelude::*};
+
+use super::{
+    auth::presented_key,
+    error::ApiErrorResponse,
+    usage::{self, Attribution},
+};
+use crate::config::{Config, RateLimitOption};
+
+pub const REQUESTS_LIMIT_HEADER: &str = "anthropic-ratelimit-requests-limit";
+pub const REQUESTS_REMAINING_HEADER: &str = "anthropic-ratelimit-requests-remaining";
+pub const TOKENS_LIMIT_HEADER: &str = "anthropic-ratelimit-tokens-limit";
+pub const TOKENS_REMAINING_HEADER: &str = "anthropic-ratelimit-tokens-remaining";
+
+/// Number of clients above which full buckets are dropped, since they are the same as new ones.
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    level: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        Self {
+            capacity: per_minute as f64,
+            level: per_minute as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.level = (self.level + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Whole seconds until the bucket holds `amount`.
+    fn wait(&self, amount: f64) -> u64 {
+        let rate = self.capacity / 60.0;
+        ((amount - self.level) / rate).ceil().max(1.0) as u64
+    }
+
+    fn remaining(&self) -> u64 {
+        self.level.max(0.0) as u64
+    }
+
+    fn is_full(&self) -> bool {
+        self.level >= self.capacity
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ClientBuckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+/// Which limit a request exceeded and when to retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    Requests { retry_after: u64 },
+    Tokens { retry_after: u64 },
+}
+
+impl From<Limited> for ApiErrorResponse {
+    fn from(value: Limited) -> Self {
+        let (message, retry_after) = match value {
+            Limited::Requests { retry_after } => ("requests per minute", retry_after),
+            Limited::Tokens { retry_after } => ("tokens per minute", retry_after),
+        };
+        ApiErrorResponse::rate_limit(format!("rate limit exceeded: {message}"))
+            .with_retry_after(retry_after)
+    }
+}
+
+/// What is left in the buckets of a client after admitting a request.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Remaining {
+    pub requests: Option<u64>,
+    pub tokens: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    option: RateLimitOption,
+    clients: Arc<Mutex<HashMap<String, ClientBuckets>>>,
+}
+
+impl RateLimiter {
+    pub fn new(option: RateLimitOption) -> Self {
+        Self {
+            option,
+            clients: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.option.requests_per_minute.is_some() || self.option.tokens_per_minute.is_some()
+    }
+
+    fn with_buckets<T>(
+        &self,
+        client: &str,
+        now: Instant,
+        f: impl FnOnce(&mut ClientBuckets) -> T,
+    ) -> T {
+        let mut clients = self.clients.lock().expect("rate limit buckets poisoned");
+        if clients.len() > PRUNE_THRESHOLD {
+            clients.retain(|_, buckets| {
+                [&mut buckets.requests, &mut buckets.tokens]
+                    .into_iter()
+                    .flatten()
+                    .any(|bucket| {
+                        bucket.refill(now);
+                        !bucket.is_full()
+                    })
+            });
+        }
+        let buckets = clients
+            .entry(client.to_string())
+            .or_insert_with(|| ClientBuckets {
+                requests: self
+                    .option
+                    .requests_per_minute
+                    .map(|limit| TokenBucket::new(limit, now)),
+                tokens: self
+                    .option
+                    .tokens_per_minute
+                    .map(|limit| TokenBucket::new(limit, now)),
+            });
+        f(buckets)
+    }
+
+    /// Take a request of `client` from its bucket, if neither of its buckets is exhausted.
+    pub fn admit(&self, client: &str, now: Instant) -> Result<Remaining, Limited> {
+        self.with_buckets(client, now, |buckets| {
+            if let Some(bucket) = &mut buckets.tokens {
+                bucket.refill(now);
+                if bucket.level < 1.0 {
+                    let retry_after = bucket.wait(1.0);
+                    return Err(Limited::Tokens { retry_after });
+                }
+            }
+            if let Some(bucket) = &mut buckets.requests {
+                bucket.refill(now);
+                if bucket.level < 1.0 {
+                    let retry_after = bucket.wait(1.0);
+                    return Err(Limited::Requests { retry_after });
+                }
+                bucket.level -= 1.0;
+            }
+            Ok(Remaining {
+                requests: buckets.requests.as_ref().map(TokenBucket::remaining),
+                tokens: buckets.tokens.as_ref().map(TokenBucket::remaining),
+            })
+        })
+    }
+
+    /// Take the `tokens` used by a generation of `client` from its bucket.
+    pub fn charge(&self, client: &str, tokens: usize, now: Instant) {
+        self.with_buckets(client, now, |buckets| {
+            if let Some(bucket) = &mut buckets.tokens {
+                bucket.refill(now);
+                bucket.level -= tokens as f64;
+            }
+        })
+    }
+
+    /// The hook charging the tokens of a generation to `client` once it stops.
+    pub fn hook(&self, client: String) -> StopHook {
+        let limiter = self.clone();
+        Arc::new(move |_: &GenerateRequest, counter: &TokenCounter| {
+            limiter.charge(&client, counter.prompt + counter.completion, Instant::now())
+        })
+    }
+}
+
+/// The buckets of the client of a request, for requests that carry several generations.
+#[derive(Debug, Clone)]
+pub struct RateClient {
+    limiter: RateLimiter,
+    client: String,
+}
+
+impl RateClient {
+    /// Take a request for another generation from the bucket of the client.
+    pub fn admit(&self) -> Result<Remaining, Limited> {
+        self.limiter.admit(&self.client, Instant::now())
+    }
+}
+
+/// The client whose buckets a request draws from: its API key, or else its IP.
+fn client_key(req: &Request, config: &Config) -> String {
+    if presented_key(req).is_some() {
+        return usage::client_id(req, config);
+    }
+    let addr = req.remote_addr();
+    addr.as_ipv4()
+        .map(|addr| addr.ip().to_string())
+        .or_else(|| addr.as_ipv6().map(|addr| addr.ip().to_string()))
+        .map(|ip| format!("ip_{ip}"))
+        .unwrap_or_else(|| usage::ANONYMOUS.into())
+}
+
+fn insert_header(res: &mut Response, name: &'static str, value: u64) {
+    res.headers_mut().insert(name, HeaderValue::from(value));
+}
+
+/// Reject requests of clients over their limits with `rate_limit_error` before they reach
+/// generation, and charge the tokens of the rest.
+#[handler]
+pub async fn limit(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let (client, hook) = {
+        let (Ok(limiter), Ok(config)) = (
+            depot.obtain::<RateLimiter>(),
+            depot.obtain::<Arc<Config>>(),
+        ) else {
+            return;
+        };
+        if !limiter.is_enabled() {
+            return;
+        }
+
+        let client = client_key(req, config);
+        let remaining = match limiter.admit(&client, Instant::now()) {
+            Ok(remaining) => remaining,
+            Err(limited) => {
+                ApiErrorResponse::from(limited).respond(res);
+                ctrl.skip_rest();
+                return;
+            }
+        };
+        if let (Some(limit), Some(remaining)) =
+            (limiter.option.requests_per_minute, remaining.requests)
+        {
+            insert_header(res, REQUESTS_LIMIT_HEADER, limit.into());
+            insert_header(res, REQUESTS_REMAINING_HEADER, remaining);
+        }
+        let hook = match (limiter.option.tokens_per_minute, remaining.tokens) {
+            (Some(limit), Some(remaining)) => {
+                insert_header(res, TOKENS_LIMIT_HEADER, limit.into());
+                insert_header(res, TOKENS_REMAINING_HEADER, remaining);
+                Some(limiter.hook(client.clone()))
+            }
+            _ => None,
+        };
+        let client = RateClient {
+            limiter: limiter.clone(),
+            client,
+        };
+        (client, hook)
+    };
+    depot.inject(client);
+    if let Some(hook) = hook {
+        Attribution::add_hook(depot, hook);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter(requests_per_minute: Option<u32>, tokens_per_minute: Option<u32>) -> RateLimiter {
+        RateLimiter::new(RateLimitOption {
+            requests_per_minute,
+            tokens_per_minute,
+        })
+    }
+
+    #[test]
+    fn test_requests_refill_over_the_minute() {
+        let limiter = limiter(Some(2), None);
+        let start = Instant::now();
+        assert_eq!(
+            limiter.admit("a", start),
+            Ok(Remaining {
+                requests: Some(1),
+                tokens: None
+            })
+        );
+        assert!(limiter.admit("a", start).is_ok());
+        assert_eq!(
+            limiter.admit("a", start),
+            Err(Limited::Requests { retry_after: 30 })
+        );
+        // other clients have their own buckets
+        assert!(limiter.admit("b", start).is_ok());
+
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.admit("a", later).is_ok());
+        assert!(limiter.admit("a", later).is_err());
+    }
+
+    #[test]
+    fn test_tokens_are_charged_after_generation() {
+        let limiter = limiter(None, Some(600));
+        let start = Instant::now();
+        assert!(limiter.admit("a", start).is_ok());
+        limiter.charge("a", 900, start);
+        // 300 tokens in debt at 10 tokens per second
+        assert_eq!(
+            limiter.admit("a", start),
+            Err(Limited::Tokens { retry_after: 31 })
+        );
+        let later = start + Duration::from_secs(31);
+        assert!(limiter.admit("a", later).is_ok());
+    }
+
+    #[test]
+    fn test_rate_client_takes_a_request_per_generation() {
+        let client = RateClient {
+            limiter: limiter(Some(2), None),
+            client: "a".into(),
+        };
+        assert!(client.admit().is_ok());
+        assert!(client.admit().is_ok());
+        assert!(matches!(client.admit(), Err(Limited::Requests { .. })));
+    }
+
+    #[test]
+    fn test_disabled_without_limits() {
+        assert!(!limiter(None, None).is_enabled());
+        assert!(limiter(None, Some(1)).is_enabled());
+    }
+}
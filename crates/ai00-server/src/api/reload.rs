@@ -0,0 +1,214 @@
+//! Reload of the config file without a restart.
+//!
+//! `POST /admin/config/reload` reads the config file again and swaps the sections that are read
+//! per request in at once: requests in flight keep the config they started with, later ones see
+//! the new one. These are `prompts`, `api_keys`, `stream`, `generation`, `batch` and `rate_limit`;
+//! the buckets of rate limits start over when their limits change. Changes to the model sections
+//! are only reported, since they take effect on the next `POST /admin/models/load`; the other
+//! sections are set up on startup and take effect on restart.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use salvo::prelude::*;
+use serde::Serialize;
+
+use super::{error::ApiErrorResponse, rate_limit::RateLimiter};
+use crate::config::Config;
+
+/// Sections applied on reload.
+const LIVE_SECTIONS: [&str; 6] = [
+    "prompts",
+    "api_keys",
+    "stream",
+    "generation",
+    "rate_limit",
+    "batch",
+];
+/// Sections that describe the model the runtime loads.
+const MODEL_SECTIONS: [&str; 10] = [
+    "model",
+    "lora",
+    "lora_adapters",
+    "state",
+    "tokenizer",
+    "bnf",
+    "adapter",
+    "fairness",
+    "warmup",
+    "cache_snapshot",
+];
+
+#[derive(Debug, Clone)]
+struct Running {
+    config: Arc<Config>,
+    rate_limiter: RateLimiter,
+}
+
+/// The running config, which [`inject`] hands to every request.
+#[derive(Debug, Clone)]
+pub struct LiveConfig {
+    path: PathBuf,
+    running: Arc<RwLock<Running>>,
+}
+
+impl LiveConfig {
+    pub fn new(path: PathBuf, config: Config) -> Self {
+        let rate_limiter = RateLimiter::new(config.rate_limit.clone());
+        let running = Running {
+            config: Arc::new(config),
+            rate_limiter,
+        };
+        Self {
+            path,
+            running: Arc::new(RwLock::new(running)),
+        }
+    }
+
+    fn running(&self) -> Running {
+        self.running
+            .read()
+            .expect("running config poisoned")
+            .clone()
+    }
+
+    /// Swap in the live sections of `config`, returning what changed.
+    fn apply(&self, config: Config) -> Result<ReloadResponse> {
+        let mut running = self.running.write().expect("running config poisoned");
+        let changed = changed_sections(&running.config, &config)?;
+
+        let mut next = Config::clone(&running.config);
+        next.prompts = config.prompts;
+        next.api_keys = config.api_keys;
+        next.stream = config.stream;
+        next.generation = config.generation;
+        next.batch = config.batch;
+        if changed.iter().any(|section| section == "rate_limit") {
+            running.rate_limiter = RateLimiter::new(config.rate_limit.clone());
+        }
+        next.rate_limit = config.rate_limit;
+        running.config = Arc::new(next);
+
+        Ok(ReloadResponse::new(changed))
+    }
+}
+
+/// Top-level sections that differ between `old` and `new`.
+fn changed_sections(old: &Config, new: &Config) -> Result<Vec<String>> {
+    let old = serde_json::to_value(old)?;
+    let new = serde_json::to_value(new)?;
+    let (Some(old), Some(new)) = (old.as_object(), new.as_object()) else {
+        return Ok(vec![]);
+    };
+    let mut sections: Vec<_> = old.keys().chain(new.keys()).cloned().collect();
+    sections.sort();
+    sections.dedup();
+    sections.retain(|section| old.get(section) != new.get(section));
+    Ok(sections)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ReloadResponse {
+    /// Sections that differ from the running config.
+    pub changed: Vec<String>,
+    /// Changed sections now in effect.
+    pub applied: Vec<String>,
+    /// Changed model sections, which take effect on the next `POST /admin/models/load`.
+    pub requires_model_reload: Vec<String>,
+    /// Changed sections set up on startup, which take effect on restart.
+    pub requires_restart: Vec<String>,
+}
+
+impl ReloadResponse {
+    fn new(changed: Vec<String>) -> Self {
+        let mut response = Self::default();
+        for section in &changed {
+            let list = match section.as_str() {
+                section if LIVE_SECTIONS.contains(&section) => &mut response.applied,
+                section if MODEL_SECTIONS.contains(&section) => &mut response.requires_model_reload,
+                _ => &mut response.requires_restart,
+            };
+            list.push(section.clone());
+        }
+        response.changed = changed;
+        response
+    }
+}
+
+/// Hand the running config and rate limiter to the request.
+#[handler]
+pub async fn inject(depot: &mut Depot) {
+    let Ok(live) = depot.obtain::<LiveConfig>() else {
+        return;
+    };
+    let Running {
+        config,
+        rate_limiter,
+    } = live.running();
+    depot.inject(config).inject(rate_limiter);
+}
+
+/// Read the config file again and apply the sections that can change without a restart.
+///
+/// `/admin/config/reload`.
+#[endpoint(responses(
+    (status_code = 200, body = ReloadResponse),
+    (status_code = 400, body = ApiErrorResponse),
+))]
+pub async fn reload(depot: &mut Depot) -> Result<Json<ReloadResponse>, ApiErrorResponse> {
+    let live = depot
+        .obtain::<LiveConfig>()
+        .map_err(|_| ApiErrorResponse::api_error("config reload is not available"))?
+        .clone();
+    let config = crate::load_config(&live.path).await.map_err(|err| {
+        ApiErrorResponse::invalid_request(format!("failed to load config: {err:#}"))
+    })?;
+    let response = live
+        .apply(config)
+        .map_err(|err| ApiErrorResponse::api_error(format!("failed to apply config: {err}")))?;
+    tracing::info!(
+        event = "config_reloaded",
+        path = %live.path.display(),
+        changed = ?response.changed,
+        requires_model_reload = ?response.requires_model_reload,
+        requires_restart = ?response.requires_restart,
+    );
+    Ok(Json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitOption;
+
+    #[test]
+    fn test_reload_applies_live_sections() {
+        let live = LiveConfig::new("Config.toml".into(), Config::default());
+        let mut config = Config {
+            rate_limit: RateLimitOption {
+                requests_per_minute: Some(10),
+                tokens_per_minute: None,
+            },
+            ..Default::default()
+        };
+        config.model.quant = 8;
+        config.listen.port = 8080;
+
+        let response = live.apply(config).unwrap();
+        assert_eq!(response.changed, ["listen", "model", "rate_limit"]);
+        assert_eq!(response.applied, ["rate_limit"]);
+        assert_eq!(response.requires_model_reload, ["model"]);
+        assert_eq!(response.requires_restart, ["listen"]);
+
+        let running = live.running();
+        assert!(running.rate_limiter.is_enabled());
+        assert_eq!(running.config.rate_limit.requests_per_minute, Some(10));
+        assert_eq!(running.config.model.quant, Config::default().model.quant);
+
+        let response = live.apply(Config::clone(&running.config)).unwrap();
+        assert!(response.changed.is_empty());
+    }
+}
@@ -17,12 +17,15 @@ pub const REQUEST_ID_HEADER: &str = "x-request-id";
 /// Header name for this service's span ID.
 pub const SPAN_ID_HEADER: &str = "x-span-id";
 
+/// Header name for this service's span ID, as sent by Anthropic's API.
+pub const ANTHROPIC_REQUEST_ID_HEADER: &str = "request-id";
+
 /// Handler that creates request context with trace_id and request_id.
 ///
 /// - Extracts `x-request-id` header as `trace_id` (for cross-service correlation)
 /// - Generates fresh UUID7 as `request_id` (this service's span ID)
 /// - Stores `RequestContext` in depot for downstream handlers
-/// - Adds both IDs to response headers
+/// - Adds both IDs to response headers (`request_id` also as `request-id`)
 #[handler]
 pub async fn request_id_handler(req: &mut Request, depot: &mut Depot, res: &mut Response) {
     // Extract trace_id from incoming header (cross-service correlation)
@@ -39,6 +42,9 @@ pub async fn request_id_handler(req: &mut Request, depot: &mut Depot, res: &mut
     if let Ok(value) = context.request_id.parse() {
         res.headers_mut().insert(SPAN_ID_HEADER, value);
     }
+    if let Ok(value) = context.request_id.parse() {
+        res.headers_mut().insert(ANTHROPIC_REQUEST_ID_HEADER, value);
+    }
 
     // Return trace_id in x-request-id if present, otherwise use our request_id
     let response_trace = trace_id.as_ref().unwrap_or(&context.request_id);
@@ -0,0 +1,254 @@
+//! Reranking of documents against a query, in the shape of the Cohere rerank API.
+//!
+//! With the `model` method, a document is scored by how likely the loaded model finds the query
+//! after it: the relevance score is the geometric mean probability of the query tokens, as with
+//! the perplexities of `/api/oai/chooses`. All documents are scored in one batch of the runtime.
+//! With the `embed` method, the score is the cosine similarity of the embeddings of the query
+//! and the document under the embed model, which needs the `embed` feature and `[embed]` config.
+
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use super::{error::ApiErrorResponse, similarity::perplexities};
+
+/// Most documents in one request.
+const MAX_DOCUMENTS: usize = 1000;
+/// Text the query follows when scored with the model.
+const QUERY_PROMPT: &str = "\n\nA query this text answers:";
+
+/// A document: text, or an object with a `text` field.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum RerankDocument {
+    Text(String),
+    Object { text: String },
+}
+
+impl RerankDocument {
+    pub fn text(&self) -> &str {
+        match self {
+            RerankDocument::Text(text) | RerankDocument::Object { text } => text,
+        }
+    }
+}
+
+/// How documents are scored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RerankMethod {
+    /// Likelihood of the query after the document under the language model.
+    #[default]
+    Model,
+    /// Cosine similarity of embeddings under the embed model.
+    Embed,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct RerankRequest {
+    /// Model to score with; the default model if not set.
+    #[serde(default)]
+    pub model: Option<String>,
+    pub query: String,
+    pub documents: Vec<RerankDocument>,
+    /// Number of results to return; all if not set.
+    #[serde(default)]
+    pub top_n: Option<usize>,
+    /// Include the documents in the results.
+    #[serde(default)]
+    pub return_documents: bool,
+    #[serde(default)]
+    pub method: RerankMethod,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RerankResult {
+    /// Index of the document in the request.
+    pub index: usize,
+    /// Relevance to the query, between 0 and 1.
+    pub relevance_score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<RerankDocument>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RerankResponse {
+    pub id: String,
+    /// Results by descending relevance.
+    pub results: Vec<RerankResult>,
+}
+
+fn validate(request: &RerankRequest) -> Result<(), ApiErrorResponse> {
+    if request.query.trim().is_empty() {
+        return Err(ApiErrorResponse::invalid_request("query cannot be empty").with_param("query"));
+    }
+    if request.documents.is_empty() {
+        let err = ApiErrorResponse::invalid_request("documents cannot be empty");
+        return Err(err.with_param("documents"));
+    }
+    if request.documents.len() > MAX_DOCUMENTS {
+        let message = format!("at most {MAX_DOCUMENTS} documents can be reranked at once");
+        return Err(ApiErrorResponse::invalid_request(message).with_param("documents"));
+    }
+    if request.top_n == Some(0) {
+        let err = ApiErrorResponse::invalid_request("top_n must be greater than 0");
+        return Err(err.with_param("top_n"));
+    }
+    Ok(())
+}
+
+/// Score the documents by the likelihood of the query after each of them.
+async fn score_with_model(
+    depot: &Depot,
+    request: &RerankRequest,
+) -> Result<Vec<f32>, ApiErrorResponse> {
+    let query = format!(" {}", request.query.trim());
+    let pairs = request
+        .documents
+        .iter()
+        .map(|document| (format!("{}{QUERY_PROMPT}", document.text()), query.clone()));
+    let perplexities = perplexities(depot, request.model.clone(), pairs, false).await?;
+    Ok(perplexities.into_iter().map(|ppl| (-ppl).exp()).collect())
+}
+
+/// Score the documents by the cosine similarity of their embeddings to the query's.
+#[cfg(feature = "embed")]
+async fn score_with_embed(
+    depot: &Depot,
+    request: &RerankRequest,
+) -> Result<Vec<f32>, ApiErrorResponse> {
+    let embed = depot
+        .obtain::<super::embed::EmbedQueue>()
+        .ok()
+        .filter(|embed| embed.is_available())
+        .ok_or_else(|| {
+            ApiErrorResponse::invalid_request("no embed model is loaded").with_param("method")
+        })?;
+
+    let mut texts = vec![format!("query: {}", request.query)];
+    texts.extend(
+        request
+            .documents
+            .iter()
+            .map(|document| format!("passage: {}", document.text())),
+    );
+    let embeddings = embed
+        .embed(texts)
+        .await
+        .map_err(|err| ApiErrorResponse::api_error(format!("failed to embed: {err}")))?;
+
+    let (query, documents) = embeddings
+        .split_first()
+        .ok_or_else(|| ApiErrorResponse::api_error("failed to embed"))?;
+    Ok(documents
+        .iter()
+        .map(|document| cosine_similarity(query, document).max(0.0))
+        .collect())
+}
+
+#[cfg(not(feature = "embed"))]
+async fn score_with_embed(
+    _depot: &Depot,
+    _request: &RerankRequest,
+) -> Result<Vec<f32>, ApiErrorResponse> {
+    Err(
+        ApiErrorResponse::invalid_request("the server is built without the `embed` feature")
+            .with_param("method"),
+    )
+}
+
+#[cfg_attr(not(feature = "embed"), allow(dead_code))]
+fn cosine_similarity(x: &[f32], y: &[f32]) -> f32 {
+    let dot: f32 = x.iter().zip(y).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm(x) * norm(y) {
+        norm if norm > 0.0 => dot / norm,
+        _ => 0.0,
+    }
+}
+
+/// Results by descending score, cut to `top_n`.
+fn rank(request: RerankRequest, scores: Vec<f32>) -> Vec<RerankResult> {
+    let top_n = request.top_n.unwrap_or(usize::MAX);
+    let return_documents = request.return_documents;
+    let mut results: Vec<_> = request
+        .documents
+        .into_iter()
+        .zip(scores)
+        .enumerate()
+        .map(|(index, (document, relevance_score))| RerankResult {
+            index,
+            relevance_score,
+            document: return_documents.then_some(document),
+        })
+        .collect();
+    results.sort_by(|x, y| y.relevance_score.total_cmp(&x.relevance_score));
+    results.truncate(top_n);
+    results
+}
+
+/// Rank documents by their relevance to a query.
+///
+/// `/api/v1/rerank`.
+#[endpoint(responses(
+    (status_code = 200, body = RerankResponse),
+    (status_code = 400, body = ApiErrorResponse),
+))]
+pub async fn rerank(
+    depot: &mut Depot,
+    body: JsonBody<RerankRequest>,
+) -> Result<Json<RerankResponse>, ApiErrorResponse> {
+    let request = body.0;
+    validate(&request)?;
+
+    let scores = match request.method {
+        RerankMethod::Model => score_with_model(depot, &request).await?,
+        RerankMethod::Embed => score_with_embed(depot, &request).await?,
+    };
+    Ok(Json(RerankResponse {
+        id: uuid::Uuid::now_v7().to_string(),
+        results: rank(request, scores),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_rank() {
+        let request: RerankRequest = serde_json::from_value(json!({
+            "query": "capital of France",
+            "documents": ["Berlin is in Germany.", {"text": "Paris is the capital of France."}, "Rome"],
+            "top_n": 2,
+            "return_documents": true
+        }))
+        .unwrap();
+        assert!(validate(&request).is_ok());
+        assert_eq!(request.method, RerankMethod::Model);
+
+        let results = rank(request, vec![0.1, 0.9, 0.3]);
+        let indices: Vec<_> = results.iter().map(|result| result.index).collect();
+        assert_eq!(indices, [1, 2]);
+        assert_eq!(
+            results[0].document.as_ref().map(RerankDocument::text),
+            Some("Paris is the capital of France.")
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let request: RerankRequest =
+            serde_json::from_value(json!({"query": "q", "documents": [], "top_n": 1})).unwrap();
+        let err = validate(&request).unwrap_err();
+        assert_eq!(err.error.param.as_deref(), Some("documents"));
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+}
@@ -0,0 +1,47 @@
+//! Live adjustment of in-flight generations.
+//!
+//! Generations are addressed by the ID returned in the `x-span-id` response header.
+//! Adjusted parameters apply from the next sampled token, so the output of an adjusted
+//! generation is not reproducible from its original request alone.
+
+use ai00_core::{sampler::SamplerAdjustment, AdjustSamplerResult, ThreadRequest};
+use salvo::{
+    oapi::extract::{JsonBody, PathParam},
+    prelude::*,
+};
+
+use super::error::ApiErrorResponse;
+use crate::types::ThreadSender;
+
+/// Change temperature, top_p, etc. of an in-flight generation.
+///
+/// `/api/requests/{id}/sampler`.
+#[endpoint]
+pub async fn adjust(
+    depot: &mut Depot,
+    id: PathParam<String>,
+    req: JsonBody<SamplerAdjustment>,
+) -> Result<StatusCode, ApiErrorResponse> {
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let adjustment = req.0;
+    adjustment
+        .validate()
+        .map_err(|err| ApiErrorResponse::invalid_request(err.to_string()))?;
+
+    let (result_sender, result_receiver) = flume::unbounded();
+    let _ = sender.send(ThreadRequest::AdjustSampler {
+        request_id: id.into_inner(),
+        adjustment,
+        sender: result_sender,
+    });
+    match result_receiver.recv_async().await {
+        Ok(AdjustSamplerResult::Adjusted) => Ok(StatusCode::OK),
+        Ok(AdjustSamplerResult::NotFound) => Err(ApiErrorResponse::not_found(
+            "no in-flight generation with this request id",
+        )),
+        Ok(AdjustSamplerResult::Rejected(message)) => {
+            Err(ApiErrorResponse::invalid_request(message))
+        }
+        Err(err) => Err(ApiErrorResponse::api_error(err.to_string())),
+    }
+}
@@ -0,0 +1,62 @@
+//! The state shared by every request: the runtime sender, stores, moderator and so on.
+//!
+//! [`SharedState`] injects its values into the depot of every request, and itself with them, so
+//! that requests answered outside of the router (the lines of a batch, the frames of a WebSocket
+//! session) get a depot with the same state through [`SharedState::fill`].
+
+use std::sync::Arc;
+
+use salvo::prelude::*;
+
+type Injection = Arc<dyn Fn(&mut Depot) + Send + Sync>;
+
+/// Handler injecting the shared state into the depot.
+#[derive(Clone, Default)]
+pub struct SharedState(Vec<Injection>);
+
+impl SharedState {
+    /// Add `value` to the state.
+    pub fn inject<V: Clone + Send + Sync + 'static>(mut self, value: V) -> Self {
+        self.0.push(Arc::new(move |depot: &mut Depot| {
+            depot.inject(value.clone());
+        }));
+        self
+    }
+
+    /// Inject every value of the state into `depot`.
+    pub fn fill(&self, depot: &mut Depot) {
+        self.0.iter().for_each(|inject| inject(depot));
+        depot.inject(self.clone());
+    }
+}
+
+#[async_trait]
+impl Handler for SharedState {
+    async fn handle(
+        &self,
+        _req: &mut Request,
+        depot: &mut Depot,
+        _res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        self.fill(depot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill() {
+        let state = SharedState::default().inject(1u32).inject("shared");
+        let mut depot = Depot::new();
+        state.fill(&mut depot);
+        assert_eq!(depot.obtain::<u32>().ok(), Some(&1));
+        assert_eq!(depot.obtain::<&str>().ok(), Some(&"shared"));
+
+        let mut other = Depot::new();
+        depot.obtain::<SharedState>().unwrap().fill(&mut other);
+        assert_eq!(other.obtain::<u32>().ok(), Some(&1));
+    }
+}
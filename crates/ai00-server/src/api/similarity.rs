@@ -0,0 +1,206 @@
+//! Similarity of text pairs under the loaded model, in the manner of a cross-encoder.
+//!
+//! The second text of a pair is scored by how likely the model finds it after the first: the
+//! mean log probability of its tokens, computed like the perplexities of `/api/oai/chooses`.
+//! The score is the geometric mean probability of those tokens, between 0 and 1. Calibrated
+//! pairs are scored by how much more likely the first text makes the second, which discounts
+//! texts that are likely anyway: the score is then above 0.5 if the first text helps. All pairs
+//! are scored in one batch of the runtime.
+//!
+//! This is cheap duplicate detection and entailment screening, not a trained similarity model.
+
+use ai00_core::{reload::TrafficClass, GenerateKind, GenerateRequest, ThreadRequest, Token};
+use futures_util::future::join_all;
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use super::{error::ApiErrorResponse, request_info_of, usage::Attribution};
+use crate::{types::ThreadSender, SLEEP};
+
+/// Most pairs in one request.
+const MAX_PAIRS: usize = 1000;
+
+/// Mean negative log probabilities of each choice after its prompt, in order.
+///
+/// With `calibrate`, the mean negative log probability of the choice on its own is subtracted.
+pub(super) async fn perplexities(
+    depot: &Depot,
+    model: Option<String>,
+    pairs: impl IntoIterator<Item = (String, String)>,
+    calibrate: bool,
+) -> Result<Vec<f32>, ApiErrorResponse> {
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let attribution = Attribution::of(depot);
+    let info = request_info_of(sender.clone(), model.as_deref().unwrap_or_default(), SLEEP).await?;
+
+    let receivers: Vec<_> = pairs
+        .into_iter()
+        .map(|(prompt, choice)| {
+            let (token_sender, token_receiver) = flume::unbounded();
+            let mut generate = GenerateRequest {
+                prompt,
+                max_tokens: 1,
+                kind: GenerateKind::Choose {
+                    choices: vec![choice],
+                    calibrate,
+                },
+                model: model.clone(),
+                traffic_class: TrafficClass::Batch,
+                ..Default::default()
+            };
+            attribution.apply(&mut generate);
+            let _ = sender.send(ThreadRequest::Generate {
+                request: Box::new(generate),
+                tokenizer: info.tokenizer.clone(),
+                sender: token_sender,
+            });
+            token_receiver
+        })
+        .collect();
+
+    let perplexities = join_all(receivers.into_iter().map(|receiver| async move {
+        while let Ok(token) = receiver.recv_async().await {
+            if let Token::Choose(ppl) = token {
+                return ppl.first().copied();
+            }
+        }
+        None
+    }))
+    .await;
+    perplexities
+        .into_iter()
+        .map(|ppl| ppl.ok_or_else(|| ApiErrorResponse::api_error("failed to score text")))
+        .collect()
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[salvo(schema(example = json!({
+    "pairs": [["The cat sat on the mat.", "A cat was sitting on a mat."]],
+    "calibrate": true
+})))]
+pub struct SimilarityRequest {
+    /// Model to score with; the default model if not set.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Pairs of texts; the second of each is scored after the first.
+    pub pairs: Vec<[String; 2]>,
+    /// Text between the texts of a pair.
+    #[serde(default = "default_separator")]
+    pub separator: String,
+    /// Score by how much the first text raises the probability of the second.
+    #[serde(default)]
+    pub calibrate: bool,
+}
+
+fn default_separator() -> String {
+    "\n\n".into()
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SimilarityResult {
+    /// Index of the pair in the request.
+    pub index: usize,
+    /// Similarity between 0 and 1.
+    pub score: f32,
+    /// Mean log probability of the tokens of the second text after the first; with
+    /// `calibrate`, less that of the second text on its own.
+    pub log_prob: f32,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SimilarityResponse {
+    pub id: String,
+    /// Results in the order of the pairs.
+    pub results: Vec<SimilarityResult>,
+}
+
+fn validate(request: &SimilarityRequest) -> Result<(), ApiErrorResponse> {
+    if request.pairs.is_empty() {
+        let err = ApiErrorResponse::invalid_request("pairs cannot be empty");
+        return Err(err.with_param("pairs"));
+    }
+    if request.pairs.len() > MAX_PAIRS {
+        let message = format!("at most {MAX_PAIRS} pairs can be scored at once");
+        return Err(ApiErrorResponse::invalid_request(message).with_param("pairs"));
+    }
+    if let Some(index) = request.pairs.iter().position(|[_, b]| b.trim().is_empty()) {
+        let err = ApiErrorResponse::invalid_request("the second text of a pair cannot be empty");
+        return Err(err.with_param(format!("pairs.{index}")));
+    }
+    Ok(())
+}
+
+/// The score of a pair, from the mean log probability of its second text.
+fn score(log_prob: f32, calibrate: bool) -> f32 {
+    match calibrate {
+        // ratio of the probabilities with and without the first text, squashed into (0, 1)
+        true => 1.0 / (1.0 + (-log_prob).exp()),
+        false => log_prob.exp(),
+    }
+}
+
+/// Score the similarity of text pairs.
+///
+/// `/api/similarity`.
+#[endpoint(responses(
+    (status_code = 200, body = SimilarityResponse),
+    (status_code = 400, body = ApiErrorResponse),
+))]
+pub async fn similarity(
+    depot: &mut Depot,
+    body: JsonBody<SimilarityRequest>,
+) -> Result<Json<SimilarityResponse>, ApiErrorResponse> {
+    let request = body.0;
+    validate(&request)?;
+
+    let pairs = request.pairs.iter().map(|[a, b]| {
+        let prompt = format!("{a}{}", request.separator);
+        (prompt, b.clone())
+    });
+    let perplexities = perplexities(depot, request.model.clone(), pairs, request.calibrate).await?;
+
+    let results = perplexities
+        .into_iter()
+        .enumerate()
+        .map(|(index, ppl)| SimilarityResult {
+            index,
+            score: score(-ppl, request.calibrate),
+            log_prob: -ppl,
+        })
+        .collect();
+    Ok(Json(SimilarityResponse {
+        id: uuid::Uuid::now_v7().to_string(),
+        results,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let request: SimilarityRequest =
+            serde_json::from_value(json!({"pairs": [["a", "b"], ["c", " "]]})).unwrap();
+        assert_eq!(request.separator, "\n\n");
+        assert!(!request.calibrate);
+        let err = validate(&request).unwrap_err();
+        assert_eq!(err.error.param.as_deref(), Some("pairs.1"));
+
+        let request: SimilarityRequest = serde_json::from_value(json!({"pairs": []})).unwrap();
+        let err = validate(&request).unwrap_err();
+        assert_eq!(err.error.param.as_deref(), Some("pairs"));
+    }
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score(0.0, false), 1.0);
+        assert!((score(-2.0_f32.ln(), false) - 0.5).abs() < 1e-6);
+        // as likely with as without the first text
+        assert_eq!(score(0.0, true), 0.5);
+        assert!(score(1.0, true) > 0.5);
+        assert!(score(-1.0, true) < 0.5);
+    }
+}
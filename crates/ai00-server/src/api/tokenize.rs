@@ -0,0 +1,155 @@
+//! Inspection of how the tokenizer of a model segments text.
+
+use salvo::{oapi::extract::JsonBody, prelude::*};
+use serde::{Deserialize, Serialize};
+use web_rwkv::tokenizer::Tokenizer;
+
+use super::{error::ApiErrorResponse, request_info_of};
+use crate::{types::ThreadSender, SLEEP};
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct TokenizeRequest {
+    /// Model whose tokenizer to use; the default model if not set.
+    #[serde(default)]
+    pub model: Option<String>,
+    pub text: String,
+}
+
+/// A token and the bytes of the text it stands for.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct TokenPiece {
+    pub id: u32,
+    /// Offset of the first byte in the UTF-8 text.
+    pub start: usize,
+    /// Offset past the last byte in the UTF-8 text.
+    pub end: usize,
+    /// Text of the token. Tokens that split a character show replacement characters.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenizeResponse {
+    pub count: usize,
+    pub tokens: Vec<TokenPiece>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct DetokenizeRequest {
+    /// Model whose tokenizer to use; the default model if not set.
+    #[serde(default)]
+    pub model: Option<String>,
+    pub tokens: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DetokenizeResponse {
+    /// The decoded text, with replacement characters for invalid UTF-8.
+    pub text: String,
+    /// Text of each token.
+    pub pieces: Vec<String>,
+}
+
+/// Split `text` into tokens along with their byte offsets.
+pub fn tokenize(tokenizer: &Tokenizer, text: &str) -> anyhow::Result<Vec<TokenPiece>> {
+    let mut start = 0;
+    let mut pieces = vec![];
+    for id in tokenizer.encode(text.as_bytes())? {
+        let bytes = tokenizer.decode(&[id])?;
+        let end = start + bytes.len();
+        pieces.push(TokenPiece {
+            id,
+            start,
+            end,
+            text: String::from_utf8_lossy(&bytes).into_owned(),
+        });
+        start = end;
+    }
+    Ok(pieces)
+}
+
+async fn tokenizer_of(
+    depot: &Depot,
+    model: Option<&str>,
+) -> Result<std::sync::Arc<Tokenizer>, ApiErrorResponse> {
+    let sender = depot.obtain::<ThreadSender>().unwrap();
+    let info = request_info_of(sender.clone(), model.unwrap_or_default(), SLEEP).await?;
+    Ok(info.tokenizer)
+}
+
+/// Split text into the tokens of a model, with the byte offsets and text of each.
+///
+/// `/api/tokenize`.
+#[endpoint(responses(
+    (status_code = 200, body = TokenizeResponse),
+    (status_code = 400, body = ApiErrorResponse),
+    (status_code = 404, body = ApiErrorResponse),
+))]
+pub async fn tokenize_text(depot: &mut Depot, req: JsonBody<TokenizeRequest>, res: &mut Response) {
+    let TokenizeRequest { model, text } = req.0;
+    let tokenizer = match tokenizer_of(depot, model.as_deref()).await {
+        Ok(tokenizer) => tokenizer,
+        Err(err) => return err.respond(res),
+    };
+    match tokenize(&tokenizer, &text) {
+        Ok(tokens) => res.render(Json(TokenizeResponse {
+            count: tokens.len(),
+            tokens,
+        })),
+        Err(err) => ApiErrorResponse::invalid_request(format!("failed to tokenize: {err}"))
+            .with_param("text")
+            .respond(res),
+    }
+}
+
+/// Decode token ids of a model into text.
+///
+/// `/api/detokenize`.
+#[endpoint(responses(
+    (status_code = 200, body = DetokenizeResponse),
+    (status_code = 400, body = ApiErrorResponse),
+    (status_code = 404, body = ApiErrorResponse),
+))]
+pub async fn detokenize(depot: &mut Depot, req: JsonBody<DetokenizeRequest>, res: &mut Response) {
+    let DetokenizeRequest { model, tokens } = req.0;
+    let tokenizer = match tokenizer_of(depot, model.as_deref()).await {
+        Ok(tokenizer) => tokenizer,
+        Err(err) => return err.respond(res),
+    };
+    let decode = || -> anyhow::Result<DetokenizeResponse> {
+        let text = String::from_utf8_lossy(&tokenizer.decode(&tokens)?).into_owned();
+        let pieces = tokens
+            .iter()
+            .map(|&id| Ok(String::from_utf8_lossy(&tokenizer.decode(&[id])?).into_owned()))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(DetokenizeResponse { text, pieces })
+    };
+    match decode() {
+        Ok(response) => res.render(Json(response)),
+        Err(err) => ApiErrorResponse::invalid_request(format!("failed to decode: {err}"))
+            .with_param("tokens")
+            .respond(res),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_ranges() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../assets/tokenizer/rwkv_vocab_v20230424.json"
+        );
+        let vocab = std::fs::read_to_string(path).unwrap();
+        let tokenizer = Tokenizer::new(&vocab).unwrap();
+        let text = "Hello, 世界!";
+        let pieces = tokenize(&tokenizer, text).unwrap();
+        assert!(!pieces.is_empty());
+        assert_eq!(pieces.last().unwrap().end, text.len());
+        for piece in &pieces {
+            let bytes = tokenizer.decode(&[piece.id]).unwrap();
+            assert_eq!(&text.as_bytes()[piece.start..piece.end], &bytes[..]);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Uploads of models, LoRAs and states into `model.path`, so that a remote server can be
+//! provisioned without shell access.
+//!
+//! A file is uploaded in chunks, each a multipart `POST /admin/files/upload` with the fields
+//! `name` (path of the file under `model.path`), `offset` (bytes of the file already uploaded),
+//! `size` (bytes of the whole file), optionally `sha256` (hex digest of the whole file), and the
+//! chunk itself as `file`. Chunks are appended to `<name>.part`; an interrupted upload resumes at
+//! the offset `GET /admin/files/upload?name=` reports, and an upload from offset 0 starts over.
+//! Once all `size` bytes are there, the file is checked against `sha256` and moved to `name`;
+//! a file that does not match is deleted.
+//!
+//! Uploads only go into `assets/models`, whatever `model.path` is set to.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use salvo::{oapi::extract::QueryParam, prelude::*};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::error::ApiErrorResponse;
+use crate::{build_path, check_path_permitted, config::Config};
+
+/// Directories that files may be uploaded into.
+const UPLOAD_PATHS: [&str; 1] = ["assets/models"];
+/// Extensions of the files that may be uploaded.
+const UPLOAD_EXTENSIONS: [&str; 6] = ["st", "safetensors", "gguf", "pth", "bin", "prefab"];
+/// Largest chunk in one request.
+const MAX_CHUNK_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct UploadStatus {
+    pub name: String,
+    /// Bytes of the file received so far.
+    pub received: u64,
+    /// Whether the file is complete and in place.
+    pub complete: bool,
+    /// Hex SHA-256 digest of the file, once complete.
+    pub sha256: Option<String>,
+}
+
+/// The file `name` resolves to under `root`, if it may be uploaded.
+fn upload_path(root: &Path, name: &str) -> Result<PathBuf, ApiErrorResponse> {
+    let path = build_path(root, name)
+        .map_err(|err| ApiErrorResponse::invalid_request(err.to_string()).with_param("name"))?;
+    let permitted = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| UPLOAD_EXTENSIONS.contains(&ext));
+    if !permitted || path.file_name().is_none() {
+        let message = format!(
+            "only {} files can be uploaded",
+            UPLOAD_EXTENSIONS.join(", ")
+        );
+        return Err(ApiErrorResponse::invalid_request(message).with_param("name"));
+    }
+    if check_path_permitted(root, &UPLOAD_PATHS).is_err() {
+        return Err(ApiErrorResponse::permission(
+            "uploads are only permitted into assets/models",
+        ));
+    }
+    Ok(path)
+}
+
+/// The file that chunks of `path` are appended to until it is complete.
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+fn file_size(path: &Path) -> u64 {
+    path.metadata().map(|meta| meta.len()).unwrap_or_default()
+}
+
+/// Append the chunk in the file `chunk` to `part`, which must have `offset` bytes already, or
+/// start `part` over if `offset` is 0. Returns the new size of `part`, or `Err` with its size
+/// if the offset is wrong.
+fn append_chunk(part: &Path, offset: u64, chunk: &Path) -> Result<Result<u64, u64>> {
+    let received = file_size(part);
+    if offset != 0 && offset != received {
+        return Ok(Err(received));
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(offset != 0)
+        .truncate(offset == 0)
+        .open(part)?;
+    let mut reader = BufReader::new(File::open(chunk)?);
+    std::io::copy(&mut reader, &mut file)?;
+    file.flush()?;
+    Ok(Ok(file_size(part)))
+}
+
+/// Hex SHA-256 digest of the whole file at `path`.
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut sha = Sha256::new();
+    std::io::copy(&mut BufReader::new(File::open(path)?), &mut sha)?;
+    Ok(format!("{:x}", sha.finalize()))
+}
+
+/// Move the complete `part` into `path` if it matches `sha256`, otherwise delete it.
+/// Returns the digest of the file.
+fn finish_upload(part: &Path, path: &Path, sha256: Option<&str>) -> Result<Result<String, String>> {
+    let digest = sha256_file(part)?;
+    if sha256.is_some_and(|sha256| !sha256.eq_ignore_ascii_case(&digest)) {
+        std::fs::remove_file(part)?;
+        return Ok(Err(digest));
+    }
+    std::fs::rename(part, path)?;
+    Ok(Ok(digest))
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T, ApiErrorResponse> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) => Err(ApiErrorResponse::api_error(format!("upload failed: {err}"))),
+        Err(err) => Err(ApiErrorResponse::api_error(format!("upload failed: {err}"))),
+    }
+}
+
+/// Create the directory `dir` of an upload. The nearest of its ancestors that exists is checked
+/// to be `permitted` first, as it may be a link out of the permitted directories, which the
+/// directories would otherwise be created through.
+fn create_upload_dir(
+    dir: &Path,
+    permitted: impl Fn(&Path) -> bool,
+) -> Result<(), ApiErrorResponse> {
+    let existing = dir.ancestors().find(|ancestor| ancestor.exists());
+    if !existing.is_some_and(permitted) {
+        return Err(ApiErrorResponse::permission(
+            "uploads are only permitted into assets/models",
+        ));
+    }
+    std::fs::create_dir_all(dir)
+        .map_err(|err| ApiErrorResponse::api_error(format!("cannot create directory: {err}")))
+}
+
+/// Upload a chunk of a model, LoRA or state file.
+///
+/// `/admin/files/upload`.
+#[endpoint(responses(
+    (status_code = 200, body = UploadStatus),
+    (status_code = 400, body = ApiErrorResponse),
+    (status_code = 403, body = ApiErrorResponse),
+))]
+pub async fn upload(
+    depot: &mut Depot,
+    req: &mut Request,
+) -> Result<Json<UploadStatus>, ApiErrorResponse> {
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    req.set_secure_max_size(MAX_CHUNK_SIZE);
+
+    let missing = |param: &str| {
+        ApiErrorResponse::invalid_request(format!("missing field `{param}`")).with_param(param)
+    };
+    let name: String = req.form("name").await.ok_or_else(|| missing("name"))?;
+    let offset: u64 = req.form("offset").await.unwrap_or_default();
+    let size: u64 = req.form("size").await.ok_or_else(|| missing("size"))?;
+    let sha256: Option<String> = req.form("sha256").await;
+    let chunk = req
+        .file("file")
+        .await
+        .map(|file| file.path().clone())
+        .ok_or_else(|| missing("file"))?;
+
+    let path = upload_path(&config.model.path, &name)?;
+    if path.exists() {
+        let err = ApiErrorResponse::invalid_request(format!("file `{name}` already exists"));
+        return Err(err.with_param("name"));
+    }
+    if let Some(parent) = path.parent() {
+        create_upload_dir(parent, |dir| {
+            check_path_permitted(dir, &UPLOAD_PATHS).is_ok()
+        })?;
+    }
+
+    let part = part_path(&path);
+    let received = {
+        let part = part.clone();
+        blocking(move || append_chunk(&part, offset, &chunk)).await?
+    };
+    let received = received.map_err(|received| {
+        let message = format!("offset {offset} does not match the {received} bytes received");
+        ApiErrorResponse::invalid_request(message)
+            .with_param("offset")
+            .with_details(serde_json::json!({ "received": received }))
+    })?;
+
+    if received > size {
+        let _ = std::fs::remove_file(&part);
+        let message = format!("received {received} bytes, more than the size of {size} bytes");
+        return Err(ApiErrorResponse::invalid_request(message).with_param("size"));
+    }
+    if received < size {
+        return Ok(Json(UploadStatus {
+            name,
+            received,
+            complete: false,
+            sha256: None,
+        }));
+    }
+
+    let digest = {
+        let path = path.clone();
+        let sha256 = sha256.clone();
+        blocking(move || finish_upload(&part, &path, sha256.as_deref())).await?
+    };
+    match digest {
+        Ok(digest) => {
+            tracing::info!(
+                event = "file_uploaded",
+                path = %path.display(),
+                size,
+                sha256 = %digest,
+                "File uploaded"
+            );
+            Ok(Json(UploadStatus {
+                name,
+                received,
+                complete: true,
+                sha256: Some(digest),
+            }))
+        }
+        Err(digest) => {
+            tracing::warn!(
+                event = "file_upload_corrupted",
+                path = %path.display(),
+                expected = sha256.as_deref().unwrap_or_default(),
+                actual = %digest,
+                "Uploaded file does not match its digest"
+            );
+            let message = format!("SHA-256 of the file is {digest}; the upload is discarded");
+            Err(ApiErrorResponse::invalid_request(message).with_param("sha256"))
+        }
+    }
+}
+
+/// How much of a file has been uploaded, to resume the upload from.
+///
+/// `/admin/files/upload`.
+#[endpoint(responses(
+    (status_code = 200, body = UploadStatus),
+    (status_code = 400, body = ApiErrorResponse),
+))]
+pub async fn status(
+    depot: &mut Depot,
+    name: QueryParam<String, true>,
+) -> Result<Json<UploadStatus>, ApiErrorResponse> {
+    let config = depot.obtain::<Arc<Config>>().unwrap();
+    let name = name.into_inner();
+    let path = upload_path(&config.model.path, &name)?;
+    let complete = path.is_file();
+    let received = match complete {
+        true => file_size(&path),
+        false => file_size(&part_path(&path)),
+    };
+    Ok(Json(UploadStatus {
+        name,
+        received,
+        complete,
+        sha256: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_part_path() {
+        let path = Path::new("assets/models/rwkv.st");
+        assert_eq!(part_path(path), Path::new("assets/models/rwkv.st.part"));
+    }
+
+    #[test]
+    fn test_append_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("model.st.part");
+        let chunk = dir.path().join("chunk");
+
+        std::fs::write(&chunk, b"abc").unwrap();
+        assert_eq!(append_chunk(&part, 0, &chunk).unwrap(), Ok(3));
+        assert_eq!(append_chunk(&part, 3, &chunk).unwrap(), Ok(6));
+        // a chunk that was already received, or one that skips bytes
+        assert_eq!(append_chunk(&part, 3, &chunk).unwrap(), Err(6));
+        assert_eq!(append_chunk(&part, 9, &chunk).unwrap(), Err(6));
+        // starting over
+        assert_eq!(append_chunk(&part, 0, &chunk).unwrap(), Ok(3));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_upload_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let models = dir.path().join("models");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(&models).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, models.join("link")).unwrap();
+
+        let root = models.canonicalize().unwrap();
+        let permitted = |dir: &Path| dir.canonicalize().is_ok_and(|dir| dir.starts_with(&root));
+        create_upload_dir(&models.join("a/b"), permitted).unwrap();
+        assert!(models.join("a/b").is_dir());
+
+        // nothing is created through a link out of the permitted directory
+        let err = create_upload_dir(&models.join("link/a/b"), permitted).unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        assert!(!outside.join("a").exists());
+    }
+
+    #[test]
+    fn test_finish_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("model.st.part");
+        let path = dir.path().join("model.st");
+        // SHA-256 of "abc"
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+        std::fs::write(&part, b"abd").unwrap();
+        assert!(finish_upload(&part, &path, Some(digest)).unwrap().is_err());
+        assert!(!part.exists() && !path.exists());
+
+        std::fs::write(&part, b"abc").unwrap();
+        let sha256 = digest.to_uppercase();
+        assert_eq!(
+            finish_upload(&part, &path, Some(&sha256)).unwrap(),
+            Ok(digest.to_string())
+        );
+        assert!(!part.exists());
+        assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+    }
+}
@@ -0,0 +1,475 @@
+//! Accounting of token usage per API key.
+//!
+//! Every generation sent to the runtime is attributed to the client that made the request:
+//! the name of a configured API key, the `app_id` of an app key, a fingerprint of any other key, or `anonymous`.
+//! Handlers apply the [`Attribution`] of the request to each generation they send, and the
+//! runtime calls its [`StopHooks`] with the final token counts once the generation stops.
+//! Totals are aggregated in memory per minute and, with the `sqlite` feature, appended to a
+//! database so that they survive restarts. `GET /api/usage?since=...` reports them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ai00_core::{GenerateRequest, StopHook, TokenCounter};
+use anyhow::Result;
+use flume::Sender;
+use salvo::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::{
+    auth::{constant_time_eq, find_key, presented_key},
+    error::ApiErrorResponse,
+};
+use crate::config::{Config, UsageOption};
+
+/// Client of requests without an API key.
+pub const ANONYMOUS: &str = "anonymous";
+/// Width of the aggregation buckets in seconds.
+const BUCKET_SECS: u64 = 60;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Tokens used by a single generation.
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    pub client: String,
+    pub model: Option<String>,
+    /// Unix timestamp in seconds.
+    pub timestamp: u64,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, other: &Self) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+impl From<&UsageRecord> for UsageTotals {
+    fn from(record: &UsageRecord) -> Self {
+        Self {
+            requests: 1,
+            prompt_tokens: record.prompt_tokens as u64,
+            completion_tokens: record.completion_tokens as u64,
+        }
+    }
+}
+
+/// Usage of every client, bucketed by the start of the minute.
+type Buckets = HashMap<String, BTreeMap<u64, UsageTotals>>;
+
+/// Usage aggregated per client, shared between requests.
+#[derive(Debug, Clone)]
+pub struct UsageLedger {
+    retention: Duration,
+    buckets: Arc<Mutex<Buckets>>,
+    sink: Option<Sender<UsageRecord>>,
+}
+
+impl UsageLedger {
+    /// Create the ledger, reloading records within the retention period from the database.
+    pub fn new(option: &UsageOption) -> Result<Self> {
+        let retention = Duration::from_secs(option.retention_days * 24 * 60 * 60);
+        let ledger = Self {
+            retention,
+            buckets: Default::default(),
+            sink: None,
+        };
+
+        #[cfg(feature = "sqlite")]
+        let ledger = match &option.sqlite {
+            Some(path) => {
+                let connection = sqlite::open(path)?;
+                let since = now().saturating_sub(retention.as_secs());
+                for record in sqlite::load(&connection, since)? {
+                    ledger.aggregate(&record);
+                }
+                let sink = Some(sqlite::spawn(connection));
+                Self { sink, ..ledger }
+            }
+            None => ledger,
+        };
+        #[cfg(not(feature = "sqlite"))]
+        if option.sqlite.is_some() {
+            tracing::warn!(
+                event = "usage_sqlite_disabled",
+                "`usage.sqlite` is set but the server was built without the `sqlite` feature"
+            );
+        }
+
+        Ok(ledger)
+    }
+
+    fn aggregate(&self, record: &UsageRecord) {
+        let horizon = now().saturating_sub(self.retention.as_secs());
+        if record.timestamp < horizon {
+            return;
+        }
+        let bucket = record.timestamp / BUCKET_SECS * BUCKET_SECS;
+        let mut buckets = self.buckets.lock().unwrap();
+        let client = buckets.entry(record.client.clone()).or_default();
+        client.entry(bucket).or_default().add(&record.into());
+        // drop buckets past the retention period
+        *client = client.split_off(&(horizon / BUCKET_SECS * BUCKET_SECS));
+    }
+
+    pub fn record(&self, record: UsageRecord) {
+        self.aggregate(&record);
+        if let Some(sink) = &self.sink {
+            let _ = sink.send(record);
+        }
+    }
+
+    /// Totals of every client in buckets starting at or after `since`, a unix timestamp.
+    pub fn totals(&self, since: u64) -> BTreeMap<String, UsageTotals> {
+        let since = since / BUCKET_SECS * BUCKET_SECS;
+        let buckets = self.buckets.lock().unwrap();
+        buckets
+            .iter()
+            .map(|(client, buckets)| {
+                let mut totals = UsageTotals::default();
+                buckets
+                    .range(since..)
+                    .for_each(|(_, bucket)| totals.add(bucket));
+                (client.clone(), totals)
+            })
+            .filter(|(_, totals)| totals.requests > 0)
+            .collect()
+    }
+
+    /// The hook recording the usage of a generation once it stops.
+    pub fn hook(&self) -> StopHook {
+        let ledger = self.clone();
+        Arc::new(move |request: &GenerateRequest, counter: &TokenCounter| {
+            ledger.record(UsageRecord {
+                client: request.client.clone().unwrap_or_else(|| ANONYMOUS.into()),
+                model: request.model.clone(),
+                timestamp: now(),
+                prompt_tokens: counter.prompt,
+                completion_tokens: counter.completion,
+            })
+        })
+    }
+}
+
+/// Hooks every generation calls once it stops, shared by all requests.
+#[derive(Clone, Default)]
+pub struct StopHooks(Vec<StopHook>);
+
+impl StopHooks {
+    pub fn with(mut self, hook: StopHook) -> Self {
+        self.0.push(hook);
+        self
+    }
+}
+
+/// What the generations of a request are attributed to: its client, the decode rate cap of its
+/// API key and the hooks they call once they stop. Injected by [`account`].
+#[derive(Clone, Default)]
+pub struct Attribution {
+    pub client: Option<String>,
+    pub max_tokens_per_second: Option<f32>,
+    pub on_stop: Vec<StopHook>,
+}
+
+impl Attribution {
+    /// The attribution of the request of `depot`; none if it has not been accounted.
+    pub fn of(depot: &Depot) -> Self {
+        depot.obtain::<Self>().cloned().unwrap_or_default()
+    }
+
+    /// Attribute `request` to the client, holding it to the decode rate cap.
+    pub fn apply(&self, request: &mut GenerateRequest) {
+        if let Some(client) = &self.client {
+            request.client = Some(client.clone());
+        }
+        cap_rate(request, self.max_tokens_per_second);
+        request.on_stop.extend(self.on_stop.iter().cloned());
+    }
+
+    /// Add `hook` to the attribution of the request of `depot`.
+    pub fn add_hook(depot: &mut Depot, hook: StopHook) {
+        let mut attribution = Self::of(depot);
+        attribution.on_stop.push(hook);
+        depot.inject(attribution);
+    }
+}
+
+/// The decode rate cap of `request` under the cap of its client, if either is set.
+fn cap_rate(request: &mut GenerateRequest, cap: Option<f32>) {
+    request.max_tokens_per_second = match (request.max_tokens_per_second, cap) {
+        (Some(rate), Some(cap)) => Some(rate.min(cap)),
+        (rate, cap) => rate.or(cap),
+    };
+}
+
+/// The client a request is attributed to, identified by its `x-api-key` or bearer token.
+///
+/// Configured API keys map to their `name` and secrets of app keys to their `app_id`. Other keys are reported by a fingerprint,
+/// so that the keys themselves never show up in usage reports.
+pub fn client_id(req: &Request, config: &Config) -> String {
+    let Some(key) = presented_key(req) else {
+        return ANONYMOUS.into();
+    };
+    if let Some(api_key) = find_key(&config.api_keys, key) {
+        return api_key.name.clone();
+    }
+    match config
+        .listen
+        .app_keys
+        .iter()
+        .find(|app| constant_time_eq(app.secret_key.as_bytes(), key.as_bytes()))
+    {
+        Some(app) => app.app_id.clone(),
+        None => {
+            let digest = format!("{:x}", Sha256::digest(key.as_bytes()));
+            format!("key_{}", &digest[..12])
+        }
+    }
+}
+
+/// Attribute the generations of the request to its client, and cap their decode rate at that
+/// of its API key.
+#[handler]
+pub async fn account(req: &mut Request, depot: &mut Depot) {
+    let Ok(config) = depot.obtain::<Arc<Config>>() else {
+        return;
+    };
+    let attribution = Attribution {
+        client: Some(client_id(req, config)),
+        max_tokens_per_second: presented_key(req)
+            .and_then(|key| find_key(&config.api_keys, key))
+            .and_then(|api_key| api_key.max_tokens_per_second),
+        on_stop: depot
+            .obtain::<StopHooks>()
+            .map(|hooks| hooks.0.clone())
+            .unwrap_or_default(),
+    };
+    depot.inject(attribution);
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClientUsage {
+    pub client: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UsageResponse {
+    /// Start of the reported period, a unix timestamp rounded down to the minute.
+    pub since: u64,
+    pub data: Vec<ClientUsage>,
+}
+
+/// Token usage per client.
+///
+/// The `since` query parameter is a unix timestamp in seconds. Usage is kept at minute
+/// resolution for `usage.retention_days`, which is also the default period.
+///
+/// `/api/usage`.
+#[endpoint(responses((status_code = 200, body = UsageResponse)))]
+pub async fn usage(
+    depot: &mut Depot,
+    req: &mut Request,
+) -> Result<Json<UsageResponse>, ApiErrorResponse> {
+    let ledger = depot
+        .obtain::<UsageLedger>()
+        .map_err(|_| ApiErrorResponse::api_error("usage accounting is not available"))?;
+    let since = match req.query::<String>("since") {
+        Some(since) => since.parse::<u64>().map_err(|_| {
+            ApiErrorResponse::invalid_request("`since` must be a unix timestamp in seconds")
+                .with_param("since")
+        })?,
+        None => now().saturating_sub(ledger.retention.as_secs()),
+    };
+
+    let data = ledger
+        .totals(since)
+        .into_iter()
+        .map(|(client, totals)| ClientUsage {
+            client,
+            requests: totals.requests,
+            prompt_tokens: totals.prompt_tokens,
+            completion_tokens: totals.completion_tokens,
+            total_tokens: totals.prompt_tokens + totals.completion_tokens,
+        })
+        .collect();
+    let since = since / BUCKET_SECS * BUCKET_SECS;
+    Ok(Json(UsageResponse { since, data }))
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::path::Path;
+
+    use flume::Sender;
+    use rusqlite::{params, Connection};
+
+    use super::UsageRecord;
+
+    pub fn open(path: &Path) -> rusqlite::Result<Connection> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage (
+                client TEXT NOT NULL,
+                model TEXT,
+                timestamp INTEGER NOT NULL,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS usage_timestamp ON usage (timestamp);",
+        )?;
+        Ok(connection)
+    }
+
+    pub fn load(connection: &Connection, since: u64) -> rusqlite::Result<Vec<UsageRecord>> {
+        let mut statement = connection.prepare(
+            "SELECT client, model, timestamp, prompt_tokens, completion_tokens
+            FROM usage WHERE timestamp >= ?1",
+        )?;
+        let records = statement.query_map(params![since as i64], |row| {
+            Ok(UsageRecord {
+                client: row.get(0)?,
+                model: row.get(1)?,
+                timestamp: row.get::<_, i64>(2)? as u64,
+                prompt_tokens: row.get::<_, i64>(3)? as usize,
+                completion_tokens: row.get::<_, i64>(4)? as usize,
+            })
+        })?;
+        records.collect()
+    }
+
+    /// Append records sent to the returned channel to the database, on a dedicated thread.
+    pub fn spawn(connection: Connection) -> Sender<UsageRecord> {
+        let (sender, receiver) = flume::unbounded::<UsageRecord>();
+        std::thread::spawn(move || {
+            while let Ok(record) = receiver.recv() {
+                let result = connection.execute(
+                    "INSERT INTO usage (client, model, timestamp, prompt_tokens, completion_tokens)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        record.client,
+                        record.model,
+                        record.timestamp as i64,
+                        record.prompt_tokens as i64,
+                        record.completion_tokens as i64,
+                    ],
+                );
+                if let Err(err) = result {
+                    tracing::warn!(event = "usage_sqlite_failed", error = %err);
+                }
+            }
+        });
+        sender
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(client: &str, timestamp: u64, prompt_tokens: usize) -> UsageRecord {
+        UsageRecord {
+            client: client.into(),
+            model: None,
+            timestamp,
+            prompt_tokens,
+            completion_tokens: 2,
+        }
+    }
+
+    #[test]
+    fn test_ledger_totals_per_client_since() {
+        let ledger = UsageLedger::new(&UsageOption::default()).unwrap();
+        let now = now();
+        ledger.record(record("a", now - 600, 10));
+        ledger.record(record("a", now, 20));
+        ledger.record(record("b", now, 5));
+
+        let totals = ledger.totals(now - 3600);
+        assert_eq!(totals["a"].requests, 2);
+        assert_eq!(totals["a"].prompt_tokens, 30);
+        assert_eq!(totals["a"].completion_tokens, 4);
+        assert_eq!(totals["b"].prompt_tokens, 5);
+
+        let totals = ledger.totals(now);
+        assert_eq!(totals["a"].prompt_tokens, 20);
+    }
+
+    #[test]
+    fn test_ledger_drops_records_past_retention() {
+        let option = UsageOption {
+            retention_days: 1,
+            ..Default::default()
+        };
+        let ledger = UsageLedger::new(&option).unwrap();
+        ledger.record(record("a", now() - 2 * 24 * 60 * 60, 10));
+        assert!(ledger.totals(0).is_empty());
+    }
+
+    #[test]
+    fn test_attribution() {
+        let ledger = UsageLedger::new(&UsageOption::default()).unwrap();
+        let attribution = Attribution {
+            client: Some("a".into()),
+            max_tokens_per_second: Some(20.0),
+            on_stop: StopHooks::default().with(ledger.hook()).0,
+        };
+        let mut request = GenerateRequest {
+            model: Some("rwkv".into()),
+            ..Default::default()
+        };
+        attribution.apply(&mut request);
+        assert_eq!(request.client.as_deref(), Some("a"));
+        assert_eq!(request.max_tokens_per_second, Some(20.0));
+
+        let counter = TokenCounter {
+            prompt: 10,
+            completion: 2,
+            ..Default::default()
+        };
+        request.stopped(&counter);
+        let totals = ledger.totals(0);
+        assert_eq!(totals["a"].requests, 1);
+        assert_eq!(totals["a"].prompt_tokens, 10);
+    }
+
+    #[test]
+    fn test_cap_rate() {
+        let mut request = GenerateRequest::default();
+        cap_rate(&mut request, None);
+        assert_eq!(request.max_tokens_per_second, None);
+        cap_rate(&mut request, Some(20.0));
+        assert_eq!(request.max_tokens_per_second, Some(20.0));
+
+        // requests may go slower than their key, not faster
+        request.max_tokens_per_second = Some(5.0);
+        cap_rate(&mut request, Some(20.0));
+        assert_eq!(request.max_tokens_per_second, Some(5.0));
+        request.max_tokens_per_second = Some(50.0);
+        cap_rate(&mut request, Some(20.0));
+        assert_eq!(request.max_tokens_per_second, Some(20.0));
+    }
+}
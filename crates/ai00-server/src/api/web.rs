@@ -0,0 +1,94 @@
+//! Serving a web UI from a directory, configured with `web.web_path`.
+//!
+//! Paths are resolved with [`build_path`], so that requests cannot reach files outside of the
+//! directory. A path naming a directory serves its `index.html`. With `web.spa_fallback`, paths
+//! without a file and without an extension serve the `index.html` of the directory, so that a
+//! single-page app can handle its own routes; missing assets stay 404.
+
+use std::path::{Path, PathBuf};
+
+use salvo::{fs::NamedFile, prelude::*};
+
+use crate::build_path;
+
+/// Handler serving the files of a web UI directory.
+#[derive(Debug, Clone)]
+pub struct WebDir {
+    root: PathBuf,
+    spa_fallback: bool,
+}
+
+impl WebDir {
+    pub fn new(root: impl Into<PathBuf>, spa_fallback: bool) -> Self {
+        Self {
+            root: root.into(),
+            spa_fallback,
+        }
+    }
+
+    /// The file to serve for the request path `path`, if any.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let path = path.trim_start_matches('/');
+        let file = build_path(&self.root, path).ok()?;
+        if file.is_file() {
+            return Some(file);
+        }
+        let index = file.join("index.html");
+        if index.is_file() {
+            return Some(index);
+        }
+        let index = self.root.join("index.html");
+        let route = Path::new(path).extension().is_none();
+        (self.spa_fallback && route && index.is_file()).then_some(index)
+    }
+}
+
+#[async_trait]
+impl Handler for WebDir {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        _depot: &mut Depot,
+        res: &mut Response,
+        _ctrl: &mut FlowCtrl,
+    ) {
+        let path = req.param::<String>("path").unwrap_or_default();
+        match self.resolve(&path) {
+            Some(file) => NamedFile::builder(file).send(req.headers(), res).await,
+            None => {
+                res.status_code(StatusCode::NOT_FOUND);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("index.html"), "<html></html>").unwrap();
+        std::fs::create_dir(root.join("assets")).unwrap();
+        std::fs::write(root.join("assets/app.js"), "").unwrap();
+
+        let web = WebDir::new(root, true);
+        assert_eq!(web.resolve(""), Some(root.join("index.html")));
+        assert_eq!(
+            web.resolve("assets/app.js"),
+            Some(root.join("assets/app.js"))
+        );
+        // routes of the app fall back to its index, missing assets do not
+        assert_eq!(web.resolve("models/list"), Some(root.join("index.html")));
+        assert_eq!(web.resolve("assets/missing.js"), None);
+        // nothing outside of the directory
+        assert_eq!(web.resolve("../secret"), None);
+        assert_eq!(web.resolve("/etc/passwd"), Some(root.join("index.html")));
+        assert_eq!(web.resolve("assets/../../secret"), None);
+
+        let web = WebDir::new(root, false);
+        assert_eq!(web.resolve("models/list"), None);
+    }
+}
@@ -1,33 +1,73 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, Ipv4Addr},
     path::PathBuf,
 };
 
 use ai00_core::{
-    reload::{AdapterOption, BnfOption, Lora, Model, State, Tokenizer},
+    reload::{
+        AdapterOption, BnfOption, CacheSnapshotOption, FairnessOption, Lora, LoraAdapter, Model,
+        State, Tokenizer, Warmup,
+    },
     ReloadRequest,
 };
 use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 
-use crate::build_path;
+use crate::{api::messages::bnf_grammars::build_structural_grammar, build_path};
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Config {
     pub model: Model,
     pub lora: Vec<Lora>,
+    /// LoRA adapters of the model that requests select by name.
+    pub lora_adapters: Vec<LoraAdapter>,
     pub state: Vec<State>,
     pub tokenizer: Tokenizer,
     pub bnf: BnfOption,
     pub adapter: AdapterOption,
+    pub fairness: FairnessOption,
+    pub warmup: Option<Warmup>,
+    pub cache_snapshot: Option<CacheSnapshotOption>,
     pub listen: ListenerOption,
+    pub api_keys: Vec<ApiKey>,
     pub web: Option<WebOption>,
-    pub prompts: PromptsConfig,
+    pub prompts: PromptProfiles,
+    pub stream: StreamOption,
+    pub generation: GenerationOption,
+    pub http: HttpOption,
+    pub state_store: StateStoreOption,
+    pub conversations: ConversationOption,
+    pub usage: UsageOption,
+    pub audit: AuditOption,
+    pub otel: OtelOption,
+    pub rate_limit: RateLimitOption,
+    pub admission: AdmissionOption,
+    pub batch: BatchOption,
+    pub hub: HubOption,
+    pub tools: ToolsOption,
+    pub vision: VisionOption,
+    pub rag: RagOption,
+    pub moderation: ModerationOption,
     #[cfg(feature = "embed")]
     pub embed: Option<EmbedOption>,
 }
 
+impl Config {
+    /// The LoRA adapters, with their paths within the model folder.
+    pub fn lora_adapters(&self) -> anyhow::Result<Vec<LoraAdapter>> {
+        let mut adapters = self.lora_adapters.clone();
+        for lora in adapters
+            .iter_mut()
+            .flat_map(|adapter| adapter.lora.iter_mut())
+        {
+            lora.path = build_path(&self.model.path, &lora.path)?;
+        }
+        Ok(adapters)
+    }
+}
+
 impl TryFrom<Config> for ReloadRequest {
     type Error = anyhow::Error;
 
@@ -41,7 +81,12 @@ impl TryFrom<Config> for ReloadRequest {
                     quant_type,
                     precision,
                     token_chunk_size,
+                    tune_token_chunk_size,
+                    max_prefill_chunk_tokens,
+                    vram_budget,
+                    pad_vocab,
                     max_batch,
+                    stream_load,
                     backend,
                 },
             mut lora,
@@ -49,8 +94,12 @@ impl TryFrom<Config> for ReloadRequest {
             tokenizer: Tokenizer {
                 path: tokenizer_path,
             },
-            bnf,
+            mut bnf,
             adapter,
+            fairness,
+            warmup,
+            cache_snapshot,
+            prompts,
             ..
         } = value;
 
@@ -62,7 +111,16 @@ impl TryFrom<Config> for ReloadRequest {
             state.path = build_path(&path, &state.path)?;
         }
 
+        // the structural grammar of tool and thinking requests, for the default stop sequences
+        for profile in prompts.iter() {
+            let grammar = build_structural_grammar(false, false, &profile.default_stop_sequences);
+            if !bnf.precompile.contains(&grammar) {
+                bnf.precompile.push(grammar);
+            }
+        }
+
         Ok(Self {
+            name: None,
             model_path,
             lora,
             state,
@@ -70,10 +128,19 @@ impl TryFrom<Config> for ReloadRequest {
             quant_type,
             precision,
             token_chunk_size,
+            tune_token_chunk_size,
+            max_prefill_chunk_tokens,
+            vram_budget,
+            pad_vocab,
             max_batch,
+            stream_load,
             tokenizer_path,
             bnf,
             adapter,
+            fairness,
+            warmup,
+            cache_snapshot,
+            adapter_of: None,
             backend,
         })
     }
@@ -145,6 +212,18 @@ pub enum EmbeddingModel {
     ModernBertEmbedLarge,
 }
 
+/// Device of the embed model, named like the devices of the embed tensor of RWKV models.
+#[cfg(feature = "embed")]
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, salvo::oapi::ToSchema,
+)]
+pub enum EmbedDevice {
+    #[default]
+    Cpu,
+    /// CUDA, which needs ONNX Runtime built with it.
+    Gpu,
+}
+
 #[cfg(feature = "embed")]
 #[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
 #[derivative(Default)]
@@ -153,6 +232,8 @@ pub struct EmbedOption {
     #[serde(with = "EmbeddingModel")]
     #[derivative(Default(value = "fastembed::EmbeddingModel::MultilingualE5Small"))]
     pub model: fastembed::EmbeddingModel,
+    /// Device the embed model runs on.
+    pub device: EmbedDevice,
     #[derivative(Default(value = "\"https://huggingface.co\".into()"))]
     pub endpoint: String,
     #[derivative(Default(value = "\"assets/models/hf\".into()"))]
@@ -168,6 +249,38 @@ pub struct AppKey {
     pub secret_key: String,
 }
 
+/// What an API key may access.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyScope {
+    /// Generation and other non-admin `/api` routes.
+    #[default]
+    Inference,
+    /// Everything, including `/admin` routes.
+    Admin,
+}
+
+impl KeyScope {
+    pub fn allows(self, scope: KeyScope) -> bool {
+        self == KeyScope::Admin || scope == KeyScope::Inference
+    }
+}
+
+/// A key accepted in the `x-api-key` or `Authorization: Bearer` header.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiKey {
+    pub key: String,
+    /// Name of the key in logs and usage reports.
+    pub name: String,
+    pub scope: KeyScope,
+    /// Requests allowed per minute. Unlimited if not set.
+    pub requests_per_minute: Option<u32>,
+    /// Most output tokens per second of each generation of the key, e.g. for a slower tier of
+    /// free users. Requests may ask for less with `max_tokens_per_second`. Unlimited if not set.
+    pub max_tokens_per_second: Option<f32>,
+}
+
 #[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
 #[derivative(Default)]
 #[serde(default)]
@@ -200,12 +313,461 @@ pub struct ListenerOption {
     pub app_keys: Vec<AppKey>,
 }
 
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct StreamOption {
+    /// Largest serialized SSE event in bytes. Bigger text, thinking and tool input deltas are
+    /// split into several events. Unlimited if not set.
+    pub max_event_size: Option<usize>,
+    /// Seconds between `ping` events of SSE streams, which keep proxies from closing idle
+    /// connections. Disabled at 0.
+    #[derivative(Default(value = "15"))]
+    pub heartbeat_interval: u64,
+    /// Seconds the events of an SSE stream are kept for clients reconnecting with
+    /// `Last-Event-ID`, after the generation ends or the client goes away.
+    #[derivative(Default(value = "30"))]
+    pub resume_window: u64,
+}
+
+/// Transport tuning of the HTTP server. Unset values keep hyper's defaults.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct HttpOption {
+    /// Maximum number of concurrent HTTP/2 streams (e.g. SSE responses) per connection.
+    pub max_concurrent_streams: Option<u32>,
+    /// Interval in seconds between HTTP/2 keep-alive pings. Pings are disabled if not set.
+    pub keep_alive_interval: Option<u64>,
+    /// Seconds to wait for a keep-alive ping to be acknowledged before closing the connection.
+    #[derivative(Default(value = "20"))]
+    pub keep_alive_timeout: u64,
+    /// HTTP/2 initial window size of each stream in bytes.
+    pub initial_stream_window_size: Option<u32>,
+    /// HTTP/2 initial window size of each connection in bytes.
+    pub initial_connection_window_size: Option<u32>,
+    /// Let HTTP/2 adjust window sizes by the measured bandwidth-delay product.
+    /// Overrides the initial window sizes.
+    #[derivative(Default(value = "false"))]
+    pub adaptive_window: bool,
+    /// Largest HTTP/2 frame the server accepts in bytes.
+    pub max_frame_size: Option<u32>,
+    /// Keep HTTP/1.1 connections alive between requests.
+    #[derivative(Default(value = "true"))]
+    pub http1_keep_alive: bool,
+}
+
+/// Limits of the states stored through `/api/states`.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct StateStoreOption {
+    /// Seconds a stored state is kept after it was last used.
+    #[derivative(Default(value = "3600"))]
+    pub ttl: u64,
+    /// Maximum number of stored states; the least recently used are dropped first.
+    #[derivative(Default(value = "64"))]
+    pub max_states: usize,
+}
+
+/// States of conversations that `/v1/messages` requests continue by `metadata.conversation_id`.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ConversationOption {
+    /// Seconds a conversation is kept after its last turn.
+    #[derivative(Default(value = "1800"))]
+    pub ttl: u64,
+    /// Maximum number of kept conversations; the least recently used are dropped first.
+    /// Set to 0 to disable reusing conversation states.
+    #[derivative(Default(value = "64"))]
+    pub max_conversations: usize,
+}
+
+/// Accounting of token usage per API key.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct UsageOption {
+    /// Days of usage kept for `/api/usage`.
+    #[derivative(Default(value = "30"))]
+    pub retention_days: u64,
+    /// SQLite database that usage records are appended to and reloaded from on startup.
+    /// Requires the `sqlite` feature.
+    pub sqlite: Option<PathBuf>,
+}
+
+/// Export of request traces to an OpenTelemetry collector over OTLP/HTTP.
+/// Requires the `otel` feature.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct OtelOption {
+    /// Traces endpoint of the collector, e.g. `http://localhost:4318/v1/traces`.
+    /// Traces are not exported if not set.
+    pub endpoint: Option<String>,
+    /// Name of the service the spans are reported under.
+    #[derivative(Default(value = "\"ai00_server\".into()"))]
+    pub service_name: String,
+    /// Fraction of the traces started by this server that are exported. Traces continued from
+    /// a `traceparent` header follow the sampling decision of the caller.
+    #[derivative(Default(value = "1.0"))]
+    pub sample_ratio: f64,
+    /// Seconds the collector may take for a batch of spans.
+    #[derivative(Default(value = "10"))]
+    pub timeout: u64,
+}
+
+/// Audit log of Messages requests: every finished request is appended as one JSON line, in the
+/// shape of a `/v1/messages` request whose last message is the answer, so that `make-binidx`
+/// can turn the log into training data. The rest of the record is under `audit`.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct AuditOption {
+    /// JSONL file the records are appended to.
+    pub path: Option<PathBuf>,
+    /// Size in bytes past which the file is rotated to `<path>.1`, `<path>.2` and so on.
+    #[derivative(Default(value = "100 * 1024 * 1024"))]
+    pub max_bytes: u64,
+    /// Rotated files kept besides the current one.
+    #[derivative(Default(value = "5"))]
+    pub max_files: usize,
+    /// Endpoint every record is POSTed to, besides or instead of the file.
+    pub url: Option<String>,
+    /// Bearer token sent to `url`.
+    pub token: Option<String>,
+    /// Seconds `url` may take for a record.
+    #[derivative(Default(value = "10"))]
+    pub timeout: u64,
+    /// Fields to redact, as dotted paths into the record where `*` matches every item of an
+    /// array or object, e.g. `"messages.*.content" = "mask"` or `"audit.prompt" = "remove"`.
+    pub redact: HashMap<String, Redact>,
+}
+
+/// How a field of an audit record is redacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Redact {
+    /// Drop the field.
+    Remove,
+    /// Replace the value by `"[redacted]"`.
+    Mask,
+    /// Replace the value by the SHA-256 of its text, so that equal values can still be matched.
+    Hash,
+}
+
+/// Limits of Messages requests per client, i.e. per API key, or per IP for requests without one.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitOption {
+    /// Requests allowed per minute. Unlimited if not set.
+    pub requests_per_minute: Option<u32>,
+    /// Prompt and completion tokens allowed per minute. Unlimited if not set.
+    pub tokens_per_minute: Option<u32>,
+}
+
+/// Admission of generation requests, so that excess load is turned away instead of queued forever.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct AdmissionOption {
+    /// Generation requests handled at once. Unlimited if not set.
+    pub max_concurrent_requests: Option<usize>,
+    /// Milliseconds a request may wait for admission before it is rejected with `overloaded_error`.
+    #[derivative(Default(value = "10000"))]
+    pub max_queue_wait_ms: u64,
+    /// Seconds to wait for in-flight generations to finish when shutting down.
+    #[derivative(Default(value = "30"))]
+    pub drain_timeout: u64,
+}
+
+/// Limits of the generations of `/v1/messages`.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct GenerationOption {
+    /// Milliseconds a generation may take, for requests without `timeout_ms`. Unlimited if not
+    /// set.
+    pub timeout_ms: Option<u64>,
+    /// Most tokens a built prompt may have, e.g. the context length the model was trained on.
+    /// Unlimited if not set.
+    pub max_context_tokens: Option<usize>,
+    /// What to do with prompts longer than `max_context_tokens`.
+    pub truncation: Truncation,
+    /// Most tokens of the summary that replaces the dropped turns with `truncation = "summarize"`.
+    #[derivative(Default(value = "256"))]
+    pub summary_max_tokens: usize,
+    /// Most output tokens of requests with `allow_exceed_context`.
+    #[derivative(Default(value = "65536"))]
+    pub max_exceed_tokens: usize,
+    /// Output tokens between the state checkpoints of requests with `allow_exceed_context`.
+    #[derivative(Default(value = "1024"))]
+    pub checkpoint_interval: usize,
+    /// Root mean square of the state at a checkpoint above which the generation counts as
+    /// drifted and ends.
+    #[derivative(Default(value = "10000.0"))]
+    pub max_state_rms: f32,
+}
+
+/// How prompts longer than the context are shortened.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Truncation {
+    /// Drop the oldest turns of the conversation until the prompt fits.
+    #[default]
+    DropOldest,
+    /// Drop the oldest turns, and put a summary of them written by the model in their place.
+    Summarize,
+    /// Reject the request.
+    Error,
+}
+
+/// Batch inference through `/v1/batches`.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct BatchOption {
+    /// Largest batch body in bytes.
+    #[derivative(Default(value = "64 * 1024 * 1024"))]
+    pub max_bytes: usize,
+    /// Most requests in one batch.
+    #[derivative(Default(value = "10000"))]
+    pub max_requests: usize,
+    /// Requests of a batch generated at once. Defaults to `max_batch` of the model, which keeps
+    /// every slot busy.
+    pub concurrency: Option<usize>,
+}
+
+/// Downloads of `hf://<owner>/<repo>[@<revision>]/<file>` model paths from the Hugging Face Hub.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct HubOption {
+    /// Base URL of the Hub, or of a mirror.
+    #[derivative(Default(value = "\"https://huggingface.co\".into()"))]
+    pub endpoint: String,
+    /// Access token for gated or private repositories. Falls back to `HF_TOKEN`.
+    pub token: Option<String>,
+}
+
+/// Handling of image blocks in messages, which RWKV models cannot read.
+///
+/// Without a captioner, requests with images are rejected.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct VisionOption {
+    /// Endpoint that captions images. It is POSTed the `source` of an image block and answers
+    /// `{"caption": "..."}`.
+    pub captioner: Option<String>,
+    /// Bearer token sent to the captioner.
+    pub token: Option<String>,
+    /// Text that replaces an image, where `{caption}` is replaced by its caption.
+    #[derivative(Default(value = "\"[Image: {caption}]\".into()"))]
+    pub template: String,
+    /// Seconds the captioner may take for an image.
+    #[derivative(Default(value = "30"))]
+    pub timeout: u64,
+}
+
+/// Retrieval of context from a vector store for requests with `metadata.rag`.
+///
+/// The last user message is embedded with the embed model, and the closest chunks of the
+/// collection are added to the system prompt. Only Qdrant is supported.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct RagOption {
+    /// Base URL of the Qdrant REST API, e.g. `http://localhost:6333`.
+    pub url: Option<String>,
+    /// Collection searched, unless a request names another.
+    pub collection: String,
+    /// Other collections requests may name.
+    pub collections: Vec<String>,
+    /// API key sent to Qdrant.
+    pub api_key: Option<String>,
+    /// Chunks retrieved, unless a request asks for another number.
+    #[derivative(Default(value = "4"))]
+    pub top_k: usize,
+    /// Most chunks retrieved, whatever number a request asks for.
+    #[derivative(Default(value = "16"))]
+    pub max_top_k: usize,
+    /// Lowest similarity score of a retrieved chunk.
+    pub min_score: Option<f32>,
+    /// Payload field holding the text of a chunk.
+    #[derivative(Default(value = "\"text\".into()"))]
+    pub text_field: String,
+    /// Payload field holding the source of a chunk, cited along with it.
+    #[derivative(Default(value = "\"source\".into()"))]
+    pub source_field: String,
+    /// Prefix of the query when embedded, as the embed model expects.
+    #[derivative(Default(value = "\"query: \".into()"))]
+    pub query_prefix: String,
+    /// Text added to the system prompt, where `{context}` is replaced by the numbered chunks.
+    #[derivative(Default(
+        value = "\"Answer using the context below where it is relevant, citing chunks as [n].\\n\\n{context}\".into()"
+    ))]
+    pub template: String,
+    /// Seconds the vector store may take for a search.
+    #[derivative(Default(value = "10"))]
+    pub timeout: u64,
+}
+
+/// Moderation of `/v1/messages` prompts and output.
+///
+/// Flagged prompts are rejected, and flagged output ends the generation with the `refusal`
+/// stop reason. Output is scanned over a sliding window, so that a phrase split across tokens
+/// is caught once it completes; the part of it already streamed is not taken back.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ModerationOption {
+    /// Regular expressions that flag text, e.g. `(?i)\bpassword\b`.
+    pub patterns: Vec<String>,
+    /// Words or phrases that flag text, matched case-insensitively.
+    pub keywords: Vec<String>,
+    /// Endpoint that checks text. It is POSTed `{"input": "..."}` and answers
+    /// `{"flagged": true, "reason": "..."}`.
+    pub endpoint: Option<String>,
+    /// Bearer token sent to the endpoint.
+    pub token: Option<String>,
+    /// Seconds the endpoint may take for a check.
+    #[derivative(Default(value = "10"))]
+    pub timeout: u64,
+    /// Check the system prompt and messages before generating.
+    #[derivative(Default(value = "true"))]
+    pub prompts: bool,
+    /// Scan the output while generating.
+    #[derivative(Default(value = "true"))]
+    pub output: bool,
+    /// Bytes of the latest output that are scanned.
+    #[derivative(Default(value = "1024"))]
+    pub window: usize,
+    /// Bytes of new output after which the endpoint checks the window again.
+    #[derivative(Default(value = "256"))]
+    pub endpoint_interval: usize,
+}
+
+/// Tools the server runs itself for requests in agentic mode.
+#[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ToolsOption {
+    /// Built-in tools offered to the model.
+    pub builtin: Vec<BuiltinTool>,
+    /// MCP servers whose tools are offered to the model.
+    pub mcp: Vec<McpServer>,
+    /// Most rounds of tool calls answered by the server in one request.
+    #[derivative(Default(value = "8"))]
+    pub max_rounds: usize,
+    /// Seconds a tool call may take before it fails.
+    #[derivative(Default(value = "30"))]
+    pub timeout: u64,
+    /// Hosts `http_fetch` may reach, with their subdomains. Empty allows any host; private,
+    /// loopback and link-local addresses are refused either way.
+    pub fetch_allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuiltinTool {
+    /// Evaluates arithmetic expressions.
+    Calculator,
+    /// Fetches a URL with GET from a public host, limited to `fetch_allow` if it is set.
+    HttpFetch,
+}
+
+/// An MCP server reached over the streamable HTTP transport.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct McpServer {
+    /// Prefix of the names of its tools, as in `<name>__<tool>`.
+    pub name: String,
+    pub url: String,
+    /// Headers sent with every message, e.g. `Authorization`.
+    pub headers: HashMap<String, String>,
+}
+
 #[derive(Debug, Derivative, Clone, Serialize, Deserialize)]
 #[derivative(Default)]
 #[serde(default)]
 pub struct WebOption {
     #[derivative(Default(value = "\"assets/www/index.zip\".into()"))]
     pub path: PathBuf,
+    /// Directory of a web UI to serve as it is, in place of the zip at `path`.
+    pub web_path: Option<PathBuf>,
+    /// Serve the `index.html` of `web_path` for paths without a file, so that the client-side
+    /// routes of a single-page app load.
+    #[derivative(Default(value = "true"))]
+    pub spa_fallback: bool,
+}
+
+/// Prompt profiles, given as one `[prompts]` table or several `[[prompts]]` tables.
+///
+/// Requests choose a profile by name with `metadata.prompt_profile` or the
+/// `x-prompt-profile` header; the first profile is the default.
+#[derive(Debug, Clone)]
+pub struct PromptProfiles(Vec<PromptsConfig>);
+
+impl Default for PromptProfiles {
+    fn default() -> Self {
+        Self(vec![PromptsConfig::default()])
+    }
+}
+
+impl PromptProfiles {
+    /// The profile requests use unless they name one.
+    pub fn default_profile(&self) -> &PromptsConfig {
+        &self.0[0]
+    }
+
+    /// The profile named `name`, or the default one if `name` is `None`.
+    pub fn get(&self, name: Option<&str>) -> Option<&PromptsConfig> {
+        match name {
+            Some(name) => self.0.iter().find(|profile| profile.name == name),
+            None => Some(self.default_profile()),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PromptsConfig> {
+        self.0.iter()
+    }
+}
+
+impl From<Vec<PromptsConfig>> for PromptProfiles {
+    fn from(profiles: Vec<PromptsConfig>) -> Self {
+        match profiles.is_empty() {
+            true => Self::default(),
+            false => Self(profiles),
+        }
+    }
+}
+
+impl Serialize for PromptProfiles {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0[..] {
+            [profile] => profile.serialize(serializer),
+            profiles => profiles.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PromptProfiles {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Profiles {
+            One(PromptsConfig),
+            Many(Vec<PromptsConfig>),
+        }
+
+        let profiles = match Profiles::deserialize(deserializer)? {
+            Profiles::One(profile) => vec![profile],
+            Profiles::Many(profiles) => profiles,
+        };
+        Ok(profiles.into())
+    }
 }
 
 /// Prompts configuration for customizing model behavior.
@@ -216,6 +778,10 @@ pub struct WebOption {
 #[derivative(Default)]
 #[serde(default)]
 pub struct PromptsConfig {
+    /// Name that requests use to select this profile.
+    #[derivative(Default(value = "String::from(\"default\")"))]
+    pub name: String,
+
     /// Header for tool system prompt (before tool definitions).
     /// Use {tools_json} as placeholder for tool definitions.
     #[derivative(Default(
@@ -268,4 +834,49 @@ pub struct PromptsConfig {
     /// With ai00 XML format, assistant turn ends with closing tag.
     #[derivative(Default(value = "vec![String::from(\"</ai00:assistant>\")]"))]
     pub default_stop_sequences: Vec<String>,
+
+    /// How thinking blocks of earlier turns are put in the prompt. Thinking of the current turn,
+    /// i.e. of assistant messages after the last user message, is always kept.
+    pub prior_thinking: PriorThinking,
+
+    /// Template of earlier thinking with `prior_thinking = "render"`.
+    /// `{thinking}` is replaced by the text of the block.
+    #[derivative(Default(value = "String::from(\"<think>\\n{thinking}\\n</think>\")"))]
+    pub prior_thinking_template: String,
+
+    /// Jinja chat template that replaces the ai00 format, e.g. for models finetuned on ChatML.
+    /// See `api::messages::template` for the variables it sees.
+    pub template: Option<String>,
+}
+
+/// How thinking blocks of earlier assistant turns are put in the prompt.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriorThinking {
+    /// Keep them, wrapped in `<think>` tags.
+    #[default]
+    Keep,
+    /// Drop them, as in training data where only the final turn shows its thinking.
+    Strip,
+    /// Render them through `prior_thinking_template`.
+    Render,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_profiles() {
+        let config: Config = toml::from_str("[prompts]\nrole_user = \"human\"\n").unwrap();
+        assert_eq!(config.prompts.iter().count(), 1);
+        assert_eq!(config.prompts.default_profile().role_user, "human");
+        assert_eq!(config.prompts.get(None).unwrap().name, "default");
+
+        let source = "[[prompts]]\nname = \"ai00\"\n\n[[prompts]]\nname = \"chatml\"\n";
+        let config: Config = toml::from_str(source).unwrap();
+        assert_eq!(config.prompts.default_profile().name, "ai00");
+        assert_eq!(config.prompts.get(Some("chatml")).unwrap().name, "chatml");
+        assert!(config.prompts.get(Some("llama")).is_none());
+    }
 }
@@ -2,10 +2,11 @@
 //!
 //! This module exports the API types and handlers for testing.
 
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, BufReader},
@@ -57,7 +58,19 @@ pub async fn load_config(path: impl AsRef<Path>) -> Result<config::Config> {
     let mut reader = BufReader::new(file);
     let mut contents = String::new();
     reader.read_to_string(&mut contents).await?;
-    Ok(toml::from_str(&contents)?)
+    let config: config::Config = toml::from_str(&contents)?;
+    let mut names = HashSet::new();
+    for profile in config.prompts.iter() {
+        if !names.insert(&profile.name) {
+            bail!("prompt profile `{}` is defined twice", profile.name);
+        }
+        if let Some(template) = &profile.template {
+            api::messages::template::check_template(template).with_context(|| {
+                format!("invalid template in prompt profile `{}`", profile.name)
+            })?;
+        }
+    }
+    Ok(config)
 }
 
 /// Text embedding model wrapper.
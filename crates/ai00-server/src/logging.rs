@@ -73,6 +73,23 @@ pub mod lifecycle {
             "Server shutting down"
         );
     }
+
+    /// Emitted when draining before shutdown ends.
+    pub fn server_drained(in_flight: usize) {
+        if in_flight == 0 {
+            tracing::info!(
+                event = "server_drained",
+                in_flight = in_flight,
+                "In-flight generations finished"
+            );
+        } else {
+            tracing::warn!(
+                event = "server_drained",
+                in_flight = in_flight,
+                "Drain timed out with generations in flight"
+            );
+        }
+    }
 }
 
 /// Model operation events
@@ -140,6 +157,28 @@ pub mod model {
         );
     }
 
+    /// Emitted when a download from the Hugging Face Hub begins or resumes.
+    pub fn model_download(source: &str, path: &str, resumed_from: u64) {
+        tracing::info!(
+            event = "model_download",
+            source = %source,
+            path = %path,
+            resumed_from = resumed_from,
+            "Downloading model"
+        );
+    }
+
+    /// Emitted when a download from the Hugging Face Hub is verified and in place.
+    pub fn model_downloaded(source: &str, path: &str, bytes: u64) {
+        tracing::info!(
+            event = "model_downloaded",
+            source = %source,
+            path = %path,
+            bytes = bytes,
+            "Model downloaded"
+        );
+    }
+
     /// Emitted when model is unloaded.
     pub fn model_unload() {
         tracing::info!(event = "model_unload", "Model unloaded");
@@ -382,6 +421,16 @@ pub mod errors {
         );
     }
 
+    /// Download from the Hugging Face Hub failed.
+    pub fn model_download_failed(source: &str, error: &str) {
+        tracing::error!(
+            event = "model_download_failed",
+            source = %source,
+            error = %error,
+            "Model download failed"
+        );
+    }
+
     /// State load failed.
     pub fn state_load_failed(path: &str, error: &str) {
         tracing::warn!(
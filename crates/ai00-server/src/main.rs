@@ -5,26 +5,29 @@ use std::{
     time::Duration,
 };
 
-use ai00_core::ThreadRequest;
-use anyhow::{anyhow, Result};
+use ai00_core::{ReloadRequest, ThreadRequest};
+use anyhow::Result;
 use clap::{CommandFactory, Parser};
 use memmap2::Mmap;
 use salvo::{
-    affix_state,
-    conn::rustls::{Keycert, RustlsConfig},
+    catcher::Catcher,
+    conn::{
+        rustls::{Keycert, RustlsConfig},
+        Acceptor,
+    },
     cors::{AllowHeaders, AllowOrigin, Cors},
     http::Method,
     jwt_auth::{ConstDecoder, HeaderFinder, QueryFinder},
     logging::Logger,
     prelude::*,
     serve_static::StaticDir,
-    Router,
+    Router, Server,
 };
 use tokio::{fs::File, signal};
 
-#[cfg(feature = "embed")]
-use ai00_server::TextEmbed;
 use ai00_server::{api, config, load_config, logging, types};
+use api::auth::ApiKeyAuth;
+use config::KeyScope;
 use types::JwtClaims;
 
 async fn load_web(path: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<()> {
@@ -55,33 +58,6 @@ async fn load_plugin(
     Ok(())
 }
 
-#[cfg(feature = "embed")]
-fn load_embed(embed: config::EmbedOption) -> Result<TextEmbed> {
-    use fastembed::{InitOptions, TextEmbedding};
-    use hf_hub::api::sync::Api;
-
-    std::env::set_var("HF_ENDPOINT", embed.endpoint);
-    std::env::set_var("HF_HOME", embed.home);
-    #[cfg(target_os = "windows")]
-    std::env::set_var("ORT_DYLIB_PATH", embed.lib);
-
-    let api = Api::new()?;
-    let info = TextEmbedding::get_model_info(&embed.model)?.clone();
-    tracing::info!("loading embed model: {}", embed.model);
-
-    let options = InitOptions::new(embed.model).with_show_download_progress(true);
-    let model = TextEmbedding::try_new(options)?;
-
-    let file = api.model(info.model_code.clone()).get("tokenizer.json")?;
-    let tokenizer = tokenizers::Tokenizer::from_file(file).map_err(|err| anyhow!("{err}"))?;
-
-    Ok(TextEmbed {
-        tokenizer,
-        model,
-        info,
-    })
-}
-
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
@@ -93,6 +69,23 @@ pub struct Args {
     port: Option<u16>,
 }
 
+/// Apply the `[http]` config section to the connection builders of the server.
+fn tune_http<A: Acceptor>(server: &mut Server<A>, option: &config::HttpOption) {
+    server.http1_mut().keep_alive(option.http1_keep_alive);
+
+    let http2 = server.http2_mut();
+    http2
+        .max_concurrent_streams(option.max_concurrent_streams)
+        .keep_alive_interval(option.keep_alive_interval.map(Duration::from_secs))
+        .keep_alive_timeout(Duration::from_secs(option.keep_alive_timeout))
+        .initial_stream_window_size(option.initial_stream_window_size)
+        .initial_connection_window_size(option.initial_connection_window_size)
+        .max_frame_size(option.max_frame_size);
+    if option.adaptive_window {
+        http2.adaptive_window(true);
+    }
+}
+
 async fn shutdown_signal() {
     let ctrl_c = signal::ctrl_c();
 
@@ -122,13 +115,22 @@ async fn main() {
 
     let pretty_mode = std::env::var("LOG_PRETTY").is_ok();
 
+    // the exporter is only known once the config is loaded
+    #[cfg(feature = "otel")]
+    let (otel_layer, otel_handle) =
+        tracing_subscriber::reload::Layer::new(None::<api::otel::OtelLayer>);
+    #[cfg(not(feature = "otel"))]
+    let otel_layer = tracing_subscriber::layer::Identity::new();
+
     if pretty_mode {
         tracing_subscriber::registry()
+            .with(otel_layer)
             .with(filter)
             .with(fmt::layer().pretty())
             .init();
     } else {
         tracing_subscriber::registry()
+            .with(otel_layer)
             .with(filter)
             .with(fmt::layer().json())
             .init();
@@ -142,31 +144,40 @@ async fn main() {
 
     logging::lifecycle::server_startup(bin_name, version);
 
+    let metrics = api::metrics::Metrics::install().expect("failed to install metrics recorder");
+
     let (sender, receiver) = flume::unbounded::<ThreadRequest>();
     tokio::spawn(ai00_core::serve(receiver));
 
+    #[cfg(feature = "chaos")]
+    let chaos = api::chaos::Chaos::default();
+    #[cfg(feature = "chaos")]
+    let sender = chaos.intercept(sender);
+
+    let config_path = args
+        .config
+        .clone()
+        .unwrap_or("assets/configs/Config.toml".into());
     let config = {
-        let path = args
-            .config
-            .clone()
-            .unwrap_or("assets/configs/Config.toml".into());
-        logging::lifecycle::config_loaded(&path.to_string_lossy());
-        load_config(path).await.expect("failed to startup")
+        logging::lifecycle::config_loaded(&config_path.to_string_lossy());
+        load_config(&config_path).await.expect("failed to startup")
     };
 
+    #[cfg(feature = "otel")]
+    let otel = api::otel::install(&config.otel, &otel_handle).unwrap_or_else(|err| {
+        tracing::error!(event = "otel_failed", error = %err, "Failed to set up trace export");
+        None
+    });
+    #[cfg(not(feature = "otel"))]
+    if config.otel.endpoint.is_some() {
+        tracing::warn!(
+            event = "otel_disabled",
+            "`otel.endpoint` is set but the server was built without the `otel` feature"
+        );
+    }
+
     #[cfg(feature = "embed")]
-    let embed = config
-        .embed
-        .clone()
-        .and_then(|embed| match load_embed(embed) {
-            Ok(embed) => Some(std::sync::Arc::new(embed)),
-            Err(err) => {
-                logging::errors::model_load_failed("embed", &err.to_string());
-                None
-            }
-        });
-    #[cfg(not(feature = "embed"))]
-    let embed: Option<()> = None;
+    let embed = api::embed::EmbedQueue::new(config.embed.clone());
 
     #[cfg(not(feature = "hip"))]
     if config.model.backend == ai00_core::reload::Backend::Hip {
@@ -176,19 +187,79 @@ async fn main() {
         );
     }
 
-    match config.clone().try_into() {
-        Ok(request) => {
-            let request = ThreadRequest::Reload {
-                request: Box::new(request),
-                sender: None,
+    let hub = api::hub::Hub::new(&config).expect("failed to create hub client");
+    let server_tools =
+        api::messages::ServerTools::new(&config.tools).expect("failed to create tool clients");
+    let captioner =
+        api::messages::Captioner::new(&config.vision).expect("failed to create captioner client");
+    let retriever =
+        api::messages::Retriever::new(&config.rag).expect("failed to create vector store client");
+    let moderator =
+        api::messages::Moderator::new(&config.moderation).expect("invalid moderation rules");
+    let artifacts = api::artifacts::ArtifactUsage::default();
+
+    // `hf://` paths are downloaded before the initial load, without holding up the server;
+    // LoRA adapters are registered once their base model is loaded
+    tokio::spawn({
+        let (hub, config, sender) = (hub.clone(), config.clone(), sender.clone());
+        let artifacts = artifacts.clone();
+        async move {
+            let config = match hub.resolve_config(config).await {
+                Ok(config) => config,
+                Err(err) => {
+                    logging::errors::model_load_failed("initial", &err.to_string());
+                    return;
+                }
+            };
+            let request = config
+                .lora_adapters()
+                .and_then(|adapters| Ok((adapters, ReloadRequest::try_from(config)?)));
+            let (adapters, request) = match request {
+                Ok(request) => request,
+                Err(err) => {
+                    logging::errors::model_load_failed("initial", &err.to_string());
+                    return;
+                }
             };
-            let _ = sender.send(request);
+            let files = api::artifacts::files_of(&request);
+            let (result_sender, result_receiver) = flume::unbounded();
+            let _ = sender.send(ThreadRequest::Reload {
+                request: Box::new(request),
+                sender: Some(result_sender),
+            });
+            if !result_receiver.recv_async().await.unwrap_or_default() {
+                return;
+            }
+            artifacts.touch(files);
+            for adapter in adapters {
+                let name = adapter.name.clone();
+                let files = api::artifacts::lora_files(&adapter.lora);
+                let (result_sender, result_receiver) = flume::unbounded();
+                let _ = sender.send(ThreadRequest::LoadLoraAdapter {
+                    model: None,
+                    adapter,
+                    sender: Some(result_sender),
+                });
+                match result_receiver.recv_async().await.unwrap_or_default() {
+                    true => artifacts.touch(files),
+                    false => {
+                        logging::errors::model_load_failed(&name, "failed to load LoRA adapter")
+                    }
+                }
+            }
         }
-        Err(err) => logging::errors::model_load_failed("initial", &err.to_string()),
-    }
+    });
 
+    let usage = api::usage::UsageLedger::new(&config.usage).expect("failed to open usage database");
+    let audit = api::messages::AuditLog::new(&config.audit).expect("failed to open audit log");
+
+    let web_dir = config.web.as_ref().and_then(|web| {
+        let path = web.web_path.clone()?;
+        tracing::info!(event = "web_dir", path = %path.display());
+        Some(api::web::WebDir::new(path, web.spa_fallback))
+    });
     let serve_path = match config.web.clone() {
-        Some(web) => {
+        Some(web) if web.web_path.is_none() => {
             if Path::new("assets/temp").exists() {
                 std::fs::remove_dir_all("assets/temp").expect("failed to delete temp dir");
             }
@@ -229,74 +300,261 @@ async fn main() {
 
             Some(path)
         }
-        None => None,
+        _ => None,
     };
 
     let cors = Cors::new()
         .allow_origin(AllowOrigin::any())
-        .allow_methods(vec![Method::GET, Method::POST, Method::DELETE])
+        .allow_methods(vec![
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::DELETE,
+        ])
         .allow_headers(AllowHeaders::any())
         .into_handler();
 
-    let admin_auth: JwtAuth<JwtClaims, _> =
+    let admin_auth = || -> JwtAuth<JwtClaims, _> {
         JwtAuth::new(ConstDecoder::from_secret(config.listen.slot.as_bytes()))
             .finders(vec![
                 Box::new(HeaderFinder::new()),
                 Box::new(QueryFinder::new("admin_token")),
                 // Box::new(CookieFinder::new("jwt_token")),
             ])
-            .force_passed(config.listen.force_pass.unwrap_or_default());
+            // `ApiKeyAuth` decides per request with the running config, accepting the token
+            .force_passed(true)
+    };
+
+    // clones share the request windows of per-key rate limits
+    let inference_auth = ApiKeyAuth::new(KeyScope::Inference);
+    let admin_key_auth = ApiKeyAuth::new(KeyScope::Admin);
 
-    let admin_router = Router::with_hoop(admin_auth)
+    let admin_router = Router::with_hoop(admin_auth())
+        .hoop(admin_key_auth.clone())
         .push(Router::with_path("/models/save").post(api::model::save))
         .push(Router::with_path("/models/load").post(api::model::load))
         .push(Router::with_path("/models/unload").get(api::model::unload))
+        .push(Router::with_path("/models/validate").post(api::model::validate))
+        .push(Router::with_path("/models/download").post(api::hub::download))
+        .push(Router::with_path("/models/adapters").post(api::model::load_lora_adapter))
+        .push(Router::with_path("/models/cache/restore").post(api::model::restore_cache))
+        .push(Router::with_path("/runtime").get(api::model::runtime))
+        .push(
+            Router::with_path("/files")
+                .get(api::artifacts::list)
+                .delete(api::artifacts::delete),
+        )
         .push(Router::with_path("/files/unzip").post(api::file::unzip))
         .push(Router::with_path("/files/dir").post(api::file::dir))
         .push(Router::with_path("/files/ls").post(api::file::dir))
+        .push(
+            Router::with_path("/files/upload")
+                .get(api::upload::status)
+                .post(api::upload::upload),
+        )
         .push(Router::with_path("/files/config/load").post(api::file::load_config))
-        .push(Router::with_path("/files/config/save").post(api::file::save_config));
-    let api_router = Router::new()
+        .push(Router::with_path("/files/config/save").post(api::file::save_config))
+        .push(Router::with_path("/config/reload").post(api::reload::reload))
+        .push(Router::with_path("/drain").post(api::admission::drain));
+    let api_usage = Router::with_path("/usage")
+        .hoop(admin_auth())
+        .hoop(admin_key_auth.clone())
+        .get(api::usage::usage);
+    let api_router = Router::with_hoop(inference_auth.clone())
         .push(Router::with_path("/adapters").get(api::adapter::adapters))
         .push(Router::with_path("/models/info").get(api::model::info))
         .push(Router::with_path("/models/list").get(api::file::models))
         .push(Router::with_path("/models/state").get(api::model::state))
+        .push(Router::with_path("/models/load/progress").get(api::model::load_progress))
+        .push(Router::with_path("/models/download/progress").get(api::hub::progress))
+        .push(Router::with_path("/requests/{id}/sampler").patch(api::sampler::adjust))
+        .push(Router::with_path("/tokenize").post(api::tokenize::tokenize_text))
+        .push(Router::with_path("/detokenize").post(api::tokenize::detokenize))
         // OpenAI-compatible endpoints
         .push(Router::with_path("/oai/models").get(api::oai::models))
         .push(Router::with_path("/oai/v1/models").get(api::oai::models))
-        .push(Router::with_path("/oai/completions").post(api::oai::completions))
-        .push(Router::with_path("/oai/v1/completions").post(api::oai::completions))
-        .push(Router::with_path("/oai/chat/completions").post(api::oai::chat_completions))
-        .push(Router::with_path("/oai/v1/chat/completions").post(api::oai::chat_completions))
+        .push(
+            Router::with_path("/oai/completions")
+                .hoop(api::admission::admit)
+                .post(api::oai::completions),
+        )
+        .push(
+            Router::with_path("/oai/v1/completions")
+                .hoop(api::admission::admit)
+                .post(api::oai::completions),
+        )
+        .push(
+            Router::with_path("/oai/chat/completions")
+                .hoop(api::admission::admit)
+                .post(api::oai::chat_completions),
+        )
+        .push(
+            Router::with_path("/oai/v1/chat/completions")
+                .hoop(api::admission::admit)
+                .post(api::oai::chat_completions),
+        )
         .push(Router::with_path("/oai/states").post(api::oai::states))
         .push(Router::with_path("/oai/v1/states").post(api::oai::states))
         .push(Router::with_path("/oai/chooses").post(api::oai::chooses))
         .push(Router::with_path("/oai/v1/chooses").post(api::oai::chooses))
         // Claude-compatible Messages API
-        .push(Router::with_path("/v1/messages").post(api::messages::messages_handler));
+        .push(
+            Router::with_path("/v1/messages")
+                .hoop(api::rate_limit::limit)
+                .hoop(api::admission::admit)
+                .post(api::messages::messages_handler),
+        )
+        .push(
+            Router::with_path("/v1/messages/ws")
+                // each request they carry is admitted on its own
+                .hoop(api::rate_limit::limit)
+                .goal(api::messages::messages_ws),
+        )
+        .push(
+            Router::with_path("/v1/batches")
+                .hoop(api::rate_limit::limit)
+                .post(api::messages::batches),
+        )
+        .push(
+            Router::with_path("/v1/embeddings")
+                .hoop(api::rate_limit::limit)
+                .hoop(api::admission::admit)
+                .post(api::embeddings::embeddings),
+        )
+        .push(
+            Router::with_path("/similarity")
+                .hoop(api::rate_limit::limit)
+                .hoop(api::admission::admit)
+                .post(api::similarity::similarity),
+        )
+        .push(
+            Router::with_path("/v1/rerank")
+                .hoop(api::rate_limit::limit)
+                .hoop(api::admission::admit)
+                .post(api::rerank::rerank),
+        )
+        .push(
+            Router::with_path("/v1/messages/count_tokens")
+                .hoop(api::rate_limit::limit)
+                .post(api::messages::count_tokens),
+        )
+        .push(
+            Router::with_path("/prompt/preview")
+                .hoop(api::rate_limit::limit)
+                .post(api::messages::preview_prompt),
+        )
+        .push(
+            Router::with_path("/sessions/{id}/continue")
+                .hoop(api::rate_limit::limit)
+                .hoop(api::admission::admit)
+                .post(api::messages::continue_session),
+        )
+        .push(
+            Router::with_path("/states")
+                .hoop(api::rate_limit::limit)
+                .hoop(api::admission::admit)
+                .post(api::messages::create_state),
+        )
+        .push(
+            Router::with_path("/states/import")
+                .hoop(api::rate_limit::limit)
+                .hoop(api::admission::admit)
+                .post(api::messages::import_state),
+        )
+        .push(
+            Router::with_path("/states/blend")
+                .hoop(api::rate_limit::limit)
+                .hoop(api::admission::admit)
+                .post(api::messages::blend_state),
+        )
+        .push(
+            Router::with_path("/states/{id}")
+                .get(api::messages::export_state)
+                .delete(api::messages::delete_state),
+        );
+    #[cfg(feature = "chaos")]
+    let admin_router = admin_router.push(
+        Router::with_path("/chaos")
+            .get(api::chaos::chaos)
+            .post(api::chaos::set_chaos),
+    );
     #[cfg(feature = "embed")]
     let api_embed = Router::new()
-        .push(Router::with_path("/oai/embeds").post(api::oai::embeds))
-        .push(Router::with_path("/oai/v1/embeds").post(api::oai::embeds));
+        .push(
+            Router::with_hoop(inference_auth)
+                .push(Router::with_path("/oai/embeds").post(api::oai::embeds))
+                .push(Router::with_path("/oai/v1/embeds").post(api::oai::embeds))
+                .push(Router::with_path("/embed/models").get(api::embed::models)),
+        )
+        .push(
+            Router::with_path("/embed/load")
+                .hoop(admin_auth())
+                .hoop(admin_key_auth)
+                .post(api::embed::load),
+        );
     #[cfg(not(feature = "embed"))]
     let api_embed = Router::new();
 
+    let admission = api::admission::Admission::new(&config.admission);
+    let readiness = api::health::Readiness::new(sender.clone());
+    let state = api::shared::SharedState::default()
+        .inject(sender)
+        .inject(api::reload::LiveConfig::new(config_path, config.clone()))
+        .inject(api::messages::SessionStore::default())
+        .inject(api::messages::StreamBacklogs::default())
+        .inject(api::messages::StateStore::new(config.state_store.clone()))
+        .inject(api::messages::ConversationStore::new(
+            config.conversations.clone(),
+        ))
+        .inject(metrics)
+        .inject(
+            api::usage::StopHooks::default()
+                .with(usage.hook())
+                .with(artifacts.hook()),
+        )
+        .inject(usage)
+        .inject(admission.clone())
+        .inject(readiness)
+        .inject(hub)
+        .inject(server_tools)
+        .inject(captioner)
+        .inject(retriever)
+        .inject(moderator)
+        .inject(artifacts);
+    #[cfg(feature = "embed")]
+    let state = state.inject(embed);
+    let state = match audit {
+        Some(audit) => state.inject(audit),
+        None => state,
+    };
+    #[cfg(feature = "chaos")]
+    let state = state.inject(chaos);
+
     let app = Router::new()
         //.hoop(CorsLayer::permissive())
         .hoop(Logger::new())
         .hoop(api::request_id::request_id_handler)
-        .hoop(
-            affix_state::inject(sender)
-                .inject(config.clone())
-                .insert("embed", embed),
-        )
+        .hoop(api::metrics::track_requests)
+        .hoop(api::error::error_parity)
+        .hoop(state)
+        .hoop(api::reload::inject)
+        .hoop(api::usage::account)
+        .push(Router::with_path("/metrics").get(api::metrics::metrics))
+        .push(Router::with_path("/healthz").get(api::health::healthz))
+        .push(Router::with_path("/readyz").get(api::health::readyz))
         .push(
             Router::with_path("/api")
                 .push(Router::with_path("/auth/exchange").post(api::auth::exchange))
+                .push(api_usage)
                 .push(api_router)
                 .push(api_embed),
         )
         .push(Router::with_path("/admin").push(admin_router));
+    #[cfg(feature = "otel")]
+    let app = match otel.is_some() {
+        true => app.hoop(api::otel::trace_request),
+        false => app,
+    };
 
     let doc = OpenApi::new(bin_name, version).merge_router(&app);
 
@@ -304,13 +562,16 @@ async fn main() {
         .push(doc.into_router("/api-docs/openapi.json"))
         .push(SwaggerUi::new("/api-docs/openapi.json").into_router("api-docs"));
     // this static serve should be after `swagger`
-    let app = match serve_path {
-        Some(path) => app
+    let app = match (web_dir, serve_path) {
+        (Some(web), _) => app.push(Router::with_path("{*path}").get(web)),
+        (None, Some(path)) => app
             .push(Router::with_path("{*path}").get(StaticDir::new(path).defaults(["index.html"]))),
-        None => app,
+        (None, None) => app,
     };
 
-    let service = Service::new(app).hoop(cors);
+    let service = Service::new(app)
+        .catcher(Catcher::default().hoop(api::error::catch_error))
+        .hoop(cors);
     let ip_addr = args.ip.unwrap_or(config.listen.ip);
     let (ipv4_addr, ipv6_addr) = match ip_addr {
         IpAddr::V4(addr) => (addr, None),
@@ -336,14 +597,22 @@ async fn main() {
     logging::lifecycle::server_binding(&url, tls, acme);
 
     // Helper macro to run server with graceful shutdown
+    let http = config.http.clone();
     macro_rules! serve_graceful {
         ($acceptor:expr, $service:expr) => {{
-            let server = salvo::server::Server::new($acceptor);
+            let mut server = Server::new($acceptor);
+            tune_http(&mut server, &http);
             let handle = server.handle();
 
-            // Spawn task that waits for shutdown signal, then tells server to stop
+            // Spawn task that waits for shutdown signal or a drain request,
+            // lets in-flight generations finish, then tells server to stop
+            let admission = admission.clone();
             tokio::spawn(async move {
-                shutdown_signal().await;
+                tokio::select! {
+                    _ = shutdown_signal() => {}
+                    _ = admission.drain_requested() => logging::lifecycle::server_shutdown("drain"),
+                }
+                logging::lifecycle::server_drained(admission.drain().await);
                 handle.stop_graceful(Some(Duration::from_secs(5)));
             });
 
@@ -429,4 +698,10 @@ async fn main() {
     };
 
     tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // flush the spans still in the batch
+    #[cfg(feature = "otel")]
+    if let Some(provider) = otel {
+        let _ = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+    }
 }
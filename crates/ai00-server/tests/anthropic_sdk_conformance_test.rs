@@ -0,0 +1,703 @@
+//! Conformance tests against the wire format of the official Anthropic SDKs.
+//!
+//! Vendored fixtures live in `tests/fixtures/anthropic_sdk/`:
+//! - `requests/`: request bodies as serialized by the SDKs for the create, stream,
+//!   tool use and thinking flows.
+//! - `responses/`: reference responses of the Anthropic API, including a stream transcript.
+//! - `shapes.json`: the required shapes of the SDK response models.
+//!
+//! Requests must be accepted by the server, and everything the server sends back must satisfy
+//! the shapes the SDKs validate, including the way their stream helpers accumulate events
+//! into a final message. The `route_*` tests post the SDK requests to `/v1/messages`, answered
+//! by the mock runtimes of `common::mocks`.
+
+mod common;
+
+use std::{path::PathBuf, sync::Arc};
+
+use ai00_server::api::error::ApiErrorResponse;
+use ai00_server::api::messages::{
+    messages_handler, ContentBlock, ContentBlockDeltaEvent, ContentBlockStartEvent,
+    ContentBlockStopEvent, ContentDelta, MessageDeltaData, MessageDeltaEvent, MessageStartData,
+    MessageStartEvent, MessageStopEvent, MessagesRequest, MessagesResponse, OutputUsage, PingEvent,
+    SessionStore, StopReason, StreamBacklogs, StreamErrorData, StreamErrorEvent, ToolChoice,
+    ToolChoiceSimple, Usage,
+};
+use ai00_server::{api::shared::SharedState, config::Config, types::ThreadSender};
+use common::mocks::{
+    create_length_limited_mock_sender, create_mock_sender, create_streaming_mock_sender,
+};
+use rstest::rstest;
+use salvo::{
+    prelude::*,
+    test::{ResponseExt, TestClient},
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/anthropic_sdk")
+        .join(name)
+}
+
+fn fixture(name: &str) -> Value {
+    let text = std::fs::read_to_string(fixture_path(name)).unwrap();
+    serde_json::from_str(&text).unwrap()
+}
+
+fn fixture_lines(name: &str) -> Vec<Value> {
+    let text = std::fs::read_to_string(fixture_path(name)).unwrap();
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn to_json<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap()
+}
+
+/// Checker of the shape language described in `shapes.json`.
+struct Shapes(Value);
+
+impl Shapes {
+    fn load() -> Self {
+        Self(fixture("shapes.json"))
+    }
+
+    /// Panic unless `value` has the shape of the definition `name`.
+    fn assert(&self, value: &Value, name: &str) {
+        if let Err(err) = self.check(value, &self.0[name], "$") {
+            panic!("not a valid {name}: {err}\n{value:#}");
+        }
+    }
+
+    fn check(&self, value: &Value, shape: &Value, path: &str) -> Result<(), String> {
+        match shape {
+            Value::String(shape) => self.check_type(value, shape, path),
+            Value::Array(shape) => {
+                let items = value
+                    .as_array()
+                    .ok_or_else(|| format!("{path}: expected an array"))?;
+                items.iter().enumerate().try_for_each(|(index, item)| {
+                    self.check(item, &shape[0], &format!("{path}[{index}]"))
+                })
+            }
+            Value::Object(shape) => {
+                let object = value
+                    .as_object()
+                    .ok_or_else(|| format!("{path}: expected an object"))?;
+                if let Some(tag) = shape.get("$tag").and_then(Value::as_str) {
+                    let variant = object
+                        .get(tag)
+                        .and_then(Value::as_str)
+                        .ok_or_else(|| format!("{path}: missing `{tag}`"))?;
+                    let shape = shape
+                        .get(variant)
+                        .ok_or_else(|| format!("{path}: unknown {tag} `{variant}`"))?;
+                    return self.check(value, shape, path);
+                }
+                shape
+                    .iter()
+                    .filter(|(key, _)| !key.starts_with('$'))
+                    .try_for_each(|(key, shape)| {
+                        let (key, optional) = match key.strip_suffix('?') {
+                            Some(key) => (key, true),
+                            None => (key.as_str(), false),
+                        };
+                        match object.get(key) {
+                            Some(value) => self.check(value, shape, &format!("{path}.{key}")),
+                            None if optional => Ok(()),
+                            None => Err(format!("{path}: missing `{key}`")),
+                        }
+                    })
+            }
+            _ => Err(format!("{path}: invalid shape {shape}")),
+        }
+    }
+
+    fn check_type(&self, value: &Value, shape: &str, path: &str) -> Result<(), String> {
+        if let Some(name) = shape.strip_prefix('#') {
+            return self.check(value, &self.0[name], path);
+        }
+        let matches = shape.split('|').any(|ty| match ty {
+            "string" => value.is_string(),
+            "integer" => value.is_u64() || value.is_i64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "any" => true,
+            literal => literal
+                .strip_prefix('=')
+                .is_some_and(|literal| value.as_str() == Some(literal)),
+        });
+        match matches {
+            true => Ok(()),
+            false => Err(format!("{path}: expected {shape}, got {value}")),
+        }
+    }
+}
+
+fn append(block: &mut Value, key: &str, text: &str) {
+    let joined = format!("{}{}", block[key].as_str().unwrap_or_default(), text);
+    block[key] = Value::String(joined);
+}
+
+/// Validate a stream of events and accumulate them into the final message,
+/// following the stream helpers of the SDKs.
+fn accumulate(shapes: &Shapes, events: &[Value]) -> Value {
+    let mut message: Option<Value> = None;
+    let mut open: Option<usize> = None;
+    let mut partial_json = String::new();
+
+    for (position, event) in events.iter().enumerate() {
+        shapes.assert(event, "stream_event");
+        let index = event["index"].as_u64().map(|index| index as usize);
+        let kind = event["type"].as_str().unwrap();
+        if kind == "message_start" {
+            assert!(message.is_none(), "duplicate message_start");
+            message = Some(event["message"].clone());
+            continue;
+        }
+        let Some(snapshot) = message.as_mut() else {
+            panic!("{kind} before message_start");
+        };
+
+        match kind {
+            "content_block_start" => {
+                let index = index.unwrap();
+                let content = snapshot["content"].as_array_mut().unwrap();
+                assert!(open.is_none(), "block {index} starts before {open:?} stops");
+                assert_eq!(index, content.len(), "blocks must start in order");
+                content.push(event["content_block"].clone());
+                open = Some(index);
+            }
+            "content_block_delta" => {
+                let index = index.unwrap();
+                assert_eq!(open, Some(index), "delta of a block that is not open");
+                let block = &mut snapshot["content"][index];
+                let delta = &event["delta"];
+                let block_type = block["type"].as_str().unwrap().to_owned();
+                match (delta["type"].as_str().unwrap(), block_type.as_str()) {
+                    ("text_delta", "text") => {
+                        append(block, "text", delta["text"].as_str().unwrap())
+                    }
+                    ("thinking_delta", "thinking") => {
+                        append(block, "thinking", delta["thinking"].as_str().unwrap())
+                    }
+                    ("signature_delta", "thinking") => {
+                        block["signature"] = delta["signature"].clone();
+                    }
+                    ("input_json_delta", "tool_use") => {
+                        partial_json.push_str(delta["partial_json"].as_str().unwrap());
+                    }
+                    (delta, block) => panic!("{delta} does not apply to a {block} block"),
+                }
+            }
+            "content_block_stop" => {
+                let index = index.unwrap();
+                assert_eq!(open.take(), Some(index), "stop of a block that is not open");
+                let block = &mut snapshot["content"][index];
+                if block["type"] == "tool_use" && !partial_json.is_empty() {
+                    let input: Value = serde_json::from_str(&partial_json)
+                        .unwrap_or_else(|err| panic!("invalid tool input {partial_json}: {err}"));
+                    block["input"] = input;
+                }
+                partial_json.clear();
+            }
+            "message_delta" => {
+                assert!(open.is_none(), "message_delta while block {open:?} is open");
+                snapshot["stop_reason"] = event["delta"]["stop_reason"].clone();
+                snapshot["stop_sequence"] = event["delta"]["stop_sequence"].clone();
+                snapshot["usage"]["output_tokens"] = event["usage"]["output_tokens"].clone();
+            }
+            "message_stop" => {
+                assert_eq!(position, events.len() - 1, "events after message_stop");
+            }
+            "ping" => {}
+            kind => panic!("unexpected {kind} event"),
+        }
+    }
+
+    let message = message.expect("empty stream");
+    assert_eq!(events.last().unwrap()["type"], "message_stop");
+    shapes.assert(&message, "message");
+    message
+}
+
+fn usage() -> Usage {
+    Usage {
+        input_tokens: 25,
+        output_tokens: 1,
+        cache_creation_input_tokens: 0,
+        cache_read_input_tokens: 0,
+        server_tool_use: None,
+    }
+}
+
+fn message_start() -> Value {
+    to_json(&MessageStartEvent {
+        event_type: "message_start",
+        message: MessageStartData {
+            id: "msg_01".to_string(),
+            object: "message",
+            role: "assistant",
+            model: "rwkv".to_string(),
+            content: vec![],
+            stop_reason: None,
+            stop_sequence: None,
+            usage: usage(),
+            metadata: Default::default(),
+        },
+    })
+}
+
+fn block_start(index: usize, content_block: ContentBlock) -> Value {
+    to_json(&ContentBlockStartEvent {
+        event_type: "content_block_start",
+        index,
+        content_block,
+    })
+}
+
+fn block_delta(index: usize, delta: ContentDelta) -> Value {
+    to_json(&ContentBlockDeltaEvent {
+        event_type: "content_block_delta",
+        index,
+        delta,
+    })
+}
+
+fn block_stop(index: usize) -> Value {
+    to_json(&ContentBlockStopEvent {
+        event_type: "content_block_stop",
+        index,
+    })
+}
+
+fn message_end(stop_reason: StopReason) -> Vec<Value> {
+    vec![
+        to_json(&MessageDeltaEvent {
+            event_type: "message_delta",
+            delta: MessageDeltaData {
+                stop_reason,
+                stop_sequence: None,
+            },
+            usage: OutputUsage { output_tokens: 12 },
+        }),
+        to_json(&MessageStopEvent {
+            event_type: "message_stop",
+        }),
+    ]
+}
+
+fn text_block(text: &str) -> ContentBlock {
+    ContentBlock::Text {
+        text: text.to_string(),
+    }
+}
+
+/// The reference fixtures must satisfy the shapes, or the shapes are wrong.
+#[test]
+fn test_reference_responses_match_shapes() {
+    let shapes = Shapes::load();
+    shapes.assert(&fixture("responses/text.json"), "message");
+    shapes.assert(&fixture("responses/tool_use.json"), "message");
+
+    let message = accumulate(&shapes, &fixture_lines("responses/stream.jsonl"));
+    assert_eq!(message["stop_reason"], "tool_use");
+    assert_eq!(
+        message["content"][2]["input"]["location"],
+        "San Francisco, CA"
+    );
+}
+
+/// Reference responses deserialize into the server's content types.
+#[rstest]
+#[case("responses/text.json")]
+#[case("responses/tool_use.json")]
+fn test_reference_responses_deserialize(#[case] name: &str) {
+    let response = fixture(name);
+    let content: Vec<ContentBlock> = serde_json::from_value(response["content"].clone()).unwrap();
+    assert!(!content.is_empty());
+    let stop_reason: StopReason = serde_json::from_value(response["stop_reason"].clone()).unwrap();
+    assert_ne!(stop_reason, StopReason::Null);
+}
+
+/// Requests serialized by the SDKs are accepted.
+#[rstest]
+#[case("requests/create.json")]
+#[case("requests/stream.json")]
+#[case("requests/tool_use.json")]
+#[case("requests/tool_result.json")]
+#[case("requests/thinking.json")]
+fn test_sdk_requests_deserialize(#[case] name: &str) {
+    let request: MessagesRequest = serde_json::from_value(fixture(name)).unwrap();
+    assert!(!request.messages.is_empty());
+    for tool in request.tools.iter().flatten() {
+        assert!(tool.validate().is_ok(), "tool {} is invalid", tool.name);
+    }
+    if let Some(thinking) = &request.thinking {
+        assert!(thinking.validate(request.max_tokens).is_ok());
+    }
+}
+
+/// The SDKs send the system prompt as an array of text blocks.
+#[test]
+fn test_sdk_system_blocks() {
+    let request: MessagesRequest = serde_json::from_value(fixture("requests/create.json")).unwrap();
+    assert_eq!(
+        request.system.as_deref(),
+        Some("You are a helpful assistant.")
+    );
+
+    let mut body = fixture("requests/create.json");
+    body["system"] = json!([
+        {"type": "text", "text": "First."},
+        {"type": "text", "text": "Second."}
+    ]);
+    let request: MessagesRequest = serde_json::from_value(body).unwrap();
+    assert_eq!(request.system.as_deref(), Some("First.\n\nSecond."));
+}
+
+/// `cache_control` on a system block marks the system prompt for caching, and survives a round trip.
+#[test]
+fn test_sdk_system_cache_control() {
+    let mut body = fixture("requests/create.json");
+    body["system"] = json!([
+        {"type": "text", "text": "Rules."},
+        {"type": "text", "text": "Long manual.", "cache_control": {"type": "ephemeral"}}
+    ]);
+    let request: MessagesRequest = serde_json::from_value(body).unwrap();
+    let system = request.system.clone().unwrap();
+    assert!(system.cache_control);
+    assert_eq!(&*system, "Rules.\n\nLong manual.");
+
+    let json = serde_json::to_value(&request).unwrap();
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert_eq!(request.system, Some(system));
+}
+
+/// Cached prompt tokens are reported apart from `input_tokens`, as the Anthropic API does.
+#[test]
+fn test_usage_cache_tokens() {
+    let counter = ai00_core::TokenCounter {
+        prompt: 100,
+        completion: 5,
+        total: 105,
+        cache_read: 80,
+        ..Default::default()
+    };
+    let usage = Usage::from(counter);
+    assert_eq!(usage.input_tokens, 20);
+    assert_eq!(usage.cache_read_input_tokens, 80);
+    assert_eq!(usage.cache_creation_input_tokens, 0);
+}
+
+/// Usage adds up over the rounds of a tool loop, and reports the calls the server answered.
+#[test]
+fn test_usage_server_tools() {
+    let round = ai00_core::TokenCounter {
+        prompt: 40,
+        completion: 10,
+        total: 50,
+        cache_read: 30,
+        ..Default::default()
+    };
+    let mut counter = round.clone();
+    counter += round;
+    assert_eq!((counter.prompt, counter.cache_read), (80, 60));
+
+    let shapes = Shapes::load();
+    let usage = Usage::from(counter.clone()).with_server_tool_requests(2);
+    let json = to_json(&usage);
+    shapes.assert(&json, "usage");
+    assert_eq!(json["server_tool_use"]["requests"], 2);
+
+    let json = to_json(&Usage::from(counter).with_server_tool_requests(0));
+    assert!(json.get("server_tool_use").is_none());
+}
+
+/// The SDKs send simple tool choices as objects.
+#[rstest]
+#[case("requests/tool_use.json", ToolChoiceSimple::Auto)]
+#[case("requests/tool_result.json", ToolChoiceSimple::Any)]
+fn test_sdk_tool_choice_objects(#[case] name: &str, #[case] expected: ToolChoiceSimple) {
+    let request: MessagesRequest = serde_json::from_value(fixture(name)).unwrap();
+    match request.tool_choice {
+        Some(ToolChoice::Typed(choice)) => assert_eq!(choice.choice_type, expected),
+        choice => panic!("Expected typed choice, got {choice:?}"),
+    }
+}
+
+/// Non-streaming responses of every flow are valid SDK messages.
+#[rstest]
+#[case(vec![text_block("Hello!")], StopReason::EndTurn)]
+#[case(vec![text_block("Hello")], StopReason::MaxTokens)]
+#[case(
+    vec![
+        text_block("Let me check."),
+        ContentBlock::ToolUse {
+            id: "toolu_01".to_string(),
+            name: "get_weather".to_string(),
+            input: json!({"location": "San Francisco, CA"}),
+        },
+    ],
+    StopReason::ToolUse
+)]
+#[case(
+    vec![
+        ContentBlock::Thinking {
+            thinking: "Euclid.".to_string(),
+            signature: "sig".to_string(),
+        },
+        text_block("Yes."),
+    ],
+    StopReason::EndTurn
+)]
+fn test_response_conforms(#[case] content: Vec<ContentBlock>, #[case] stop_reason: StopReason) {
+    let response =
+        MessagesResponse::new("rwkv".to_string(), content, usage()).with_stop_reason(stop_reason);
+    Shapes::load().assert(&to_json(&response), "message");
+}
+
+/// Streamed text accumulates into a valid message.
+#[test]
+fn test_text_stream_conforms() {
+    let mut events = vec![
+        message_start(),
+        block_start(0, text_block("")),
+        to_json(&PingEvent { event_type: "ping" }),
+        block_delta(
+            0,
+            ContentDelta::Text {
+                text: "Hello".to_string(),
+            },
+        ),
+        block_delta(
+            0,
+            ContentDelta::Text {
+                text: ", world".to_string(),
+            },
+        ),
+        block_stop(0),
+    ];
+    events.extend(message_end(StopReason::EndTurn));
+
+    let message = accumulate(&Shapes::load(), &events);
+    assert_eq!(message["content"][0]["text"], "Hello, world");
+    assert_eq!(message["stop_reason"], "end_turn");
+}
+
+/// Streamed thinking and text accumulate into a valid message.
+#[test]
+fn test_thinking_stream_conforms() {
+    let mut events = vec![
+        message_start(),
+        block_start(
+            0,
+            ContentBlock::Thinking {
+                thinking: String::new(),
+                signature: String::new(),
+            },
+        ),
+        block_delta(
+            0,
+            ContentDelta::Thinking {
+                thinking: "Euclid.".to_string(),
+            },
+        ),
+        block_delta(
+            0,
+            ContentDelta::Signature {
+                signature: "sig".to_string(),
+            },
+        ),
+        block_stop(0),
+        block_start(1, text_block("")),
+        block_delta(
+            1,
+            ContentDelta::Text {
+                text: "Yes.".to_string(),
+            },
+        ),
+        block_stop(1),
+    ];
+    events.extend(message_end(StopReason::EndTurn));
+
+    let message = accumulate(&Shapes::load(), &events);
+    assert_eq!(message["content"][0]["thinking"], "Euclid.");
+    assert_eq!(message["content"][0]["signature"], "sig");
+    assert_eq!(message["content"][1]["text"], "Yes.");
+}
+
+/// Streamed tool calls accumulate into a valid message with a parsed input.
+#[test]
+fn test_tool_use_stream_conforms() {
+    let mut events = vec![
+        message_start(),
+        block_start(
+            0,
+            ContentBlock::ToolUse {
+                id: "toolu_01".to_string(),
+                name: "get_weather".to_string(),
+                input: json!({}),
+            },
+        ),
+        block_delta(
+            0,
+            ContentDelta::InputJson {
+                partial_json: "{\"location\": \"San Fra".to_string(),
+            },
+        ),
+        block_delta(
+            0,
+            ContentDelta::InputJson {
+                partial_json: "ncisco, CA\"}".to_string(),
+            },
+        ),
+        block_stop(0),
+    ];
+    events.extend(message_end(StopReason::ToolUse));
+
+    let message = accumulate(&Shapes::load(), &events);
+    assert_eq!(
+        message["content"][0]["input"]["location"],
+        "San Francisco, CA"
+    );
+    assert_eq!(message["stop_reason"], "tool_use");
+}
+
+/// Error bodies and stream error events are recognized by the SDKs.
+#[test]
+fn test_errors_conform() {
+    let shapes = Shapes::load();
+    let error = ApiErrorResponse::invalid_request("max_tokens: field required");
+    shapes.assert(&to_json(&error), "error_response");
+
+    let event = StreamErrorEvent {
+        event_type: "error",
+        error: StreamErrorData {
+            error_type: "overloaded_error".to_string(),
+            message: "Overloaded".to_string(),
+            partial_content: Some(vec![text_block("Hel")]),
+            details: None,
+        },
+    };
+    shapes.assert(&to_json(&event), "stream_event");
+}
+
+/// The messages route, with the state the server injects and `sender` as the runtime.
+fn service(sender: ThreadSender) -> Service {
+    let state = SharedState::default()
+        .inject(sender)
+        .inject(Arc::new(Config::default()))
+        .inject(SessionStore::default())
+        .inject(StreamBacklogs::default());
+    let router = Router::new()
+        .hoop(state)
+        .push(Router::with_path("v1/messages").post(messages_handler));
+    Service::new(router)
+}
+
+/// Post `body` to the messages route, and return the status and the body of the response.
+async fn post(sender: ThreadSender, body: &Value) -> (StatusCode, String) {
+    let mut res = TestClient::post("http://127.0.0.1:65535/v1/messages")
+        .json(body)
+        .send(&service(sender))
+        .await;
+    let status = res.status_code.unwrap_or(StatusCode::OK);
+    (status, res.take_string().await.unwrap())
+}
+
+/// The events of a server-sent event stream.
+fn sse_events(body: &str) -> Vec<Value> {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| serde_json::from_str(data.trim()).unwrap())
+        .collect()
+}
+
+/// A non-streaming request of the SDK gets a valid message.
+#[tokio::test]
+async fn test_route_create_conforms() {
+    let (status, body) = post(
+        create_mock_sender("Hello!"),
+        &fixture("requests/create.json"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let message: Value = serde_json::from_str(&body).unwrap();
+    Shapes::load().assert(&message, "message");
+    assert_eq!(message["content"][0]["text"], "Hello!");
+    assert_eq!(message["stop_reason"], "end_turn");
+}
+
+/// Running out of tokens is reported as `max_tokens`.
+#[tokio::test]
+async fn test_route_max_tokens_conforms() {
+    let sender = create_length_limited_mock_sender("Hello, wor");
+    let (status, body) = post(sender, &fixture("requests/create.json")).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let message: Value = serde_json::from_str(&body).unwrap();
+    Shapes::load().assert(&message, "message");
+    assert_eq!(message["stop_reason"], "max_tokens");
+}
+
+/// A streaming request of the SDK gets events that accumulate into a valid message.
+#[tokio::test]
+async fn test_route_stream_conforms() {
+    let sender = create_streaming_mock_sender(vec!["Hello", ", ", "world"]);
+    let (status, body) = post(sender, &fixture("requests/stream.json")).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let message = accumulate(&Shapes::load(), &sse_events(&body));
+    assert_eq!(message["content"][0]["text"], "Hello, world");
+    assert_eq!(message["stop_reason"], "end_turn");
+}
+
+/// A tool call of the model comes back as a valid `tool_use` block.
+#[tokio::test]
+async fn test_route_tool_use_conforms() {
+    let output = r#"Let me check.
+<ai00:function_calls>
+  <invoke name="get_weather">
+    <parameter name="location">San Francisco, CA</parameter>
+  </invoke>
+</ai00:function_calls>"#;
+    let (status, body) = post(
+        create_mock_sender(output),
+        &fixture("requests/tool_use.json"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let message: Value = serde_json::from_str(&body).unwrap();
+    Shapes::load().assert(&message, "message");
+    let content = message["content"].as_array().unwrap();
+    let call = content
+        .iter()
+        .find(|block| block["type"] == "tool_use")
+        .unwrap_or_else(|| panic!("no tool_use block in {message:#}"));
+    assert_eq!(call["name"], "get_weather");
+    assert_eq!(call["input"]["location"], "San Francisco, CA");
+    assert_eq!(message["stop_reason"], "tool_use");
+}
+
+/// Invalid requests are rejected with an error body the SDKs recognize.
+#[tokio::test]
+async fn test_route_error_conforms() {
+    let mut body = fixture("requests/create.json");
+    body["messages"] = json!([]);
+    let (status, body) = post(create_mock_sender("Hello!"), &body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let error: Value = serde_json::from_str(&body).unwrap();
+    Shapes::load().assert(&error, "error_response");
+    assert_eq!(error["error"]["type"], "invalid_request_error");
+}
@@ -14,7 +14,7 @@ use ai00_core::{
 use ai00_server::api::messages::{
     bnf_generator::{
         generate_schema_aware_grammar, generate_tool_grammars, generate_tool_name_grammar,
-        json_schema_to_kbnf, GeneratorContext,
+        json_schema_to_kbnf, schema_to_grammar, GeneratorContext,
     },
     bnf_grammars::{
         build_structural_grammar, wrap_grammar_with_thinking, GRAMMAR_JSON_PRIMITIVES,
@@ -132,6 +132,7 @@ async fn setup_model_internal() -> (Sender<ThreadRequest>, Arc<Tokenizer>) {
 
     // Create reload request
     let reload_request = ReloadRequest {
+        name: None,
         model_path: model_path(),
         lora: vec![],
         state: vec![],
@@ -139,13 +140,23 @@ async fn setup_model_internal() -> (Sender<ThreadRequest>, Arc<Tokenizer>) {
         quant_type: Default::default(),
         precision: Precision::Fp16,
         token_chunk_size: 128,
+        tune_token_chunk_size: false,
+        max_prefill_chunk_tokens: None,
+        vram_budget: None,
+        pad_vocab: true,
         max_batch: 4,
+        stream_load: false,
         tokenizer_path: tokenizer_path(),
         bnf: BnfOption {
             enable_bytes_cache: true,
             start_nonterminal: "start".to_string(),
+            ..Default::default()
         },
         adapter: AdapterOption::Auto,
+        fairness: Default::default(),
+        warmup: None,
+        cache_snapshot: None,
+        adapter_of: None,
         backend: Backend::WebGpu,
     };
 
@@ -181,11 +192,15 @@ async fn generate_with_bnf(
 
     let request = GenerateRequest {
         prompt: prompt.to_string(),
+        cache_prefix: None,
         model_text: String::new(),
         max_tokens,
         stop: vec![],
+        stop_tokens: vec![],
+        stop_bytes: vec![],
         bias: Arc::new(HashMap::new()),
         bnf_schema,
+        regex: None,
         sampler: Arc::new(RwLock::new(
             ai00_core::sampler::nucleus::NucleusSampler::default(),
         )),
@@ -193,6 +208,9 @@ async fn generate_with_bnf(
         state: Default::default(),
         request_id: None,
         trace_id: None,
+        model: None,
+        traffic_class: Default::default(),
+        ..Default::default()
     };
 
     sender
@@ -258,6 +276,39 @@ fn test_bnf_sampler_compiles_unified_grammar() {
     );
 }
 
+/// Test that a cached grammar is compiled once and copied afterwards.
+#[test]
+fn test_bnf_cache_reuses_compiled_grammar() {
+    use ai00_core::sampler::{
+        bnf::{BnfCache, BnfSampler},
+        Formatter,
+    };
+
+    let tokenizer = load_tokenizer();
+    let cache = std::sync::Mutex::new(BnfCache::new(1));
+    let grammar = build_structural_grammar(false, false, &["\n\n".to_string()]);
+
+    let mut first = BnfSampler::cached(&cache, &tokenizer, &grammar).unwrap();
+    let second = BnfSampler::cached(&cache, &tokenizer, &grammar).unwrap();
+    assert_eq!(cache.lock().unwrap().len(), 1);
+
+    // the copy starts from the initial state, whatever the first sampler accepted since
+    let token = tokenizer.encode(b"Hi").unwrap()[0];
+    first.update(token);
+    let vocab_size = tokenizer.token_index_to_bytes().len();
+    let mut fresh = vec![0.0; vocab_size];
+    let mut copied = vec![0.0; vocab_size];
+    BnfSampler::new(&tokenizer, &grammar)
+        .unwrap()
+        .transform(&mut fresh);
+    second.transform(&mut copied);
+    assert_eq!(fresh, copied);
+
+    // the least recently used grammar makes room for another
+    BnfSampler::cached(&cache, &tokenizer, r#"start::='yes' | 'no';"#).unwrap();
+    assert_eq!(cache.lock().unwrap().len(), 1);
+}
+
 /// Test BnfSampler compiles unified grammar with all stop sequence variants.
 #[test]
 fn test_bnf_sampler_compiles_unified_grammar_with_stop_sequences() {
@@ -882,6 +933,72 @@ fn test_json_schema_to_kbnf_types() {
     assert!(grammar.contains("test_object"));
 }
 
+/// Whether the grammar takes `text`, fed to it token by token, without rejecting a token.
+fn grammar_accepts(tokenizer: &Tokenizer, grammar: &str, text: &str) -> bool {
+    use ai00_core::sampler::Formatter;
+
+    let mut sampler = ai00_core::sampler::bnf::BnfSampler::new(tokenizer, grammar)
+        .expect("Should compile grammar");
+    let tokens = tokenizer.encode(text.as_bytes()).expect("Should tokenize");
+    tokens.iter().all(|&token| !sampler.update(token))
+}
+
+/// Test the grammars of the JSON Schema features that tool schemas use.
+#[test]
+fn test_json_schema_coverage_grammars() {
+    let tokenizer = load_tokenizer();
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "when": {"type": "string", "format": "date-time"},
+            "count": {"type": "integer", "minimum": 1, "maximum": 5},
+            "tags": {"type": "array", "items": {"type": "string"}, "minItems": 1, "maxItems": 2},
+            "owner": {"$ref": "#/$defs/person"},
+            "note": {"type": ["string", "null"]}
+        },
+        "required": ["when", "count", "tags", "owner"],
+        "additionalProperties": false,
+        "$defs": {
+            "person": {
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string"},
+                    "manager": {"$ref": "#/$defs/person"}
+                },
+                "required": ["name"]
+            }
+        }
+    });
+    let grammar = schema_to_grammar(&schema, "start");
+
+    // properties are listed in the order of their names, required ones first
+    let valid = r#"{"count": 3, "owner": {"name": "Ada", "manager": {"name": "Bob"}}, "tags": ["a", "b"], "when": "2024-05-01T12:30:00Z", "note": null}"#;
+    assert!(grammar_accepts(&tokenizer, &grammar, valid), "{grammar}");
+
+    let invalid = [
+        r#"{"count": 7"#,
+        r#"{"count": 3, "owner": {"age": 3"#,
+        r#"{"count": 3, "owner": {"name": "Ada"}, "tags": []"#,
+        r#"{"count": 3, "owner": {"name": "Ada"}, "tags": ["a", "b", "c"]"#,
+        r#"{"count": 3, "owner": {"name": "Ada"}, "tags": ["a"], "when": "tomorrow""#,
+        r#"{"count": 3, "owner": {"name": "Ada"}, "tags": ["a"], "when": "2024-05-01T12:30:00Z", "extra""#,
+    ];
+    for text in invalid {
+        assert!(!grammar_accepts(&tokenizer, &grammar, text), "{text}");
+    }
+}
+
+/// Test that stops with quotes, backslashes and line breaks compile into working terminators.
+#[test]
+fn test_structural_grammar_escaped_terminators() {
+    let tokenizer = load_tokenizer();
+    for stop in ["\"", "\\", "'", "END\n", "(.*)"] {
+        let grammar = build_structural_grammar(false, false, &[stop.to_string()]);
+        let text = format!("Hello world{stop}");
+        assert!(grammar_accepts(&tokenizer, &grammar, &text), "{stop:?}");
+    }
+}
+
 /// Test complete tool grammar generation.
 #[test]
 fn test_generate_tool_grammars_integration() {
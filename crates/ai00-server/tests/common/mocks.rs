@@ -4,9 +4,67 @@
 
 #![allow(dead_code)]
 
-use ai00_core::{FinishReason, ThreadRequest, Token, TokenCounter};
+use ai00_core::{FinishReason, ReloadRequest, RuntimeInfo, ThreadRequest, Token, TokenCounter};
 use flume::Sender;
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
+use web_rwkv::{
+    runtime::{
+        model::{ModelCustomInfo, ModelInfo, ModelVersion},
+        v7,
+    },
+    tokenizer::Tokenizer,
+};
+
+/// Info of the mock model, which has the real RWKV vocabulary.
+pub fn runtime_info() -> RuntimeInfo {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../../assets/tokenizer/rwkv_vocab_v20230424.json"
+    );
+    let tokenizer = Tokenizer::new(&std::fs::read_to_string(path).unwrap()).unwrap();
+    RuntimeInfo {
+        name: "rwkv".to_string(),
+        reload: Arc::new(ReloadRequest {
+            model_path: "rwkv.st".into(),
+            ..Default::default()
+        }),
+        info: ModelInfo {
+            version: ModelVersion::V7,
+            num_layer: 1,
+            num_emb: 64,
+            num_hidden: 256,
+            num_vocab: 65536,
+            num_head: 1,
+            custom: ModelCustomInfo::V7(v7::CustomInfo {
+                w: 8,
+                a: 8,
+                g: 8,
+                v: 8,
+            }),
+        },
+        states: vec![],
+        tokenizer: Arc::new(tokenizer),
+        chunk_benchmark: vec![],
+    }
+}
+
+/// Answer the requests for model info, as a runtime with the mock model loaded would.
+///
+/// Handlers ask for the info of the model before generating, and wait until they get it.
+fn answer_info(request: ThreadRequest) {
+    match request {
+        ThreadRequest::Info(sender) => {
+            let _ = sender.send(runtime_info());
+        }
+        ThreadRequest::InfoOf { sender, .. } => {
+            let _ = sender.send(Some(runtime_info()));
+        }
+        ThreadRequest::Models(sender) => {
+            let _ = sender.send(vec![runtime_info()]);
+        }
+        _ => {}
+    }
+}
 
 /// Create a mock ThreadSender that responds with predetermined text.
 pub fn create_mock_sender(text_response: &str) -> Sender<ThreadRequest> {
@@ -26,16 +84,12 @@ pub fn create_mock_sender(text_response: &str) -> Sender<ThreadRequest> {
                             completion: response.len() / 4,
                             total: 10 + response.len() / 4,
                             duration: Duration::from_millis(100),
+                            ..Default::default()
                         },
                     ));
                     let _ = sender.send(Token::Done);
                 }
-                ThreadRequest::Info(info_sender) => {
-                    // For info requests, we'd need a RuntimeInfo
-                    // Skip for now as tests may not need this
-                    drop(info_sender);
-                }
-                _ => {}
+                request => answer_info(request),
             }
         }
     });
@@ -63,9 +117,12 @@ pub fn create_streaming_mock_sender(tokens: Vec<&str>) -> Sender<ThreadRequest>
                         completion: tokens.len(),
                         total: 10 + tokens.len(),
                         duration: Duration::from_millis(tokens.len() as u64 * 10),
+                        ..Default::default()
                     },
                 ));
                 let _ = sender.send(Token::Done);
+            } else {
+                answer_info(request);
             }
         }
     });
@@ -90,9 +147,12 @@ pub fn create_length_limited_mock_sender(text: &str) -> Sender<ThreadRequest> {
                         completion: response.len() / 4,
                         total: 10 + response.len() / 4,
                         duration: Duration::from_millis(100),
+                        ..Default::default()
                     },
                 ));
                 let _ = sender.send(Token::Done);
+            } else {
+                answer_info(request);
             }
         }
     });
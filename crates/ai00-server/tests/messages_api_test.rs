@@ -4,10 +4,12 @@ mod common;
 
 use ai00_server::api::error::{ApiErrorKind, ApiErrorResponse};
 use ai00_server::api::messages::{
-    emit_error, generate_thinking_signature, generate_tool_system_prompt, validate_tool_name,
-    ContentBlock, MessageContent, MessageParam, MessageRole, MessagesRequest, MessagesResponse,
-    StopReason, StreamErrorEvent, ThinkingConfig, ThinkingExtractor, ThinkingStreamParser,
-    ThinkingStreamState, Tool, ToolChoice, ToolChoiceSimple, ToolChoiceSpecific,
+    emit_error, emit_message_metadata, event_data, generate_thinking_signature,
+    generate_tool_system_prompt, split_delta, validate_tool_name, ContentBlock, ContentDelta,
+    CountTokensRequest, MessageContent, MessageParam, MessageRole, MessagesRequest,
+    MessagesResponse, ResponseFormat, ResponseMetadata, ResponseTimings, StopReason,
+    StreamErrorEvent, ThinkingConfig, ThinkingExtractor, ThinkingStreamParser, ThinkingStreamState,
+    Tool, ToolChoice, ToolChoiceSimple, ToolChoiceSpecific,
 };
 use ai00_server::config::PromptsConfig;
 use rstest::rstest;
@@ -72,11 +74,36 @@ fn test_messages_response_serialization() {
 #[case(StopReason::MaxTokens, "max_tokens")]
 #[case(StopReason::StopSequence, "stop_sequence")]
 #[case(StopReason::ToolUse, "tool_use")]
+#[case(StopReason::Timeout, "timeout")]
 fn test_stop_reason_serialization(#[case] reason: StopReason, #[case] expected: &str) {
     let json = serde_json::to_value(reason).unwrap();
     assert_eq!(json, expected);
 }
 
+/// Test that a fired stop sequence is reported in the response.
+#[test]
+fn test_stop_sequence_reported() {
+    let reason = ai00_core::FinishReason::StopSequence("END".to_string());
+    assert_eq!(reason.stop_sequence(), Some("END"));
+    // OpenAI clients only see a plain stop
+    assert_eq!(serde_json::to_value(&reason).unwrap(), "stop");
+
+    let response = MessagesResponse::new("rwkv".to_string(), vec![], Default::default())
+        .with_stop_reason(reason.clone().into())
+        .with_stop_sequence(reason.stop_sequence().unwrap().to_string());
+    let json = serde_json::to_value(&response).unwrap();
+    assert_eq!(json["stop_reason"], "stop_sequence");
+    assert_eq!(json["stop_sequence"], "END");
+}
+
+/// Test that a generation stopped at its wall-clock limit is reported as a timeout.
+#[test]
+fn test_timeout_reported() {
+    let reason = ai00_core::FinishReason::Timeout;
+    assert_eq!(serde_json::to_value(&reason).unwrap(), "timeout");
+    assert_eq!(StopReason::from(reason), StopReason::Timeout);
+}
+
 /// Test error response format.
 #[test]
 fn test_error_response_format() {
@@ -95,6 +122,7 @@ fn test_error_response_format() {
 #[case(ApiErrorKind::AuthenticationError, "authentication_error")]
 #[case(ApiErrorKind::PermissionError, "permission_error")]
 #[case(ApiErrorKind::NotFoundError, "not_found_error")]
+#[case(ApiErrorKind::RequestTooLarge, "request_too_large")]
 #[case(ApiErrorKind::RateLimitError, "rate_limit_error")]
 #[case(ApiErrorKind::ApiError, "api_error")]
 #[case(ApiErrorKind::OverloadedError, "overloaded_error")]
@@ -123,8 +151,8 @@ fn test_full_request_deserialization() {
     let request: MessagesRequest = serde_json::from_value(json).unwrap();
     assert_eq!(request.model, "rwkv-7-g1");
     assert_eq!(
-        request.system,
-        Some("You are a helpful assistant.".to_string())
+        request.system.as_deref(),
+        Some("You are a helpful assistant.")
     );
     assert!(request.stream);
     assert_eq!(request.temperature, Some(0.7));
@@ -136,6 +164,179 @@ fn test_full_request_deserialization() {
     );
 }
 
+/// Test stop token IDs and raw byte stop sequences.
+#[test]
+fn test_stop_tokens_and_bytes_deserialization() {
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100,
+        "stop_tokens": [0, 261],
+        "stop_bytes": [[0xe2, 0x80], [0x0a, 0x0a]]
+    });
+
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert_eq!(request.stop_tokens, Some(vec![0, 261]));
+    assert_eq!(
+        request.stop_bytes,
+        Some(vec![vec![0xe2, 0x80], vec![0x0a, 0x0a]])
+    );
+}
+
+/// Test that a request can start from a stored state.
+#[test]
+fn test_state_id_deserialization() {
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100,
+        "state_id": "0b3e1c56-52a4-4c4d-9d43-1b0b3f8c1e2a"
+    });
+
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    let state_id = serde_json::to_value(request.state_id).unwrap();
+    assert_eq!(state_id, json!("0b3e1c56-52a4-4c4d-9d43-1b0b3f8c1e2a"));
+
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100,
+        "state_id": "not-a-state"
+    });
+    assert!(serde_json::from_value::<MessagesRequest>(json).is_err());
+}
+
+/// Test min-p and typical sampling parameters.
+#[test]
+fn test_min_p_typical_p_deserialization() {
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100,
+        "temperature": 0.3,
+        "min_p": 0.05,
+        "typical_p": 0.9
+    });
+
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert_eq!(request.min_p, Some(0.05));
+    assert_eq!(request.typical_p, Some(0.9));
+
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100
+    });
+
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert!(request.min_p.is_none());
+    assert!(request.typical_p.is_none());
+}
+
+/// Test penalty parameters.
+#[test]
+fn test_penalty_deserialization() {
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100,
+        "presence_penalty": 0.5,
+        "frequency_penalty": -0.2,
+        "repetition_penalty": 1.2,
+        "repetition_window": 128
+    });
+
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert_eq!(request.presence_penalty, Some(0.5));
+    assert_eq!(request.frequency_penalty, Some(-0.2));
+    assert_eq!(request.repetition_penalty, Some(1.2));
+    assert_eq!(request.repetition_window, Some(128));
+}
+
+/// Test dynamic temperature parameters.
+#[test]
+fn test_dynatemp_deserialization() {
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100,
+        "temperature": 1.0,
+        "dynatemp_range": 0.5,
+        "dynatemp_exponent": 1.5
+    });
+
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert_eq!(request.dynatemp_range, Some(0.5));
+    assert_eq!(request.dynatemp_exponent, Some(1.5));
+}
+
+/// Test response_format deserialization.
+#[test]
+fn test_response_format_deserialization() {
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100,
+        "response_format": {"type": "json_object"}
+    });
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert!(matches!(
+        request.response_format,
+        Some(ResponseFormat::JsonObject)
+    ));
+
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "max_tokens": 100,
+        "response_format": {
+            "type": "json_schema",
+            "json_schema": {
+                "name": "answer",
+                "schema": {"type": "object", "properties": {"answer": {"type": "string"}}}
+            }
+        }
+    });
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    let Some(ResponseFormat::JsonSchema { json_schema }) = request.response_format else {
+        panic!("expected json_schema response format");
+    };
+    assert_eq!(json_schema.name.as_deref(), Some("answer"));
+    assert_eq!(json_schema.schema["type"], "object");
+}
+
+/// Test regex constraint deserialization.
+#[test]
+fn test_regex_deserialization() {
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Today's date?"}],
+        "max_tokens": 16,
+        "regex": "\\d{4}-\\d{2}-\\d{2}"
+    });
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert_eq!(request.regex.as_deref(), Some(r"\d{4}-\d{2}-\d{2}"));
+}
+
+/// Test tool_results_preview deserialization.
+#[test]
+fn test_tool_results_preview_deserialization() {
+    let json = json!({
+        "model": "rwkv-7-g1",
+        "messages": [{"role": "user", "content": "Weather in Paris?"}],
+        "max_tokens": 100,
+        "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+        "tool_results_preview": {
+            "get_weather": "Sunny, 22°C",
+            "search": [{"type": "text", "text": "no results"}]
+        }
+    });
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    let previews = request.tool_results_preview.unwrap();
+    assert_eq!(previews["get_weather"].to_text(), "Sunny, 22°C");
+    assert_eq!(previews["search"].to_text(), "no results");
+}
+
 // =============================================================================
 // Tool Definition Tests
 // =============================================================================
@@ -232,6 +433,25 @@ fn test_tool_validation() {
     assert!(tool.validate().is_ok());
 }
 
+/// Test that count_tokens requests accept the prompt fields the SDKs send.
+#[test]
+fn test_count_tokens_request_deserialization() {
+    let json = json!({
+        "model": "rwkv",
+        "system": [{"type": "text", "text": "Be brief."}],
+        "messages": [{"role": "user", "content": "Hello"}],
+        "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+        "tool_choice": {"type": "auto"},
+        "thinking": {"type": "enabled", "budget_tokens": 1024}
+    });
+
+    let request: CountTokensRequest = serde_json::from_value(json).unwrap();
+    assert_eq!(request.system.as_deref(), Some("Be brief."));
+    assert_eq!(request.messages.len(), 1);
+    assert_eq!(request.tools.unwrap()[0].name, "get_weather");
+    assert!(request.thinking.unwrap().is_enabled());
+}
+
 /// Test ToolChoice deserialization - simple string variants.
 #[rstest]
 #[case("auto", ToolChoiceSimple::Auto)]
@@ -307,6 +527,18 @@ fn test_tool_choice_specific_new() {
     assert!(choice.disable_parallel_tool_use.is_none());
 }
 
+/// Test which tool choices limit a turn to a single tool call.
+#[rstest]
+#[case(json!("auto"), false)]
+#[case(json!({"type": "auto"}), false)]
+#[case(json!({"type": "auto", "disable_parallel_tool_use": false}), false)]
+#[case(json!({"type": "any", "disable_parallel_tool_use": true}), true)]
+#[case(json!({"type": "tool", "name": "get_weather"}), true)]
+fn test_tool_choice_single_tool_use(#[case] json: serde_json::Value, #[case] expected: bool) {
+    let choice: ToolChoice = serde_json::from_value(json).unwrap();
+    assert_eq!(choice.is_single_tool_use(), expected);
+}
+
 // =============================================================================
 // Tool Prompt Injection Tests (Hermes/Qwen Format)
 // =============================================================================
@@ -1061,6 +1293,34 @@ fn test_stream_error_event_simple() {
     assert!(text.contains("error"));
 }
 
+/// Test that the data of an event is the JSON of the event object.
+#[test]
+fn test_event_data() {
+    let event = emit_error("api_error", "Generation failed", None);
+    let data: serde_json::Value = serde_json::from_str(&event_data(&event).unwrap()).unwrap();
+    assert_eq!(data["type"], "error");
+    assert_eq!(data["error"]["message"], "Generation failed");
+}
+
+/// Test that the timings of a generation are sent in a message_metadata event.
+#[test]
+fn test_message_metadata_event() {
+    let metadata = ResponseMetadata {
+        timings: Some(ResponseTimings {
+            queue_ms: 1,
+            prefill_ms: 20,
+            decode_ms: 300,
+            total_ms: 320,
+        }),
+        ..Default::default()
+    };
+    let event = emit_message_metadata(metadata);
+    let data: serde_json::Value = serde_json::from_str(&event_data(&event).unwrap()).unwrap();
+    assert_eq!(data["type"], "message_metadata");
+    assert_eq!(data["metadata"]["timings"]["total_ms"], 320);
+    assert!(data["metadata"].get("seed").is_none());
+}
+
 /// Test streaming error event with partial content.
 #[test]
 fn test_stream_error_event_with_partial() {
@@ -1081,6 +1341,7 @@ fn test_stream_error_event_with_partial() {
             error_type: "overloaded_error".to_string(),
             message: "Server overloaded".to_string(),
             partial_content: Some(partial),
+            details: None,
         },
     };
 
@@ -1109,15 +1370,45 @@ fn test_bnf_schema_serialization() {
         max_tokens: 100,
         stream: false,
         stop_sequences: None,
+        stop_tokens: None,
+        stop_bytes: None,
+        banned_phrases: vec![],
         temperature: None,
         top_p: None,
         top_k: None,
+        min_p: None,
+        typical_p: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        repetition_penalty: None,
+        repetition_window: None,
+        dynatemp_range: None,
+        dynatemp_exponent: None,
+        logit_bias: Default::default(),
+        logit_bias_tokens: Default::default(),
         tools: None,
         tool_choice: None,
         thinking: None,
         metadata: None,
         bnf_schema: Some("start ::= \"hello\"".into()),
         bnf_validation: None,
+        grammar_terminators: None,
+        regex: None,
+        response_format: None,
+        tool_results_preview: None,
+        tool_call_retries: None,
+        tool_input_validation: Default::default(),
+        traffic_class: None,
+        timeout_ms: None,
+        max_tokens_per_second: None,
+        allow_exceed_context: false,
+        adapter: None,
+        state_id: None,
+        raw_mode: false,
+        agentic: false,
+        seed: None,
+        debug: None,
+        return_timings: false,
     };
     let json = serde_json::to_value(&request).unwrap();
     assert_eq!(json["bnf_schema"], "start ::= \"hello\"");
@@ -1135,6 +1426,27 @@ fn test_bnf_schema_optional() {
     assert!(request.bnf_schema.is_none());
 }
 
+/// Test raw_mode defaults to off and can be enabled.
+#[test]
+fn test_raw_mode_flag() {
+    let json = json!({
+        "model": "test",
+        "messages": [{"role": "user", "content": "hi"}],
+        "max_tokens": 100
+    });
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert!(!request.raw_mode);
+
+    let json = json!({
+        "model": "test",
+        "messages": [{"role": "user", "content": "hi"}],
+        "max_tokens": 100,
+        "raw_mode": true
+    });
+    let request: MessagesRequest = serde_json::from_value(json).unwrap();
+    assert!(request.raw_mode);
+}
+
 /// Test bnf_schema is not serialized when None.
 #[test]
 fn test_bnf_schema_skips_serialization_when_none() {
@@ -1148,15 +1460,45 @@ fn test_bnf_schema_skips_serialization_when_none() {
         max_tokens: 100,
         stream: false,
         stop_sequences: None,
+        stop_tokens: None,
+        stop_bytes: None,
+        banned_phrases: vec![],
         temperature: None,
         top_p: None,
         top_k: None,
+        min_p: None,
+        typical_p: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        repetition_penalty: None,
+        repetition_window: None,
+        dynatemp_range: None,
+        dynatemp_exponent: None,
+        logit_bias: Default::default(),
+        logit_bias_tokens: Default::default(),
         tools: None,
         tool_choice: None,
         thinking: None,
         metadata: None,
         bnf_schema: None,
         bnf_validation: None,
+        grammar_terminators: None,
+        regex: None,
+        response_format: None,
+        tool_results_preview: None,
+        tool_call_retries: None,
+        tool_input_validation: Default::default(),
+        traffic_class: None,
+        timeout_ms: None,
+        max_tokens_per_second: None,
+        allow_exceed_context: false,
+        adapter: None,
+        state_id: None,
+        raw_mode: false,
+        agentic: false,
+        seed: None,
+        debug: None,
+        return_timings: false,
     };
     let json = serde_json::to_value(&request).unwrap();
     assert!(json.get("bnf_schema").is_none());
@@ -1244,15 +1586,45 @@ fn test_bnf_validation_request_serialization() {
         max_tokens: 100,
         stream: false,
         stop_sequences: None,
+        stop_tokens: None,
+        stop_bytes: None,
+        banned_phrases: vec![],
         temperature: None,
         top_p: None,
         top_k: None,
+        min_p: None,
+        typical_p: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        repetition_penalty: None,
+        repetition_window: None,
+        dynatemp_range: None,
+        dynatemp_exponent: None,
+        logit_bias: Default::default(),
+        logit_bias_tokens: Default::default(),
         tools: None,
         tool_choice: None,
         thinking: None,
         metadata: None,
         bnf_schema: None,
         bnf_validation: Some(BnfValidationLevel::Structural),
+        grammar_terminators: None,
+        regex: None,
+        response_format: None,
+        tool_results_preview: None,
+        tool_call_retries: None,
+        tool_input_validation: Default::default(),
+        traffic_class: None,
+        timeout_ms: None,
+        max_tokens_per_second: None,
+        allow_exceed_context: false,
+        adapter: None,
+        state_id: None,
+        raw_mode: false,
+        agentic: false,
+        seed: None,
+        debug: None,
+        return_timings: false,
     };
     let json = serde_json::to_value(&request).unwrap();
     assert_eq!(json["bnf_validation"], "structural");
@@ -1299,15 +1671,45 @@ fn test_bnf_validation_skips_serialization_when_none() {
         max_tokens: 100,
         stream: false,
         stop_sequences: None,
+        stop_tokens: None,
+        stop_bytes: None,
+        banned_phrases: vec![],
         temperature: None,
         top_p: None,
         top_k: None,
+        min_p: None,
+        typical_p: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        repetition_penalty: None,
+        repetition_window: None,
+        dynatemp_range: None,
+        dynatemp_exponent: None,
+        logit_bias: Default::default(),
+        logit_bias_tokens: Default::default(),
         tools: None,
         tool_choice: None,
         thinking: None,
         metadata: None,
         bnf_schema: None,
         bnf_validation: None,
+        grammar_terminators: None,
+        regex: None,
+        response_format: None,
+        tool_results_preview: None,
+        tool_call_retries: None,
+        tool_input_validation: Default::default(),
+        traffic_class: None,
+        timeout_ms: None,
+        max_tokens_per_second: None,
+        allow_exceed_context: false,
+        adapter: None,
+        state_id: None,
+        raw_mode: false,
+        agentic: false,
+        seed: None,
+        debug: None,
+        return_timings: false,
     };
     let json = serde_json::to_value(&request).unwrap();
     assert!(json.get("bnf_validation").is_none());
@@ -1745,15 +2147,45 @@ fn test_has_tools_detection() {
         max_tokens: 100,
         stream: false,
         stop_sequences: None,
+        stop_tokens: None,
+        stop_bytes: None,
+        banned_phrases: vec![],
         temperature: None,
         top_p: None,
         top_k: None,
+        min_p: None,
+        typical_p: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        repetition_penalty: None,
+        repetition_window: None,
+        dynatemp_range: None,
+        dynatemp_exponent: None,
+        logit_bias: Default::default(),
+        logit_bias_tokens: Default::default(),
         tools: None,
         tool_choice: None,
         thinking: None,
         metadata: None,
         bnf_schema: None,
         bnf_validation: None,
+        grammar_terminators: None,
+        regex: None,
+        response_format: None,
+        tool_results_preview: None,
+        tool_call_retries: None,
+        tool_input_validation: Default::default(),
+        traffic_class: None,
+        timeout_ms: None,
+        max_tokens_per_second: None,
+        allow_exceed_context: false,
+        adapter: None,
+        state_id: None,
+        raw_mode: false,
+        agentic: false,
+        seed: None,
+        debug: None,
+        return_timings: false,
     };
 
     let has_tools = request_no_tools
@@ -2146,3 +2578,65 @@ fn test_tool_use_tags_not_parsed() {
         "tool_use should appear in text"
     );
 }
+
+// =============================================================================
+// SSE Event Size Tests
+// =============================================================================
+
+fn delta_payload(delta: &ContentDelta) -> &str {
+    match delta {
+        ContentDelta::Text { text } => text,
+        ContentDelta::Thinking { thinking } => thinking,
+        ContentDelta::Signature { signature } => signature,
+        ContentDelta::InputJson { partial_json } => partial_json,
+    }
+}
+
+/// Split deltas reassemble to the original payload and every event fits the cap.
+#[rstest]
+#[case::text(ContentDelta::Text { text: "Hello, world! ".repeat(200) })]
+#[case::unicode(ContentDelta::Thinking { thinking: "思考中…🤔 \"quoted\"\n".repeat(100) })]
+#[case::json(ContentDelta::InputJson {
+    partial_json: json!({"path": "/tmp/a b", "content": "x\ty\u{1}".repeat(300)}).to_string(),
+})]
+fn test_split_delta_reassembles(#[case] delta: ContentDelta) {
+    let max_event_size = 256;
+    let original = delta_payload(&delta).to_string();
+    let events = split_delta(3, delta, max_event_size);
+
+    assert!(events.len() > 1);
+    let mut reassembled = String::new();
+    for event in &events {
+        assert_eq!(event.index, 3);
+        let json = serde_json::to_string(event).unwrap();
+        assert!(
+            json.len() <= max_event_size,
+            "event of {} bytes",
+            json.len()
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["type"], "content_block_delta");
+        reassembled += delta_payload(&event.delta);
+    }
+    assert_eq!(reassembled, original);
+}
+
+/// Small deltas are emitted unchanged.
+#[test]
+fn test_split_delta_keeps_small_delta() {
+    let delta = ContentDelta::Text {
+        text: "short".into(),
+    };
+    let events = split_delta(0, delta, 1024);
+    assert_eq!(events.len(), 1);
+    assert_eq!(delta_payload(&events[0].delta), "short");
+}
+
+/// A cap below the event overhead still makes progress, one character per event.
+#[test]
+fn test_split_delta_tiny_cap() {
+    let delta = ContentDelta::Text { text: "abc".into() };
+    let events = split_delta(0, delta, 1);
+    let chunks: Vec<_> = events.iter().map(|e| delta_payload(&e.delta)).collect();
+    assert_eq!(chunks, vec!["a", "b", "c"]);
+}
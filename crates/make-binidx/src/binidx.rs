@@ -15,29 +15,45 @@
 //! - Doc_idx: i64[] LE (indices into sizes array, one per doc + sentinel)
 //!
 //! This format is used by RWKV trainers for efficient data loading.
+//!
+//! Optionally a loss mask is written alongside, as a second binidx dataset of
+//! uint8 (`.mask.bin` / `.mask.idx`) with the same documents: 1 for tokens to
+//! train on and 0 for tokens whose loss is masked out.
 
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::Path;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 /// Magic header for Megatron MMapIndexedDataset format.
-const IDX_MAGIC: &[u8; 9] = b"MMIDIDX\x00\x00";
+pub const IDX_MAGIC: &[u8; 9] = b"MMIDIDX\x00\x00";
 
 /// Version of the index format.
-const IDX_VERSION: u64 = 1;
+pub const IDX_VERSION: u64 = 1;
 
 /// Data type code for uint16 tokens (Megatron dtype mapping: 8 = np.uint16).
-const DTYPE_UINT16: u8 = 8;
+pub const DTYPE_UINT16: u8 = 8;
+
+/// Data type code for the uint8 loss mask (Megatron dtype mapping: 1 = np.uint8).
+pub const DTYPE_UINT8: u8 = 1;
+
+/// Loss mask stream, one byte per token.
+struct MaskWriter {
+    bin_writer: BufWriter<File>,
+    pointers: Vec<i64>,
+    current_byte_offset: i64,
+}
 
 /// Writer for binidx format files.
 pub struct BinidxWriter {
     bin_writer: BufWriter<File>,
+    mask: Option<MaskWriter>,
     sizes: Vec<i32>,
     pointers: Vec<i64>,
     current_byte_offset: i64,
     total_tokens: u64,
+    trained_tokens: u64,
 }
 
 impl BinidxWriter {
@@ -52,18 +68,55 @@ impl BinidxWriter {
 
         Ok(Self {
             bin_writer: BufWriter::new(bin_file),
+            mask: None,
             sizes: Vec::new(),
             pointers: Vec::new(),
             current_byte_offset: 0,
             total_tokens: 0,
+            trained_tokens: 0,
         })
     }
 
+    /// Also write a loss mask to `{output_path}.mask.bin`.
+    ///
+    /// Its `.mask.idx` file is written when `finish()` is called.
+    pub fn with_loss_mask(mut self, output_path: &Path) -> Result<Self> {
+        let mask_path = output_path.with_extension("mask.bin");
+        let mask_file = File::create(&mask_path)
+            .with_context(|| format!("Failed to create {:?}", mask_path))?;
+
+        self.mask = Some(MaskWriter {
+            bin_writer: BufWriter::new(mask_file),
+            pointers: Vec::new(),
+            current_byte_offset: 0,
+        });
+        Ok(self)
+    }
+
     /// Add a document's tokens to the dataset.
     ///
     /// Tokens are written as little-endian u16. A token 0 (EOS) is automatically
-    /// appended after the document.
+    /// appended after the document. With a loss mask, every token is trained on.
     pub fn add_document(&mut self, tokens: &[u32]) -> Result<()> {
+        self.write_document(tokens, None)
+    }
+
+    /// Add a document's tokens to the dataset, with the tokens to train on.
+    ///
+    /// `mask` has one entry per token; the appended EOS is trained on if the
+    /// last token is. Without a loss mask, `mask` only counts towards the stats.
+    pub fn add_masked_document(&mut self, tokens: &[u32], mask: &[bool]) -> Result<()> {
+        if mask.len() != tokens.len() {
+            bail!(
+                "Loss mask has {} entries for {} tokens",
+                mask.len(),
+                tokens.len()
+            );
+        }
+        self.write_document(tokens, Some(mask))
+    }
+
+    fn write_document(&mut self, tokens: &[u32], mask: Option<&[bool]>) -> Result<()> {
         // Record byte offset before writing this document
         self.pointers.push(self.current_byte_offset);
 
@@ -84,6 +137,23 @@ impl BinidxWriter {
         // Update byte offset (each token is 2 bytes for u16)
         self.current_byte_offset += doc_size as i64 * 2;
 
+        // Write the mask (one byte per token, EOS included)
+        let eos_trained = mask.map_or(true, |mask| mask.last().copied().unwrap_or(true));
+        let trained = mask.map_or(tokens.len(), |mask| mask.iter().filter(|&&m| m).count());
+        self.trained_tokens += (trained + eos_trained as usize) as u64;
+        if let Some(writer) = &mut self.mask {
+            writer.pointers.push(writer.current_byte_offset);
+            match mask {
+                Some(mask) => {
+                    let bytes: Vec<u8> = mask.iter().map(|&m| m as u8).collect();
+                    writer.bin_writer.write_all(&bytes)?;
+                }
+                None => writer.bin_writer.write_all(&vec![1u8; tokens.len()])?,
+            }
+            writer.bin_writer.write_all(&[eos_trained as u8])?;
+            writer.current_byte_offset += doc_size as i64;
+        }
+
         Ok(())
     }
 
@@ -96,52 +166,71 @@ impl BinidxWriter {
 
         // Write the idx file
         let idx_path = output_path.with_extension("idx");
-        let mut idx_file =
-            File::create(&idx_path).with_context(|| format!("Failed to create {:?}", idx_path))?;
-
-        // Write header: magic (9 bytes) + version (8 bytes) + dtype (1 byte)
-        idx_file.write_all(IDX_MAGIC)?;
-        idx_file.write_all(&IDX_VERSION.to_le_bytes())?;
-        idx_file.write_all(&[DTYPE_UINT16])?;
-
-        // Write element count (number of sequences)
-        let num_seqs = self.sizes.len() as u64;
-        idx_file.write_all(&num_seqs.to_le_bytes())?;
-
-        // Write doc count (num_seqs + 1 for the sentinel at the end)
-        // In Megatron format: doc_idx has one entry per document plus a sentinel
-        // For our use case, each sequence IS a document, so doc_count = num_seqs + 1
-        let num_docs = num_seqs + 1;
-        idx_file.write_all(&num_docs.to_le_bytes())?;
-
-        // Write sizes array (i32 for each sequence)
-        for size in &self.sizes {
-            idx_file.write_all(&size.to_le_bytes())?;
-        }
-
-        // Write pointers array (i64 byte offsets into .bin file)
-        for ptr in &self.pointers {
-            idx_file.write_all(&ptr.to_le_bytes())?;
-        }
+        write_idx(&idx_path, DTYPE_UINT16, &self.sizes, &self.pointers)?;
 
-        // Write doc_idx array (i64 indices into sizes array)
-        // Each document maps to its sequence index, plus a sentinel at the end
-        for i in 0..=self.sizes.len() {
-            idx_file.write_all(&(i as i64).to_le_bytes())?;
+        // Write the mask idx file, with the same sizes and byte offsets of u8
+        if let Some(mut mask) = self.mask.take() {
+            mask.bin_writer.flush()?;
+            let idx_path = output_path.with_extension("mask.idx");
+            write_idx(&idx_path, DTYPE_UINT8, &self.sizes, &mask.pointers)?;
         }
 
         Ok(BinidxStats {
             num_documents: self.sizes.len(),
             total_tokens: self.total_tokens,
+            trained_tokens: self.trained_tokens,
         })
     }
 }
 
+/// Write an idx file for sequences of `sizes` elements at byte offsets `pointers`.
+fn write_idx(idx_path: &Path, dtype: u8, sizes: &[i32], pointers: &[i64]) -> Result<()> {
+    let mut idx_file = BufWriter::new(
+        File::create(idx_path).with_context(|| format!("Failed to create {:?}", idx_path))?,
+    );
+
+    // Write header: magic (9 bytes) + version (8 bytes) + dtype (1 byte)
+    idx_file.write_all(IDX_MAGIC)?;
+    idx_file.write_all(&IDX_VERSION.to_le_bytes())?;
+    idx_file.write_all(&[dtype])?;
+
+    // Write element count (number of sequences)
+    let num_seqs = sizes.len() as u64;
+    idx_file.write_all(&num_seqs.to_le_bytes())?;
+
+    // Write doc count (num_seqs + 1 for the sentinel at the end)
+    // In Megatron format: doc_idx has one entry per document plus a sentinel
+    // For our use case, each sequence IS a document, so doc_count = num_seqs + 1
+    let num_docs = num_seqs + 1;
+    idx_file.write_all(&num_docs.to_le_bytes())?;
+
+    // Write sizes array (i32 for each sequence)
+    for size in sizes {
+        idx_file.write_all(&size.to_le_bytes())?;
+    }
+
+    // Write pointers array (i64 byte offsets into .bin file)
+    for ptr in pointers {
+        idx_file.write_all(&ptr.to_le_bytes())?;
+    }
+
+    // Write doc_idx array (i64 indices into sizes array)
+    // Each document maps to its sequence index, plus a sentinel at the end
+    for i in 0..=sizes.len() {
+        idx_file.write_all(&(i as i64).to_le_bytes())?;
+    }
+
+    idx_file.flush()?;
+    Ok(())
+}
+
 /// Statistics about a written binidx dataset.
 #[derive(Debug, Clone)]
 pub struct BinidxStats {
     pub num_documents: usize,
     pub total_tokens: u64,
+    /// Tokens not masked out of the loss (all of them without a mask).
+    pub trained_tokens: u64,
 }
 
 #[cfg(test)]
@@ -258,6 +347,39 @@ mod tests {
         let bin_data = std::fs::read(output_path.with_extension("bin"))?;
         assert_eq!(bin_data.len(), 320); // 160 tokens * 2 bytes
 
+        Ok(())
+    }
+    #[test]
+    fn test_write_loss_mask() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let output_path = temp_dir.path().join("test");
+
+        let mut writer = BinidxWriter::new(&output_path)?.with_loss_mask(&output_path)?;
+        writer.add_masked_document(&[1, 2, 3], &[false, true, true])?; // [0, 1, 1, 1]
+        writer.add_masked_document(&[10, 20], &[true, false])?; // [1, 0, 0]
+        writer.add_document(&[5])?; // [1, 1]
+        assert!(writer.add_masked_document(&[1, 2], &[true]).is_err());
+
+        let stats = writer.finish(&output_path)?;
+        assert_eq!(stats.total_tokens, 9);
+        assert_eq!(stats.trained_tokens, 6);
+
+        let mask = std::fs::read(output_path.with_extension("mask.bin"))?;
+        assert_eq!(mask, [0, 1, 1, 1, 1, 0, 0, 1, 1]);
+
+        // Same layout as the token idx, with uint8 dtype and byte offsets of u8
+        let idx = std::fs::read(output_path.with_extension("idx"))?;
+        let mask_idx = std::fs::read(output_path.with_extension("mask.idx"))?;
+        assert_eq!(mask_idx[17], DTYPE_UINT8);
+        assert_eq!(mask_idx.len(), idx.len());
+        // Sizes match: [4, 3, 2]
+        assert_eq!(&mask_idx[34..46], &idx[34..46]);
+        // Pointers: [0, 4, 7]
+        let ptr1 = i64::from_le_bytes(mask_idx[54..62].try_into().unwrap());
+        let ptr2 = i64::from_le_bytes(mask_idx[62..70].try_into().unwrap());
+        assert_eq!(ptr1, 4);
+        assert_eq!(ptr2, 7);
+
         Ok(())
     }
 }
@@ -0,0 +1,173 @@
+//! Duplicate filtering of training prompts.
+//!
+//! - `exact`: a 64-bit hash of every prompt is kept, and prompts whose hash was
+//!   seen before are dropped.
+//! - `minhash`: every prompt gets a MinHash signature over its word 3-grams.
+//!   Signatures are bucketed by bands (locality-sensitive hashing), and a prompt
+//!   is dropped if a prompt sharing a bucket has an estimated Jaccard similarity
+//!   of at least the threshold. This catches agent loops repeated with small
+//!   variations, such as different ids or timestamps.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use clap::ValueEnum;
+
+/// Number of hash functions in a MinHash signature.
+const NUM_HASHES: usize = 128;
+
+/// Rows per LSH band; `NUM_HASHES / BAND_ROWS` bands.
+const BAND_ROWS: usize = 8;
+
+/// Words per shingle.
+const SHINGLE_WORDS: usize = 3;
+
+/// How duplicate prompts are detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DedupeMode {
+    /// Drop prompts identical to an earlier one.
+    Exact,
+    /// Drop prompts nearly identical to an earlier one.
+    Minhash,
+}
+
+impl DedupeMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DedupeMode::Exact => "exact",
+            DedupeMode::Minhash => "minhash",
+        }
+    }
+}
+
+/// Filter of prompts seen before.
+pub struct Deduper {
+    mode: DedupeMode,
+    threshold: f64,
+    seen: HashSet<u64>,
+    coefficients: Vec<(u64, u64)>,
+    signatures: Vec<Vec<u64>>,
+    buckets: HashMap<(usize, u64), Vec<usize>>,
+}
+
+fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// SplitMix64, for deterministic hash coefficients.
+fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl Deduper {
+    /// Create a filter; `threshold` is the Jaccard similarity from which
+    /// `minhash` considers prompts duplicates.
+    pub fn new(mode: DedupeMode, threshold: f64) -> Self {
+        let mut state = 0;
+        let coefficients = (0..NUM_HASHES)
+            .map(|_| (split_mix(&mut state) | 1, split_mix(&mut state)))
+            .collect();
+        Self {
+            mode,
+            threshold,
+            seen: HashSet::new(),
+            coefficients,
+            signatures: Vec::new(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Whether `text` duplicates an earlier prompt. If not, it is remembered.
+    pub fn is_duplicate(&mut self, text: &str) -> bool {
+        match self.mode {
+            DedupeMode::Exact => !self.seen.insert(hash_of(text)),
+            DedupeMode::Minhash => self.is_near_duplicate(text),
+        }
+    }
+
+    fn signature(&self, text: &str) -> Vec<u64> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let shingles: Vec<u64> = match words.len() < SHINGLE_WORDS {
+            true => vec![hash_of(&words)],
+            false => words.windows(SHINGLE_WORDS).map(hash_of).collect(),
+        };
+        self.coefficients
+            .iter()
+            .map(|&(a, b)| {
+                shingles
+                    .iter()
+                    .map(|&shingle| {
+                        let h = shingle.wrapping_mul(a).wrapping_add(b);
+                        h ^ (h >> 29)
+                    })
+                    .min()
+                    .unwrap_or(u64::MAX)
+            })
+            .collect()
+    }
+
+    fn is_near_duplicate(&mut self, text: &str) -> bool {
+        let signature = self.signature(text);
+        let bands: Vec<(usize, u64)> = signature
+            .chunks(BAND_ROWS)
+            .enumerate()
+            .map(|(band, rows)| (band, hash_of(rows)))
+            .collect();
+
+        let mut candidates: Vec<usize> = bands
+            .iter()
+            .filter_map(|band| self.buckets.get(band))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        let duplicate = candidates.into_iter().any(|index| {
+            let other = &self.signatures[index];
+            let equal = signature.iter().zip(other).filter(|(a, b)| a == b).count();
+            equal as f64 / NUM_HASHES as f64 >= self.threshold
+        });
+        if duplicate {
+            return true;
+        }
+
+        let index = self.signatures.len();
+        self.signatures.push(signature);
+        for band in bands {
+            self.buckets.entry(band).or_default().push(index);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_duplicates() {
+        let mut deduper = Deduper::new(DedupeMode::Exact, 0.0);
+        assert!(!deduper.is_duplicate("User: hi\nAssistant: hello"));
+        assert!(deduper.is_duplicate("User: hi\nAssistant: hello"));
+        assert!(!deduper.is_duplicate("User: hi\nAssistant: hello!"));
+    }
+
+    #[test]
+    fn test_near_duplicates() {
+        let base = "call the search tool with the query weather in paris and report \
+                    the temperature in celsius to the user then stop";
+        let mut deduper = Deduper::new(DedupeMode::Minhash, 0.8);
+        assert!(!deduper.is_duplicate(&format!("{base} request 1")));
+        assert!(deduper.is_duplicate(&format!("{base} request 2")));
+        assert!(!deduper.is_duplicate(
+            "summarize the quarterly report for the finance team in three short bullet points"
+        ));
+    }
+}
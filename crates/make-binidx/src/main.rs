@@ -6,20 +6,29 @@
 //! Supports streaming from files or stdin for processing large datasets.
 
 mod binidx;
+mod dedupe;
+mod reader;
+mod split;
 
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
 use std::path::PathBuf;
 
 use ai00_server::api::messages::prompt::build_training_prompt;
-use ai00_server::api::messages::MessagesRequest;
-use ai00_server::config::Config;
-use anyhow::{Context, Result};
+use ai00_server::api::messages::{MessageRole, MessagesRequest};
+use ai00_server::config::{Config, PromptsConfig};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
 use indicatif::{ProgressBar, ProgressStyle};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use web_rwkv::tokenizer::Tokenizer;
 
 use binidx::BinidxWriter;
+use dedupe::{DedupeMode, Deduper};
+use reader::BinidxReader;
+use split::{split_path, Document, SplitWriter};
 
 /// Convert JSONL message requests to RWKV binidx format.
 ///
@@ -36,6 +45,9 @@ use binidx::BinidxWriter;
 ///
 ///   # Text-only mode for debugging
 ///   make-binidx -i data.jsonl -p config.toml --text-only
+///
+///   # Inspect written files (see `make-binidx inspect --help`)
+///   make-binidx inspect output -t tokenizer.json
 #[derive(Parser, Debug)]
 #[command(name = "make-binidx")]
 #[command(about = "Convert JSONL message requests to RWKV binidx format")]
@@ -60,6 +72,10 @@ struct Args {
     #[arg(short, long)]
     prompts_config: PathBuf,
 
+    /// Prompt profile of the config to format with (default: the first)
+    #[arg(long)]
+    prompt_profile: Option<String>,
+
     /// Context length for chunking (default: 4096)
     #[arg(long, default_value = "4096")]
     ctx_len: usize,
@@ -75,6 +91,65 @@ struct Args {
     /// Separator between prompts in text-only mode (default: "---")
     #[arg(long, default_value = "---")]
     separator: String,
+
+    /// Write a loss mask alongside (.mask.bin and .mask.idx) that trains
+    /// only on assistant messages
+    #[arg(long)]
+    loss_mask: bool,
+
+    /// Skip prompts that duplicate an earlier one: exactly, or nearly
+    /// (MinHash over word 3-grams)
+    #[arg(long, value_enum)]
+    dedupe: Option<DedupeMode>,
+
+    /// Estimated Jaccard similarity from which --dedupe minhash drops a
+    /// prompt (default: 0.9)
+    #[arg(long, default_value = "0.9")]
+    dedupe_threshold: f64,
+
+    /// Fraction of documents for training (e.g. 0.95); the rest goes to a
+    /// validation set. Writes <output>_train and <output>_val
+    #[arg(long)]
+    split: Option<f64>,
+
+    /// Shuffle documents, through a buffer of --shuffle-buffer documents
+    #[arg(long)]
+    shuffle: bool,
+
+    /// Documents held in memory for --shuffle (default: 10000)
+    #[arg(long, default_value = "10000")]
+    shuffle_buffer: usize,
+
+    /// Seed for --split and --shuffle (default: random)
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+/// Inspect binidx files: counts, length histogram, sampled documents and index
+/// integrity. Exits with an error if the index is inconsistent.
+///
+/// Examples:
+///   make-binidx inspect output
+///   make-binidx inspect output -t tokenizer.json --samples 5 --seed 1
+#[derive(Parser, Debug)]
+#[command(name = "make-binidx inspect")]
+struct InspectArgs {
+    /// Basename of the dataset (reads .bin and .idx, and .mask.bin and
+    /// .mask.idx if present)
+    path: PathBuf,
+
+    /// Path to tokenizer JSON file, to decode sampled documents
+    /// (prints token ids without it)
+    #[arg(short, long)]
+    tokenizer: Option<PathBuf>,
+
+    /// Number of random documents to print (default: 3)
+    #[arg(long, default_value = "3")]
+    samples: usize,
+
+    /// Seed for sampling documents (default: random)
+    #[arg(long)]
+    seed: Option<u64>,
 }
 
 /// Input source for JSONL data.
@@ -126,6 +201,83 @@ fn parse_line(line: &str, line_num: usize) -> Result<Option<MessagesRequest>> {
     Ok(Some(request))
 }
 
+/// Byte ranges of `prompt` written by the assistant.
+///
+/// Messages are located in order by their text, which the chat format must keep
+/// verbatim, searching from the marker that opens their turn so that text repeated
+/// in the system prompt or an earlier turn is not mistaken for them. An assistant
+/// span also covers a default stop sequence that closes it, so that the model
+/// learns to end its turn.
+fn assistant_spans(
+    req: &MessagesRequest,
+    prompt: &str,
+    prompts: &PromptsConfig,
+) -> Result<Vec<Range<usize>>> {
+    let mut spans = Vec::new();
+    let mut cursor = 0;
+    let mut turn = None;
+
+    for (index, message) in req.messages.iter().enumerate() {
+        // tool results sit in the open turn; a message of another role opens a turn
+        if !(message.role == MessageRole::User && message.content.is_tool_result_only())
+            && turn.replace(message.role) != Some(message.role)
+        {
+            let role = match message.role {
+                MessageRole::User => &prompts.role_user,
+                MessageRole::Assistant => &prompts.role_assistant,
+            };
+            // templates need not write the markers
+            let marker = format!("<ai00:{role}>\n");
+            if let Some(offset) = prompt[cursor..].find(&marker) {
+                cursor += offset + marker.len();
+            }
+        }
+
+        let content = message.content.to_text();
+        if content.is_empty() {
+            continue;
+        }
+        let Some(offset) = prompt[cursor..].find(&content) else {
+            bail!(
+                "Message {} is not verbatim in the prompt, so it cannot be masked",
+                index
+            );
+        };
+        let start = cursor + offset;
+        let mut end = start + content.len();
+
+        if message.role == MessageRole::Assistant {
+            let rest = &prompt[end..];
+            let closing = rest.strip_prefix('\n').unwrap_or(rest);
+            if let Some(stop) = prompts
+                .default_stop_sequences
+                .iter()
+                .find(|stop| !stop.is_empty() && closing.starts_with(stop.as_str()))
+            {
+                end += rest.len() - closing.len() + stop.len();
+            }
+            spans.push(start..end);
+        }
+        cursor = end;
+    }
+
+    Ok(spans)
+}
+
+/// Whether each token starts within one of `spans`, by the byte offsets of the tokens.
+fn token_mask(tokenizer: &Tokenizer, tokens: &[u32], spans: &[Range<usize>]) -> Result<Vec<bool>> {
+    let mut start = 0;
+    let mut mask = Vec::with_capacity(tokens.len());
+    for &token in tokens {
+        mask.push(spans.iter().any(|span| span.contains(&start)));
+        start += tokenizer
+            .decode(&[token])
+            .with_context(|| "Failed to decode token")?
+            .len();
+    }
+    Ok(mask)
+}
+
 /// Load config and extract PromptsConfig.
 fn load_prompts_config(path: &PathBuf) -> Result<Config> {
     let contents =
@@ -145,10 +297,20 @@ fn load_tokenizer(path: &PathBuf) -> Result<Tokenizer> {
     Tokenizer::new(&contents).with_context(|| format!("Failed to parse tokenizer {:?}", path))
 }
 
+/// Create the duplicate filter of `--dedupe`, if any.
+fn new_deduper(args: &Args) -> Option<Deduper> {
+    args.dedupe
+        .map(|mode| Deduper::new(mode, args.dedupe_threshold))
+}
+
 /// Run text-only mode: print formatted prompts to stdout (streaming).
 fn run_text_only(args: &Args) -> Result<()> {
     eprintln!("Loading config from {:?}...", args.prompts_config);
     let config = load_prompts_config(&args.prompts_config)?;
+    let prompts = config
+        .prompts
+        .get(args.prompt_profile.as_deref())
+        .context("Unknown prompt profile")?;
 
     let source = get_input_source(args)?;
     match &source {
@@ -158,6 +320,8 @@ fn run_text_only(args: &Args) -> Result<()> {
 
     let reader = create_reader(&source)?;
     let mut count = 0usize;
+    let mut deduper = new_deduper(args);
+    let mut duplicate_count = 0u64;
 
     for (line_num, line) in reader.lines().enumerate() {
         let line = line.with_context(|| format!("Failed to read line {}", line_num + 1))?;
@@ -172,8 +336,16 @@ fn run_text_only(args: &Args) -> Result<()> {
             &req.messages,
             req.tools.as_deref(),
             req.thinking.as_ref(),
-            &config.prompts,
-        );
+            prompts,
+        )
+        .with_context(|| format!("Failed to build prompt for line {}", line_num + 1))?;
+
+        if let Some(deduper) = &mut deduper {
+            if deduper.is_duplicate(&prompt) {
+                duplicate_count += 1;
+                continue;
+            }
+        }
 
         // Print separator between prompts (not before first)
         if count > 0 {
@@ -185,6 +357,13 @@ fn run_text_only(args: &Args) -> Result<()> {
     }
 
     eprintln!("\nProcessed {} prompts", count);
+    if let Some(mode) = args.dedupe {
+        eprintln!(
+            "Dropped {} duplicates (--dedupe {})",
+            duplicate_count,
+            mode.as_str()
+        );
+    }
     Ok(())
 }
 
@@ -198,6 +377,10 @@ fn run_binidx(args: &Args) -> Result<()> {
 
     eprintln!("Loading config from {:?}...", args.prompts_config);
     let config = load_prompts_config(&args.prompts_config)?;
+    let prompts = config
+        .prompts
+        .get(args.prompt_profile.as_deref())
+        .context("Unknown prompt profile")?;
 
     let source = get_input_source(args)?;
     match &source {
@@ -205,8 +388,28 @@ fn run_binidx(args: &Args) -> Result<()> {
         InputSource::Stdin => eprintln!("Streaming from stdin..."),
     }
 
-    eprintln!("Creating binidx files at {:?}...", output_path);
-    let mut writer = BinidxWriter::new(output_path)?;
+    let (train_path, val_path) = match args.split {
+        Some(_) => (
+            split_path(output_path, "train"),
+            split_path(output_path, "val"),
+        ),
+        None => (output_path.clone(), output_path.clone()),
+    };
+    let create_writer = |path: &PathBuf| -> Result<BinidxWriter> {
+        eprintln!("Creating binidx files at {:?}...", path);
+        let writer = BinidxWriter::new(path)?;
+        match args.loss_mask {
+            true => writer.with_loss_mask(path),
+            false => Ok(writer),
+        }
+    };
+    let mut writer = SplitWriter::new(create_writer(&train_path)?, args.seed);
+    if let Some(split) = args.split {
+        writer = writer.with_split(create_writer(&val_path)?, split);
+    }
+    if args.shuffle {
+        writer = writer.with_shuffle(args.shuffle_buffer);
+    }
 
     // Progress spinner (unknown total when streaming)
     let pb = ProgressBar::new_spinner();
@@ -220,6 +423,9 @@ fn run_binidx(args: &Args) -> Result<()> {
     let mut total_prompt_tokens = 0u64;
     let mut doc_count = 0u64;
     let mut skipped_count = 0u64;
+    let mut unmaskable_count = 0u64;
+    let mut deduper = new_deduper(args);
+    let mut duplicate_count = 0u64;
 
     for (line_num, line) in reader.lines().enumerate() {
         let line = line.with_context(|| format!("Failed to read line {}", line_num + 1))?;
@@ -234,17 +440,45 @@ fn run_binidx(args: &Args) -> Result<()> {
             &req.messages,
             req.tools.as_deref(),
             req.thinking.as_ref(),
-            &config.prompts,
-        );
+            prompts,
+        )
+        .with_context(|| format!("Failed to build prompt for line {}", line_num + 1))?;
+
+        if let Some(deduper) = &mut deduper {
+            if deduper.is_duplicate(&prompt) {
+                duplicate_count += 1;
+                continue;
+            }
+        }
 
         // Tokenize using same approach as server:
         // Token 0 prefix + encoded prompt
+        let encoded = tokenizer
+            .encode(prompt.as_bytes())
+            .with_context(|| "Failed to tokenize prompt")?;
+
+        // Train only on assistant tokens (never on the token 0 prefix)
+        let mask = match args.loss_mask {
+            true => {
+                let spans = match assistant_spans(&req, &prompt, prompts) {
+                    Ok(spans) => spans,
+                    Err(err) => {
+                        pb.suspend(|| {
+                            eprintln!("Warning: skipping line {}: {:#}", line_num + 1, err)
+                        });
+                        unmaskable_count += 1;
+                        continue;
+                    }
+                };
+                let mut mask = vec![false];
+                mask.extend(token_mask(&tokenizer, &encoded, &spans)?);
+                Some(mask)
+            }
+            false => None,
+        };
+
         let mut tokens = vec![0u32];
-        tokens.extend(
-            tokenizer
-                .encode(prompt.as_bytes())
-                .with_context(|| "Failed to tokenize prompt")?,
-        );
+        tokens.extend(encoded);
 
         // Skip if exceeds max_tokens filter
         if let Some(max) = args.max_tokens {
@@ -256,8 +490,8 @@ fn run_binidx(args: &Args) -> Result<()> {
 
         total_prompt_tokens += tokens.len() as u64;
 
-        // Write to binidx immediately (adds EOS token)
-        writer.add_document(&tokens)?;
+        // Write to binidx, possibly after shuffling (adds EOS token)
+        writer.add(Document { tokens, mask })?;
 
         doc_count += 1;
         pb.set_position(doc_count);
@@ -266,9 +500,12 @@ fn run_binidx(args: &Args) -> Result<()> {
     pb.finish_with_message("done");
 
     // Finish and get stats
-    let stats = writer.finish(output_path)?;
+    let (stats, val_stats) = writer.finish(&train_path, &val_path)?;
 
-    eprintln!("\n=== Statistics ===");
+    match args.split {
+        Some(split) => eprintln!("\n=== Statistics (train, --split {}) ===", split),
+        None => eprintln!("\n=== Statistics ==="),
+    }
     eprintln!("Documents:    {}", stats.num_documents);
     if skipped_count > 0 {
         eprintln!(
@@ -277,12 +514,44 @@ fn run_binidx(args: &Args) -> Result<()> {
             args.max_tokens.unwrap()
         );
     }
+    if unmaskable_count > 0 {
+        eprintln!(
+            "Skipped:      {} (messages not verbatim in the prompt, cannot be masked)",
+            unmaskable_count
+        );
+    }
+    if let Some(mode) = args.dedupe {
+        eprintln!(
+            "Duplicates:   {} dropped (--dedupe {})",
+            duplicate_count,
+            mode.as_str()
+        );
+    }
     eprintln!(
         "Total tokens: {} (including EOS markers)",
         stats.total_tokens
     );
     eprintln!("Prompt tokens: {} (before EOS)", total_prompt_tokens);
-    eprintln!("Output files: {:?}.bin, {:?}.idx", output_path, output_path);
+    eprintln!("Output files: {:?}.bin, {:?}.idx", train_path, train_path);
+    if args.loss_mask {
+        eprintln!(
+            "Trained tokens: {} (assistant spans, EOS included)",
+            stats.trained_tokens
+        );
+        eprintln!(
+            "Mask files:   {:?}.mask.bin, {:?}.mask.idx",
+            train_path, train_path
+        );
+    }
+    if let Some(val_stats) = &val_stats {
+        eprintln!("\n=== Validation ===");
+        eprintln!("Documents:    {}", val_stats.num_documents);
+        eprintln!(
+            "Total tokens: {} (including EOS markers)",
+            val_stats.total_tokens
+        );
+        eprintln!("Output files: {:?}.bin, {:?}.idx", val_path, val_path);
+    }
 
     // Print magic_prime calculation hint for RWKV trainer
     let data_len = stats.total_tokens;
@@ -297,7 +566,132 @@ fn run_binidx(args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Power-of-two histogram of `sizes`: (upper bound, count) per bucket.
+fn length_histogram(sizes: &[i32]) -> Vec<(u64, usize)> {
+    let mut buckets: Vec<(u64, usize)> = Vec::new();
+    for &size in sizes {
+        let bound = (size.max(1) as u64).next_power_of_two();
+        match buckets.iter_mut().find(|(b, _)| *b == bound) {
+            Some((_, count)) => *count += 1,
+            None => buckets.push((bound, 1)),
+        }
+    }
+    buckets.sort_unstable();
+    buckets
+}
+
+/// Run inspect mode: print statistics and samples of a binidx dataset.
+fn run_inspect(args: &InspectArgs) -> Result<()> {
+    let mut reader = BinidxReader::open(&args.path)?;
+    let mut problems = reader.verify();
+
+    let mut mask = match args.path.with_extension("mask.idx").exists() {
+        true => Some(BinidxReader::open_mask(&args.path)?),
+        false => None,
+    };
+    if let Some(mask) = &mask {
+        problems.extend(mask.verify().into_iter().map(|p| format!("Mask: {p}")));
+        if mask.sizes() != reader.sizes() {
+            problems.push("Mask: document sizes differ from the tokens".into());
+        }
+    }
+
+    let sizes = reader.sizes().to_vec();
+    let mut sorted = sizes.clone();
+    sorted.sort_unstable();
+    let total: u64 = sizes.iter().map(|&size| size.max(0) as u64).sum();
+
+    println!("=== {:?} ===", args.path);
+    println!("Documents:    {}", sizes.len());
+    println!("Total tokens: {} (including EOS markers)", total);
+    if let (Some(min), Some(max)) = (sorted.first(), sorted.last()) {
+        let percentile = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+        println!(
+            "Length:       min {}, median {}, p95 {}, max {}, mean {:.1}",
+            min,
+            percentile(50),
+            percentile(95),
+            max,
+            total as f64 / sizes.len() as f64
+        );
+
+        println!("\nLength histogram:");
+        let histogram = length_histogram(&sizes);
+        let widest = histogram.iter().map(|(_, count)| *count).max().unwrap_or(1);
+        for (bound, count) in histogram {
+            let bar = "#".repeat((count * 40).div_ceil(widest));
+            println!("  <= {:>7} {:>9}  {}", bound, count, bar);
+        }
+    }
+
+    // Count trained tokens only if the mask can be read entirely
+    if let Some(mask) = mask.as_mut().filter(|_| problems.is_empty()) {
+        let mut trained = 0u64;
+        for index in 0..mask.sizes().len() {
+            trained += mask.document(index)?.iter().filter(|&&m| m != 0).count() as u64;
+        }
+        println!(
+            "\nTrained tokens: {} of {} ({:.1}%, loss mask)",
+            trained,
+            total,
+            100.0 * trained as f64 / total.max(1) as f64
+        );
+    }
+
+    let tokenizer = match &args.tokenizer {
+        Some(path) => Some(load_tokenizer(path)?),
+        None => None,
+    };
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let amount = args.samples.min(sizes.len());
+    let mut samples = rand::seq::index::sample(&mut rng, sizes.len(), amount).into_vec();
+    samples.sort_unstable();
+    for index in samples {
+        let tokens = match reader.document(index) {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                println!("\n--- Document {} (unreadable: {:#}) ---", index, err);
+                continue;
+            }
+        };
+        println!("\n--- Document {} ({} tokens) ---", index, tokens.len());
+        if tokens.last() != Some(&0) {
+            println!("(does not end with EOS)");
+        }
+        match &tokenizer {
+            Some(tokenizer) => {
+                let text = tokenizer
+                    .decode(&tokens)
+                    .with_context(|| format!("Failed to decode document {}", index))?;
+                println!("{}", String::from_utf8_lossy(&text));
+            }
+            None => println!("{:?}", tokens),
+        }
+    }
+
+    if !problems.is_empty() {
+        println!("\n=== Integrity problems ===");
+        for problem in &problems {
+            println!("  {}", problem);
+        }
+        bail!("Index integrity check failed ({} problems)", problems.len());
+    }
+    println!("\nIndex integrity: OK");
+    Ok(())
+}
+
 fn main() -> Result<()> {
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "inspect")
+    {
+        let args = InspectArgs::parse_from(std::env::args_os().skip(1));
+        return run_inspect(&args);
+    }
+
     let args = Args::parse();
 
     // Validate arguments
@@ -310,6 +704,19 @@ fn main() -> Result<()> {
         }
     }
 
+    if !(0.0..=1.0).contains(&args.dedupe_threshold) {
+        anyhow::bail!("--dedupe-threshold must be between 0 and 1");
+    }
+    if args
+        .split
+        .is_some_and(|split| !(split > 0.0 && split < 1.0))
+    {
+        anyhow::bail!("--split must be between 0 and 1 (exclusive)");
+    }
+    if args.text_only && (args.split.is_some() || args.shuffle) {
+        anyhow::bail!("--split and --shuffle require binidx output");
+    }
+
     // Note: --input is validated in get_input_source() which checks for
     // piped stdin when no input file is provided.
 
@@ -319,3 +726,33 @@ fn main() -> Result<()> {
         run_binidx(&args)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assistant_spans_anchored() -> Result<()> {
+        let config = Config::default();
+        let prompts = config.prompts.default_profile();
+        let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "rwkv",
+            "max_tokens": 16,
+            "system": "Answer every greeting with hello",
+            "messages": [
+                {"role": "user", "content": "hello"},
+                {"role": "assistant", "content": "hello"},
+            ],
+        }))?;
+        let prompt =
+            build_training_prompt(req.system.as_deref(), &req.messages, None, None, prompts)?;
+
+        let spans = assistant_spans(&req, &prompt, prompts)?;
+        assert_eq!(spans.len(), 1);
+        let marker = format!("<ai00:{}>\n", prompts.role_assistant);
+        let start = prompt.find(&marker).unwrap() + marker.len();
+        assert_eq!(spans[0].start, start);
+        assert!(prompt[spans[0].clone()].starts_with("hello"));
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+//! binidx file format reader, for inspecting written datasets.
+//!
+//! The `.idx` file is read into memory; documents are read from the `.bin`
+//! file on demand. Reads the uint16 tokens and the uint8 loss masks that
+//! [`BinidxWriter`](crate::binidx::BinidxWriter) writes.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::binidx::{DTYPE_UINT16, DTYPE_UINT8, IDX_MAGIC, IDX_VERSION};
+
+/// Reader of a binidx dataset.
+pub struct BinidxReader {
+    bin_file: File,
+    bin_len: u64,
+    dtype: u8,
+    sizes: Vec<i32>,
+    pointers: Vec<i64>,
+    doc_idx: Vec<i64>,
+}
+
+/// Cursor over the bytes of an idx file.
+struct IdxCursor<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> IdxCursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let Some(bytes) = self.data.get(self.offset..self.offset + len) else {
+            bail!("Index truncated at byte {}", self.offset);
+        };
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn i32s(&mut self, count: usize) -> Result<Vec<i32>> {
+        let bytes = self.take(count.checked_mul(4).context("Index too large")?)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+
+    fn i64s(&mut self, count: usize) -> Result<Vec<i64>> {
+        let bytes = self.take(count.checked_mul(8).context("Index too large")?)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|b| i64::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+}
+
+impl BinidxReader {
+    /// Open `{path}.bin` and `{path}.idx`.
+    ///
+    /// Fails if the index header is not a supported Megatron index; use
+    /// [`verify`](Self::verify) to check the rest of the index.
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_files(&path.with_extension("bin"), &path.with_extension("idx"))
+    }
+
+    /// Open the loss mask of `path`, `{path}.mask.bin` and `{path}.mask.idx`.
+    pub fn open_mask(path: &Path) -> Result<Self> {
+        Self::open_files(
+            &path.with_extension("mask.bin"),
+            &path.with_extension("mask.idx"),
+        )
+    }
+
+    fn open_files(bin_path: &Path, idx_path: &Path) -> Result<Self> {
+        let data =
+            std::fs::read(idx_path).with_context(|| format!("Failed to read {:?}", idx_path))?;
+        let mut cursor = IdxCursor {
+            data: &data,
+            offset: 0,
+        };
+
+        if cursor.take(IDX_MAGIC.len())? != IDX_MAGIC {
+            bail!("{:?} is not a binidx index (bad magic)", idx_path);
+        }
+        let version = cursor.u64()?;
+        if version != IDX_VERSION {
+            bail!("{:?} has unsupported version {}", idx_path, version);
+        }
+        let dtype = cursor.take(1)?[0];
+        if dtype != DTYPE_UINT16 && dtype != DTYPE_UINT8 {
+            bail!("{:?} has unsupported dtype {}", idx_path, dtype);
+        }
+        let num_seqs = cursor.u64()? as usize;
+        let num_docs = cursor.u64()? as usize;
+        let sizes = cursor.i32s(num_seqs)?;
+        let pointers = cursor.i64s(num_seqs)?;
+        let doc_idx = cursor.i64s(num_docs)?;
+        if cursor.offset != data.len() {
+            bail!(
+                "{:?} has {} trailing bytes",
+                idx_path,
+                data.len() - cursor.offset
+            );
+        }
+
+        let bin_file =
+            File::open(bin_path).with_context(|| format!("Failed to open {:?}", bin_path))?;
+        let bin_len = bin_file.metadata()?.len();
+        Ok(Self {
+            bin_file,
+            bin_len,
+            dtype,
+            sizes,
+            pointers,
+            doc_idx,
+        })
+    }
+
+    /// Bytes per element.
+    fn item_size(&self) -> u64 {
+        match self.dtype {
+            DTYPE_UINT8 => 1,
+            _ => 2,
+        }
+    }
+
+    /// Length of every sequence, in elements.
+    pub fn sizes(&self) -> &[i32] {
+        &self.sizes
+    }
+
+    /// Elements of sequence `index`: tokens, or 0/1 for a loss mask.
+    pub fn document(&mut self, index: usize) -> Result<Vec<u32>> {
+        let (Some(&size), Some(&pointer)) = (self.sizes.get(index), self.pointers.get(index))
+        else {
+            bail!(
+                "Document {} out of range ({} documents)",
+                index,
+                self.sizes.len()
+            );
+        };
+        let mut bytes = vec![0u8; size.max(0) as usize * self.item_size() as usize];
+        self.bin_file.seek(SeekFrom::Start(pointer.max(0) as u64))?;
+        self.bin_file
+            .read_exact(&mut bytes)
+            .with_context(|| format!("Failed to read document {}", index))?;
+        Ok(match self.dtype {
+            DTYPE_UINT8 => bytes.into_iter().map(u32::from).collect(),
+            _ => bytes
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as u32)
+                .collect(),
+        })
+    }
+
+    /// Problems of the index: sizes, pointers and document indices that are
+    /// inconsistent with each other or with the size of the `.bin` file.
+    pub fn verify(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let item_size = self.item_size() as i64;
+
+        let mut expected = 0i64;
+        for (index, (&size, &pointer)) in self.sizes.iter().zip(&self.pointers).enumerate() {
+            if size <= 0 {
+                problems.push(format!("Document {index} has size {size}"));
+            }
+            if pointer != expected {
+                problems.push(format!(
+                    "Document {index} starts at byte {pointer}, expected {expected}"
+                ));
+            }
+            expected = pointer + size.max(0) as i64 * item_size;
+        }
+        if expected as u64 != self.bin_len {
+            problems.push(format!(
+                "Index covers {} bytes, but the .bin file has {}",
+                expected, self.bin_len
+            ));
+        }
+
+        let doc_idx: Vec<i64> = (0..=self.sizes.len() as i64).collect();
+        if self.doc_idx != doc_idx {
+            problems.push(format!(
+                "Document index has {} entries, expected 0..={}",
+                self.doc_idx.len(),
+                self.sizes.len()
+            ));
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binidx::BinidxWriter;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_read_back() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let output_path = temp_dir.path().join("test");
+
+        let mut writer = BinidxWriter::new(&output_path)?.with_loss_mask(&output_path)?;
+        writer.add_masked_document(&[0, 7, 8], &[false, false, true])?;
+        writer.add_document(&[300, 65535])?;
+        writer.finish(&output_path)?;
+
+        let mut reader = BinidxReader::open(&output_path)?;
+        assert!(reader.verify().is_empty());
+        assert_eq!(reader.sizes(), &[4, 3]);
+        assert_eq!(reader.document(0)?, [0, 7, 8, 0]);
+        assert_eq!(reader.document(1)?, [300, 65535, 0]);
+        assert!(reader.document(2).is_err());
+
+        let mut mask = BinidxReader::open_mask(&output_path)?;
+        assert!(mask.verify().is_empty());
+        assert_eq!(mask.document(0)?, [0, 0, 1, 1]);
+        assert_eq!(mask.document(1)?, [1, 1, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_truncated_bin() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let output_path = temp_dir.path().join("test");
+
+        let mut writer = BinidxWriter::new(&output_path)?;
+        writer.add_document(&[1, 2, 3])?;
+        writer.finish(&output_path)?;
+
+        let bin_path = output_path.with_extension("bin");
+        let data = std::fs::read(&bin_path)?;
+        std::fs::write(&bin_path, &data[..data.len() - 2])?;
+
+        let mut reader = BinidxReader::open(&output_path)?;
+        assert_eq!(reader.verify().len(), 1);
+        assert!(reader.document(0).is_err());
+        Ok(())
+    }
+}
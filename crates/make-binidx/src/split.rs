@@ -0,0 +1,185 @@
+//! Train/validation splitting and shuffling of the written documents.
+//!
+//! Input is streamed, so shuffling uses a buffer of documents: once it is full,
+//! every new document replaces a random buffered one, which is written out. The
+//! rest of the buffer is written in random order at the end. Documents can move
+//! anywhere after their position, but only about a buffer ahead of it.
+
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::binidx::{BinidxStats, BinidxWriter};
+
+/// A tokenized document, with its loss mask if any.
+pub struct Document {
+    pub tokens: Vec<u32>,
+    pub mask: Option<Vec<bool>>,
+}
+
+/// Buffer that emits its items in random order.
+pub struct ShuffleBuffer<T> {
+    capacity: usize,
+    items: Vec<T>,
+}
+
+impl<T> ShuffleBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            items: Vec::new(),
+        }
+    }
+
+    /// Add `item`; once the buffer is full, returns a random buffered item.
+    pub fn push(&mut self, item: T, rng: &mut impl Rng) -> Option<T> {
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+            return None;
+        }
+        let index = rng.gen_range(0..self.items.len());
+        Some(mem::replace(&mut self.items[index], item))
+    }
+
+    /// The remaining items, in random order.
+    pub fn drain(mut self, rng: &mut impl Rng) -> Vec<T> {
+        self.items.shuffle(rng);
+        self.items
+    }
+}
+
+/// Path of the `name` split of `output_path`, e.g. `output_train`.
+pub fn split_path(output_path: &Path, name: &str) -> PathBuf {
+    let mut file_name = output_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!("_{name}"));
+    output_path.with_file_name(file_name)
+}
+
+/// Writer of the train and, with a split, validation datasets.
+pub struct SplitWriter {
+    train: BinidxWriter,
+    val: Option<(BinidxWriter, f64)>,
+    shuffle: Option<ShuffleBuffer<Document>>,
+    rng: StdRng,
+}
+
+impl SplitWriter {
+    /// Write all documents to `train`.
+    pub fn new(train: BinidxWriter, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            train,
+            val: None,
+            shuffle: None,
+            rng,
+        }
+    }
+
+    /// Write a document to `train` with probability `split`, else to `val`.
+    pub fn with_split(mut self, val: BinidxWriter, split: f64) -> Self {
+        self.val = Some((val, split));
+        self
+    }
+
+    /// Shuffle documents through a buffer of `capacity` documents.
+    pub fn with_shuffle(mut self, capacity: usize) -> Self {
+        self.shuffle = Some(ShuffleBuffer::new(capacity));
+        self
+    }
+
+    pub fn add(&mut self, document: Document) -> Result<()> {
+        let document = match &mut self.shuffle {
+            Some(buffer) => match buffer.push(document, &mut self.rng) {
+                Some(document) => document,
+                None => return Ok(()),
+            },
+            None => document,
+        };
+        self.write(document)
+    }
+
+    fn write(&mut self, document: Document) -> Result<()> {
+        let writer = match &mut self.val {
+            Some((val, split)) if !self.rng.gen_bool(*split) => val,
+            _ => &mut self.train,
+        };
+        match &document.mask {
+            Some(mask) => writer.add_masked_document(&document.tokens, mask),
+            None => writer.add_document(&document.tokens),
+        }
+    }
+
+    /// Write the buffered documents and the idx files of the datasets at
+    /// `train_path` and `val_path`.
+    pub fn finish(
+        mut self,
+        train_path: &Path,
+        val_path: &Path,
+    ) -> Result<(BinidxStats, Option<BinidxStats>)> {
+        if let Some(buffer) = self.shuffle.take() {
+            for document in buffer.drain(&mut self.rng) {
+                self.write(document)?;
+            }
+        }
+        let train = self.train.finish(train_path)?;
+        let val = match self.val {
+            Some((val, _)) => Some(val.finish(val_path)?),
+            None => None,
+        };
+        Ok((train, val))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_shuffle_buffer() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut buffer = ShuffleBuffer::new(4);
+        let mut out: Vec<u32> = (0..100)
+            .filter_map(|item| buffer.push(item, &mut rng))
+            .collect();
+        assert_eq!(out.len(), 96);
+        out.extend(buffer.drain(&mut rng));
+
+        assert_ne!(out, (0..100).collect::<Vec<_>>());
+        out.sort_unstable();
+        assert_eq!(out, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_split_writer() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let output_path = temp_dir.path().join("data");
+        let train_path = split_path(&output_path, "train");
+        let val_path = split_path(&output_path, "val");
+        assert_eq!(train_path, temp_dir.path().join("data_train"));
+
+        let mut writer = SplitWriter::new(BinidxWriter::new(&train_path)?, Some(7))
+            .with_split(BinidxWriter::new(&val_path)?, 0.8)
+            .with_shuffle(16);
+        for token in 1..=1000 {
+            writer.add(Document {
+                tokens: vec![token],
+                mask: None,
+            })?;
+        }
+        let (train, val) = writer.finish(&train_path, &val_path)?;
+        let val = val.unwrap();
+
+        assert_eq!(train.num_documents + val.num_documents, 1000);
+        assert!((700..900).contains(&train.num_documents));
+        assert!(val_path.with_extension("idx").exists());
+        Ok(())
+    }
+}
@@ -222,6 +222,123 @@ fn test_binidx_from_file() {
     assert_eq!(&magic_bytes, b"MMIDIDX\x00\x00", "Invalid idx magic number");
 }
 
+#[test]
+fn test_binidx_split() {
+    let temp_dir = TempDir::new().unwrap();
+    let jsonl_path = temp_dir.path().join("many.jsonl");
+    let lines: Vec<String> = (0..50)
+        .map(|i| {
+            format!(
+                r#"{{"model":"rwkv","messages":[{{"role":"user","content":"Question {i}"}}],"max_tokens":100}}"#
+            )
+        })
+        .collect();
+    fs::write(&jsonl_path, lines.join("\n")).unwrap();
+    let config_path = create_test_config(&temp_dir);
+    let output_path = temp_dir.path().join("output");
+    let tokenizer_path = assets_dir().join("tokenizer/rwkv_vocab_v20230424.json");
+
+    // Skip test if tokenizer not available
+    if !tokenizer_path.exists() {
+        eprintln!("Skipping test: tokenizer not found at {:?}", tokenizer_path);
+        return;
+    }
+
+    let output = Command::new(binary_path())
+        .args([
+            "--input",
+            jsonl_path.to_str().unwrap(),
+            "--output",
+            output_path.to_str().unwrap(),
+            "--tokenizer",
+            tokenizer_path.to_str().unwrap(),
+            "--prompts-config",
+            config_path.to_str().unwrap(),
+            "--split",
+            "0.8",
+            "--shuffle",
+            "--seed",
+            "1",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success(), "Command failed: {}", stderr);
+    assert!(stderr.contains("=== Validation ==="));
+    for name in ["output_train", "output_val"] {
+        let path = temp_dir.path().join(name);
+        assert!(path.with_extension("bin").exists(), "Missing {name}.bin");
+        assert!(path.with_extension("idx").exists(), "Missing {name}.idx");
+    }
+    assert!(!output_path.with_extension("bin").exists());
+}
+
+#[test]
+fn test_inspect_binidx() {
+    let temp_dir = TempDir::new().unwrap();
+    let jsonl_path = create_test_jsonl(&temp_dir);
+    let config_path = create_test_config(&temp_dir);
+    let output_path = temp_dir.path().join("output");
+    let tokenizer_path = assets_dir().join("tokenizer/rwkv_vocab_v20230424.json");
+
+    // Skip test if tokenizer not available
+    if !tokenizer_path.exists() {
+        eprintln!("Skipping test: tokenizer not found at {:?}", tokenizer_path);
+        return;
+    }
+
+    let output = Command::new(binary_path())
+        .args([
+            "--input",
+            jsonl_path.to_str().unwrap(),
+            "--output",
+            output_path.to_str().unwrap(),
+            "--tokenizer",
+            tokenizer_path.to_str().unwrap(),
+            "--prompts-config",
+            config_path.to_str().unwrap(),
+        ])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+
+    let output = Command::new(binary_path())
+        .args([
+            "inspect",
+            output_path.to_str().unwrap(),
+            "--tokenizer",
+            tokenizer_path.to_str().unwrap(),
+            "--samples",
+            "2",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success(), "Command failed: {}", stderr);
+    assert!(stdout.contains("Documents:    2"));
+    assert!(stdout.contains("Hello!"), "Should decode sampled documents");
+    assert!(stdout.contains("Index integrity: OK"));
+
+    // A truncated .bin file fails the integrity check
+    let bin_path = output_path.with_extension("bin");
+    let data = fs::read(&bin_path).unwrap();
+    fs::write(&bin_path, &data[..data.len() - 2]).unwrap();
+
+    let output = Command::new(binary_path())
+        .args(["inspect", output_path.to_str().unwrap(), "--samples", "0"])
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(stdout.contains("Integrity problems"));
+}
+
 #[test]
 fn test_binidx_from_stdin() {
     let temp_dir = TempDir::new().unwrap();
@@ -309,6 +426,36 @@ fn test_custom_separator() {
     assert!(!stdout.contains("---"), "Should not use default separator");
 }
 
+#[test]
+fn test_text_only_dedupe() {
+    let temp_dir = TempDir::new().unwrap();
+    let jsonl_path = temp_dir.path().join("dupes.jsonl");
+    let line = r#"{"model":"rwkv","messages":[{"role":"user","content":"Test"}],"max_tokens":100}"#;
+    fs::write(&jsonl_path, format!("{line}\n{line}\n{line}\n")).unwrap();
+    let config_path = create_test_config(&temp_dir);
+
+    let output = Command::new(binary_path())
+        .args([
+            "--input",
+            jsonl_path.to_str().unwrap(),
+            "--prompts-config",
+            config_path.to_str().unwrap(),
+            "--text-only",
+            "--dedupe",
+            "exact",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(output.status.success(), "Command failed: {}", stderr);
+    assert!(!stdout.contains("---"), "Duplicates should be dropped");
+    assert!(stderr.contains("Processed 1 prompts"));
+    assert!(stderr.contains("Dropped 2 duplicates"));
+}
+
 #[test]
 fn test_empty_lines_skipped() {
     let temp_dir = TempDir::new().unwrap();
@@ -404,3 +551,57 @@ fn test_missing_tokenizer_binidx_mode() {
         "Should mention missing --tokenizer"
     );
 }
+
+#[test]
+fn test_binidx_loss_mask_from_audit_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = create_test_config(&temp_dir);
+    let output_path = temp_dir.path().join("output");
+    let tokenizer_path = assets_dir().join("tokenizer/rwkv_vocab_v20230424.json");
+
+    if !tokenizer_path.exists() {
+        eprintln!("Skipping test: tokenizer not found at {:?}", tokenizer_path);
+        return;
+    }
+
+    // An audit log record: the request with the answer appended, plus `audit`
+    let jsonl_path = temp_dir.path().join("audit.jsonl");
+    fs::write(
+        &jsonl_path,
+        r#"{"model":"rwkv","messages":[{"role":"user","content":"Hi"},{"role":"assistant","content":[{"type":"text","text":"Hello!"}]}],"max_tokens":100,"audit":{"request_id":"req_1","stop_reason":"end_turn"}}"#,
+    )
+    .unwrap();
+
+    let output = Command::new(binary_path())
+        .args([
+            "--input",
+            jsonl_path.to_str().unwrap(),
+            "--output",
+            output_path.to_str().unwrap(),
+            "--tokenizer",
+            tokenizer_path.to_str().unwrap(),
+            "--prompts-config",
+            config_path.to_str().unwrap(),
+            "--loss-mask",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "Command failed: {}", stderr);
+
+    // One mask byte per u16 token
+    let bin = fs::read(output_path.with_extension("bin")).unwrap();
+    let mask = fs::read(output_path.with_extension("mask.bin")).unwrap();
+    assert_eq!(mask.len() * 2, bin.len());
+    assert!(output_path.with_extension("mask.idx").exists());
+
+    // The user turn is masked out, the assistant turn and EOS are trained on
+    assert_eq!(mask[0], 0, "Token 0 prefix should be masked");
+    assert!(mask.contains(&1), "Assistant tokens should be trained on");
+    assert_eq!(
+        *mask.last().unwrap(),
+        1,
+        "EOS after the answer is trained on"
+    );
+}